@@ -0,0 +1,120 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Loading of compressed kernel images, like the `Image.gz` distributions ship.
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use flate2::read::GzDecoder;
+use remain::sorted;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// `lz4 -l` streams, which the kernel build makes `Image.lz4` with.
+const LZ4_LEGACY_MAGIC: [u8; 4] = [0x02, 0x21, 0x4c, 0x18];
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 << 20;
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    Gzip(io::Error),
+    Lz4Block(lz4_flex::block::DecompressError),
+    Lz4Frame(io::Error),
+    ReadImage(io::Error),
+    WriteKernel(GuestMemoryError),
+}
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            Gzip(e) => write!(f, "failed to decompress gzip kernel: {}", e),
+            Lz4Block(e) => write!(f, "failed to decompress lz4 kernel: {}", e),
+            Lz4Frame(e) => write!(f, "failed to decompress lz4 kernel: {}", e),
+            ReadImage(e) => write!(f, "failed to read kernel image: {}", e),
+            WriteKernel(e) => write!(f, "failed to write kernel to guest memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Decompresses the blocks of an lz4 legacy stream, which may be followed by more streams.
+fn decompress_lz4_legacy<F: Read>(image: &mut F) -> Result<Vec<u8>> {
+    let mut kernel = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        match image.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::ReadImage(e)),
+        }
+        if header == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+
+        let mut block = vec![0u8; u32::from_le_bytes(header) as usize];
+        image.read_exact(&mut block).map_err(Error::ReadImage)?;
+        let start = kernel.len();
+        kernel.resize(start + LZ4_LEGACY_BLOCK_SIZE, 0);
+        let len = lz4_flex::block::decompress_into(&block, &mut kernel[start..])
+            .map_err(Error::Lz4Block)?;
+        kernel.truncate(start + len);
+    }
+    Ok(kernel)
+}
+
+/// Decompresses a gzip or lz4 compressed `kernel_image` into guest memory at `guest_addr`.
+/// Returns the size of the decompressed kernel, or `None` if the image isn't compressed in a
+/// known format, in which case it should be loaded as is.
+pub fn load_compressed_kernel<F>(
+    guest_mem: &GuestMemory,
+    kernel_image: &mut F,
+    guest_addr: GuestAddress,
+) -> Result<Option<usize>>
+where
+    F: Read + Seek,
+{
+    let mut magic = [0u8; 4];
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .map_err(Error::ReadImage)?;
+    // Images too small to hold the magic of any format are loaded as is.
+    if kernel_image.read_exact(&mut magic).is_err() {
+        return Ok(None);
+    }
+
+    let mut kernel = Vec::new();
+    if magic[..2] == GZIP_MAGIC {
+        kernel_image
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::ReadImage)?;
+        GzDecoder::new(kernel_image)
+            .read_to_end(&mut kernel)
+            .map_err(Error::Gzip)?;
+    } else if magic == LZ4_LEGACY_MAGIC {
+        kernel = decompress_lz4_legacy(kernel_image)?;
+    } else if magic == LZ4_FRAME_MAGIC {
+        kernel_image
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::ReadImage)?;
+        lz4_flex::frame::FrameDecoder::new(kernel_image)
+            .read_to_end(&mut kernel)
+            .map_err(Error::Lz4Frame)?;
+    } else {
+        return Ok(None);
+    }
+
+    guest_mem
+        .write_all_at_addr(&kernel, guest_addr)
+        .map_err(Error::WriteKernel)?;
+    Ok(Some(kernel.len()))
+}
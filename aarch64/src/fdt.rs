@@ -7,11 +7,12 @@ use std::fs::File;
 use std::io::Read;
 
 use arch::fdt::{
-    begin_node, end_node, finish_fdt, generate_prop32, generate_prop64, property, property_cstring,
-    property_null, property_string, property_string_list, property_u32, property_u64, start_fdt,
-    Error, Result,
+    apply_overlays, begin_node, end_node, finish_fdt, generate_prop32, generate_prop64, property,
+    property_cstring, property_null, property_string, property_string_list, property_u32,
+    property_u64, start_fdt, Error, Result,
 };
-use arch::SERIAL_ADDR;
+use arch::numa::{numa_distance, NumaNode};
+use arch::{PlatformDeviceNode, SERIAL_ADDR};
 use devices::{PciAddress, PciInterruptPin};
 use hypervisor::PsciVersion;
 use vm_memory::{GuestAddress, GuestMemory};
@@ -24,6 +25,7 @@ use crate::AARCH64_GIC_CPUI_BASE;
 use crate::AARCH64_GIC_CPUI_SIZE;
 use crate::AARCH64_GIC_DIST_BASE;
 use crate::AARCH64_GIC_DIST_SIZE;
+use crate::AARCH64_GIC_ITS_SIZE;
 use crate::AARCH64_GIC_REDIST_SIZE;
 
 // These are RTC related constants
@@ -50,6 +52,7 @@ use crate::AARCH64_PMU_IRQ;
 // If we had a more complex interrupt architecture, then we'd need an enum for
 // these.
 const PHANDLE_GIC: u32 = 1;
+const PHANDLE_GIC_ITS: u32 = 2;
 
 // These are specified by the Linux GIC bindings
 const GIC_FDT_IRQ_NUM_CELLS: u32 = 3;
@@ -72,7 +75,42 @@ fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemory) -> Result<()>
     Ok(())
 }
 
-fn create_cpu_nodes(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
+// Describes the memory of each NUMA node with a memory node of its own.
+fn create_numa_memory_nodes(
+    fdt: &mut Vec<u8>,
+    numa_ranges: &[Vec<(GuestAddress, u64)>],
+) -> Result<()> {
+    for (node, ranges) in numa_ranges.iter().enumerate() {
+        for &(start, size) in ranges {
+            let mem_reg_prop = generate_prop64(&[start.offset(), size]);
+
+            begin_node(fdt, &format!("memory@{:x}", start.offset()))?;
+            property_string(fdt, "device_type", "memory")?;
+            property(fdt, "reg", &mem_reg_prop)?;
+            property_u32(fdt, "numa-node-id", node as u32)?;
+            end_node(fdt)?;
+        }
+    }
+    Ok(())
+}
+
+fn create_numa_distance_map_node(fdt: &mut Vec<u8>, numa_nodes: &[NumaNode]) -> Result<()> {
+    let mut matrix = Vec::new();
+    for from in 0..numa_nodes.len() {
+        for to in 0..numa_nodes.len() {
+            let distance = numa_distance(numa_nodes, from, to);
+            matrix.extend_from_slice(&[from as u32, to as u32, distance as u32]);
+        }
+    }
+
+    begin_node(fdt, "distance-map")?;
+    property_string(fdt, "compatible", "numa-distance-map-v1")?;
+    property(fdt, "distance-matrix", &generate_prop32(&matrix))?;
+    end_node(fdt)?;
+    Ok(())
+}
+
+fn create_cpu_nodes(fdt: &mut Vec<u8>, num_cpus: u32, numa_nodes: &[NumaNode]) -> Result<()> {
     begin_node(fdt, "cpus")?;
     property_u32(fdt, "#address-cells", 0x1)?;
     property_u32(fdt, "#size-cells", 0x0)?;
@@ -86,13 +124,24 @@ fn create_cpu_nodes(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
             property_string(fdt, "enable-method", "psci")?;
         }
         property_u32(fdt, "reg", cpu_id)?;
+        if let Some(node) = numa_nodes
+            .iter()
+            .position(|node| node.cpus.contains(&(cpu_id as usize)))
+        {
+            property_u32(fdt, "numa-node-id", node as u32)?;
+        }
         end_node(fdt)?;
     }
     end_node(fdt)?;
     Ok(())
 }
 
-fn create_gic_node(fdt: &mut Vec<u8>, is_gicv3: bool, num_cpus: u64) -> Result<()> {
+// The ITS is placed right below the redistributors.
+fn its_base(num_cpus: u64) -> u64 {
+    AARCH64_GIC_DIST_BASE - AARCH64_GIC_REDIST_SIZE * num_cpus - AARCH64_GIC_ITS_SIZE
+}
+
+fn create_gic_node(fdt: &mut Vec<u8>, is_gicv3: bool, has_its: bool, num_cpus: u64) -> Result<()> {
     let mut gic_reg_prop = [AARCH64_GIC_DIST_BASE, AARCH64_GIC_DIST_SIZE, 0, 0];
 
     begin_node(fdt, "intc")?;
@@ -112,17 +161,34 @@ fn create_gic_node(fdt: &mut Vec<u8>, is_gicv3: bool, num_cpus: u64) -> Result<(
     property_u32(fdt, "phandle", PHANDLE_GIC)?;
     property_u32(fdt, "#address-cells", 2)?;
     property_u32(fdt, "#size-cells", 2)?;
+    if has_its {
+        let its_base = its_base(num_cpus);
+        let its_reg_prop = generate_prop64(&[its_base, AARCH64_GIC_ITS_SIZE]);
+        property_null(fdt, "ranges")?;
+        begin_node(fdt, &format!("msic@{:x}", its_base))?;
+        property_string(fdt, "compatible", "arm,gic-v3-its")?;
+        property_null(fdt, "msi-controller")?;
+        property_u32(fdt, "#msi-cells", 1)?;
+        property(fdt, "reg", &its_reg_prop)?;
+        property_u32(fdt, "phandle", PHANDLE_GIC_ITS)?;
+        end_node(fdt)?;
+    }
     end_node(fdt)?;
 
     Ok(())
 }
 
+// Returns the CPU mask of a PPI's interrupt specifier, which has room for the first 8 CPUs.
+fn ppi_cpu_mask(num_cpus: u32) -> u32 {
+    let cpus = (1u64 << num_cpus.min(8)) - 1;
+    ((cpus as u32) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK
+}
+
 fn create_timer_node(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
     // These are fixed interrupt numbers for the timer device.
     let irqs = [13, 14, 11, 10];
     let compatible = "arm,armv8-timer";
-    let cpu_mask: u32 =
-        (((1 << num_cpus) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
+    let cpu_mask = ppi_cpu_mask(num_cpus);
 
     let mut timer_reg_cells = Vec::new();
     for &irq in &irqs {
@@ -143,8 +209,7 @@ fn create_timer_node(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
 
 fn create_pmu_node(fdt: &mut Vec<u8>, num_cpus: u32) -> Result<()> {
     let compatible = "arm,armv8-pmuv3";
-    let cpu_mask: u32 =
-        (((1 << num_cpus) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
+    let cpu_mask = ppi_cpu_mask(num_cpus);
     let irq = generate_prop32(&[
         GIC_FDT_IRQ_TYPE_PPI,
         AARCH64_PMU_IRQ,
@@ -245,6 +310,7 @@ fn create_pci_nodes(
     pci_irqs: Vec<(PciAddress, u32, PciInterruptPin)>,
     pci_device_base: u64,
     pci_device_size: u64,
+    has_its: bool,
 ) -> Result<()> {
     // Add devicetree nodes describing a PCI generic host controller.
     // See Documentation/devicetree/bindings/pci/host-generic-pci.txt in the kernel
@@ -267,7 +333,7 @@ fn create_pci_nodes(
         (pci_device_size >> 32) as u32, // size
         pci_device_size as u32,
     ]);
-    let bus_range = generate_prop32(&[0, 0]); // Only bus 0
+    let bus_range = generate_prop32(&[0, (AARCH64_PCI_CFG_SIZE >> 20) as u32 - 1]);
     let reg = generate_prop64(&[AARCH64_PCI_CFG_BASE, AARCH64_PCI_CFG_SIZE]);
 
     let mut interrupts: Vec<u32> = Vec::new();
@@ -305,7 +371,7 @@ fn create_pci_nodes(
     let interrupt_map_mask = generate_prop32(&masks);
 
     begin_node(fdt, "pci")?;
-    property_string(fdt, "compatible", "pci-host-cam-generic")?;
+    property_string(fdt, "compatible", "pci-host-ecam-generic")?;
     property_string(fdt, "device_type", "pci")?;
     property(fdt, "ranges", &ranges)?;
     property(fdt, "bus-range", &bus_range)?;
@@ -315,19 +381,26 @@ fn create_pci_nodes(
     property_u32(fdt, "#interrupt-cells", 1)?;
     property(fdt, "interrupt-map", &interrupt_map)?;
     property(fdt, "interrupt-map-mask", &interrupt_map_mask)?;
+    if has_its {
+        // MSIs from any requester ID go to the ITS, with the requester ID as the device ID.
+        let msi_map = generate_prop32(&[0, PHANDLE_GIC_ITS, 0, 0x10000]);
+        property(fdt, "msi-map", &msi_map)?;
+    }
     property_null(fdt, "dma-coherent")?;
     end_node(fdt)?;
 
     Ok(())
 }
 
+const PCLK_NODE_NAME: &str = "pclk@3M";
+
 fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<()> {
     // the kernel driver for pl030 really really wants a clock node
     // associated with an AMBA device or it will fail to probe, so we
     // need to make up a clock node to associate with the pl030 rtc
     // node and an associated handle with a unique phandle value.
     const CLK_PHANDLE: u32 = 24;
-    begin_node(fdt, "pclk@3M")?;
+    begin_node(fdt, PCLK_NODE_NAME)?;
     property_u32(fdt, "#clock-cells", 0)?;
     property_string(fdt, "compatible", "fixed-clock")?;
     property_u32(fdt, "clock-frequency", 3141592)?;
@@ -349,6 +422,77 @@ fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<()> {
     Ok(())
 }
 
+fn create_battery_node(fdt: &mut Vec<u8>, mmio_base: u64, irq: u32) -> Result<()> {
+    let bat_name = format!("goldfish_battery@{:x}", mmio_base);
+    let reg = generate_prop64(&[mmio_base, devices::bat::GOLDFISHBAT_MMIO_LEN]);
+    let irqs = generate_prop32(&[GIC_FDT_IRQ_TYPE_SPI, irq, IRQ_TYPE_LEVEL_HIGH]);
+
+    begin_node(fdt, &bat_name)?;
+    property_string(fdt, "compatible", "google,goldfish-battery")?;
+    property(fdt, "reg", &reg)?;
+    property(fdt, "interrupts", &irqs)?;
+    end_node(fdt)?;
+    Ok(())
+}
+
+fn create_platform_device_nodes(fdt: &mut Vec<u8>, nodes: &[PlatformDeviceNode]) -> Result<()> {
+    for node in nodes {
+        // Name the node after the device model in its first compatible string, e.g. "pl011" for
+        // "arm,pl011".
+        let model = node
+            .compatible
+            .first()
+            .map(|c| c.rsplit(',').next().unwrap_or(c))
+            .unwrap_or("platform");
+        let name = match node.regions.first() {
+            Some((addr, _)) => format!("{}@{:x}", model, addr),
+            None => model.to_string(),
+        };
+        let reg_cells: Vec<u64> = node
+            .regions
+            .iter()
+            .flat_map(|&(addr, size)| vec![addr, size])
+            .collect();
+        let irq_cells: Vec<u32> = node
+            .irqs
+            .iter()
+            .flat_map(|&(irq, level)| {
+                let irq_type = if level {
+                    IRQ_TYPE_LEVEL_HIGH
+                } else {
+                    IRQ_TYPE_EDGE_RISING
+                };
+                vec![GIC_FDT_IRQ_TYPE_SPI, irq, irq_type]
+            })
+            .collect();
+
+        begin_node(fdt, &name)?;
+        property_string_list(fdt, "compatible", node.compatible.clone())?;
+        if !reg_cells.is_empty() {
+            property(fdt, "reg", &generate_prop64(&reg_cells))?;
+        }
+        if !irq_cells.is_empty() {
+            property(fdt, "interrupts", &generate_prop32(&irq_cells))?;
+        }
+        end_node(fdt)?;
+    }
+    Ok(())
+}
+
+// Labels the nodes that device tree overlays can refer to, with the paths of the nodes.
+fn create_symbols_node(fdt: &mut Vec<u8>, has_its: bool, num_cpus: u64) -> Result<()> {
+    begin_node(fdt, "__symbols__")?;
+    property_string(fdt, "intc", "/intc")?;
+    if has_its {
+        property_string(fdt, "its", &format!("/intc/msic@{:x}", its_base(num_cpus)))?;
+    }
+    property_string(fdt, "pclk", &format!("/{}", PCLK_NODE_NAME))?;
+    property_string(fdt, "pci", "/pci")?;
+    end_node(fdt)?;
+
+    Ok(())
+}
+
 /// Creates a flattened device tree containing all of the parameters for the
 /// kernel and loads it into the guest memory at the specified offset.
 ///
@@ -365,7 +509,13 @@ fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<()> {
 /// * `initrd` - An optional tuple of initrd guest physical address and size
 /// * `android_fstab` - An optional file holding Android fstab entries
 /// * `is_gicv3` - True if gicv3, false if v2
+/// * `has_its` - True if the gicv3 has an ITS for PCI MSIs
 /// * `psci_version` - the current PSCI version
+/// * `bat_mmio_base_and_irq` - The battery base address and irq number
+/// * `platform_dev_nodes` - The vfio platform devices to describe
+/// * `dt_overlays` - Device tree overlays to apply to the device tree, in order
+/// * `numa_nodes` - The guest NUMA nodes, if the guest has any
+/// * `numa_ranges` - The guest memory of each NUMA node
 pub fn create_fdt(
     fdt_max_size: usize,
     guest_mem: &GuestMemory,
@@ -378,8 +528,14 @@ pub fn create_fdt(
     initrd: Option<(GuestAddress, usize)>,
     android_fstab: Option<File>,
     is_gicv3: bool,
+    has_its: bool,
     use_pmu: bool,
     psci_version: PsciVersion,
+    bat_mmio_base_and_irq: Option<(u64, u32)>,
+    platform_dev_nodes: &[PlatformDeviceNode],
+    dt_overlays: &[Vec<u8>],
+    numa_nodes: &[NumaNode],
+    numa_ranges: &[Vec<(GuestAddress, u64)>],
 ) -> Result<()> {
     let mut fdt = vec![0; fdt_max_size];
     start_fdt(&mut fdt, fdt_max_size)?;
@@ -394,23 +550,42 @@ pub fn create_fdt(
         arch::android::create_android_fdt(&mut fdt, android_fstab)?;
     }
     create_chosen_node(&mut fdt, cmdline, initrd)?;
-    create_memory_node(&mut fdt, guest_mem)?;
-    create_cpu_nodes(&mut fdt, num_cpus)?;
-    create_gic_node(&mut fdt, is_gicv3, num_cpus as u64)?;
+    if numa_nodes.is_empty() {
+        create_memory_node(&mut fdt, guest_mem)?;
+    } else {
+        create_numa_memory_nodes(&mut fdt, numa_ranges)?;
+        create_numa_distance_map_node(&mut fdt, numa_nodes)?;
+    }
+    create_cpu_nodes(&mut fdt, num_cpus, numa_nodes)?;
+    create_gic_node(&mut fdt, is_gicv3, has_its, num_cpus as u64)?;
     create_timer_node(&mut fdt, num_cpus)?;
     if use_pmu {
         create_pmu_node(&mut fdt, num_cpus)?;
     }
     create_serial_nodes(&mut fdt)?;
     create_psci_node(&mut fdt, &psci_version)?;
-    create_pci_nodes(&mut fdt, pci_irqs, pci_device_base, pci_device_size)?;
+    create_pci_nodes(
+        &mut fdt,
+        pci_irqs,
+        pci_device_base,
+        pci_device_size,
+        has_its,
+    )?;
     create_rtc_node(&mut fdt)?;
+    if let Some((bat_mmio_base, bat_irq)) = bat_mmio_base_and_irq {
+        create_battery_node(&mut fdt, bat_mmio_base, bat_irq)?;
+    }
+    create_platform_device_nodes(&mut fdt, platform_dev_nodes)?;
+    if !dt_overlays.is_empty() {
+        create_symbols_node(&mut fdt, has_its, num_cpus as u64)?;
+    }
     // End giant node
     end_node(&mut fdt)?;
 
     // Allocate another buffer so we can format and then write fdt to guest
     let mut fdt_final = vec![0; fdt_max_size];
     finish_fdt(&mut fdt, &mut fdt_final, fdt_max_size)?;
+    apply_overlays(&mut fdt_final, dt_overlays, fdt_max_size)?;
 
     let fdt_address = GuestAddress(AARCH64_PHYS_MEM_START + fdt_load_offset);
     let written = guest_mem
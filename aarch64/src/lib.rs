@@ -11,23 +11,26 @@ use std::io::{self, Seek};
 use std::sync::Arc;
 
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, CpuFeatures, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware,
+    SerialParameters, VmComponents, VmDevices, VmImage,
 };
-use base::Event;
+use base::{pagesize, Event, MemoryMappingBuilder, MmapError};
 use devices::{
-    Bus, BusError, IrqChip, IrqChipAArch64, PciAddress, PciConfigMmio, PciDevice, PciInterruptPin,
+    Bus, BusError, IrqChip, IrqChipAArch64, PciAddress, PciConfigMmio, PciInterruptPin,
+    PCIE_CONFIG_REGISTER_BITS,
 };
 use hypervisor::{
-    DeviceKind, Hypervisor, HypervisorCap, PsciVersion, VcpuAArch64, VcpuFeature, VmAArch64,
+    DeviceKind, Hypervisor, HypervisorCap, PsciVersion, VcpuAArch64, VcpuFeature, VcpuRegAArch64,
+    VmAArch64,
 };
 use minijail::Minijail;
 use remain::sorted;
 use resources::SystemAllocator;
 use sync::Mutex;
-use vm_control::BatteryType;
+use vm_control::{BatControl, BatteryType};
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
+mod decompress;
 mod fdt;
 
 // We place the kernel at offset 8MB
@@ -54,6 +57,7 @@ const AARCH64_BIOS_MAX_LEN: u64 = 1 << 20;
 const AARCH64_GIC_DIST_BASE: u64 = AARCH64_AXI_BASE - AARCH64_GIC_DIST_SIZE;
 const AARCH64_GIC_CPUI_BASE: u64 = AARCH64_GIC_DIST_BASE - AARCH64_GIC_CPUI_SIZE;
 const AARCH64_GIC_REDIST_SIZE: u64 = 0x20000;
+const AARCH64_GIC_ITS_SIZE: u64 = 0x20000;
 
 // PSR (Processor State Register) bits
 const PSR_MODE_EL1H: u64 = 0x00000005;
@@ -62,26 +66,6 @@ const PSR_I_BIT: u64 = 0x00000080;
 const PSR_A_BIT: u64 = 0x00000100;
 const PSR_D_BIT: u64 = 0x00000200;
 
-macro_rules! offset__of {
-    ($str:ty, $($field:ident).+ $([$idx:expr])*) => {
-        unsafe { &(*(0 as *const $str))$(.$field)*  $([$idx])* as *const _ as usize }
-    }
-}
-
-const KVM_REG_ARM64: u64 = 0x6000000000000000;
-const KVM_REG_SIZE_U64: u64 = 0x0030000000000000;
-const KVM_REG_ARM_COPROC_SHIFT: u64 = 16;
-const KVM_REG_ARM_CORE: u64 = 0x0010 << KVM_REG_ARM_COPROC_SHIFT;
-
-macro_rules! arm64_core_reg {
-    ($reg: tt) => {
-        KVM_REG_ARM64
-            | KVM_REG_SIZE_U64
-            | KVM_REG_ARM_CORE
-            | ((offset__of!(kvm_sys::user_pt_regs, $reg) / 4) as u64)
-    };
-}
-
 fn get_kernel_addr() -> GuestAddress {
     GuestAddress(AARCH64_PHYS_MEM_START + AARCH64_KERNEL_OFFSET)
 }
@@ -106,9 +90,9 @@ const AARCH64_RTC_SIZE: u64 = 0x1000;
 // The RTC device gets the second interrupt line
 const AARCH64_RTC_IRQ: u32 = 1;
 
-// PCI MMIO configuration region base address.
+// PCI Express ECAM region base address.
 const AARCH64_PCI_CFG_BASE: u64 = 0x10000;
-// PCI MMIO configuration region size.
+// PCI Express ECAM region size, 1 MiB for each of buses 0 to 15.
 const AARCH64_PCI_CFG_SIZE: u64 = 0x1000000;
 // This is the base address of MMIO devices.
 const AARCH64_MMIO_BASE: u64 = 0x1010000;
@@ -117,36 +101,62 @@ const AARCH64_MMIO_SIZE: u64 = 0x100000;
 // Virtio devices start at SPI interrupt number 3
 const AARCH64_IRQ_BASE: u32 = 3;
 
-// PMU PPI interrupt, same as qemu
+// PMU PPI interrupt, same as qemu. PPIs are numbered from 16 among all interrupts.
 const AARCH64_PMU_IRQ: u32 = 7;
 
+// The stolen time structures KVM updates for each VCPU are placed here, after the MMIO region,
+// outside of guest RAM.
+const AARCH64_PVTIME_IPA_START: u64 = 0x1f00000;
+// Size of the stolen time structure of a VCPU.
+const AARCH64_PVTIME_SIZE: u64 = 64;
+
+// The firmware the hypervisor loads into protected VMs gets a region of its own right below RAM.
+const AARCH64_PROTECTED_VM_FW_MAX_SIZE: u64 = 0x200000;
+const AARCH64_PROTECTED_VM_FW_START: u64 =
+    AARCH64_PHYS_MEM_START - AARCH64_PROTECTED_VM_FW_MAX_SIZE;
+
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
+    AddProtectedVmFirmwareMemory(base::Error),
+    AddPvtimeMemory(base::Error),
+    BindNumaMemory(GuestMemoryError),
     BiosLoadFailure(arch::LoadImageError),
     CloneEvent(base::Error),
     Cmdline(kernel_cmdline::Error),
+    CreateBatDevices(arch::DeviceRegistrationError),
     CreateDevices(Box<dyn StdError>),
     CreateEvent(base::Error),
     CreateFdt(arch::fdt::Error),
     CreateGICFailure(base::Error),
     CreateIrqChip(Box<dyn StdError>),
     CreatePciRoot(arch::DeviceRegistrationError),
+    CreatePlatformBus(arch::DeviceRegistrationError),
+    CreateProtectedVmFirmwareMemory(MmapError),
+    CreatePvtimeMemory(MmapError),
     CreateSerialDevices(arch::DeviceRegistrationError),
     CreateSocket(io::Error),
     CreateVcpu(base::Error),
     CreateVm(Box<dyn StdError>),
+    DecompressKernel(decompress::Error),
     DowncastVcpu,
+    EnableProtectedVm(base::Error),
+    GetProtectedVmInfo(base::Error),
     GetPsciVersion(base::Error),
     GetSerialCmdline(GetSerialCmdlineError),
+    InitPmu(base::Error),
+    InitPvtime(base::Error),
     InitrdLoadFailure(arch::LoadImageError),
     KernelLoadFailure(arch::LoadImageError),
+    ProtectedVmFirmwareTooLarge(u64),
+    ProtectedVmUnsupported,
     RegisterIrqfd(base::Error),
     RegisterPci(BusError),
     RegisterVsock(arch::DeviceRegistrationError),
     SetDeviceAttr(base::Error),
     SetReg(base::Error),
     SetupGuestMemory(GuestMemoryError),
+    SnapshotUnsupported,
     VcpuInit(base::Error),
 }
 
@@ -157,30 +167,59 @@ impl Display for Error {
 
         #[sorted]
         match self {
+            AddProtectedVmFirmwareMemory(e) => {
+                write!(
+                    f,
+                    "failed to add protected VM firmware memory to the vm: {}",
+                    e
+                )
+            }
+            AddPvtimeMemory(e) => write!(f, "failed to add stolen time memory to the vm: {}", e),
+            BindNumaMemory(e) => {
+                write!(f, "failed to bind NUMA node memory to its host node: {}", e)
+            }
             BiosLoadFailure(e) => write!(f, "bios could not be loaded: {}", e),
             CloneEvent(e) => write!(f, "unable to clone an Event: {}", e),
             Cmdline(e) => write!(f, "the given kernel command line was invalid: {}", e),
+            CreateBatDevices(e) => write!(f, "unable to create battery devices: {}", e),
             CreateDevices(e) => write!(f, "error creating devices: {}", e),
             CreateEvent(e) => write!(f, "unable to make an Event: {}", e),
             CreateFdt(e) => write!(f, "FDT could not be created: {}", e),
             CreateGICFailure(e) => write!(f, "failed to create GIC: {}", e),
             CreateIrqChip(e) => write!(f, "failed to create IRQ chip: {}", e),
             CreatePciRoot(e) => write!(f, "failed to create a PCI root hub: {}", e),
+            CreatePlatformBus(e) => write!(f, "failed to place platform devices: {}", e),
+            CreateProtectedVmFirmwareMemory(e) => {
+                write!(f, "failed to create protected VM firmware memory: {}", e)
+            }
+            CreatePvtimeMemory(e) => write!(f, "failed to create stolen time memory: {}", e),
             CreateSerialDevices(e) => write!(f, "unable to create serial devices: {}", e),
             CreateSocket(e) => write!(f, "failed to create socket: {}", e),
             CreateVcpu(e) => write!(f, "failed to create VCPU: {}", e),
             CreateVm(e) => write!(f, "failed to create vm: {}", e),
+            DecompressKernel(e) => write!(f, "failed to load compressed kernel: {}", e),
             DowncastVcpu => write!(f, "vm created wrong kind of vcpu"),
+            EnableProtectedVm(e) => write!(f, "failed to enable protected VM: {}", e),
+            GetProtectedVmInfo(e) => write!(f, "failed to get protected VM info: {}", e),
             GetPsciVersion(e) => write!(f, "failed to get PSCI version: {}", e),
             GetSerialCmdline(e) => write!(f, "failed to get serial cmdline: {}", e),
+            InitPmu(e) => write!(f, "failed to initialize VCPU PMU: {}", e),
+            InitPvtime(e) => write!(f, "failed to set up VCPU stolen time: {}", e),
             InitrdLoadFailure(e) => write!(f, "initrd could not be loaded: {}", e),
             KernelLoadFailure(e) => write!(f, "kernel could not be loaded: {}", e),
+            ProtectedVmFirmwareTooLarge(size) => write!(
+                f,
+                "protected VM firmware of {} bytes doesn't fit in its {} byte region",
+                size, AARCH64_PROTECTED_VM_FW_MAX_SIZE
+            ),
+            ProtectedVmUnsupported => write!(f, "the hypervisor doesn't support protected VMs"),
             RegisterIrqfd(e) => write!(f, "failed to register irq fd: {}", e),
             RegisterPci(e) => write!(f, "error registering PCI bus: {}", e),
             RegisterVsock(e) => write!(f, "error registering virtual socket device: {}", e),
             SetDeviceAttr(e) => write!(f, "failed to set device attr: {}", e),
             SetReg(e) => write!(f, "failed to set register: {}", e),
             SetupGuestMemory(e) => write!(f, "failed to set up guest memory: {}", e),
+            SnapshotUnsupported => write!(f, "VM snapshots aren't supported on aarch64"),
             VcpuInit(e) => write!(f, "failed to initialize VCPU: {}", e),
         }
     }
@@ -219,7 +258,7 @@ impl arch::LinuxArch for AArch64 {
         mut components: VmComponents,
         serial_parameters: &BTreeMap<(SerialHardware, u8), SerialParameters>,
         serial_jail: Option<Minijail>,
-        _battery: (&Option<BatteryType>, Option<Minijail>),
+        battery: (&Option<BatteryType>, Option<Minijail>),
         create_devices: FD,
         create_vm: FV,
         create_irq_chip: FI,
@@ -233,7 +272,7 @@ impl arch::LinuxArch for AArch64 {
             &mut V,
             &mut SystemAllocator,
             &Event,
-        ) -> std::result::Result<Vec<(Box<dyn PciDevice>, Option<Minijail>)>, E1>,
+        ) -> std::result::Result<VmDevices, E1>,
         FV: FnOnce(GuestMemory) -> std::result::Result<V, E2>,
         FI: FnOnce(&V, /* vcpu_count: */ usize) -> std::result::Result<I, E3>,
         E1: StdError + 'static,
@@ -246,10 +285,20 @@ impl arch::LinuxArch for AArch64 {
         };
 
         let mut resources = Self::get_resource_allocator(components.memory_size);
-        let mem = Self::setup_memory(components.memory_size)?;
+        let mem = Self::setup_memory(components.memory_size, components.mergeable_memory)?;
+        let numa_ranges = arch::numa::numa_node_ranges(
+            &arch_memory_regions(components.memory_size),
+            &components.numa_nodes,
+        );
+        arch::numa::bind_numa_memory(&mem, &components.numa_nodes, &numa_ranges)
+            .map_err(Error::BindNumaMemory)?;
         let mut vm = create_vm(mem.clone()).map_err(|e| Error::CreateVm(Box::new(e)))?;
 
-        let mut use_pmu = vm
+        if components.protected_vm {
+            Self::setup_protected_vm(&mut vm)?;
+        }
+
+        let use_pmu = vm
             .get_hypervisor()
             .check_capability(&HypervisorCap::ArmPmuV3);
         let vcpu_count = components.vcpu_count;
@@ -267,8 +316,20 @@ impl arch::LinuxArch for AArch64 {
         let mut irq_chip =
             create_irq_chip(&vm, vcpu_count).map_err(|e| Error::CreateIrqChip(Box::new(e)))?;
 
-        for vcpu in &vcpus {
-            use_pmu &= vcpu.init_pmu(AARCH64_PMU_IRQ as u64 + 16).is_ok();
+        if use_pmu {
+            // VCPUs created with the PMU feature can't run until their PMU is initialized, which
+            // has to wait for the irq chip.
+            for vcpu in &vcpus {
+                vcpu.init_pmu(AARCH64_PMU_IRQ as u64 + 16)
+                    .map_err(Error::InitPmu)?;
+            }
+        }
+
+        if vm
+            .get_hypervisor()
+            .check_capability(&HypervisorCap::ArmPvTime)
+        {
+            Self::setup_pvtime(&mut vm, &vcpus)?;
         }
 
         let mut mmio_bus = devices::Bus::new();
@@ -278,11 +339,13 @@ impl arch::LinuxArch for AArch64 {
         // Event used by PMDevice to notify crosvm that
         // guest OS is trying to suspend.
         let suspend_evt = Event::new().map_err(Error::CreateEvent)?;
+        // Never signaled, as there is no ACPI to put the guest to sleep with.
+        let sleep_evt = Event::new().map_err(Error::CreateEvent)?;
 
-        let pci_devices = create_devices(&mem, &mut vm, &mut resources, &exit_evt)
+        let vm_devices = create_devices(&mem, &mut vm, &mut resources, &exit_evt)
             .map_err(|e| Error::CreateDevices(Box::new(e)))?;
-        let (pci, pci_irqs, pid_debug_label_map) = arch::generate_pci_root(
-            pci_devices,
+        let (pci, pci_irqs, mut pid_debug_label_map) = arch::generate_pci_root(
+            vm_devices.pci,
             &mut irq_chip,
             &mut mmio_bus,
             &mut resources,
@@ -290,7 +353,19 @@ impl arch::LinuxArch for AArch64 {
             (devices::AARCH64_GIC_NR_IRQS - AARCH64_IRQ_BASE) as usize,
         )
         .map_err(Error::CreatePciRoot)?;
-        let pci_bus = Arc::new(Mutex::new(PciConfigMmio::new(pci)));
+        let (platform_dev_nodes, platform_pid_labels) = arch::generate_platform_bus(
+            vm_devices.platform,
+            &mut irq_chip,
+            &mut mmio_bus,
+            &mut resources,
+            &mut vm,
+        )
+        .map_err(Error::CreatePlatformBus)?;
+        pid_debug_label_map.extend(platform_pid_labels);
+        let pci_bus = Arc::new(Mutex::new(PciConfigMmio::new(
+            pci.clone(),
+            PCIE_CONFIG_REGISTER_BITS,
+        )));
 
         // ARM doesn't really use the io bus like x86, so just create an empty bus.
         let io_bus = devices::Bus::new();
@@ -320,6 +395,33 @@ impl arch::LinuxArch for AArch64 {
             .insert(pci_bus.clone(), AARCH64_PCI_CFG_BASE, AARCH64_PCI_CFG_SIZE)
             .map_err(Error::RegisterPci)?;
 
+        let (bat_control, bat_mmio_base_and_irq) = match battery.0 {
+            Some(BatteryType::Goldfish) => {
+                let bat_irq = resources.allocate_irq().ok_or(Error::CreateBatDevices(
+                    arch::DeviceRegistrationError::AllocateIrq,
+                ))?;
+                // The battery is described to the guest in the device tree, so its AML is unused.
+                let mut amls = Vec::new();
+                let (control_socket, bat_mmio_base) = arch::add_goldfish_battery(
+                    &mut amls,
+                    battery.1,
+                    &mut mmio_bus,
+                    &mut irq_chip,
+                    bat_irq,
+                    &mut resources,
+                )
+                .map_err(Error::CreateBatDevices)?;
+                (
+                    Some(BatControl {
+                        type_: BatteryType::Goldfish,
+                        control_socket,
+                    }),
+                    Some((bat_mmio_base, bat_irq)),
+                )
+            }
+            None => (None, None),
+        };
+
         let mut cmdline = Self::get_base_linux_cmdline();
         get_serial_cmdline(&mut cmdline, serial_parameters, "mmio")
             .map_err(Error::GetSerialCmdline)?;
@@ -340,9 +442,21 @@ impl arch::LinuxArch for AArch64 {
                     .map_err(Error::BiosLoadFailure)?;
             }
             VmImage::Kernel(ref mut kernel_image) => {
+                // arm64 kernels can't decompress themselves, so compressed images are
+                // decompressed into guest memory here.
                 let kernel_size =
-                    arch::load_image(&mem, kernel_image, get_kernel_addr(), u64::max_value())
-                        .map_err(Error::KernelLoadFailure)?;
+                    match decompress::load_compressed_kernel(&mem, kernel_image, get_kernel_addr())
+                        .map_err(Error::DecompressKernel)?
+                    {
+                        Some(kernel_size) => kernel_size,
+                        None => arch::load_image(
+                            &mem,
+                            kernel_image,
+                            get_kernel_addr(),
+                            u64::max_value(),
+                        )
+                        .map_err(Error::KernelLoadFailure)?,
+                    };
                 let kernel_end = get_kernel_addr().offset() + kernel_size as u64;
                 initrd = match components.initrd_image {
                     Some(initrd_file) => {
@@ -374,8 +488,14 @@ impl arch::LinuxArch for AArch64 {
             initrd,
             components.android_fstab,
             irq_chip.get_vgic_version() == DeviceKind::ArmVgicV3,
+            irq_chip.has_its(),
             use_pmu,
             psci_version,
+            bat_mmio_base_and_irq,
+            &platform_dev_nodes,
+            &components.dt_overlays,
+            &components.numa_nodes,
+            &numa_ranges,
         )
         .map_err(Error::CreateFdt)?;
 
@@ -387,14 +507,19 @@ impl arch::LinuxArch for AArch64 {
             vcpus: Some(vcpus),
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            cpu_features: components.cpu_features,
             irq_chip,
             has_bios,
+            pvh_entry: None,
             io_bus,
             mmio_bus,
+            pci_root: pci,
             pid_debug_label_map,
             suspend_evt,
+            sleep_evt,
             rt_cpus: components.rt_cpus,
-            bat_control: None,
+            bat_control,
+            cpu_hotplug: None,
         })
     }
 
@@ -406,20 +531,98 @@ impl arch::LinuxArch for AArch64 {
         _vcpu_id: usize,
         _num_cpus: usize,
         _has_bios: bool,
+        _pvh_entry: Option<GuestAddress>,
         _no_smt: bool,
+        _cpu_features: &CpuFeatures,
     ) -> std::result::Result<(), Self::Error> {
         // AArch64 doesn't configure vcpus on the vcpu thread, so nothing to do here.
         Ok(())
     }
+
+    // Saving the GIC isn't implemented, so there is no point in saving the VCPUs either.
+    fn snapshot_vcpu<T: VcpuAArch64>(_vcpu: &T) -> Result<Vec<u8>> {
+        Err(Error::SnapshotUnsupported)
+    }
+
+    fn restore_vcpu<T: VcpuAArch64>(_vcpu: &T, _data: &[u8]) -> Result<()> {
+        Err(Error::SnapshotUnsupported)
+    }
+
+    fn snapshot_irq_chip(_irq_chip: &dyn IrqChipAArch64, _vcpu_count: usize) -> Result<Vec<u8>> {
+        Err(Error::SnapshotUnsupported)
+    }
+
+    fn restore_irq_chip(_irq_chip: &mut dyn IrqChipAArch64, _data: &[u8]) -> Result<()> {
+        Err(Error::SnapshotUnsupported)
+    }
 }
 
 impl AArch64 {
-    fn setup_memory(mem_size: u64) -> Result<GuestMemory> {
+    fn setup_memory(mem_size: u64, mergeable: bool) -> Result<GuestMemory> {
         let arch_mem_regions = arch_memory_regions(mem_size);
-        let mem = GuestMemory::new(&arch_mem_regions).map_err(Error::SetupGuestMemory)?;
+        let mem = if mergeable {
+            GuestMemory::new_mergeable(&arch_mem_regions, &[])
+        } else {
+            GuestMemory::new(&arch_mem_regions)
+        }
+        .map_err(Error::SetupGuestMemory)?;
         Ok(mem)
     }
 
+    /// Sets up paravirtualized stolen time for `vcpus`, so the guest can tell how long its VCPUs
+    /// were preempted by the host. Their stolen time structures are kept in memory of their own,
+    /// which the guest finds through the PV_TIME_ST hypercall.
+    fn setup_pvtime<V: VmAArch64, Vcpu: VcpuAArch64>(vm: &mut V, vcpus: &[Vcpu]) -> Result<()> {
+        let page_size = pagesize() as u64;
+        let size = (vcpus.len() as u64 * AARCH64_PVTIME_SIZE + page_size - 1) & !(page_size - 1);
+        let mem = MemoryMappingBuilder::new(size as usize)
+            .build()
+            .map_err(Error::CreatePvtimeMemory)?;
+        vm.add_memory_region(
+            GuestAddress(AARCH64_PVTIME_IPA_START),
+            Box::new(mem),
+            false,
+            false,
+        )
+        .map_err(Error::AddPvtimeMemory)?;
+
+        for (vcpu_id, vcpu) in vcpus.iter().enumerate() {
+            vcpu.init_pvtime(AARCH64_PVTIME_IPA_START + vcpu_id as u64 * AARCH64_PVTIME_SIZE)
+                .map_err(Error::InitPvtime)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `vm` a protected VM, with the firmware of the hypervisor loaded into the region
+    /// reserved for it.
+    fn setup_protected_vm<V: VmAArch64>(vm: &mut V) -> Result<()> {
+        if !vm
+            .get_hypervisor()
+            .check_capability(&HypervisorCap::ArmProtectedVm)
+        {
+            return Err(Error::ProtectedVmUnsupported);
+        }
+        let fw_size = vm
+            .get_protected_vm_firmware_size()
+            .map_err(Error::GetProtectedVmInfo)?;
+        if fw_size > AARCH64_PROTECTED_VM_FW_MAX_SIZE {
+            return Err(Error::ProtectedVmFirmwareTooLarge(fw_size));
+        }
+
+        let mem = MemoryMappingBuilder::new(AARCH64_PROTECTED_VM_FW_MAX_SIZE as usize)
+            .build()
+            .map_err(Error::CreateProtectedVmFirmwareMemory)?;
+        vm.add_memory_region(
+            GuestAddress(AARCH64_PROTECTED_VM_FW_START),
+            Box::new(mem),
+            false,
+            false,
+        )
+        .map_err(Error::AddProtectedVmFirmwareMemory)?;
+        vm.enable_protected_vm(GuestAddress(AARCH64_PROTECTED_VM_FW_START))
+            .map_err(Error::EnableProtectedVm)
+    }
+
     fn get_high_mmio_base_size(mem_size: u64) -> (u64, u64) {
         let base = AARCH64_PHYS_MEM_START + mem_size;
         let size = u64::max_value() - base;
@@ -494,12 +697,11 @@ impl AArch64 {
 
         // set up registers
         let mut data: u64;
-        let mut reg_id: u64;
 
         // All interrupts masked
         data = PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT | PSR_MODE_EL1H;
-        reg_id = arm64_core_reg!(pstate);
-        vcpu.set_one_reg(reg_id, data).map_err(Error::SetReg)?;
+        vcpu.set_one_reg(VcpuRegAArch64::Pstate, data)
+            .map_err(Error::SetReg)?;
 
         // Other cpus are powered off initially
         if vcpu_id == 0 {
@@ -508,15 +710,14 @@ impl AArch64 {
             } else {
                 data = AARCH64_PHYS_MEM_START + AARCH64_KERNEL_OFFSET;
             }
-            reg_id = arm64_core_reg!(pc);
-            vcpu.set_one_reg(reg_id, data).map_err(Error::SetReg)?;
+            vcpu.set_one_reg(VcpuRegAArch64::Pc, data)
+                .map_err(Error::SetReg)?;
 
             /* X0 -- fdt address */
             let mem_size = guest_mem.memory_size();
             data = (AARCH64_PHYS_MEM_START + fdt_offset(mem_size, has_bios)) as u64;
-            // hack -- can't get this to do offsetof(regs[0]) but luckily it's at offset 0
-            reg_id = arm64_core_reg!(regs);
-            vcpu.set_one_reg(reg_id, data).map_err(Error::SetReg)?;
+            vcpu.set_one_reg(VcpuRegAArch64::X(0), data)
+                .map_err(Error::SetReg)?;
         }
 
         Ok(())
@@ -22,8 +22,12 @@ extern "C" {
     fn fdt_open_into(fdt: *const c_void, buf: *mut c_void, bufsize: c_int) -> c_int;
     fn fdt_finish(fdt: *const c_void) -> c_int;
     fn fdt_pack(fdt: *mut c_void) -> c_int;
+    fn fdt_overlay_apply(fdt: *mut c_void, fdto: *mut c_void) -> c_int;
 }
 
+// The libfdt error for a blob that is shorter than its header says.
+const FDT_ERR_TRUNCATED: c_int = 8;
+
 #[derive(Debug)]
 pub enum Error {
     FdtCreateError(c_int),
@@ -34,6 +38,7 @@ pub enum Error {
     FdtOpenIntoError(c_int),
     FdtFinishError(c_int),
     FdtPackError(c_int),
+    FdtOverlayApplyError(c_int),
     FdtGuestMemoryWriteError,
     FdtFileParseError,
     FdtIoError(io::Error),
@@ -54,6 +59,7 @@ impl Display for Error {
             FdtOpenIntoError(ret) => write!(f, "error copying FDT to Guest, code={}", ret),
             FdtFinishError(ret) => write!(f, "error performing FDT finish, code={}", ret),
             FdtPackError(ret) => write!(f, "error packing FDT, code={}", ret),
+            FdtOverlayApplyError(ret) => write!(f, "error applying FDT overlay, code={}", ret),
             FdtGuestMemoryWriteError => write!(f, "error writing FDT to guest memory"),
             FdtFileParseError => write!(f, "parse error reading FDT parameters"),
             FdtIoError(ret) => write!(f, "I/O error reading FDT parameters code={}", ret),
@@ -239,3 +245,53 @@ pub fn finish_fdt(fdt: &mut Vec<u8>, fdt_final: &mut Vec<u8>, fdt_max_size: usiz
     }
     Ok(())
 }
+
+/// Applies device tree overlays, in order, to a finished FDT of at most `fdt_max_size` bytes.
+/// Overlays can only refer to labels of nodes in the FDT that are listed in its `__symbols__`.
+pub fn apply_overlays(fdt: &mut Vec<u8>, overlays: &[Vec<u8>], fdt_max_size: usize) -> Result<()> {
+    if overlays.is_empty() {
+        return Ok(());
+    }
+
+    // Safe because we allocated fdt with fdt_max_size, which libfdt can move it within.
+    let mut fdt_ret = unsafe {
+        fdt_open_into(
+            fdt.as_ptr() as *const c_void,
+            fdt.as_mut_ptr() as *mut c_void,
+            fdt_max_size as c_int,
+        )
+    };
+    if fdt_ret != 0 {
+        return Err(Error::FdtOpenIntoError(fdt_ret));
+    }
+
+    for overlay in overlays {
+        // libfdt trusts the size in the header, so check the overlay holds that much.
+        let overlay_size = overlay
+            .get(4..8)
+            .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize);
+        if overlay_size.map_or(true, |size| size > overlay.len()) {
+            return Err(Error::FdtOverlayApplyError(-FDT_ERR_TRUNCATED));
+        }
+        // libfdt damages the overlay while applying it, so work on a copy.
+        let mut overlay = overlay.clone();
+        // Safe because libfdt checks the overlay header, doesn't write past the sizes in the
+        // headers of either blob, and fdt is the fdt_max_size it was opened into.
+        fdt_ret = unsafe {
+            fdt_overlay_apply(
+                fdt.as_mut_ptr() as *mut c_void,
+                overlay.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if fdt_ret != 0 {
+            return Err(Error::FdtOverlayApplyError(fdt_ret));
+        }
+    }
+
+    // Safe since we allocated fdt
+    fdt_ret = unsafe { fdt_pack(fdt.as_mut_ptr() as *mut c_void) };
+    if fdt_ret != 0 {
+        return Err(Error::FdtPackError(fdt_ret));
+    }
+    Ok(())
+}
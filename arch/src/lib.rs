@@ -4,8 +4,10 @@
 
 pub mod android;
 pub mod fdt;
+pub mod numa;
 pub mod pstore;
 pub mod serial;
+mod serial_socket;
 
 use std::collections::BTreeMap;
 use std::error::Error as StdError;
@@ -17,13 +19,14 @@ use std::sync::Arc;
 
 use acpi_tables::aml::Aml;
 use acpi_tables::sdt::SDT;
-use base::{syslog, AsRawDescriptor, Event};
+use base::{syslog, AsRawDescriptor, Event, MmapError, RawDescriptor};
+use devices::vfio::VfioError;
 use devices::virtio::VirtioDevice;
 use devices::{
-    Bus, BusDevice, BusError, IrqChip, PciAddress, PciDevice, PciDeviceError, PciInterruptPin,
-    PciRoot, ProxyDevice,
+    Bus, BusDevice, BusError, CpuHotplugController, IrqChip, IrqEventIndex, PciAddress, PciDevice,
+    PciDeviceError, PciInterruptPin, PciRoot, PcieHotplugSlot, ProxyDevice, VfioPlatformDevice,
 };
-use hypervisor::{IoEventAddress, Vm};
+use hypervisor::{Datamatch, IoEventAddress, Vm};
 use minijail::Minijail;
 use resources::{MmioType, SystemAllocator};
 use sync::Mutex;
@@ -35,7 +38,7 @@ use vm_control::{
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-use gdbstub::arch::x86::reg::X86_64CoreRegs as GdbStubRegs;
+use {gdbstub::arch::x86::reg::X86_64CoreRegs as GdbStubRegs, hypervisor::Watchpoint};
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use {
@@ -48,6 +51,7 @@ use {
     hypervisor::{HypervisorX86_64 as HypervisorArch, VcpuX86_64 as VcpuArch, VmX86_64 as VmArch},
 };
 
+pub use numa::NumaNode;
 pub use serial::{
     add_serial_devices, get_serial_cmdline, set_default_serial_parameters, GetSerialCmdlineError,
     SerialHardware, SerialParameters, SerialType, SERIAL_ADDR,
@@ -64,6 +68,16 @@ pub struct Pstore {
     pub size: u32,
 }
 
+/// Where the gdb stub waits for a debugger to connect.
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum GdbAddress {
+    /// A TCP port on all of the host's interfaces.
+    Tcp(u16),
+    /// A unix domain socket created at the given path.
+    Unix(PathBuf),
+}
+
 /// Mapping of guest VCPU threads to host CPU cores.
 #[derive(Clone, Debug, PartialEq)]
 pub enum VcpuAffinity {
@@ -76,13 +90,27 @@ pub enum VcpuAffinity {
     PerVcpu(BTreeMap<usize, Vec<usize>>),
 }
 
+/// The CPU model and features advertised to the guest, when they shouldn't simply follow the host.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuFeatures {
+    /// Named CPU model whose features are the baseline, instead of all the host's features.
+    pub model: Option<String>,
+    /// Features added (`true`) to or removed (`false`) from the baseline, applied in order.
+    pub features: Vec<(String, bool)>,
+    /// Whether the guest can run its own VMs, with the host CPU's virtualization extensions.
+    pub nested_virt: bool,
+}
+
 /// Holds the pieces needed to build a VM. Passed to `build_vm` in the `LinuxArch` trait below to
 /// create a `RunnableLinuxVm`.
 pub struct VmComponents {
     pub memory_size: u64,
     pub vcpu_count: usize,
+    /// Number of VCPUs the guest can have once VCPUs are hot-added, at least `vcpu_count`.
+    pub max_vcpu_count: usize,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub cpu_features: CpuFeatures,
     pub vm_image: VmImage,
     pub android_fstab: Option<File>,
     pub pstore: Option<Pstore>,
@@ -92,8 +120,37 @@ pub struct VmComponents {
     pub acpi_sdts: Vec<SDT>,
     pub rt_cpus: Vec<usize>,
     pub protected_vm: bool,
+    /// Kernel image handed to the BIOS, along with the initrd and kernel parameters, through the
+    /// fw_cfg interface.
+    pub fw_cfg_kernel: Option<File>,
+    /// Named files handed to the BIOS through the fw_cfg interface.
+    pub fw_cfg_files: Vec<(String, Vec<u8>)>,
+    /// Firmware device paths the BIOS should try to boot from, in order.
+    pub boot_order: Vec<String>,
+    /// Writable flash placed right below the BIOS, such as the variable store of UEFI firmware.
+    pub pflash: Option<File>,
+    /// Whether the guest may only read the memory the BIOS is loaded into. Only x86_64 maps the
+    /// BIOS into a region of its own, which this needs.
+    pub read_only_bios: bool,
+    /// Whether guest memory is private memory that the host's KSM may merge, rather than memory
+    /// shared with device processes.
+    pub mergeable_memory: bool,
+    /// Device tree overlays applied, in order, to the device tree generated for the guest.
+    pub dt_overlays: Vec<Vec<u8>>,
+    /// NUMA nodes of the guest. Empty for a guest without NUMA, otherwise their memory adds up to
+    /// `memory_size`.
+    pub numa_nodes: Vec<NumaNode>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    pub gdb: Option<(u32, VmControlRequestSocket)>, // port and control socket.
+    pub gdb: Option<(GdbAddress, VmControlRequestSocket)>, // address and control socket.
+}
+
+/// Devices made by the `create_devices` function passed to `build_vm`, each with the jail to run
+/// it in.
+#[derive(Default)]
+pub struct VmDevices {
+    pub pci: Vec<(Box<dyn PciDevice>, Option<Minijail>)>,
+    /// Only placed on architectures that describe devices to the guest with a device tree.
+    pub platform: Vec<(VfioPlatformDevice, Option<Minijail>)>,
 }
 
 /// Holds the elements needed to run a Linux VM. Created by `build_vm`.
@@ -107,16 +164,24 @@ pub struct RunnableLinuxVm<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch> {
     pub vcpus: Option<Vec<Vcpu>>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub cpu_features: CpuFeatures,
     pub irq_chip: I,
     pub has_bios: bool,
+    /// The entry point of a kernel booted with the x86 PVH boot protocol, if it is.
+    pub pvh_entry: Option<GuestAddress>,
     pub io_bus: Bus,
     pub mmio_bus: Bus,
+    pub pci_root: Arc<Mutex<PciRoot>>,
     pub pid_debug_label_map: BTreeMap<u32, String>,
     pub suspend_evt: Event,
+    /// Signaled when the guest puts itself to sleep in S3.
+    pub sleep_evt: Event,
     pub rt_cpus: Vec<usize>,
     pub bat_control: Option<BatControl>,
+    /// Present if VCPUs can be hot-added, in which case `vcpu_count` counts every possible VCPU.
+    pub cpu_hotplug: Option<Arc<Mutex<CpuHotplugController>>>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    pub gdb: Option<(u32, VmControlRequestSocket)>,
+    pub gdb: Option<(GdbAddress, VmControlRequestSocket)>,
 }
 
 /// The device and optional jail.
@@ -158,7 +223,7 @@ pub trait LinuxArch {
             &mut V,
             &mut SystemAllocator,
             &Event,
-        ) -> std::result::Result<Vec<(Box<dyn PciDevice>, Option<Minijail>)>, E1>,
+        ) -> std::result::Result<VmDevices, E1>,
         FV: FnOnce(GuestMemory) -> std::result::Result<V, E2>,
         FI: FnOnce(&V, /* vcpu_count: */ usize) -> std::result::Result<I, E3>,
         E1: StdError + 'static,
@@ -176,6 +241,8 @@ pub trait LinuxArch {
     /// * `vcpu_id` - The id of the given `vcpu`.
     /// * `num_cpus` - Number of virtual CPUs the guest will have.
     /// * `has_bios` - Whether the `VmImage` is a `Bios` image
+    /// * `pvh_entry` - The entry point of a kernel booted with the PVH boot protocol, if it is.
+    /// * `cpu_features` - The CPU model and features to advertise to the guest.
     fn configure_vcpu(
         guest_mem: &GuestMemory,
         hypervisor: &dyn HypervisorArch,
@@ -184,9 +251,27 @@ pub trait LinuxArch {
         vcpu_id: usize,
         num_cpus: usize,
         has_bios: bool,
+        pvh_entry: Option<GuestAddress>,
         no_smt: bool,
+        cpu_features: &CpuFeatures,
     ) -> Result<(), Self::Error>;
 
+    /// Saves the registers and other state of the paused `vcpu` for a VM snapshot.
+    fn snapshot_vcpu<T: VcpuArch>(vcpu: &T) -> Result<Vec<u8>, Self::Error>;
+
+    /// Loads the state saved by `snapshot_vcpu` into the paused `vcpu`.
+    fn restore_vcpu<T: VcpuArch>(vcpu: &T, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Saves the state of `irq_chip` and of the interrupt controllers of its `vcpu_count` VCPUs
+    /// for a VM snapshot, with the VCPUs paused.
+    fn snapshot_irq_chip(
+        irq_chip: &dyn IrqChipArch,
+        vcpu_count: usize,
+    ) -> Result<Vec<u8>, Self::Error>;
+
+    /// Loads the state saved by `snapshot_irq_chip` into `irq_chip`, with the VCPUs paused.
+    fn restore_irq_chip(irq_chip: &mut dyn IrqChipArch, data: &[u8]) -> Result<(), Self::Error>;
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     /// Reads vCPU's registers.
     fn debug_read_registers<T: VcpuArch>(vcpu: &T) -> Result<GdbStubRegs, Self::Error>;
@@ -195,6 +280,18 @@ pub trait LinuxArch {
     /// Writes vCPU's registers.
     fn debug_write_registers<T: VcpuArch>(vcpu: &T, regs: &GdbStubRegs) -> Result<(), Self::Error>;
 
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    /// Reads the vCPU register with gdb register number `reg`, in target byte order.
+    fn debug_read_register<T: VcpuArch>(vcpu: &T, reg: usize) -> Result<Vec<u8>, Self::Error>;
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    /// Writes `data`, in target byte order, to the vCPU register with gdb register number `reg`.
+    fn debug_write_register<T: VcpuArch>(
+        vcpu: &T,
+        reg: usize,
+        data: &[u8],
+    ) -> Result<(), Self::Error>;
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     /// Reads bytes from the guest memory.
     fn debug_read_memory<T: VcpuArch>(
@@ -218,16 +315,19 @@ pub trait LinuxArch {
     fn debug_enable_singlestep<T: VcpuArch>(vcpu: &T) -> Result<(), Self::Error>;
 
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    /// Set hardware breakpoints at the given addresses.
+    /// Set hardware breakpoints at the given addresses and the given hardware watchpoints.
     fn debug_set_hw_breakpoints<T: VcpuArch>(
         vcpu: &T,
         breakpoints: &[GuestAddress],
+        watchpoints: &[Watchpoint],
     ) -> Result<(), Self::Error>;
 }
 
 /// Errors for device manager.
 #[derive(Debug)]
 pub enum DeviceRegistrationError {
+    /// Could not add a mapping of device memory to the VM.
+    AddMemoryRegion(base::Error),
     /// Could not allocate IO space for the device.
     AllocateIoAddrs(PciDeviceError),
     /// Could not allocate MMIO or IO resource for the device.
@@ -248,8 +348,12 @@ pub enum DeviceRegistrationError {
     EventCreate(base::Error),
     /// Missing a required serial device.
     MissingRequiredSerialDevice(u8),
+    /// Could not map a region of a vfio platform device.
+    MapVfioRegion(MmapError),
     /// Could not add a device to the mmio bus.
     MmioInsert(BusError),
+    /// Could not remove a device from the mmio bus.
+    MmioRemove(BusError),
     /// Failed to register ioevent with VM.
     RegisterIoevent(base::Error),
     /// Failed to register irq event with VM.
@@ -266,6 +370,14 @@ pub enum DeviceRegistrationError {
     RegisterDeviceCapabilities(PciDeviceError),
     // Failed to register battery device.
     RegisterBattery(devices::BatteryError),
+    /// The root port slot to plug a device into already has one.
+    SlotOccupied,
+    /// Failed to unregister ioevent with VM.
+    UnregisterIoevent(base::Error),
+    /// Failed to unregister irq event with VM.
+    UnregisterIrqfd(base::Error),
+    /// Failed to set up the interrupts of a vfio platform device.
+    VfioPlatformIrq(VfioError),
 }
 
 impl Display for DeviceRegistrationError {
@@ -273,6 +385,7 @@ impl Display for DeviceRegistrationError {
         use self::DeviceRegistrationError::*;
 
         match self {
+            AddMemoryRegion(e) => write!(f, "failed to add device memory to VM: {}", e),
             AllocateIoAddrs(e) => write!(f, "Allocating IO addresses: {}", e),
             AllocateIoResource(e) => write!(f, "Allocating IO resource: {}", e),
             AllocateDeviceAddrs(e) => write!(f, "Allocating device addresses: {}", e),
@@ -284,7 +397,9 @@ impl Display for DeviceRegistrationError {
             EventClone(e) => write!(f, "failed to clone event: {}", e),
             EventCreate(e) => write!(f, "failed to create event: {}", e),
             MissingRequiredSerialDevice(n) => write!(f, "missing required serial device {}", n),
+            MapVfioRegion(e) => write!(f, "failed to map vfio platform device region: {}", e),
             MmioInsert(e) => write!(f, "failed to add to mmio bus: {}", e),
+            MmioRemove(e) => write!(f, "failed to remove from mmio bus: {}", e),
             RegisterIoevent(e) => write!(f, "failed to register ioevent to VM: {}", e),
             RegisterIrqfd(e) => write!(f, "failed to register irq event to VM: {}", e),
             ProxyDeviceCreation(e) => write!(f, "failed to create proxy device: {}", e),
@@ -294,6 +409,12 @@ impl Display for DeviceRegistrationError {
                 write!(f, "could not register PCI device capabilities: {}", e)
             }
             RegisterBattery(e) => write!(f, "failed to register battery device to VM: {}", e),
+            SlotOccupied => write!(f, "the pcie slot is already occupied"),
+            UnregisterIoevent(e) => write!(f, "failed to unregister ioevent from VM: {}", e),
+            UnregisterIrqfd(e) => write!(f, "failed to unregister irq event from VM: {}", e),
+            VfioPlatformIrq(e) => {
+                write!(f, "failed to set up vfio platform device interrupts: {}", e)
+            }
         }
     }
 }
@@ -308,7 +429,7 @@ pub fn generate_pci_root(
     max_irqs: usize,
 ) -> Result<
     (
-        PciRoot,
+        Arc<Mutex<PciRoot>>,
         Vec<(PciAddress, u32, PciInterruptPin)>,
         BTreeMap<u32, String>,
     ),
@@ -413,11 +534,332 @@ pub fn generate_pci_root(
                 .map_err(DeviceRegistrationError::MmioInsert)?;
         }
     }
-    Ok((root, pci_irqs, pid_labels))
+    Ok((Arc::new(Mutex::new(root)), pci_irqs, pid_labels))
+}
+
+/// A vfio platform device placed by `generate_platform_bus`, as the device tree describes it.
+pub struct PlatformDeviceNode {
+    pub compatible: Vec<String>,
+    /// Guest address and size of each MMIO region.
+    pub regions: Vec<(u64, u64)>,
+    /// Guest interrupt number of each interrupt, and whether it's level triggered.
+    pub irqs: Vec<(u32, bool)>,
+}
+
+/// Places vfio platform devices in the MMIO space of the VM and connects their interrupts.
+/// Returns the device tree nodes of the devices that asked for one, and the debug labels of the
+/// jailed devices by pid.
+pub fn generate_platform_bus(
+    devices: Vec<(VfioPlatformDevice, Option<Minijail>)>,
+    irq_chip: &mut impl IrqChip,
+    mmio_bus: &mut Bus,
+    resources: &mut SystemAllocator,
+    vm: &mut impl Vm,
+) -> Result<(Vec<PlatformDeviceNode>, BTreeMap<u32, String>), DeviceRegistrationError> {
+    let mut nodes = Vec::new();
+    let mut pid_labels = BTreeMap::new();
+
+    for (mut device, jail) in devices.into_iter() {
+        let ranges = device
+            .allocate_regions(resources)
+            .map_err(DeviceRegistrationError::AllocateIoResource)?;
+        let mappings = device
+            .mmap_regions()
+            .map_err(DeviceRegistrationError::MapVfioRegion)?;
+        for (addr, mapping) in mappings {
+            vm.add_memory_region(addr, Box::new(mapping), false, false)
+                .map_err(DeviceRegistrationError::AddMemoryRegion)?;
+        }
+
+        let mut irqs = Vec::new();
+        let platform_irqs = device
+            .get_platform_irqs()
+            .map_err(DeviceRegistrationError::VfioPlatformIrq)?;
+        for irq in platform_irqs {
+            let irq_num = resources
+                .allocate_irq()
+                .ok_or(DeviceRegistrationError::AllocateIrq)?;
+            let irq_evt = Event::new().map_err(DeviceRegistrationError::EventCreate)?;
+            // Level triggered interrupts stay masked in the host until the guest's EOI.
+            let level = device.irq_is_automask(&irq);
+            let resample_evt = if level {
+                Some(Event::new().map_err(DeviceRegistrationError::EventCreate)?)
+            } else {
+                None
+            };
+            irq_chip
+                .register_irq_event(irq_num, &irq_evt, resample_evt.as_ref())
+                .map_err(DeviceRegistrationError::RegisterIrqfd)?;
+            device
+                .assign_platform_irq(irq_evt, resample_evt, irq.index)
+                .map_err(DeviceRegistrationError::VfioPlatformIrq)?;
+            irqs.push((irq_num, level));
+        }
+
+        if let Some(compatible) = device.dt_compatible() {
+            nodes.push(PlatformDeviceNode {
+                compatible: compatible.clone(),
+                regions: ranges.clone(),
+                irqs,
+            });
+        }
+
+        let mut keep_rds = device.keep_rds();
+        syslog::push_descriptors(&mut keep_rds);
+        let arced_dev: Arc<Mutex<dyn BusDevice>> = if let Some(jail) = jail {
+            let proxy = ProxyDevice::new(device, &jail, keep_rds)
+                .map_err(DeviceRegistrationError::ProxyDeviceCreation)?;
+            pid_labels.insert(proxy.pid() as u32, proxy.debug_label());
+            Arc::new(Mutex::new(proxy))
+        } else {
+            device.on_sandboxed();
+            Arc::new(Mutex::new(device))
+        };
+        for (start, size) in ranges {
+            mmio_bus
+                .insert(arced_dev.clone(), start, size)
+                .map_err(DeviceRegistrationError::MmioInsert)?;
+        }
+    }
+    Ok((nodes, pid_labels))
+}
+
+/// A PCI device added to a running VM by `add_hotplug_pci_device`, along with the resources that
+/// `remove_hotplug_pci_device` gives back when it is unplugged.
+pub struct HotplugPciDevice {
+    pub address: PciAddress,
+    /// The root port slot the device is plugged into, if it isn't on the root bus.
+    pub slot: Option<PcieHotplugSlot>,
+    /// Set if the irq chip needs the control loop to service the device's irq event.
+    pub irq_event_index: Option<IrqEventIndex>,
+    /// The process the device runs in, if it was jailed. It exits once the device is dropped.
+    pub pid: Option<u32>,
+    device: Arc<Mutex<dyn BusDevice>>,
+    irq_num: u32,
+    irqfd: Event,
+    ranges: Vec<(u64, u64)>,
+    ioevents: Vec<(Event, u64, Datamatch)>,
+}
+
+impl HotplugPciDevice {
+    /// Returns true if the guest has left bus mastering enabled, meaning its driver may still be
+    /// using the device.
+    pub fn bus_master_enabled(&self) -> bool {
+        const PCI_COMMAND_REG: usize = 1;
+        const PCI_COMMAND_MASTER: u32 = 0x4;
+        self.device.lock().config_register_read(PCI_COMMAND_REG) & PCI_COMMAND_MASTER != 0
+    }
+}
+
+/// Adds `device` to a running VM. With a `slot`, the device is plugged into the slot of a PCI
+/// Express root port and the guest is notified. Otherwise it is added to the root PCI bus, and the
+/// guest has to rescan the bus to find it.
+///
+/// Like `generate_pci_root`, the device is moved to a jailed process of its own if `jail` is given.
+///
+/// # Arguments
+///
+/// * `device` - the device to add
+/// * `jail` - the jail to run the device in, if sandboxing is enabled
+/// * `pci_root` - the root PCI bus of the VM
+/// * `slot` - the empty root port slot to plug the device into, if any
+/// * `irq_chip` - the IrqChip object for registering irq events
+/// * `mmio_bus` - bus to add the device's BARs to
+/// * `resources` - the SystemAllocator to allocate the address, BARs and irq from
+/// * `vm` - the VM for registering ioevents
+pub fn add_hotplug_pci_device(
+    mut device: Box<dyn PciDevice>,
+    jail: Option<Minijail>,
+    pci_root: &Mutex<PciRoot>,
+    slot: Option<&PcieHotplugSlot>,
+    irq_chip: &mut impl IrqChip,
+    mmio_bus: &mut Bus,
+    resources: &mut SystemAllocator,
+    vm: &mut impl Vm,
+) -> Result<HotplugPciDevice, DeviceRegistrationError> {
+    if slot.map_or(false, |s| !s.is_empty()) {
+        return Err(DeviceRegistrationError::SlotOccupied);
+    }
+    let address = device
+        .allocate_address(resources)
+        .map_err(DeviceRegistrationError::AllocateDeviceAddrs)?;
+
+    let mut keep_rds = device.keep_rds();
+    syslog::push_descriptors(&mut keep_rds);
+
+    let mut ranges = Vec::new();
+    let mut irq_num = None;
+    let mut ioevents = Vec::new();
+    let res = register_hotplug_pci_device(
+        device.as_mut(),
+        irq_chip,
+        resources,
+        vm,
+        &mut ranges,
+        &mut irq_num,
+        &mut ioevents,
+        &mut keep_rds,
+    );
+    let res = res.and_then(|(irq_num, irqfd, irq_event_index)| {
+        let (device, pid): (Arc<Mutex<dyn BusDevice>>, _) = match &jail {
+            Some(jail) => {
+                let proxy = ProxyDevice::new(device, jail, keep_rds)
+                    .map_err(DeviceRegistrationError::ProxyDeviceCreation)?;
+                let pid = proxy.pid() as u32;
+                (Arc::new(Mutex::new(proxy)), Some(pid))
+            }
+            None => {
+                device.on_sandboxed();
+                (Arc::new(Mutex::new(device)), None)
+            }
+        };
+        Ok((irq_num, irqfd, irq_event_index, device, pid))
+    });
+    let (irq_num, irqfd, irq_event_index, device, pid) = match res {
+        Ok(r) => r,
+        Err(e) => {
+            for (event, addr, datamatch) in ioevents {
+                let _ = vm.unregister_ioevent(&event, IoEventAddress::Mmio(addr), datamatch);
+            }
+            for (base, _len) in &ranges {
+                let _ = resources.mmio_allocator_any().release_containing(*base);
+            }
+            if let Some(irq) = irq_num {
+                resources.release_irq(irq);
+            }
+            resources.release_pci(address.bus, address.dev, address.func);
+            return Err(e);
+        }
+    };
+
+    for (i, range) in ranges.iter().enumerate() {
+        if let Err(e) = mmio_bus.insert(device.clone(), range.0, range.1) {
+            for range in &ranges[..i] {
+                let _ = mmio_bus.remove(range.0, range.1);
+            }
+            return Err(DeviceRegistrationError::MmioInsert(e));
+        }
+    }
+    match slot {
+        Some(slot) => {
+            slot.plug(device.clone());
+        }
+        None => pci_root.lock().add_device(address, device.clone()),
+    }
+
+    Ok(HotplugPciDevice {
+        address,
+        slot: slot.cloned(),
+        irq_event_index,
+        pid,
+        device,
+        irq_num,
+        irqfd,
+        ranges,
+        ioevents,
+    })
+}
+
+// Allocates the BARs and irq of a hot-added device and registers its events, recording what was
+// allocated in `ranges`, `irq_num` and `ioevents` so the caller can release them if a later step
+// fails. The events the device needs if it is jailed are added to `keep_rds`.
+#[allow(clippy::too_many_arguments)]
+fn register_hotplug_pci_device(
+    device: &mut dyn PciDevice,
+    irq_chip: &mut impl IrqChip,
+    resources: &mut SystemAllocator,
+    vm: &mut impl Vm,
+    ranges: &mut Vec<(u64, u64)>,
+    irq_num: &mut Option<u32>,
+    ioevents: &mut Vec<(Event, u64, Datamatch)>,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> Result<(u32, Event, Option<IrqEventIndex>), DeviceRegistrationError> {
+    ranges.extend(
+        device
+            .allocate_io_bars(resources)
+            .map_err(DeviceRegistrationError::AllocateIoAddrs)?,
+    );
+    ranges.extend(
+        device
+            .allocate_device_bars(resources)
+            .map_err(DeviceRegistrationError::AllocateDeviceAddrs)?,
+    );
+
+    let irq = resources
+        .allocate_irq()
+        .ok_or(DeviceRegistrationError::AllocateIrq)?;
+    *irq_num = Some(irq);
+    let irqfd = Event::new().map_err(DeviceRegistrationError::EventCreate)?;
+    let irq_resample_fd = Event::new().map_err(DeviceRegistrationError::EventCreate)?;
+    let irq_event_index = irq_chip
+        .register_irq_event(irq, &irqfd, Some(&irq_resample_fd))
+        .map_err(DeviceRegistrationError::RegisterIrqfd)?;
+    let registered_irqfd = irqfd
+        .try_clone()
+        .map_err(DeviceRegistrationError::EventClone)?;
+    keep_rds.push(irqfd.as_raw_descriptor());
+    keep_rds.push(irq_resample_fd.as_raw_descriptor());
+    device.assign_irq(irqfd, irq_resample_fd, irq, PciInterruptPin::IntA);
+
+    device
+        .register_device_capabilities()
+        .map_err(DeviceRegistrationError::RegisterDeviceCapabilities)?;
+    for (event, addr, datamatch) in device.ioevents() {
+        // Kept to unregister the event once the device, which may be in another process by then,
+        // is unplugged.
+        let event = event
+            .try_clone()
+            .map_err(DeviceRegistrationError::EventClone)?;
+        vm.register_ioevent(&event, IoEventAddress::Mmio(addr), datamatch)
+            .map_err(DeviceRegistrationError::RegisterIoevent)?;
+        keep_rds.push(event.as_raw_descriptor());
+        ioevents.push((event, addr, datamatch));
+    }
+
+    Ok((irq, registered_irqfd, irq_event_index))
+}
+
+/// Removes a device added by `add_hotplug_pci_device` from the VM and releases its resources. The
+/// guest should have released the device first, see `HotplugPciDevice::bus_master_enabled` and
+/// `PcieHotplugSlot::request_unplug`.
+pub fn remove_hotplug_pci_device(
+    device: HotplugPciDevice,
+    pci_root: &Mutex<PciRoot>,
+    irq_chip: &mut impl IrqChip,
+    mmio_bus: &mut Bus,
+    resources: &mut SystemAllocator,
+    vm: &mut impl Vm,
+) -> Result<(), DeviceRegistrationError> {
+    let address = device.address;
+    match &device.slot {
+        Some(slot) => {
+            slot.unplug();
+        }
+        None => {
+            pci_root.lock().remove_device(address);
+        }
+    }
+    for (base, len) in &device.ranges {
+        mmio_bus
+            .remove(*base, *len)
+            .map_err(DeviceRegistrationError::MmioRemove)?;
+        let _ = resources.mmio_allocator_any().release_containing(*base);
+    }
+    for (event, addr, datamatch) in device.ioevents {
+        vm.unregister_ioevent(&event, IoEventAddress::Mmio(addr), datamatch)
+            .map_err(DeviceRegistrationError::UnregisterIoevent)?;
+    }
+    irq_chip
+        .unregister_irq_event(device.irq_num, &device.irqfd)
+        .map_err(DeviceRegistrationError::UnregisterIrqfd)?;
+    resources.release_irq(device.irq_num);
+    resources.release_pci(address.bus, address.dev, address.func);
+    Ok(())
 }
 
 /// Adds goldfish battery
 /// return the platform needed resouces include its AML data, irq number
+/// and the base of its MMIO region
 ///
 /// # Arguments
 ///
@@ -434,7 +876,7 @@ pub fn add_goldfish_battery(
     irq_chip: &mut impl IrqChip,
     irq_num: u32,
     resources: &mut SystemAllocator,
-) -> Result<BatControlRequestSocket, DeviceRegistrationError> {
+) -> Result<(BatControlRequestSocket, u64), DeviceRegistrationError> {
     let alloc = resources.get_anon_alloc();
     let mmio_base = resources
         .mmio_allocator(MmioType::Low)
@@ -501,7 +943,7 @@ pub fn add_goldfish_battery(
         }
     }
 
-    Ok(control_socket)
+    Ok((control_socket, mmio_base))
 }
 
 /// Errors for image loading.
@@ -0,0 +1,123 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guest NUMA topology, shared by the architectures that describe it to the guest.
+
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+/// Distance from a node to itself, in the units of the ACPI SLIT.
+pub const NUMA_LOCAL_DISTANCE: u8 = 10;
+/// Distance between two nodes when none is given.
+pub const NUMA_REMOTE_DISTANCE: u8 = 20;
+
+/// A NUMA node of the guest: some VCPUs and the part of guest memory closest to them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NumaNode {
+    /// Size in bytes of the node's part of guest memory. Nodes get guest memory in order, starting
+    /// from the lowest address.
+    pub memory_size: u64,
+    /// Indices of the guest VCPUs in the node.
+    pub cpus: Vec<usize>,
+    /// Host NUMA node the node's memory is allocated from, if it is bound to one.
+    pub host_node: Option<u32>,
+    /// Distance to every node, this one included. Empty for the default distances.
+    pub distances: Vec<u8>,
+}
+
+/// Returns the distance from node `from` to node `to`.
+pub fn numa_distance(nodes: &[NumaNode], from: usize, to: usize) -> u8 {
+    match nodes[from].distances.get(to) {
+        Some(&distance) => distance,
+        None if from == to => NUMA_LOCAL_DISTANCE,
+        None => NUMA_REMOTE_DISTANCE,
+    }
+}
+
+/// Splits the guest RAM in `ram_regions`, sorted by address, among `nodes`. Returns the ranges of
+/// guest memory of each node, which can be more than one when a node's memory spans a hole.
+pub fn numa_node_ranges(
+    ram_regions: &[(GuestAddress, u64)],
+    nodes: &[NumaNode],
+) -> Vec<Vec<(GuestAddress, u64)>> {
+    let mut regions = ram_regions.iter().copied();
+    let mut region = regions.next();
+    let mut node_ranges = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let mut ranges = Vec::new();
+        let mut remaining = node.memory_size;
+        while remaining > 0 {
+            let (start, size) = match region {
+                Some(r) => r,
+                None => break,
+            };
+            let len = remaining.min(size);
+            ranges.push((start, len));
+            remaining -= len;
+            region = if len == size {
+                regions.next()
+            } else {
+                Some((start.unchecked_add(len), size - len))
+            };
+        }
+        node_ranges.push(ranges);
+    }
+    node_ranges
+}
+
+/// Binds the memory of the nodes that have a host node to it, with `node_ranges` as returned by
+/// `numa_node_ranges`. Should be done before the memory is first touched.
+pub fn bind_numa_memory(
+    mem: &GuestMemory,
+    nodes: &[NumaNode],
+    node_ranges: &[Vec<(GuestAddress, u64)>],
+) -> Result<(), GuestMemoryError> {
+    for (node, ranges) in nodes.iter().zip(node_ranges) {
+        if let Some(host_node) = node.host_node {
+            for &(start, size) in ranges {
+                mem.bind_to_numa_node(start, size, host_node)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(memory_size: u64) -> NumaNode {
+        NumaNode {
+            memory_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ranges_across_hole() {
+        let ram = [(GuestAddress(0), 0x3000), (GuestAddress(0x10000), 0x2000)];
+        let ranges = numa_node_ranges(&ram, &[node(0x1000), node(0x3000), node(0x1000)]);
+        assert_eq!(
+            ranges,
+            vec![
+                vec![(GuestAddress(0), 0x1000)],
+                vec![
+                    (GuestAddress(0x1000), 0x2000),
+                    (GuestAddress(0x10000), 0x1000)
+                ],
+                vec![(GuestAddress(0x11000), 0x1000)],
+            ]
+        );
+    }
+
+    #[test]
+    fn distances() {
+        let mut nodes = vec![node(0x1000), node(0x1000)];
+        assert_eq!(numa_distance(&nodes, 0, 0), NUMA_LOCAL_DISTANCE);
+        assert_eq!(numa_distance(&nodes, 0, 1), NUMA_REMOTE_DISTANCE);
+        nodes[1].distances = vec![32, 10];
+        assert_eq!(numa_distance(&nodes, 1, 0), 32);
+        assert_eq!(numa_distance(&nodes, 1, 1), 10);
+        assert_eq!(numa_distance(&nodes, 0, 1), NUMA_REMOTE_DISTANCE);
+    }
+}
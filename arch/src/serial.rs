@@ -19,6 +19,7 @@ use devices::{Bus, ProxyDevice, Serial, SerialDevice};
 use minijail::Minijail;
 use sync::Mutex;
 
+use crate::serial_socket;
 use crate::DeviceRegistrationError;
 
 #[derive(Debug)]
@@ -58,6 +59,7 @@ pub enum SerialType {
     Sink,
     Syslog,
     UnixSocket,
+    UnixListen,
 }
 
 impl Display for SerialType {
@@ -68,6 +70,7 @@ impl Display for SerialType {
             SerialType::Sink => "Sink".to_string(),
             SerialType::Syslog => "Syslog".to_string(),
             SerialType::UnixSocket => "UnixSocket".to_string(),
+            SerialType::UnixListen => "UnixListen".to_string(),
         };
 
         write!(f, "{}", s)
@@ -83,6 +86,7 @@ impl FromStr for SerialType {
             "sink" | "Sink" => Ok(SerialType::Sink),
             "syslog" | "Syslog" => Ok(SerialType::Syslog),
             "unix" | "UnixSocket" => Ok(SerialType::UnixSocket),
+            "unix-listen" | "UnixListen" => Ok(SerialType::UnixListen),
             _ => Err(Error::InvalidSerialType(s.to_string())),
         }
     }
@@ -217,6 +221,17 @@ impl SerialParameters {
     ) -> std::result::Result<T, Error> {
         let evt = evt.try_clone().map_err(Error::CloneEvent)?;
         keep_rds.push(evt.as_raw_descriptor());
+        // A listening socket carries both directions, but an explicit input takes precedence.
+        let (listen_input, listen_output) = match self.type_ {
+            SerialType::UnixListen => {
+                let path = self.path.as_ref().ok_or(Error::PathRequired)?;
+                let (input, output, descriptor) =
+                    serial_socket::bind(path).map_err(Error::FileError)?;
+                keep_rds.push(descriptor);
+                (Some(input), Some(output))
+            }
+            _ => (None, None),
+        };
         let input: Option<Box<dyn io::Read + Send>> = if let Some(input_path) = &self.input {
             let input_file = File::open(input_path.as_path()).map_err(Error::FileError)?;
             keep_rds.push(input_file.as_raw_descriptor());
@@ -232,6 +247,8 @@ impl SerialParameters {
                 }
             }
             Some(Box::new(StdinWrapper))
+        } else if let Some(listen_input) = listen_input {
+            Some(Box::new(listen_input))
         } else {
             None
         };
@@ -327,6 +344,9 @@ impl SerialParameters {
                     None => return Err(Error::PathRequired),
                 }
             }
+            SerialType::UnixListen => {
+                listen_output.map(|output| Box::new(output) as Box<dyn io::Write + Send>)
+            }
         };
         Ok(T::new(protected_vm, evt, input, output, keep_rds.to_vec()))
     }
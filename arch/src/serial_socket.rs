@@ -0,0 +1,254 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A listening unix socket that serves as a reconnectable serial console.
+//!
+//! Only one client is attached at a time; others wait in the listen backlog until it disconnects.
+//! Output written while no client is attached is kept in a bounded backlog and sent to the next
+//! client that connects, so clients may disconnect and reconnect at any point.
+
+use std::collections::VecDeque;
+use std::fs::remove_file;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use base::{error, info, RawDescriptor};
+use sync::Mutex;
+
+// Output kept for the next client while none is attached. The oldest bytes are dropped first.
+const BACKLOG_CAPACITY: usize = 64 * 1024;
+
+struct Shared {
+    // Taken by the connection thread when it starts.
+    listener: Option<UnixListener>,
+    input: Option<Sender<Vec<u8>>>,
+    client: Option<UnixStream>,
+    backlog: VecDeque<u8>,
+}
+
+impl Shared {
+    fn buffer(&mut self, data: &[u8]) {
+        self.backlog.extend(data);
+        let excess = self.backlog.len().saturating_sub(BACKLOG_CAPACITY);
+        self.backlog.drain(..excess);
+    }
+
+    // Sends as much of `data` to the client as it takes without blocking and buffers the rest.
+    // Clients that fail are disconnected.
+    fn send(&mut self, data: &[u8]) {
+        let fd = match &self.client {
+            Some(client) => client.as_raw_fd(),
+            None => return self.buffer(data),
+        };
+
+        let mut sent = 0;
+        while sent < data.len() {
+            let remaining = &data[sent..];
+            // Safe because the buffer is valid for its length and the return value is checked.
+            let ret = unsafe {
+                libc::send(
+                    fd,
+                    remaining.as_ptr() as *const libc::c_void,
+                    remaining.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                )
+            };
+            if ret >= 0 {
+                sent += ret as usize;
+                continue;
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::Interrupted => {}
+                ErrorKind::WouldBlock => break,
+                _ => {
+                    info!("serial socket client disconnected: {}", e);
+                    self.disconnect();
+                    break;
+                }
+            }
+        }
+        self.buffer(&data[sent..]);
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(client) = self.client.take() {
+            // Wakes up the connection thread so it waits for the next client.
+            let _ = client.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// The output half of a socket bound with `bind`.
+pub struct ListenSocketOutput {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// The input half of a socket bound with `bind`.
+pub struct ListenSocketInput {
+    shared: Arc<Mutex<Shared>>,
+    input: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+/// Binds a listening socket at `path`, replacing any stale socket left there, and returns its
+/// input and output halves along with the descriptor that must be kept open after jailing.
+///
+/// No thread is started until either half is first used, so this may be called before the device
+/// is forked into its own process.
+pub fn bind(path: &Path) -> io::Result<(ListenSocketInput, ListenSocketOutput, RawDescriptor)> {
+    match remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    let descriptor = listener.as_raw_fd();
+    let (input_send, input_recv) = channel();
+    let shared = Arc::new(Mutex::new(Shared {
+        listener: Some(listener),
+        input: Some(input_send),
+        client: None,
+        backlog: VecDeque::new(),
+    }));
+    Ok((
+        ListenSocketInput {
+            shared: shared.clone(),
+            input: input_recv,
+            pending: VecDeque::new(),
+        },
+        ListenSocketOutput { shared },
+        descriptor,
+    ))
+}
+
+// Starts the thread that accepts clients and forwards their input, if it is not running yet.
+fn start(shared: &Arc<Mutex<Shared>>) {
+    let (listener, input) = {
+        let mut locked = shared.lock();
+        match (locked.listener.take(), locked.input.take()) {
+            (Some(listener), Some(input)) => (listener, input),
+            _ => return,
+        }
+    };
+    let shared = shared.clone();
+    let res = thread::Builder::new()
+        .name("serial socket".to_owned())
+        .spawn(move || run(listener, input, shared));
+    if let Err(e) = res {
+        error!("failed to spawn serial socket thread: {}", e);
+    }
+}
+
+fn run(listener: UnixListener, input: Sender<Vec<u8>>, shared: Arc<Mutex<Shared>>) {
+    loop {
+        let mut client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(e) => {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("failed to accept serial socket client: {}", e);
+                return;
+            }
+        };
+        match client.try_clone() {
+            Ok(writer) => {
+                let mut locked = shared.lock();
+                locked.client = Some(writer);
+                let backlog: Vec<u8> = locked.backlog.drain(..).collect();
+                locked.send(&backlog);
+            }
+            Err(e) => {
+                error!("failed to clone serial socket client: {}", e);
+                continue;
+            }
+        }
+
+        let mut buf = [0u8; 256];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) => break,
+                // Input is dropped if the device reads from somewhere else.
+                Ok(len) => {
+                    let _ = input.send(buf[..len].to_vec());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+
+        // The client may already be gone if sending to it failed.
+        shared.lock().client = None;
+    }
+}
+
+impl Write for ListenSocketOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        start(&self.shared);
+        self.shared.lock().send(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ListenSocketInput {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        start(&self.shared);
+        if self.pending.is_empty() {
+            match self.input.recv() {
+                Ok(data) => self.pending.extend(data),
+                // The connection thread only exits on fatal errors; report end of input.
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.pending.len());
+        for (dst, src) in out.iter_mut().zip(self.pending.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn reconnect() {
+        let path = temp_dir().join(format!("crosvm_serial_socket_{}", std::process::id()));
+        let (mut input, mut output, _) = bind(&path).unwrap();
+
+        // Output is kept until a client connects.
+        output.write_all(b"hello").unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.write_all(b"ls").unwrap();
+        let mut buf = [0u8; 2];
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ls");
+        drop(client);
+
+        // A new client gets the output written after the previous one left.
+        let mut client = UnixStream::connect(&path).unwrap();
+        output.write_all(b"again").unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"again");
+
+        remove_file(&path).unwrap();
+    }
+}
@@ -57,12 +57,32 @@ impl MemoryMapping {
 
 pub trait Unix {
     fn remove_range(&self, mem_offset: usize, count: usize) -> Result<()>;
+    fn discard_range(&self, mem_offset: usize, count: usize) -> Result<()>;
+    fn bind_to_numa_node(&self, mem_offset: usize, count: usize, node: u32) -> Result<()>;
+    fn mlock(&self, mem_offset: usize, count: usize) -> Result<()>;
+    fn set_mergeable(&self, mem_offset: usize, count: usize) -> Result<()>;
 }
 
 impl Unix for MemoryMapping {
     fn remove_range(&self, mem_offset: usize, count: usize) -> Result<()> {
         self.0.remove_range(mem_offset, count)
     }
+
+    fn discard_range(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.0.discard_range(mem_offset, count)
+    }
+
+    fn bind_to_numa_node(&self, mem_offset: usize, count: usize, node: u32) -> Result<()> {
+        self.0.bind_to_numa_node(mem_offset, count, node)
+    }
+
+    fn mlock(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.0.mlock(mem_offset, count)
+    }
+
+    fn set_mergeable(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.0.set_mergeable(mem_offset, count)
+    }
 }
 
 pub struct MemoryMappingBuilder<'a> {
@@ -71,6 +91,7 @@ pub struct MemoryMappingBuilder<'a> {
     offset: Option<u64>,
     protection: Option<Protection>,
     populate: bool,
+    private: bool,
 }
 
 /// Builds a MemoryMapping object from the specified arguments.
@@ -83,6 +104,7 @@ impl<'a> MemoryMappingBuilder<'a> {
             offset: None,
             protection: None,
             populate: false,
+            private: false,
         }
     }
 
@@ -118,6 +140,15 @@ impl<'a> MemoryMappingBuilder<'a> {
         self
     }
 
+    /// Map private memory, which KSM can merge but child processes don't share. Only new mappings
+    /// without a descriptor can be private.
+    ///
+    /// Default: Map shared memory
+    pub fn private(mut self) -> MemoryMappingBuilder<'a> {
+        self.private = true;
+        self
+    }
+
     /// Build a MemoryMapping from the provided options.
     pub fn build(self) -> Result<MemoryMapping> {
         match self.descriptor {
@@ -126,11 +157,14 @@ impl<'a> MemoryMappingBuilder<'a> {
                     // Population not supported for new mmaps
                     return Err(MmapError::InvalidArgument);
                 }
-                MemoryMappingBuilder::wrap(SysUtilMmap::new_protection(
-                    self.size,
-                    self.protection.unwrap_or_else(Protection::read_write),
-                ))
+                let protection = self.protection.unwrap_or_else(Protection::read_write);
+                MemoryMappingBuilder::wrap(if self.private {
+                    SysUtilMmap::new_private_protection(self.size, protection)
+                } else {
+                    SysUtilMmap::new_protection(self.size, protection)
+                })
             }
+            Some(_) if self.private => Err(MmapError::InvalidArgument),
             Some(descriptor) => {
                 MemoryMappingBuilder::wrap(SysUtilMmap::from_fd_offset_protection_populate(
                     &wrap_descriptor(descriptor),
@@ -153,8 +187,8 @@ impl<'a> MemoryMappingBuilder<'a> {
     /// address space, the destructors of those objects will conflict and the space could
     /// be unmapped while still in use.
     pub unsafe fn build_fixed(self, addr: *mut u8) -> Result<MemoryMapping> {
-        if self.populate {
-            // Population not supported for fixed mapping.
+        if self.populate || self.private {
+            // Population and private memory not supported for fixed mapping.
             return Err(MmapError::InvalidArgument);
         }
         match self.descriptor {
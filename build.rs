@@ -0,0 +1,49 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+// The directories of `seccomp` to take device policies from for each target architecture, in the
+// order they are searched. Some devices only have a policy for the other ARM flavor.
+fn policy_arches(target_arch: &str) -> &'static [&'static str] {
+    match target_arch {
+        "x86_64" => &["x86_64"],
+        "aarch64" => &["aarch64", "arm"],
+        "arm" => &["arm", "aarch64"],
+        _ => &[],
+    }
+}
+
+// Generates `EMBEDDED_POLICIES`, the seccomp policies built into crosvm, for `src/seccomp.rs`.
+fn main() {
+    let seccomp_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("seccomp");
+    println!("cargo:rerun-if-changed={}", seccomp_dir.display());
+
+    let mut code = String::from("pub static EMBEDDED_POLICIES: &[(&str, &[(&str, &str)])] = &[\n");
+    if env::var_os("CARGO_FEATURE_EMBEDDED_SECCOMP").is_some() {
+        let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+        for arch in policy_arches(&target_arch) {
+            let mut policies: Vec<PathBuf> = fs::read_dir(seccomp_dir.join(arch))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "policy"))
+                .collect();
+            policies.sort();
+
+            writeln!(code, "    ({:?}, &[", arch).unwrap();
+            for path in policies {
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                writeln!(code, "        ({:?}, include_str!({:?})),", name, path).unwrap();
+            }
+            writeln!(code, "    ]),").unwrap();
+        }
+    }
+    code.push_str("];\n");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("seccomp_policies.rs"), code).unwrap();
+}
@@ -0,0 +1,216 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A pool of threads for running blocking calls from async code.
+//!
+//! Some operations have no asynchronous equivalent on every backend, e.g. ioctls, `fallocate` with
+//! the poll executor or `getdents64`. Running them directly in a future would stall every other
+//! task on the executor, so they are sent to a `BlockingPool` instead and their result is awaited.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use async_task::{Runnable, Task};
+use sync::{Condvar, Mutex};
+use sys_util::error;
+
+struct State {
+    tasks: VecDeque<Runnable>,
+    num_threads: usize,
+    num_idle: usize,
+    shutting_down: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    condvar: Condvar,
+    max_threads: usize,
+    keepalive: Duration,
+}
+
+impl Inner {
+    fn schedule(self: &Arc<Self>, runnable: Runnable) {
+        let mut state = self.state.lock();
+        if state.shutting_down {
+            // Dropping the runnable cancels its task.
+            return;
+        }
+        state.tasks.push_back(runnable);
+
+        if state.num_idle > 0 {
+            self.condvar.notify_one();
+        } else if state.num_threads < self.max_threads {
+            let inner = self.clone();
+            let res = thread::Builder::new()
+                .name("blocking pool".to_owned())
+                .spawn(move || inner.run());
+            match res {
+                Ok(_) => state.num_threads += 1,
+                // Queued tasks still run on the existing threads, if any.
+                Err(e) => error!("failed to spawn blocking pool thread: {}", e),
+            }
+        }
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock();
+        loop {
+            if state.shutting_down {
+                break;
+            }
+            if let Some(runnable) = state.tasks.pop_front() {
+                drop(state);
+                runnable.run();
+                state = self.state.lock();
+                continue;
+            }
+
+            state.num_idle += 1;
+            let (guard, result) = self.condvar.wait_timeout(state, self.keepalive);
+            state = guard;
+            state.num_idle -= 1;
+            if result.timed_out() && state.tasks.is_empty() {
+                break;
+            }
+        }
+        state.num_threads -= 1;
+    }
+}
+
+/// Runs blocking closures on a set of threads that grows on demand up to a limit. Threads that have
+/// been idle for longer than a keepalive period exit.
+///
+/// Dropping the pool cancels tasks that haven't started yet. Threads that are running a closure
+/// finish it first, but nothing waits for them.
+///
+/// # Example
+///
+/// ```
+/// use std::fs::File;
+///
+/// use cros_async::{BlockingPool, Executor};
+///
+/// let pool = BlockingPool::default();
+/// let ex = Executor::new().unwrap();
+/// let res = ex
+///     .run_until(pool.spawn(|| File::open("/proc/self/stat").map(|_| ())))
+///     .unwrap();
+/// assert!(res.is_ok());
+/// ```
+pub struct BlockingPool {
+    inner: Arc<Inner>,
+}
+
+impl BlockingPool {
+    /// Creates a pool that runs at most `max_threads` closures at once and stops threads that have
+    /// been idle for `keepalive`. No threads are started until the first closure is spawned.
+    pub fn new(max_threads: usize, keepalive: Duration) -> BlockingPool {
+        BlockingPool {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    tasks: VecDeque::new(),
+                    num_threads: 0,
+                    num_idle: 0,
+                    shutting_down: false,
+                }),
+                condvar: Condvar::new(),
+                max_threads: max_threads.max(1),
+                keepalive,
+            }),
+        }
+    }
+
+    /// Runs `f` on a pool thread. The returned `Task` resolves to the result of `f` and can be
+    /// awaited from any executor. Dropping the `Task` before `f` starts cancels it.
+    pub fn spawn<F, R>(&self, f: F) -> Task<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let inner: Weak<Inner> = Arc::downgrade(&self.inner);
+        let schedule = move |runnable| {
+            if let Some(inner) = inner.upgrade() {
+                inner.schedule(runnable);
+            }
+        };
+        let (runnable, task) = async_task::spawn(async move { f() }, schedule);
+        runnable.schedule();
+        task
+    }
+}
+
+impl Default for BlockingPool {
+    /// A pool of up to 256 threads that are kept for 10 seconds when idle.
+    fn default() -> BlockingPool {
+        BlockingPool::new(256, Duration::from_secs(10))
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        let tasks = {
+            let mut state = self.inner.state.lock();
+            state.shutting_down = true;
+            std::mem::take(&mut state.tasks)
+        };
+        self.inner.condvar.notify_all();
+        // Cancel pending tasks outside the lock.
+        drop(tasks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Barrier;
+    use std::time::Instant;
+
+    use futures::executor::block_on;
+
+    #[test]
+    fn runs_in_parallel() {
+        let pool = BlockingPool::new(2, Duration::from_secs(10));
+        let barrier = Arc::new(Barrier::new(2));
+        let tasks: Vec<Task<u32>> = (0..2)
+            .map(|i| {
+                let barrier = barrier.clone();
+                pool.spawn(move || {
+                    barrier.wait();
+                    i
+                })
+            })
+            .collect();
+        let results: Vec<u32> = tasks.into_iter().map(block_on).collect();
+        assert_eq!(results, vec![0, 1]);
+    }
+
+    #[test]
+    fn limits_threads() {
+        let pool = BlockingPool::new(1, Duration::from_secs(10));
+        let tasks: Vec<Task<thread::ThreadId>> = (0..4)
+            .map(|_| pool.spawn(|| thread::current().id()))
+            .collect();
+        let ids: Vec<_> = tasks.into_iter().map(block_on).collect();
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert_eq!(pool.inner.state.lock().num_threads, 1);
+    }
+
+    #[test]
+    fn idle_threads_exit() {
+        let pool = BlockingPool::new(4, Duration::from_millis(10));
+        block_on(pool.spawn(|| ()));
+
+        let start = Instant::now();
+        while pool.inner.state.lock().num_threads != 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "idle thread didn't exit"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
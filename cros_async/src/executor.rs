@@ -2,14 +2,18 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fmt::{self, Display};
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_task::Task;
 
 use crate::poll_source::Error as PollError;
-use crate::uring_executor::use_uring;
+use crate::uring_executor::{uring_unavailable_reason, use_uring};
 use crate::{
-    AsyncResult, FdExecutor, IntoAsync, IoSourceExt, PollSource, URingExecutor, UringSource,
+    AsyncResult, BackingMemory, FdExecutor, IntoAsync, IoSourceExt, MemRegion, PollSource,
+    TimerAsync, URingExecutor, UringSource,
 };
 
 pub(crate) fn async_uring_from<'a, F: IntoAsync + 'a>(
@@ -27,6 +31,32 @@ pub(crate) fn async_poll_from<'a, F: IntoAsync + 'a>(
     Ok(PollSource::new(f, ex).map(|u| Box::new(u) as Box<dyn IoSourceExt<F>>)?)
 }
 
+/// The I/O backend an `Executor` is built on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutorKind {
+    Uring,
+    Fd,
+}
+
+impl Display for ExecutorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecutorKind::Uring => write!(f, "uring"),
+            ExecutorKind::Fd => write!(f, "poll"),
+        }
+    }
+}
+
+/// Returns the backend that `Executor::new` picks in this process, along with the reason for the
+/// choice.
+pub fn executor_kind() -> (ExecutorKind, &'static str) {
+    if use_uring() {
+        (ExecutorKind::Uring, "io_uring is available")
+    } else {
+        (ExecutorKind::Fd, uring_unavailable_reason())
+    }
+}
+
 /// An executor for scheduling tasks that poll futures to completion.
 ///
 /// All asynchronous operations must run within an executor, which is capable of spawning futures as
@@ -134,6 +164,19 @@ impl Executor {
         }
     }
 
+    /// Returns the I/O backend of this executor.
+    pub fn kind(&self) -> ExecutorKind {
+        match self {
+            Executor::Uring(_) => ExecutorKind::Uring,
+            Executor::Fd(_) => ExecutorKind::Fd,
+        }
+    }
+
+    /// Completes after `dur` has elapsed, without blocking other tasks on this executor.
+    pub async fn sleep(&self, dur: Duration) -> AsyncResult<()> {
+        TimerAsync::sleep(self, dur).await
+    }
+
     /// Create a new `Box<dyn IoSourceExt<F>>` associated with `self`. Callers may then use the
     /// returned `IoSourceExt` to directly start async operations without needing a separate
     /// reference to the executor.
@@ -147,6 +190,19 @@ impl Executor {
         }
     }
 
+    /// Registers `regions` of `mem` so reads and writes to them don't have to pin the memory on
+    /// every operation. See `URingExecutor::register_buffers`. Does nothing on the poll executor.
+    pub fn register_buffers(
+        &self,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        regions: &[MemRegion],
+    ) -> AsyncResult<()> {
+        match self {
+            Executor::Uring(ex) => Ok(ex.register_buffers(mem, regions)?),
+            Executor::Fd(_) => Ok(()),
+        }
+    }
+
     /// Spawn a new future for this executor to run to completion. Callers may use the returned
     /// `Task` to await on the result of `f`. Dropping the returned `Task` will cancel `f`,
     /// preventing it from being polled again. To drop a `Task` without canceling the future
@@ -227,6 +283,28 @@ impl Executor {
         }
     }
 
+    /// Spawn a new future for this executor to run to completion without keeping a handle to it.
+    /// Equivalent to calling `Task::detach` on the result of `spawn`.
+    pub fn spawn_detached<F>(&self, f: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(f).detach()
+    }
+
+    /// Spawn a thread-local future for this executor to run to completion without keeping a
+    /// handle to it. Equivalent to calling `Task::detach` on the result of `spawn_local`.
+    ///
+    /// # Panics
+    ///
+    /// Same as `spawn_local`.
+    pub fn spawn_local_detached<F>(&self, f: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.spawn_local(f).detach()
+    }
+
     /// Run the executor indefinitely, driving all spawned futures to completion. This method will
     /// block the current thread and only return in the case of an error.
     ///
@@ -303,3 +381,46 @@ impl Executor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use futures::future::pending;
+
+    #[test]
+    fn spawn_per_request_tasks() {
+        async fn go(ex: &Executor) -> Vec<u32> {
+            // Tasks spawned while the executor is running are polled alongside the caller.
+            let tasks: Vec<Task<u32>> = (0..4u32)
+                .map(|i| ex.spawn_local(async move { i * 2 }))
+                .collect();
+            let mut results = Vec::new();
+            for task in tasks {
+                results.push(task.await);
+            }
+            results
+        }
+
+        let ex = Executor::new().unwrap();
+        assert_eq!(ex.run_until(go(&ex)).unwrap(), vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn cancel_and_detach() {
+        let ex = Executor::new().unwrap();
+
+        let done = Rc::new(RefCell::new(false));
+        let done_task = done.clone();
+        ex.spawn_local_detached(async move {
+            *done_task.borrow_mut() = true;
+        });
+
+        let stuck = ex.spawn_local(pending::<u32>());
+        assert_eq!(ex.run_until(stuck.cancel()).unwrap(), None);
+        assert!(*done.borrow());
+    }
+}
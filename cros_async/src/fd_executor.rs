@@ -258,9 +258,11 @@ impl RawExecutor {
         loop {
             self.state.store(PROCESSING, Ordering::Release);
             self.queue.set_waker(cx.waker().clone());
+            let trace = cros_tracing::trace_event!(Executor, "fd_executor run tasks");
             for runnable in self.queue.iter() {
                 runnable.run();
             }
+            drop(trace);
 
             if let Poll::Ready(val) = done.as_mut().poll(cx) {
                 return Ok(val);
@@ -446,7 +448,7 @@ impl FdExecutor {
     pub fn wait_writable<F: AsRawFd>(&self, f: &F) -> Result<PendingOperation> {
         let token = self
             .raw
-            .add_operation(f.as_raw_fd(), WatchingEvents::empty().set_read())?;
+            .add_operation(f.as_raw_fd(), WatchingEvents::empty().set_write())?;
 
         Ok(PendingOperation {
             token: Some(token),
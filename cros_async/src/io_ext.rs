@@ -30,6 +30,15 @@ pub enum Error {
     /// An error with a polled(FD) source.
     #[error("An error with a poll source: {0}")]
     Poll(crate::poll_source::Error),
+    /// Failed to create or arm a timer.
+    #[error("Failed to create or arm a timer: {0}")]
+    Timer(sys_util::Error),
+    /// A future did not complete before its timeout expired.
+    #[error("The operation timed out")]
+    TimedOut,
+    /// A socket operation failed.
+    #[error("A socket operation failed: {0}")]
+    Socket(std::io::Error),
     /// An error with a uring source.
     #[error("An error with a uring source: {0}")]
     Uring(crate::uring_executor::Error),
@@ -54,6 +63,10 @@ pub trait ReadAsync {
     /// Reads from the iosource at `file_offset` and fill the given `vec`.
     async fn read_to_vec<'a>(&'a self, file_offset: u64, vec: Vec<u8>) -> Result<(usize, Vec<u8>)>;
 
+    /// Reads from the iosource at its current position and fills the given `vec`, advancing the
+    /// position by the number of bytes read. Works for non-seekable sources like pipes and sockets.
+    async fn read_to_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> Result<(usize, Vec<u8>)>;
+
     /// Reads to the given `mem` at the given offsets from the file starting at `file_offset`.
     async fn read_to_mem<'a>(
         &'a self,
@@ -79,6 +92,10 @@ pub trait WriteAsync {
         vec: Vec<u8>,
     ) -> Result<(usize, Vec<u8>)>;
 
+    /// Writes from the given `vec` to the file at its current position, advancing the position by
+    /// the number of bytes written. Works for non-seekable sources like pipes and sockets.
+    async fn write_from_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> Result<(usize, Vec<u8>)>;
+
     /// Writes from the given `mem` from the given offsets to the file starting at `file_offset`.
     async fn write_from_mem<'a>(
         &'a self,
@@ -87,11 +104,29 @@ pub trait WriteAsync {
         mem_offsets: &'a [MemRegion],
     ) -> Result<usize>;
 
-    /// See `fallocate(2)`. Note this op is synchronous when using the Polled backend.
+    /// See `fallocate(2)`. Note this op is synchronous when using the Polled backend or when the
+    /// kernel's io_uring doesn't support it.
     async fn fallocate(&self, file_offset: u64, len: u64, mode: u32) -> Result<()>;
 
     /// Sync all completed write operations to the backing storage.
     async fn fsync(&self) -> Result<()>;
+
+    /// Writes like `write_from_mem` and then syncs like `fsync`, so the written data is on the
+    /// backing storage when this completes. The uring backend submits both at once, with the sync
+    /// ordered after the write.
+    async fn write_from_mem_then_fsync<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: &'a [MemRegion],
+    ) -> Result<usize> {
+        let len = self.write_from_mem(file_offset, mem, mem_offsets).await?;
+        self.fsync().await?;
+        Ok(len)
+    }
+
+    /// Wait for the FD of `self` to be writable.
+    async fn wait_writable(&self) -> Result<()>;
 }
 
 /// Subtrait for general async IO.
@@ -105,6 +140,10 @@ pub trait IoSourceExt<F>: ReadAsync + WriteAsync {
 
     /// Provides a ref to the underlying IO source.
     fn as_source(&self) -> &F;
+
+    /// Gets the attributes of the source, like `fstat(2)`. Uses `IORING_OP_STATX` with the uring
+    /// backend, falling back to a synchronous `fstat` on kernels that don't support it.
+    async fn stat(&self) -> Result<libc::stat64>;
 }
 
 /// Marker trait signifying that the implementor is suitable for use with
@@ -277,6 +316,32 @@ mod tests {
         poll_ex.run_until(go(poll_source)).unwrap();
     }
 
+    #[test]
+    fn streaming_pipe() {
+        async fn go<F: AsRawFd>(rx: Box<dyn IoSourceExt<F>>, tx: Box<dyn IoSourceExt<F>>) {
+            for chunk in [b"hello", b"world"].iter() {
+                let (len, _) = tx.write_from_vec_streaming(chunk.to_vec()).await.unwrap();
+                assert_eq!(len, 5);
+            }
+            let (len, v) = rx.read_to_vec_streaming(vec![0u8; 5]).await.unwrap();
+            assert_eq!(&v[..len], b"hello");
+            let (len, v) = rx.read_to_vec_streaming(vec![0u8; 5]).await.unwrap();
+            assert_eq!(&v[..len], b"world");
+        }
+
+        let (rx, tx) = sys_util::pipe(true).unwrap();
+        let uring_ex = URingExecutor::new().unwrap();
+        let rx = async_uring_from(rx, &uring_ex).unwrap();
+        let tx = async_uring_from(tx, &uring_ex).unwrap();
+        uring_ex.run_until(go(rx, tx)).unwrap();
+
+        let (rx, tx) = sys_util::pipe(true).unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let rx = async_poll_from(rx, &poll_ex).unwrap();
+        let tx = async_poll_from(tx, &poll_ex).unwrap();
+        poll_ex.run_until(go(rx, tx)).unwrap();
+    }
+
     #[test]
     fn readmem() {
         async fn go<F: AsRawFd>(async_source: Box<dyn IoSourceExt<F>>) {
@@ -19,13 +19,13 @@
 //!
 //! ## Completing one of several futures.
 //!
-//! If there are several top level tasks that should run until any one completes, use the "select"
-//! family of executor constructors. These return an [`Executor`](trait.Executor.html) whose `run`
-//! function will return when the first future completes. The uncompleted futures will also be
-//! returned so they can be run further or otherwise cleaned up. These functions are inspired by
+//! If there are several top level tasks that should run until any one completes, use the
+//! [`select!`](macro.select.html) macro, which takes any number of futures, or
+//! [`select_all`](fn.select_all.html) when the number of futures is only known at runtime. These
+//! return a future that completes when the first of the futures completes. The uncompleted futures
+//! will also be returned so they can be run further or otherwise cleaned up. These are inspired by
 //! the `select_all` function from futures-rs, but built to be run inside an FD based executor and
-//! to poll only when necessary. See the docs for [`select2`](fn.select2.html),
-//! [`select3`](fn.select3.html), [`select4`](fn.select4.html), and [`select5`](fn.select5.html).
+//! to poll only when necessary.
 //!
 //! ## Completing all of several futures.
 //!
@@ -37,6 +37,17 @@
 //! [`complete3`](fn.complete3.html), [`complete4`](fn.complete4.html), and
 //! [`complete5`](fn.complete5.html).
 //!
+//! # Spawning tasks.
+//!
+//! Futures that only need to run for a while, such as one per in-flight request, can be started
+//! from inside a running executor with [`Executor::spawn`](enum.Executor.html#method.spawn) or
+//! `spawn_local`. These return a `Task` that can be awaited for the result or cancelled, and
+//! `spawn_detached`/`spawn_local_detached` start tasks nobody waits on.
+//!
+//! To spread tasks over several cores, use an [`ExecutorPool`](struct.ExecutorPool.html), which
+//! runs one executor per worker thread. Blocking calls with no async equivalent can be moved off
+//! the executor with a [`BlockingPool`](struct.BlockingPool.html).
+//!
 //! # Implementing new FD-based futures.
 //!
 //! For URing implementations should provide an implementation of the `IoSource` trait.
@@ -58,30 +69,41 @@
 //! See the docs for `IoSourceExt` if support for kernels <5.4 is required. Focus on `UringSource` if
 //! all systems have support for io_uring.
 
+mod blocking;
 mod complete;
 mod event;
 mod executor;
 mod fd_executor;
 mod io_ext;
 mod poll_source;
+mod pool;
 mod queue;
-mod select;
+#[doc(hidden)]
+pub mod select;
 mod timer;
+mod unix_seqpacket;
 mod uring_executor;
 pub mod uring_mem;
 mod uring_source;
 mod waker;
 
+pub use async_task::Task;
+pub use blocking::BlockingPool;
 pub use event::EventAsync;
-pub use executor::Executor;
+pub use executor::{executor_kind, Executor, ExecutorKind};
 pub use fd_executor::FdExecutor;
 pub use io_ext::{
     Error as AsyncError, IntoAsync, IoSourceExt, ReadAsync, Result as AsyncResult, WriteAsync,
 };
+pub use io_uring::{Feature as UringFeature, Opcode as UringOpcode};
 pub use poll_source::PollSource;
-pub use select::SelectResult;
-pub use timer::TimerAsync;
-pub use uring_executor::URingExecutor;
+pub use pool::{Error as PoolError, ExecutorPool};
+pub use select::{select_all, SelectAll, SelectResult};
+pub use timer::{with_timeout, TimeoutExt, TimerAsync};
+pub use unix_seqpacket::{UnixSeqpacketAsync, UnixSeqpacketListenerAsync};
+pub use uring_executor::{
+    disable_uring, enable_uring_sqpoll, uring_caps, MultishotPoll, URingExecutor, UringCaps,
+};
 pub use uring_mem::{BackingMemory, MemRegion};
 pub use uring_source::UringSource;
 
@@ -172,211 +194,6 @@ pub fn run_one_poll<F: Future>(fut: F) -> Result<F::Output> {
         .map_err(Error::FdExecutor)
 }
 
-// Select helpers to run until any future completes.
-
-/// Creates a combinator that runs the two given futures until one completes, returning a tuple
-/// containing the result of the finished future and the still pending future.
-///
-///  # Example
-///
-///    ```
-///    use cros_async::{SelectResult, select2, run_one};
-///    use futures::future::pending;
-///    use futures::pin_mut;
-///
-///    let first = async {5};
-///    let second = async {let () = pending().await;};
-///    pin_mut!(first);
-///    pin_mut!(second);
-///    match run_one(select2(first, second)) {
-///        Ok((SelectResult::Finished(5), SelectResult::Pending(_second))) => (),
-///        _ => panic!("Select didn't return the first future"),
-///    };
-///    ```
-pub async fn select2<F1: Future + Unpin, F2: Future + Unpin>(
-    f1: F1,
-    f2: F2,
-) -> (SelectResult<F1>, SelectResult<F2>) {
-    select::Select2::new(f1, f2).await
-}
-
-/// Creates a combinator that runs the three given futures until one or more completes, returning a
-/// tuple containing the result of the finished future(s) and the still pending future(s).
-///
-///  # Example
-///
-///    ```
-///    use cros_async::{SelectResult, select3, run_one};
-///    use futures::future::pending;
-///    use futures::pin_mut;
-///
-///    let first = async {4};
-///    let second = async {let () = pending().await;};
-///    let third = async {5};
-///    pin_mut!(first);
-///    pin_mut!(second);
-///    pin_mut!(third);
-///    match run_one(select3(first, second, third)) {
-///        Ok((SelectResult::Finished(4),
-///            SelectResult::Pending(_second),
-///            SelectResult::Finished(5))) => (),
-///        _ => panic!("Select didn't return the futures"),
-///    };
-///    ```
-pub async fn select3<F1: Future + Unpin, F2: Future + Unpin, F3: Future + Unpin>(
-    f1: F1,
-    f2: F2,
-    f3: F3,
-) -> (SelectResult<F1>, SelectResult<F2>, SelectResult<F3>) {
-    select::Select3::new(f1, f2, f3).await
-}
-
-/// Creates a combinator that runs the four given futures until one or more completes, returning a
-/// tuple containing the result of the finished future(s) and the still pending future(s).
-///
-///  # Example
-///
-///    ```
-///    use cros_async::{SelectResult, select4, run_one};
-///    use futures::future::pending;
-///    use futures::pin_mut;
-///
-///    let first = async {4};
-///    let second = async {let () = pending().await;};
-///    let third = async {5};
-///    let fourth = async {let () = pending().await;};
-///    pin_mut!(first);
-///    pin_mut!(second);
-///    pin_mut!(third);
-///    pin_mut!(fourth);
-///    match run_one(select4(first, second, third, fourth)) {
-///        Ok((SelectResult::Finished(4), SelectResult::Pending(_second),
-///            SelectResult::Finished(5), SelectResult::Pending(_fourth))) => (),
-///        _ => panic!("Select didn't return the futures"),
-///    };
-///    ```
-pub async fn select4<
-    F1: Future + Unpin,
-    F2: Future + Unpin,
-    F3: Future + Unpin,
-    F4: Future + Unpin,
->(
-    f1: F1,
-    f2: F2,
-    f3: F3,
-    f4: F4,
-) -> (
-    SelectResult<F1>,
-    SelectResult<F2>,
-    SelectResult<F3>,
-    SelectResult<F4>,
-) {
-    select::Select4::new(f1, f2, f3, f4).await
-}
-
-/// Creates a combinator that runs the five given futures until one or more completes, returning a
-/// tuple containing the result of the finished future(s) and the still pending future(s).
-///
-///  # Example
-///
-///    ```
-///    use cros_async::{SelectResult, select5, run_one};
-///    use futures::future::pending;
-///    use futures::pin_mut;
-///
-///    let first = async {4};
-///    let second = async {let () = pending().await;};
-///    let third = async {5};
-///    let fourth = async {let () = pending().await;};
-///    let fifth = async {6};
-///    pin_mut!(first);
-///    pin_mut!(second);
-///    pin_mut!(third);
-///    pin_mut!(fourth);
-///    pin_mut!(fifth);
-///    match run_one(select5(first, second, third, fourth, fifth)) {
-///        Ok((SelectResult::Finished(4), SelectResult::Pending(_second),
-///            SelectResult::Finished(5), SelectResult::Pending(_fourth),
-///            SelectResult::Finished(6))) => (),
-///        _ => panic!("Select didn't return the futures"),
-///    };
-///    ```
-pub async fn select5<
-    F1: Future + Unpin,
-    F2: Future + Unpin,
-    F3: Future + Unpin,
-    F4: Future + Unpin,
-    F5: Future + Unpin,
->(
-    f1: F1,
-    f2: F2,
-    f3: F3,
-    f4: F4,
-    f5: F5,
-) -> (
-    SelectResult<F1>,
-    SelectResult<F2>,
-    SelectResult<F3>,
-    SelectResult<F4>,
-    SelectResult<F5>,
-) {
-    select::Select5::new(f1, f2, f3, f4, f5).await
-}
-
-/// Creates a combinator that runs the six given futures until one or more completes, returning a
-/// tuple containing the result of the finished future(s) and the still pending future(s).
-///
-///  # Example
-///
-///    ```
-///    use cros_async::{SelectResult, select6, run_one};
-///    use futures::future::pending;
-///    use futures::pin_mut;
-///
-///    let first = async {1};
-///    let second = async {let () = pending().await;};
-///    let third = async {3};
-///    let fourth = async {let () = pending().await;};
-///    let fifth = async {5};
-///    let sixth = async {6};
-///    pin_mut!(first);
-///    pin_mut!(second);
-///    pin_mut!(third);
-///    pin_mut!(fourth);
-///    pin_mut!(fifth);
-///    pin_mut!(sixth);
-///    match run_one(select6(first, second, third, fourth, fifth, sixth)) {
-///        Ok((SelectResult::Finished(1), SelectResult::Pending(_second),
-///            SelectResult::Finished(3), SelectResult::Pending(_fourth),
-///            SelectResult::Finished(5), SelectResult::Finished(6))) => (),
-///        _ => panic!("Select didn't return the futures"),
-///    };
-///    ```
-pub async fn select6<
-    F1: Future + Unpin,
-    F2: Future + Unpin,
-    F3: Future + Unpin,
-    F4: Future + Unpin,
-    F5: Future + Unpin,
-    F6: Future + Unpin,
->(
-    f1: F1,
-    f2: F2,
-    f3: F3,
-    f4: F4,
-    f5: F5,
-    f6: F6,
-) -> (
-    SelectResult<F1>,
-    SelectResult<F2>,
-    SelectResult<F3>,
-    SelectResult<F4>,
-    SelectResult<F5>,
-    SelectResult<F6>,
-) {
-    select::Select6::new(f1, f2, f3, f4, f5, f6).await
-}
-
 // Combination helpers to run until all futures are complete.
 
 /// Creates a combinator that runs the two given futures to completion, returning a tuple of the
@@ -43,6 +43,9 @@ pub enum Error {
     /// An error occurred when setting the FD non-blocking.
     #[error("An error occurred setting the FD non-blocking: {0}.")]
     SettingNonBlocking(sys_util::Error),
+    /// An error occurred when executing fstat synchronously.
+    #[error("An error occurred when executing fstat synchronously: {0}")]
+    Stat(sys_util::Error),
     /// An error occurred when writing the FD.
     #[error("An error occurred when writing the FD: {0}.")]
     Write(sys_util::Error),
@@ -71,33 +74,22 @@ impl<F: AsRawFd> PollSource<F> {
     pub fn into_source(self) -> F {
         self.source
     }
-}
-
-impl<F: AsRawFd> Deref for PollSource<F> {
-    type Target = F;
-
-    fn deref(&self) -> &Self::Target {
-        &self.source
-    }
-}
 
-#[async_trait(?Send)]
-impl<F: AsRawFd> ReadAsync for PollSource<F> {
-    /// Reads from the iosource at `file_offset` and fill the given `vec`.
-    async fn read_to_vec<'a>(
-        &'a self,
-        file_offset: u64,
+    // Reads into `vec` at `file_offset`, or at the current position if it is `None`.
+    async fn read_vec(
+        &self,
+        file_offset: Option<u64>,
         mut vec: Vec<u8>,
     ) -> AsyncResult<(usize, Vec<u8>)> {
         loop {
+            let fd = self.source.as_raw_fd();
+            let ptr = vec.as_mut_ptr() as *mut libc::c_void;
             // Safe because this will only modify `vec` and we check the return value.
             let res = unsafe {
-                libc::pread64(
-                    self.source.as_raw_fd(),
-                    vec.as_mut_ptr() as *mut libc::c_void,
-                    vec.len(),
-                    file_offset as libc::off64_t,
-                )
+                match file_offset {
+                    Some(offset) => libc::pread64(fd, ptr, vec.len(), offset as libc::off64_t),
+                    None => libc::read(fd, ptr, vec.len()),
+                }
             };
 
             if res >= 0 {
@@ -117,6 +109,65 @@ impl<F: AsRawFd> ReadAsync for PollSource<F> {
         }
     }
 
+    // Writes from `vec` at `file_offset`, or at the current position if it is `None`.
+    async fn write_vec(
+        &self,
+        file_offset: Option<u64>,
+        vec: Vec<u8>,
+    ) -> AsyncResult<(usize, Vec<u8>)> {
+        loop {
+            let fd = self.source.as_raw_fd();
+            let ptr = vec.as_ptr() as *const libc::c_void;
+            // Safe because this will not modify any memory and we check the return value.
+            let res = unsafe {
+                match file_offset {
+                    Some(offset) => libc::pwrite64(fd, ptr, vec.len(), offset as libc::off64_t),
+                    None => libc::write(fd, ptr, vec.len()),
+                }
+            };
+
+            if res >= 0 {
+                return Ok((res as usize, vec));
+            }
+
+            match sys_util::Error::last() {
+                e if e.errno() == libc::EWOULDBLOCK => {
+                    let op = self
+                        .ex
+                        .wait_writable(&self.source)
+                        .map_err(Error::AddingWaker)?;
+                    op.await.map_err(Error::Executor)?;
+                }
+                e => return Err(Error::Write(e).into()),
+            }
+        }
+    }
+}
+
+impl<F: AsRawFd> Deref for PollSource<F> {
+    type Target = F;
+
+    fn deref(&self) -> &Self::Target {
+        &self.source
+    }
+}
+
+#[async_trait(?Send)]
+impl<F: AsRawFd> ReadAsync for PollSource<F> {
+    /// Reads from the iosource at `file_offset` and fill the given `vec`.
+    async fn read_to_vec<'a>(
+        &'a self,
+        file_offset: u64,
+        vec: Vec<u8>,
+    ) -> AsyncResult<(usize, Vec<u8>)> {
+        self.read_vec(Some(file_offset), vec).await
+    }
+
+    /// Reads from the iosource at its current position and fills the given `vec`.
+    async fn read_to_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> AsyncResult<(usize, Vec<u8>)> {
+        self.read_vec(None, vec).await
+    }
+
     /// Reads to the given `mem` at the given offsets from the file starting at `file_offset`.
     async fn read_to_mem<'a>(
         &'a self,
@@ -206,32 +257,12 @@ impl<F: AsRawFd> WriteAsync for PollSource<F> {
         file_offset: u64,
         vec: Vec<u8>,
     ) -> AsyncResult<(usize, Vec<u8>)> {
-        loop {
-            // Safe because this will not modify any memory and we check the return value.
-            let res = unsafe {
-                libc::pwrite64(
-                    self.source.as_raw_fd(),
-                    vec.as_ptr() as *const libc::c_void,
-                    vec.len(),
-                    file_offset as libc::off64_t,
-                )
-            };
-
-            if res >= 0 {
-                return Ok((res as usize, vec));
-            }
+        self.write_vec(Some(file_offset), vec).await
+    }
 
-            match sys_util::Error::last() {
-                e if e.errno() == libc::EWOULDBLOCK => {
-                    let op = self
-                        .ex
-                        .wait_writable(&self.source)
-                        .map_err(Error::AddingWaker)?;
-                    op.await.map_err(Error::Executor)?;
-                }
-                e => return Err(Error::Write(e).into()),
-            }
-        }
+    /// Writes from the given `vec` to the file at its current position.
+    async fn write_from_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> AsyncResult<(usize, Vec<u8>)> {
+        self.write_vec(None, vec).await
     }
 
     /// Writes from the given `mem` from the given offsets to the file starting at `file_offset`.
@@ -302,6 +333,16 @@ impl<F: AsRawFd> WriteAsync for PollSource<F> {
             Err(AsyncError::Poll(Error::Fsync(sys_util::Error::last())))
         }
     }
+
+    /// Wait for the FD of `self` to be writable.
+    async fn wait_writable(&self) -> AsyncResult<()> {
+        let op = self
+            .ex
+            .wait_writable(&self.source)
+            .map_err(Error::AddingWaker)?;
+        op.await.map_err(Error::Executor)?;
+        Ok(())
+    }
 }
 
 #[async_trait(?Send)]
@@ -320,6 +361,19 @@ impl<F: AsRawFd> IoSourceExt<F> for PollSource<F> {
     fn as_source(&self) -> &F {
         &self.source
     }
+
+    /// Gets the attributes of the source. Note this op is synchronous.
+    async fn stat(&self) -> AsyncResult<libc::stat64> {
+        // Safe because this is only used as an out parameter and any bit pattern is valid.
+        let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+        // Safe because the kernel only writes to `st` and the return value is checked.
+        let ret = unsafe { libc::fstat64(self.source.as_raw_fd(), &mut st) };
+        if ret == 0 {
+            Ok(st)
+        } else {
+            Err(AsyncError::Poll(Error::Stat(sys_util::Error::last())))
+        }
+    }
 }
 
 impl<F: AsRawFd> DerefMut for PollSource<F> {
@@ -392,4 +446,19 @@ mod tests {
         let ex = FdExecutor::new().unwrap();
         ex.run_until(go(&ex)).unwrap();
     }
+
+    #[test]
+    fn stat() {
+        async fn go(ex: &FdExecutor) {
+            let f = tempfile::tempfile().unwrap();
+            f.set_len(4096).unwrap();
+            let source = PollSource::new(f, ex).unwrap();
+            let st = source.stat().await.unwrap();
+            assert_eq!(st.st_size, 4096);
+            assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFREG);
+        }
+
+        let ex = FdExecutor::new().unwrap();
+        ex.run_until(go(&ex)).unwrap();
+    }
 }
@@ -0,0 +1,213 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A pool of executors, each driven by its own thread.
+//!
+//! Every worker thread owns an `Executor` (backed by its own io_uring when available). New tasks go
+//! to the worker with the fewest live tasks and stay there until they complete, so I/O sources a
+//! task creates are always used from the thread that registered them. This lets heavily loaded
+//! devices spread independent requests over several cores.
+
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use async_task::Task;
+use sys_util::{error, EventFd};
+use thiserror::Error as ThisError;
+
+use crate::{AsyncError, EventAsync, Executor};
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    /// Failed to create the event used to stop a worker.
+    #[error("Failed to create the worker shutdown event: {0}")]
+    CreateEvent(sys_util::Error),
+    /// Failed to create a worker's executor.
+    #[error("Failed to create a worker executor: {0}")]
+    CreateExecutor(AsyncError),
+    /// Failed to start a worker thread.
+    #[error("Failed to spawn a worker thread: {0}")]
+    SpawnThread(io::Error),
+    /// A pool needs at least one worker.
+    #[error("An executor pool needs at least one worker")]
+    NoWorkers,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+struct Worker {
+    ex: Executor,
+    // Number of tasks spawned on this worker that haven't completed yet.
+    load: Arc<AtomicUsize>,
+    shutdown: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+// Decrements a worker's load when its task completes or is dropped.
+struct LoadGuard(Arc<AtomicUsize>);
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs tasks on a fixed set of worker threads, each with its own executor.
+///
+/// Dropping the pool stops the workers and drops any tasks that haven't completed.
+///
+/// # Example
+///
+/// ```
+/// use cros_async::ExecutorPool;
+/// use futures::executor::block_on;
+///
+/// let pool = ExecutorPool::new(2).unwrap();
+/// let tasks: Vec<_> = (0..8u32)
+///     .map(|i| pool.spawn(move |_ex| async move { i * 2 }))
+///     .collect();
+/// let results: Vec<u32> = tasks.into_iter().map(block_on).collect();
+/// assert_eq!(results, vec![0, 2, 4, 6, 8, 10, 12, 14]);
+/// ```
+pub struct ExecutorPool {
+    workers: Vec<Worker>,
+}
+
+impl ExecutorPool {
+    /// Starts a pool with `num_workers` worker threads.
+    pub fn new(num_workers: usize) -> Result<ExecutorPool> {
+        if num_workers == 0 {
+            return Err(Error::NoWorkers);
+        }
+
+        let mut workers = Vec::with_capacity(num_workers);
+        for i in 0..num_workers {
+            let ex = Executor::new().map_err(Error::CreateExecutor)?;
+            let shutdown = EventFd::new().map_err(Error::CreateEvent)?;
+            let thread_shutdown = shutdown.try_clone().map_err(Error::CreateEvent)?;
+            let thread_ex = ex.clone();
+            let thread = thread::Builder::new()
+                .name(format!("executor pool {}", i))
+                .spawn(move || run_worker(thread_ex, thread_shutdown))
+                .map_err(Error::SpawnThread)?;
+            workers.push(Worker {
+                ex,
+                load: Arc::new(AtomicUsize::new(0)),
+                shutdown,
+                thread: Some(thread),
+            });
+        }
+
+        Ok(ExecutorPool { workers })
+    }
+
+    /// Returns the number of worker threads.
+    pub fn num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs the future returned by `f` on the least loaded worker. `f` is called on the worker
+    /// thread with that worker's executor, which the future should use to create any I/O sources.
+    /// The future itself doesn't need to be `Send` since it never leaves the worker.
+    ///
+    /// The returned `Task` can be awaited from any thread or executor, and dropping it cancels the
+    /// future. Use `Task::detach` to let it run on its own.
+    pub fn spawn<F, Fut>(&self, f: F) -> Task<Fut::Output>
+    where
+        F: FnOnce(Executor) -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static,
+    {
+        // `new` guarantees there is at least one worker.
+        let worker = self
+            .workers
+            .iter()
+            .min_by_key(|w| w.load.load(Ordering::Relaxed))
+            .unwrap();
+        worker.load.fetch_add(1, Ordering::Relaxed);
+        let guard = LoadGuard(worker.load.clone());
+        let ex = worker.ex.clone();
+        worker.ex.spawn(async move {
+            let _guard = guard;
+            // Now on the worker thread, so the future may be thread-local.
+            ex.spawn_local(f(ex.clone())).await
+        })
+    }
+}
+
+impl Drop for ExecutorPool {
+    fn drop(&mut self) {
+        for worker in self.workers.iter() {
+            if let Err(e) = worker.shutdown.write(1) {
+                error!("failed to stop executor pool worker: {}", e);
+            }
+        }
+        for worker in self.workers.iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    error!("executor pool worker panicked");
+                }
+            }
+        }
+    }
+}
+
+fn run_worker(ex: Executor, shutdown: EventFd) {
+    let shutdown = match EventAsync::new(shutdown, &ex) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            error!("failed to set up executor pool worker: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = ex.run_until(shutdown.next_val()) {
+        error!("executor pool worker failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Barrier;
+
+    use futures::executor::block_on;
+
+    #[test]
+    fn tasks_run_in_parallel() {
+        let pool = ExecutorPool::new(2).unwrap();
+        assert_eq!(pool.num_workers(), 2);
+
+        // Neither task can finish unless both run at the same time on different workers.
+        let barrier = Arc::new(Barrier::new(2));
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                pool.spawn(move |_ex| async move {
+                    barrier.wait();
+                    thread::current().id()
+                })
+            })
+            .collect();
+        let ids: Vec<_> = tasks.into_iter().map(block_on).collect();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn drop_pool_with_pending_tasks() {
+        let pool = ExecutorPool::new(1).unwrap();
+        pool.spawn(|_ex| futures::future::pending::<()>()).detach();
+        drop(pool);
+    }
+
+    #[test]
+    fn no_workers() {
+        match ExecutorPool::new(0) {
+            Err(Error::NoWorkers) => {}
+            _ => panic!("pool without workers was created"),
+        }
+    }
+}
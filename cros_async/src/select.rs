@@ -2,8 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-// Need non-snake case so the macro can re-use type names for variables.
-#![allow(non_snake_case)]
+//! Combinators that run several futures until one or more of them completes.
 
 use std::future::Future;
 use std::pin::Pin;
@@ -11,77 +10,153 @@ use std::task::{Context, Poll};
 
 use futures::future::{maybe_done, FutureExt, MaybeDone};
 
+// Used by the `select!` macro, which expands in other crates.
+#[doc(hidden)]
+pub use futures::future::{
+    maybe_done as __maybe_done, poll_fn as __poll_fn, FutureExt as __FutureExt,
+    MaybeDone as __MaybeDone,
+};
+
 pub enum SelectResult<F: Future> {
     Pending(F),
     Finished(F::Output),
 }
 
-// Macro-generate future combinators to allow for running different numbers of top-level futures in
-// this FutureList. Generates the implementation of `FutureList` for the select types. For an
-// explicit example this is modeled after, see `UnitFutures`.
-macro_rules! generate {
-    ($(
-        $(#[$doc:meta])*
-        ($Select:ident, <$($Fut:ident),*>),
-    )*) => ($(
-        #[must_use = "Combinations of futures don't do anything unless run in an executor."]
-        paste::item! {
-            pub(crate) struct $Select<$($Fut: Future + Unpin),*> {
-                $($Fut: MaybeDone<$Fut>,)*
+/// Creates a combinator that runs any number of futures until one or more completes, returning a
+/// tuple with a `SelectResult` for each future, in the order they were given. Finished futures
+/// yield their output and the others are returned so they can be run further or otherwise cleaned
+/// up. The futures may have different types, but must all be `Unpin`.
+///
+///  # Example
+///
+///    ```
+///    use cros_async::{select, SelectResult, run_one};
+///    use futures::future::pending;
+///    use futures::pin_mut;
+///
+///    let first = async {1};
+///    let second = async {let () = pending().await;};
+///    let third = async {"three"};
+///    pin_mut!(first);
+///    pin_mut!(second);
+///    pin_mut!(third);
+///    match run_one(select!(first, second, third)) {
+///        Ok((SelectResult::Finished(1),
+///            SelectResult::Pending(_second),
+///            SelectResult::Finished("three"))) => (),
+///        _ => panic!("Select didn't return the futures"),
+///    };
+///    ```
+#[macro_export]
+macro_rules! select {
+    // Gives each future its own binding. Every expansion of this arm introduces a distinct `fut`
+    // identifier thanks to macro hygiene.
+    (@bind [$($bound:tt)*] $next:expr, $($rest:expr,)*) => {
+        $crate::select!(@bind [$($bound)* (fut = $next)] $($rest,)*)
+    };
+    (@bind [$(($name:ident = $fut:expr))*]) => {{
+        $(let mut $name = $crate::select::__maybe_done($fut);)*
+        $crate::select::__poll_fn(move |cx| {
+            let mut complete = false;
+            $(
+                complete |= $crate::select::__FutureExt::poll_unpin(&mut $name, cx).is_ready();
+            )*
+            if complete {
+                ::std::task::Poll::Ready(($(
+                    match ::std::mem::replace(&mut $name, $crate::select::__MaybeDone::Gone) {
+                        $crate::select::__MaybeDone::Future(f) => $crate::SelectResult::Pending(f),
+                        $crate::select::__MaybeDone::Done(o) => $crate::SelectResult::Finished(o),
+                        $crate::select::__MaybeDone::Gone => unreachable!(),
+                    },
+                )*))
+            } else {
+                ::std::task::Poll::Pending
             }
-        }
+        })
+    }};
+    ($($fut:expr),+ $(,)?) => {
+        $crate::select!(@bind [] $($fut,)+)
+    };
+}
 
-        impl<$($Fut: Future + Unpin),*> $Select<$($Fut),*> {
-            paste::item! {
-                pub(crate) fn new($($Fut: $Fut),*) -> $Select<$($Fut),*> {
-                    $Select {
-                        $($Fut: maybe_done($Fut),)*
-                    }
-                }
-            }
-        }
+/// Creates a combinator that runs all the futures in `futures` until one or more completes,
+/// returning a `SelectResult` for each of them in the original order. Useful when the number of
+/// futures is only known at runtime. Futures of different types can be boxed to fit in the `Vec`.
+///
+/// If `futures` is empty, the combinator never completes.
+///
+///  # Example
+///
+///    ```
+///    use std::future::Future;
+///    use std::pin::Pin;
+///
+///    use cros_async::{select_all, SelectResult, run_one};
+///    use futures::future::pending;
+///
+///    let futures: Vec<Pin<Box<dyn Future<Output = u32>>>> = vec![
+///        Box::pin(pending()),
+///        Box::pin(async { 2 }),
+///    ];
+///    let results = run_one(select_all(futures)).unwrap();
+///    match results.as_slice() {
+///        [SelectResult::Pending(_), SelectResult::Finished(2)] => (),
+///        _ => panic!("Select didn't return the futures"),
+///    };
+///    ```
+pub fn select_all<F: Future + Unpin>(futures: Vec<F>) -> SelectAll<F> {
+    SelectAll {
+        futures: futures.into_iter().map(maybe_done).collect(),
+    }
+}
 
-        impl<$($Fut: Future + Unpin),*> Future for $Select<$($Fut),*> {
-            type Output = ($(SelectResult<$Fut>),*);
+/// Future for the [`select_all`] function.
+#[must_use = "Combinations of futures don't do anything unless run in an executor."]
+pub struct SelectAll<F: Future + Unpin> {
+    futures: Vec<MaybeDone<F>>,
+}
 
-            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-                let mut complete = false;
-                $(
-                    let $Fut = Pin::new(&mut self.$Fut);
-                    // The future impls `Unpin`, use `poll_unpin` to avoid wrapping it in
-                    // `Pin` to call `poll`.
-                    complete |= self.$Fut.poll_unpin(cx).is_ready();
-                )*
+impl<F: Future + Unpin> Future for SelectAll<F> {
+    type Output = Vec<SelectResult<F>>;
 
-                if complete {
-                    Poll::Ready(($(
-                        match std::mem::replace(&mut self.$Fut, MaybeDone::Gone) {
-                            MaybeDone::Future(f) => SelectResult::Pending(f),
-                            MaybeDone::Done(o) => SelectResult::Finished(o),
-                            MaybeDone::Gone => unreachable!(),
-                        }
-                    ), *))
-                } else {
-                    Poll::Pending
-                }
-            }
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut complete = false;
+        for f in self.futures.iter_mut() {
+            // The future impls `Unpin`, use `poll_unpin` to avoid wrapping it in `Pin` to call
+            // `poll`.
+            complete |= f.poll_unpin(cx).is_ready();
         }
-    )*)
-}
 
-generate! {
-    /// _Future for the [`select2`] function.
-    (Select2, <_Fut1, _Fut2>),
-
-    /// _Future for the [`select3`] function.
-    (Select3, <_Fut1, _Fut2, _Fut3>),
+        if complete {
+            Poll::Ready(
+                self.futures
+                    .drain(..)
+                    .map(|f| match f {
+                        MaybeDone::Future(f) => SelectResult::Pending(f),
+                        MaybeDone::Done(o) => SelectResult::Finished(o),
+                        MaybeDone::Gone => unreachable!(),
+                    })
+                    .collect(),
+            )
+        } else {
+            Poll::Pending
+        }
+    }
+}
 
-    /// _Future for the [`select4`] function.
-    (Select4, <_Fut1, _Fut2, _Fut3, _Fut4>),
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// _Future for the [`select5`] function.
-    (Select5, <_Fut1, _Fut2, _Fut3, _Fut4, _Fut5>),
+    use futures::future::Ready;
+    use futures::task::noop_waker;
 
-    /// _Future for the [`select6`] function.
-    (Select6, <_Fut1, _Fut2, _Fut3, _Fut4, _Fut5, _Fut6>),
+    #[test]
+    fn select_all_empty() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut select = select_all(Vec::<Ready<()>>::new());
+        assert!(Pin::new(&mut select).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut select).poll(&mut cx).is_pending());
+    }
 }
@@ -2,9 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::future::Future;
+use std::time::Duration;
+
+use futures::future::{select, Either, FutureExt, LocalBoxFuture};
+use futures::pin_mut;
 use sys_util::TimerFd;
 
-use crate::{AsyncResult, Executor, IntoAsync, IoSourceExt};
+use crate::{AsyncError, AsyncResult, Executor, IntoAsync, IoSourceExt};
 #[cfg(test)]
 use crate::{FdExecutor, URingExecutor};
 
@@ -33,8 +38,87 @@ impl TimerAsync {
     pub async fn next_val(&self) -> AsyncResult<u64> {
         self.io_source.read_u64().await
     }
+
+    /// Arms the timer to first fire after `dur` and then every `interval`, if given. See
+    /// `sys_util::TimerFd::reset`.
+    pub fn reset(&self, dur: Duration, interval: Option<Duration>) -> AsyncResult<()> {
+        self.io_source
+            .as_source()
+            .reset(dur, interval)
+            .map_err(AsyncError::Timer)
+    }
+
+    /// Creates a timer that first fires after `interval` and then every `interval`.
+    pub fn periodic(interval: Duration, ex: &Executor) -> AsyncResult<TimerAsync> {
+        let timer = TimerAsync::new(TimerFd::new().map_err(AsyncError::Timer)?, ex)?;
+        timer.reset(interval, Some(interval))?;
+        Ok(timer)
+    }
+
+    /// Completes after `dur` has elapsed.
+    pub async fn sleep(ex: &Executor, dur: Duration) -> AsyncResult<()> {
+        let timer = TimerAsync::new(TimerFd::new().map_err(AsyncError::Timer)?, ex)?;
+        timer.reset(dur, None)?;
+        timer.next_val().await.map(|_| ())
+    }
+}
+
+/// Runs `f` until it completes or `dur` elapses, whichever comes first. `f` is dropped if it
+/// doesn't complete in time, in which case `AsyncError::TimedOut` is returned.
+///
+///  # Example
+///
+///    ```
+///    use std::time::Duration;
+///
+///    use cros_async::{with_timeout, AsyncError, Executor};
+///    use futures::future::pending;
+///
+///    let ex = Executor::new().unwrap();
+///    let res = ex.run_until(with_timeout(&ex, pending::<()>(), Duration::from_millis(10)));
+///    assert!(matches!(res.unwrap(), Err(AsyncError::TimedOut)));
+///    ```
+pub async fn with_timeout<F: Future>(ex: &Executor, f: F, dur: Duration) -> AsyncResult<F::Output> {
+    let timeout = TimerAsync::sleep(ex, dur);
+    pin_mut!(f);
+    pin_mut!(timeout);
+    match select(f, timeout).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right((res, _)) => res.and(Err(AsyncError::TimedOut)),
+    }
 }
 
+/// Adds a `timeout` method to every future, for bounding how long a worker waits on something like a
+/// reply from the guest.
+///
+///  # Example
+///
+///    ```
+///    use std::time::Duration;
+///
+///    use cros_async::{AsyncError, Executor, TimeoutExt};
+///    use futures::future::pending;
+///
+///    let ex = Executor::new().unwrap();
+///    let res = ex.run_until(pending::<()>().timeout(&ex, Duration::from_millis(10)));
+///    assert!(matches!(res.unwrap(), Err(AsyncError::TimedOut)));
+///    ```
+pub trait TimeoutExt: Future + Sized {
+    /// Runs `self` until it completes or `dur` elapses, using a timer on `ex`. See `with_timeout`.
+    fn timeout<'a>(
+        self,
+        ex: &'a Executor,
+        dur: Duration,
+    ) -> LocalBoxFuture<'a, AsyncResult<Self::Output>>
+    where
+        Self: 'a,
+    {
+        with_timeout(ex, self, dur).boxed_local()
+    }
+}
+
+impl<F: Future> TimeoutExt for F {}
+
 impl IntoAsync for TimerFd {}
 
 #[cfg(test)]
@@ -87,4 +171,58 @@ mod tests {
         let ex = FdExecutor::new().unwrap();
         ex.run_until(this_test(&ex)).unwrap();
     }
+
+    #[test]
+    fn sleep_and_periodic() {
+        async fn this_test(ex: &Executor) {
+            let dur = Duration::from_millis(50);
+            let now = Instant::now();
+            ex.sleep(dur).await.expect("failed to sleep");
+            assert!(now.elapsed() >= dur);
+
+            let t = TimerAsync::periodic(dur, ex).expect("failed to create timer");
+            for _ in 0..2 {
+                t.next_val().await.expect("unable to wait for timer");
+            }
+            assert!(now.elapsed() >= dur * 3);
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(this_test(&ex)).unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        async fn this_test(ex: &Executor) {
+            let dur = Duration::from_millis(50);
+            match with_timeout(ex, futures::future::pending::<()>(), dur).await {
+                Err(AsyncError::TimedOut) => {}
+                r => panic!("unexpected result: {:?}", r),
+            }
+            let res = with_timeout(ex, async { 5 }, Duration::from_secs(10)).await;
+            assert_eq!(res.unwrap(), 5);
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(this_test(&ex)).unwrap();
+    }
+
+    #[test]
+    fn timeout_ext() {
+        async fn this_test(ex: &Executor) {
+            let dur = Duration::from_millis(50);
+            let now = Instant::now();
+            match futures::future::pending::<()>().timeout(ex, dur).await {
+                Err(AsyncError::TimedOut) => {}
+                r => panic!("unexpected result: {:?}", r),
+            }
+            assert!(now.elapsed() >= dur);
+
+            let res = async { 5 }.timeout(ex, Duration::from_secs(10)).await;
+            assert_eq!(res.unwrap(), 5);
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(this_test(&ex)).unwrap();
+    }
 }
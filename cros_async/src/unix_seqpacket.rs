@@ -0,0 +1,282 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Async versions of `sys_util::net::UnixSeqpacket` and `UnixSeqpacketListener`.
+//!
+//! The wrapped sockets are switched to non-blocking mode. Each operation is tried directly first
+//! and only waits on the executor for the socket to become readable or writable if it would have
+//! blocked, so packets that are already queued are handled without a round trip through the
+//! executor.
+
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use sys_util::net::{UnixSeqpacket, UnixSeqpacketListener};
+use sys_util::{add_fd_flags, ScmSocket};
+
+use crate::{AsyncError, AsyncResult, Executor, IntoAsync, IoSourceExt};
+
+fn set_nonblocking<F: AsRawFd>(f: &F) -> AsyncResult<()> {
+    add_fd_flags(f.as_raw_fd(), libc::O_NONBLOCK)
+        .map_err(|e| AsyncError::Socket(io::Error::from(e)))
+}
+
+// Runs `op` until it succeeds or fails with something other than `WouldBlock`, waiting for
+// `source` to be readable in between.
+async fn until_readable<F, T>(
+    source: &dyn IoSourceExt<F>,
+    mut op: impl FnMut(&F) -> io::Result<T>,
+) -> AsyncResult<T> {
+    loop {
+        match op(source.as_source()) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => source.wait_readable().await?,
+            res => return res.map_err(AsyncError::Socket),
+        }
+    }
+}
+
+// Like `until_readable`, but waits for `source` to be writable.
+async fn until_writable<F, T>(
+    source: &dyn IoSourceExt<F>,
+    mut op: impl FnMut(&F) -> io::Result<T>,
+) -> AsyncResult<T> {
+    loop {
+        match op(source.as_source()) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => source.wait_writable().await?,
+            res => return res.map_err(AsyncError::Socket),
+        }
+    }
+}
+
+/// An async version of `sys_util::net::UnixSeqpacket`.
+pub struct UnixSeqpacketAsync {
+    io_source: Box<dyn IoSourceExt<UnixSeqpacket>>,
+}
+
+impl UnixSeqpacketAsync {
+    /// Wraps `sock` for use with `ex`. The socket is put in non-blocking mode.
+    pub fn new(sock: UnixSeqpacket, ex: &Executor) -> AsyncResult<UnixSeqpacketAsync> {
+        set_nonblocking(&sock)?;
+        ex.async_from(sock)
+            .map(|io_source| UnixSeqpacketAsync { io_source })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new_poll(
+        sock: UnixSeqpacket,
+        ex: &crate::FdExecutor,
+    ) -> AsyncResult<UnixSeqpacketAsync> {
+        set_nonblocking(&sock)?;
+        crate::executor::async_poll_from(sock, ex).map(|io_source| UnixSeqpacketAsync { io_source })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new_uring(
+        sock: UnixSeqpacket,
+        ex: &crate::URingExecutor,
+    ) -> AsyncResult<UnixSeqpacketAsync> {
+        set_nonblocking(&sock)?;
+        crate::executor::async_uring_from(sock, ex)
+            .map(|io_source| UnixSeqpacketAsync { io_source })
+    }
+
+    /// Provides a ref to the underlying socket.
+    pub fn as_source(&self) -> &UnixSeqpacket {
+        self.io_source.as_source()
+    }
+
+    /// Yields the underlying socket. It is left in non-blocking mode.
+    pub fn into_source(self) -> UnixSeqpacket {
+        self.io_source.into_source()
+    }
+
+    /// Sends `buf` as a single packet.
+    pub async fn send(&self, buf: &[u8]) -> AsyncResult<usize> {
+        until_writable(&*self.io_source, |s| s.send(buf)).await
+    }
+
+    /// Sends `buf` as a single packet along with the file descriptors in `fds`.
+    pub async fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> AsyncResult<usize> {
+        until_writable(&*self.io_source, |s| {
+            s.send_with_fds(&[IoSlice::new(buf)], fds)
+                .map_err(io::Error::from)
+        })
+        .await
+    }
+
+    /// Receives the next packet into `buf`, returning the number of bytes read. Any part of the
+    /// packet that doesn't fit in `buf` is discarded.
+    pub async fn recv(&self, buf: &mut [u8]) -> AsyncResult<usize> {
+        until_readable(&*self.io_source, |s| s.recv(buf)).await
+    }
+
+    /// Receives the next packet into a new `Vec`.
+    pub async fn recv_as_vec(&self) -> AsyncResult<Vec<u8>> {
+        until_readable(&*self.io_source, |s| s.recv_as_vec()).await
+    }
+
+    /// Receives the next packet and any file descriptors sent with it. The caller takes ownership
+    /// of the returned file descriptors.
+    pub async fn recv_as_vec_with_fds(&self) -> AsyncResult<(Vec<u8>, Vec<RawFd>)> {
+        until_readable(&*self.io_source, |s| s.recv_as_vec_with_fds()).await
+    }
+}
+
+/// An async version of `sys_util::net::UnixSeqpacketListener`.
+pub struct UnixSeqpacketListenerAsync {
+    io_source: Box<dyn IoSourceExt<UnixSeqpacketListener>>,
+}
+
+impl UnixSeqpacketListenerAsync {
+    /// Wraps `listener` for use with `ex`. The listener is put in non-blocking mode.
+    pub fn new(
+        listener: UnixSeqpacketListener,
+        ex: &Executor,
+    ) -> AsyncResult<UnixSeqpacketListenerAsync> {
+        set_nonblocking(&listener)?;
+        ex.async_from(listener)
+            .map(|io_source| UnixSeqpacketListenerAsync { io_source })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new_poll(
+        listener: UnixSeqpacketListener,
+        ex: &crate::FdExecutor,
+    ) -> AsyncResult<UnixSeqpacketListenerAsync> {
+        set_nonblocking(&listener)?;
+        crate::executor::async_poll_from(listener, ex)
+            .map(|io_source| UnixSeqpacketListenerAsync { io_source })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new_uring(
+        listener: UnixSeqpacketListener,
+        ex: &crate::URingExecutor,
+    ) -> AsyncResult<UnixSeqpacketListenerAsync> {
+        set_nonblocking(&listener)?;
+        crate::executor::async_uring_from(listener, ex)
+            .map(|io_source| UnixSeqpacketListenerAsync { io_source })
+    }
+
+    /// Provides a ref to the underlying listener.
+    pub fn as_source(&self) -> &UnixSeqpacketListener {
+        self.io_source.as_source()
+    }
+
+    /// Waits for and accepts a new connection. The returned socket is in blocking mode; wrap it
+    /// with `UnixSeqpacketAsync::new` to use it from the executor.
+    pub async fn accept(&self) -> AsyncResult<UnixSeqpacket> {
+        until_readable(&*self.io_source, |l| l.accept()).await
+    }
+}
+
+impl IntoAsync for UnixSeqpacketListener {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::{FdExecutor, URingExecutor};
+
+    async fn ping_pong(a: UnixSeqpacketAsync, b: UnixSeqpacketAsync) {
+        // `b` has nothing queued yet so this has to wait for the send below.
+        let recv = async {
+            let mut buf = [0u8; 4];
+            let n = b.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            b.send(b"pong").await.unwrap();
+        };
+        let send = async {
+            a.send(b"ping").await.unwrap();
+            assert_eq!(a.recv_as_vec().await.unwrap(), b"pong");
+        };
+        futures::join!(recv, send);
+    }
+
+    #[test]
+    fn send_recv() {
+        let (a, b) = UnixSeqpacket::pair().unwrap();
+        let ex = Executor::new().unwrap();
+        let a = UnixSeqpacketAsync::new(a, &ex).unwrap();
+        let b = UnixSeqpacketAsync::new(b, &ex).unwrap();
+        ex.run_until(ping_pong(a, b)).unwrap();
+    }
+
+    #[test]
+    fn send_recv_poll_and_ring() {
+        let (a, b) = UnixSeqpacket::pair().unwrap();
+        let uring_ex = URingExecutor::new().unwrap();
+        let a = UnixSeqpacketAsync::new_uring(a, &uring_ex).unwrap();
+        let b = UnixSeqpacketAsync::new_uring(b, &uring_ex).unwrap();
+        uring_ex.run_until(ping_pong(a, b)).unwrap();
+
+        let (a, b) = UnixSeqpacket::pair().unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let a = UnixSeqpacketAsync::new_poll(a, &poll_ex).unwrap();
+        let b = UnixSeqpacketAsync::new_poll(b, &poll_ex).unwrap();
+        poll_ex.run_until(ping_pong(a, b)).unwrap();
+    }
+
+    #[test]
+    fn send_recv_fds() {
+        async fn go(a: UnixSeqpacketAsync, b: UnixSeqpacketAsync) {
+            let (mut rx, tx) = sys_util::pipe(true).unwrap();
+            a.send_with_fds(b"fd", &[tx.as_raw_fd()]).await.unwrap();
+            drop(tx);
+
+            let (buf, fds) = b.recv_as_vec_with_fds().await.unwrap();
+            assert_eq!(buf, b"fd");
+            assert_eq!(fds.len(), 1);
+            // Safe because `fds[0]` was just received and nothing else owns it.
+            let mut tx = unsafe { File::from_raw_fd(fds[0]) };
+            std::io::Write::write_all(&mut tx, b"hi").unwrap();
+            drop(tx);
+
+            let mut out = Vec::new();
+            rx.read_to_end(&mut out).unwrap();
+            assert_eq!(out, b"hi");
+        }
+
+        let (a, b) = UnixSeqpacket::pair().unwrap();
+        let uring_ex = URingExecutor::new().unwrap();
+        let a = UnixSeqpacketAsync::new_uring(a, &uring_ex).unwrap();
+        let b = UnixSeqpacketAsync::new_uring(b, &uring_ex).unwrap();
+        uring_ex.run_until(go(a, b)).unwrap();
+
+        let (a, b) = UnixSeqpacket::pair().unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let a = UnixSeqpacketAsync::new_poll(a, &poll_ex).unwrap();
+        let b = UnixSeqpacketAsync::new_poll(b, &poll_ex).unwrap();
+        poll_ex.run_until(go(a, b)).unwrap();
+    }
+
+    #[test]
+    fn accept() {
+        async fn go(listener: UnixSeqpacketListenerAsync) {
+            let path = listener.as_source().path().unwrap();
+            // The first poll of `accept` finds no pending connection and has to wait for `connect`.
+            let (server, client) = futures::join!(listener.accept(), async {
+                UnixSeqpacket::connect(&path).unwrap()
+            });
+            client.send(b"hello").unwrap();
+            assert_eq!(server.unwrap().recv_as_vec().unwrap(), b"hello");
+        }
+
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let listener = UnixSeqpacketListener::bind(dir.path().join("uring.sock")).unwrap();
+        let uring_ex = URingExecutor::new().unwrap();
+        let listener = UnixSeqpacketListenerAsync::new_uring(listener, &uring_ex).unwrap();
+        uring_ex.run_until(go(listener)).unwrap();
+
+        let listener = UnixSeqpacketListener::bind(dir.path().join("poll.sock")).unwrap();
+        let poll_ex = FdExecutor::new().unwrap();
+        let listener = UnixSeqpacketListenerAsync::new_poll(listener, &poll_ex).unwrap();
+        poll_ex.run_until(go(listener)).unwrap();
+    }
+}
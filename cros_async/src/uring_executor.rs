@@ -27,11 +27,11 @@
 //! duration, the functions must maintain ownership of the memory.  The core of this problem is that
 //! the lifetime of the future isn't tied to the scope in which the kernel can modify the buffer the
 //! future has a reference to.  The buffer can be modified at any point from submission until the
-//! operation completes. The operation can't be synchronously canceled when the future is dropped,
-//! and Drop can't be used for safety guarantees. To ensure this never happens, only memory that
-//! implements `BackingMemory` is accepted.  For implementors of `BackingMemory` the mut borrow
-//! isn't an issue because those are already Ok with external modifications to the memory (Like a
-//! `VolatileSlice`).
+//! operation completes. Dropping the future asks the kernel to cancel the operation, but that
+//! happens asynchronously, and Drop can't be used for safety guarantees anyway. To ensure this
+//! never happens, only memory that implements `BackingMemory` is accepted.  For implementors of
+//! `BackingMemory` the mut borrow isn't an issue because those are already Ok with external
+//! modifications to the memory (Like a `VolatileSlice`).
 //!
 //! ### Buffer lifetime
 //!
@@ -41,9 +41,11 @@
 //! the executor.  The executor holds the Arc and ensures all operations are complete before dropping
 //! it, that guarantees the memory is valid for the duration.
 //!
-//! The buffers _have_ to be on the heap. Because we don't have a way to cancel a future if it is
-//! dropped(can't rely on drop running), there is no way to ensure the kernel's buffer remains valid
-//! until the operation completes unless the executor holds an Arc to the memory on the heap.
+//! The buffers _have_ to be on the heap. Because canceling an operation when its future is dropped
+//! only completes later (and drop isn't guaranteed to run), there is no way to ensure the kernel's
+//! buffer remains valid until the operation completes unless the executor holds an Arc to the
+//! memory on the heap. The Arc for a canceled operation is released once the kernel returns its
+//! completion, so futures that lose a `select!` or time out don't keep their buffers for long.
 //!
 //! ## Using `Vec` for reads/writes.
 //!
@@ -63,10 +65,11 @@ use std::sync::{Arc, Weak};
 use std::task::Waker;
 use std::task::{Context, Poll};
 use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 use async_task::Task;
 use futures::task::noop_waker;
-use io_uring::URingContext;
+use io_uring::{Feature, Features, Opcode, Probe, URingContext};
 use pin_utils::pin_mut;
 use slab::Slab;
 use sync::Mutex;
@@ -94,6 +97,9 @@ pub enum Error {
     /// Error doing the IO.
     #[error("Error during IO: {0}")]
     Io(io::Error),
+    /// Failed to register or unregister fixed buffers with the uring.
+    #[error("Error registering buffers with the URing context: {0}")]
+    RegisteringBuffers(io_uring::Error),
     /// Creating a context to wait on FDs failed.
     #[error("Error creating the fd waiting context: {0}")]
     CreatingContext(io_uring::Error),
@@ -112,14 +118,45 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+const UNKNOWN: u32 = 0;
+const URING: u32 = 1;
+const FD: u32 = 2;
+const DISABLED: u32 = 3;
+static USE_URING: AtomicU32 = AtomicU32::new(UNKNOWN);
+
+/// Stops `Executor::new` from using io_uring in this process, and in processes forked from it
+/// afterwards. Executors that already exist keep their backend.
+pub fn disable_uring() {
+    USE_URING.store(DISABLED, Ordering::Relaxed);
+}
+
+// Idle time of the SQPOLL thread in milliseconds, or 0 if SQPOLL isn't used.
+static SQPOLL_IDLE_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Makes uring executors created afterwards in this process, and in processes forked from it, use
+/// a kernel thread to poll for submissions. The thread sleeps after `idle` without new operations.
+/// This saves a syscall per submission for busy devices at the cost of CPU time. Executors fall
+/// back to normal submission if the kernel refuses to create an SQPOLL uring.
+pub fn enable_uring_sqpoll(idle: Duration) {
+    let idle_ms = idle.as_millis().max(1).min(u32::MAX as u128) as u32;
+    SQPOLL_IDLE_MS.store(idle_ms, Ordering::Relaxed);
+}
+
+fn new_uring_context() -> Result<URingContext> {
+    let idle_ms = SQPOLL_IDLE_MS.load(Ordering::Relaxed);
+    if idle_ms != 0 {
+        match URingContext::new_sqpoll(NUM_ENTRIES, Duration::from_millis(idle_ms.into())) {
+            Ok(ctx) => return Ok(ctx),
+            Err(e) => warn!("Failed to create an SQPOLL uring, not using SQPOLL: {}", e),
+        }
+    }
+    URingContext::new(NUM_ENTRIES).map_err(Error::CreatingContext)
+}
+
 // Checks if the uring executor is available.
 // Caches the result so that the check is only run once.
 // Useful for falling back to the FD executor on pre-uring kernels.
 pub(crate) fn use_uring() -> bool {
-    const UNKNOWN: u32 = 0;
-    const URING: u32 = 1;
-    const FD: u32 = 2;
-    static USE_URING: AtomicU32 = AtomicU32::new(UNKNOWN);
     match USE_URING.load(Ordering::Relaxed) {
         UNKNOWN => {
             // Create a dummy uring context to check that the kernel understands the syscalls.
@@ -132,11 +169,55 @@ pub(crate) fn use_uring() -> bool {
             }
         }
         URING => true,
-        FD => false,
+        FD | DISABLED => false,
         _ => unreachable!("invalid use uring state"),
     }
 }
 
+// Explains why `use_uring` returns false.
+pub(crate) fn uring_unavailable_reason() -> &'static str {
+    match USE_URING.load(Ordering::Relaxed) {
+        DISABLED => "io_uring was disabled",
+        _ => "the kernel doesn't support io_uring",
+    }
+}
+
+/// What io_uring supports on the running kernel. Devices can check this up front to pick a code
+/// path, e.g. whether registering buffers is worthwhile, instead of handling errors at runtime.
+#[derive(Clone, Copy, Debug)]
+pub struct UringCaps {
+    probe: Probe,
+    features: Features,
+}
+
+impl UringCaps {
+    fn new(ctx: &URingContext) -> UringCaps {
+        UringCaps {
+            probe: ctx.probe(),
+            features: ctx.features(),
+        }
+    }
+
+    /// Returns true if the kernel can run `op`.
+    pub fn supports(&self, op: Opcode) -> bool {
+        self.probe.supports(op)
+    }
+
+    /// Returns true if urings created by the kernel have `feature`.
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.has(feature)
+    }
+}
+
+/// Returns the io_uring capabilities of the running kernel, or `None` if `Executor::new` won't use
+/// io_uring in this process.
+pub fn uring_caps() -> Option<UringCaps> {
+    if !use_uring() {
+        return None;
+    }
+    URingContext::new(8).ok().map(|ctx| UringCaps::new(&ctx))
+}
+
 pub struct RegisteredSource {
     tag: usize,
     ex: Weak<RawExecutor>,
@@ -175,6 +256,33 @@ impl RegisteredSource {
         })
     }
 
+    /// Starts a write like `start_write_from_mem` and an fsync that the kernel only runs once the
+    /// write completed in full. Both are submitted together. If the write fails or is short, the
+    /// fsync fails with `ECANCELED`.
+    pub fn start_write_from_mem_then_fsync(
+        &self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        addrs: &[MemRegion],
+    ) -> Result<(PendingOperation, PendingOperation)> {
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        let (write_token, fsync_token) =
+            ex.submit_write_then_fsync(self, mem, file_offset, addrs)?;
+
+        Ok((
+            PendingOperation {
+                waker_token: Some(write_token),
+                ex: self.ex.clone(),
+                submitted: false,
+            },
+            PendingOperation {
+                waker_token: Some(fsync_token),
+                ex: self.ex.clone(),
+                submitted: false,
+            },
+        ))
+    }
+
     pub fn start_fallocate(&self, offset: u64, len: u64, mode: u32) -> Result<PendingOperation> {
         let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
         let token = ex.submit_fallocate(self, offset, len, mode)?;
@@ -197,6 +305,43 @@ impl RegisteredSource {
         })
     }
 
+    /// Starts a `statx` of the source that fills in `mem`, which must hold at least a
+    /// `libc::statx`.
+    pub fn start_statx(
+        &self,
+        mask: u32,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+    ) -> Result<PendingOperation> {
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        let token = ex.submit_statx(self, mask, mem)?;
+
+        Ok(PendingOperation {
+            waker_token: Some(token),
+            ex: self.ex.clone(),
+            submitted: false,
+        })
+    }
+
+    /// Returns true if the kernel can run `op` on the uring. Sources fall back to a synchronous
+    /// syscall for operations it can't.
+    pub fn supports(&self, op: Opcode) -> Result<bool> {
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        Ok(ex.caps.supports(op))
+    }
+
+    /// Starts watching the source for `events` with a poll that stays in the uring after it fires,
+    /// so a source that signals often doesn't need a new poll added for every event. See
+    /// `MultishotPoll::next`.
+    pub fn poll_multishot(&self, events: WatchingEvents) -> Result<MultishotPoll> {
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        let token = ex.start_multishot(self, events)?;
+
+        Ok(MultishotPoll {
+            token,
+            ex: self.ex.clone(),
+        })
+    }
+
     pub fn poll_fd_readable(&self) -> Result<PendingOperation> {
         let events = WatchingEvents::empty().set_read();
 
@@ -209,6 +354,19 @@ impl RegisteredSource {
             submitted: false,
         })
     }
+
+    pub fn poll_fd_writable(&self) -> Result<PendingOperation> {
+        let events = WatchingEvents::empty().set_write();
+
+        let ex = self.ex.upgrade().ok_or(Error::ExecutorGone)?;
+        let token = ex.submit_poll(self, &events)?;
+
+        Ok(PendingOperation {
+            waker_token: Some(token),
+            ex: self.ex.clone(),
+            submitted: false,
+        })
+    }
 }
 
 impl Drop for RegisteredSource {
@@ -241,22 +399,63 @@ struct OpData {
     canceled: bool,
 }
 
+// A poll that stays armed across events, see `MultishotPoll`.
+struct MultishotData {
+    file: Arc<File>,
+    events: WatchingEvents,
+    waker: Option<Waker>,
+    // Set by completions until `MultishotPoll::next` returns.
+    signaled: bool,
+    error: Option<io::Error>,
+    // False when there is no poll in the uring, either because the kernel ended it or because it
+    // hasn't been added yet.
+    armed: bool,
+    // Kernels before 5.13 reject multishot polls, in which case a one-shot poll is added for every
+    // call to `MultishotPoll::next` instead.
+    oneshot: bool,
+    canceled: bool,
+}
+
 // The current status of an operation that's been submitted to the uring.
 enum OpStatus {
     Nop,
     Pending(OpData),
     Completed(Option<::std::io::Result<u32>>),
+    Multishot(MultishotData),
+}
+
+// Memory registered with the uring so reads and writes to it don't have to pin it every time.
+struct FixedBuffers {
+    // Keeps the registered memory valid.
+    _mem: Arc<dyn BackingMemory + Send + Sync>,
+    // The address and length of each buffer, in the order they were registered.
+    buffers: Vec<(usize, usize)>,
+}
+
+impl FixedBuffers {
+    // Returns the index of the buffer that contains all of `iovec`, if any.
+    fn find(&self, iovec: &libc::iovec) -> Option<u16> {
+        let start = iovec.iov_base as usize;
+        let end = start.checked_add(iovec.iov_len)?;
+        self.buffers
+            .iter()
+            .position(|&(addr, len)| start >= addr && end <= addr + len)
+            .map(|index| index as u16)
+    }
 }
 
 struct Ring {
     ops: Slab<OpStatus>,
     registered_sources: Slab<Arc<File>>,
+    fixed_buffers: Option<FixedBuffers>,
 }
 
 struct RawExecutor {
     // The URingContext needs to be first so that it is dropped first, closing the uring fd, and
     // releasing the resources borrowed by the kernel before we free them.
     ctx: URingContext,
+    // What `ctx` supports.
+    caps: UringCaps,
     queue: RunnableQueue,
     ring: Mutex<Ring>,
     thread_id: Mutex<Option<ThreadId>>,
@@ -265,12 +464,16 @@ struct RawExecutor {
 
 impl RawExecutor {
     fn new() -> Result<RawExecutor> {
+        let ctx = new_uring_context()?;
+        let caps = UringCaps::new(&ctx);
         Ok(RawExecutor {
-            ctx: URingContext::new(NUM_ENTRIES).map_err(Error::CreatingContext)?,
+            ctx,
+            caps,
             queue: RunnableQueue::new(),
             ring: Mutex::new(Ring {
                 ops: Slab::with_capacity(NUM_ENTRIES),
                 registered_sources: Slab::with_capacity(NUM_ENTRIES),
+                fixed_buffers: None,
             }),
             thread_id: Mutex::new(None),
             state: AtomicI32::new(PROCESSING),
@@ -352,9 +555,11 @@ impl RawExecutor {
         loop {
             self.state.store(PROCESSING, Ordering::Release);
             self.queue.set_waker(cx.waker().clone());
+            let trace = cros_tracing::trace_event!(Executor, "uring_executor run tasks");
             for runnable in self.queue.iter() {
                 runnable.run();
             }
+            drop(trace);
 
             if let Poll::Ready(val) = done.as_mut().poll(cx) {
                 return Ok(val);
@@ -369,14 +574,19 @@ impl RawExecutor {
                 continue;
             }
 
-            let events = self.ctx.wait().map_err(Error::URingEnter)?;
+            let events = self.ctx.wait_completions().map_err(Error::URingEnter)?;
 
             // Set the state back to PROCESSING to prevent any tasks woken up by the loop below from
             // writing to the eventfd.
             self.state.store(PROCESSING, Ordering::Release);
 
             let mut ring = self.ring.lock();
-            for (raw_token, result) in events {
+            for io_uring::Completion {
+                user_data: raw_token,
+                result,
+                more,
+            } in events
+            {
                 // While the `expect()` might fail on arbitrary `u64`s, the `raw_token` was
                 // something that we originally gave to the kernel and that was created from a
                 // `usize` so we should always be able to convert it back into a `usize`.
@@ -388,6 +598,12 @@ impl RawExecutor {
                     .ops
                     .get_mut(token)
                     .expect("Received completion token for unexpected operation");
+                if let OpStatus::Multishot(data) = op {
+                    if complete_multishot(data, result, more) {
+                        ring.ops.remove(token);
+                    }
+                    continue;
+                }
                 match mem::replace(op, OpStatus::Completed(Some(result))) {
                     // No one is waiting on a Nop.
                     OpStatus::Nop => mem::drop(ring.ops.remove(token)),
@@ -404,6 +620,7 @@ impl RawExecutor {
                     OpStatus::Completed(_) => {
                         panic!("uring operation completed more than once")
                     }
+                    OpStatus::Multishot(_) => unreachable!(),
                 }
             }
         }
@@ -418,6 +635,7 @@ impl RawExecutor {
             .expect("`get_result` called on unknown operation");
         match op {
             OpStatus::Nop => panic!("`get_result` called on nop"),
+            OpStatus::Multishot(_) => panic!("`get_result` called on multishot poll"),
             OpStatus::Pending(data) => {
                 if data.canceled {
                     panic!("`get_result` called on canceled operation");
@@ -439,6 +657,7 @@ impl RawExecutor {
         if let Some(op) = ring.ops.get_mut(token.0) {
             match op {
                 OpStatus::Nop => panic!("`cancel_operation` called on nop"),
+                OpStatus::Multishot(_) => panic!("`cancel_operation` called on multishot poll"),
                 OpStatus::Pending(data) => {
                     if data.canceled {
                         panic!("uring operation canceled more than once");
@@ -449,8 +668,36 @@ impl RawExecutor {
                     data.canceled = true;
 
                     // Keep the rest of the op data as the uring might still be accessing either
-                    // the source of the backing memory so it needs to live until the kernel
-                    // completes the operation.  TODO: cancel the operation in the uring.
+                    // the source or the backing memory so it needs to live until the kernel
+                    // completes the operation. Ask the kernel to stop the operation early so that
+                    // happens soon, even if the source never becomes ready. Like a wake-up, the
+                    // cancel request's own completion isn't waited on. Kernels before 5.5 can't
+                    // cancel, so there the operation is left to complete on its own.
+                    if !self.caps.supports(Opcode::AsyncCancel) {
+                        return;
+                    }
+                    let entry = ring.ops.vacant_entry();
+                    let cancel_token = entry.key();
+                    if let Err(e) = self
+                        .ctx
+                        .add_cancel(usize_to_u64(token.0), usize_to_u64(cancel_token))
+                    {
+                        // The operation still completes on its own at some point.
+                        warn!("Failed to cancel uring operation: {}", e);
+                        return;
+                    }
+                    entry.insert(OpStatus::Nop);
+                    mem::drop(ring);
+
+                    // The executor submits the request the next time it waits, but it may already
+                    // be waiting if the operation was dropped on another thread.
+                    if !self.runs_tasks_on_current_thread() {
+                        match self.ctx.submit() {
+                            Ok(()) => {}
+                            Err(io_uring::Error::RingEnter(libc::EBUSY)) => {}
+                            Err(e) => warn!("Failed to submit uring cancel request: {}", e),
+                        }
+                    }
                 }
                 OpStatus::Completed(_) => {
                     ring.ops.remove(token.0);
@@ -459,7 +706,56 @@ impl RawExecutor {
         }
     }
 
+    fn register_buffers(
+        &self,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        regions: &[MemRegion],
+    ) -> Result<()> {
+        let iovecs = regions
+            .iter()
+            .map(|&mem_range| {
+                mem.get_iovec(mem_range)
+                    .map(|iovec| iovec.iovec())
+                    .map_err(|_| Error::InvalidOffset)
+            })
+            .collect::<Result<Vec<libc::iovec>>>()?;
+
+        let mut ring = self.ring.lock();
+        unsafe {
+            // Safe because the memory stays valid as long as `ring.fixed_buffers` holds the Arc,
+            // which is only dropped after the buffers are replaced or unregistered.
+            self.ctx
+                .register_buffers(&iovecs)
+                .map_err(Error::RegisteringBuffers)?;
+        }
+        ring.fixed_buffers = Some(FixedBuffers {
+            _mem: mem,
+            buffers: iovecs
+                .iter()
+                .map(|iovec| (iovec.iov_base as usize, iovec.iov_len))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    fn unregister_buffers(&self) -> Result<()> {
+        let mut ring = self.ring.lock();
+        if ring.fixed_buffers.is_some() {
+            self.ctx
+                .unregister_buffers()
+                .map_err(Error::RegisteringBuffers)?;
+            // Operations that used the buffers hold their own reference to the memory.
+            ring.fixed_buffers = None;
+        }
+        Ok(())
+    }
+
     fn register_source(&self, f: Arc<File>) -> usize {
+        // Only SQPOLL urings have a file table. Ops on files that aren't in it still work on newer
+        // kernels.
+        if let Err(e) = self.ctx.register_file(f.as_raw_fd()) {
+            warn!("Failed to register file with the uring: {}", e);
+        }
         self.ring.lock().registered_sources.insert(f)
     }
 
@@ -467,7 +763,10 @@ impl RawExecutor {
         // There isn't any need to pull pending ops out, the all have Arc's to the file and mem they
         // need.let them complete. deregister with pending ops is not a common path no need to
         // optimize that case yet.
-        self.ring.lock().registered_sources.remove(source.tag);
+        let f = self.ring.lock().registered_sources.remove(source.tag);
+        if let Err(e) = self.ctx.unregister_file(f.as_raw_fd()) {
+            warn!("Failed to unregister file from the uring: {}", e);
+        }
     }
 
     fn submit_poll(
@@ -497,6 +796,106 @@ impl RawExecutor {
         Ok(WakerToken(next_op_token))
     }
 
+    fn start_multishot(&self, source: &RegisteredSource, events: WatchingEvents) -> Result<usize> {
+        let mut ring = self.ring.lock();
+        let file = ring
+            .registered_sources
+            .get(source.tag)
+            .map(Arc::clone)
+            .ok_or(Error::InvalidSource)?;
+        // The poll is added by the first call to `MultishotPoll::next`.
+        Ok(ring.ops.insert(OpStatus::Multishot(MultishotData {
+            file,
+            events,
+            waker: None,
+            signaled: false,
+            error: None,
+            armed: false,
+            oneshot: false,
+            canceled: false,
+        })))
+    }
+
+    fn poll_multishot(&self, token: usize, cx: &mut Context) -> Poll<Result<()>> {
+        let mut ring = self.ring.lock();
+        let data = match ring.ops.get_mut(token) {
+            Some(OpStatus::Multishot(data)) => data,
+            _ => panic!("`poll_multishot` called on unknown operation"),
+        };
+        if let Some(e) = data.error.take() {
+            return Poll::Ready(Err(Error::Io(e)));
+        }
+        if data.signaled {
+            data.signaled = false;
+            return Poll::Ready(Ok(()));
+        }
+        data.waker = Some(cx.waker().clone());
+        if data.armed {
+            return Poll::Pending;
+        }
+
+        let fd = data.file.as_raw_fd();
+        let user_data = usize_to_u64(token);
+        let res = if data.oneshot {
+            self.ctx.add_poll_fd(fd, &data.events, user_data)
+        } else {
+            self.ctx.add_poll_fd_multishot(fd, &data.events, user_data)
+        };
+        if let Err(e) = res {
+            return Poll::Ready(Err(Error::SubmittingOp(e)));
+        }
+        data.armed = true;
+        mem::drop(ring);
+
+        // Like `PendingOperation`, submit now if the executor could already be waiting.
+        if !self.runs_tasks_on_current_thread() {
+            match self.ctx.submit() {
+                Ok(()) | Err(io_uring::Error::RingEnter(libc::EBUSY)) => {}
+                Err(e) => return Poll::Ready(Err(Error::URingEnter(e))),
+            }
+        }
+        Poll::Pending
+    }
+
+    fn cancel_multishot(&self, token: usize) {
+        let mut ring = self.ring.lock();
+        let data = match ring.ops.get_mut(token) {
+            Some(OpStatus::Multishot(data)) => data,
+            _ => panic!("`cancel_multishot` called on unknown operation"),
+        };
+        if !data.armed {
+            ring.ops.remove(token);
+            return;
+        }
+        // The entry is removed when the kernel completes the poll for the last time. Without
+        // cancel support that happens the next time the source signals, or never for a multishot
+        // poll, which is no worse than leaking the poll until the executor is dropped.
+        data.waker = None;
+        data.canceled = true;
+        if !self.caps.supports(Opcode::AsyncCancel) {
+            return;
+        }
+
+        let entry = ring.ops.vacant_entry();
+        let cancel_token = entry.key();
+        if let Err(e) = self
+            .ctx
+            .add_cancel(usize_to_u64(token), usize_to_u64(cancel_token))
+        {
+            warn!("Failed to cancel uring poll: {}", e);
+            return;
+        }
+        entry.insert(OpStatus::Nop);
+        mem::drop(ring);
+
+        if !self.runs_tasks_on_current_thread() {
+            match self.ctx.submit() {
+                Ok(()) | Err(io_uring::Error::RingEnter(libc::EBUSY)) => {}
+                Err(e) => warn!("Failed to submit uring cancel request: {}", e),
+            }
+        }
+    }
+
     fn submit_fallocate(
         &self,
         source: &RegisteredSource,
@@ -555,6 +954,52 @@ impl RawExecutor {
         Ok(WakerToken(next_op_token))
     }
 
+    fn submit_statx(
+        &self,
+        source: &RegisteredSource,
+        mask: u32,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+    ) -> Result<WakerToken> {
+        let statx_buf = mem
+            .get_iovec(MemRegion {
+                offset: 0,
+                len: mem::size_of::<libc::statx>(),
+            })
+            .map_err(|_| Error::InvalidOffset)?
+            .iovec()
+            .iov_base as *mut libc::statx;
+
+        let mut ring = self.ring.lock();
+        let src = ring
+            .registered_sources
+            .get(source.tag)
+            .map(Arc::clone)
+            .ok_or(Error::InvalidSource)?;
+        let entry = ring.ops.vacant_entry();
+        let next_op_token = entry.key();
+        unsafe {
+            // Safe because the buffer is within the Memory that an Arc is kept for until the
+            // operation completes.
+            self.ctx
+                .add_statx(
+                    src.as_raw_fd(),
+                    mask,
+                    statx_buf,
+                    usize_to_u64(next_op_token),
+                )
+                .map_err(Error::SubmittingOp)?;
+        }
+
+        entry.insert(OpStatus::Pending(OpData {
+            _file: src,
+            _mem: Some(mem),
+            waker: None,
+            canceled: false,
+        }));
+
+        Ok(WakerToken(next_op_token))
+    }
+
     fn submit_read_to_vectored(
         &self,
         source: &RegisteredSource,
@@ -576,6 +1021,15 @@ impl RawExecutor {
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
 
+        // A single buffer inside registered memory can skip pinning the pages.
+        let fixed = match (addrs, &ring.fixed_buffers) {
+            ([mem_range], Some(fixed_buffers)) => {
+                let iovec = mem.get_iovec(*mem_range).unwrap().iovec();
+                fixed_buffers.find(&iovec).map(|index| (iovec, index))
+            }
+            _ => None,
+        };
+
         // We can't insert the OpData into the slab yet because `iovecs` borrows `mem` below.
         let entry = ring.ops.vacant_entry();
         let next_op_token = entry.key();
@@ -590,9 +1044,23 @@ impl RawExecutor {
             // Safe because all the addresses are within the Memory that an Arc is kept for the
             // duration to ensure the memory is valid while the kernel accesses it.
             // Tested by `dont_drop_backing_mem_read` unit test.
-            self.ctx
-                .add_readv_iter(iovecs, src.as_raw_fd(), offset, usize_to_u64(next_op_token))
-                .map_err(Error::SubmittingOp)?;
+            match fixed {
+                Some((iovec, index)) => self.ctx.add_read_fixed(
+                    iovec.iov_base as *mut u8,
+                    iovec.iov_len,
+                    src.as_raw_fd(),
+                    offset,
+                    index,
+                    usize_to_u64(next_op_token),
+                ),
+                None => self.ctx.add_readv_iter(
+                    iovecs,
+                    src.as_raw_fd(),
+                    offset,
+                    usize_to_u64(next_op_token),
+                ),
+            }
+            .map_err(Error::SubmittingOp)?;
         }
 
         entry.insert(OpStatus::Pending(OpData {
@@ -626,6 +1094,15 @@ impl RawExecutor {
             .map(Arc::clone)
             .ok_or(Error::InvalidSource)?;
 
+        // A single buffer inside registered memory can skip pinning the pages.
+        let fixed = match (addrs, &ring.fixed_buffers) {
+            ([mem_range], Some(fixed_buffers)) => {
+                let iovec = mem.get_iovec(*mem_range).unwrap().iovec();
+                fixed_buffers.find(&iovec).map(|index| (iovec, index))
+            }
+            _ => None,
+        };
+
         // We can't insert the OpData into the slab yet because `iovecs` borrows `mem` below.
         let entry = ring.ops.vacant_entry();
         let next_op_token = entry.key();
@@ -640,9 +1117,23 @@ impl RawExecutor {
             // Safe because all the addresses are within the Memory that an Arc is kept for the
             // duration to ensure the memory is valid while the kernel accesses it.
             // Tested by `dont_drop_backing_mem_write` unit test.
-            self.ctx
-                .add_writev_iter(iovecs, src.as_raw_fd(), offset, usize_to_u64(next_op_token))
-                .map_err(Error::SubmittingOp)?;
+            match fixed {
+                Some((iovec, index)) => self.ctx.add_write_fixed(
+                    iovec.iov_base as *const u8,
+                    iovec.iov_len,
+                    src.as_raw_fd(),
+                    offset,
+                    index,
+                    usize_to_u64(next_op_token),
+                ),
+                None => self.ctx.add_writev_iter(
+                    iovecs,
+                    src.as_raw_fd(),
+                    offset,
+                    usize_to_u64(next_op_token),
+                ),
+            }
+            .map_err(Error::SubmittingOp)?;
         }
 
         entry.insert(OpStatus::Pending(OpData {
@@ -654,6 +1145,65 @@ impl RawExecutor {
 
         Ok(WakerToken(next_op_token))
     }
+
+    fn submit_write_then_fsync(
+        &self,
+        source: &RegisteredSource,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        offset: u64,
+        addrs: &[MemRegion],
+    ) -> Result<(WakerToken, WakerToken)> {
+        if addrs
+            .iter()
+            .any(|&mem_range| mem.get_iovec(mem_range).is_err())
+        {
+            return Err(Error::InvalidOffset);
+        }
+
+        let mut ring = self.ring.lock();
+        let src = ring
+            .registered_sources
+            .get(source.tag)
+            .map(Arc::clone)
+            .ok_or(Error::InvalidSource)?;
+
+        let write_token = ring.ops.insert(OpStatus::Pending(OpData {
+            _file: src.clone(),
+            _mem: Some(mem.clone()),
+            waker: None,
+            canceled: false,
+        }));
+        let fsync_token = ring.ops.insert(OpStatus::Pending(OpData {
+            _file: src.clone(),
+            _mem: None,
+            waker: None,
+            canceled: false,
+        }));
+
+        // The addresses have already been validated, so unwrapping them will succeed.
+        let iovecs = addrs
+            .iter()
+            .map(|&mem_range| mem.get_iovec(mem_range).unwrap().iovec());
+
+        let res = unsafe {
+            // Safe because all the addresses are within the Memory that an Arc is kept for the
+            // duration to ensure the memory is valid while the kernel accesses it.
+            self.ctx.add_writev_iter_then_fsync(
+                iovecs,
+                src.as_raw_fd(),
+                offset,
+                usize_to_u64(write_token),
+                usize_to_u64(fsync_token),
+            )
+        };
+        if let Err(e) = res {
+            ring.ops.remove(write_token);
+            ring.ops.remove(fsync_token);
+            return Err(Error::SubmittingOp(e));
+        }
+
+        Ok((WakerToken(write_token), WakerToken(fsync_token)))
+    }
 }
 
 impl WeakWake for RawExecutor {
@@ -684,6 +1234,12 @@ impl Drop for RawExecutor {
                     data.canceled = true;
                 }
                 OpStatus::Completed(_) => {}
+                OpStatus::Multishot(data) => {
+                    if let Some(waker) = data.waker.take() {
+                        waker.wake();
+                    }
+                    data.canceled = true;
+                }
             }
         }
 
@@ -733,6 +1289,11 @@ impl URingExecutor {
         self.raw.spawn_local(f)
     }
 
+    /// Returns what the uring backing this executor supports.
+    pub fn caps(&self) -> UringCaps {
+        self.raw.caps
+    }
+
     pub fn run(&self) -> Result<()> {
         let waker = new_waker(Arc::downgrade(&self.raw));
         let mut cx = Context::from_waker(&waker);
@@ -746,6 +1307,26 @@ impl URingExecutor {
         self.raw.run(&mut ctx, f)
     }
 
+    /// Registers `regions` of `mem` with the uring, replacing any memory registered before. Reads
+    /// and writes to a single region of memory that lies within a registered region then skip
+    /// pinning the memory on every operation. Useful for guest memory that is used for most I/O.
+    ///
+    /// Registered memory counts against the locked memory limit, and each region may be at most
+    /// 1 GiB. On kernels before 5.13 this waits for pending operations to complete, so it should be
+    /// called before starting any.
+    pub fn register_buffers(
+        &self,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        regions: &[MemRegion],
+    ) -> Result<()> {
+        self.raw.register_buffers(mem, regions)
+    }
+
+    /// Unregisters the memory added with `register_buffers`.
+    pub fn unregister_buffers(&self) -> Result<()> {
+        self.raw.unregister_buffers()
+    }
+
     /// Register a file and memory pair for buffered asynchronous operation.
     pub(crate) fn register_source<F: AsRawFd>(&self, fd: &F) -> Result<RegisteredSource> {
         let duped_fd = unsafe {
@@ -772,12 +1353,62 @@ unsafe fn dup_fd(fd: RawFd) -> Result<RawFd> {
     }
 }
 
+// Records a completion of a multishot poll and wakes the task waiting on it. Returns true once the
+// poll is finished with and its entry can be removed.
+fn complete_multishot(data: &mut MultishotData, result: io::Result<u32>, more: bool) -> bool {
+    if !more {
+        data.armed = false;
+    }
+    if data.canceled {
+        return !more;
+    }
+    match result {
+        Ok(_) => data.signaled = true,
+        // Kernels before 5.13 reject multishot polls. Retry with a one-shot poll.
+        Err(e) if !data.oneshot && e.raw_os_error() == Some(libc::EINVAL) => data.oneshot = true,
+        Err(e) => data.error = Some(e),
+    }
+    if let Some(waker) = data.waker.take() {
+        waker.wake();
+    }
+    false
+}
+
 // Converts a `usize` into a `u64` and panics if the conversion fails.
 #[inline]
 fn usize_to_u64(val: usize) -> u64 {
     val.try_into().expect("`usize` doesn't fit inside a `u64`")
 }
 
+/// A poll that stays in the uring after it fires, created by `RegisteredSource::poll_multishot`.
+/// Dropping it removes the poll.
+pub struct MultishotPoll {
+    token: usize,
+    ex: Weak<RawExecutor>,
+}
+
+impl MultishotPoll {
+    /// Waits until the source signals, or returns right away if it has signaled since the last
+    /// call returned. Several signals in between are reported once. The first call also returns if
+    /// the source is already ready, but later calls don't check that it still is, so callers
+    /// should handle everything that is pending, e.g. read the eventfd, before calling it again.
+    pub async fn next(&self) -> Result<()> {
+        futures::future::poll_fn(|cx| match self.ex.upgrade() {
+            Some(ex) => ex.poll_multishot(self.token, cx),
+            None => Poll::Ready(Err(Error::ExecutorGone)),
+        })
+        .await
+    }
+}
+
+impl Drop for MultishotPoll {
+    fn drop(&mut self) {
+        if let Some(ex) = self.ex.upgrade() {
+            ex.cancel_multishot(self.token);
+        }
+    }
+}
+
 pub struct PendingOperation {
     waker_token: Option<WakerToken>,
     ex: Weak<RawExecutor>,
@@ -939,6 +1570,34 @@ mod tests {
         assert_eq!(Arc::strong_count(&bm), 1);
     }
 
+    #[test]
+    fn cancel_releases_backing_mem() {
+        let bm =
+            Arc::new(VecIoWrapper::from(vec![0u8; 4096])) as Arc<dyn BackingMemory + Send + Sync>;
+
+        // Nothing is ever written to the pipe so the read only completes when it is canceled.
+        let (rx, _tx) = sys_util::pipe(true).unwrap();
+
+        let ex = URingExecutor::new().unwrap();
+        let registered_source = ex.register_source(&rx).expect("register source failed");
+        // Without cancel support the read never completes.
+        if !registered_source.supports(Opcode::AsyncCancel).unwrap() {
+            return;
+        }
+        let pending_op = registered_source
+            .start_read_to_mem(0, Arc::clone(&bm), &[MemRegion { offset: 0, len: 8 }])
+            .expect("failed to start read to mem");
+
+        // The kernel may still hold the memory right after the drop.
+        drop(pending_op);
+        assert_eq!(Arc::strong_count(&bm), 2);
+
+        // Once the cancel request is processed, the executor releases the memory.
+        ex.run_until(UringQueueEmpty { ex: &ex })
+            .expect("Failed to wait for the canceled read");
+        assert_eq!(Arc::strong_count(&bm), 1);
+    }
+
     #[test]
     fn canceled_before_completion() {
         async fn cancel_io(op: PendingOperation) {
@@ -977,6 +1636,70 @@ mod tests {
             .expect("Failed to run executor");
     }
 
+    #[test]
+    fn fixed_buffers() {
+        let mut data = vec![0xa5u8; 4096];
+        data.extend_from_slice(&[0u8; 4096]);
+        let bm = Arc::new(VecIoWrapper::from(data));
+
+        let f = tempfile::tempfile().unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let registered_source = ex.register_source(&f).expect("register source failed");
+        ex.register_buffers(
+            bm.clone(),
+            &[MemRegion {
+                offset: 0,
+                len: 8192,
+            }],
+        )
+        .expect("failed to register buffers");
+
+        // Write the first half of the buffer and read it back into the second half.
+        let op = registered_source
+            .start_write_from_mem(
+                0,
+                bm.clone(),
+                &[MemRegion {
+                    offset: 0,
+                    len: 4096,
+                }],
+            )
+            .expect("failed to start write from mem");
+        assert_eq!(ex.run_until(op).unwrap().unwrap(), 4096);
+        let op = registered_source
+            .start_read_to_mem(
+                0,
+                bm.clone(),
+                &[MemRegion {
+                    offset: 4096,
+                    len: 4096,
+                }],
+            )
+            .expect("failed to start read to mem");
+        assert_eq!(ex.run_until(op).unwrap().unwrap(), 4096);
+
+        // Unregistering drops the executor's reference to the memory.
+        ex.unregister_buffers()
+            .expect("failed to unregister buffers");
+        let data: Vec<u8> = match Arc::try_unwrap(bm) {
+            Ok(bm) => bm.into(),
+            Err(_) => panic!("too many refs on buf"),
+        };
+        assert_eq!(data[..4096], data[4096..]);
+    }
+
+    #[test]
+    fn caps() {
+        let caps = uring_caps().expect("io_uring not available");
+        let ex = URingExecutor::new().unwrap();
+        for op in &[Opcode::ReadFixed, Opcode::AsyncCancel, Opcode::Statx] {
+            assert_eq!(caps.supports(*op), ex.caps().supports(*op));
+        }
+        // Registered buffers work on every kernel with io_uring.
+        assert!(caps.supports(Opcode::ReadFixed) && caps.supports(Opcode::WriteFixed));
+        assert!(caps.has_feature(Feature::SingleMmap));
+    }
+
     #[test]
     fn drop_before_completion() {
         const VALUE: u64 = 0xef6c_a8df_b842_eb9c;
@@ -1054,4 +1777,33 @@ mod tests {
             e => panic!("Unexpected error after dropping executor: {}", e),
         }
     }
+
+    #[test]
+    fn multishot_poll() {
+        async fn go(evt: &sys_util::EventFd, poll: &MultishotPoll) {
+            // Signaled before waiting.
+            evt.write(1).unwrap();
+            poll.next().await.unwrap();
+            assert_eq!(evt.read().unwrap(), 1);
+
+            // Signaled while waiting, without adding the poll again.
+            for i in 2..4 {
+                let (res, _) = futures::join!(poll.next(), async { evt.write(i).unwrap() });
+                res.unwrap();
+                assert_eq!(evt.read().unwrap(), i);
+            }
+        }
+
+        let evt = sys_util::EventFd::new().unwrap();
+        let ex = URingExecutor::new().unwrap();
+        let source = ex.register_source(&evt).unwrap();
+        let poll = source
+            .poll_multishot(WatchingEvents::empty().set_read())
+            .unwrap();
+        ex.run_until(go(&evt, &poll)).unwrap();
+
+        // Dropping the poll removes it from the uring.
+        drop(poll);
+        ex.run_until(UringQueueEmpty { ex: &ex }).unwrap();
+    }
 }
@@ -4,17 +4,25 @@
 
 use std::convert::TryInto;
 use std::io;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::AsRawFd;
+use std::ptr;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use io_uring::Opcode;
+use sys_util::WatchingEvents;
 
-use crate::uring_executor::{Error, RegisteredSource, Result, URingExecutor};
+use crate::uring_executor::{Error, MultishotPoll, RegisteredSource, Result, URingExecutor};
 use crate::uring_mem::{BackingMemory, MemRegion, VecIoWrapper};
 use crate::AsyncError;
 use crate::AsyncResult;
 
+// An offset of -1 makes io_uring use, and advance, the file's current position. Non-seekable files
+// ignore the offset entirely.
+const CURRENT_POSITION: u64 = u64::MAX;
+
 /// `UringSource` wraps FD backed IO sources for use with io_uring. It is a thin wrapper around
 /// registering an IO source with the uring that provides an `IoSource` implementation.
 /// Most useful functions are provided by 'IoSourceExt'.
@@ -57,6 +65,12 @@ impl<F: AsRawFd> UringSource<F> {
     pub fn into_source(self) -> F {
         self.source
     }
+
+    /// Starts a poll for `events` on the source that stays in the uring across signals, for
+    /// sources like eventfds that are signaled often. See `MultishotPoll::next`.
+    pub fn poll_multishot(&self, events: WatchingEvents) -> Result<MultishotPoll> {
+        self.registered_source.poll_multishot(events)
+    }
 }
 
 #[async_trait(?Send)]
@@ -86,6 +100,11 @@ impl<F: AsRawFd> crate::ReadAsync for UringSource<F> {
         Ok((len as usize, bytes))
     }
 
+    /// Reads from the iosource at its current position and fills the given `vec`.
+    async fn read_to_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> AsyncResult<(usize, Vec<u8>)> {
+        self.read_to_vec(CURRENT_POSITION, vec).await
+    }
+
     /// Wait for the FD of `self` to be readable.
     async fn wait_readable(&self) -> AsyncResult<()> {
         let op = self.registered_source.poll_fd_readable()?;
@@ -166,6 +185,11 @@ impl<F: AsRawFd> crate::WriteAsync for UringSource<F> {
         Ok((len as usize, bytes))
     }
 
+    /// Writes from the given `vec` to the file at its current position.
+    async fn write_from_vec_streaming<'a>(&'a self, vec: Vec<u8>) -> AsyncResult<(usize, Vec<u8>)> {
+        self.write_from_vec(CURRENT_POSITION, vec).await
+    }
+
     /// Writes from the given `mem` from the given offsets to the file starting at `file_offset`.
     async fn write_from_mem<'a>(
         &'a self,
@@ -180,8 +204,14 @@ impl<F: AsRawFd> crate::WriteAsync for UringSource<F> {
         Ok(len as usize)
     }
 
-    /// See `fallocate(2)`. Note this op is synchronous when using the Polled backend.
+    /// See `fallocate(2)`. Falls back to a synchronous `fallocate` on kernels whose io_uring doesn't
+    /// support it.
     async fn fallocate(&self, file_offset: u64, len: u64, mode: u32) -> AsyncResult<()> {
+        // Kernels before 5.6 don't support the opcode.
+        if !self.registered_source.supports(Opcode::Fallocate)? {
+            return fallocate(&self.source, file_offset, len, mode)
+                .map_err(|e| Error::Io(e).into());
+        }
         let op = self
             .registered_source
             .start_fallocate(file_offset, len, mode)?;
@@ -195,6 +225,35 @@ impl<F: AsRawFd> crate::WriteAsync for UringSource<F> {
         let _ = op.await?;
         Ok(())
     }
+
+    /// Writes from `mem` and then syncs the file, with a single submission.
+    async fn write_from_mem_then_fsync<'a>(
+        &'a self,
+        file_offset: u64,
+        mem: Arc<dyn BackingMemory + Send + Sync>,
+        mem_offsets: &'a [MemRegion],
+    ) -> AsyncResult<usize> {
+        let (write, fsync) = self.registered_source.start_write_from_mem_then_fsync(
+            file_offset,
+            mem,
+            mem_offsets,
+        )?;
+        let len = write.await?;
+        match fsync.await {
+            Ok(_) => {}
+            // A short write breaks the link. Sync anyway so callers get the same guarantee.
+            Err(Error::Io(e)) if e.raw_os_error() == Some(libc::ECANCELED) => self.fsync().await?,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(len as usize)
+    }
+
+    /// Wait for the FD of `self` to be writable.
+    async fn wait_writable(&self) -> AsyncResult<()> {
+        let op = self.registered_source.poll_fd_writable()?;
+        op.await?;
+        Ok(())
+    }
 }
 
 #[async_trait(?Send)]
@@ -213,6 +272,88 @@ impl<F: AsRawFd> crate::IoSourceExt<F> for UringSource<F> {
     fn as_source_mut(&mut self) -> &mut F {
         &mut self.source
     }
+
+    /// Gets the attributes of the source with `IORING_OP_STATX`, or a synchronous `fstat` if the
+    /// kernel doesn't support it.
+    async fn stat(&self) -> AsyncResult<libc::stat64> {
+        // Kernels before 5.6 don't support the opcode.
+        if !self.registered_source.supports(Opcode::Statx)? {
+            return fstat(&self.source).map_err(|e| Error::Io(e).into());
+        }
+        let buf = Arc::new(VecIoWrapper::from(vec![0u8; mem::size_of::<libc::statx>()]));
+        let op = self
+            .registered_source
+            .start_statx(libc::STATX_BASIC_STATS, buf.clone())?;
+        let _ = op.await?;
+        let bytes: Vec<u8> = if let Ok(v) = Arc::try_unwrap(buf) {
+            v.into()
+        } else {
+            panic!("too many refs on buf");
+        };
+        // Safe because the buffer is large enough for a `statx` and any bit pattern is valid.
+        let stx: libc::statx = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const _) };
+        Ok(statx_to_stat64(&stx))
+    }
+}
+
+fn fstat<F: AsRawFd>(source: &F) -> io::Result<libc::stat64> {
+    // Safe because this is only used as an out parameter and any bit pattern is valid.
+    let mut st: libc::stat64 = unsafe { mem::zeroed() };
+    // Safe because the kernel only writes to `st` and the return value is checked.
+    let ret = unsafe { libc::fstat64(source.as_raw_fd(), &mut st) };
+    if ret == 0 {
+        Ok(st)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn fallocate<F: AsRawFd>(source: &F, file_offset: u64, len: u64, mode: u32) -> io::Result<()> {
+    // Safe because this doesn't touch memory and the return value is checked.
+    let ret = unsafe {
+        libc::fallocate64(
+            source.as_raw_fd(),
+            mode as libc::c_int,
+            file_offset as libc::off64_t,
+            len as libc::off64_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Same encoding as glibc's `makedev`.
+fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+fn statx_to_stat64(stx: &libc::statx) -> libc::stat64 {
+    // Safe because `stat64` is plain data and the padding fields must be zero anyway.
+    let mut st: libc::stat64 = unsafe { mem::zeroed() };
+    st.st_dev = makedev(stx.stx_dev_major, stx.stx_dev_minor) as _;
+    st.st_ino = stx.stx_ino as _;
+    st.st_nlink = stx.stx_nlink as _;
+    st.st_mode = stx.stx_mode as _;
+    st.st_uid = stx.stx_uid;
+    st.st_gid = stx.stx_gid;
+    st.st_rdev = makedev(stx.stx_rdev_major, stx.stx_rdev_minor) as _;
+    st.st_size = stx.stx_size as _;
+    st.st_blksize = stx.stx_blksize as _;
+    st.st_blocks = stx.stx_blocks as _;
+    st.st_atime = stx.stx_atime.tv_sec as _;
+    st.st_atime_nsec = stx.stx_atime.tv_nsec as _;
+    st.st_mtime = stx.stx_mtime.tv_sec as _;
+    st.st_mtime_nsec = stx.stx_mtime.tv_nsec as _;
+    st.st_ctime = stx.stx_ctime.tv_sec as _;
+    st.st_ctime_nsec = stx.stx_ctime.tv_nsec as _;
+    st
 }
 
 impl<F: AsRawFd> Deref for UringSource<F> {
@@ -235,7 +376,7 @@ mod tests {
     use std::os::unix::io::AsRawFd;
     use std::path::PathBuf;
 
-    use crate::io_ext::{ReadAsync, WriteAsync};
+    use crate::io_ext::{IoSourceExt, ReadAsync, WriteAsync};
     use crate::UringSource;
 
     use super::*;
@@ -510,6 +651,25 @@ mod tests {
         ex.run_until(go(&ex)).unwrap();
     }
 
+    #[test]
+    fn stat() {
+        async fn go(ex: &URingExecutor) {
+            let f = tempfile::tempfile().unwrap();
+            f.set_len(4096).unwrap();
+            let source = UringSource::new(f, ex).unwrap();
+            let st = source.stat().await.unwrap();
+            let expected = fstat(&source.source).unwrap();
+            assert_eq!(st.st_size, 4096);
+            assert_eq!(st.st_dev, expected.st_dev);
+            assert_eq!(st.st_ino, expected.st_ino);
+            assert_eq!(st.st_mode, expected.st_mode);
+            assert_eq!(st.st_mtime, expected.st_mtime);
+        }
+
+        let ex = URingExecutor::new().unwrap();
+        ex.run_until(go(&ex)).unwrap();
+    }
+
     #[test]
     fn wait_read() {
         async fn go(ex: &URingExecutor) {
@@ -544,6 +704,33 @@ mod tests {
         ex.run_until(go(&ex)).unwrap();
     }
 
+    #[test]
+    fn writemem_then_fsync() {
+        async fn go(ex: &URingExecutor) {
+            let f = tempfile::tempfile().unwrap();
+            let source = UringSource::new(f, ex).unwrap();
+            let vw = Arc::new(crate::uring_mem::VecIoWrapper::from(vec![0x55u8; 64]));
+            let ret = source
+                .write_from_mem_then_fsync(0, vw, &[MemRegion { offset: 0, len: 32 }])
+                .await
+                .unwrap();
+            assert_eq!(32, ret);
+            assert_eq!(fstat(&source.source).unwrap().st_size, 32);
+
+            // A failed write fails the whole chain.
+            let f = File::open("/dev/zero").unwrap();
+            let source = UringSource::new(f, ex).unwrap();
+            let vw = Arc::new(crate::uring_mem::VecIoWrapper::from(vec![0x55u8; 64]));
+            assert!(source
+                .write_from_mem_then_fsync(0, vw, &[MemRegion { offset: 0, len: 32 }])
+                .await
+                .is_err());
+        }
+
+        let ex = URingExecutor::new().unwrap();
+        ex.run_until(go(&ex)).unwrap();
+    }
+
     #[test]
     fn writevec() {
         async fn go(ex: &URingExecutor) {
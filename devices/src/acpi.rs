@@ -9,6 +9,7 @@ use base::{error, warn, Event};
 /// ACPI PM resource for handling OS suspend/resume request
 pub struct ACPIPMResource {
     suspend_evt: Event,
+    sleep_evt: Event,
     exit_evt: Event,
     pm1_status: u16,
     pm1_enable: u16,
@@ -18,10 +19,12 @@ pub struct ACPIPMResource {
 }
 
 impl ACPIPMResource {
-    /// Constructs ACPI Power Management Resouce.
-    pub fn new(suspend_evt: Event, exit_evt: Event) -> ACPIPMResource {
+    /// Constructs ACPI Power Management Resouce. `suspend_evt` is signaled when the guest enters
+    /// S1, and `sleep_evt` when it enters S3.
+    pub fn new(suspend_evt: Event, sleep_evt: Event, exit_evt: Event) -> ACPIPMResource {
         ACPIPMResource {
             suspend_evt,
+            sleep_evt,
             exit_evt,
             pm1_status: 0,
             pm1_enable: 0,
@@ -30,6 +33,19 @@ impl ACPIPMResource {
             sleep_status: 0,
         }
     }
+
+    // Signals the event for the sleep state the guest asked for with `sleep_type`, the SLP_TYP
+    // value it took from `_S1_`, `_S3_` or `_S5_`.
+    fn enter_sleep_state(&self, sleep_type: u16) {
+        let (evt, name) = match sleep_type {
+            SLEEP_TYPE_S5 => (&self.exit_evt, "exit"),
+            SLEEP_TYPE_S3 => (&self.sleep_evt, "sleep"),
+            _ => (&self.suspend_evt, "suspend"),
+        };
+        if let Err(e) = evt.write(1) {
+            error!("ACPIPM: failed to trigger {} event: {}", name, e);
+        }
+    }
 }
 
 /// the ACPI PM register length.
@@ -49,6 +65,8 @@ const BITMASK_PM1CNT_WAKE_STATUS: u16 = 0x8000;
 const BITMASK_SLEEPCNT_WAKE_STATUS: u8 = 0x80;
 
 const BITMASK_PM1CNT_SLEEP_TYPE: u16 = 0x1C00;
+const BITMASK_SLEEPCNT_SLEEP_TYPE: u8 = 0x1C;
+const SLEEP_TYPE_S3: u16 = 3;
 const SLEEP_TYPE_S5: u16 = 0;
 
 impl BusDevice for ACPIPMResource {
@@ -99,15 +117,7 @@ impl BusDevice for ACPIPMResource {
             PM1_ENABLE => self.pm1_enable = val,
             PM1_CONTROL => {
                 if (val & BITMASK_PM1CNT_SLEEP_ENABLE) == BITMASK_PM1CNT_SLEEP_ENABLE {
-                    if val & BITMASK_PM1CNT_SLEEP_TYPE == SLEEP_TYPE_S5 {
-                        if let Err(e) = self.exit_evt.write(1) {
-                            error!("ACPIPM: failed to trigger exit event: {}", e);
-                        }
-                    } else {
-                        if let Err(e) = self.suspend_evt.write(1) {
-                            error!("ACPIPM: failed to trigger suspend event: {}", e);
-                        }
-                    }
+                    self.enter_sleep_state((val & BITMASK_PM1CNT_SLEEP_TYPE) >> 10);
                 }
                 self.pm1_control = val & !BITMASK_PM1CNT_SLEEP_ENABLE;
             }
@@ -115,9 +125,9 @@ impl BusDevice for ACPIPMResource {
                 let sleep_control = val as u8;
                 if (sleep_control & BITMASK_SLEEPCNT_SLEEP_ENABLE) == BITMASK_SLEEPCNT_SLEEP_ENABLE
                 {
-                    if let Err(e) = self.suspend_evt.write(1) {
-                        error!("ACPIPM: failed to trigger suspend event: {}", e);
-                    }
+                    self.enter_sleep_state(
+                        ((sleep_control & BITMASK_SLEEPCNT_SLEEP_TYPE) >> 2) as u16,
+                    );
                 }
                 self.sleep_control = sleep_control as u8 & !BITMASK_SLEEPCNT_SLEEP_ENABLE;
             }
@@ -127,6 +137,33 @@ impl BusDevice for ACPIPMResource {
             }
         };
     }
+
+    // The registers, laid out as they are on the bus.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(ACPIPM_RESOURCE_LEN as usize);
+        data.extend_from_slice(&self.pm1_status.to_le_bytes());
+        data.extend_from_slice(&self.pm1_enable.to_le_bytes());
+        data.extend_from_slice(&self.pm1_control.to_le_bytes());
+        data.push(self.sleep_control);
+        data.push(self.sleep_status);
+        Some(data)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        if data.len() != ACPIPM_RESOURCE_LEN as usize {
+            return false;
+        }
+        let reg16 = |offset: u16| {
+            let offset = offset as usize;
+            u16::from_le_bytes([data[offset], data[offset + 1]])
+        };
+        self.pm1_status = reg16(PM1_STATUS);
+        self.pm1_enable = reg16(PM1_ENABLE);
+        self.pm1_control = reg16(PM1_CONTROL);
+        self.sleep_control = data[SLEEP_CONTROL as usize];
+        self.sleep_status = data[SLEEP_STATUS as usize];
+        true
+    }
 }
 
 impl BusResumeDevice for ACPIPMResource {
@@ -148,6 +185,14 @@ impl Aml for ACPIPMResource {
         )
         .to_aml_bytes(bytes);
 
+        // S3. Like S1, it pauses the VCPUs, and also puts the devices to sleep. Once woken up, the
+        // guest continues from where it wrote SLP_EN.
+        aml::Name::new(
+            "_S3_".into(),
+            &aml::Package::new(vec![&3u8, &3u8, &aml::ZERO, &aml::ZERO]),
+        )
+        .to_aml_bytes(bytes);
+
         // S5
         aml::Name::new(
             "_S5_".into(),
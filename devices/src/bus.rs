@@ -12,7 +12,7 @@ use std::sync::Arc;
 
 use base::RawDescriptor;
 use msg_socket::MsgOnSocket;
-use sync::Mutex;
+use sync::{Mutex, RwLock};
 
 /// Information about how a device was accessed.
 #[derive(Copy, Clone, Eq, PartialEq, Debug, MsgOnSocket)]
@@ -83,6 +83,27 @@ pub trait BusDevice: Send {
     }
     /// Invoked when the device is sandboxed.
     fn on_sandboxed(&mut self) {}
+    /// Invoked when the VM is suspended. Devices with timers or workers that act on their own
+    /// should quiesce them until `on_resume` is called.
+    fn on_suspend(&mut self) {}
+    /// Invoked when the VM resumes after `on_suspend`.
+    fn on_resume(&mut self) {}
+    /// Invoked after `on_suspend` when the guest put itself to sleep through ACPI. Devices with
+    /// workers should stop them, keeping what they need to carry on from where they were when
+    /// `on_wake` is called.
+    fn on_sleep(&mut self) {}
+    /// Invoked before `on_resume` when the guest is woken up after `on_sleep`.
+    fn on_wake(&mut self) {}
+    /// Saves the state of the device for a VM snapshot, with the VCPUs paused. Returns `None` if
+    /// the device can't be snapshotted, which fails the whole snapshot.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Loads the state saved by `snapshot`, with the VCPUs paused. Returns false if the state
+    /// can't be restored.
+    fn restore(&mut self, _data: &[u8]) -> bool {
+        false
+    }
 }
 
 pub trait BusDeviceSync: BusDevice + Sync {
@@ -98,8 +119,17 @@ pub trait BusResumeDevice: Send {
 
 #[derive(Debug)]
 pub enum Error {
+    /// The insertion failed because the new device was given an empty range.
+    Empty { device: String, base: u64 },
+    /// The removal failed because no device was inserted at the given range.
+    NotFound { base: u64, len: u64 },
     /// The insertion failed because the new device overlapped with an old device.
-    Overlap,
+    Overlap {
+        device: String,
+        range: BusRange,
+        other_device: String,
+        other_range: BusRange,
+    },
 }
 
 impl Display for Error {
@@ -107,7 +137,30 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
-            Overlap => write!(f, "new device overlaps with an old device"),
+            Empty { device, base } => write!(
+                f,
+                "{} at {:#x} has a length of zero; it must cover at least one address",
+                device, base
+            ),
+            NotFound { base, len } => write!(
+                f,
+                "no device was inserted at {}",
+                BusRange {
+                    base: *base,
+                    len: *len
+                }
+            ),
+            Overlap {
+                device,
+                range,
+                other_device,
+                other_range,
+            } => write!(
+                f,
+                "{} at {} overlaps with {} at {}; move one of them to a free range \
+                 (`crosvm dump-memmap` lists the registered ranges)",
+                device, range, other_device, other_range
+            ),
         }
     }
 }
@@ -136,6 +189,17 @@ impl BusRange {
     }
 }
 
+impl Display for BusRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..{:#x}",
+            self.base,
+            self.base.saturating_add(self.len)
+        )
+    }
+}
+
 impl Eq for BusRange {}
 
 impl PartialEq for BusRange {
@@ -162,6 +226,14 @@ enum BusDeviceEntry {
     InnerSync(Arc<dyn BusDeviceSync>),
 }
 
+// The label is captured at insertion so that listing the bus never has to lock a device that a
+// VCPU may be holding.
+#[derive(Clone)]
+struct BusEntry {
+    label: String,
+    device: BusDeviceEntry,
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
@@ -169,9 +241,13 @@ enum BusDeviceEntry {
 ///
 /// the 'resume_notify_devices' contains the devices which requires to be notified before the system
 /// resume back from S3 suspended state.
+///
+/// Clones of a bus share their devices, so a device inserted or removed after the VCPUs have been
+/// given their clones is seen by all of them. The devices are behind a reader-writer lock so that
+/// VCPUs accessing the bus at the same time don't wait on each other.
 #[derive(Clone)]
 pub struct Bus {
-    devices: BTreeMap<BusRange, BusDeviceEntry>,
+    devices: Arc<RwLock<BTreeMap<BusRange, BusEntry>>>,
     resume_notify_devices: Vec<Arc<Mutex<dyn BusResumeDevice>>>,
     access_id: usize,
 }
@@ -180,7 +256,7 @@ impl Bus {
     /// Constructs an a bus with an empty address space.
     pub fn new() -> Bus {
         Bus {
-            devices: BTreeMap::new(),
+            devices: Arc::new(RwLock::new(BTreeMap::new())),
             resume_notify_devices: Vec::new(),
             access_id: 0,
         }
@@ -191,16 +267,16 @@ impl Bus {
         self.access_id = id;
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, &BusDeviceEntry)> {
-        let (range, dev) = self
-            .devices
+    fn first_before(&self, addr: u64) -> Option<(BusRange, BusDeviceEntry)> {
+        let devices = self.devices.read();
+        let (range, dev) = devices
             .range(..=BusRange { base: addr, len: 1 })
             .rev()
             .next()?;
-        Some((*range, dev))
+        Some((*range, dev.device.clone()))
     }
 
-    fn get_device(&self, addr: u64) -> Option<(u64, u64, &BusDeviceEntry)> {
+    fn get_device(&self, addr: u64) -> Option<(u64, u64, BusDeviceEntry)> {
         if let Some((range, dev)) = self.first_before(addr) {
             let offset = addr - range.base;
             if offset < range.len {
@@ -210,30 +286,55 @@ impl Bus {
         None
     }
 
-    /// Puts the given device at the given address space.
-    pub fn insert(&mut self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
+    fn insert_entry(&mut self, entry: BusEntry, base: u64, len: u64) -> Result<()> {
         if len == 0 {
-            return Err(Error::Overlap);
+            return Err(Error::Empty {
+                device: entry.label,
+                base,
+            });
         }
 
+        let mut devices = self.devices.write();
         // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
+        if let Some((range, other)) = devices
             .iter()
-            .any(|(range, _dev)| range.overlaps(base, len))
+            .find(|(range, _dev)| range.overlaps(base, len))
         {
-            return Err(Error::Overlap);
+            return Err(Error::Overlap {
+                device: entry.label,
+                range: BusRange { base, len },
+                other_device: other.label.clone(),
+                other_range: *range,
+            });
         }
 
-        if self
-            .devices
-            .insert(BusRange { base, len }, BusDeviceEntry::OuterSync(device))
-            .is_some()
-        {
-            return Err(Error::Overlap);
+        devices.insert(BusRange { base, len }, entry);
+        Ok(())
+    }
+
+    /// Removes the device at `base` that was inserted with a length of `len`.
+    pub fn remove(&mut self, base: u64, len: u64) -> Result<()> {
+        let mut devices = self.devices.write();
+        match devices.get_key_value(&BusRange { base, len }) {
+            Some((range, _)) if range.len == len => {
+                devices.remove(&BusRange { base, len });
+                Ok(())
+            }
+            _ => Err(Error::NotFound { base, len }),
         }
+    }
 
-        Ok(())
+    /// Puts the given device at the given address space.
+    pub fn insert(&mut self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
+        let label = device.lock().debug_label();
+        self.insert_entry(
+            BusEntry {
+                label,
+                device: BusDeviceEntry::OuterSync(device),
+            },
+            base,
+            len,
+        )
     }
 
     /// Puts the given device that implements BusDeviceSync at the given address space. Devices
@@ -245,28 +346,42 @@ impl Bus {
         base: u64,
         len: u64,
     ) -> Result<()> {
-        if len == 0 {
-            return Err(Error::Overlap);
-        }
+        let label = device.debug_label();
+        self.insert_entry(
+            BusEntry {
+                label,
+                device: BusDeviceEntry::InnerSync(device),
+            },
+            base,
+            len,
+        )
+    }
 
-        // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
+    /// Returns the range and debug label of every device on the bus, ordered by base address.
+    pub fn device_ranges(&self) -> Vec<(BusRange, String)> {
+        self.devices
+            .read()
             .iter()
-            .any(|(range, _dev)| range.overlaps(base, len))
-        {
-            return Err(Error::Overlap);
-        }
+            .map(|(range, entry)| (*range, entry.label.clone()))
+            .collect()
+    }
 
-        if self
-            .devices
-            .insert(BusRange { base, len }, BusDeviceEntry::InnerSync(device))
-            .is_some()
-        {
-            return Err(Error::Overlap);
+    /// Returns every device on `buses` once, however many ranges it occupies. Devices inserted
+    /// with `insert_sync` aren't included.
+    pub fn unique_devices(buses: &[&Bus]) -> Vec<Arc<Mutex<dyn BusDevice>>> {
+        let mut devices: Vec<Arc<Mutex<dyn BusDevice>>> = Vec::new();
+        for bus in buses {
+            for entry in bus.devices.read().values() {
+                if let BusDeviceEntry::OuterSync(device) = &entry.device {
+                    // Compare data pointers only, as vtable pointers of one type aren't always equal.
+                    let ptr = Arc::as_ptr(device) as *const u8;
+                    if !devices.iter().any(|d| Arc::as_ptr(d) as *const u8 == ptr) {
+                        devices.push(device.clone());
+                    }
+                }
+            }
         }
-
-        Ok(())
+        devices
     }
 
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
@@ -400,6 +515,60 @@ mod tests {
         assert!(bus.insert(dummy.clone(), 0x0, 0x10).is_ok());
     }
 
+    #[test]
+    fn bus_insert_error_names_devices() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        let constant = Arc::new(Mutex::new(ConstantDevice {
+            uses_full_addr: false,
+        }));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        match bus.insert(constant.clone(), 0x18, 0x10) {
+            Err(Error::Overlap {
+                device,
+                range,
+                other_device,
+                other_range,
+            }) => {
+                assert_eq!(device, "constant device");
+                assert_eq!((range.base, range.len), (0x18, 0x10));
+                assert_eq!(other_device, "dummy device");
+                assert_eq!((other_range.base, other_range.len), (0x10, 0x10));
+            }
+            _ => panic!("expected an overlap error"),
+        }
+        match bus.insert(constant, 0x30, 0) {
+            Err(Error::Empty { device, base }) => {
+                assert_eq!(device, "constant device");
+                assert_eq!(base, 0x30);
+            }
+            _ => panic!("expected an empty range error"),
+        }
+    }
+
+    #[test]
+    fn bus_device_ranges() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        let constant = Arc::new(Mutex::new(ConstantDevice {
+            uses_full_addr: false,
+        }));
+        assert!(bus.insert(constant, 0x20, 0x8).is_ok());
+        assert!(bus.insert(dummy, 0x10, 0x10).is_ok());
+        let ranges: Vec<_> = bus
+            .device_ranges()
+            .into_iter()
+            .map(|(range, label)| (range.base, range.len, label))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (0x10, 0x10, "dummy device".to_owned()),
+                (0x20, 0x8, "constant device".to_owned()),
+            ]
+        );
+    }
+
     #[test]
     fn bus_read_write() {
         let mut bus = Bus::new();
@@ -417,6 +586,35 @@ mod tests {
         assert!(!bus.write(0x06, &mut [0, 0, 0, 0]));
     }
 
+    #[test]
+    fn bus_remove_shared_with_clones() {
+        let mut bus = Bus::new();
+        let vcpu_bus = bus.clone();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(vcpu_bus.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.remove(0x10, 0x8).is_err());
+        assert!(bus.remove(0x18, 0x10).is_err());
+        assert!(bus.remove(0x10, 0x10).is_ok());
+        assert!(!vcpu_bus.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.insert(dummy, 0x10, 0x20).is_ok());
+    }
+
+    #[test]
+    fn bus_unique_devices() {
+        let mut io_bus = Bus::new();
+        let mut mmio_bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        let constant = Arc::new(Mutex::new(ConstantDevice {
+            uses_full_addr: false,
+        }));
+        assert!(io_bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(io_bus.insert(dummy.clone(), 0x40, 0x10).is_ok());
+        assert!(mmio_bus.insert(dummy, 0x1000, 0x10).is_ok());
+        assert!(mmio_bus.insert(constant, 0x2000, 0x10).is_ok());
+        assert_eq!(Bus::unique_devices(&[&io_bus, &mmio_bus]).len(), 2);
+    }
+
     #[test]
     fn bus_read_write_values() {
         let mut bus = Bus::new();
@@ -118,4 +118,23 @@ impl BusDevice for Cmos {
             o => panic!("bad read offset on CMOS device: {}", o),
         }
     }
+
+    // The index register followed by the data. The clock isn't saved, as it is read from the
+    // host.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let mut snapshot = vec![self.index];
+        snapshot.extend_from_slice(&self.data);
+        Some(snapshot)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        match data.split_first() {
+            Some((&index, cmos_data)) if cmos_data.len() == DATA_LEN => {
+                self.index = index & INDEX_MASK;
+                self.data.copy_from_slice(cmos_data);
+                true
+            }
+            _ => false,
+        }
+    }
 }
@@ -0,0 +1,527 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! ACPI vCPU hotplug, signalled to the guest through a Generic Event Device (GED).
+//!
+//! Every possible vCPU gets a processor device in the DSDT whose `_STA` reads the present bit of
+//! the controller. Hot-adding or removing a vCPU latches an insert or remove bit for it and raises
+//! the GED interrupt, whose `_EVT` method scans the vCPUs and notifies the guest of each change.
+//! The guest acknowledges removals by calling the processor's `_EJ0`.
+
+use std::fmt::{self, Display};
+
+use acpi_tables::{aml, aml::Aml};
+use base::{error, warn, Event};
+
+use crate::{BusAccessInfo, BusDevice};
+
+/// The length of the IO region of the CPU hotplug controller.
+pub const CPU_HOTPLUG_RESOURCE_LEN: u8 = 12;
+
+// Index of the vCPU that the status register refers to.
+const CPU_SELECT: u64 = 0;
+// Status bits of the selected vCPU.
+const CPU_STATUS: u64 = 4;
+// Pending GED events, cleared by reading.
+const GED_EVENT: u64 = 8;
+
+const CPU_PRESENT: u8 = 1 << 0;
+const CPU_INSERTING: u8 = 1 << 1;
+const CPU_REMOVING: u8 = 1 << 2;
+const CPU_EJECT: u8 = 1 << 3;
+
+const GED_CPU_HOTPLUG: u32 = 1 << 0;
+
+// ACPI notification values for device checks and eject requests.
+const NOTIFY_DEVICE_CHECK: u8 = 1;
+const NOTIFY_EJECT_REQUEST: u8 = 3;
+
+#[derive(Debug)]
+pub enum CpuHotplugError {
+    AlreadyPresent(usize),
+    BootCpu,
+    InvalidCpu(usize),
+    NotPresent(usize),
+    TriggerIrq(base::Error),
+}
+
+impl Display for CpuHotplugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CpuHotplugError::*;
+
+        match self {
+            AlreadyPresent(cpu) => write!(f, "vcpu {} is already present", cpu),
+            BootCpu => write!(f, "the boot vcpu can't be removed"),
+            InvalidCpu(cpu) => write!(f, "vcpu {} is beyond the maximum number of vcpus", cpu),
+            NotPresent(cpu) => write!(f, "vcpu {} is not present", cpu),
+            TriggerIrq(e) => write!(f, "failed to trigger the GED interrupt: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, CpuHotplugError>;
+
+/// Tracks which vCPUs the guest may use and raises GED events when that changes.
+pub struct CpuHotplugController {
+    io_base: u64,
+    gsi: u32,
+    irq_evt: Event,
+    cpus: Vec<u8>,
+    selected: u32,
+    ged_event: u32,
+}
+
+impl CpuHotplugController {
+    /// Constructs a controller for `max_cpus` vCPUs, of which the first `present_cpus` are
+    /// present at boot.
+    ///
+    /// # Arguments
+    ///
+    /// * `io_base` - The base address of the controller on the IO bus.
+    /// * `gsi` - The edge triggered interrupt that `irq_evt` is registered to.
+    /// * `irq_evt` - The event written to raise the GED interrupt.
+    pub fn new(
+        io_base: u64,
+        gsi: u32,
+        irq_evt: Event,
+        present_cpus: usize,
+        max_cpus: usize,
+    ) -> CpuHotplugController {
+        let cpus = (0..max_cpus)
+            .map(|cpu| if cpu < present_cpus { CPU_PRESENT } else { 0 })
+            .collect();
+        CpuHotplugController {
+            io_base,
+            gsi,
+            irq_evt,
+            cpus,
+            selected: 0,
+            ged_event: 0,
+        }
+    }
+
+    /// Returns the number of vCPUs that are present in the guest.
+    pub fn present_cpus(&self) -> usize {
+        self.cpus
+            .iter()
+            .filter(|&&status| status & CPU_PRESENT != 0)
+            .count()
+    }
+
+    /// Makes `cpu` present and tells the guest to bring it up.
+    pub fn add_cpu(&mut self, cpu: usize) -> Result<()> {
+        let status = self
+            .cpus
+            .get_mut(cpu)
+            .ok_or(CpuHotplugError::InvalidCpu(cpu))?;
+        if *status & CPU_PRESENT != 0 {
+            return Err(CpuHotplugError::AlreadyPresent(cpu));
+        }
+        *status = CPU_PRESENT | CPU_INSERTING;
+        self.trigger()
+    }
+
+    /// Asks the guest to give up `cpu`. It stays present until the guest ejects it.
+    pub fn remove_cpu(&mut self, cpu: usize) -> Result<()> {
+        if cpu == 0 {
+            return Err(CpuHotplugError::BootCpu);
+        }
+        let status = self
+            .cpus
+            .get_mut(cpu)
+            .ok_or(CpuHotplugError::InvalidCpu(cpu))?;
+        if *status & CPU_PRESENT == 0 {
+            return Err(CpuHotplugError::NotPresent(cpu));
+        }
+        *status |= CPU_REMOVING;
+        self.trigger()
+    }
+
+    fn trigger(&mut self) -> Result<()> {
+        self.ged_event |= GED_CPU_HOTPLUG;
+        self.irq_evt.write(1).map_err(CpuHotplugError::TriggerIrq)
+    }
+
+    fn selected_status(&mut self) -> Option<&mut u8> {
+        self.cpus.get_mut(self.selected as usize)
+    }
+}
+
+impl BusDevice for CpuHotplugController {
+    fn debug_label(&self) -> String {
+        "CpuHotplugController".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        let val = match info.offset {
+            CPU_SELECT => self.selected,
+            CPU_STATUS => match self.selected_status() {
+                Some(status) => (*status & !CPU_EJECT) as u32,
+                None => 0,
+            },
+            GED_EVENT => std::mem::replace(&mut self.ged_event, 0),
+            _ => {
+                warn!("CpuHotplugController: Bad read from {}", info);
+                return;
+            }
+        };
+
+        let val_arr = val.to_le_bytes();
+        let len = data.len().min(val_arr.len());
+        data[..len].copy_from_slice(&val_arr[..len]);
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        let mut val_arr = [0u8; 4];
+        let len = data.len().min(val_arr.len());
+        val_arr[..len].copy_from_slice(&data[..len]);
+        let val = u32::from_le_bytes(val_arr);
+
+        match info.offset {
+            CPU_SELECT => self.selected = val,
+            CPU_STATUS => {
+                let val = val as u8;
+                let status = match self.selected_status() {
+                    Some(status) => status,
+                    None => {
+                        error!("CpuHotplugController: no vcpu {} to update", self.selected);
+                        return;
+                    }
+                };
+                // The insert and remove bits are cleared by writing 1 once the guest has been
+                // notified.
+                *status &= !(val & (CPU_INSERTING | CPU_REMOVING));
+                if val & CPU_EJECT != 0 {
+                    *status &= !(CPU_PRESENT | CPU_REMOVING);
+                }
+            }
+            GED_EVENT => {}
+            _ => {
+                warn!("CpuHotplugController: Bad write to {}", info);
+            }
+        }
+    }
+}
+
+// Processor device of one possible vCPU.
+struct CpuDevice {
+    cpu_id: usize,
+}
+
+impl Aml for CpuDevice {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        let cpu_id = self.cpu_id as u8;
+        // The MADT entry of the enabled vCPU, which the guest looks up when it is added.
+        let mat = vec![0u8, 8, cpu_id, cpu_id, 1, 0, 0, 0];
+        aml::Device::new(
+            format!("C{:03X}", self.cpu_id).as_str().into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0007"),
+                &aml::Name::new("_UID".into(), &self.cpu_id),
+                &aml::Method::new(
+                    "_STA".into(),
+                    0,
+                    false,
+                    vec![&aml::Return::new(&aml::MethodCall::new(
+                        "CSTA".into(),
+                        vec![&self.cpu_id],
+                    ))],
+                ),
+                &aml::Name::new("_MAT".into(), &aml::Buffer::new(mat)),
+                &aml::Method::new(
+                    "_EJ0".into(),
+                    1,
+                    false,
+                    vec![&aml::MethodCall::new("CEJ0".into(), vec![&self.cpu_id])],
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+// Sends the notification in Arg1 to the processor device of the vCPU in Arg0, if it's `cpu_id`.
+struct CpuNotify {
+    cpu_id: usize,
+}
+
+impl Aml for CpuNotify {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        let object: aml::Path = format!("C{:03X}", self.cpu_id).as_str().into();
+        aml::If::new(
+            &aml::Equal::new(&aml::Arg(0), &self.cpu_id),
+            vec![&aml::Notify::new(&object, &aml::Arg(1))],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+// Identification, registers and methods of the processor container.
+struct CpuMethods {
+    io_base: u64,
+    max_cpus: usize,
+}
+
+impl Aml for CpuMethods {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        let max_cpus = self.max_cpus;
+        let cpu_notifies: Vec<CpuNotify> =
+            (0..max_cpus).map(|cpu_id| CpuNotify { cpu_id }).collect();
+
+        aml::Name::new("_HID".into(), &"ACPI0010").to_aml_bytes(bytes);
+        aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A05")).to_aml_bytes(bytes);
+        aml::Mutex::new("CPLK".into(), 0).to_aml_bytes(bytes);
+        aml::OpRegion::new(
+            "PRST".into(),
+            aml::OpRegionSpace::SystemIO,
+            self.io_base as usize,
+            CPU_HOTPLUG_RESOURCE_LEN as usize,
+        )
+        .to_aml_bytes(bytes);
+        aml::Field::new(
+            "PRST".into(),
+            aml::FieldAccessType::DWord,
+            aml::FieldUpdateRule::Preserve,
+            vec![aml::FieldEntry::Named(*b"CSEL", 32)],
+        )
+        .to_aml_bytes(bytes);
+        // Written as zeroes so that setting one bit doesn't also acknowledge the others.
+        aml::Field::new(
+            "PRST".into(),
+            aml::FieldAccessType::Byte,
+            aml::FieldUpdateRule::WriteAsZeroes,
+            vec![
+                aml::FieldEntry::Reserved(32),
+                aml::FieldEntry::Named(*b"CPEN", 1),
+                aml::FieldEntry::Named(*b"CINS", 1),
+                aml::FieldEntry::Named(*b"CRMV", 1),
+                aml::FieldEntry::Named(*b"CEJF", 1),
+            ],
+        )
+        .to_aml_bytes(bytes);
+        aml::Field::new(
+            "PRST".into(),
+            aml::FieldAccessType::DWord,
+            aml::FieldUpdateRule::Preserve,
+            vec![
+                aml::FieldEntry::Reserved(64),
+                aml::FieldEntry::Named(*b"GDAT", 32),
+            ],
+        )
+        .to_aml_bytes(bytes);
+        // CSTA(cpu): the _STA value of a vCPU.
+        aml::Method::new(
+            "CSTA".into(),
+            1,
+            true,
+            vec![
+                &aml::Acquire::new("CPLK".into(), 0xffff),
+                &aml::Store::new(&aml::Path::new("CSEL"), &aml::Arg(0)),
+                &aml::Store::new(&aml::Local(0), &aml::ZERO),
+                &aml::If::new(
+                    &aml::Equal::new(&aml::Path::new("CPEN"), &aml::ONE),
+                    vec![&aml::Store::new(&aml::Local(0), &0xfu8)],
+                ),
+                &aml::Release::new("CPLK".into()),
+                &aml::Return::new(&aml::Local(0)),
+            ],
+        )
+        .to_aml_bytes(bytes);
+        // CEJ0(cpu): the guest has given up a vCPU.
+        aml::Method::new(
+            "CEJ0".into(),
+            1,
+            true,
+            vec![
+                &aml::Acquire::new("CPLK".into(), 0xffff),
+                &aml::Store::new(&aml::Path::new("CSEL"), &aml::Arg(0)),
+                &aml::Store::new(&aml::Path::new("CEJF"), &aml::ONE),
+                &aml::Release::new("CPLK".into()),
+            ],
+        )
+        .to_aml_bytes(bytes);
+        // CTFY(cpu, value): notify the processor device of a vCPU.
+        aml::Method::new(
+            "CTFY".into(),
+            2,
+            false,
+            cpu_notifies.iter().map(|n| n as &dyn Aml).collect(),
+        )
+        .to_aml_bytes(bytes);
+        // CSCN(): notify the guest of every vCPU that was added or is to be removed.
+        aml::Method::new(
+            "CSCN".into(),
+            0,
+            true,
+            vec![
+                &aml::Acquire::new("CPLK".into(), 0xffff),
+                &aml::Store::new(&aml::Local(0), &aml::ZERO),
+                &aml::While::new(
+                    &aml::LessThan::new(&aml::Local(0), &max_cpus),
+                    vec![
+                        &aml::Store::new(&aml::Path::new("CSEL"), &aml::Local(0)),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Path::new("CINS"), &aml::ONE),
+                            vec![
+                                &aml::MethodCall::new(
+                                    "CTFY".into(),
+                                    vec![&aml::Local(0), &NOTIFY_DEVICE_CHECK],
+                                ),
+                                &aml::Store::new(&aml::Path::new("CINS"), &aml::ONE),
+                            ],
+                        ),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Path::new("CRMV"), &aml::ONE),
+                            vec![
+                                &aml::MethodCall::new(
+                                    "CTFY".into(),
+                                    vec![&aml::Local(0), &NOTIFY_EJECT_REQUEST],
+                                ),
+                                &aml::Store::new(&aml::Path::new("CRMV"), &aml::ONE),
+                            ],
+                        ),
+                        &aml::Add::new(&aml::Local(0), &aml::Local(0), &aml::ONE),
+                    ],
+                ),
+                &aml::Release::new("CPLK".into()),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+impl Aml for CpuHotplugController {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        let methods = CpuMethods {
+            io_base: self.io_base,
+            max_cpus: self.cpus.len(),
+        };
+        let cpu_devices: Vec<CpuDevice> = (0..self.cpus.len())
+            .map(|cpu_id| CpuDevice { cpu_id })
+            .collect();
+        let mut children: Vec<&dyn Aml> = vec![&methods];
+        children.extend(cpu_devices.iter().map(|d| d as &dyn Aml));
+        aml::Device::new("_SB_.CPUS".into(), children).to_aml_bytes(bytes);
+
+        aml::Device::new(
+            "_SB_.GED_".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0013"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::Interrupt::new(
+                        true, true, false, false, self.gsi,
+                    )]),
+                ),
+                &aml::Method::new(
+                    "_EVT".into(),
+                    1,
+                    true,
+                    vec![
+                        &aml::Store::new(&aml::Local(0), &aml::Path::new("\\_SB_.CPUS.GDAT")),
+                        &aml::If::new(
+                            &aml::Equal::new(
+                                &aml::And::new(&aml::ZERO, &aml::Local(0), &GED_CPU_HOTPLUG),
+                                &GED_CPU_HOTPLUG,
+                            ),
+                            vec![&aml::MethodCall::new("\\_SB_.CPUS.CSCN".into(), vec![])],
+                        ),
+                    ],
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> CpuHotplugController {
+        CpuHotplugController::new(0x700, 5, Event::new().unwrap(), 2, 4)
+    }
+
+    fn read(dev: &mut CpuHotplugController, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        dev.read(
+            BusAccessInfo {
+                offset,
+                address: 0x700 + offset,
+                id: 0,
+            },
+            &mut data,
+        );
+        u32::from_le_bytes(data)
+    }
+
+    fn write(dev: &mut CpuHotplugController, offset: u64, val: u32) {
+        dev.write(
+            BusAccessInfo {
+                offset,
+                address: 0x700 + offset,
+                id: 0,
+            },
+            &val.to_le_bytes(),
+        );
+    }
+
+    fn status(dev: &mut CpuHotplugController, cpu: u32) -> u32 {
+        write(dev, CPU_SELECT, cpu);
+        read(dev, CPU_STATUS)
+    }
+
+    #[test]
+    fn boot_cpus_present() {
+        let mut dev = controller();
+        assert_eq!(dev.present_cpus(), 2);
+        assert_eq!(status(&mut dev, 1), CPU_PRESENT as u32);
+        assert_eq!(status(&mut dev, 2), 0);
+        assert_eq!(status(&mut dev, 4), 0);
+        assert_eq!(read(&mut dev, GED_EVENT), 0);
+    }
+
+    #[test]
+    fn add_and_eject_cpu() {
+        let mut dev = controller();
+        dev.add_cpu(2).unwrap();
+        assert!(matches!(
+            dev.add_cpu(2),
+            Err(CpuHotplugError::AlreadyPresent(2))
+        ));
+        assert!(matches!(
+            dev.add_cpu(4),
+            Err(CpuHotplugError::InvalidCpu(4))
+        ));
+        assert_eq!(dev.irq_evt.read().unwrap(), 1);
+        assert_eq!(read(&mut dev, GED_EVENT), GED_CPU_HOTPLUG);
+        assert_eq!(read(&mut dev, GED_EVENT), 0);
+        assert_eq!(status(&mut dev, 2), (CPU_PRESENT | CPU_INSERTING) as u32);
+        write(&mut dev, CPU_STATUS, CPU_INSERTING as u32);
+        assert_eq!(status(&mut dev, 2), CPU_PRESENT as u32);
+        assert_eq!(dev.present_cpus(), 3);
+
+        assert!(matches!(dev.remove_cpu(0), Err(CpuHotplugError::BootCpu)));
+        assert!(matches!(
+            dev.remove_cpu(3),
+            Err(CpuHotplugError::NotPresent(3))
+        ));
+        dev.remove_cpu(2).unwrap();
+        assert_eq!(status(&mut dev, 2), (CPU_PRESENT | CPU_REMOVING) as u32);
+        write(&mut dev, CPU_STATUS, CPU_EJECT as u32);
+        assert_eq!(status(&mut dev, 2), 0);
+        assert_eq!(dev.present_cpus(), 2);
+    }
+
+    #[test]
+    fn aml_devices() {
+        let mut bytes = Vec::new();
+        controller().to_aml_bytes(&mut bytes);
+        for name in [b"CPUS", b"GED_", b"C000", b"C003", b"CSCN"].iter() {
+            assert!(bytes.windows(4).any(|w| w == &name[..]));
+        }
+        assert!(!bytes.windows(4).any(|w| w == b"C004"));
+    }
+}
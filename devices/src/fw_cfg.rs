@@ -0,0 +1,309 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! QEMU-compatible firmware configuration (fw_cfg) device.
+//!
+//! Firmware selects an item by writing its 16-bit key to the selector register and then reads the
+//! item's contents one byte at a time from the data register. Named blobs ("files") are listed in
+//! a directory item so firmware can look them up by name.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use remain::sorted;
+
+use crate::{BusAccessInfo, BusDevice};
+
+/// I/O port of the fw_cfg registers on x86.
+pub const FW_CFG_IO_BASE: u64 = 0x510;
+/// Size of the fw_cfg register window: a 16-bit selector followed by the 8-bit data register.
+pub const FW_CFG_IO_SIZE: u64 = 0x2;
+
+const SELECTOR_OFFSET: u64 = 0x0;
+const DATA_OFFSET: u64 = 0x1;
+
+pub const FW_CFG_SIGNATURE: u16 = 0x00;
+pub const FW_CFG_ID: u16 = 0x01;
+pub const FW_CFG_RAM_SIZE: u16 = 0x03;
+pub const FW_CFG_NB_CPUS: u16 = 0x05;
+pub const FW_CFG_KERNEL_SIZE: u16 = 0x08;
+pub const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+pub const FW_CFG_MAX_CPUS: u16 = 0x0f;
+pub const FW_CFG_KERNEL_DATA: u16 = 0x11;
+pub const FW_CFG_INITRD_DATA: u16 = 0x12;
+pub const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+pub const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+pub const FW_CFG_SETUP_SIZE: u16 = 0x17;
+pub const FW_CFG_SETUP_DATA: u16 = 0x18;
+pub const FW_CFG_FILE_DIR: u16 = 0x19;
+pub const FW_CFG_FILE_FIRST: u16 = 0x20;
+pub const FW_CFG_IRQ0_OVERRIDE: u16 = 0x8002;
+
+const FW_CFG_SIGNATURE_DATA: &[u8] = b"QEMU";
+// Only the traditional selector/data interface is implemented, not DMA.
+const FW_CFG_VERSION_TRADITIONAL: u32 = 0x1;
+// The file directory lives in the 0x20..0x4000 key range reserved for files.
+const FW_CFG_FILE_MAX: usize = 0x4000 - FW_CFG_FILE_FIRST as usize;
+/// Maximum length of a file name, not counting the nul terminator.
+pub const FW_CFG_MAX_FILE_NAME: usize = 55;
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    DuplicateFile(String),
+    FileNameTooLong(String),
+    TooManyFiles,
+}
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            DuplicateFile(name) => write!(f, "fw_cfg file {} was added more than once", name),
+            FileNameTooLong(name) => write!(
+                f,
+                "fw_cfg file name {} is longer than {} bytes",
+                name, FW_CFG_MAX_FILE_NAME
+            ),
+            TooManyFiles => write!(f, "too many fw_cfg files"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A firmware configuration device holding the items and files that firmware can read.
+pub struct FwCfg {
+    items: BTreeMap<u16, Vec<u8>>,
+    files: Vec<String>,
+    selector: u16,
+    offset: usize,
+}
+
+impl FwCfg {
+    /// Constructs an fw_cfg device that only holds the signature, interface ID and an empty file
+    /// directory.
+    pub fn new() -> FwCfg {
+        let mut fw_cfg = FwCfg {
+            items: BTreeMap::new(),
+            files: Vec::new(),
+            selector: 0,
+            offset: 0,
+        };
+        fw_cfg.add_item(FW_CFG_SIGNATURE, FW_CFG_SIGNATURE_DATA.to_vec());
+        fw_cfg.add_u32(FW_CFG_ID, FW_CFG_VERSION_TRADITIONAL);
+        fw_cfg.update_file_dir();
+        fw_cfg
+    }
+
+    /// Sets the contents of the item with the well-known `key`, replacing any previous contents.
+    pub fn add_item(&mut self, key: u16, data: Vec<u8>) {
+        self.items.insert(key, data);
+    }
+
+    /// Sets the item with the well-known `key` to a little-endian u16.
+    pub fn add_u16(&mut self, key: u16, value: u16) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Sets the item with the well-known `key` to a little-endian u32.
+    pub fn add_u32(&mut self, key: u16, value: u32) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Sets the item with the well-known `key` to a little-endian u64.
+    pub fn add_u64(&mut self, key: u16, value: u64) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Adds a named file, which firmware finds through the file directory.
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        if name.len() > FW_CFG_MAX_FILE_NAME {
+            return Err(Error::FileNameTooLong(name.to_owned()));
+        }
+        if self.files.iter().any(|f| f == name) {
+            return Err(Error::DuplicateFile(name.to_owned()));
+        }
+        if self.files.len() >= FW_CFG_FILE_MAX {
+            return Err(Error::TooManyFiles);
+        }
+
+        let key = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        self.files.push(name.to_owned());
+        self.add_item(key, data);
+        self.update_file_dir();
+        Ok(())
+    }
+
+    // Rebuilds the file directory item. Unlike every other item, its fields are big-endian.
+    fn update_file_dir(&mut self) {
+        let mut dir = Vec::with_capacity(4 + self.files.len() * 64);
+        dir.extend_from_slice(&(self.files.len() as u32).to_be_bytes());
+        for (i, name) in self.files.iter().enumerate() {
+            let key = FW_CFG_FILE_FIRST + i as u16;
+            let size = self.items.get(&key).map_or(0, |data| data.len());
+            dir.extend_from_slice(&(size as u32).to_be_bytes());
+            dir.extend_from_slice(&key.to_be_bytes());
+            dir.extend_from_slice(&0u16.to_be_bytes());
+            let mut file_name = [0u8; FW_CFG_MAX_FILE_NAME + 1];
+            file_name[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&file_name);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let byte = self
+            .items
+            .get(&self.selector)
+            .and_then(|data| data.get(self.offset))
+            .copied()
+            .unwrap_or(0);
+        self.offset = self.offset.saturating_add(1);
+        byte
+    }
+}
+
+impl Default for FwCfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn debug_label(&self) -> String {
+        "fw_cfg".to_owned()
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        // Writing to the data register was deprecated long ago and is ignored, as in QEMU.
+        if info.offset != SELECTOR_OFFSET || data.len() != 2 {
+            return;
+        }
+        self.selector = u16::from_le_bytes([data[0], data[1]]);
+        self.offset = 0;
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        match info.offset {
+            // Wider reads of the data register return consecutive bytes of the item.
+            DATA_OFFSET => {
+                for byte in data.iter_mut() {
+                    *byte = self.read_data();
+                }
+            }
+            SELECTOR_OFFSET if data.len() == 2 => {
+                data.copy_from_slice(&self.selector.to_le_bytes())
+            }
+            _ => {
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+    }
+
+    // The items come from the command line, so only the position of the firmware in them is saved.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let mut data = self.selector.to_le_bytes().to_vec();
+        data.extend_from_slice(&(self.offset as u64).to_le_bytes());
+        Some(data)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        if data.len() != 10 {
+            return false;
+        }
+        let mut offset = [0u8; 8];
+        offset.copy_from_slice(&data[2..]);
+        self.selector = u16::from_le_bytes([data[0], data[1]]);
+        self.offset = u64::from_le_bytes(offset) as usize;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            address: FW_CFG_IO_BASE + offset,
+            offset,
+            id: 0,
+        }
+    }
+
+    fn read_item(fw_cfg: &mut FwCfg, key: u16, len: usize) -> Vec<u8> {
+        fw_cfg.write(access(SELECTOR_OFFSET), &key.to_le_bytes());
+        let mut data = vec![0u8; len];
+        for byte in data.iter_mut() {
+            let mut b = [0u8];
+            fw_cfg.read(access(DATA_OFFSET), &mut b);
+            *byte = b[0];
+        }
+        data
+    }
+
+    #[test]
+    fn signature_and_id() {
+        let mut fw_cfg = FwCfg::new();
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_SIGNATURE, 4), b"QEMU");
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_ID, 4), [1, 0, 0, 0]);
+        // Reading past the end of an item, or reading a missing item, returns zeros.
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_SIGNATURE, 6), b"QEMU\0\0");
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_KERNEL_SIZE, 4), [0; 4]);
+    }
+
+    #[test]
+    fn selector_resets_offset() {
+        let mut fw_cfg = FwCfg::new();
+        fw_cfg.add_u16(FW_CFG_NB_CPUS, 4);
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_NB_CPUS, 1), [4]);
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_NB_CPUS, 2), [4, 0]);
+
+        let mut wide = [0u8; 4];
+        fw_cfg.write(access(SELECTOR_OFFSET), &FW_CFG_SIGNATURE.to_le_bytes());
+        fw_cfg.read(access(DATA_OFFSET), &mut wide);
+        assert_eq!(&wide, b"QEMU");
+    }
+
+    #[test]
+    fn file_directory() {
+        let mut fw_cfg = FwCfg::new();
+        fw_cfg
+            .add_file("bootorder", b"/pci@i0cf8/*@4\n".to_vec())
+            .unwrap();
+        fw_cfg.add_file("opt/test", vec![1, 2, 3]).unwrap();
+
+        let dir = read_item(&mut fw_cfg, FW_CFG_FILE_DIR, 4 + 2 * 64);
+        assert_eq!(&dir[0..4], &[0, 0, 0, 2]);
+        let entry = &dir[4 + 64..4 + 128];
+        assert_eq!(&entry[0..4], &[0, 0, 0, 3]);
+        assert_eq!(&entry[4..6], &(FW_CFG_FILE_FIRST + 1).to_be_bytes());
+        assert_eq!(&entry[8..17], b"opt/test\0");
+
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_FILE_FIRST + 1, 3), [1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut fw_cfg = FwCfg::new();
+        fw_cfg.add_file("opt/test", Vec::new()).unwrap();
+        match fw_cfg.add_file("opt/test", Vec::new()) {
+            Err(Error::DuplicateFile(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let long_name = "a".repeat(FW_CFG_MAX_FILE_NAME + 1);
+        match fw_cfg.add_file(&long_name, Vec::new()) {
+            Err(Error::FileNameTooLong(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -9,4 +9,7 @@ pub trait IrqChipAArch64: IrqChip {
     /// Get the version of VGIC that this chip is emulating. Currently KVM may either implement
     /// VGIC version 2 or 3.
     fn get_vgic_version(&self) -> DeviceKind;
+
+    /// Returns whether the chip has an ITS, which lets PCI devices send MSIs to a VGIC v3.
+    fn has_its(&self) -> bool;
 }
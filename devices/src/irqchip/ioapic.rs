@@ -126,6 +126,15 @@ impl BusDevice for Ioapic {
             }
         }
     }
+
+    // The state is saved with the rest of the irqchip.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
 }
 
 impl Ioapic {
@@ -157,6 +166,22 @@ impl Ioapic {
         self.resample_events = resample_events;
     }
 
+    /// Adds a resample event for `gsi` once the resample events were registered, for an irq event
+    /// registered later such as that of a hot-added device.
+    pub fn add_resample_event(&mut self, gsi: usize, resample_evt: Event) {
+        if let Some(resample_events) = self.resample_events.get_mut(gsi) {
+            resample_events.push(resample_evt);
+        }
+    }
+
+    /// Removes a resample event of `gsi` added by `register_resample_events` or
+    /// `add_resample_event`.
+    pub fn remove_resample_event(&mut self, gsi: usize, resample_evt: &Event) {
+        if let Some(resample_events) = self.resample_events.get_mut(gsi) {
+            resample_events.retain(|evt| evt != resample_evt);
+        }
+    }
+
     // The ioapic must be informed about EOIs in order to avoid sending multiple interrupts of the
     // same type at the same time.
     pub fn end_of_interrupt(&mut self, vector: u8) {
@@ -360,6 +385,7 @@ impl Ioapic {
             gsi,
             msi_address,
             msi_data,
+            msi_devid: None,
         };
         self.irq_socket
             .send(&request)
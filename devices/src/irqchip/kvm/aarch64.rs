@@ -5,10 +5,9 @@
 use std::sync::Arc;
 use sync::Mutex;
 
-use base::{errno_result, ioctl_with_ref, Result, SafeDescriptor};
-use hypervisor::kvm::{KvmVcpu, KvmVm};
+use base::{Result, SafeDescriptor};
+use hypervisor::kvm::{KvmVcpu, KvmVgic, KvmVm, VgicAddresses};
 use hypervisor::{DeviceKind, IrqRoute, Vm};
-use kvm_sys::*;
 
 use crate::IrqChipAArch64;
 
@@ -31,6 +30,8 @@ pub struct KvmKernelIrqChip {
     pub(super) vcpus: Arc<Mutex<Vec<Option<KvmVcpu>>>>,
     vgic: SafeDescriptor,
     device_kind: DeviceKind,
+    // The ITS translating MSIs into LPIs, if the kernel emulates one for the VGIC v3.
+    its: Option<SafeDescriptor>,
     pub(super) routes: Arc<Mutex<Vec<IrqRoute>>>,
 }
 
@@ -43,6 +44,7 @@ const AARCH64_GIC_CPUI_SIZE: u64 = 0x20000;
 const AARCH64_GIC_DIST_BASE: u64 = AARCH64_AXI_BASE - AARCH64_GIC_DIST_SIZE;
 const AARCH64_GIC_CPUI_BASE: u64 = AARCH64_GIC_DIST_BASE - AARCH64_GIC_CPUI_SIZE;
 const AARCH64_GIC_REDIST_SIZE: u64 = 0x20000;
+const AARCH64_GIC_ITS_SIZE: u64 = 0x20000;
 
 // This is the minimum number of SPI interrupts aligned to 32 + 32 for the
 // PPI (16) and GSI (16).
@@ -55,95 +57,23 @@ const AARCH64_AXI_BASE: u64 = 0x40000000;
 impl KvmKernelIrqChip {
     /// Construct a new KvmKernelIrqchip.
     pub fn new(vm: KvmVm, num_vcpus: usize) -> Result<KvmKernelIrqChip> {
-        let cpu_if_addr: u64 = AARCH64_GIC_CPUI_BASE;
-        let dist_if_addr: u64 = AARCH64_GIC_DIST_BASE;
-        let redist_addr: u64 = dist_if_addr - (AARCH64_GIC_REDIST_SIZE * num_vcpus as u64);
-        let raw_cpu_if_addr = &cpu_if_addr as *const u64;
-        let raw_dist_if_addr = &dist_if_addr as *const u64;
-        let raw_redist_addr = &redist_addr as *const u64;
-
-        let cpu_if_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            attr: KVM_VGIC_V2_ADDR_TYPE_CPU as u64,
-            addr: raw_cpu_if_addr as u64,
-            flags: 0,
+        let dist = AARCH64_GIC_DIST_BASE;
+        let redist = dist - (AARCH64_GIC_REDIST_SIZE * num_vcpus as u64);
+        // The ITS sits right below the redistributors.
+        let addrs = VgicAddresses {
+            dist,
+            cpu_if: AARCH64_GIC_CPUI_BASE,
+            redist,
+            its: redist - AARCH64_GIC_ITS_SIZE,
         };
-        let redist_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            attr: KVM_VGIC_V3_ADDR_TYPE_REDIST as u64,
-            addr: raw_redist_addr as u64,
-            flags: 0,
-        };
-        let mut dist_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_ADDR,
-            addr: raw_dist_if_addr as u64,
-            attr: 0,
-            flags: 0,
-        };
-
-        let (vgic, device_kind, cpu_redist_attr, dist_attr_attr) =
-            match vm.create_device(DeviceKind::ArmVgicV3) {
-                Err(_) => (
-                    vm.create_device(DeviceKind::ArmVgicV2)?,
-                    DeviceKind::ArmVgicV2,
-                    cpu_if_attr,
-                    KVM_VGIC_V2_ADDR_TYPE_DIST as u64,
-                ),
-                Ok(vgic) => (
-                    vgic,
-                    DeviceKind::ArmVgicV3,
-                    redist_attr,
-                    KVM_VGIC_V3_ADDR_TYPE_DIST as u64,
-                ),
-            };
-        dist_attr.attr = dist_attr_attr;
-
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &cpu_redist_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
-
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &dist_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
-
-        // We need to tell the kernel how many irqs to support with this vgic
-        let nr_irqs: u32 = AARCH64_GIC_NR_IRQS;
-        let nr_irqs_ptr = &nr_irqs as *const u32;
-        let nr_irqs_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
-            attr: 0,
-            addr: nr_irqs_ptr as u64,
-            flags: 0,
-        };
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &nr_irqs_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
-
-        // Finalize the GIC
-        let init_gic_attr = kvm_device_attr {
-            group: KVM_DEV_ARM_VGIC_GRP_CTRL,
-            attr: KVM_DEV_ARM_VGIC_CTRL_INIT as u64,
-            addr: 0,
-            flags: 0,
-        };
-
-        // Safe because we allocated the struct that's being passed in
-        let ret = unsafe { ioctl_with_ref(&vgic, KVM_SET_DEVICE_ATTR(), &init_gic_attr) };
-        if ret != 0 {
-            return errno_result();
-        }
+        let KvmVgic { vgic, kind, its } = vm.create_vgic(&addrs, AARCH64_GIC_NR_IRQS)?;
 
         Ok(KvmKernelIrqChip {
             vm,
             vcpus: Arc::new(Mutex::new((0..num_vcpus).map(|_| None).collect())),
             vgic,
-            device_kind,
+            device_kind: kind,
+            its,
             routes: Arc::new(Mutex::new(kvm_default_irq_routing_table())),
         })
     }
@@ -155,6 +85,10 @@ impl KvmKernelIrqChip {
             vcpus: self.vcpus.clone(),
             vgic: self.vgic.try_clone()?,
             device_kind: self.device_kind,
+            its: match &self.its {
+                Some(its) => Some(its.try_clone()?),
+                None => None,
+            },
             routes: self.routes.clone(),
         })
     }
@@ -164,4 +98,8 @@ impl IrqChipAArch64 for KvmKernelIrqChip {
     fn get_vgic_version(&self) -> DeviceKind {
         self.device_kind
     }
+
+    fn has_its(&self) -> bool {
+        self.its.is_some()
+    }
 }
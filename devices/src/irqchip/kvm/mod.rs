@@ -10,7 +10,6 @@ use hypervisor::VmAArch64;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::VmX86_64;
 use hypervisor::{HypervisorCap, IrqRoute, MPState, Vcpu};
-use kvm_sys::kvm_mp_state;
 use resources::SystemAllocator;
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -62,6 +61,14 @@ impl IrqChip for KvmKernelIrqChip {
         self.vm.set_gsi_routing(&*routes)
     }
 
+    /// Remove the route of an IRQ line or MSI vector.
+    fn unroute_irq(&mut self, irq: u32) -> Result<()> {
+        let mut routes = self.routes.lock();
+        routes.retain(|r| r.gsi != irq);
+
+        self.vm.set_gsi_routing(&*routes)
+    }
+
     /// Replace all irq routes with the supplied routes
     fn set_irq_routes(&mut self, routes: &[IrqRoute]) -> Result<()> {
         let mut current_routes = self.routes.lock();
@@ -138,7 +145,7 @@ impl IrqChip for KvmKernelIrqChip {
     /// Set the current MP state of the specified VCPU.
     fn set_mp_state(&mut self, vcpu_id: usize, state: &MPState) -> Result<()> {
         match self.vcpus.lock().get(vcpu_id) {
-            Some(Some(vcpu)) => vcpu.set_mp_state(&kvm_mp_state::from(state)),
+            Some(Some(vcpu)) => vcpu.set_mp_state(&state.into()),
             _ => Err(Error::new(libc::ENOENT)),
         }
     }
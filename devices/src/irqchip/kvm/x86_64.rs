@@ -15,7 +15,6 @@ use hypervisor::{
     HypervisorCap, IoapicState, IrqRoute, IrqSource, IrqSourceChip, LapicState, MPState, PicSelect,
     PicState, PitState, Vcpu, VcpuX86_64, Vm, VmX86_64, NUM_IOAPIC_PINS,
 };
-use kvm_sys::*;
 use resources::SystemAllocator;
 
 use base::{error, Error, Event, Result};
@@ -89,7 +88,7 @@ impl IrqChipX86_64 for KvmKernelIrqChip {
 
     /// Set the current state of the PIC
     fn set_pic_state(&mut self, select: PicSelect, state: &PicState) -> Result<()> {
-        self.vm.set_pic_state(select, &kvm_pic_state::from(state))
+        self.vm.set_pic_state(select, &state.into())
     }
 
     /// Get the current state of the IOAPIC
@@ -99,7 +98,7 @@ impl IrqChipX86_64 for KvmKernelIrqChip {
 
     /// Set the current state of the IOAPIC
     fn set_ioapic_state(&mut self, state: &IoapicState) -> Result<()> {
-        self.vm.set_ioapic_state(&kvm_ioapic_state::from(state))
+        self.vm.set_ioapic_state(&state.into())
     }
 
     /// Get the current state of the specified VCPU's local APIC
@@ -113,7 +112,7 @@ impl IrqChipX86_64 for KvmKernelIrqChip {
     /// Set the current state of the specified VCPU's local APIC
     fn set_lapic_state(&mut self, vcpu_id: usize, state: &LapicState) -> Result<()> {
         match self.vcpus.lock().get(vcpu_id) {
-            Some(Some(vcpu)) => vcpu.set_lapic(&kvm_lapic_state::from(state)),
+            Some(Some(vcpu)) => vcpu.set_lapic(&state.into()),
             _ => Err(Error::new(libc::ENOENT)),
         }
     }
@@ -125,7 +124,7 @@ impl IrqChipX86_64 for KvmKernelIrqChip {
 
     /// Sets the state of the PIT. Sets the pit state via the KVM API.
     fn set_pit(&mut self, state: &PitState) -> Result<()> {
-        self.vm.set_pit_state(&kvm_pit_state2::from(state))
+        self.vm.set_pit_state(&state.into())
     }
 
     /// Returns true if the PIT uses port 0x61 for the PC speaker, false if 0x61 is unused.
@@ -165,6 +164,7 @@ fn kvm_dummy_msi_routes() -> Vec<IrqRoute> {
                 source: IrqSource::Msi {
                     address: 0,
                     data: 0,
+                    devid: None,
                 },
             },
         );
@@ -322,6 +322,15 @@ impl IrqChip for KvmSplitIrqChip {
 
             if let Some(resample_event) = resample_event {
                 evt.resample_event = Some(resample_event.try_clone()?);
+                // `finalize_devices` hands the resample events registered until then to the ioapic
+                // and pic, later ones are added as they come. Without them, a level triggered
+                // interrupt of a hot-added device is never resampled after the guest's EOI.
+                self.ioapic
+                    .lock()
+                    .add_resample_event(irq as usize, resample_event.try_clone()?);
+                self.pic
+                    .lock()
+                    .add_resample_event(irq as usize, resample_event.try_clone()?);
             }
 
             let mut irq_events = self.irq_events.lock();
@@ -338,13 +347,19 @@ impl IrqChip for KvmSplitIrqChip {
     fn unregister_irq_event(&mut self, irq: u32, irq_event: &Event) -> Result<()> {
         if irq < NUM_IOAPIC_PINS as u32 {
             let mut irq_events = self.irq_events.lock();
-            for (index, evt) in irq_events.iter().enumerate() {
-                if let Some(evt) = evt {
-                    if evt.gsi == irq && irq_event.eq(&evt.event) {
-                        irq_events[index] = None;
-                        break;
-                    }
-                }
+            let index = irq_events.iter().position(|evt| match evt {
+                Some(evt) => evt.gsi == irq && irq_event.eq(&evt.event),
+                None => false,
+            });
+            let removed = index.and_then(|index| irq_events[index].take());
+            drop(irq_events);
+            if let Some(resample_event) = removed.and_then(|evt| evt.resample_event) {
+                self.ioapic
+                    .lock()
+                    .remove_resample_event(irq as usize, &resample_event);
+                self.pic
+                    .lock()
+                    .remove_resample_event(irq as usize, &resample_event);
             }
             Ok(())
         } else {
@@ -366,6 +381,18 @@ impl IrqChip for KvmSplitIrqChip {
         self.vm.set_gsi_routing(&*msi_routes)
     }
 
+    /// Remove the route of an IRQ line or MSI vector.
+    fn unroute_irq(&mut self, irq: u32) -> Result<()> {
+        let mut routes = self.routes.lock();
+        routes.retain(|r| r.gsi != irq);
+
+        // We only call set_gsi_routing with the msi routes
+        let mut msi_routes = routes.clone();
+        msi_routes.retain(|r| matches!(r.source, IrqSource::Msi { .. }));
+
+        self.vm.set_gsi_routing(&*msi_routes)
+    }
+
     /// Replace all irq routes with the supplied routes
     fn set_irq_routes(&mut self, routes: &[IrqRoute]) -> Result<()> {
         let mut current_routes = self.routes.lock();
@@ -510,7 +537,7 @@ impl IrqChip for KvmSplitIrqChip {
     /// Set the current MP state of the specified VCPU.
     fn set_mp_state(&mut self, vcpu_id: usize, state: &MPState) -> Result<()> {
         match self.vcpus.lock().get(vcpu_id) {
-            Some(Some(vcpu)) => vcpu.set_mp_state(&kvm_mp_state::from(state)),
+            Some(Some(vcpu)) => vcpu.set_mp_state(&state.into()),
             _ => Err(Error::new(libc::ENOENT)),
         }
     }
@@ -669,7 +696,7 @@ impl IrqChipX86_64 for KvmSplitIrqChip {
     /// Set the current state of the specified VCPU's local APIC
     fn set_lapic_state(&mut self, vcpu_id: usize, state: &LapicState) -> Result<()> {
         match self.vcpus.lock().get(vcpu_id) {
-            Some(Some(vcpu)) => vcpu.set_lapic(&kvm_lapic_state::from(state)),
+            Some(Some(vcpu)) => vcpu.set_lapic(&state.into()),
             _ => Err(Error::new(libc::ENOENT)),
         }
     }
@@ -832,6 +859,7 @@ mod tests {
             source: IrqSource::Msi {
                 address: 4276092928,
                 data: 0,
+                devid: None,
             },
         })
         .expect("failed to set msi rout");
@@ -841,11 +869,32 @@ mod tests {
             source: IrqSource::Msi {
                 address: 4276092928,
                 data: 32801,
+                devid: None,
             },
         })
         .expect("failed to set msi rout");
     }
 
+    #[test]
+    fn split_irqchip_unroute_irq() {
+        let mut chip = get_split_chip();
+        chip.route_irq(IrqRoute {
+            gsi: 30,
+            source: IrqSource::Msi {
+                address: 4276092928,
+                data: 0,
+                devid: None,
+            },
+        })
+        .expect("failed to set msi route");
+        assert!(chip.routes.lock().iter().any(|r| r.gsi == 30));
+
+        chip.unroute_irq(30).expect("failed to remove msi route");
+        assert!(!chip.routes.lock().iter().any(|r| r.gsi == 30));
+        // legacy routes are untouched
+        assert_eq!(chip.routes_to_chips(4).len(), 2);
+    }
+
     #[test]
     fn irq_event_tokens() {
         let mut chip = get_split_chip();
@@ -972,6 +1021,54 @@ mod tests {
         assert_eq!(state.redirect_table[14].get_vector(), 44);
     }
 
+    #[test]
+    fn resample_event_after_finalize() {
+        let mut chip = get_split_chip();
+
+        let mut mmio_bus = Bus::new();
+        let mut io_bus = Bus::new();
+        let mut resources = SystemAllocator::builder()
+            .add_io_addresses(0xc000, 0x10000)
+            .add_low_mmio_addresses(0, 2048)
+            .add_high_mmio_addresses(2048, 4096)
+            .create_allocator(5)
+            .expect("failed to create SystemAllocator");
+
+        chip.finalize_devices(&mut resources, &mut io_bus, &mut mmio_bus)
+            .expect("failed to finalize devices");
+
+        // register an event and a resample event for irq line 1 the way a hot-added device does
+        let evt = Event::new().expect("failed to create event");
+        let mut resample_evt = Event::new().expect("failed to create event");
+        let evt_index = chip
+            .register_irq_event(1, &evt, Some(&resample_evt))
+            .expect("failed to register_irq_event")
+            .expect("register_irq_event should not return None");
+
+        // Same pic setup as in finalize_devices, with auto EOI.
+        io_bus.write(0x20, &[0x11]);
+        io_bus.write(0x21, &[0x08]);
+        io_bus.write(0x21, &[0xff]);
+        io_bus.write(0x21, &[0x13]);
+
+        evt.write(1).expect("failed to write to event");
+        chip.service_irq_event(evt_index)
+            .expect("failed to service irq");
+        assert_eq!(
+            chip.get_external_interrupt(0)
+                .expect("failed to get external interrupt"),
+            Some(0x9)
+        );
+
+        // the auto EOI should have written the resample event
+        assert_eq!(
+            resample_evt
+                .read_timeout(std::time::Duration::from_secs(1))
+                .expect("failed to read_timeout"),
+            EventReadResult::Count(1)
+        );
+    }
+
     #[test]
     fn get_external_interrupt() {
         let mut chip = get_split_chip();
@@ -74,6 +74,10 @@ pub trait IrqChip: Send {
     /// Route an IRQ line to an interrupt controller, or to a particular MSI vector.
     fn route_irq(&mut self, route: IrqRoute) -> Result<()>;
 
+    /// Remove the route of an IRQ line or MSI vector, so that its GSI can be given to another
+    /// device.
+    fn unroute_irq(&mut self, irq: u32) -> Result<()>;
+
     /// Replace all irq routes with the supplied routes
     fn set_irq_routes(&mut self, routes: &[IrqRoute]) -> Result<()>;
 
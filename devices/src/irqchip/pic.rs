@@ -122,6 +122,15 @@ impl BusDevice for Pic {
             }
         };
     }
+
+    // The state is saved with the rest of the irqchip.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
 }
 
 impl Pic {
@@ -160,6 +169,22 @@ impl Pic {
         self.resample_events = resample_events;
     }
 
+    /// Adds a resample event for `gsi` once the resample events were registered, for an irq event
+    /// registered later such as that of a hot-added device.
+    pub fn add_resample_event(&mut self, gsi: usize, resample_evt: Event) {
+        if let Some(resample_events) = self.resample_events.get_mut(gsi) {
+            resample_events.push(resample_evt);
+        }
+    }
+
+    /// Removes a resample event of `gsi` added by `register_resample_events` or
+    /// `add_resample_event`.
+    pub fn remove_resample_event(&mut self, gsi: usize, resample_evt: &Event) {
+        if let Some(resample_events) = self.resample_events.get_mut(gsi) {
+            resample_events.retain(|evt| evt != resample_evt);
+        }
+    }
+
     pub fn service_irq(&mut self, irq: u8, level: bool) -> bool {
         assert!(irq <= 15, "Unexpectedly high value irq: {} vs 15", irq);
 
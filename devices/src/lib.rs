@@ -6,12 +6,16 @@
 
 mod bus;
 mod cmos;
+pub mod cpu_hotplug;
+pub mod fw_cfg;
 mod i8042;
 pub mod irqchip;
 mod pci;
+pub mod pflash;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod pit;
 pub mod pl030;
+mod platform;
 mod proxy;
 #[macro_use]
 mod register_space;
@@ -29,22 +33,27 @@ pub use self::bat::{BatteryError, GoldfishBattery};
 pub use self::bus::Error as BusError;
 pub use self::bus::{Bus, BusAccessInfo, BusDevice, BusRange, BusResumeDevice};
 pub use self::cmos::Cmos;
+pub use self::cpu_hotplug::{CpuHotplugController, CpuHotplugError};
+pub use self::fw_cfg::{Error as FwCfgError, FwCfg};
 pub use self::i8042::I8042Device;
 pub use self::irqchip::*;
 #[cfg(feature = "audio")]
 pub use self::pci::{Ac97Backend, Ac97Dev, Ac97Parameters};
 pub use self::pci::{
     PciAddress, PciConfigIo, PciConfigMmio, PciDevice, PciDeviceError, PciInterruptPin, PciRoot,
-    VfioPciDevice,
+    PcieHotplugSlot, PcieRootPort, VfioPciDevice, PCIE_CONFIG_REGISTER_BITS,
+    PCI_CONFIG_REGISTER_BITS,
 };
+pub use self::pflash::{Error as PflashError, Pflash};
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub use self::pit::{Pit, PitError};
 pub use self::pl030::Pl030;
+pub use self::platform::VfioPlatformDevice;
 pub use self::proxy::Error as ProxyError;
 pub use self::proxy::ProxyDevice;
 pub use self::serial::Serial;
 pub use self::serial_device::SerialDevice;
 pub use self::usb::host_backend::host_backend_device_provider::HostBackendDeviceProvider;
 pub use self::usb::xhci::xhci_controller::XhciController;
-pub use self::vfio::{VfioContainer, VfioDevice};
+pub use self::vfio::{VfioContainer, VfioDevice, VfioDeviceType};
 pub use self::virtio::VirtioPciDevice;
@@ -3,7 +3,6 @@
 // found in the LICENSE file.
 
 use std::collections::VecDeque;
-use std::convert::AsRef;
 use std::convert::TryInto;
 use std::fmt::{self, Display};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -134,6 +133,8 @@ enum AudioError {
     InvalidBufferOffset,
     // Guest did not provide a buffer when needed.
     NoBufferAvailable,
+    // Guest memory is private, so the audio server can't map it.
+    PrivateGuestMemory,
     // Failure to read guest memory.
     ReadingGuestError(GuestMemoryError),
     // Failure to respond to the ServerRequest.
@@ -152,6 +153,7 @@ impl Display for AudioError {
             CreateStream(e) => write!(f, "Failed to create audio stream: {}.", e),
             InvalidBufferOffset => write!(f, "Offset > max usize"),
             NoBufferAvailable => write!(f, "No buffer was available from the Guest"),
+            PrivateGuestMemory => write!(f, "Guest memory isn't shared with the audio server"),
             ReadingGuestError(e) => write!(f, "Failed to read guest memory: {}.", e),
             RespondRequest(e) => write!(f, "Failed to respond to the ServerRequest: {}", e),
             WaitForAction(e) => write!(f, "Failed to wait for a message from the stream: {}", e),
@@ -255,7 +257,7 @@ impl Ac97BusMaster {
     /// Returns any file descriptors that need to be kept open when entering a jail.
     pub fn keep_rds(&self) -> Option<Vec<RawDescriptor>> {
         let mut rds = self.audio_server.keep_fds();
-        rds.push(self.mem.as_raw_descriptor());
+        rds.extend(self.mem.shm().map(AsRawDescriptor::as_raw_descriptor));
         Some(rds)
     }
 
@@ -588,7 +590,10 @@ impl Ac97BusMaster {
                 sample_rate,
                 buffer_frames,
                 &Self::stream_effects(func),
-                self.mem.as_ref().inner(),
+                self.mem
+                    .shm()
+                    .ok_or(AudioError::PrivateGuestMemory)?
+                    .inner(),
                 starting_offsets,
             )
             .map_err(AudioError::CreateStream)?;
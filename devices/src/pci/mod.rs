@@ -16,19 +16,28 @@ mod msix;
 mod pci_configuration;
 mod pci_device;
 mod pci_root;
+mod pcie_root_port;
 mod vfio_pci;
 
 #[cfg(feature = "audio")]
 pub use self::ac97::{Ac97Backend, Ac97Dev, Ac97Parameters};
-pub use self::msix::{MsixCap, MsixConfig, MsixStatus};
+pub use self::msix::{
+    MsixCap, MsixConfig, MsixConfigSnapshot, MsixStatus, BITS_PER_PBA_ENTRY,
+    MAX_MSIX_VECTORS_PER_DEVICE, MSIX_PBA_ENTRIES_MODULO, MSIX_TABLE_ENTRIES_MODULO,
+};
 pub use self::pci_configuration::{
     PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability, PciCapabilityID,
-    PciClassCode, PciConfiguration, PciDisplaySubclass, PciHeaderType, PciProgrammingInterface,
-    PciSerialBusSubClass, PciSubclass,
+    PciClassCode, PciConfiguration, PciDisplaySubclass, PciExtendedCapability,
+    PciExtendedCapabilityID, PciHeaderType, PciProgrammingInterface, PciSerialBusSubClass,
+    PciSubclass,
 };
 pub use self::pci_device::Error as PciDeviceError;
 pub use self::pci_device::PciDevice;
-pub use self::pci_root::{PciAddress, PciConfigIo, PciConfigMmio, PciRoot};
+pub use self::pci_root::{
+    PciAddress, PciConfigIo, PciConfigMmio, PciRoot, PCIE_CONFIG_REGISTER_BITS,
+    PCI_CONFIG_REGISTER_BITS,
+};
+pub use self::pcie_root_port::{PcieHotplugSlot, PcieRootPort};
 pub use self::vfio_pci::VfioPciDevice;
 
 /// PCI has four interrupt pins A->D.
@@ -2,23 +2,23 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use crate::pci::{PciCapability, PciCapabilityID};
+use crate::pci::{PciAddress, PciCapability, PciCapabilityID};
 use base::{error, AsRawDescriptor, Error as SysError, Event, RawDescriptor};
-use msg_socket::{MsgError, MsgReceiver, MsgSender};
+use msg_socket::{MsgError, MsgOnSocket, MsgReceiver, MsgSender};
 use std::convert::TryInto;
 use std::fmt::{self, Display};
 use vm_control::{MaybeOwnedDescriptor, VmIrqRequest, VmIrqRequestSocket, VmIrqResponse};
 
 use data_model::DataInit;
 
-const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
+pub const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
 pub const MSIX_TABLE_ENTRIES_MODULO: u64 = 16;
 pub const MSIX_PBA_ENTRIES_MODULO: u64 = 8;
 pub const BITS_PER_PBA_ENTRY: usize = 64;
 const FUNCTION_MASK_BIT: u16 = 0x4000;
 const MSIX_ENABLE_BIT: u16 = 0x8000;
 
-#[derive(Clone)]
+#[derive(Clone, MsgOnSocket)]
 struct MsixTableEntry {
     msg_addr_lo: u32,
     msg_addr_hi: u32,
@@ -57,6 +57,8 @@ pub struct MsixConfig {
     enabled: bool,
     msi_device_socket: VmIrqRequestSocket,
     msix_num: u16,
+    // The PCI requester ID the device's MSIs are sent with, once it has an address.
+    devid: Option<u32>,
 }
 
 enum MsixError {
@@ -87,6 +89,16 @@ impl Display for MsixError {
 
 type MsixResult<T> = std::result::Result<T, MsixError>;
 
+/// The state of a `MsixConfig` saved in a VM snapshot. The GSIs of the vectors aren't part of it,
+/// as they are allocated again when the snapshot is restored.
+#[derive(MsgOnSocket)]
+pub struct MsixConfigSnapshot {
+    table_entries: Vec<MsixTableEntry>,
+    pba_entries: Vec<u64>,
+    masked: bool,
+    enabled: bool,
+}
+
 pub enum MsixStatus {
     Changed,
     EntryChanged(usize),
@@ -111,9 +123,16 @@ impl MsixConfig {
             enabled: false,
             msi_device_socket: vm_socket,
             msix_num: msix_vectors,
+            devid: None,
         }
     }
 
+    /// Sets the PCI address of the device, which identifies the sender of its MSIs to interrupt
+    /// controllers that need to know it, like the ARM ITS.
+    pub fn set_pci_address(&mut self, address: PciAddress) {
+        self.devid = Some(address.to_bdf().into());
+    }
+
     /// Get the number of MSI-X vectors in this configuration.
     pub fn num_vectors(&self) -> u16 {
         self.msix_num
@@ -219,6 +238,7 @@ impl MsixConfig {
                 gsi,
                 msi_address,
                 msi_data,
+                msi_devid: self.devid,
             })
             .map_err(MsixError::AddMsiRouteSend)?;
         if let VmIrqResponse::Err(e) = self
@@ -232,34 +252,70 @@ impl MsixConfig {
     }
 
     fn msix_enable(&mut self) -> MsixResult<()> {
-        self.irq_vec.clear();
+        // Each vector keeps its GSI once allocated, so enabling MSI-X again after the guest
+        // disabled it only restores the routes.
         for i in 0..self.msix_num {
-            let irqfd = Event::new().unwrap();
-            self.msi_device_socket
-                .send(&VmIrqRequest::AllocateOneMsi {
-                    irqfd: MaybeOwnedDescriptor::Borrowed(irqfd.as_raw_descriptor()),
-                })
-                .map_err(MsixError::AllocateOneMsiSend)?;
-            let irq_num: u32;
-            match self
-                .msi_device_socket
-                .recv()
-                .map_err(MsixError::AllocateOneMsiRecv)?
-            {
-                VmIrqResponse::AllocateOneMsi { gsi } => irq_num = gsi,
-                VmIrqResponse::Err(e) => return Err(MsixError::AllocateOneMsi(e)),
-                _ => unreachable!(),
-            }
-            self.irq_vec.push(IrqfdGsi {
-                irqfd,
-                gsi: irq_num,
-            });
+            let irq_num = match self.irq_vec.get(i as usize) {
+                Some(irq) => irq.gsi,
+                None => {
+                    let irqfd = Event::new().unwrap();
+                    self.msi_device_socket
+                        .send(&VmIrqRequest::AllocateOneMsi {
+                            irqfd: MaybeOwnedDescriptor::Borrowed(irqfd.as_raw_descriptor()),
+                        })
+                        .map_err(MsixError::AllocateOneMsiSend)?;
+                    let gsi = match self
+                        .msi_device_socket
+                        .recv()
+                        .map_err(MsixError::AllocateOneMsiRecv)?
+                    {
+                        VmIrqResponse::AllocateOneMsi { gsi } => gsi,
+                        VmIrqResponse::Err(e) => return Err(MsixError::AllocateOneMsi(e)),
+                        _ => unreachable!(),
+                    };
+                    self.irq_vec.push(IrqfdGsi { irqfd, gsi });
+                    gsi
+                }
+            };
 
             self.add_msi_route(i, irq_num)?;
         }
         Ok(())
     }
 
+    /// Saves the MSI-X table, PBA and control bits for a VM snapshot.
+    pub fn snapshot(&self) -> MsixConfigSnapshot {
+        MsixConfigSnapshot {
+            table_entries: self.table_entries.clone(),
+            pba_entries: self.pba_entries.clone(),
+            masked: self.masked,
+            enabled: self.enabled,
+        }
+    }
+
+    /// Loads the state saved by `snapshot`, routing the vectors again if MSI-X was enabled. Returns
+    /// false if the snapshot was taken with a different number of vectors or the routes couldn't
+    /// be added.
+    pub fn restore(&mut self, snapshot: MsixConfigSnapshot) -> bool {
+        if snapshot.table_entries.len() != self.table_entries.len()
+            || snapshot.pba_entries.len() != self.pba_entries.len()
+        {
+            return false;
+        }
+        self.table_entries = snapshot.table_entries;
+        self.pba_entries = snapshot.pba_entries;
+        self.masked = snapshot.masked;
+        self.enabled = snapshot.enabled;
+        if self.enabled {
+            if let Err(e) = self.msix_enable() {
+                error!("failed to enable restored MSI-X: {}", e);
+                self.enabled = false;
+                return false;
+            }
+        }
+        true
+    }
+
     /// Read MSI-X table
     ///  # Arguments
     ///  * 'offset' - the offset within the MSI-X Table
@@ -489,7 +545,14 @@ impl MsixConfig {
     /// If the vector is unmasked, writing to irqfd which wakes up KVM to
     /// inject virtual interrupt to the guest.
     pub fn trigger(&mut self, vector: u16) {
-        if self.table_entries[vector as usize].masked() || self.masked() {
+        let entry = match self.table_entries.get(vector as usize) {
+            Some(entry) => entry,
+            None => {
+                error!("MSI-X vector {} out of range", vector);
+                return;
+            }
+        };
+        if entry.masked() || self.masked() {
             self.set_pba_bit(vector, true);
         } else if let Some(irq) = self.irq_vec.get(vector as usize) {
             irq.irqfd.write(1).unwrap();
@@ -564,7 +627,7 @@ impl MsixCap {
         pba_pci_bar: u8,
         pba_off: u32,
     ) -> Self {
-        assert!(table_size < MAX_MSIX_VECTORS_PER_DEVICE);
+        assert!(table_size <= MAX_MSIX_VECTORS_PER_DEVICE);
 
         // Set the table size and enable MSI-X.
         let msg_ctl: u16 = MSIX_ENABLE_BIT + table_size - 1;
@@ -9,7 +9,11 @@ use crate::pci::PciInterruptPin;
 use base::warn;
 
 // The number of 32bit registers in the config space, 256 bytes.
-const NUM_CONFIGURATION_REGISTERS: usize = 64;
+// PCI Express functions have 4 KiB of configuration space, of which conventional PCI uses the
+// first 256 bytes.
+const NUM_CONFIGURATION_REGISTERS: usize = 1024;
+/// Number of 32-bit registers in conventional PCI configuration space.
+pub const NUM_CONVENTIONAL_CONFIGURATION_REGISTERS: usize = 64;
 
 pub const COMMAND_REG: usize = 1;
 pub const COMMAND_REG_IO_SPACE_MASK: u32 = 0x0000_0001;
@@ -25,6 +29,13 @@ const NUM_BAR_REGS: usize = 6;
 const CAPABILITY_LIST_HEAD_OFFSET: usize = 0x34;
 const FIRST_CAPABILITY_OFFSET: usize = 0x40;
 const CAPABILITY_MAX_OFFSET: usize = 192;
+const FIRST_EXTENDED_CAPABILITY_OFFSET: usize = 0x100;
+const EXTENDED_CAPABILITY_MAX_OFFSET: usize = NUM_CONFIGURATION_REGISTERS * 4;
+// The extended capability header is the ID in bits 15:0, the version in 19:16 and the offset of
+// the next capability in 31:20.
+const EXTENDED_CAPABILITY_HEADER_LEN: usize = 4;
+const EXTENDED_CAPABILITY_VERSION_SHIFT: u32 = 16;
+const EXTENDED_CAPABILITY_NEXT_SHIFT: u32 = 20;
 
 const INTERRUPT_LINE_PIN_REG: usize = 15;
 
@@ -186,6 +197,27 @@ pub trait PciCapability {
     fn id(&self) -> PciCapabilityID;
 }
 
+/// Types of PCI Express extended capabilities.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciExtendedCapabilityID {
+    AdvancedErrorReporting = 0x0001,
+    VirtualChannel = 0x0002,
+    DeviceSerialNumber = 0x0003,
+    PowerBudgeting = 0x0004,
+    VendorSpecific = 0x000B,
+    AccessControlServices = 0x000D,
+    AlternativeRoutingIdInterpretation = 0x000E,
+    SingleRootIoVirtualization = 0x0010,
+}
+
+/// A PCI Express extended capability, placed in configuration space after the first 256 bytes.
+pub trait PciExtendedCapability {
+    fn bytes(&self) -> &[u8];
+    fn id(&self) -> PciExtendedCapabilityID;
+    fn version(&self) -> u8;
+}
+
 /// Contains the configuration space of a PCI node.
 /// See the [specification](https://en.wikipedia.org/wiki/PCI_configuration_space).
 /// The configuration space is accessed with DWORD reads and writes from the guest.
@@ -196,6 +228,8 @@ pub struct PciConfiguration {
     bar_configs: [Option<PciBarConfiguration>; NUM_BAR_REGS],
     // Contains the byte offset and size of the last capability.
     last_capability: Option<(usize, usize)>,
+    // Contains the byte offset and size of the last extended capability.
+    last_extended_capability: Option<(usize, usize)>,
 }
 
 /// See pci_regs.h in kernel
@@ -314,11 +348,18 @@ impl PciConfiguration {
             }
             PciHeaderType::Bridge => {
                 registers[3] = 0x0001_0000; // Header type 1 (bridge)
-                writable_bits[9] = 0xfff0_fff0; // Memory base and limit
+                writable_bits[6] = 0x00ff_ffff; // Primary, secondary and subordinate bus numbers
+                writable_bits[7] = 0x0000_f0f0; // I/O base and limit
+                writable_bits[8] = 0xfff0_fff0; // Memory base and limit
+                writable_bits[9] = 0xfff0_fff0; // Prefetchable memory base and limit
                 writable_bits[15] = 0xffff_00ff; // Bridge control (r/w), interrupt line (r/w)
             }
         };
-        registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+        // Bridges have no subsystem IDs in the header, register 11 is the upper half of their
+        // prefetchable memory limit.
+        if let PciHeaderType::Device = header_type {
+            registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+        }
 
         PciConfiguration {
             registers,
@@ -326,6 +367,7 @@ impl PciConfiguration {
             bar_used: [false; NUM_BAR_REGS],
             bar_configs: [None; NUM_BAR_REGS],
             last_capability: None,
+            last_extended_capability: None,
         }
     }
 
@@ -334,6 +376,21 @@ impl PciConfiguration {
         *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
     }
 
+    /// Returns the contents of every register, for a device snapshot.
+    pub fn snapshot(&self) -> Vec<u32> {
+        self.registers.to_vec()
+    }
+
+    /// Loads the registers saved by `snapshot`. Returns false if `registers` doesn't cover the
+    /// whole configuration space.
+    pub fn restore(&mut self, registers: &[u32]) -> bool {
+        if registers.len() != NUM_CONFIGURATION_REGISTERS {
+            return false;
+        }
+        self.registers.copy_from_slice(registers);
+        true
+    }
+
     /// Writes data to PciConfiguration.registers.
     /// `reg_idx` - index into PciConfiguration.registers.
     /// `offset`  - PciConfiguration.registers is in unit of DWord, offset define byte
@@ -574,6 +631,45 @@ impl PciConfiguration {
         Ok(cap_offset)
     }
 
+    /// Adds the PCI Express extended capability `cap_data` to the list of extended capabilities.
+    /// `cap_data` should include the four-byte extended capability header, but not populate it.
+    /// The header is generated from `cap_data.id()` and `cap_data.version()`.
+    pub fn add_extended_capability(
+        &mut self,
+        cap_data: &dyn PciExtendedCapability,
+    ) -> Result<usize> {
+        let total_len = cap_data.bytes().len();
+        if total_len < EXTENDED_CAPABILITY_HEADER_LEN {
+            return Err(Error::CapabilityLengthInvalid(total_len));
+        }
+        let cap_offset = match self.last_extended_capability {
+            Some((offset, len)) => Self::next_dword(offset, len),
+            None => FIRST_EXTENDED_CAPABILITY_OFFSET,
+        };
+        let end_offset = cap_offset
+            .checked_add(total_len)
+            .ok_or(Error::CapabilitySpaceFull(total_len))?;
+        if end_offset > EXTENDED_CAPABILITY_MAX_OFFSET {
+            return Err(Error::CapabilitySpaceFull(total_len));
+        }
+        if let Some((last_offset, _)) = self.last_extended_capability {
+            self.registers[last_offset / 4] |=
+                (cap_offset as u32) << EXTENDED_CAPABILITY_NEXT_SHIFT;
+        }
+        self.registers[cap_offset / 4] = cap_data.id() as u32
+            | u32::from(cap_data.version() & 0xf) << EXTENDED_CAPABILITY_VERSION_SHIFT;
+        for (i, byte) in cap_data
+            .bytes()
+            .iter()
+            .enumerate()
+            .skip(EXTENDED_CAPABILITY_HEADER_LEN)
+        {
+            self.write_byte_internal(cap_offset + i, *byte, false);
+        }
+        self.last_extended_capability = Some((cap_offset, total_len));
+        Ok(cap_offset)
+    }
+
     // Find the next aligned offset after the one given.
     fn next_dword(offset: usize, len: usize) -> usize {
         let next = offset + len;
@@ -712,6 +808,73 @@ mod tests {
         assert_eq!((cap2_data >> 24) & 0xFF, 0x55); // cap2.foo
     }
 
+    #[repr(packed)]
+    #[derive(Clone, Copy)]
+    #[allow(dead_code)]
+    struct TestExtendedCap {
+        _header: u32,
+        data: u32,
+    }
+
+    // It is safe to implement DataInit; all members are simple numbers and any value is valid.
+    unsafe impl DataInit for TestExtendedCap {}
+
+    impl PciExtendedCapability for TestExtendedCap {
+        fn bytes(&self) -> &[u8] {
+            self.as_slice()
+        }
+
+        fn id(&self) -> PciExtendedCapabilityID {
+            PciExtendedCapabilityID::VendorSpecific
+        }
+
+        fn version(&self) -> u8 {
+            1
+        }
+    }
+
+    #[test]
+    fn add_extended_capability() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+        );
+
+        // Extended configuration space reads as zero, meaning no extended capabilities.
+        assert_eq!(cfg.read_reg(FIRST_EXTENDED_CAPABILITY_OFFSET / 4), 0);
+
+        let cap1 = TestExtendedCap {
+            _header: 0,
+            data: 0xAAAA_AAAA,
+        };
+        let cap1_offset = cfg.add_extended_capability(&cap1).unwrap();
+        assert_eq!(cap1_offset, FIRST_EXTENDED_CAPABILITY_OFFSET);
+
+        let cap2 = TestExtendedCap {
+            _header: 0,
+            data: 0x5555_5555,
+        };
+        let cap2_offset = cfg.add_extended_capability(&cap2).unwrap();
+        assert_eq!(cap2_offset, cap1_offset + 8);
+
+        // ID 0x000B, version 1 and the offset of the next capability.
+        let cap1_header = cfg.read_reg(cap1_offset / 4);
+        assert_eq!(cap1_header, (cap2_offset as u32) << 20 | 0x1_000B);
+        assert_eq!(cfg.read_reg(cap1_offset / 4 + 1), 0xAAAA_AAAA);
+        assert_eq!(cfg.read_reg(cap2_offset / 4), 0x1_000B);
+        assert_eq!(cfg.read_reg(cap2_offset / 4 + 1), 0x5555_5555);
+
+        // Extended capabilities are read-only.
+        cfg.write_reg(cap1_offset / 4 + 1, 0, &[0, 0, 0, 0]);
+        assert_eq!(cfg.read_reg(cap1_offset / 4 + 1), 0xAAAA_AAAA);
+    }
+
     #[derive(Copy, Clone)]
     enum TestPI {
         Test = 0x5a,
@@ -1094,4 +1257,31 @@ mod tests {
             Err(Error::BarSizeInvalid(0x8))
         );
     }
+
+    #[test]
+    fn snapshot_restore() {
+        let new_cfg = || {
+            PciConfiguration::new(
+                0x1234,
+                0x5678,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioController,
+                None,
+                PciHeaderType::Device,
+                0xABCD,
+                0x2468,
+            )
+        };
+        let mut cfg = new_cfg();
+        // Enable memory decoding and set the interrupt line.
+        cfg.write_reg(1, 0, &[0x02, 0x00]);
+        cfg.write_reg(15, 0, &[0x0b]);
+        let snapshot = cfg.snapshot();
+
+        let mut restored = new_cfg();
+        assert!(restored.restore(&snapshot));
+        assert_eq!(restored.read_reg(1) & 0xffff, 0x0002);
+        assert_eq!(restored.read_reg(15) & 0xff, 0x0b);
+        assert!(!restored.restore(&snapshot[..16]));
+    }
 }
@@ -4,6 +4,7 @@
 
 use std::fmt::{self, Display};
 
+use acpi_tables::sdt::SDT;
 use base::{Event, RawDescriptor};
 use hypervisor::Datamatch;
 use resources::{Error as SystemAllocatorFaliure, SystemAllocator};
@@ -128,6 +129,26 @@ pub trait PciDevice: Send {
     fn write_bar(&mut self, addr: u64, data: &[u8]);
     /// Invoked when the device is sandboxed.
     fn on_device_sandboxed(&mut self) {}
+    /// Invoked when the VM is suspended.
+    fn on_device_suspend(&mut self) {}
+    /// Invoked when the VM resumes after being suspended.
+    fn on_device_resume(&mut self) {}
+    /// Invoked when the guest puts itself to sleep through ACPI.
+    fn on_device_sleep(&mut self) {}
+    /// Invoked when the guest is woken up after `on_device_sleep`.
+    fn on_device_wake(&mut self) {}
+    /// Saves the state of the device, including its configuration space, for a VM snapshot.
+    /// Returns `None` if the device can't be snapshotted.
+    fn snapshot_device(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Loads the state saved by `snapshot_device`. Returns false if it can't be restored.
+    fn restore_device(&mut self, _data: &[u8]) -> bool {
+        false
+    }
+    /// Adds the ACPI tables that describe the device to the guest to `sdts`. Called once the
+    /// device's address is allocated.
+    fn generate_acpi(&mut self, _sdts: &mut Vec<SDT>) {}
 }
 
 impl<T: PciDevice> BusDevice for T {
@@ -183,6 +204,30 @@ impl<T: PciDevice> BusDevice for T {
     fn on_sandboxed(&mut self) {
         self.on_device_sandboxed();
     }
+
+    fn on_suspend(&mut self) {
+        self.on_device_suspend();
+    }
+
+    fn on_resume(&mut self) {
+        self.on_device_resume();
+    }
+
+    fn on_sleep(&mut self) {
+        self.on_device_sleep();
+    }
+
+    fn on_wake(&mut self) {
+        self.on_device_wake();
+    }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        self.snapshot_device()
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        self.restore_device(data)
+    }
 }
 
 impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
@@ -233,6 +278,27 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn on_device_sandboxed(&mut self) {
         (**self).on_device_sandboxed()
     }
+    fn on_device_suspend(&mut self) {
+        (**self).on_device_suspend()
+    }
+    fn on_device_resume(&mut self) {
+        (**self).on_device_resume()
+    }
+    fn on_device_sleep(&mut self) {
+        (**self).on_device_sleep()
+    }
+    fn on_device_wake(&mut self) {
+        (**self).on_device_wake()
+    }
+    fn snapshot_device(&mut self) -> Option<Vec<u8>> {
+        (**self).snapshot_device()
+    }
+    fn restore_device(&mut self, data: &[u8]) -> bool {
+        (**self).restore_device(data)
+    }
+    fn generate_acpi(&mut self, sdts: &mut Vec<SDT>) {
+        (**self).generate_acpi(sdts)
+    }
 }
 
 #[cfg(test)]
@@ -14,6 +14,7 @@ use crate::pci::pci_configuration::{
     PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType,
 };
 use crate::pci::pci_device::{Error, PciDevice};
+use crate::pci::pcie_root_port::PcieHotplugSlot;
 use crate::{BusAccessInfo, BusDevice};
 use resources::SystemAllocator;
 
@@ -50,6 +51,13 @@ impl PciDevice for PciRootConfiguration {
     fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
 }
 
+/// Number of configuration address bits that select a register of a function with conventional
+/// PCI configuration space, as used by the 0xcf8 mechanism and CAM.
+pub const PCI_CONFIG_REGISTER_BITS: usize = 8;
+/// Number of configuration address bits that select a register of a function with PCI Express
+/// extended configuration space, as used by ECAM.
+pub const PCIE_CONFIG_REGISTER_BITS: usize = 12;
+
 /// PCI Device Address, AKA Bus:Device.Function
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PciAddress {
@@ -74,12 +82,19 @@ impl PciAddress {
     const REGISTER_OFFSET: usize = 2;
     const REGISTER_MASK: u32 = 0x3f;
 
-    /// Construct PciAddress and register tuple from CONFIG_ADDRESS value.
-    pub fn from_config_address(config_address: u32) -> (Self, usize) {
-        let bus = ((config_address >> Self::BUS_OFFSET) & Self::BUS_MASK) as u8;
-        let dev = ((config_address >> Self::DEVICE_OFFSET) & Self::DEVICE_MASK) as u8;
-        let func = ((config_address >> Self::FUNCTION_OFFSET) & Self::FUNCTION_MASK) as u8;
-        let register = ((config_address >> Self::REGISTER_OFFSET) & Self::REGISTER_MASK) as usize;
+    /// Construct PciAddress and register tuple from a configuration address whose low
+    /// `register_bits_num` bits select the register: `PCI_CONFIG_REGISTER_BITS` for a
+    /// CONFIG_ADDRESS value or a CAM offset, `PCIE_CONFIG_REGISTER_BITS` for an ECAM offset.
+    pub fn from_config_address(config_address: u32, register_bits_num: usize) -> (Self, usize) {
+        let func_offset = register_bits_num;
+        let dev_offset = func_offset + 3;
+        let bus_offset = dev_offset + 5;
+        let register_mask = (1u32 << (register_bits_num - Self::REGISTER_OFFSET)) - 1;
+
+        let bus = ((config_address >> bus_offset) & Self::BUS_MASK) as u8;
+        let dev = ((config_address >> dev_offset) & Self::DEVICE_MASK) as u8;
+        let func = ((config_address >> func_offset) & Self::FUNCTION_MASK) as u8;
+        let register = ((config_address >> Self::REGISTER_OFFSET) & register_mask) as usize;
 
         (PciAddress { bus, dev, func }, register)
     }
@@ -107,6 +122,13 @@ impl PciAddress {
             | ((Self::REGISTER_MASK & register as u32) << Self::REGISTER_OFFSET)
     }
 
+    /// Returns the bus, device and function numbers packed into a 16-bit routing ID.
+    pub fn to_bdf(&self) -> u16 {
+        ((self.bus as u16) << 8)
+            | ((Self::DEVICE_MASK as u16 & self.dev as u16) << 3)
+            | (Self::FUNCTION_MASK as u16 & self.func as u16)
+    }
+
     /// Returns true if the address points to PCI root host-bridge.
     fn is_root(&self) -> bool {
         matches!(
@@ -126,6 +148,8 @@ pub struct PciRoot {
     root_configuration: PciRootConfiguration,
     /// Devices attached to this bridge.
     devices: BTreeMap<PciAddress, Arc<Mutex<dyn BusDevice>>>,
+    /// Hotplug slots of the root ports on this bridge, whose devices are on the secondary buses.
+    hotplug_slots: Vec<PcieHotplugSlot>,
 }
 
 const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
@@ -148,6 +172,7 @@ impl PciRoot {
                 ),
             },
             devices: BTreeMap::new(),
+            hotplug_slots: Vec::new(),
         }
     }
 
@@ -159,12 +184,46 @@ impl PciRoot {
         }
     }
 
+    /// Remove the device at `address` from this root PCI bus, returning it if there was one.
+    pub fn remove_device(&mut self, address: PciAddress) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        self.devices.remove(&address)
+    }
+
+    /// Add the hotplug `slot` of a root port on this bus, so that configuration accesses to the
+    /// secondary bus of the port reach the device in the slot.
+    pub fn add_hotplug_slot(&mut self, slot: PcieHotplugSlot) {
+        self.hotplug_slots.push(slot);
+    }
+
+    // Finds the device at `address` on the root bus or behind one of its root ports. A root port
+    // only has device 0 on its secondary bus.
+    fn device(&self, address: PciAddress) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        if let Some(d) = self.devices.get(&address) {
+            return Some(d.clone());
+        }
+        if address.dev != 0 {
+            return None;
+        }
+        self.hotplug_slots
+            .iter()
+            .find_map(|slot| slot.device_on_bus(address.bus))
+    }
+
+    /// Returns the configuration registers of the root bridge itself, for a VM snapshot.
+    pub fn snapshot_root_config(&self) -> Vec<u32> {
+        self.root_configuration.config.snapshot()
+    }
+
+    /// Loads the registers saved by `snapshot_root_config`. Returns false if they don't fit.
+    pub fn restore_root_config(&mut self, registers: &[u32]) -> bool {
+        self.root_configuration.config.restore(registers)
+    }
+
     pub fn config_space_read(&self, address: PciAddress, register: usize) -> u32 {
         if address.is_root() {
             self.root_configuration.config_register_read(register)
         } else {
-            self.devices
-                .get(&address)
+            self.device(address)
                 .map_or(0xffff_ffff, |d| d.lock().config_register_read(register))
         }
     }
@@ -182,7 +241,7 @@ impl PciRoot {
         if address.is_root() {
             self.root_configuration
                 .config_register_write(register, offset, data);
-        } else if let Some(d) = self.devices.get(&address) {
+        } else if let Some(d) = self.device(address) {
             d.lock().config_register_write(register, offset, data);
         }
     }
@@ -191,13 +250,13 @@ impl PciRoot {
 /// Emulates PCI configuration access mechanism #1 (I/O ports 0xcf8 and 0xcfc).
 pub struct PciConfigIo {
     /// PCI root bridge.
-    pci_root: PciRoot,
+    pci_root: Arc<Mutex<PciRoot>>,
     /// Current address to read/write from (0xcf8 register, litte endian).
     config_address: u32,
 }
 
 impl PciConfigIo {
-    pub fn new(pci_root: PciRoot) -> Self {
+    pub fn new(pci_root: Arc<Mutex<PciRoot>>) -> Self {
         PciConfigIo {
             pci_root,
             config_address: 0,
@@ -210,8 +269,9 @@ impl PciConfigIo {
             return 0xffff_ffff;
         }
 
-        let (address, register) = PciAddress::from_config_address(self.config_address);
-        self.pci_root.config_space_read(address, register)
+        let (address, register) =
+            PciAddress::from_config_address(self.config_address, PCI_CONFIG_REGISTER_BITS);
+        self.pci_root.lock().config_space_read(address, register)
     }
 
     fn config_space_write(&mut self, offset: u64, data: &[u8]) {
@@ -220,8 +280,10 @@ impl PciConfigIo {
             return;
         }
 
-        let (address, register) = PciAddress::from_config_address(self.config_address);
+        let (address, register) =
+            PciAddress::from_config_address(self.config_address, PCI_CONFIG_REGISTER_BITS);
         self.pci_root
+            .lock()
             .config_space_write(address, register, offset, data)
     }
 
@@ -280,34 +342,63 @@ impl BusDevice for PciConfigIo {
             _ => (),
         };
     }
+
+    // The root bridge is saved by `PciConfigMmio`, which every architecture has.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        Some(self.config_address.to_le_bytes().to_vec())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        match data.try_into() {
+            Ok(config_address) => {
+                self.config_address = u32::from_le_bytes(config_address);
+                true
+            }
+            Err(_) => false,
+        }
+    }
 }
 
-/// Emulates PCI memory-mapped configuration access mechanism.
+/// Emulates PCI memory-mapped configuration access mechanisms: CAM, with 256 bytes of
+/// configuration space per function, or PCI Express ECAM, with 4 KiB.
 pub struct PciConfigMmio {
     /// PCI root bridge.
-    pci_root: PciRoot,
+    pci_root: Arc<Mutex<PciRoot>>,
+    /// Number of address bits that select the register, `PCI_CONFIG_REGISTER_BITS` or
+    /// `PCIE_CONFIG_REGISTER_BITS`.
+    register_bits_num: usize,
 }
 
 impl PciConfigMmio {
-    pub fn new(pci_root: PciRoot) -> Self {
-        PciConfigMmio { pci_root }
+    pub fn new(pci_root: Arc<Mutex<PciRoot>>, register_bits_num: usize) -> Self {
+        PciConfigMmio {
+            pci_root,
+            register_bits_num,
+        }
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (address, register) = PciAddress::from_config_address(config_address);
-        self.pci_root.config_space_read(address, register)
+        let (address, register) =
+            PciAddress::from_config_address(config_address, self.register_bits_num);
+        self.pci_root.lock().config_space_read(address, register)
     }
 
     fn config_space_write(&mut self, config_address: u32, offset: u64, data: &[u8]) {
-        let (address, register) = PciAddress::from_config_address(config_address);
+        let (address, register) =
+            PciAddress::from_config_address(config_address, self.register_bits_num);
         self.pci_root
+            .lock()
             .config_space_write(address, register, offset, data)
     }
 }
 
 impl BusDevice for PciConfigMmio {
     fn debug_label(&self) -> String {
-        "pci config mmio".to_owned()
+        if self.register_bits_num == PCIE_CONFIG_REGISTER_BITS {
+            "pcie config mmio".to_owned()
+        } else {
+            "pci config mmio".to_owned()
+        }
     }
 
     fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
@@ -333,4 +424,60 @@ impl BusDevice for PciConfigMmio {
         }
         self.config_space_write(info.offset as u32, info.offset % 4, data)
     }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let registers = self.pci_root.lock().snapshot_root_config();
+        Some(
+            registers
+                .iter()
+                .flat_map(|r| r.to_le_bytes().to_vec())
+                .collect(),
+        )
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        if data.len() % 4 != 0 {
+            return false;
+        }
+        let registers: Vec<u32> = data
+            .chunks(4)
+            .map(|r| u32::from_le_bytes([r[0], r[1], r[2], r[3]]))
+            .collect();
+        self.pci_root.lock().restore_root_config(&registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_address() {
+        // CONFIG_ADDRESS of 03:04.5, register 0x3c with the enable bit set.
+        let (address, register) =
+            PciAddress::from_config_address(0x8003_253c, PCI_CONFIG_REGISTER_BITS);
+        assert!(
+            address
+                == PciAddress {
+                    bus: 3,
+                    dev: 4,
+                    func: 5
+                }
+        );
+        assert_eq!(register, 0x3c / 4);
+        assert_eq!(address.to_config_address(register), 0x0003_253c);
+
+        // ECAM offset of the same function, register 0x104 in extended configuration space.
+        let (address, register) =
+            PciAddress::from_config_address(0x0032_5104, PCIE_CONFIG_REGISTER_BITS);
+        assert!(
+            address
+                == PciAddress {
+                    bus: 3,
+                    dev: 4,
+                    func: 5
+                }
+        );
+        assert_eq!(register, 0x104 / 4);
+    }
 }
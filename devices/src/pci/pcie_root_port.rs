@@ -0,0 +1,580 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+
+use base::{warn, Event, RawDescriptor};
+use data_model::DataInit;
+use resources::{Alloc, SystemAllocator};
+use sync::Mutex;
+
+use crate::pci::pci_configuration::{
+    PciBridgeSubclass, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration,
+    PciHeaderType,
+};
+use crate::pci::pci_device::{PciDevice, Result};
+use crate::pci::{PciAddress, PciDeviceError, PciInterruptPin};
+use crate::BusDevice;
+
+const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+const PCI_DEVICE_ID_INTEL_PCIE_ROOT_PORT: u16 = 0x3420;
+
+// PCI Express Capabilities Register
+const PCIE_CAP_VERSION: u16 = 0x2;
+const PCIE_CAP_TYPE_ROOT_PORT: u16 = 0x4 << 4;
+const PCIE_CAP_SLOT_IMPLEMENTED: u16 = 1 << 8;
+// Link Capabilities and Link Status Registers
+const PCIE_LINK_SPEED_2_5GT: u32 = 0x1;
+const PCIE_LINK_WIDTH_X1: u32 = 0x1 << 4;
+const PCIE_LINK_CAP_ACTIVE_REPORTING: u32 = 1 << 20;
+const PCIE_LINK_CAP_PORT_NUMBER_SHIFT: u32 = 24;
+const PCIE_LINK_STS_ACTIVE: u16 = 1 << 13;
+// Slot Capabilities Register
+const PCIE_SLOT_CAP_ATTENTION_BUTTON: u32 = 1 << 0;
+const PCIE_SLOT_CAP_POWER_CONTROLLER: u32 = 1 << 1;
+const PCIE_SLOT_CAP_HOTPLUG_CAPABLE: u32 = 1 << 6;
+const PCIE_SLOT_CAP_NO_COMMAND_COMPLETED: u32 = 1 << 18;
+const PCIE_SLOT_CAP_SLOT_NUMBER_SHIFT: u32 = 19;
+// Slot Control Register
+const PCIE_SLOT_CTL_ATTENTION_BUTTON_ENABLE: u16 = 1 << 0;
+const PCIE_SLOT_CTL_PRESENCE_CHANGED_ENABLE: u16 = 1 << 3;
+const PCIE_SLOT_CTL_HOTPLUG_INTERRUPT_ENABLE: u16 = 1 << 5;
+const PCIE_SLOT_CTL_POWER_OFF: u16 = 1 << 10;
+const PCIE_SLOT_CTL_LINK_CHANGED_ENABLE: u16 = 1 << 12;
+// Slot Status Register
+const PCIE_SLOT_STS_ATTENTION_BUTTON: u16 = 1 << 0;
+const PCIE_SLOT_STS_PRESENCE_CHANGED: u16 = 1 << 3;
+const PCIE_SLOT_STS_PRESENCE: u16 = 1 << 6;
+const PCIE_SLOT_STS_LINK_CHANGED: u16 = 1 << 8;
+// Event bits of the Slot Status Register, which the guest clears by writing 1 to them.
+const PCIE_SLOT_STS_EVENTS: u16 = 0x011f;
+// Link Capabilities 2 Register
+const PCIE_LINK_CAP2_SPEEDS_2_5GT: u32 = 1 << 1;
+
+// Offsets of the dwords holding the Link Control and Status Registers and the Slot Control and
+// Status Registers in the PCI Express capability.
+const PCIE_CAP_LINK_CTL_OFFSET: usize = 0x10;
+const PCIE_CAP_SLOT_CTL_OFFSET: usize = 0x18;
+
+// Bus number register of a type 1 header, with the secondary bus number in bits 15-8.
+const BUS_NUMBER_REG: usize = 6;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// PCI Express Capability Structure, version 2
+pub struct PcieCap {
+    // To make add_capability() happy
+    _cap_vndr: u8,
+    _cap_next: u8,
+    // PCI Express Capabilities Register
+    //   3-0: Capability version
+    //   7-4: Device/port type
+    //   8:   Slot implemented
+    pcie_cap: u16,
+    dev_cap: u32,
+    dev_ctl: u16,
+    dev_sts: u16,
+    // Link Capabilities Register
+    //   3-0:   Max link speed
+    //   9-4:   Max link width
+    //   31-24: Port number
+    link_cap: u32,
+    link_ctl: u16,
+    // Link Status Register
+    //   3-0: Current link speed
+    //   9-4: Negotiated link width
+    link_sts: u16,
+    // Slot Capabilities Register
+    //   31-19: Physical slot number
+    slot_cap: u32,
+    slot_ctl: u16,
+    slot_sts: u16,
+    root_ctl: u16,
+    root_cap: u16,
+    root_sts: u32,
+    dev_cap2: u32,
+    dev_ctl2: u16,
+    dev_sts2: u16,
+    // Link Capabilities 2 Register
+    //   7-1: Supported link speeds vector
+    link_cap2: u32,
+    // Link Control 2 Register
+    //   3-0: Target link speed
+    link_ctl2: u16,
+    link_sts2: u16,
+    slot_cap2: u32,
+    slot_ctl2: u16,
+    slot_sts2: u16,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for PcieCap {}
+
+impl PciCapability for PcieCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::PCIExpress
+    }
+}
+
+impl PcieCap {
+    /// Creates the capability of a root port with a x1 2.5 GT/s link and a hotplug slot that has
+    /// the same number as the port. The slot has an attention button and power control, which
+    /// the guest uses to release a device before it is removed.
+    pub fn new_root_port(port_number: u8) -> Self {
+        PcieCap {
+            pcie_cap: PCIE_CAP_VERSION | PCIE_CAP_TYPE_ROOT_PORT | PCIE_CAP_SLOT_IMPLEMENTED,
+            link_cap: PCIE_LINK_SPEED_2_5GT
+                | PCIE_LINK_WIDTH_X1
+                | PCIE_LINK_CAP_ACTIVE_REPORTING
+                | u32::from(port_number) << PCIE_LINK_CAP_PORT_NUMBER_SHIFT,
+            link_sts: (PCIE_LINK_SPEED_2_5GT | PCIE_LINK_WIDTH_X1) as u16,
+            slot_cap: PCIE_SLOT_CAP_ATTENTION_BUTTON
+                | PCIE_SLOT_CAP_POWER_CONTROLLER
+                | PCIE_SLOT_CAP_HOTPLUG_CAPABLE
+                | PCIE_SLOT_CAP_NO_COMMAND_COMPLETED
+                | u32::from(port_number) << PCIE_SLOT_CAP_SLOT_NUMBER_SHIFT,
+            link_cap2: PCIE_LINK_CAP2_SPEEDS_2_5GT,
+            link_ctl2: PCIE_LINK_SPEED_2_5GT as u16,
+            ..Default::default()
+        }
+    }
+}
+
+struct SlotState {
+    // Slot Control Register.
+    control: u16,
+    // Events of the Slot Status Register the guest hasn't cleared yet.
+    events: u16,
+    // Number the guest gave the secondary bus of the port, 0 until it configures the port.
+    secondary_bus: u8,
+    device: Option<Arc<Mutex<dyn BusDevice>>>,
+    unplug_requested: bool,
+    irq_evt: Option<Event>,
+}
+
+impl SlotState {
+    // The link to the device is up while the slot is occupied and powered.
+    fn link_active(&self) -> bool {
+        self.device.is_some() && self.control & PCIE_SLOT_CTL_POWER_OFF == 0
+    }
+
+    fn status(&self) -> u16 {
+        if self.device.is_some() {
+            self.events | PCIE_SLOT_STS_PRESENCE
+        } else {
+            self.events
+        }
+    }
+
+    // Records `events` in the Slot Status Register and interrupts the guest if it enabled any of
+    // them.
+    fn notify(&mut self, events: u16) {
+        self.events |= events;
+        let mut enabled = 0;
+        if self.control & PCIE_SLOT_CTL_ATTENTION_BUTTON_ENABLE != 0 {
+            enabled |= PCIE_SLOT_STS_ATTENTION_BUTTON;
+        }
+        if self.control & PCIE_SLOT_CTL_PRESENCE_CHANGED_ENABLE != 0 {
+            enabled |= PCIE_SLOT_STS_PRESENCE_CHANGED;
+        }
+        if self.control & PCIE_SLOT_CTL_LINK_CHANGED_ENABLE != 0 {
+            enabled |= PCIE_SLOT_STS_LINK_CHANGED;
+        }
+        if self.control & PCIE_SLOT_CTL_HOTPLUG_INTERRUPT_ENABLE == 0 || events & enabled == 0 {
+            return;
+        }
+        if let Some(irq_evt) = &self.irq_evt {
+            if let Err(e) = irq_evt.write(1) {
+                warn!("failed to signal pcie hotplug interrupt: {}", e);
+            }
+        }
+    }
+}
+
+/// The hotplug slot of a `PcieRootPort`. Clones refer to the same slot, so that devices can be
+/// plugged in and out while the root port itself is on the PCI bus.
+///
+/// The guest is told about a device with the presence detect and link state of the slot. Before
+/// a device is removed, the attention button is pressed and the guest releases the device by
+/// turning the power of the slot off, at which point `unplug_event` is signaled.
+#[derive(Clone)]
+pub struct PcieHotplugSlot {
+    state: Arc<Mutex<SlotState>>,
+    unplug_evt: Arc<Event>,
+}
+
+impl PcieHotplugSlot {
+    fn new() -> base::Result<Self> {
+        Ok(PcieHotplugSlot {
+            state: Arc::new(Mutex::new(SlotState {
+                control: 0,
+                events: 0,
+                secondary_bus: 0,
+                device: None,
+                unplug_requested: false,
+                irq_evt: None,
+            })),
+            unplug_evt: Arc::new(Event::new()?),
+        })
+    }
+
+    /// Returns true if no device is plugged into the slot.
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().device.is_none()
+    }
+
+    /// Plugs `device` into the slot, where the guest finds it as device 0 of the secondary bus of
+    /// the port. Returns false if the slot is already occupied.
+    pub fn plug(&self, device: Arc<Mutex<dyn BusDevice>>) -> bool {
+        let mut state = self.state.lock();
+        if state.device.is_some() {
+            return false;
+        }
+        state.device = Some(device);
+        state.unplug_requested = false;
+        let events = if state.link_active() {
+            PCIE_SLOT_STS_PRESENCE_CHANGED | PCIE_SLOT_STS_LINK_CHANGED
+        } else {
+            PCIE_SLOT_STS_PRESENCE_CHANGED
+        };
+        state.notify(events);
+        true
+    }
+
+    /// Asks the guest to release the device in the slot by pressing the attention button, unless
+    /// that was already done. Returns true if the guest isn't using the slot, so the device can be
+    /// unplugged right away. Otherwise `unplug_event` is signaled once the guest is done with it.
+    pub fn request_unplug(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.device.is_none() || !state.link_active() {
+            return true;
+        }
+        // Pressing the button again would make the guest cancel the unplug.
+        if state.unplug_requested {
+            return false;
+        }
+        state.unplug_requested = true;
+        state.notify(PCIE_SLOT_STS_ATTENTION_BUTTON);
+        false
+    }
+
+    /// Returns true if an unplug was requested and the guest has since released the device.
+    pub fn is_released(&self) -> bool {
+        let state = self.state.lock();
+        state.unplug_requested && !state.link_active()
+    }
+
+    /// Event signaled when the guest releases a device after `request_unplug`.
+    pub fn unplug_event(&self) -> &Event {
+        &self.unplug_evt
+    }
+
+    /// Removes the device from the slot, returning it if there was one.
+    pub fn unplug(&self) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        let mut state = self.state.lock();
+        let device = state.device.take()?;
+        state.unplug_requested = false;
+        state.notify(PCIE_SLOT_STS_PRESENCE_CHANGED);
+        Some(device)
+    }
+
+    /// Returns the device in the slot if `bus` is the secondary bus of the port and the link to
+    /// the device is up.
+    pub fn device_on_bus(&self, bus: u8) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        let state = self.state.lock();
+        if bus == 0 || bus != state.secondary_bus || !state.link_active() {
+            return None;
+        }
+        state.device.clone()
+    }
+
+    fn set_secondary_bus(&self, bus: u8) {
+        self.state.lock().secondary_bus = bus;
+    }
+
+    fn set_irq_evt(&self, irq_evt: Event) {
+        self.state.lock().irq_evt = Some(irq_evt);
+    }
+
+    fn link_active(&self) -> bool {
+        self.state.lock().link_active()
+    }
+
+    fn read_registers(&self) -> u32 {
+        let state = self.state.lock();
+        u32::from(state.status()) << 16 | u32::from(state.control)
+    }
+
+    // Writes the Slot Control and Status Registers, `offset` bytes into their dword.
+    fn write_registers(&self, offset: u64, data: &[u8]) {
+        let mut value = 0u32;
+        let mut mask = 0u32;
+        for (i, byte) in data.iter().enumerate() {
+            let shift = (offset as usize + i) * 8;
+            value |= u32::from(*byte) << shift;
+            mask |= 0xff << shift;
+        }
+
+        let mut state = self.state.lock();
+        let old_control = state.control;
+        state.control = ((u32::from(old_control) & !mask) | (value & mask)) as u16;
+        state.events &= !(((value & mask) >> 16) as u16 & PCIE_SLOT_STS_EVENTS);
+
+        let power_changed = (old_control ^ state.control) & PCIE_SLOT_CTL_POWER_OFF != 0;
+        if power_changed && state.device.is_some() {
+            state.notify(PCIE_SLOT_STS_LINK_CHANGED);
+            if state.unplug_requested && !state.link_active() {
+                if let Err(e) = self.unplug_evt.write(1) {
+                    warn!("failed to signal pcie slot unplug: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// A PCI Express root port, a PCI-to-PCI bridge between the root complex and the bus of a single
+/// hotplug slot. The slot starts out empty; see `PcieHotplugSlot`.
+pub struct PcieRootPort {
+    port_number: u8,
+    config_regs: PciConfiguration,
+    pci_address: Option<PciAddress>,
+    // Offset of the PCI Express capability, once it is added.
+    cap_offset: Option<usize>,
+    slot: PcieHotplugSlot,
+    irq_resample_evt: Option<Event>,
+}
+
+impl PcieRootPort {
+    /// Constructs a root port with the given port and slot number.
+    pub fn new(port_number: u8) -> base::Result<Self> {
+        let config_regs = PciConfiguration::new(
+            PCI_VENDOR_ID_INTEL,
+            PCI_DEVICE_ID_INTEL_PCIE_ROOT_PORT,
+            PciClassCode::BridgeDevice,
+            &PciBridgeSubclass::PciToPciBridge,
+            None,
+            PciHeaderType::Bridge,
+            0,
+            0,
+        );
+
+        Ok(PcieRootPort {
+            port_number,
+            config_regs,
+            pci_address: None,
+            cap_offset: None,
+            slot: PcieHotplugSlot::new()?,
+            irq_resample_evt: None,
+        })
+    }
+
+    /// Returns the hotplug slot of the port.
+    pub fn hotplug_slot(&self) -> PcieHotplugSlot {
+        self.slot.clone()
+    }
+
+    fn link_reg_idx(&self) -> Option<usize> {
+        self.cap_offset
+            .map(|offset| (offset + PCIE_CAP_LINK_CTL_OFFSET) / 4)
+    }
+
+    fn slot_reg_idx(&self) -> Option<usize> {
+        self.cap_offset
+            .map(|offset| (offset + PCIE_CAP_SLOT_CTL_OFFSET) / 4)
+    }
+}
+
+impl PciDevice for PcieRootPort {
+    fn debug_label(&self) -> String {
+        format!("pcie root port {}", self.port_number)
+    }
+
+    fn allocate_address(&mut self, resources: &mut SystemAllocator) -> Result<PciAddress> {
+        if self.pci_address.is_none() {
+            self.pci_address = match resources.allocate_pci(self.debug_label()) {
+                Some(Alloc::PciBar {
+                    bus,
+                    dev,
+                    func,
+                    bar: _,
+                }) => Some(PciAddress { bus, dev, func }),
+                _ => None,
+            }
+        }
+        self.pci_address.ok_or(PciDeviceError::PciAllocationFailed)
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        Vec::new()
+    }
+
+    fn assign_irq(
+        &mut self,
+        irq_evt: Event,
+        irq_resample_evt: Event,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.config_regs.set_irq(irq_num as u8, irq_pin);
+        self.slot.set_irq_evt(irq_evt);
+        self.irq_resample_evt = Some(irq_resample_evt);
+    }
+
+    fn register_device_capabilities(&mut self) -> Result<()> {
+        let offset = self
+            .config_regs
+            .add_capability(&PcieCap::new_root_port(self.port_number))
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+        self.cap_offset = Some(offset);
+        Ok(())
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        let value = self.config_regs.read_reg(reg_idx);
+        if Some(reg_idx) == self.slot_reg_idx() {
+            self.slot.read_registers()
+        } else if Some(reg_idx) == self.link_reg_idx() && self.slot.link_active() {
+            value | u32::from(PCIE_LINK_STS_ACTIVE) << 16
+        } else {
+            value
+        }
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if Some(reg_idx) == self.slot_reg_idx() {
+            self.slot.write_registers(offset, data);
+            return;
+        }
+        (&mut self.config_regs).write_reg(reg_idx, offset, data);
+        if reg_idx == BUS_NUMBER_REG {
+            let secondary_bus = (self.config_regs.read_reg(BUS_NUMBER_REG) >> 8) as u8;
+            self.slot.set_secondary_bus(secondary_bus);
+        }
+    }
+
+    fn read_bar(&mut self, _addr: u64, _data: &mut [u8]) {}
+
+    fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_port_config() {
+        assert_eq!(std::mem::size_of::<PcieCap>(), 60);
+
+        let mut port = PcieRootPort::new(3).unwrap();
+        port.register_device_capabilities().unwrap();
+
+        // Type 1 header of a PCI-to-PCI bridge.
+        assert_eq!(port.read_config_register(2) >> 16, 0x0604);
+        assert_eq!((port.read_config_register(3) >> 16) & 0x7f, 1);
+
+        // The PCI Express capability is first in the list.
+        let cap_offset = (port.read_config_register(13) & 0xff) as usize;
+        let cap = port.read_config_register(cap_offset / 4);
+        assert_eq!(cap & 0xff, PciCapabilityID::PCIExpress as u32);
+        assert_eq!(cap >> 16, 0x142);
+        let link_cap = port.read_config_register(cap_offset / 4 + 3);
+        assert_eq!(link_cap >> PCIE_LINK_CAP_PORT_NUMBER_SHIFT, 3);
+
+        // Secondary and subordinate bus numbers are assigned by the guest.
+        port.write_config_register(6, 0, &[0x00, 0x01, 0x01, 0x00]);
+        assert_eq!(port.read_config_register(6), 0x0001_0100);
+    }
+
+    struct TestDevice;
+
+    impl BusDevice for TestDevice {
+        fn debug_label(&self) -> String {
+            "test device".to_owned()
+        }
+
+        fn config_register_read(&self, _reg_idx: usize) -> u32 {
+            0x1234_5678
+        }
+    }
+
+    #[test]
+    fn hotplug() {
+        let mut port = PcieRootPort::new(0).unwrap();
+        port.register_device_capabilities().unwrap();
+        let irq_evt = Event::new().unwrap();
+        port.assign_irq(
+            irq_evt.try_clone().unwrap(),
+            Event::new().unwrap(),
+            5,
+            PciInterruptPin::IntA,
+        );
+        let slot = port.hotplug_slot();
+        let slot_reg = port.slot_reg_idx().unwrap();
+        let link_reg = port.link_reg_idx().unwrap();
+        port.write_config_register(6, 0, &[0x00, 0x02, 0x02, 0x00]);
+
+        // The guest enables hotplug interrupts for all events.
+        let control = PCIE_SLOT_CTL_ATTENTION_BUTTON_ENABLE
+            | PCIE_SLOT_CTL_PRESENCE_CHANGED_ENABLE
+            | PCIE_SLOT_CTL_HOTPLUG_INTERRUPT_ENABLE
+            | PCIE_SLOT_CTL_LINK_CHANGED_ENABLE;
+        port.write_config_register(slot_reg, 0, &control.to_le_bytes());
+        assert!(slot.device_on_bus(2).is_none());
+
+        assert!(slot.plug(Arc::new(Mutex::new(TestDevice))));
+        assert!(!slot.plug(Arc::new(Mutex::new(TestDevice))));
+        assert_eq!(irq_evt.read().unwrap(), 1);
+        let status = (port.read_config_register(slot_reg) >> 16) as u16;
+        assert_eq!(
+            status,
+            PCIE_SLOT_STS_PRESENCE | PCIE_SLOT_STS_PRESENCE_CHANGED | PCIE_SLOT_STS_LINK_CHANGED
+        );
+        assert_ne!(
+            port.read_config_register(link_reg) & u32::from(PCIE_LINK_STS_ACTIVE) << 16,
+            0
+        );
+        assert!(slot.device_on_bus(1).is_none());
+        assert_eq!(
+            slot.device_on_bus(2)
+                .unwrap()
+                .lock()
+                .config_register_read(0),
+            0x1234_5678
+        );
+
+        // The guest clears the events.
+        port.write_config_register(slot_reg, 2, &status.to_le_bytes());
+        assert_eq!(
+            (port.read_config_register(slot_reg) >> 16) as u16,
+            PCIE_SLOT_STS_PRESENCE
+        );
+
+        // Pressing the attention button asks the guest to turn the power off.
+        assert!(!slot.request_unplug());
+        assert_eq!(irq_evt.read().unwrap(), 1);
+        assert!(!slot.request_unplug());
+        assert_eq!(
+            (port.read_config_register(slot_reg) >> 16) as u16,
+            PCIE_SLOT_STS_PRESENCE | PCIE_SLOT_STS_ATTENTION_BUTTON
+        );
+        assert!(!slot.is_released());
+        port.write_config_register(
+            slot_reg,
+            0,
+            &(control | PCIE_SLOT_CTL_POWER_OFF).to_le_bytes(),
+        );
+        assert_eq!(slot.unplug_event().read().unwrap(), 1);
+        assert!(slot.is_released());
+        assert!(slot.device_on_bus(2).is_none());
+
+        assert!(slot.unplug().is_some());
+        assert!(slot.is_empty());
+        assert!(slot.request_unplug());
+    }
+}
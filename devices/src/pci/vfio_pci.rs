@@ -6,7 +6,8 @@ use std::sync::Arc;
 use std::u32;
 
 use base::{
-    error, AsRawDescriptor, Event, MappedRegion, MemoryMapping, MemoryMappingBuilder, RawDescriptor,
+    error, info, AsRawDescriptor, Event, MappedRegion, MemoryMapping, MemoryMappingBuilder,
+    RawDescriptor,
 };
 use hypervisor::Datamatch;
 use msg_socket::{MsgReceiver, MsgSender};
@@ -22,18 +23,22 @@ use crate::pci::msix::{
     MsixConfig, BITS_PER_PBA_ENTRY, MSIX_PBA_ENTRIES_MODULO, MSIX_TABLE_ENTRIES_MODULO,
 };
 
+use crate::pci::pci_configuration::NUM_CONVENTIONAL_CONFIGURATION_REGISTERS;
 use crate::pci::pci_device::{Error as PciDeviceError, PciDevice};
 use crate::pci::{PciAddress, PciClassCode, PciInterruptPin};
 
 use crate::vfio::{VfioDevice, VfioIrqType};
 
 const PCI_VENDOR_ID: u32 = 0x0;
+const PCI_VENDOR_ID_REG: usize = 0;
+const PCI_COMMAND_REG: usize = 1;
 const INTEL_VENDOR_ID: u16 = 0x8086;
 const PCI_COMMAND: u32 = 0x4;
 const PCI_COMMAND_MEMORY: u8 = 0x2;
 const PCI_BASE_CLASS_CODE: u32 = 0x0B;
 
 const PCI_INTERRUPT_PIN: u32 = 0x3D;
+const PCI_INTERRUPT_LINE_PIN_REG: usize = 15;
 
 struct VfioPciConfig {
     device: Arc<VfioDevice>,
@@ -131,6 +136,8 @@ struct VfioMsiCap {
     vm_socket_irq: VmIrqRequestSocket,
     irqfd: Option<Event>,
     gsi: Option<u32>,
+    // The PCI requester ID the MSI is sent with, once the device has an address.
+    devid: Option<u32>,
 }
 
 impl VfioMsiCap {
@@ -147,6 +154,7 @@ impl VfioMsiCap {
             vm_socket_irq,
             irqfd: None,
             gsi: None,
+            devid: None,
         }
     }
 
@@ -241,6 +249,7 @@ impl VfioMsiCap {
             gsi,
             msi_address: self.address,
             msi_data: self.data.into(),
+            msi_devid: self.devid,
         }) {
             error!("failed to send AddMsiRoute request at {:?}", e);
             return;
@@ -436,6 +445,8 @@ pub struct VfioPciDevice {
     pci_address: Option<PciAddress>,
     interrupt_evt: Option<Event>,
     interrupt_resample_evt: Option<Event>,
+    // The pin the guest's interrupt routing lists for this device, if it uses INTx.
+    intx_pin: Option<PciInterruptPin>,
     mmio_regions: Vec<MmioInfo>,
     io_regions: Vec<IoInfo>,
     msi_cap: Option<VfioMsiCap>,
@@ -443,6 +454,9 @@ pub struct VfioPciDevice {
     irq_type: Option<VfioIrqType>,
     vm_socket_mem: VmMemoryControlRequestSocket,
     device_data: Option<DeviceData>,
+    // The Memory Space Enable bit of an SR-IOV virtual function always reads back as 0, so the
+    // value the guest wrote is kept here.
+    virtfn_memory_enabled: bool,
 
     // scratch MemoryMapping to avoid unmap beform vm exit
     mem: Vec<MemoryMapping>,
@@ -479,7 +493,17 @@ impl VfioPciDevice {
             cap_next = config.read_config_byte(offset).into();
         }
 
-        let vendor_id = config.read_config_word(PCI_VENDOR_ID);
+        let vendor_id = match dev.virtfn() {
+            Some(virtfn) => {
+                info!(
+                    "vfio device {} is a virtual function of {}",
+                    dev.device_name(),
+                    virtfn.physfn
+                );
+                virtfn.vendor_id
+            }
+            None => config.read_config_word(PCI_VENDOR_ID),
+        };
         let class_code = config.read_config_byte(PCI_BASE_CLASS_CODE);
 
         let is_intel_gfx = vendor_id == INTEL_VENDOR_ID
@@ -498,6 +522,7 @@ impl VfioPciDevice {
             pci_address: None,
             interrupt_evt: None,
             interrupt_resample_evt: None,
+            intx_pin: None,
             mmio_regions: Vec::new(),
             io_regions: Vec::new(),
             msi_cap,
@@ -505,6 +530,7 @@ impl VfioPciDevice {
             irq_type: None,
             vm_socket_mem: vfio_device_socket_mem,
             device_data,
+            virtfn_memory_enabled: false,
             mem: Vec::new(),
         }
     }
@@ -539,6 +565,11 @@ impl VfioPciDevice {
         if self.interrupt_evt.is_none() || self.interrupt_resample_evt.is_none() {
             return;
         }
+        // Devices without an interrupt pin, such as SR-IOV virtual functions, have no INTx to
+        // fall back to.
+        if self.config.read_config_byte(PCI_INTERRUPT_PIN) == 0 {
+            return;
+        }
 
         if let Some(ref interrupt_evt) = self.interrupt_evt {
             let mut fds = Vec::new();
@@ -553,7 +584,10 @@ impl VfioPciDevice {
                     self.disable_intx();
                     return;
                 }
-                if let Err(e) = self.device.resample_virq_enable(irq_resample_evt) {
+                if let Err(e) = self
+                    .device
+                    .resample_virq_enable(irq_resample_evt, VfioIrqType::Intx)
+                {
                     error!("resample enable failed: {}", e);
                     self.disable_intx();
                     return;
@@ -763,6 +797,12 @@ impl PciDevice for VfioPciDevice {
                 self.debug_label(),
             ) {
                 self.pci_address = Some(address);
+                if let Some(msi_cap) = self.msi_cap.as_mut() {
+                    msi_cap.devid = Some(address.to_bdf().into());
+                }
+                if let Some(msix_cap) = self.msix_cap.as_mut() {
+                    msix_cap.config.set_pci_address(address);
+                }
             }
         }
         self.pci_address.ok_or(PciDeviceError::PciAllocationFailed)
@@ -791,7 +831,7 @@ impl PciDevice for VfioPciDevice {
         irq_evt: Event,
         irq_resample_evt: Event,
         irq_num: u32,
-        _irq_pin: PciInterruptPin,
+        irq_pin: PciInterruptPin,
     ) {
         self.config.write_config_byte(irq_num as u8, 0x3C);
         self.interrupt_evt = Some(irq_evt);
@@ -799,6 +839,7 @@ impl PciDevice for VfioPciDevice {
 
         // enable INTX
         if self.config.read_config_byte(PCI_INTERRUPT_PIN) > 0 {
+            self.intx_pin = Some(irq_pin);
             self.enable_intx();
         }
     }
@@ -949,6 +990,11 @@ impl PciDevice for VfioPciDevice {
     }
 
     fn read_config_register(&self, reg_idx: usize) -> u32 {
+        // None of the extended capabilities of the host device are emulated, so the guest is shown
+        // an empty extended configuration space.
+        if reg_idx >= NUM_CONVENTIONAL_CONFIGURATION_REGISTERS {
+            return 0;
+        }
         let reg: u32 = (reg_idx * 4) as u32;
 
         let mut config = self.config.read_config_dword(reg);
@@ -971,10 +1017,33 @@ impl PciDevice for VfioPciDevice {
             config &= 0xffff00ff;
         }
 
+        // The guest looks up the interrupt routing of the device by the pin it was assigned, which
+        // needn't be the one the host device uses.
+        if reg_idx == PCI_INTERRUPT_LINE_PIN_REG {
+            if let Some(pin) = self.intx_pin {
+                config = (config & 0xffff_00ff) | ((pin.to_mask() + 1) << 8);
+            }
+        }
+
+        if let Some(virtfn) = self.device.virtfn() {
+            match reg_idx {
+                PCI_VENDOR_ID_REG => {
+                    config = (u32::from(virtfn.device_id) << 16) | u32::from(virtfn.vendor_id);
+                }
+                PCI_COMMAND_REG if self.virtfn_memory_enabled => {
+                    config |= u32::from(PCI_COMMAND_MEMORY);
+                }
+                _ => (),
+            }
+        }
+
         config
     }
 
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if reg_idx >= NUM_CONVENTIONAL_CONFIGURATION_REGISTERS {
+            return;
+        }
         let start = (reg_idx * 4) as u64 + offset;
 
         let mut msi_change: Option<VfioMsiChange> = None;
@@ -1002,6 +1071,10 @@ impl PciDevice for VfioPciDevice {
             None => (),
         }
 
+        if reg_idx == PCI_COMMAND_REG && offset == 0 && self.device.virtfn().is_some() {
+            self.virtfn_memory_enabled = data[0] & PCI_COMMAND_MEMORY != 0;
+        }
+
         // if guest enable memory access, then enable bar mappable once
         if start == PCI_COMMAND as u64
             && data.len() == 2
@@ -0,0 +1,371 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parallel flash device speaking the Intel/Sharp CFI command set, like QEMU's pflash_cfi01.
+//!
+//! UEFI firmware such as OVMF keeps its variable store in flash it programs one byte at a time and
+//! erases in blocks. Every program and erase is written through to the backing file, so variables
+//! persist across boots.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use base::error;
+use remain::sorted;
+
+use crate::{BusAccessInfo, BusDevice};
+
+/// Size of the blocks the flash is erased in.
+pub const PFLASH_BLOCK_SIZE: u64 = 0x1000;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ARRAY_ALT: u8 = 0x00;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_BLOCK_ERASE_CONFIRM: u8 = 0xd0;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_CFI_QUERY: u8 = 0x98;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+const MANUFACTURER_ID: u8 = 0x89; // Intel
+const DEVICE_ID: u8 = 0x18;
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    BadSize(u64),
+    ReadImage(io::Error),
+}
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            BadSize(size) => write!(
+                f,
+                "flash image of {} bytes isn't a non-zero multiple of {} bytes",
+                size, PFLASH_BLOCK_SIZE
+            ),
+            ReadImage(e) => write!(f, "failed to read flash image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// What reads of the flash return.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadMode {
+    Array,
+    Status,
+    Id,
+    CfiQuery,
+}
+
+// The first cycle of a two cycle command, waiting for its second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pending {
+    None,
+    Program,
+    BlockErase,
+}
+
+/// A flash device backed by a file, which has to be a multiple of `PFLASH_BLOCK_SIZE` long.
+pub struct Pflash {
+    image: File,
+    data: Vec<u8>,
+    read_mode: ReadMode,
+    pending: Pending,
+    status: u8,
+}
+
+impl Pflash {
+    /// Constructs a flash device holding the contents of `image`, which is updated as the flash
+    /// is programmed and erased.
+    pub fn new(mut image: File) -> Result<Pflash> {
+        let size = image.seek(SeekFrom::End(0)).map_err(Error::ReadImage)?;
+        if size == 0 || size % PFLASH_BLOCK_SIZE != 0 {
+            return Err(Error::BadSize(size));
+        }
+        image.seek(SeekFrom::Start(0)).map_err(Error::ReadImage)?;
+        let mut data = Vec::with_capacity(size as usize);
+        image.read_to_end(&mut data).map_err(Error::ReadImage)?;
+
+        Ok(Pflash {
+            image,
+            data,
+            read_mode: ReadMode::Array,
+            pending: Pending::None,
+            status: STATUS_READY,
+        })
+    }
+
+    /// Returns the size of the flash in bytes.
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn persist(&mut self, offset: usize, len: usize) -> bool {
+        match self
+            .image
+            .write_all_at(&self.data[offset..offset + len], offset as u64)
+        {
+            Ok(()) => true,
+            Err(e) => {
+                error!("failed to write flash image: {}", e);
+                false
+            }
+        }
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) {
+        let len = data.len().min(self.data.len() - offset);
+        self.data[offset..offset + len].copy_from_slice(&data[..len]);
+        if !self.persist(offset, len) {
+            self.status |= STATUS_PROGRAM_ERROR;
+        }
+    }
+
+    fn erase_block(&mut self, offset: usize) {
+        let block_size = PFLASH_BLOCK_SIZE as usize;
+        let start = offset - offset % block_size;
+        for byte in &mut self.data[start..start + block_size] {
+            *byte = 0xff;
+        }
+        if !self.persist(start, block_size) {
+            self.status |= STATUS_ERASE_ERROR;
+        }
+    }
+
+    // The Common Flash Interface query structure of a byte wide device with a single region of
+    // uniform blocks.
+    fn cfi_query(&self, offset: usize) -> u8 {
+        let blocks = self.data.len() / PFLASH_BLOCK_SIZE as usize - 1;
+        let block_size = PFLASH_BLOCK_SIZE as usize / 256;
+        match offset {
+            0x10 => b'Q',
+            0x11 => b'R',
+            0x12 => b'Y',
+            // Intel/Sharp extended command set.
+            0x13 => 0x01,
+            // Typical timeouts of single byte program, in 2^n us, and block erase, in 2^n ms.
+            0x1f => 0x04,
+            0x21 => 0x0a,
+            // Maximum timeouts, as 2^n times the typical ones.
+            0x23 => 0x04,
+            0x25 => 0x04,
+            // Device size as a power of two.
+            0x27 => self.data.len().next_power_of_two().trailing_zeros() as u8,
+            0x2c => 1,
+            0x2d => blocks as u8,
+            0x2e => (blocks >> 8) as u8,
+            0x2f => block_size as u8,
+            0x30 => (block_size >> 8) as u8,
+            _ => 0,
+        }
+    }
+}
+
+impl BusDevice for Pflash {
+    fn debug_label(&self) -> String {
+        "pflash".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        let offset = info.offset as usize;
+        if offset >= self.data.len() {
+            return;
+        }
+
+        let value = match self.read_mode {
+            ReadMode::Array => {
+                let len = data.len().min(self.data.len() - offset);
+                data[..len].copy_from_slice(&self.data[offset..offset + len]);
+                return;
+            }
+            ReadMode::Status => self.status,
+            ReadMode::Id => match offset {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                _ => 0,
+            },
+            ReadMode::CfiQuery => self.cfi_query(offset),
+        };
+        for byte in data.iter_mut() {
+            *byte = value;
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        let offset = info.offset as usize;
+        if offset >= self.data.len() || data.is_empty() {
+            return;
+        }
+
+        match self.pending {
+            Pending::Program => {
+                self.pending = Pending::None;
+                self.program(offset, data);
+                self.status |= STATUS_READY;
+                self.read_mode = ReadMode::Status;
+                return;
+            }
+            Pending::BlockErase => {
+                self.pending = Pending::None;
+                if data[0] == CMD_BLOCK_ERASE_CONFIRM {
+                    self.erase_block(offset);
+                } else {
+                    // The erase was not confirmed, which is a command sequence error.
+                    self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                }
+                self.status |= STATUS_READY;
+                self.read_mode = ReadMode::Status;
+                return;
+            }
+            Pending::None => {}
+        }
+
+        match data[0] {
+            CMD_READ_ARRAY | CMD_READ_ARRAY_ALT => self.read_mode = ReadMode::Array,
+            CMD_PROGRAM | CMD_PROGRAM_ALT => self.pending = Pending::Program,
+            CMD_BLOCK_ERASE => self.pending = Pending::BlockErase,
+            CMD_CLEAR_STATUS => {
+                self.status = 0;
+                self.read_mode = ReadMode::Array;
+            }
+            CMD_READ_STATUS => self.read_mode = ReadMode::Status,
+            CMD_READ_ID => self.read_mode = ReadMode::Id,
+            CMD_CFI_QUERY => self.read_mode = ReadMode::CfiQuery,
+            cmd => {
+                error!("unsupported pflash command {:#x}", cmd);
+                self.read_mode = ReadMode::Array;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempfile;
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            address: offset,
+            offset,
+            id: 0,
+        }
+    }
+
+    fn read_byte(pflash: &mut Pflash, offset: u64) -> u8 {
+        let mut data = [0u8];
+        pflash.read(access(offset), &mut data);
+        data[0]
+    }
+
+    fn read_image(image: &mut File, offset: u64) -> u8 {
+        let mut data = [0u8];
+        image.read_exact_at(&mut data, offset).unwrap();
+        data[0]
+    }
+
+    fn make_pflash(blocks: u64) -> (Pflash, File) {
+        let mut image = tempfile().unwrap();
+        image
+            .write_all(&vec![0x5a; (blocks * PFLASH_BLOCK_SIZE) as usize])
+            .unwrap();
+        let pflash = Pflash::new(image.try_clone().unwrap()).unwrap();
+        (pflash, image)
+    }
+
+    #[test]
+    fn bad_size() {
+        let mut image = tempfile().unwrap();
+        image.write_all(&[0; 100]).unwrap();
+        match Pflash::new(image) {
+            Err(Error::BadSize(100)) => {}
+            r => panic!("unexpected result {:?}", r.map(|p| p.size())),
+        }
+    }
+
+    #[test]
+    fn program() {
+        let (mut pflash, mut image) = make_pflash(2);
+        assert_eq!(read_byte(&mut pflash, 0x1234), 0x5a);
+
+        pflash.write(access(0x1234), &[CMD_PROGRAM_ALT]);
+        pflash.write(access(0x1234), &[0x12]);
+        assert_eq!(read_byte(&mut pflash, 0x1234), STATUS_READY);
+        pflash.write(access(0), &[CMD_READ_ARRAY]);
+        assert_eq!(read_byte(&mut pflash, 0x1234), 0x12);
+        assert_eq!(read_image(&mut image, 0x1234), 0x12);
+    }
+
+    #[test]
+    fn block_erase() {
+        let (mut pflash, mut image) = make_pflash(2);
+        pflash.write(access(0x1100), &[CMD_BLOCK_ERASE]);
+        pflash.write(access(0x1100), &[CMD_BLOCK_ERASE_CONFIRM]);
+        pflash.write(access(0), &[CMD_READ_ARRAY]);
+        assert_eq!(read_byte(&mut pflash, 0xfff), 0x5a);
+        assert_eq!(read_byte(&mut pflash, 0x1000), 0xff);
+        assert_eq!(read_byte(&mut pflash, 0x1fff), 0xff);
+        assert_eq!(read_image(&mut image, 0x1000), 0xff);
+        assert_eq!(read_image(&mut image, 0xfff), 0x5a);
+
+        // An erase that isn't confirmed leaves the flash alone and sets the error bits.
+        pflash.write(access(0), &[CMD_BLOCK_ERASE]);
+        pflash.write(access(0), &[CMD_READ_ARRAY]);
+        assert_eq!(
+            read_byte(&mut pflash, 0),
+            STATUS_READY | STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR
+        );
+        pflash.write(access(0), &[CMD_CLEAR_STATUS]);
+        assert_eq!(read_byte(&mut pflash, 0), 0x5a);
+    }
+
+    #[test]
+    fn detected_as_flash() {
+        // The status register, unlike the contents of RAM or ROM, reads back neither the written
+        // command nor the contents of the flash.
+        let (mut pflash, _image) = make_pflash(1);
+        pflash.write(access(0), &[CMD_CLEAR_STATUS]);
+        assert_eq!(read_byte(&mut pflash, 0), 0x5a);
+        pflash.write(access(0), &[CMD_READ_STATUS]);
+        let status = read_byte(&mut pflash, 0);
+        assert_ne!(status, 0x5a);
+        assert_ne!(status, CMD_READ_STATUS);
+    }
+
+    #[test]
+    fn id_and_cfi_query() {
+        let (mut pflash, _image) = make_pflash(4);
+        pflash.write(access(0), &[CMD_READ_ID]);
+        assert_eq!(read_byte(&mut pflash, 0), MANUFACTURER_ID);
+        assert_eq!(read_byte(&mut pflash, 1), DEVICE_ID);
+
+        pflash.write(access(0), &[CMD_CFI_QUERY]);
+        assert_eq!(read_byte(&mut pflash, 0x10), b'Q');
+        assert_eq!(read_byte(&mut pflash, 0x11), b'R');
+        assert_eq!(read_byte(&mut pflash, 0x12), b'Y');
+        assert_eq!(read_byte(&mut pflash, 0x27), 14);
+        assert_eq!(read_byte(&mut pflash, 0x2d), 3);
+        assert_eq!(read_byte(&mut pflash, 0x30), 0x00);
+        assert_eq!(read_byte(&mut pflash, 0x2f), 0x10);
+    }
+}
@@ -257,6 +257,15 @@ impl BusDevice for Pit {
             }
         };
     }
+
+    // The state is saved with the rest of the irqchip.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
 }
 
 impl Pit {
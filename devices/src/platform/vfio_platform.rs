@@ -0,0 +1,174 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use base::{pagesize, Event, MemoryMapping, MemoryMappingBuilder, MmapError, RawDescriptor};
+use resources::{MmioType, SystemAllocator};
+use vfio_sys::*;
+use vm_memory::GuestAddress;
+
+use crate::vfio::{VfioDevice, VfioError, VfioIrq, VfioIrqType};
+use crate::{BusAccessInfo, BusDevice};
+
+// An MMIO region of the device and where the guest sees it.
+struct MmioRegion {
+    index: u32,
+    start: u64,
+    length: u64,
+}
+
+/// Passes a host platform device, usually a peripheral of an ARM SoC, through to the guest.
+///
+/// The device's MMIO regions are placed in guest MMIO space and its interrupts are forwarded to
+/// guest interrupts. Accesses to the parts of regions that can't be mapped into the guest are
+/// forwarded to the device by the `BusDevice` implementation.
+pub struct VfioPlatformDevice {
+    device: VfioDevice,
+    mmio_regions: Vec<MmioRegion>,
+    // The host keeps signaling these for the device's interrupts.
+    interrupt_evts: Vec<Event>,
+    // Compatible strings the device tree describes the device with, if it does.
+    dt_compatible: Option<Vec<String>>,
+}
+
+impl VfioPlatformDevice {
+    /// Constructs a new vfio platform device for the given vfio device. When `dt_compatible` is
+    /// given, the device tree describes the device to the guest as compatible with those strings.
+    pub fn new(device: VfioDevice, dt_compatible: Option<Vec<String>>) -> Self {
+        VfioPlatformDevice {
+            device,
+            mmio_regions: Vec::new(),
+            interrupt_evts: Vec::new(),
+            dt_compatible,
+        }
+    }
+
+    /// Returns the compatible strings of the device tree node describing the device, if it has
+    /// one.
+    pub fn dt_compatible(&self) -> Option<&Vec<String>> {
+        self.dt_compatible.as_ref()
+    }
+
+    /// Returns the interrupts of the device.
+    pub fn get_platform_irqs(&self) -> Result<Vec<VfioIrq>, VfioError> {
+        self.device.get_irqs()
+    }
+
+    /// Returns whether the host masks `irq` each time it fires, until the guest acknowledges it.
+    /// This is the case for level triggered interrupts.
+    pub fn irq_is_automask(&self, irq: &VfioIrq) -> bool {
+        irq.flags & VFIO_IRQ_INFO_AUTOMASKED != 0
+    }
+
+    /// Makes the host signal `irq_evt` when the interrupt at `index` fires. An automasked
+    /// interrupt is unmasked again when `resample_evt` is signaled.
+    pub fn assign_platform_irq(
+        &mut self,
+        irq_evt: Event,
+        resample_evt: Option<Event>,
+        index: u32,
+    ) -> Result<(), VfioError> {
+        self.device
+            .irq_enable(vec![&irq_evt], VfioIrqType::Platform(index))?;
+        self.interrupt_evts.push(irq_evt);
+        if let Some(resample_evt) = resample_evt {
+            if let Err(e) = self
+                .device
+                .resample_virq_enable(&resample_evt, VfioIrqType::Platform(index))
+            {
+                let _ = self.device.irq_disable(VfioIrqType::Platform(index));
+                return Err(e);
+            }
+            self.interrupt_evts.push(resample_evt);
+        }
+        Ok(())
+    }
+
+    /// Allocates guest MMIO space for the regions of the device. Returns the address and size of
+    /// each region.
+    pub fn allocate_regions(
+        &mut self,
+        resources: &mut SystemAllocator,
+    ) -> Result<Vec<(u64, u64)>, resources::Error> {
+        let mut ranges = Vec::new();
+        for index in 0..self.device.get_region_count() {
+            let size = self.device.get_region_size(index);
+            if size == 0 {
+                continue;
+            }
+            // Regions are mapped into the guest, so they take up whole pages.
+            let alloc_size = (size + pagesize() as u64 - 1) & !(pagesize() as u64 - 1);
+            let alloc_id = resources.get_anon_alloc();
+            let start = resources
+                .mmio_allocator(MmioType::High)
+                .allocate_with_align(
+                    alloc_size,
+                    alloc_id,
+                    format!(
+                        "vfio_platform {} region {}",
+                        self.device.device_name(),
+                        index
+                    ),
+                    pagesize() as u64,
+                )?;
+            self.mmio_regions.push(MmioRegion {
+                index,
+                start,
+                length: size,
+            });
+            ranges.push((start, size));
+        }
+        Ok(ranges)
+    }
+
+    /// Maps the parts of the device's regions that support it. Returns the guest address of each
+    /// mapping, which the caller adds to guest memory so the guest accesses them without exits.
+    pub fn mmap_regions(&self) -> Result<Vec<(GuestAddress, MemoryMapping)>, MmapError> {
+        let mut mappings = Vec::new();
+        for region in &self.mmio_regions {
+            if self.device.get_region_flags(region.index) & VFIO_REGION_INFO_FLAG_MMAP == 0 {
+                continue;
+            }
+            let region_offset = self.device.get_region_offset(region.index);
+            for mmap in self.device.get_region_mmap(region.index) {
+                let mapping = MemoryMappingBuilder::new(mmap.size as usize)
+                    .from_descriptor(&self.device)
+                    .offset(region_offset + mmap.offset)
+                    .build()?;
+                mappings.push((GuestAddress(region.start + mmap.offset), mapping));
+            }
+        }
+        Ok(mappings)
+    }
+
+    /// Gets the descriptors used by the device, which need to be kept open when it's jailed.
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.device.keep_rds()
+    }
+
+    fn find_region(&self, addr: u64) -> Option<&MmioRegion> {
+        self.mmio_regions
+            .iter()
+            .find(|region| addr >= region.start && addr < region.start + region.length)
+    }
+}
+
+impl BusDevice for VfioPlatformDevice {
+    fn debug_label(&self) -> String {
+        format!("vfio {} device", self.device.device_name())
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        if let Some(region) = self.find_region(info.address) {
+            self.device
+                .region_read(region.index, data, info.address - region.start);
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        if let Some(region) = self.find_region(info.address) {
+            self.device
+                .region_write(region.index, data, info.address - region.start);
+        }
+    }
+}
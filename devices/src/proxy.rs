@@ -8,7 +8,7 @@ use std::fmt::{self, Display};
 use std::time::Duration;
 use std::{self, io};
 
-use base::{error, net::UnixSeqpacket, AsRawDescriptor, RawDescriptor};
+use base::{error, net::UnixSeqpacket, syslog, AsRawDescriptor, RawDescriptor};
 use libc::{self, pid_t};
 use minijail::{self, Minijail};
 use msg_socket::{MsgOnSocket, MsgReceiver, MsgSender, MsgSocket};
@@ -55,6 +55,12 @@ enum Command {
         len: u32,
         data: [u8; 4],
     },
+    Suspend,
+    Resume,
+    Sleep,
+    Wake,
+    Snapshot,
+    Restore(Vec<u8>),
     Shutdown,
 }
 
@@ -67,6 +73,8 @@ enum CommandResult {
         mem_bus_new_state: Option<bool>,
         io_bus_new_state: Option<bool>,
     },
+    SnapshotResult(Option<Vec<u8>>),
+    RestoreResult(bool),
 }
 
 fn child_proc<D: BusDevice>(sock: UnixSeqpacket, device: &mut D) {
@@ -112,6 +120,26 @@ fn child_proc<D: BusDevice>(sock: UnixSeqpacket, device: &mut D) {
                     io_bus_new_state: res.io_bus_new_state,
                 })
             }
+            Command::Suspend => {
+                device.on_suspend();
+                sock.send(&CommandResult::Ok)
+            }
+            Command::Resume => {
+                device.on_resume();
+                sock.send(&CommandResult::Ok)
+            }
+            Command::Sleep => {
+                device.on_sleep();
+                sock.send(&CommandResult::Ok)
+            }
+            Command::Wake => {
+                device.on_wake();
+                sock.send(&CommandResult::Ok)
+            }
+            Command::Snapshot => sock.send(&CommandResult::SnapshotResult(device.snapshot())),
+            Command::Restore(data) => {
+                sock.send(&CommandResult::RestoreResult(device.restore(&data)))
+            }
             Command::Shutdown => {
                 running = false;
                 sock.send(&CommandResult::Ok)
@@ -152,9 +180,15 @@ impl ProxyDevice {
         let (child_sock, parent_sock) = UnixSeqpacket::pair().map_err(Error::Io)?;
 
         keep_rds.push(child_sock.as_raw_descriptor());
-        // Forking here is safe as long as the program is still single threaded.
+        cros_tracing::push_descriptors(&mut keep_rds);
+        // Forking here is safe as long as no other thread holds a lock the child takes. That holds
+        // while the program is still single threaded. Later, such as for hot-added devices, the
+        // caller must make sure of it, except for the syslogger, which stays locked across the fork.
+        let log_guard = syslog::lock_for_fork();
+        let fork = unsafe { jail.fork(Some(&keep_rds)) };
+        drop(log_guard);
         let pid = unsafe {
-            match jail.fork(Some(&keep_rds)).map_err(Error::ForkingJail)? {
+            match fork.map_err(Error::ForkingJail)? {
                 0 => {
                     device.on_sandboxed();
                     child_proc(child_sock, &mut device);
@@ -280,6 +314,36 @@ impl BusDevice for ProxyDevice {
             data: buffer,
         });
     }
+
+    fn on_suspend(&mut self) {
+        self.sync_send(&Command::Suspend);
+    }
+
+    fn on_resume(&mut self) {
+        self.sync_send(&Command::Resume);
+    }
+
+    fn on_sleep(&mut self) {
+        self.sync_send(&Command::Sleep);
+    }
+
+    fn on_wake(&mut self) {
+        self.sync_send(&Command::Wake);
+    }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        match self.sync_send(&Command::Snapshot) {
+            Some(CommandResult::SnapshotResult(data)) => data,
+            _ => None,
+        }
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        match self.sync_send(&Command::Restore(data.to_vec())) {
+            Some(CommandResult::RestoreResult(restored)) => restored,
+            _ => false,
+        }
+    }
 }
 
 impl Drop for ProxyDevice {
@@ -336,6 +400,21 @@ mod tests {
         fn config_register_read(&self, _reg_idx: usize) -> u32 {
             self.config as u32
         }
+
+        fn snapshot(&mut self) -> Option<Vec<u8>> {
+            Some(vec![self.data, self.config])
+        }
+
+        fn restore(&mut self, data: &[u8]) -> bool {
+            match data {
+                [data, config] => {
+                    self.data = *data;
+                    self.config = *config;
+                    true
+                }
+                _ => false,
+            }
+        }
     }
 
     fn new_proxied_echo_device() -> ProxyDevice {
@@ -375,4 +454,26 @@ mod tests {
         proxy_device.config_register_write(0, 0, &[42]);
         assert_eq!(proxy_device.config_register_read(0), 42);
     }
+
+    #[test]
+    #[ignore]
+    fn test_proxied_snapshot() {
+        let mut proxy_device = new_proxied_echo_device();
+        let address = BusAccessInfo {
+            offset: 0,
+            address: 0,
+            id: 0,
+        };
+        proxy_device.write(address, &[42]);
+        proxy_device.config_register_write(0, 0, &[7]);
+        let snapshot = proxy_device.snapshot().expect("failed to snapshot");
+
+        let mut other_device = new_proxied_echo_device();
+        assert!(other_device.restore(&snapshot));
+        let mut read_buffer = [0];
+        other_device.read(address, &mut read_buffer);
+        assert_eq!(read_buffer, [42]);
+        assert_eq!(other_device.config_register_read(0), 7);
+        assert!(!other_device.restore(&[]));
+    }
 }
@@ -10,6 +10,7 @@ use std::sync::Arc;
 use std::thread::{self};
 
 use base::{error, Event, RawDescriptor, Result};
+use msg_socket::{deserialize_from_slice, serialize_to_vec, MsgOnSocket};
 
 use crate::bus::BusAccessInfo;
 use crate::{BusDevice, SerialDevice};
@@ -82,6 +83,20 @@ pub struct Serial {
     out: Option<Box<dyn io::Write + Send>>,
 }
 
+/// The registers of a `Serial` and the input the guest hasn't read yet, saved in a VM snapshot.
+#[derive(MsgOnSocket)]
+struct SerialSnapshot {
+    interrupt_enable: u8,
+    interrupt_identification: u8,
+    line_control: u8,
+    line_status: u8,
+    modem_control: u8,
+    modem_status: u8,
+    scratch: u8,
+    baud_divisor: u16,
+    in_buffer: Vec<u8>,
+}
+
 impl SerialDevice for Serial {
     fn new(
         _protected_vm: bool,
@@ -372,6 +387,42 @@ impl BusDevice for Serial {
             _ => 0,
         };
     }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        let snapshot = SerialSnapshot {
+            interrupt_enable: self.interrupt_enable.load(Ordering::SeqCst),
+            interrupt_identification: self.interrupt_identification,
+            line_control: self.line_control,
+            line_status: self.line_status,
+            modem_control: self.modem_control,
+            modem_status: self.modem_status,
+            scratch: self.scratch,
+            baud_divisor: self.baud_divisor,
+            in_buffer: self.in_buffer.iter().copied().collect(),
+        };
+        serialize_to_vec(&snapshot).ok()
+    }
+
+    fn restore(&mut self, data: &[u8]) -> bool {
+        let snapshot: SerialSnapshot = match deserialize_from_slice(data) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("serial got an invalid snapshot: {}", e);
+                return false;
+            }
+        };
+        self.interrupt_enable
+            .store(snapshot.interrupt_enable, Ordering::SeqCst);
+        self.interrupt_identification = snapshot.interrupt_identification;
+        self.line_control = snapshot.line_control;
+        self.line_status = snapshot.line_status;
+        self.modem_control = snapshot.modem_control;
+        self.modem_status = snapshot.modem_status;
+        self.scratch = snapshot.scratch;
+        self.baud_divisor = snapshot.baud_divisor;
+        self.in_buffer = snapshot.in_buffer.into_iter().collect();
+        true
+    }
 }
 
 #[cfg(test)]
@@ -435,6 +486,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn serial_snapshot_restore() {
+        let mut serial = Serial::new(false, Event::new().unwrap(), None, None, Vec::new());
+        serial.write(serial_bus_address(SCR), &[0x5a]);
+        serial.queue_input_bytes(&['a' as u8, 'b' as u8]).unwrap();
+        let snapshot = serial.snapshot().unwrap();
+
+        let mut restored = Serial::new(false, Event::new().unwrap(), None, None, Vec::new());
+        assert!(restored.restore(&snapshot));
+        let mut data = [0u8; 1];
+        restored.read(serial_bus_address(SCR), &mut data[..]);
+        assert_eq!(data[0], 0x5a);
+        restored.read(serial_bus_address(LSR), &mut data[..]);
+        assert_eq!(data[0] & LSR_DATA_BIT, LSR_DATA_BIT);
+        restored.read(serial_bus_address(DATA), &mut data[..]);
+        assert_eq!(data[0], 'a' as u8);
+        restored.read(serial_bus_address(DATA), &mut data[..]);
+        assert_eq!(data[0], 'b' as u8);
+
+        assert!(!restored.restore(&snapshot[1..]));
+    }
+
     #[test]
     fn serial_input() {
         let intr_evt = Event::new().unwrap();
@@ -7,7 +7,7 @@ use std::sync::Arc;
 
 use super::error::*;
 use super::host_device::HostDevice;
-use crate::usb::xhci::usb_hub::UsbHub;
+use crate::usb::xhci::usb_hub::{Error as HubError, UsbHub};
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
 use crate::utils::{EventHandler, EventLoop, FailHandle};
@@ -15,12 +15,13 @@ use base::net::UnixSeqpacket;
 use base::{
     error, AsRawDescriptor, FromRawDescriptor, IntoRawDescriptor, RawDescriptor, WatchingEvents,
 };
+use libc::ENODEV;
 use msg_socket::{MsgReceiver, MsgSender, MsgSocket};
 use std::collections::HashMap;
 use std::mem;
 use std::time::Duration;
 use sync::Mutex;
-use usb_util::Device;
+use usb_util::{Device, Error as UsbUtilError};
 use vm_control::{
     MaybeOwnedDescriptor, UsbControlAttachedDevice, UsbControlCommand, UsbControlResult,
     UsbControlSocket, USB_CONTROL_MAX_PORTS,
@@ -183,8 +184,30 @@ impl ProviderInner {
 
         let arc_mutex_device = Arc::new(Mutex::new(device));
 
+        // Resetting the device is used to make sure it is in a known state, but it may
+        // still function if the reset fails.
+        if let Err(e) = arc_mutex_device.lock().reset() {
+            error!("failed to reset device after attach: {:?}", e);
+        }
+
+        let host_device = Box::new(HostDevice::new(
+            self.fail_handle.clone(),
+            self.job_queue.clone(),
+            arc_mutex_device.clone(),
+        ));
+        let port = match self.usb_hub.connect_backend(host_device) {
+            Ok(port) => port,
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                return UsbControlResult::NoAvailablePort;
+            }
+        };
+
         let event_handler: Arc<dyn EventHandler> = Arc::new(UsbUtilEventHandler {
             device: arc_mutex_device.clone(),
+            event_loop: self.event_loop.clone(),
+            usb_hub: self.usb_hub.clone(),
+            port,
         });
 
         if let Err(e) = self.event_loop.add_event(
@@ -193,36 +216,16 @@ impl ProviderInner {
             Arc::downgrade(&event_handler),
         ) {
             error!("failed to add USB device fd to event handler: {}", e);
+            let _ = self.usb_hub.disconnect_port(port);
             return UsbControlResult::FailedToOpenDevice;
         }
 
         let device_ctx = HostDeviceContext {
             event_handler,
-            device: arc_mutex_device.clone(),
+            device: arc_mutex_device,
         };
-
-        // Resetting the device is used to make sure it is in a known state, but it may
-        // still function if the reset fails.
-        if let Err(e) = arc_mutex_device.lock().reset() {
-            error!("failed to reset device after attach: {:?}", e);
-        }
-
-        let host_device = Box::new(HostDevice::new(
-            self.fail_handle.clone(),
-            self.job_queue.clone(),
-            arc_mutex_device,
-        ));
-        let port = self.usb_hub.connect_backend(host_device);
-        match port {
-            Ok(port) => {
-                self.devices.lock().insert(port, device_ctx);
-                UsbControlResult::Ok { port }
-            }
-            Err(e) => {
-                error!("failed to connect device to hub: {}", e);
-                UsbControlResult::NoAvailablePort
-            }
-        }
+        self.devices.lock().insert(port, device_ctx);
+        UsbControlResult::Ok { port }
     }
 
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
@@ -282,6 +285,10 @@ impl ProviderInner {
             UsbControlCommand::AttachDevice { descriptor, .. } => {
                 self.handle_attach_device(descriptor)
             }
+            UsbControlCommand::AttachDeviceById { .. } => {
+                error!("UsbControlCommand::AttachDeviceById must be handled by the main process");
+                UsbControlResult::FailedToOpenDevice
+            }
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
         };
@@ -300,10 +307,38 @@ impl EventHandler for ProviderInner {
 
 struct UsbUtilEventHandler {
     device: Arc<Mutex<Device>>,
+    event_loop: Arc<EventLoop>,
+    usb_hub: Arc<UsbHub>,
+    port: u8,
 }
 
 impl EventHandler for UsbUtilEventHandler {
     fn on_event(&self) -> std::result::Result<(), ()> {
-        self.device.lock().poll_transfers().map_err(|_e| ())
+        // The lock must not be held while detaching, as dropping the backend device locks it.
+        let result = self.device.lock().poll_transfers();
+        match result {
+            Ok(()) => Ok(()),
+            // The device was unplugged from the host. Detach it from the guest and stop polling
+            // it instead of failing the whole controller, so it can be attached again later.
+            Err(UsbUtilError::IoctlFailed(_, e)) if e.errno() == ENODEV => {
+                usb_debug!("host device on port {} is gone", self.port);
+                match self.usb_hub.disconnect_port(self.port) {
+                    Ok(()) | Err(HubError::AlreadyDetached(_)) => {}
+                    Err(e) => error!("failed to detach port {}: {}", self.port, e),
+                }
+                let fd = self.device.lock().fd();
+                if let Err(e) = self
+                    .event_loop
+                    .remove_event_for_fd(&MaybeOwnedDescriptor::Borrowed(fd.as_raw_descriptor()))
+                {
+                    error!(
+                        "failed to remove unplugged USB device from event loop: {}",
+                        e
+                    );
+                }
+                Ok(())
+            }
+            Err(_) => Err(()),
+        }
     }
 }
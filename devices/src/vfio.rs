@@ -4,6 +4,7 @@
 
 use data_model::vec_with_array_field;
 use std::collections::HashMap;
+use std::error::Error as StdError;
 use std::ffi::CString;
 use std::fmt;
 use std::fs::{File, OpenOptions};
@@ -17,13 +18,15 @@ use sync::Mutex;
 
 use base::{
     ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref, ioctl_with_val, warn,
-    AsRawDescriptor, Error, Event, FromRawDescriptor, RawDescriptor, SafeDescriptor,
+    AsRawDescriptor, Error, Event, FromRawDescriptor, RawDescriptor,
 };
-use hypervisor::{DeviceKind, Vm};
+use hypervisor::{VfioGroupRegistry, Vm};
 use vm_memory::GuestMemory;
 
 use vfio_sys::*;
 
+use crate::virtio::MemoryMapper;
+
 #[derive(Debug)]
 pub enum VfioError {
     OpenContainer(io::Error),
@@ -35,10 +38,12 @@ pub enum VfioError {
     GroupSetContainer(Error),
     ContainerSetIOMMU(Error),
     GroupGetDeviceFD(Error),
-    CreateVfioKvmDevice(Error),
-    KvmSetDeviceAttr(Error),
+    CreateVfioGroupRegistry(Error),
+    AddVfioGroup(Error),
+    RemoveVfioGroup(Error),
     VfioDeviceGetInfo(Error),
     VfioDeviceGetRegionInfo(Error),
+    VfioDeviceGetIrqInfo(Error),
     InvalidPath,
     IommuDmaMap(Error),
     IommuDmaUnmap(Error),
@@ -60,10 +65,12 @@ impl fmt::Display for VfioError {
             VfioError::GroupSetContainer(e) => write!(f, "failed to add vfio group into vfio container: {}", e),
             VfioError::ContainerSetIOMMU(e) => write!(f, "failed to set container's IOMMU driver type as VfioType1V2: {}", e),
             VfioError::GroupGetDeviceFD(e) => write!(f, "failed to get vfio device fd: {}", e),
-            VfioError::CreateVfioKvmDevice(e) => write!(f, "failed to create KVM vfio device: {}", e),
-            VfioError::KvmSetDeviceAttr(e) => write!(f, "failed to set KVM vfio device's attribute: {}", e),
+            VfioError::CreateVfioGroupRegistry(e) => write!(f, "failed to create the hypervisor's vfio group registry: {}", e),
+            VfioError::AddVfioGroup(e) => write!(f, "failed to add vfio group to the vm: {}", e),
+            VfioError::RemoveVfioGroup(e) => write!(f, "failed to remove vfio group from the vm: {}", e),
             VfioError::VfioDeviceGetInfo(e) => write!(f, "failed to get vfio device's info or info doesn't match: {}", e),
             VfioError::VfioDeviceGetRegionInfo(e) => write!(f, "failed to get vfio device's region info: {}", e),
+            VfioError::VfioDeviceGetIrqInfo(e) => write!(f, "failed to get vfio device's irq info: {}", e),
             VfioError::InvalidPath => write!(f,"invalid file path"),
             VfioError::IommuDmaMap(e) => write!(f, "failed to add guest memory map into iommu table: {}", e),
             VfioError::IommuDmaUnmap(e) => write!(f, "failed to remove guest memory map from iommu table: {}", e),
@@ -75,6 +82,8 @@ impl fmt::Display for VfioError {
     }
 }
 
+impl StdError for VfioError {}
+
 fn get_error() -> Error {
     Error::last()
 }
@@ -82,14 +91,26 @@ fn get_error() -> Error {
 /// VfioContainer contain multi VfioGroup, and delegate an IOMMU domain table
 pub struct VfioContainer {
     container: File,
-    kvm_vfio_dev: Option<SafeDescriptor>,
+    group_registry: Option<Box<dyn VfioGroupRegistry>>,
     groups: HashMap<u32, Arc<VfioGroup>>,
+    // Whether all of guest memory is mapped at its guest physical address.
+    identity_map: bool,
 }
 
 const VFIO_API_VERSION: u8 = 0;
 impl VfioContainer {
     /// Open VfioContainer
     pub fn new() -> Result<Self, VfioError> {
+        Self::open(true)
+    }
+
+    /// Opens a container that starts out with no DMA mappings, for devices whose mappings the
+    /// guest makes through the virtio-iommu.
+    pub fn new_guest_mapped() -> Result<Self, VfioError> {
+        Self::open(false)
+    }
+
+    fn open(identity_map: bool) -> Result<Self, VfioError> {
         let container = OpenOptions::new()
             .read(true)
             .write(true)
@@ -104,8 +125,9 @@ impl VfioContainer {
 
         Ok(VfioContainer {
             container,
-            kvm_vfio_dev: None,
+            group_registry: None,
             groups: HashMap::new(),
+            identity_map,
         })
     }
 
@@ -128,10 +150,20 @@ impl VfioContainer {
         unsafe { ioctl_with_val(self, VFIO_SET_IOMMU(), val.into()) }
     }
 
-    unsafe fn vfio_dma_map(&self, iova: u64, size: u64, user_addr: u64) -> Result<(), VfioError> {
+    unsafe fn vfio_dma_map(
+        &self,
+        iova: u64,
+        size: u64,
+        user_addr: u64,
+        write: bool,
+    ) -> Result<(), VfioError> {
+        let mut flags = VFIO_DMA_MAP_FLAG_READ;
+        if write {
+            flags |= VFIO_DMA_MAP_FLAG_WRITE;
+        }
         let dma_map = vfio_iommu_type1_dma_map {
             argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
-            flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+            flags,
             vaddr: user_addr,
             iova,
             size,
@@ -174,15 +206,24 @@ impl VfioContainer {
 
         // Add all guest memory regions into vfio container's iommu table,
         // then vfio kernel driver could access guest memory from gfn
-        guest_mem.with_regions(|_index, guest_addr, size, host_addr, _fd_offset| {
-            // Safe because the guest regions are guaranteed not to overlap
-            unsafe { self.vfio_dma_map(guest_addr.0, size as u64, host_addr as u64) }
-        })?;
+        if self.identity_map {
+            guest_mem.with_regions(|_index, guest_addr, size, host_addr, _fd_offset| {
+                // Devices may only read what the guest may only read.
+                let write = !guest_mem.is_read_only(guest_addr);
+                // Safe because the guest regions are guaranteed not to overlap
+                unsafe { self.vfio_dma_map(guest_addr.0, size as u64, host_addr as u64, write) }
+            })?;
+        }
 
-        let vfio_descriptor = vm
-            .create_device(DeviceKind::Vfio)
-            .map_err(VfioError::CreateVfioKvmDevice)?;
-        self.kvm_vfio_dev = Some(vfio_descriptor);
+        // The VM may only have room for one group registry (KVM has a single vfio device per VM),
+        // so keep the one created the first time when the container is initialized again after
+        // all of its groups were removed.
+        if self.group_registry.is_none() {
+            let group_registry = vm
+                .create_vfio_group_registry()
+                .map_err(VfioError::CreateVfioGroupRegistry)?;
+            self.group_registry = Some(group_registry);
+        }
 
         Ok(())
     }
@@ -204,11 +245,11 @@ impl VfioContainer {
                     self.init(vm, guest_mem)?;
                 }
 
-                let kvm_vfio_file = self
-                    .kvm_vfio_dev
+                self.group_registry
                     .as_ref()
-                    .expect("kvm vfio device should exist");
-                group.kvm_device_add_group(kvm_vfio_file)?;
+                    .expect("vfio group registry should exist")
+                    .add_group(group.as_ref())
+                    .map_err(VfioError::AddVfioGroup)?;
 
                 self.groups.insert(id, group.clone());
 
@@ -216,6 +257,24 @@ impl VfioContainer {
             }
         }
     }
+
+    /// Returns true if the group `id` has been added to this container.
+    pub fn has_group(&self, id: u32) -> bool {
+        self.groups.contains_key(&id)
+    }
+
+    /// Removes the group `id` from this container, so the host can take back its devices. The
+    /// devices of the group must have been dropped already.
+    pub fn remove_group(&mut self, id: u32) -> Result<(), VfioError> {
+        if let Some(group) = self.groups.remove(&id) {
+            if let Some(group_registry) = self.group_registry.as_ref() {
+                group_registry
+                    .remove_group(group.as_ref())
+                    .map_err(VfioError::RemoveVfioGroup)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl AsRawDescriptor for VfioContainer {
@@ -224,6 +283,28 @@ impl AsRawDescriptor for VfioContainer {
     }
 }
 
+impl MemoryMapper for VfioContainer {
+    unsafe fn add_map(
+        &mut self,
+        iova: u64,
+        host_addr: u64,
+        size: u64,
+        writable: bool,
+    ) -> std::result::Result<(), Box<dyn StdError + Send>> {
+        self.vfio_dma_map(iova, size, host_addr, writable)
+            .map_err(|e| Box::new(e) as Box<dyn StdError + Send>)
+    }
+
+    fn remove_map(
+        &mut self,
+        iova: u64,
+        size: u64,
+    ) -> std::result::Result<(), Box<dyn StdError + Send>> {
+        self.vfio_dma_unmap(iova, size)
+            .map_err(|e| Box::new(e) as Box<dyn StdError + Send>)
+    }
+}
+
 struct VfioGroup {
     group: File,
 }
@@ -272,31 +353,6 @@ impl VfioGroup {
         Ok(VfioGroup { group: group_file })
     }
 
-    fn kvm_device_add_group(&self, kvm_vfio_file: &SafeDescriptor) -> Result<(), VfioError> {
-        let group_descriptor = self.as_raw_descriptor();
-        let group_descriptor_ptr = &group_descriptor as *const i32;
-        let vfio_dev_attr = kvm_sys::kvm_device_attr {
-            flags: 0,
-            group: kvm_sys::KVM_DEV_VFIO_GROUP,
-            attr: kvm_sys::KVM_DEV_VFIO_GROUP_ADD as u64,
-            addr: group_descriptor_ptr as u64,
-        };
-
-        // Safe as we are the owner of vfio_dev_fd and vfio_dev_attr which are valid value,
-        // and we verify the return value.
-        if 0 != unsafe {
-            ioctl_with_ref(
-                kvm_vfio_file,
-                kvm_sys::KVM_SET_DEVICE_ATTR(),
-                &vfio_dev_attr,
-            )
-        } {
-            return Err(VfioError::KvmSetDeviceAttr(get_error()));
-        }
-
-        Ok(())
-    }
-
     fn get_device(&self, name: &str) -> Result<File, VfioError> {
         let path: CString = CString::new(name.as_bytes()).expect("CString::new() failed");
         let path_ptr = path.as_ptr();
@@ -323,6 +379,23 @@ pub enum VfioIrqType {
     Intx,
     Msi,
     Msix,
+    /// The interrupt of a platform device at the given index.
+    Platform(u32),
+}
+
+/// Bus a vfio device sits on in the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VfioDeviceType {
+    Pci,
+    Platform,
+}
+
+/// An interrupt of a vfio platform device.
+#[derive(Copy, Clone, Debug)]
+pub struct VfioIrq {
+    /// VFIO_IRQ_INFO_* flags of the interrupt.
+    pub flags: u32,
+    pub index: u32,
 }
 
 struct VfioRegion {
@@ -337,12 +410,25 @@ struct VfioRegion {
     cap_info: Option<(u32, u32)>,
 }
 
+/// Identity of an SR-IOV virtual function. A VF reads back 0xffff as its vendor and device IDs,
+/// so they are taken from sysfs instead.
+#[derive(Clone, Debug)]
+pub struct VfioVirtfn {
+    /// Name of the physical function, formatted as BUS:DEVICE.FUNCTION.
+    pub physfn: String,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
 /// Vfio device for exposing regions which could be read/write to kernel vfio device.
 pub struct VfioDevice {
     dev: File,
     name: String,
+    virtfn: Option<VfioVirtfn>,
     container: Arc<Mutex<VfioContainer>>,
     group_descriptor: RawDescriptor,
+    device_type: VfioDeviceType,
+    num_irqs: u32,
     // vec for vfio device's regions
     regions: Vec<VfioRegion>,
 }
@@ -357,37 +443,79 @@ impl VfioDevice {
         guest_mem: &GuestMemory,
         container: Arc<Mutex<VfioContainer>>,
     ) -> Result<Self, VfioError> {
-        let mut uuid_path = PathBuf::new();
-        uuid_path.push(sysfspath);
-        uuid_path.push("iommu_group");
-        let group_path = uuid_path.read_link().map_err(|_| VfioError::InvalidPath)?;
-        let group_osstr = group_path.file_name().ok_or(VfioError::InvalidPath)?;
-        let group_str = group_osstr.to_str().ok_or(VfioError::InvalidPath)?;
-        let group_id = group_str
-            .parse::<u32>()
-            .map_err(|_| VfioError::InvalidPath)?;
-
+        let group_id = Self::group_id(sysfspath)?;
         let group = container.lock().get_group(group_id, vm, guest_mem)?;
         let name_osstr = sysfspath.file_name().ok_or(VfioError::InvalidPath)?;
         let name_str = name_osstr.to_str().ok_or(VfioError::InvalidPath)?;
         let name = String::from(name_str);
         let dev = group.get_device(&name)?;
-        let regions = Self::get_regions(&dev)?;
+        let (device_type, dev_info) = Self::get_device_info(&dev)?;
+        let regions = Self::get_regions(&dev, dev_info.num_regions)?;
+        let virtfn = Self::read_virtfn(sysfspath)?;
 
         Ok(VfioDevice {
             dev,
             name,
+            virtfn,
             container,
             group_descriptor: group.as_raw_descriptor(),
+            device_type,
+            num_irqs: dev_info.num_irqs,
             regions,
         })
     }
 
-    /// Returns PCI device name, formatted as BUS:DEVICE.FUNCTION string.
+    /// Returns the IOMMU group of the device at `sysfspath`.
+    pub fn group_id(sysfspath: &Path) -> Result<u32, VfioError> {
+        let mut uuid_path = PathBuf::new();
+        uuid_path.push(sysfspath);
+        uuid_path.push("iommu_group");
+        let group_path = uuid_path.read_link().map_err(|_| VfioError::InvalidPath)?;
+        let group_osstr = group_path.file_name().ok_or(VfioError::InvalidPath)?;
+        let group_str = group_osstr.to_str().ok_or(VfioError::InvalidPath)?;
+        group_str.parse::<u32>().map_err(|_| VfioError::InvalidPath)
+    }
+
+    // Reads the SR-IOV identity of the device at `sysfspath`, which is `None` unless it's a
+    // virtual function.
+    fn read_virtfn(sysfspath: &Path) -> Result<Option<VfioVirtfn>, VfioError> {
+        let physfn_path = match sysfspath.join("physfn").read_link() {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let physfn = physfn_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(VfioError::InvalidPath)?;
+        let read_id = |file: &str| {
+            let id = std::fs::read_to_string(sysfspath.join(file))
+                .map_err(|_| VfioError::InvalidPath)?;
+            u16::from_str_radix(id.trim().trim_start_matches("0x"), 16)
+                .map_err(|_| VfioError::InvalidPath)
+        };
+        Ok(Some(VfioVirtfn {
+            physfn: physfn.to_string(),
+            vendor_id: read_id("vendor")?,
+            device_id: read_id("device")?,
+        }))
+    }
+
+    /// Returns PCI device name, formatted as BUS:DEVICE.FUNCTION string, or the name of a
+    /// platform device.
     pub fn device_name(&self) -> &String {
         &self.name
     }
 
+    /// Returns the bus the device sits on in the host.
+    pub fn device_type(&self) -> VfioDeviceType {
+        self.device_type
+    }
+
+    /// Returns the SR-IOV identity of this device if it's a virtual function.
+    pub fn virtfn(&self) -> Option<&VfioVirtfn> {
+        self.virtfn.as_ref()
+    }
+
     /// Enable vfio device's irq and associate Irqfd Event with device.
     /// When MSIx is enabled, multi vectors will be supported, so descriptors is vector and the vector
     /// length is the num of MSIx vectors
@@ -405,6 +533,7 @@ impl VfioDevice {
             VfioIrqType::Intx => irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX,
             VfioIrqType::Msi => irq_set[0].index = VFIO_PCI_MSI_IRQ_INDEX,
             VfioIrqType::Msix => irq_set[0].index = VFIO_PCI_MSIX_IRQ_INDEX,
+            VfioIrqType::Platform(index) => irq_set[0].index = index,
         }
         irq_set[0].start = 0;
         irq_set[0].count = count as u32;
@@ -437,12 +566,23 @@ impl VfioDevice {
     /// generate another interrupts.
     /// This function enable resample irqfd and let vfio kernel could get EOI notification.
     ///
+    /// Level triggered interrupts of platform devices are handled the same way.
+    ///
     /// descriptor: should be resample IrqFd.
-    pub fn resample_virq_enable(&self, descriptor: &Event) -> Result<(), VfioError> {
+    pub fn resample_virq_enable(
+        &self,
+        descriptor: &Event,
+        irq_type: VfioIrqType,
+    ) -> Result<(), VfioError> {
         let mut irq_set = vec_with_array_field::<vfio_irq_set, u32>(1);
         irq_set[0].argsz = (mem::size_of::<vfio_irq_set>() + mem::size_of::<u32>()) as u32;
         irq_set[0].flags = VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_UNMASK;
-        irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX;
+        match irq_type {
+            VfioIrqType::Intx => irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX,
+            VfioIrqType::Msi => irq_set[0].index = VFIO_PCI_MSI_IRQ_INDEX,
+            VfioIrqType::Msix => irq_set[0].index = VFIO_PCI_MSIX_IRQ_INDEX,
+            VfioIrqType::Platform(index) => irq_set[0].index = index,
+        }
         irq_set[0].start = 0;
         irq_set[0].count = 1;
 
@@ -473,6 +613,7 @@ impl VfioDevice {
             VfioIrqType::Intx => irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX,
             VfioIrqType::Msi => irq_set[0].index = VFIO_PCI_MSI_IRQ_INDEX,
             VfioIrqType::Msix => irq_set[0].index = VFIO_PCI_MSIX_IRQ_INDEX,
+            VfioIrqType::Platform(index) => irq_set[0].index = index,
         }
         irq_set[0].start = 0;
         irq_set[0].count = 0;
@@ -495,6 +636,7 @@ impl VfioDevice {
             VfioIrqType::Intx => irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX,
             VfioIrqType::Msi => irq_set[0].index = VFIO_PCI_MSI_IRQ_INDEX,
             VfioIrqType::Msix => irq_set[0].index = VFIO_PCI_MSIX_IRQ_INDEX,
+            VfioIrqType::Platform(index) => irq_set[0].index = index,
         }
         irq_set[0].start = 0;
         irq_set[0].count = 1;
@@ -517,6 +659,7 @@ impl VfioDevice {
             VfioIrqType::Intx => irq_set[0].index = VFIO_PCI_INTX_IRQ_INDEX,
             VfioIrqType::Msi => irq_set[0].index = VFIO_PCI_MSI_IRQ_INDEX,
             VfioIrqType::Msix => irq_set[0].index = VFIO_PCI_MSIX_IRQ_INDEX,
+            VfioIrqType::Platform(index) => irq_set[0].index = index,
         }
         irq_set[0].start = 0;
         irq_set[0].count = 1;
@@ -530,9 +673,7 @@ impl VfioDevice {
         }
     }
 
-    #[allow(clippy::cast_ptr_alignment)]
-    fn get_regions(dev: &File) -> Result<Vec<VfioRegion>, VfioError> {
-        let mut regions: Vec<VfioRegion> = Vec::new();
+    fn get_device_info(dev: &File) -> Result<(VfioDeviceType, vfio_device_info), VfioError> {
         let mut dev_info = vfio_device_info {
             argsz: mem::size_of::<vfio_device_info>() as u32,
             flags: 0,
@@ -541,16 +682,29 @@ impl VfioDevice {
         };
         // Safe as we are the owner of dev and dev_info which are valid value,
         // and we verify the return value.
-        let mut ret = unsafe { ioctl_with_mut_ref(dev, VFIO_DEVICE_GET_INFO(), &mut dev_info) };
-        if ret < 0
-            || (dev_info.flags & VFIO_DEVICE_FLAGS_PCI) == 0
-            || dev_info.num_regions < VFIO_PCI_CONFIG_REGION_INDEX + 1
-            || dev_info.num_irqs < VFIO_PCI_MSIX_IRQ_INDEX + 1
-        {
+        let ret = unsafe { ioctl_with_mut_ref(dev, VFIO_DEVICE_GET_INFO(), &mut dev_info) };
+        if ret < 0 {
             return Err(VfioError::VfioDeviceGetInfo(get_error()));
         }
 
-        for i in VFIO_PCI_BAR0_REGION_INDEX..dev_info.num_regions {
+        if (dev_info.flags & VFIO_DEVICE_FLAGS_PCI) != 0
+            && dev_info.num_regions > VFIO_PCI_CONFIG_REGION_INDEX
+            && dev_info.num_irqs > VFIO_PCI_MSIX_IRQ_INDEX
+        {
+            Ok((VfioDeviceType::Pci, dev_info))
+        } else if (dev_info.flags & VFIO_DEVICE_FLAGS_PLATFORM) != 0 {
+            Ok((VfioDeviceType::Platform, dev_info))
+        } else {
+            Err(VfioError::VfioDeviceGetInfo(get_error()))
+        }
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    fn get_regions(dev: &File, num_regions: u32) -> Result<Vec<VfioRegion>, VfioError> {
+        let mut regions: Vec<VfioRegion> = Vec::new();
+        let mut ret;
+        // BAR0 is region 0, which is also where the regions of platform devices start.
+        for i in VFIO_PCI_BAR0_REGION_INDEX..num_regions {
             let argsz = mem::size_of::<vfio_region_info>() as u32;
             let mut reg_info = vfio_region_info {
                 argsz,
@@ -691,6 +845,22 @@ impl VfioDevice {
         }
     }
 
+    /// get the number of regions of the device
+    pub fn get_region_count(&self) -> u32 {
+        self.regions.len() as u32
+    }
+
+    /// get a region's size
+    pub fn get_region_size(&self, index: u32) -> u64 {
+        match self.regions.get(index as usize) {
+            Some(v) => v.size,
+            None => {
+                warn!("get_region_size with invalid index: {}", index);
+                0
+            }
+        }
+    }
+
     /// get a region's offset
     /// return: Region offset from the start of vfio device descriptor
     pub fn get_region_offset(&self, index: u32) -> u64 {
@@ -799,6 +969,33 @@ impl VfioDevice {
         }
     }
 
+    /// Returns the interrupts of a platform device, whose indices start at 0.
+    pub fn get_irqs(&self) -> Result<Vec<VfioIrq>, VfioError> {
+        let mut irqs = Vec::new();
+        for index in 0..self.num_irqs {
+            let mut irq_info = vfio_irq_info {
+                argsz: mem::size_of::<vfio_irq_info>() as u32,
+                flags: 0,
+                index,
+                count: 0,
+            };
+            // Safe as we are the owner of self and irq_info which are valid value,
+            // and we verify the return value.
+            let ret =
+                unsafe { ioctl_with_mut_ref(self, VFIO_DEVICE_GET_IRQ_INFO(), &mut irq_info) };
+            if ret < 0 {
+                return Err(VfioError::VfioDeviceGetIrqInfo(get_error()));
+            }
+            if irq_info.count != 0 {
+                irqs.push(VfioIrq {
+                    flags: irq_info.flags,
+                    index,
+                });
+            }
+        }
+        Ok(irqs)
+    }
+
     /// get vfio device's descriptors which are passed into minijail process
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
         let mut rds = Vec::new();
@@ -815,7 +1012,9 @@ impl VfioDevice {
         size: u64,
         user_addr: u64,
     ) -> Result<(), VfioError> {
-        self.container.lock().vfio_dma_map(iova, size, user_addr)
+        self.container
+            .lock()
+            .vfio_dma_map(iova, size, user_addr, true)
     }
 
     /// Remove (iova, user_addr) map from vfio container iommu table
@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -13,7 +13,7 @@ use remain::sorted;
 use thiserror::Error as ThisError;
 
 use base::{self, error, info, warn, AsRawDescriptor, Event, RawDescriptor};
-use cros_async::{select6, EventAsync, Executor};
+use cros_async::{select, EventAsync, Executor};
 use data_model::{DataInit, Le16, Le32, Le64};
 use msg_socket::MsgSender;
 use vm_control::{
@@ -22,8 +22,8 @@ use vm_control::{
 use vm_memory::{GuestAddress, GuestMemory};
 
 use super::{
-    copy_config, descriptor_utils, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice,
-    TYPE_BALLOON,
+    copy_config, descriptor_utils, DescriptorChain, DeviceMetrics, Interrupt, Queue, Reader,
+    VirtioDevice, TYPE_BALLOON,
 };
 
 #[sorted]
@@ -41,9 +41,12 @@ pub enum BalloonError {
 }
 pub type Result<T> = std::result::Result<T, BalloonError>;
 
-// Balloon has three virt IO queues: Inflate, Deflate, and Stats.
+// Balloon has three virt IO queues: Inflate, Deflate, and Stats. A fourth queue carries guest
+// memory requests when `VIRTIO_BALLOON_F_GUEST_REQUEST` is offered.
 const QUEUE_SIZE: u16 = 128;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+const GUEST_REQUEST_QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+const STATS_QUEUE_INDEX: usize = 2;
 
 const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
 
@@ -51,6 +54,7 @@ const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
 const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0; // Tell before reclaiming pages
 const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1; // Stats reporting enabled
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2; // Deflate balloon on OOM
+const VIRTIO_BALLOON_F_GUEST_REQUEST: u32 = 23; // Guest agent resize requests (not in the spec)
 
 // virtio_balloon_config is the balloon device configuration space defined by the virtio spec.
 #[derive(Copy, Clone, Debug, Default)]
@@ -68,6 +72,8 @@ unsafe impl DataInit for virtio_balloon_config {}
 struct BalloonConfig {
     num_pages: AtomicUsize,
     actual_pages: AtomicUsize,
+    // Pages given back to the host with `remove_range` and not yet deflated.
+    removed_pages: AtomicUsize,
 }
 
 // The constants defining stats types in virtio_baloon_stat
@@ -111,16 +117,17 @@ impl BalloonStat {
     }
 }
 
-// Processes one message's list of addresses.
+// Processes one message's list of addresses. Returns the number of pages handled.
 fn handle_address_chain<F>(
     avail_desc: DescriptorChain,
     mem: &GuestMemory,
     desc_handler: &mut F,
-) -> descriptor_utils::Result<()>
+) -> descriptor_utils::Result<u64>
 where
     F: FnMut(GuestAddress),
 {
     let mut reader = Reader::new(mem.clone(), avail_desc)?;
+    let mut pages = 0;
     for res in reader.iter::<Le32>() {
         let pfn = match res {
             Ok(pfn) => pfn,
@@ -132,16 +139,18 @@ where
         let guest_address = GuestAddress((u64::from(pfn.to_native())) << VIRTIO_BALLOON_PFN_SHIFT);
 
         desc_handler(guest_address);
+        pages += 1;
     }
-    Ok(())
+    Ok(pages)
 }
 
 // Async task that handles the main balloon inflate and deflate queues.
 async fn handle_queue<F>(
     mem: &GuestMemory,
-    mut queue: Queue,
+    queue: &mut Queue,
     mut queue_event: EventAsync,
     interrupt: Rc<RefCell<Interrupt>>,
+    metrics: &DeviceMetrics,
     mut desc_handler: F,
 ) where
     F: FnMut(GuestAddress),
@@ -155,8 +164,9 @@ async fn handle_queue<F>(
             Ok(d) => d,
         };
         let index = avail_desc.index;
-        if let Err(e) = handle_address_chain(avail_desc, mem, &mut desc_handler) {
-            error!("balloon: failed to process inflate addresses: {}", e);
+        match handle_address_chain(avail_desc, mem, &mut desc_handler) {
+            Ok(pages) => metrics.add_request(pages << VIRTIO_BALLOON_PFN_SHIFT),
+            Err(e) => error!("balloon: failed to process inflate addresses: {}", e),
         }
         queue.add_used(mem, index, 0);
         interrupt.borrow_mut().signal_used_queue(queue.vector);
@@ -167,14 +177,17 @@ async fn handle_queue<F>(
 // balloon stats from the control pipe.
 // The guests queues an initial buffer on boot, which is read and then this future will block until
 // signaled from the command socket that stats should be collected again.
+// The index of the buffer held while waiting is kept in `pending_desc` so that it can be returned
+// to the guest if the worker stops.
 async fn handle_stats_queue(
     mem: &GuestMemory,
-    mut queue: Queue,
+    queue: &mut Queue,
     mut queue_event: EventAsync,
     mut stats_rx: mpsc::Receiver<()>,
     command_socket: &BalloonControlResponseSocket,
     config: Arc<BalloonConfig>,
     interrupt: Rc<RefCell<Interrupt>>,
+    pending_desc: &Cell<Option<u16>>,
 ) {
     loop {
         let stats_desc = match queue.next_async(mem, &mut queue_event).await {
@@ -185,6 +198,7 @@ async fn handle_stats_queue(
             Ok(d) => d,
         };
         let index = stats_desc.index;
+        pending_desc.set(Some(index));
         let mut reader = match Reader::new(mem.clone(), stats_desc) {
             Ok(r) => r,
             Err(e) => {
@@ -218,11 +232,61 @@ async fn handle_stats_queue(
         }
 
         // Request a new stats_desc to the guest.
+        pending_desc.set(None);
         queue.add_used(&mem, index, 0);
         interrupt.borrow_mut().signal_used_queue(queue.vector);
     }
 }
 
+// Reads the amount of memory, in bytes, that the guest asks to keep from one guest request buffer.
+fn parse_guest_request(
+    avail_desc: DescriptorChain,
+    mem: &GuestMemory,
+) -> descriptor_utils::Result<u64> {
+    let mut reader = Reader::new(mem.clone(), avail_desc)?;
+    let num_bytes: Le64 = reader
+        .read_obj()
+        .map_err(descriptor_utils::Error::IoError)?;
+    Ok(num_bytes.to_native())
+}
+
+// Async task that handles the guest request queue. Each request is forwarded to the host, whose
+// balloon policy decides how much of it to honor, and then returned to the guest right away.
+async fn handle_guest_request_queue(
+    mem: &GuestMemory,
+    queue: Option<(&mut Queue, EventAsync)>,
+    command_socket: &BalloonControlResponseSocket,
+    interrupt: Rc<RefCell<Interrupt>>,
+) {
+    let (queue, mut queue_event) = match queue {
+        Some(q) => q,
+        // The guest didn't enable the queue, so it will never send a request.
+        None => return futures::future::pending().await,
+    };
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        match parse_guest_request(avail_desc, mem) {
+            Ok(num_bytes) => {
+                info!("guest requested to keep {} bytes of memory", num_bytes);
+                let result = BalloonControlResult::GuestRequest { num_bytes };
+                if let Err(e) = command_socket.send(&result) {
+                    error!("failed to send guest request: {}", e);
+                }
+            }
+            Err(e) => error!("balloon: failed to read guest request: {}", e),
+        }
+        queue.add_used(mem, index, 0);
+        interrupt.borrow_mut().signal_used_queue(queue.vector);
+    }
+}
+
 // Async task that handles the command socket. The command socket handles messages from the host
 // requesting that the guest balloon be adjusted or to report guest memory statistics.
 async fn handle_command_socket(
@@ -250,6 +314,17 @@ async fn handle_command_socket(
                         error!("failed to signal the stat handler: {}", e);
                     }
                 }
+                BalloonControlCommand::Accounting => {
+                    let actual_pages = config.actual_pages.load(Ordering::Relaxed) as u64;
+                    let removed_pages = config.removed_pages.load(Ordering::Relaxed) as u64;
+                    let result = BalloonControlResult::Accounting {
+                        balloon_actual: actual_pages << VIRTIO_BALLOON_PFN_SHIFT,
+                        removed_bytes: removed_pages << VIRTIO_BALLOON_PFN_SHIFT,
+                    };
+                    if let Err(e) = command_socket.send(&result) {
+                        error!("failed to send accounting result: {}", e);
+                    }
+                }
             },
             Err(e) => {
                 return Err(BalloonError::ReceivingCommand(e));
@@ -289,70 +364,131 @@ fn run_worker(
     kill_evt: Event,
     mem: GuestMemory,
     config: Arc<BalloonConfig>,
+    metrics: DeviceMetrics,
 ) {
     // Wrap the interrupt in a `RefCell` so it can be shared between async functions.
     let interrupt = Rc::new(RefCell::new(interrupt));
 
     let ex = Executor::new().unwrap();
 
-    // The first queue is used for inflate messages
-    let inflate_event =
-        EventAsync::new(queue_evts.remove(0).0, &ex).expect("failed to set up the inflate event");
-    let inflate = handle_queue(
-        &mem,
-        queues.remove(0),
-        inflate_event,
-        interrupt.clone(),
-        |guest_address| {
-            if let Err(e) = mem.remove_range(guest_address, 1 << VIRTIO_BALLOON_PFN_SHIFT) {
-                warn!("Marking pages unused failed: {}, addr={}", e, guest_address);
-            }
-        },
-    );
-    pin_mut!(inflate);
-
-    // The second queue is used for deflate messages
-    let deflate_event =
-        EventAsync::new(queue_evts.remove(0).0, &ex).expect("failed to set up the deflate event");
-    let deflate = handle_queue(
-        &mem,
-        queues.remove(0),
-        deflate_event,
-        interrupt.clone(),
-        std::mem::drop, // Ignore these.
-    );
-    pin_mut!(deflate);
-
-    // The third queue is used for stats messages
-    let (stats_tx, stats_rx) = mpsc::channel::<()>(1);
-    let stats_event =
-        EventAsync::new(queue_evts.remove(0).0, &ex).expect("failed to set up the stats event");
-    let stats = handle_stats_queue(
-        &mem,
-        queues.remove(0),
-        stats_event,
-        stats_rx,
-        command_socket,
-        config.clone(),
-        interrupt.clone(),
-    );
-    pin_mut!(stats);
-
-    // Future to handle command messages that resize the balloon.
-    let command = handle_command_socket(&ex, command_socket, interrupt.clone(), config, stats_tx);
-    pin_mut!(command);
-
-    // Process any requests to resample the irq value.
-    let resample = handle_irq_resample(&ex, interrupt.clone());
-    pin_mut!(resample);
-
-    // Exit if the kill event is triggered.
-    let kill_evt = EventAsync::new(kill_evt.0, &ex).expect("failed to set up the kill event");
-    let kill = wait_kill(kill_evt);
-    pin_mut!(kill);
-
-    if let Err(e) = ex.run_until(select6(inflate, deflate, stats, command, resample, kill)) {
-        error!("error happened in executor: {}", e);
+    // Keep track of the pages actually released to the host so it can be reported with
+    // `BalloonControlCommand::Accounting`.
+    let inflate_config = config.clone();
+    let removed_pages = &inflate_config.removed_pages;
+
+    // The stats buffer the guest is waiting on to be asked for stats again, if any.
+    let pending_stats_desc = Cell::new(None);
+
+    // The tasks only borrow the queues so that whatever the guest left in them can be returned
+    // after the tasks stop.
+    {
+        let mut queues = queues.iter_mut();
+
+        // The first queue is used for inflate messages
+        let inflate_event = EventAsync::new(queue_evts.remove(0).0, &ex)
+            .expect("failed to set up the inflate event");
+        let inflate = handle_queue(
+            &mem,
+            queues.next().unwrap(),
+            inflate_event,
+            interrupt.clone(),
+            &metrics,
+            |guest_address| match mem.remove_range(guest_address, 1 << VIRTIO_BALLOON_PFN_SHIFT) {
+                Ok(()) => {
+                    removed_pages.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => warn!("Marking pages unused failed: {}, addr={}", e, guest_address),
+            },
+        );
+        pin_mut!(inflate);
+
+        // The second queue is used for deflate messages
+        let deflate_event = EventAsync::new(queue_evts.remove(0).0, &ex)
+            .expect("failed to set up the deflate event");
+        let deflate = handle_queue(
+            &mem,
+            queues.next().unwrap(),
+            deflate_event,
+            interrupt.clone(),
+            &metrics,
+            |_guest_address| {
+                // The guest is free to use deflated pages again, they'll be faulted back in.
+                let _ = removed_pages.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pages| {
+                    pages.checked_sub(1)
+                });
+            },
+        );
+        pin_mut!(deflate);
+
+        // The third queue is used for stats messages
+        let (stats_tx, stats_rx) = mpsc::channel::<()>(1);
+        let stats_event =
+            EventAsync::new(queue_evts.remove(0).0, &ex).expect("failed to set up the stats event");
+        let stats = handle_stats_queue(
+            &mem,
+            queues.next().unwrap(),
+            stats_event,
+            stats_rx,
+            command_socket,
+            config.clone(),
+            interrupt.clone(),
+            &pending_stats_desc,
+        );
+        pin_mut!(stats);
+
+        // The optional fourth queue is used for guest requests.
+        let guest_request_queue = queues.next().map(|queue| {
+            let guest_request_event = EventAsync::new(queue_evts.remove(0).0, &ex)
+                .expect("failed to set up the guest request event");
+            (queue, guest_request_event)
+        });
+        let guest_request = handle_guest_request_queue(
+            &mem,
+            guest_request_queue,
+            command_socket,
+            interrupt.clone(),
+        );
+        pin_mut!(guest_request);
+
+        // Future to handle command messages that resize the balloon.
+        let command =
+            handle_command_socket(&ex, command_socket, interrupt.clone(), config, stats_tx);
+        pin_mut!(command);
+
+        // Process any requests to resample the irq value.
+        let resample = handle_irq_resample(&ex, interrupt.clone());
+        pin_mut!(resample);
+
+        // Exit if the kill event is triggered.
+        let kill_evt = EventAsync::new(kill_evt.0, &ex).expect("failed to set up the kill event");
+        let kill = wait_kill(kill_evt);
+        pin_mut!(kill);
+
+        if let Err(e) = ex.run_until(select!(
+            inflate,
+            deflate,
+            stats,
+            guest_request,
+            command,
+            resample,
+            kill,
+        )) {
+            error!("error happened in executor: {}", e);
+        }
+    }
+
+    // Give the guest back every buffer it is still waiting on so that it isn't leaked across a
+    // device reset.
+    let stats_desc = pending_stats_desc.take();
+    for (queue_index, queue) in queues.iter_mut().enumerate() {
+        let mut returned = queue.drain(&mem, |_| 0);
+        if let (STATS_QUEUE_INDEX, Some(desc_index)) = (queue_index, stats_desc) {
+            queue.add_used(&mem, desc_index, 0);
+            returned += 1;
+        }
+        if returned > 0 {
+            interrupt.borrow_mut().signal_used_queue(queue.vector);
+        }
     }
 }
 
@@ -361,28 +497,41 @@ pub struct Balloon {
     command_socket: Option<BalloonControlResponseSocket>,
     config: Arc<BalloonConfig>,
     features: u64,
+    guest_requests: bool,
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<BalloonControlResponseSocket>>,
+    metrics: DeviceMetrics,
 }
 
 impl Balloon {
     /// Creates a new virtio balloon device.
+    ///
+    /// If `guest_requests` is set, the device offers a queue through which a guest agent can ask
+    /// the host to resize the balloon.
     pub fn new(
         base_features: u64,
         command_socket: BalloonControlResponseSocket,
+        guest_requests: bool,
     ) -> Result<Balloon> {
+        let mut features = base_features
+            | 1 << VIRTIO_BALLOON_F_MUST_TELL_HOST
+            | 1 << VIRTIO_BALLOON_F_STATS_VQ
+            | 1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        if guest_requests {
+            features |= 1 << VIRTIO_BALLOON_F_GUEST_REQUEST;
+        }
         Ok(Balloon {
             command_socket: Some(command_socket),
             config: Arc::new(BalloonConfig {
                 num_pages: AtomicUsize::new(0),
                 actual_pages: AtomicUsize::new(0),
+                removed_pages: AtomicUsize::new(0),
             }),
             kill_evt: None,
             worker_thread: None,
-            features: base_features
-                | 1 << VIRTIO_BALLOON_F_MUST_TELL_HOST
-                | 1 << VIRTIO_BALLOON_F_STATS_VQ
-                | 1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
+            features,
+            guest_requests,
+            metrics: DeviceMetrics::default(),
         })
     }
 
@@ -419,7 +568,11 @@ impl VirtioDevice for Balloon {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        if self.guest_requests {
+            GUEST_REQUEST_QUEUE_SIZES
+        } else {
+            QUEUE_SIZES
+        }
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
@@ -442,6 +595,10 @@ impl VirtioDevice for Balloon {
         self.features &= value;
     }
 
+    fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -449,7 +606,13 @@ impl VirtioDevice for Balloon {
         queues: Vec<Queue>,
         queue_evts: Vec<Event>,
     ) {
-        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+        // The guest request queue is only present if the guest acknowledged the feature.
+        let expected_queues = if self.features & (1 << VIRTIO_BALLOON_F_GUEST_REQUEST) != 0 {
+            GUEST_REQUEST_QUEUE_SIZES.len()
+        } else {
+            QUEUE_SIZES.len()
+        };
+        if queues.len() != expected_queues || queue_evts.len() != expected_queues {
             return;
         }
 
@@ -463,6 +626,7 @@ impl VirtioDevice for Balloon {
         self.kill_evt = Some(self_kill_evt);
 
         let config = self.config.clone();
+        let metrics = self.metrics.clone();
         let command_socket = self.command_socket.take().unwrap();
         let worker_result = thread::Builder::new()
             .name("virtio_balloon".to_string())
@@ -475,6 +639,7 @@ impl VirtioDevice for Balloon {
                     kill_evt,
                     mem,
                     config,
+                    metrics,
                 );
                 command_socket // Return the command socket so it can be re-used.
             });
@@ -511,6 +676,10 @@ impl VirtioDevice for Balloon {
         }
         false
     }
+
+    fn uses_async_executor(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -553,4 +722,33 @@ mod tests {
             GuestAddress(0xaa55aa55u64 << VIRTIO_BALLOON_PFN_SHIFT)
         );
     }
+
+    #[test]
+    fn desc_parsing_guest_request() {
+        let memory = GuestMemory::new(&vec![(GuestAddress(0x0), 0x10000)]).unwrap();
+        memory
+            .write_obj_at_addr(Le64::from(512u64 << 20), GuestAddress(0x100))
+            .unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(DescriptorType::Readable, 8)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert_eq!(parse_guest_request(chain, &memory).unwrap(), 512 << 20);
+
+        // A request that is too short to hold the size is rejected.
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(DescriptorType::Readable, 4)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert!(parse_guest_request(chain, &memory).is_err());
+    }
 }
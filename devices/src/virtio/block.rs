@@ -6,6 +6,7 @@ use std::cmp::{max, min};
 use std::fmt::{self, Display};
 use std::io::{self, Write};
 use std::mem::size_of;
+use std::panic::{self, AssertUnwindSafe};
 use std::result;
 use std::sync::Arc;
 use std::thread;
@@ -15,8 +16,8 @@ use std::u32;
 use base::Error as SysError;
 use base::Result as SysResult;
 use base::{
-    error, info, iov_max, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, Timer,
-    WaitContext,
+    error, info, iov_max, warn, AsRawDescriptor, Event, LandlockRuleset, PollToken, RawDescriptor,
+    Timer, WaitContext,
 };
 use data_model::{DataInit, Le16, Le32, Le64};
 use disk::DiskFile;
@@ -26,8 +27,8 @@ use vm_control::{DiskControlCommand, DiskControlResponseSocket, DiskControlResul
 use vm_memory::GuestMemory;
 
 use super::{
-    copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
-    TYPE_BLOCK,
+    copy_config, DescriptorChain, DescriptorError, DeviceMetrics, Interrupt, Queue, Reader,
+    VirtioDevice, Writer, TYPE_BLOCK,
 };
 
 const QUEUE_SIZE: u16 = 256;
@@ -258,6 +259,7 @@ struct Worker {
     sparse: bool,
     id: Option<BlockId>,
     control_socket: Option<DiskControlResponseSocket>,
+    metrics: DeviceMetrics,
 }
 
 impl Worker {
@@ -271,6 +273,7 @@ impl Worker {
         flush_timer: &mut Timer,
         flush_timer_armed: &mut bool,
         mem: &GuestMemory,
+        metrics: &DeviceMetrics,
     ) -> result::Result<usize, ExecuteError> {
         let mut reader =
             Reader::new(mem.clone(), avail_desc.clone()).map_err(ExecuteError::Descriptor)?;
@@ -296,7 +299,14 @@ impl Worker {
             flush_timer,
             flush_timer_armed,
         ) {
-            Ok(()) => VIRTIO_BLK_S_OK,
+            Ok(()) => {
+                // Count the data moved in either direction, but not the request header.
+                let header_size = size_of::<virtio_blk_req_header>();
+                let data_bytes =
+                    reader.bytes_read().saturating_sub(header_size) + writer.bytes_written();
+                metrics.add_request(data_bytes as u64);
+                VIRTIO_BLK_S_OK
+            }
             Err(e) => {
                 error!("failed executing disk request: {}", e);
                 e.status()
@@ -309,6 +319,44 @@ impl Worker {
         Ok(available_bytes)
     }
 
+    // Fails a request without executing it by writing an I/O error status to it. Returns the number
+    // of bytes written.
+    fn fail_request(
+        avail_desc: DescriptorChain,
+        mem: &GuestMemory,
+    ) -> result::Result<usize, ExecuteError> {
+        let mut writer = Writer::new(mem.clone(), avail_desc).map_err(ExecuteError::Descriptor)?;
+        let status_offset = writer
+            .available_bytes()
+            .checked_sub(1)
+            .ok_or(ExecuteError::MissingStatus)?;
+        let mut status_writer = writer.split_at(status_offset);
+        status_writer
+            .write_all(&[VIRTIO_BLK_S_IOERR])
+            .map_err(ExecuteError::WriteStatus)?;
+        Ok(1)
+    }
+
+    // Fails every request still in the queues so the guest gets its buffers back once the worker
+    // stops.
+    fn drain_queues(&mut self) {
+        let mem = &self.mem;
+        for queue in self.queues.iter_mut() {
+            let failed = queue.drain(mem, |avail_desc| {
+                match Worker::fail_request(avail_desc, mem) {
+                    Ok(len) => len as u32,
+                    Err(e) => {
+                        error!("block: failed to return request: {}", e);
+                        0
+                    }
+                }
+            });
+            if failed > 0 {
+                queue.trigger_interrupt(mem, &self.interrupt);
+            }
+        }
+    }
+
     fn process_queue(
         &mut self,
         queue_index: usize,
@@ -333,6 +381,7 @@ impl Worker {
                 flush_timer,
                 flush_timer_armed,
                 &self.mem,
+                &self.metrics,
             ) {
                 Ok(len) => len,
                 Err(e) => {
@@ -375,13 +424,15 @@ impl Worker {
         DiskControlResult::Ok
     }
 
-    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
+    fn run(&mut self, queue_evt: Event, kill_evt: Event, suspend_evt: Event, sleep_evt: Event) {
         #[derive(PollToken)]
         enum Token {
             FlushTimer,
             QueueAvailable,
             ControlRequest,
             InterruptResample,
+            Suspend,
+            Sleep,
             Kill,
         }
 
@@ -389,6 +440,7 @@ impl Worker {
             Ok(t) => t,
             Err(e) => {
                 error!("Failed to create the flush timer: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -398,6 +450,8 @@ impl Worker {
             (&flush_timer, Token::FlushTimer),
             (&queue_evt, Token::QueueAvailable),
             (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&suspend_evt, Token::Suspend),
+            (&sleep_evt, Token::Sleep),
             (&kill_evt, Token::Kill),
         ])
         .and_then(|pc| {
@@ -409,10 +463,13 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
 
+        // Any other way out of the loop leaves the device unusable until the driver resets it.
+        let mut killed = false;
         'wait: loop {
             let events = match wait_ctx.wait() {
                 Ok(v) => v,
@@ -477,19 +534,55 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => break 'wait,
+                    // Flush right away rather than leaving the flush timer pending while the VM
+                    // is suspended, so the disk image is consistent for as long as it's frozen.
+                    Token::Suspend => {
+                        if let Err(e) = suspend_evt.read() {
+                            error!("failed reading suspend Event: {}", e);
+                            break 'wait;
+                        }
+                        if let Err(e) = self.disk_image.fsync() {
+                            error!("Failed to flush the disk: {}", e);
+                            break 'wait;
+                        }
+                        if let Err(e) = flush_timer.clear() {
+                            error!("Failed to clear flush timer: {}", e);
+                            break 'wait;
+                        }
+                        flush_timer_armed = false;
+                    }
+                    // Complete what the driver already queued and hand the queue back as it is,
+                    // to be picked up again when the device wakes.
+                    Token::Sleep => {
+                        self.process_queue(0, &mut flush_timer, &mut flush_timer_armed);
+                        if let Err(e) = self.disk_image.fsync() {
+                            error!("Failed to flush the disk: {}", e);
+                        }
+                        return;
+                    }
+                    Token::Kill => {
+                        killed = true;
+                        break 'wait;
+                    }
                 }
             }
             if needs_config_interrupt {
                 self.interrupt.signal_config_changed();
             }
         }
+
+        self.drain_queues();
+        if !killed {
+            self.interrupt.signal_needs_reset();
+        }
     }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     kill_evt: Option<Event>,
+    suspend_evt: Option<Event>,
+    sleep_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<Worker>>,
     disk_image: Option<Box<dyn DiskFile>>,
     disk_size: Arc<Mutex<u64>>,
@@ -500,6 +593,8 @@ pub struct Block {
     block_size: u32,
     id: Option<BlockId>,
     control_socket: Option<DiskControlResponseSocket>,
+    metrics: DeviceMetrics,
+    landlock_ruleset: Option<LandlockRuleset>,
 }
 
 fn build_config_space(disk_size: u64, seg_max: u32, block_size: u32) -> virtio_blk_config {
@@ -567,6 +662,8 @@ impl Block {
 
         Ok(Block {
             kill_evt: None,
+            suspend_evt: None,
+            sleep_evt: None,
             worker_thread: None,
             disk_image: Some(disk_image),
             disk_size: Arc::new(Mutex::new(disk_size)),
@@ -577,9 +674,17 @@ impl Block {
             block_size,
             id,
             control_socket,
+            metrics: DeviceMetrics::default(),
+            landlock_ruleset: None,
         })
     }
 
+    /// Restricts the files the device can open to those `ruleset` allows, once it runs in its
+    /// sandbox.
+    pub fn set_landlock_ruleset(&mut self, ruleset: LandlockRuleset) {
+        self.landlock_ruleset = Some(ruleset);
+    }
+
     // Execute a single block device request.
     // `writer` includes the data region only; the status byte is not included.
     // It is up to the caller to convert the result of this function into a status byte
@@ -599,6 +704,8 @@ impl Block {
 
         let req_type = req_header.req_type.to_native();
         let sector = req_header.sector.to_native();
+        let _trace =
+            cros_tracing::trace_event!(Block, "virtio_blk request {} sector {}", req_type, sector);
         // Delay after a write when the file is auto-flushed.
         let flush_delay = Duration::from_secs(60);
 
@@ -755,9 +862,24 @@ impl VirtioDevice for Block {
             keep_rds.push(control_socket.as_raw_descriptor());
         }
 
+        if let Some(ruleset) = &self.landlock_ruleset {
+            keep_rds.push(ruleset.as_raw_descriptor());
+        }
+
         keep_rds
     }
 
+    fn on_device_sandboxed(&mut self) {
+        if let Some(ruleset) = self.landlock_ruleset.take() {
+            if let Err(e) = ruleset.restrict_self() {
+                error!(
+                    "failed to restrict the files the block device can open: {}",
+                    e
+                );
+            }
+        }
+    }
+
     fn features(&self) -> u64 {
         self.avail_features
     }
@@ -778,6 +900,10 @@ impl VirtioDevice for Block {
         copy_config(data, 0, config_space.as_slice(), offset);
     }
 
+    fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -798,10 +924,30 @@ impl VirtioDevice for Block {
         };
         self.kill_evt = Some(self_kill_evt);
 
+        let (self_suspend_evt, suspend_evt) =
+            match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed creating suspend Event pair: {}", e);
+                    return;
+                }
+            };
+        self.suspend_evt = Some(self_suspend_evt);
+
+        let (self_sleep_evt, sleep_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating sleep Event pair: {}", e);
+                return;
+            }
+        };
+        self.sleep_evt = Some(self_sleep_evt);
+
         let read_only = self.read_only;
         let sparse = self.sparse;
         let disk_size = self.disk_size.clone();
         let id = self.id.take();
+        let metrics = self.metrics.clone();
         if let Some(disk_image) = self.disk_image.take() {
             let control_socket = self.control_socket.take();
             let worker_result =
@@ -818,8 +964,17 @@ impl VirtioDevice for Block {
                             sparse,
                             id,
                             control_socket,
+                            metrics,
                         };
-                        worker.run(queue_evts.remove(0), kill_evt);
+                        // Keep the worker, and with it the disk, if it panics so the device can be
+                        // activated again once the driver resets it.
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            worker.run(queue_evts.remove(0), kill_evt, suspend_evt, sleep_evt)
+                        }));
+                        if result.is_err() {
+                            error!("virtio_blk worker panicked");
+                            worker.interrupt.signal_needs_reset();
+                        }
                         worker
                     });
 
@@ -835,7 +990,21 @@ impl VirtioDevice for Block {
         }
     }
 
+    fn on_device_suspend(&mut self) {
+        if let Some(suspend_evt) = &self.suspend_evt {
+            if let Err(e) = suspend_evt.write(1) {
+                error!(
+                    "{}: failed to notify the suspend event: {}",
+                    self.debug_label(),
+                    e
+                );
+            }
+        }
+    }
+
     fn reset(&mut self) -> bool {
+        self.suspend_evt = None;
+        self.sleep_evt = None;
         if let Some(kill_evt) = self.kill_evt.take() {
             if kill_evt.write(1).is_err() {
                 error!("{}: failed to notify the kill event", self.debug_label());
@@ -852,12 +1021,50 @@ impl VirtioDevice for Block {
                 Ok(worker) => {
                     self.disk_image = Some(worker.disk_image);
                     self.control_socket = worker.control_socket;
+                    self.id = worker.id;
                     return true;
                 }
             }
         }
         false
     }
+
+    fn virtio_sleep(&mut self) -> Option<Vec<Queue>> {
+        self.kill_evt = None;
+        self.suspend_evt = None;
+        if let Some(sleep_evt) = self.sleep_evt.take() {
+            if let Err(e) = sleep_evt.write(1) {
+                error!(
+                    "{}: failed to notify the sleep event: {}",
+                    self.debug_label(),
+                    e
+                );
+                return None;
+            }
+        }
+
+        match self.worker_thread.take()?.join() {
+            Err(_) => {
+                error!("{}: failed to get back resources", self.debug_label());
+                None
+            }
+            Ok(worker) => {
+                self.disk_image = Some(worker.disk_image);
+                self.control_socket = worker.control_socket;
+                self.id = worker.id;
+                Some(worker.queues)
+            }
+        }
+    }
+
+    // The disk itself isn't part of the snapshot, and everything else comes from the command line.
+    fn virtio_snapshot(&self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn virtio_restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -982,6 +1189,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            &DeviceMetrics::default(),
         )
         .expect("execute failed");
 
@@ -990,6 +1198,43 @@ mod tests {
         assert_eq!(status, VIRTIO_BLK_S_OK);
     }
 
+    #[test]
+    fn fail_unprocessed_request() {
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_IN),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            vec![
+                // Request header
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                // I/O buffer (1 sector of data)
+                (DescriptorType::Writable, 512),
+                // Request status
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        let len = Worker::fail_request(avail_desc, &mem).expect("fail_request failed");
+        assert_eq!(len, 1);
+
+        let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512) as u64);
+        let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
     #[test]
     fn read_beyond_last_sector() {
         let mut f = tempfile().unwrap();
@@ -1036,6 +1281,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            &DeviceMetrics::default(),
         )
         .expect("execute failed");
 
@@ -1092,6 +1338,7 @@ mod tests {
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
+            &DeviceMetrics::default(),
         )
         .expect("execute failed");
 
@@ -2,12 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::io::{self, Read, Write};
+use std::io::{self, stdin, Read, Write};
 use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
 use std::thread;
 
-use base::{error, Event, PollToken, RawDescriptor, WaitContext};
+use base::{block_signal, error, Event, PollToken, RawDescriptor, SignalFd, Terminal, WaitContext};
 use data_model::{DataInit, Le16, Le32};
+use libc::SIGWINCH;
+use sync::Mutex;
 use vm_memory::GuestMemory;
 
 use super::{
@@ -21,6 +24,9 @@ const QUEUE_SIZE: u16 = 256;
 // If VIRTIO_CONSOLE_F_MULTIPORT is implemented, more queues will be needed.
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
 
+// The config space holds the size of the console.
+const VIRTIO_CONSOLE_F_SIZE: u32 = 0;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtio_console_config {
@@ -33,11 +39,15 @@ struct virtio_console_config {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_console_config {}
 
+// The size of the host terminal as `(columns, rows)`.
+type WindowSize = Arc<Mutex<(u16, u16)>>;
+
 struct Worker {
     mem: GuestMemory,
     interrupt: Interrupt,
     input: Option<Box<dyn io::Read + Send>>,
     output: Option<Box<dyn io::Write + Send>>,
+    window_size: Option<WindowSize>,
 }
 
 fn write_output(output: &mut Box<dyn io::Write>, data: &[u8]) -> io::Result<()> {
@@ -204,6 +214,26 @@ impl Worker {
         }
     }
 
+    // Reads the size of the host terminal and tells the guest if it changed.
+    fn update_window_size(&self) {
+        let window_size = match self.window_size.as_ref() {
+            Some(w) => w,
+            None => return,
+        };
+        let size = match stdin().window_size() {
+            Ok(size) => size,
+            Err(e) => {
+                error!("console: failed to get the terminal size: {}", e);
+                return;
+            }
+        };
+        let mut current = window_size.lock();
+        if *current != size {
+            *current = size;
+            self.interrupt.signal_config_changed();
+        }
+    }
+
     fn run(&mut self, mut queues: Vec<Queue>, mut queue_evts: Vec<Event>, kill_evt: Event) {
         #[derive(PollToken)]
         enum Token {
@@ -211,6 +241,7 @@ impl Worker {
             TransmitQueueAvailable,
             InputAvailable,
             InterruptResample,
+            WindowResize,
             Kill,
         }
 
@@ -249,6 +280,28 @@ impl Worker {
             }
         };
 
+        // SIGWINCH was blocked before the device was sandboxed so that it stays pending until it
+        // is read from here.
+        let winch_fd = if self.window_size.is_some() {
+            match SignalFd::new(SIGWINCH) {
+                Ok(fd) => {
+                    if let Err(e) = wait_ctx.add(&fd, Token::WindowResize) {
+                        error!("failed adding SIGWINCH to WaitContext: {}", e);
+                        return;
+                    }
+                    Some(fd)
+                }
+                Err(e) => {
+                    error!("failed creating SIGWINCH SignalFd: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // The terminal may have been resized since the guest last read the config space.
+        self.update_window_size();
+
         let mut output: Box<dyn io::Write> = match self.output.take() {
             Some(o) => o,
             None => Box::new(io::sink()),
@@ -289,6 +342,22 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
+                    Token::WindowResize => {
+                        if let Some(fd) = winch_fd.as_ref() {
+                            // Several resizes may be coalesced, only the latest size matters.
+                            loop {
+                                match fd.read() {
+                                    Ok(Some(_)) => continue,
+                                    Ok(None) => break,
+                                    Err(e) => {
+                                        error!("failed reading SIGWINCH SignalFd: {}", e);
+                                        break 'wait;
+                                    }
+                                }
+                            }
+                        }
+                        self.update_window_size();
+                    }
                     Token::Kill => break 'wait,
                 }
             }
@@ -304,6 +373,26 @@ pub struct Console {
     input: Option<Box<dyn io::Read + Send>>,
     output: Option<Box<dyn io::Write + Send>>,
     keep_rds: Vec<RawDescriptor>,
+    window_size: Option<WindowSize>,
+}
+
+impl Console {
+    /// Makes the console follow the size of the host terminal on stdin, if stdin is a terminal.
+    ///
+    /// This blocks SIGWINCH for the calling thread and any thread or process it later creates, so
+    /// it must be called before the device is sandboxed.
+    pub fn track_stdin_window_size(&mut self) {
+        // Not being a terminal is the common case for non-interactive VMs.
+        let size = match stdin().window_size() {
+            Ok(size) => size,
+            Err(_) => return,
+        };
+        if let Err(e) = block_signal(SIGWINCH) {
+            error!("failed to block SIGWINCH: {}", e);
+            return;
+        }
+        self.window_size = Some(Arc::new(Mutex::new(size)));
+    }
 }
 
 impl SerialDevice for Console {
@@ -321,6 +410,7 @@ impl SerialDevice for Console {
             input,
             output,
             keep_rds,
+            window_size: None,
         }
     }
 }
@@ -344,7 +434,11 @@ impl VirtioDevice for Console {
     }
 
     fn features(&self) -> u64 {
-        self.base_features
+        if self.window_size.is_some() {
+            self.base_features | 1 << VIRTIO_CONSOLE_F_SIZE
+        } else {
+            self.base_features
+        }
     }
 
     fn device_type(&self) -> u32 {
@@ -356,7 +450,10 @@ impl VirtioDevice for Console {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let (cols, rows) = self.window_size.as_ref().map_or((0, 0), |w| *w.lock());
         let config = virtio_console_config {
+            cols: cols.into(),
+            rows: rows.into(),
             max_nr_ports: 1.into(),
             ..Default::default()
         };
@@ -385,6 +482,7 @@ impl VirtioDevice for Console {
 
         let input = self.input.take();
         let output = self.output.take();
+        let window_size = self.window_size.clone();
 
         let worker_result = thread::Builder::new()
             .name("virtio_console".to_string())
@@ -394,6 +492,7 @@ impl VirtioDevice for Console {
                     interrupt,
                     input,
                     output,
+                    window_size,
                 };
                 worker.run(queues, queue_evts, kill_evt);
                 worker
@@ -8,7 +8,9 @@ use std::mem;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use base::{error, warn, AsRawDescriptor, Error as SysError, Event, RawDescriptor};
+use base::{
+    error, warn, AsRawDescriptor, Error as SysError, Event, LandlockRuleset, RawDescriptor,
+};
 use data_model::{DataInit, Le32};
 use msg_socket::{MsgReceiver, MsgSender};
 use resources::Alloc;
@@ -124,6 +126,7 @@ pub struct Fs {
     pci_bar: Option<Alloc>,
     socket: Option<FsMappingRequestSocket>,
     workers: Vec<(Event, thread::JoinHandle<Result<()>>)>,
+    landlock_ruleset: Option<LandlockRuleset>,
 }
 
 impl Fs {
@@ -160,9 +163,16 @@ impl Fs {
             pci_bar: None,
             socket: Some(socket),
             workers: Vec::with_capacity(num_workers + 1),
+            landlock_ruleset: None,
         })
     }
 
+    /// Restricts the files the device can open to those `ruleset` allows, once it runs in its
+    /// sandbox.
+    pub fn set_landlock_ruleset(&mut self, ruleset: LandlockRuleset) {
+        self.landlock_ruleset = Some(ruleset);
+    }
+
     fn stop_workers(&mut self) {
         for (kill_evt, handle) in mem::replace(&mut self.workers, Vec::new()) {
             if let Err(e) = kill_evt.write(1) {
@@ -193,10 +203,24 @@ impl VirtioDevice for Fs {
         if let Some(rd) = self.socket.as_ref().map(|s| s.as_raw_descriptor()) {
             fds.push(rd);
         }
+        if let Some(ruleset) = &self.landlock_ruleset {
+            fds.push(ruleset.as_raw_descriptor());
+        }
 
         fds
     }
 
+    fn on_device_sandboxed(&mut self) {
+        if let Some(ruleset) = self.landlock_ruleset.take() {
+            if let Err(e) = ruleset.restrict_self() {
+                error!(
+                    "failed to restrict the files the virtio-fs device can open: {}",
+                    e
+                );
+            }
+        }
+    }
+
     fn device_type(&self) -> u32 {
         TYPE_FS
     }
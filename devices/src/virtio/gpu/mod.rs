@@ -14,6 +14,7 @@ use std::mem::{self, size_of};
 use std::num::NonZeroU8;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -42,20 +43,23 @@ use sync::Mutex;
 use vm_memory::{GuestAddress, GuestMemory};
 
 use super::{
-    copy_config, resource_bridge::*, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice,
-    Writer, TYPE_GPU,
+    copy_config, resource_bridge::*, DescriptorChain, DeviceMetrics, Interrupt, Queue, Reader,
+    VirtioDevice, Writer, TYPE_GPU,
 };
 
 use super::{PciCapabilityType, VirtioPciShmCap};
 
 use self::protocol::*;
-use self::virtio_gpu::VirtioGpu;
+use self::virtio_gpu::{BlobMapper, VirtioGpu};
 
 use crate::pci::{
     PciAddress, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability,
 };
 
-use vm_control::VmMemoryControlRequestSocket;
+use vm_control::{
+    GpuControlCommand, GpuControlResponseSocket, GpuControlResult, GpuDisplayInfo,
+    VmMemoryControlRequestSocket,
+};
 
 pub const DEFAULT_DISPLAY_WIDTH: u32 = 1280;
 pub const DEFAULT_DISPLAY_HEIGHT: u32 = 1024;
@@ -127,9 +131,7 @@ fn build(
     display_height: u32,
     rutabaga_builder: RutabagaBuilder,
     event_devices: Vec<EventDevice>,
-    gpu_device_socket: VmMemoryControlRequestSocket,
-    pci_bar: Alloc,
-    map_request: Arc<Mutex<Option<ExternalMapping>>>,
+    blob_mapper: BlobMapper,
     external_blob: bool,
 ) -> Option<VirtioGpu> {
     let mut display_opt = None;
@@ -157,9 +159,7 @@ fn build(
         display_height,
         rutabaga_builder,
         event_devices,
-        gpu_device_socket,
-        pci_bar,
-        map_request,
+        blob_mapper,
         external_blob,
     )
 }
@@ -208,15 +208,17 @@ struct Frontend {
     return_cursor_descriptors: VecDeque<ReturnDescriptor>,
     fence_descriptors: Vec<FenceDescriptor>,
     virtio_gpu: VirtioGpu,
+    metrics: DeviceMetrics,
 }
 
 impl Frontend {
-    fn new(virtio_gpu: VirtioGpu) -> Frontend {
+    fn new(virtio_gpu: VirtioGpu, metrics: DeviceMetrics) -> Frontend {
         Frontend {
             return_ctrl_descriptors: Default::default(),
             return_cursor_descriptors: Default::default(),
             fence_descriptors: Default::default(),
             virtio_gpu,
+            metrics,
         }
     }
 
@@ -256,6 +258,7 @@ impl Frontend {
         cmd: GpuCommand,
         reader: &mut Reader,
     ) -> VirtioGpuResult {
+        let _trace = cros_tracing::trace_event!(Gpu, "virtio_gpu {:?}", cmd);
         self.virtio_gpu.force_ctx_0();
 
         match cmd {
@@ -530,9 +533,11 @@ impl Frontend {
                     Writer::new(mem.clone(), desc.clone()),
                 ) {
                     (Ok(mut reader), Ok(mut writer)) => {
-                        if let Some(ret_desc) =
-                            self.process_descriptor(mem, desc.index, &mut reader, &mut writer)
-                        {
+                        let ret_desc =
+                            self.process_descriptor(mem, desc.index, &mut reader, &mut writer);
+                        self.metrics
+                            .add_request((reader.bytes_read() + writer.bytes_written()) as u64);
+                        if let Some(ret_desc) = ret_desc {
                             queue.add_used(&mem, ret_desc.index, ret_desc.len);
                             signal_used = true;
                         }
@@ -589,6 +594,7 @@ impl Frontend {
         };
 
         if writer.available_bytes() != 0 {
+            let mut fenced = false;
             let mut fence_id = 0;
             let mut ctx_id = 0;
             let mut flags = 0;
@@ -609,7 +615,10 @@ impl Frontend {
                         fence_ctx_idx: info,
                     };
                     gpu_response = match self.virtio_gpu.create_fence(fence_data) {
-                        Ok(_) => gpu_response,
+                        Ok(_) => {
+                            fenced = true;
+                            gpu_response
+                        }
                         Err(fence_resp) => {
                             warn!("create_fence {} -> {:?}", fence_id, fence_resp);
                             fence_resp
@@ -625,7 +634,9 @@ impl Frontend {
                 Err(e) => debug!("ctrl queue response encode error: {}", e),
             }
 
-            if flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+            // A fence that couldn't be created, e.g. on a context lost to a renderer reload, never
+            // completes, so don't hold the response back for it.
+            if fenced {
                 self.fence_descriptors.push(FenceDescriptor {
                     desc_fence: RutabagaFenceData {
                         flags,
@@ -656,6 +667,52 @@ impl Frontend {
         self.return_ctrl_descriptors.pop_front()
     }
 
+    fn reload_renderer(&mut self, mem: &GuestMemory) {
+        if let Err(e) = self.virtio_gpu.reload_renderer(mem) {
+            error!("failed to reload the renderer: {}", e);
+        }
+
+        // The new renderer knows nothing about the fences of the old one, so complete them now
+        // rather than leaving the guest waiting on them forever.
+        for f_desc in self.fence_descriptors.drain(..) {
+            self.return_ctrl_descriptors.push_back(ReturnDescriptor {
+                index: f_desc.index,
+                len: f_desc.len,
+            });
+        }
+    }
+
+    fn list_displays(&self) -> GpuControlResult {
+        let displays = self
+            .virtio_gpu
+            .display_info()
+            .iter()
+            .enumerate()
+            .map(|(display, &(width, height))| GpuDisplayInfo {
+                display: display as u32,
+                width,
+                height,
+            })
+            .collect();
+        GpuControlResult::Displays(displays)
+    }
+
+    fn set_display_resolution(
+        &mut self,
+        display: u32,
+        width: u32,
+        height: u32,
+    ) -> GpuControlResult {
+        if display as usize >= self.virtio_gpu.display_info().len() {
+            return GpuControlResult::NoSuchDisplay(display);
+        }
+        if width == 0 || height == 0 {
+            return GpuControlResult::InvalidResolution { width, height };
+        }
+        self.virtio_gpu.set_display_size(width, height);
+        GpuControlResult::Ok
+    }
+
     fn fence_poll(&mut self) {
         let completed_fences = self.virtio_gpu.fence_poll();
         let return_descs = &mut self.return_ctrl_descriptors;
@@ -677,6 +734,14 @@ impl Frontend {
     }
 }
 
+// What a worker hands back once it stops, for the device to be activated again.
+struct WorkerResources {
+    resource_bridges: Vec<ResourceResponseSocket>,
+    gpu_control_socket: Option<GpuControlResponseSocket>,
+    // None if the worker failed to start.
+    blob_mapper: Option<BlobMapper>,
+}
+
 struct Worker {
     interrupt: Interrupt,
     exit_evt: Event,
@@ -686,6 +751,8 @@ struct Worker {
     cursor_queue: Queue,
     cursor_evt: Event,
     resource_bridges: Vec<ResourceResponseSocket>,
+    gpu_control_socket: Option<GpuControlResponseSocket>,
+    config_event: Arc<AtomicBool>,
     kill_evt: Event,
     state: Frontend,
 }
@@ -697,6 +764,7 @@ impl Worker {
             CtrlQueue,
             CursorQueue,
             Display,
+            GpuControl,
             InterruptResample,
             Kill,
             ResourceBridge { index: usize },
@@ -722,6 +790,12 @@ impl Worker {
             }
         }
 
+        if let Some(gpu_control_socket) = &self.gpu_control_socket {
+            if let Err(e) = wait_ctx.add(gpu_control_socket, Token::GpuControl) {
+                error!("failed to add gpu control socket to WaitContext: {}", e);
+            }
+        }
+
         // TODO(davidriley): The entire main loop processing is somewhat racey and incorrect with
         // respect to cursor vs control queue processing.  As both currently and originally
         // written, while the control queue is only processed/read from after the the cursor queue
@@ -787,6 +861,48 @@ impl Worker {
                     Token::ResourceBridge { index } => {
                         process_resource_bridge[index] = true;
                     }
+                    Token::GpuControl => {
+                        let command = match self.gpu_control_socket.as_ref().map(|s| s.recv()) {
+                            Some(Ok(command)) => command,
+                            Some(Err(e)) => {
+                                error!("failed to receive gpu control command: {}", e);
+                                if let Some(socket) = self.gpu_control_socket.take() {
+                                    let _ = wait_ctx.delete(&socket);
+                                }
+                                continue;
+                            }
+                            None => continue,
+                        };
+                        let result = match command {
+                            GpuControlCommand::ReloadRenderer => {
+                                // The guest learns that its contexts are gone from the errors
+                                // its next commands on them get.
+                                self.state.reload_renderer(&self.mem);
+                                GpuControlResult::Ok
+                            }
+                            GpuControlCommand::SetDisplayResolution {
+                                display,
+                                width,
+                                height,
+                            } => {
+                                let result =
+                                    self.state.set_display_resolution(display, width, height);
+                                if let GpuControlResult::Ok = result {
+                                    // The guest reads the new size with GET_DISPLAY_INFO and sets
+                                    // the scanout again.
+                                    self.config_event.store(true, Ordering::SeqCst);
+                                    self.interrupt.signal_config_changed();
+                                }
+                                result
+                            }
+                            GpuControlCommand::ListDisplays => self.state.list_displays(),
+                        };
+                        if let Some(socket) = &self.gpu_control_socket {
+                            if let Err(e) = socket.send(&result) {
+                                error!("failed to send gpu control result: {}", e);
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -865,27 +981,32 @@ impl DisplayBackend {
 pub struct Gpu {
     exit_evt: Event,
     gpu_device_socket: Option<VmMemoryControlRequestSocket>,
+    gpu_control_socket: Option<GpuControlResponseSocket>,
     resource_bridges: Vec<ResourceResponseSocket>,
     event_devices: Vec<EventDevice>,
     kill_evt: Option<Event>,
-    config_event: bool,
-    worker_thread: Option<thread::JoinHandle<()>>,
+    config_event: Arc<AtomicBool>,
+    worker_thread: Option<thread::JoinHandle<WorkerResources>>,
+    // Whether the event devices went to a worker, which keeps them when it stops.
+    event_devices_taken: bool,
     num_scanouts: NonZeroU8,
     display_backends: Vec<DisplayBackend>,
     display_width: u32,
     display_height: u32,
-    rutabaga_builder: Option<RutabagaBuilder>,
+    rutabaga_builder: RutabagaBuilder,
     pci_bar: Option<Alloc>,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
     external_blob: bool,
     rutabaga_component: RutabagaComponentType,
     base_features: u64,
+    metrics: DeviceMetrics,
 }
 
 impl Gpu {
     pub fn new(
         exit_evt: Event,
         gpu_device_socket: Option<VmMemoryControlRequestSocket>,
+        gpu_control_socket: Option<GpuControlResponseSocket>,
         num_scanouts: NonZeroU8,
         resource_bridges: Vec<ResourceResponseSocket>,
         display_backends: Vec<DisplayBackend>,
@@ -943,27 +1064,30 @@ impl Gpu {
         Gpu {
             exit_evt,
             gpu_device_socket,
+            gpu_control_socket,
             num_scanouts,
             resource_bridges,
             event_devices,
-            config_event: false,
+            config_event: Arc::new(AtomicBool::new(false)),
             kill_evt: None,
             worker_thread: None,
+            event_devices_taken: false,
             display_backends,
             display_width: gpu_parameters.display_width,
             display_height: gpu_parameters.display_height,
-            rutabaga_builder: Some(rutabaga_builder),
+            rutabaga_builder,
             pci_bar: None,
             map_request,
             external_blob,
             rutabaga_component: component,
             base_features,
+            metrics: DeviceMetrics::default(),
         }
     }
 
     fn get_config(&self) -> virtio_gpu_config {
         let mut events_read = 0;
-        if self.config_event {
+        if self.config_event.load(Ordering::SeqCst) {
             events_read |= VIRTIO_GPU_EVENT_DISPLAY;
         }
 
@@ -1025,6 +1149,10 @@ impl VirtioDevice for Gpu {
             keep_rds.push(gpu_device_socket.as_raw_descriptor());
         }
 
+        if let Some(ref gpu_control_socket) = self.gpu_control_socket {
+            keep_rds.push(gpu_control_socket.as_raw_descriptor());
+        }
+
         keep_rds.push(self.exit_evt.as_raw_descriptor());
         for bridge in &self.resource_bridges {
             keep_rds.push(bridge.as_raw_descriptor());
@@ -1066,10 +1194,14 @@ impl VirtioDevice for Gpu {
         let mut cfg = self.get_config();
         copy_config(cfg.as_mut_slice(), offset, data, 0);
         if (cfg.events_clear.to_native() & VIRTIO_GPU_EVENT_DISPLAY) != 0 {
-            self.config_event = false;
+            self.config_event.store(false, Ordering::SeqCst);
         }
     }
 
+    fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -1098,7 +1230,27 @@ impl VirtioDevice for Gpu {
         };
         self.kill_evt = Some(self_kill_evt);
 
+        let blob_mapper = match (
+            self.gpu_device_socket.take(),
+            self.pci_bar.take(),
+            interrupt.backend_requester(),
+        ) {
+            (Some(socket), Some(pci_bar), _) => BlobMapper::PciBar {
+                socket,
+                pci_bar,
+                map_request: Arc::clone(&self.map_request),
+            },
+            // Served to a vhost-user frontend, which maps blobs into its shared memory region.
+            (None, None, Some(backend_req)) => BlobMapper::VhostUser(backend_req),
+            _ => {
+                error!("virtio_gpu has nowhere to map blob resources");
+                return;
+            }
+        };
+
         let resource_bridges = mem::replace(&mut self.resource_bridges, Vec::new());
+        let gpu_control_socket = self.gpu_control_socket.take();
+        let config_event = Arc::clone(&self.config_event);
 
         let ctrl_queue = queues.remove(0);
         let ctrl_evt = queue_evts.remove(0);
@@ -1108,56 +1260,98 @@ impl VirtioDevice for Gpu {
         let display_width = self.display_width;
         let display_height = self.display_height;
         let event_devices = self.event_devices.split_off(0);
-        let map_request = Arc::clone(&self.map_request);
+        self.event_devices_taken |= !event_devices.is_empty();
         let external_blob = self.external_blob;
-        if let (Some(gpu_device_socket), Some(pci_bar), Some(rutabaga_builder)) = (
-            self.gpu_device_socket.take(),
-            self.pci_bar.take(),
-            self.rutabaga_builder.take(),
-        ) {
-            let worker_result =
-                thread::Builder::new()
-                    .name("virtio_gpu".to_string())
-                    .spawn(move || {
-                        let virtio_gpu = match build(
-                            &display_backends,
-                            display_width,
-                            display_height,
-                            rutabaga_builder,
-                            event_devices,
-                            gpu_device_socket,
-                            pci_bar,
-                            map_request,
-                            external_blob,
-                        ) {
-                            Some(backend) => backend,
-                            None => return,
-                        };
-
-                        Worker {
-                            interrupt,
-                            exit_evt,
-                            mem,
-                            ctrl_queue,
-                            ctrl_evt,
-                            cursor_queue,
-                            cursor_evt,
-                            resource_bridges,
-                            kill_evt,
-                            state: Frontend::new(virtio_gpu),
+        let metrics = self.metrics.clone();
+        let rutabaga_builder = self.rutabaga_builder.clone();
+        let worker_result =
+            thread::Builder::new()
+                .name("virtio_gpu".to_string())
+                .spawn(move || {
+                    let virtio_gpu = match build(
+                        &display_backends,
+                        display_width,
+                        display_height,
+                        rutabaga_builder,
+                        event_devices,
+                        blob_mapper,
+                        external_blob,
+                    ) {
+                        Some(backend) => backend,
+                        None => {
+                            return WorkerResources {
+                                resource_bridges,
+                                gpu_control_socket,
+                                blob_mapper: None,
+                            }
                         }
-                        .run()
-                    });
+                    };
 
-            match worker_result {
-                Err(e) => {
-                    error!("failed to spawn virtio_gpu worker: {}", e);
-                    return;
-                }
-                Ok(join_handle) => {
-                    self.worker_thread = Some(join_handle);
-                }
+                    let mut worker = Worker {
+                        interrupt,
+                        exit_evt,
+                        mem,
+                        ctrl_queue,
+                        ctrl_evt,
+                        cursor_queue,
+                        cursor_evt,
+                        resource_bridges,
+                        gpu_control_socket,
+                        config_event,
+                        kill_evt,
+                        state: Frontend::new(virtio_gpu, metrics),
+                    };
+                    worker.run();
+                    WorkerResources {
+                        resource_bridges: worker.resource_bridges,
+                        gpu_control_socket: worker.gpu_control_socket,
+                        blob_mapper: Some(worker.state.virtio_gpu.into_blob_mapper()),
+                    }
+                });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_gpu worker: {}", e);
+                return;
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        // The event devices can't be handed to another worker.
+        if self.event_devices_taken {
+            return false;
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+        let resources = match self.worker_thread.take().map(thread::JoinHandle::join) {
+            Some(Ok(resources)) => resources,
+            Some(Err(_)) => {
+                error!("{}: failed to get back resources", self.debug_label());
+                return false;
+            }
+            None => return true,
+        };
+        self.resource_bridges = resources.resource_bridges;
+        self.gpu_control_socket = resources.gpu_control_socket;
+        match resources.blob_mapper {
+            Some(BlobMapper::PciBar {
+                socket, pci_bar, ..
+            }) => {
+                self.gpu_device_socket = Some(socket);
+                self.pci_bar = Some(pci_bar);
+                true
             }
+            // The frontend hands the backend request channel to every activation.
+            Some(BlobMapper::VhostUser(_)) => true,
+            None => false,
         }
     }
 
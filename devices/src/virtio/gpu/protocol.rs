@@ -21,6 +21,7 @@ use data_model::{DataInit, Le32, Le64};
 use gpu_display::GpuDisplayError;
 use msg_socket::MsgError;
 use rutabaga_gfx::RutabagaError;
+use vhost::Error as VhostError;
 
 pub const VIRTIO_GPU_F_VIRGL: u32 = 0;
 pub const VIRTIO_GPU_F_EDID: u32 = 1;
@@ -805,6 +806,7 @@ pub enum GpuResponse {
     ErrRutabaga(RutabagaError),
     ErrDisplay(GpuDisplayError),
     ErrMapping(ExternalMappingError),
+    ErrVhostUser(VhostError),
     ErrScanout {
         num_scanouts: u32,
     },
@@ -839,6 +841,12 @@ impl From<ExternalMappingError> for GpuResponse {
     }
 }
 
+impl From<VhostError> for GpuResponse {
+    fn from(e: VhostError) -> GpuResponse {
+        GpuResponse::ErrVhostUser(e)
+    }
+}
+
 impl Display for GpuResponse {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::GpuResponse::*;
@@ -847,6 +855,7 @@ impl Display for GpuResponse {
             ErrSys(e) => write!(f, "system error: {}", e),
             ErrRutabaga(e) => write!(f, "renderer error: {}", e),
             ErrDisplay(e) => write!(f, "display error: {}", e),
+            ErrVhostUser(e) => write!(f, "vhost-user error: {}", e),
             ErrScanout { num_scanouts } => write!(f, "non-zero scanout: {}", num_scanouts),
             _ => Ok(()),
         }
@@ -1024,6 +1033,7 @@ impl GpuResponse {
             GpuResponse::ErrRutabaga(_) => VIRTIO_GPU_RESP_ERR_UNSPEC,
             GpuResponse::ErrDisplay(_) => VIRTIO_GPU_RESP_ERR_UNSPEC,
             GpuResponse::ErrMapping(_) => VIRTIO_GPU_RESP_ERR_UNSPEC,
+            GpuResponse::ErrVhostUser(_) => VIRTIO_GPU_RESP_ERR_UNSPEC,
             GpuResponse::ErrScanout { num_scanouts: _ } => VIRTIO_GPU_RESP_ERR_UNSPEC,
             GpuResponse::ErrOutOfMemory => VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
             GpuResponse::ErrInvalidScanoutId => VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID,
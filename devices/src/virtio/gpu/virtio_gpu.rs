@@ -4,20 +4,22 @@
 
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
+use std::collections::BTreeSet as Set;
 use std::num::NonZeroU32;
 use std::rc::Rc;
 use std::result::Result;
 use std::sync::Arc;
 
 use crate::virtio::resource_bridge::{BufferInfo, PlaneInfo, ResourceInfo, ResourceResponse};
-use base::{error, AsRawDescriptor, ExternalMapping};
+use base::{error, info, AsRawDescriptor, ExternalMapping};
 
 use data_model::VolatileSlice;
 
 use gpu_display::*;
 use rutabaga_gfx::{
-    ResourceCreate3D, ResourceCreateBlob, Rutabaga, RutabagaBuilder, RutabagaFenceData,
-    RutabagaIovec, Transfer3D,
+    ResourceCreate3D, ResourceCreateBlob, Rutabaga, RutabagaBuilder, RutabagaComponentType,
+    RutabagaError, RutabagaFenceData, RutabagaIovec, RutabagaResult, Transfer3D,
+    RUTABAGA_PIPE_TEXTURE_2D,
 };
 
 use msg_socket::{MsgReceiver, MsgSender};
@@ -30,34 +32,81 @@ use super::protocol::{GpuResponse::*, GpuResponsePlaneInfo, VirtioGpuResult};
 use super::VirtioScanoutBlobData;
 use sync::Mutex;
 
+use vhost::user::{
+    BackendRequester, ShmMapping, VHOST_USER_FS_FLAG_MAP_R, VHOST_USER_FS_FLAG_MAP_W,
+};
 use vm_memory::{GuestAddress, GuestMemory};
 
 use vm_control::{
     MaybeOwnedDescriptor, MemSlot, VmMemoryControlRequestSocket, VmMemoryRequest, VmMemoryResponse,
 };
 
+/// The parameters a resource was created with, kept so the resource can be recreated when the
+/// renderer is reloaded.
+#[derive(Copy, Clone)]
+enum ResourceCreateInfo {
+    Create3D(ResourceCreate3D),
+    Blob {
+        ctx_id: u32,
+        resource_create_blob: ResourceCreateBlob,
+    },
+}
+
+/// Where blob resources are mapped for the guest to access.
+pub enum BlobMapper {
+    /// Into the PCI BAR of the device, by the main process at the other end of `socket`.
+    PciBar {
+        socket: VmMemoryControlRequestSocket,
+        pci_bar: Alloc,
+        map_request: Arc<Mutex<Option<ExternalMapping>>>,
+    },
+    /// Into the shared memory region of the device, by the vhost-user frontend the device is
+    /// served to, once the frontend set up the channel for backend requests. Only blobs that can
+    /// be exported as a file are mapped, since the frontend is another process.
+    VhostUser(Arc<Mutex<Option<BackendRequester>>>),
+}
+
+/// Where a blob resource is mapped.
+#[derive(Copy, Clone)]
+enum BlobMapping {
+    /// A memory slot of the hypervisor.
+    Slot(MemSlot),
+    /// The range of the shared memory region starting at this offset.
+    Shm(u64),
+}
+
 struct VirtioGpuResource {
     resource_id: u32,
     width: u32,
     height: u32,
     size: u64,
-    slot: Option<MemSlot>,
+    mapping: Option<BlobMapping>,
     scanout_data: Option<VirtioScanoutBlobData>,
     display_import: Option<(Rc<RefCell<GpuDisplay>>, u32)>,
+    create_info: ResourceCreateInfo,
+    backing: Option<Vec<(GuestAddress, usize)>>,
 }
 
 impl VirtioGpuResource {
     /// Creates a new VirtioGpuResource with the given metadata.  Width and height are used by the
     /// display, while size is useful for hypervisor mapping.
-    pub fn new(resource_id: u32, width: u32, height: u32, size: u64) -> VirtioGpuResource {
+    fn new(
+        resource_id: u32,
+        width: u32,
+        height: u32,
+        size: u64,
+        create_info: ResourceCreateInfo,
+    ) -> VirtioGpuResource {
         VirtioGpuResource {
             resource_id,
             width,
             height,
             size,
-            slot: None,
+            mapping: None,
             scanout_data: None,
             display_import: None,
+            create_info,
+            backing: None,
         }
     }
 
@@ -78,11 +127,14 @@ pub struct VirtioGpu {
     cursor_surface_id: Option<u32>,
     // Maps event devices to scanout number.
     event_devices: Map<u32, u32>,
-    gpu_device_socket: VmMemoryControlRequestSocket,
-    pci_bar: Alloc,
-    map_request: Arc<Mutex<Option<ExternalMapping>>>,
+    blob_mapper: BlobMapper,
     rutabaga: Rutabaga,
+    rutabaga_builder: RutabagaBuilder,
     resources: Map<u32, VirtioGpuResource>,
+    contexts: Set<u32>,
+    // Contexts the guest created before the renderer was reloaded. Commands on them fail until the
+    // guest destroys them.
+    lost_contexts: Set<u32>,
     external_blob: bool,
 }
 
@@ -108,6 +160,51 @@ fn sglist_to_rutabaga_iovecs(
     Ok(rutabaga_iovecs)
 }
 
+// Recreates `resource` in a freshly built `rutabaga`, restoring its contents from guest memory when
+// it has any.
+fn replay_resource(
+    rutabaga: &mut Rutabaga,
+    resource: &VirtioGpuResource,
+    mem: &GuestMemory,
+) -> RutabagaResult<()> {
+    let iovecs = match &resource.backing {
+        Some(vecs) => {
+            sglist_to_rutabaga_iovecs(&vecs[..], mem).map_err(|_| RutabagaError::InvalidIovec)?
+        }
+        None => Vec::new(),
+    };
+
+    match resource.create_info {
+        ResourceCreateInfo::Create3D(resource_create_3d) => {
+            rutabaga.resource_create_3d(resource.resource_id, resource_create_3d)?;
+            if resource.backing.is_some() {
+                rutabaga.attach_backing(resource.resource_id, iovecs)?;
+                if resource_create_3d.target == RUTABAGA_PIPE_TEXTURE_2D {
+                    let transfer = Transfer3D::new_2d(
+                        0,
+                        0,
+                        resource_create_3d.width,
+                        resource_create_3d.height,
+                    );
+                    rutabaga.transfer_write(0, resource.resource_id, transfer)?;
+                }
+            }
+        }
+        ResourceCreateInfo::Blob {
+            ctx_id,
+            resource_create_blob,
+        } => {
+            rutabaga.resource_create_blob(
+                ctx_id,
+                resource.resource_id,
+                resource_create_blob,
+                iovecs,
+            )?;
+        }
+    }
+    Ok(())
+}
+
 impl VirtioGpu {
     /// Creates a new instance of the VirtioGpu state tracker.
     pub fn new(
@@ -116,12 +213,11 @@ impl VirtioGpu {
         display_height: u32,
         rutabaga_builder: RutabagaBuilder,
         event_devices: Vec<EventDevice>,
-        gpu_device_socket: VmMemoryControlRequestSocket,
-        pci_bar: Alloc,
-        map_request: Arc<Mutex<Option<ExternalMapping>>>,
+        blob_mapper: BlobMapper,
         external_blob: bool,
     ) -> Option<VirtioGpu> {
         let rutabaga = rutabaga_builder
+            .clone()
             .build()
             .map_err(|e| error!("failed to build rutabaga {}", e))
             .ok()?;
@@ -134,11 +230,12 @@ impl VirtioGpu {
             scanout_surface_id: None,
             cursor_resource_id: None,
             cursor_surface_id: None,
-            gpu_device_socket,
-            pci_bar,
-            map_request,
+            blob_mapper,
             rutabaga,
+            rutabaga_builder,
             resources: Default::default(),
+            contexts: Default::default(),
+            lost_contexts: Default::default(),
             external_blob,
         };
 
@@ -152,6 +249,13 @@ impl VirtioGpu {
         Some(virtio_gpu)
     }
 
+    /// Unmaps every blob resource, then drops the renderer and everything else but where blobs are
+    /// mapped, which is handed back.
+    pub fn into_blob_mapper(mut self) -> BlobMapper {
+        self.unmap_blobs();
+        self.blob_mapper
+    }
+
     /// Imports the event device
     pub fn import_event_device(
         &mut self,
@@ -184,6 +288,20 @@ impl VirtioGpu {
         [(self.display_width, self.display_height)]
     }
 
+    /// Changes the display size reported to the guest. The scanout surface and the cursor on it
+    /// are released, and created again at the new size when the guest next sets them.
+    pub fn set_display_size(&mut self, width: u32, height: u32) {
+        let mut display = self.display.borrow_mut();
+        if let Some(surface_id) = self.cursor_surface_id.take() {
+            display.release_surface(surface_id);
+        }
+        if let Some(surface_id) = self.scanout_surface_id.take() {
+            display.release_surface(surface_id);
+        }
+        self.display_width = width;
+        self.display_height = height;
+    }
+
     /// Processes the internal `display` events and returns `true` if the main display was closed.
     pub fn process_display(&mut self) -> bool {
         let mut display = self.display.borrow_mut();
@@ -513,6 +631,7 @@ impl VirtioGpu {
             resource_create_3d.width,
             resource_create_3d.height,
             0,
+            ResourceCreateInfo::Create3D(resource_create_3d),
         );
 
         // Rely on rutabaga to check for duplicate resource ids.
@@ -531,12 +650,18 @@ impl VirtioGpu {
     ) -> VirtioGpuResult {
         let rutabaga_iovecs = sglist_to_rutabaga_iovecs(&vecs[..], mem).map_err(|_| ErrUnspec)?;
         self.rutabaga.attach_backing(resource_id, rutabaga_iovecs)?;
+        if let Some(resource) = self.resources.get_mut(&resource_id) {
+            resource.backing = Some(vecs);
+        }
         Ok(OkNoData)
     }
 
     /// Detaches any previously attached iovecs from the resource.
     pub fn detach_backing(&mut self, resource_id: u32) -> VirtioGpuResult {
         self.rutabaga.detach_backing(resource_id)?;
+        if let Some(resource) = self.resources.get_mut(&resource_id) {
+            resource.backing = None;
+        }
         Ok(OkNoData)
     }
 
@@ -557,6 +682,7 @@ impl VirtioGpu {
         resource_id: u32,
         transfer: Transfer3D,
     ) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga
             .transfer_write(ctx_id, resource_id, transfer)?;
         Ok(OkNoData)
@@ -574,6 +700,7 @@ impl VirtioGpu {
         transfer: Transfer3D,
         buf: Option<VolatileSlice>,
     ) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga
             .transfer_read(ctx_id, resource_id, transfer, buf)?;
         Ok(OkNoData)
@@ -588,6 +715,7 @@ impl VirtioGpu {
         vecs: Vec<(GuestAddress, usize)>,
         mem: &GuestMemory,
     ) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         let rutabaga_iovecs = sglist_to_rutabaga_iovecs(&vecs[..], mem).map_err(|_| ErrUnspec)?;
         self.rutabaga.resource_create_blob(
             ctx_id,
@@ -596,14 +724,26 @@ impl VirtioGpu {
             rutabaga_iovecs,
         )?;
 
-        let resource = VirtioGpuResource::new(resource_id, 0, 0, resource_create_blob.size);
+        let mut resource = VirtioGpuResource::new(
+            resource_id,
+            0,
+            0,
+            resource_create_blob.size,
+            ResourceCreateInfo::Blob {
+                ctx_id,
+                resource_create_blob,
+            },
+        );
+        if !vecs.is_empty() {
+            resource.backing = Some(vecs);
+        }
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
         self.result_from_query(resource_id)
     }
 
-    /// Uses the hypervisor to map the rutabaga blob resource.
+    /// Maps the rutabaga blob resource at `offset` of the device's host visible memory.
     pub fn resource_map_blob(&mut self, resource_id: u32, offset: u64) -> VirtioGpuResult {
         let resource = self
             .resources
@@ -613,9 +753,31 @@ impl VirtioGpu {
         let map_info = self.rutabaga.map_info(resource_id).map_err(|_| ErrUnspec)?;
         let export = self.rutabaga.export_blob(resource_id);
 
+        let (socket, pci_bar, map_request) = match &self.blob_mapper {
+            BlobMapper::PciBar {
+                socket,
+                pci_bar,
+                map_request,
+            } => (socket, *pci_bar, map_request),
+            BlobMapper::VhostUser(backend_req) => {
+                let export = export.map_err(|_| ErrUnspec)?;
+                let backend_req = backend_req.lock();
+                let backend_req = backend_req.as_ref().ok_or(ErrUnspec)?;
+                let mapping = ShmMapping {
+                    fd_offset: 0,
+                    shm_offset: offset,
+                    len: resource.size,
+                    flags: VHOST_USER_FS_FLAG_MAP_R | VHOST_USER_FS_FLAG_MAP_W,
+                };
+                backend_req.map(&export.os_handle, &[mapping])?;
+                resource.mapping = Some(BlobMapping::Shm(offset));
+                return Ok(OkMapInfo { map_info });
+            }
+        };
+
         let request = match export {
             Ok(ref export) => VmMemoryRequest::RegisterFdAtPciBarOffset(
-                self.pci_bar,
+                pci_bar,
                 MaybeOwnedDescriptor::Borrowed(export.os_handle.as_raw_descriptor()),
                 resource.size as usize,
                 offset,
@@ -628,22 +790,22 @@ impl VirtioGpu {
                 let mapping = self.rutabaga.map(resource_id)?;
                 // Scope for lock
                 {
-                    let mut map_req = self.map_request.lock();
+                    let mut map_req = map_request.lock();
                     if map_req.is_some() {
                         return Err(ErrUnspec);
                     }
                     *map_req = Some(mapping);
                 }
-                VmMemoryRequest::RegisterHostPointerAtPciBarOffset(self.pci_bar, offset)
+                VmMemoryRequest::RegisterHostPointerAtPciBarOffset(pci_bar, offset)
             }
         };
 
-        self.gpu_device_socket.send(&request)?;
-        let response = self.gpu_device_socket.recv()?;
+        socket.send(&request)?;
+        let response = socket.recv()?;
 
         match response {
             VmMemoryResponse::RegisterMemory { pfn: _, slot } => {
-                resource.slot = Some(slot);
+                resource.mapping = Some(BlobMapping::Slot(slot));
                 Ok(OkMapInfo { map_info })
             }
             VmMemoryResponse::Err(e) => Err(ErrSys(e)),
@@ -651,21 +813,38 @@ impl VirtioGpu {
         }
     }
 
-    /// Uses the hypervisor to unmap the blob resource.
+    /// Unmaps the blob resource from the device's host visible memory.
     pub fn resource_unmap_blob(&mut self, resource_id: u32) -> VirtioGpuResult {
         let resource = self
             .resources
             .get_mut(&resource_id)
             .ok_or(ErrInvalidResourceId)?;
 
-        let slot = resource.slot.ok_or(ErrUnspec)?;
-        let request = VmMemoryRequest::UnregisterMemory(slot);
-        self.gpu_device_socket.send(&request)?;
-        let response = self.gpu_device_socket.recv()?;
+        let (socket, request) = match (resource.mapping.ok_or(ErrUnspec)?, &self.blob_mapper) {
+            (BlobMapping::Slot(slot), BlobMapper::PciBar { socket, .. }) => {
+                (socket, VmMemoryRequest::UnregisterMemory(slot))
+            }
+            (BlobMapping::Shm(offset), BlobMapper::VhostUser(backend_req)) => {
+                let backend_req = backend_req.lock();
+                let backend_req = backend_req.as_ref().ok_or(ErrUnspec)?;
+                let mapping = ShmMapping {
+                    fd_offset: 0,
+                    shm_offset: offset,
+                    len: resource.size,
+                    flags: 0,
+                };
+                backend_req.unmap(&[mapping])?;
+                resource.mapping = None;
+                return Ok(OkNoData);
+            }
+            _ => return Err(ErrUnspec),
+        };
+        socket.send(&request)?;
+        let response = socket.recv()?;
 
         match response {
             VmMemoryResponse::Ok => {
-                resource.slot = None;
+                resource.mapping = None;
                 Ok(OkNoData)
             }
             VmMemoryResponse::Err(e) => Err(ErrSys(e)),
@@ -673,32 +852,126 @@ impl VirtioGpu {
         }
     }
 
+    fn unmap_blobs(&mut self) {
+        let mapped: Vec<u32> = self
+            .resources
+            .values()
+            .filter(|resource| resource.mapping.is_some())
+            .map(|resource| resource.resource_id)
+            .collect();
+        for resource_id in mapped {
+            if let Err(e) = self.resource_unmap_blob(resource_id) {
+                error!("failed to unmap blob resource {}: {}", resource_id, e);
+            }
+        }
+    }
+
+    // Fails commands on a context that was lost when the renderer was reloaded, so the guest
+    // finds out its rendering state is gone instead of drawing with a context that has none.
+    fn check_context(&self, ctx_id: u32) -> VirtioGpuResult {
+        if self.lost_contexts.contains(&ctx_id) {
+            return Err(ErrInvalidContextId);
+        }
+        Ok(OkNoData)
+    }
+
     /// Creates a rutabaga context.
     pub fn create_context(&mut self, ctx_id: u32, context_init: u32) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga.create_context(ctx_id, context_init)?;
+        self.contexts.insert(ctx_id);
         Ok(OkNoData)
     }
 
     /// Destroys a rutabaga context.
     pub fn destroy_context(&mut self, ctx_id: u32) -> VirtioGpuResult {
+        // The renderer holding a lost context is gone already, the guest only lets go of the id.
+        if self.lost_contexts.remove(&ctx_id) {
+            return Ok(OkNoData);
+        }
+        self.contexts.remove(&ctx_id);
         self.rutabaga.destroy_context(ctx_id)?;
         Ok(OkNoData)
     }
 
     /// Attaches a resource to a rutabaga context.
     pub fn context_attach_resource(&mut self, ctx_id: u32, resource_id: u32) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga.context_attach_resource(ctx_id, resource_id)?;
         Ok(OkNoData)
     }
 
     /// Detaches a resource from a rutabaga context.
     pub fn context_detach_resource(&mut self, ctx_id: u32, resource_id: u32) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga.context_detach_resource(ctx_id, resource_id)?;
         Ok(OkNoData)
     }
 
+    /// Tears down the renderer and builds a new one with the same parameters, e.g. after the host
+    /// graphics driver was updated or the renderer got into a bad state.
+    ///
+    /// The guest's contexts are lost: every command on one of them fails with
+    /// `ErrInvalidContextId` until the guest destroys it, which tells guest userspace to create a
+    /// new context and rebuild its rendering state. Resources are recreated with the same ids and,
+    /// if they have guest backing, their contents are uploaded again, so the display keeps
+    /// working. Resources that can't be recreated, such as the blobs of lost contexts, are dropped
+    /// and commands on them fail with `ErrInvalidResourceId`. Blob mappings are removed from the
+    /// guest address space because they point at memory owned by the old renderer.
+    pub fn reload_renderer(&mut self, mem: &GuestMemory) -> VirtioGpuResult {
+        if !self.rutabaga.supports_rebuild() {
+            error!("the renderer doesn't support being reloaded");
+            return Err(ErrUnspec);
+        }
+
+        self.unmap_blobs();
+
+        for resource in self.resources.values_mut() {
+            if let Some((display, import_id)) = resource.display_import.take() {
+                display.borrow_mut().release_import(import_id);
+            }
+        }
+
+        let lost_contexts = self.contexts.len();
+        self.lost_contexts
+            .extend(std::mem::take(&mut self.contexts));
+
+        // Components like virglrenderer only allow one instance per process, so the old renderer
+        // has to be dropped before its replacement is built. The 2D renderer stands in for it in
+        // the meantime, and for good if the build fails.
+        self.rutabaga = RutabagaBuilder::new(RutabagaComponentType::Rutabaga2D).build()?;
+        self.rutabaga = self.rutabaga_builder.clone().build()?;
+
+        let mut lost = Vec::new();
+        for resource in self.resources.values() {
+            if let Err(e) = replay_resource(&mut self.rutabaga, resource, mem) {
+                error!(
+                    "failed to recreate resource {}: {}",
+                    resource.resource_id, e
+                );
+                lost.push(resource.resource_id);
+            }
+        }
+        for resource_id in &lost {
+            self.resources.remove(resource_id);
+        }
+
+        info!(
+            "renderer reloaded: {} contexts lost, {} resources recreated, {} resources lost",
+            lost_contexts,
+            self.resources.len(),
+            lost.len()
+        );
+
+        if let Some(resource_id) = self.scanout_resource_id {
+            self.flush_resource(resource_id.get())?;
+        }
+        Ok(OkNoData)
+    }
+
     /// Submits a command buffer to a rutabaga context.
     pub fn submit_command(&mut self, ctx_id: u32, commands: &mut [u8]) -> VirtioGpuResult {
+        self.check_context(ctx_id)?;
         self.rutabaga.submit_command(ctx_id, commands)?;
         Ok(OkNoData)
     }
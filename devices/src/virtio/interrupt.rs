@@ -2,12 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use super::{INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING, VIRTIO_MSI_NO_VECTOR};
+use super::{
+    DeviceMetrics, INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
+    VIRTIO_MSI_NO_VECTOR,
+};
 use crate::pci::MsixConfig;
-use base::Event;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use base::{error, Event};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
 use sync::Mutex;
+use vhost::user::BackendRequester;
 
 pub struct Interrupt {
     interrupt_status: Arc<AtomicUsize>,
@@ -15,6 +20,13 @@ pub struct Interrupt {
     interrupt_resample_evt: Event,
     pub msix_config: Option<Arc<Mutex<MsixConfig>>>,
     config_msix_vector: u16,
+    metrics: DeviceMetrics,
+    needs_reset: Arc<AtomicBool>,
+    // The vhost-user call event of each queue, indexed by the queue's vector.
+    call_evts: Option<Arc<Mutex<Vec<Option<Event>>>>>,
+    // The channel requests such as config changes are made of a vhost-user frontend over, once the
+    // frontend set it up.
+    backend_req: Option<Arc<Mutex<Option<BackendRequester>>>>,
 }
 
 impl Interrupt {
@@ -31,20 +43,70 @@ impl Interrupt {
             interrupt_resample_evt,
             msix_config,
             config_msix_vector,
+            metrics: DeviceMetrics::default(),
+            needs_reset: Arc::new(AtomicBool::new(false)),
+            call_evts: None,
+            backend_req: None,
         }
     }
 
+    /// Creates an `Interrupt` for a device served to a vhost-user frontend, which signals the
+    /// queue with vector `i` by writing `call_evts[i]`, if the frontend gave one. The frontend has
+    /// no ISR for the driver to acknowledge, so every signal is written. Config changes are sent
+    /// over `backend_req`, if the frontend set up that channel.
+    pub fn new_vhost_user(
+        call_evts: Arc<Mutex<Vec<Option<Event>>>>,
+        backend_req: Arc<Mutex<Option<BackendRequester>>>,
+    ) -> base::Result<Interrupt> {
+        let mut interrupt = Interrupt::new(
+            Arc::new(AtomicUsize::new(0)),
+            Event::new()?,
+            Event::new()?,
+            None,
+            VIRTIO_MSI_NO_VECTOR,
+        );
+        interrupt.call_evts = Some(call_evts);
+        interrupt.backend_req = Some(backend_req);
+        Ok(interrupt)
+    }
+
+    /// The channel to the vhost-user frontend of a device created with `new_vhost_user`, which
+    /// the device can also ask to map files into its shared memory region over.
+    pub fn backend_requester(&self) -> Option<Arc<Mutex<Option<BackendRequester>>>> {
+        self.backend_req.clone()
+    }
+
+    /// Sets `needs_reset` when the device stops working, for the transport to report to the
+    /// driver.
+    pub fn set_needs_reset_flag(&mut self, needs_reset: Arc<AtomicBool>) {
+        self.needs_reset = needs_reset;
+    }
+
+    /// Counts the interrupts injected through this `Interrupt` in `metrics`.
+    pub fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     /// Virtqueue Interrupts From The Device
     ///
     /// If MSI-X is enabled in this device, MSI-X interrupt is preferred.
     /// Write to the irqfd to VMM to deliver virtual interrupt to the guest
     fn signal(&self, vector: u16, interrupt_status_mask: u32) {
+        if let Some(call_evts) = &self.call_evts {
+            if let Some(Some(call_evt)) = call_evts.lock().get(vector as usize) {
+                call_evt.write(1).unwrap();
+                self.metrics.add_interrupt();
+            }
+            return;
+        }
+
         // Don't need to set ISR for MSI-X interrupts
         if let Some(msix_config) = &self.msix_config {
             let mut msix_config = msix_config.lock();
             if msix_config.enabled() {
                 if vector != VIRTIO_MSI_NO_VECTOR {
                     msix_config.trigger(vector);
+                    self.metrics.add_interrupt();
                 }
                 return;
             }
@@ -59,6 +121,7 @@ impl Interrupt {
         {
             // Write to irqfd to inject INTx interrupt
             self.interrupt_evt.write(1).unwrap();
+            self.metrics.add_interrupt();
         }
     }
 
@@ -69,9 +132,24 @@ impl Interrupt {
 
     /// Notify the driver that the device configuration has changed.
     pub fn signal_config_changed(&self) {
+        if let Some(backend_req) = &self.backend_req {
+            if let Some(backend_req) = &*backend_req.lock() {
+                if let Err(e) = backend_req.config_changed() {
+                    error!("failed to signal a config change to the frontend: {}", e);
+                }
+            }
+            return;
+        }
         self.signal(self.config_msix_vector, INTERRUPT_STATUS_CONFIG_CHANGED)
     }
 
+    /// Tell the driver the device stopped working and has to be reset before it is used again,
+    /// such as after its worker hit an error it can't recover from.
+    pub fn signal_needs_reset(&self) {
+        self.needs_reset.store(true, Ordering::SeqCst);
+        self.signal_config_changed();
+    }
+
     /// Handle interrupt resampling event, reading the value from the event and doing the resample.
     pub fn interrupt_resample(&self) {
         let _ = self.interrupt_resample_evt.read();
@@ -92,3 +170,13 @@ impl Interrupt {
         &self.interrupt_resample_evt
     }
 }
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        // A worker that panics drops its `Interrupt` while unwinding, which is the last chance to
+        // let the driver know the device is gone.
+        if thread::panicking() {
+            self.signal_needs_reset();
+        }
+    }
+}
@@ -0,0 +1,1045 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements the virtio-iommu device, which lets the guest manage the DMA mappings of the
+//! endpoints behind it, such as devices passed through with VFIO.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::thread;
+
+use acpi_tables::sdt::SDT;
+use base::{error, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
+use data_model::{DataInit, Le16, Le32, Le64};
+use sync::Mutex;
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{
+    copy_config, descriptor_utils, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_IOMMU,
+};
+use crate::pci::PciAddress;
+
+const QUEUE_SIZE: u16 = 256;
+// The request queue, and the event queue that faults would be reported on. Faults are never
+// reported, so the event queue stays unused.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+// Feature bits.
+const VIRTIO_IOMMU_F_INPUT_RANGE: u32 = 0;
+const VIRTIO_IOMMU_F_MAP_UNMAP: u32 = 2;
+const VIRTIO_IOMMU_F_PROBE: u32 = 4;
+
+// The guest maps memory in 4 KiB pages.
+const PAGE_SIZE: u64 = 0x1000;
+// The last IOVA the guest can map. Host IOMMUs translate at least 39 bits.
+const IOVA_END: u64 = (1 << 39) - 1;
+// The space the guest gives the properties of an endpoint in a probe response.
+const PROBE_SIZE: u32 = 512;
+
+// Request types.
+const VIRTIO_IOMMU_T_ATTACH: u8 = 1;
+const VIRTIO_IOMMU_T_DETACH: u8 = 2;
+const VIRTIO_IOMMU_T_MAP: u8 = 3;
+const VIRTIO_IOMMU_T_UNMAP: u8 = 4;
+const VIRTIO_IOMMU_T_PROBE: u8 = 5;
+
+// Request statuses.
+const VIRTIO_IOMMU_S_OK: u8 = 0;
+const VIRTIO_IOMMU_S_IOERR: u8 = 1;
+const VIRTIO_IOMMU_S_UNSUPP: u8 = 2;
+const VIRTIO_IOMMU_S_INVAL: u8 = 4;
+const VIRTIO_IOMMU_S_RANGE: u8 = 5;
+const VIRTIO_IOMMU_S_NOENT: u8 = 6;
+
+// Flags of map requests.
+const VIRTIO_IOMMU_MAP_F_READ: u32 = 1 << 0;
+const VIRTIO_IOMMU_MAP_F_WRITE: u32 = 1 << 1;
+
+// Endpoint properties reported by probe requests.
+const VIRTIO_IOMMU_PROBE_T_RESV_MEM: u16 = 1;
+const VIRTIO_IOMMU_RESV_MEM_T_MSI: u8 = 1;
+
+// Endpoints signal MSIs by writing to this range, which the IOMMU doesn't translate.
+const MSI_IOVA_START: u64 = 0xfee0_0000;
+const MSI_IOVA_END: u64 = 0xfeef_ffff;
+
+// The ACPI VIOT table tells the guest which endpoints are behind the virtio-iommu.
+const VIOT_REVISION: u8 = 0;
+const VIOT_OEM_REVISION: u32 = 1;
+const VIOT_NODE_PCI_RANGE: u8 = 1;
+const VIOT_NODE_VIRTIO_IOMMU_PCI: u8 = 3;
+// The standard header all ACPI tables start with.
+const ACPI_HEADER_LEN: u32 = 36;
+
+// virtio_iommu_config is the device configuration space defined by the virtio spec.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_iommu_config {
+    page_size_mask: Le64,
+    input_range_start: Le64,
+    input_range_end: Le64,
+    domain_range_start: Le32,
+    domain_range_end: Le32,
+    probe_size: Le32,
+    padding: [u8; 4],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_iommu_config {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_iommu_req_head {
+    req_type: u8,
+    reserved: [u8; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_iommu_req_head {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_iommu_req_tail {
+    status: u8,
+    reserved: [u8; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_iommu_req_tail {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_iommu_probe_resv_mem {
+    prop_type: Le16,
+    length: Le16,
+    subtype: u8,
+    reserved: [u8; 3],
+    start: Le64,
+    end: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_iommu_probe_resv_mem {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ViotHeader {
+    _node_count: u16,
+    _node_offset: u16,
+    _reserved: [u8; 8],
+}
+
+// Safe as ViotHeader structure only contains raw data
+unsafe impl DataInit for ViotHeader {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ViotVirtioIommuPci {
+    _type: u8,
+    _reserved: u8,
+    _length: u16,
+    _segment: u16,
+    _bdf: u16,
+    _reserved2: [u8; 8],
+}
+
+// Safe as ViotVirtioIommuPci structure only contains raw data
+unsafe impl DataInit for ViotVirtioIommuPci {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ViotPciRange {
+    _type: u8,
+    _reserved: u8,
+    _length: u16,
+    _endpoint_start: u32,
+    _segment_start: u16,
+    _segment_end: u16,
+    _bdf_start: u16,
+    _bdf_end: u16,
+    _output_node: u16,
+    _reserved2: [u8; 6],
+}
+
+// Safe as ViotPciRange structure only contains raw data
+unsafe impl DataInit for ViotPciRange {}
+
+/// Holds the DMA mappings of an endpoint behind the virtio-iommu, such as a VFIO container.
+pub trait MemoryMapper: AsRawDescriptor + Send {
+    /// Makes the `size` bytes of host memory at `host_addr` visible to the endpoint at `iova`,
+    /// read-only unless `writable` is set.
+    ///
+    /// # Safety
+    ///
+    /// The host memory must stay mapped until `remove_map` is called for it.
+    unsafe fn add_map(
+        &mut self,
+        iova: u64,
+        host_addr: u64,
+        size: u64,
+        writable: bool,
+    ) -> std::result::Result<(), Box<dyn StdError + Send>>;
+
+    /// Removes the mapping of the `size` bytes at `iova`.
+    fn remove_map(
+        &mut self,
+        iova: u64,
+        size: u64,
+    ) -> std::result::Result<(), Box<dyn StdError + Send>>;
+}
+
+// A guest request, without its reserved fields.
+#[derive(Debug, PartialEq)]
+enum Request {
+    Attach {
+        domain: u32,
+        endpoint: u32,
+        flags: u32,
+    },
+    Detach {
+        domain: u32,
+        endpoint: u32,
+    },
+    Map {
+        domain: u32,
+        virt_start: u64,
+        virt_end: u64,
+        phys_start: u64,
+        flags: u32,
+    },
+    Unmap {
+        domain: u32,
+        virt_start: u64,
+        virt_end: u64,
+    },
+    Probe {
+        endpoint: u32,
+    },
+    Unsupported,
+}
+
+// The fields of requests aren't naturally aligned, so they are read one at a time.
+fn read_u32(reader: &mut Reader) -> io::Result<u32> {
+    reader.read_obj::<Le32>().map(Le32::to_native)
+}
+
+fn read_u64(reader: &mut Reader) -> io::Result<u64> {
+    reader.read_obj::<Le64>().map(Le64::to_native)
+}
+
+impl Request {
+    fn read(reader: &mut Reader) -> io::Result<Request> {
+        let head: virtio_iommu_req_head = reader.read_obj()?;
+        let request = match head.req_type {
+            VIRTIO_IOMMU_T_ATTACH => Request::Attach {
+                domain: read_u32(reader)?,
+                endpoint: read_u32(reader)?,
+                flags: read_u32(reader)?,
+            },
+            VIRTIO_IOMMU_T_DETACH => Request::Detach {
+                domain: read_u32(reader)?,
+                endpoint: read_u32(reader)?,
+            },
+            VIRTIO_IOMMU_T_MAP => Request::Map {
+                domain: read_u32(reader)?,
+                virt_start: read_u64(reader)?,
+                virt_end: read_u64(reader)?,
+                phys_start: read_u64(reader)?,
+                flags: read_u32(reader)?,
+            },
+            VIRTIO_IOMMU_T_UNMAP => Request::Unmap {
+                domain: read_u32(reader)?,
+                virt_start: read_u64(reader)?,
+                virt_end: read_u64(reader)?,
+            },
+            VIRTIO_IOMMU_T_PROBE => Request::Probe {
+                endpoint: read_u32(reader)?,
+            },
+            _ => Request::Unsupported,
+        };
+        Ok(request)
+    }
+}
+
+// A range of IOVAs the guest mapped to guest memory.
+struct Mapping {
+    virt_end: u64,
+    host_addr: u64,
+    writable: bool,
+}
+
+impl Mapping {
+    // Adds the mapping, which starts at `iova`, to `mapper`.
+    fn add_to(
+        &self,
+        mapper: &Mutex<dyn MemoryMapper>,
+        iova: u64,
+    ) -> std::result::Result<(), Box<dyn StdError + Send>> {
+        // Safe because `host_addr` points at guest memory, which stays mapped as long as the VM
+        // runs.
+        unsafe {
+            mapper.lock().add_map(
+                iova,
+                self.host_addr,
+                self.virt_end - iova + 1,
+                self.writable,
+            )
+        }
+    }
+
+    // Removes the mapping, which starts at `iova`, from `mapper`.
+    fn remove_from(&self, mapper: &Mutex<dyn MemoryMapper>, iova: u64) {
+        if let Err(e) = mapper.lock().remove_map(iova, self.virt_end - iova + 1) {
+            error!("virtio-iommu: failed to unmap {:#x}: {}", iova, e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Domain {
+    endpoints: BTreeSet<u32>,
+    // The mappings shared by the endpoints of the domain, by the IOVA they start at.
+    mappings: BTreeMap<u64, Mapping>,
+}
+
+struct Endpoint {
+    mapper: Arc<Mutex<dyn MemoryMapper>>,
+    domain: Option<u32>,
+}
+
+// The endpoints behind the IOMMU and the domains the guest put them in.
+struct IommuState {
+    mem: GuestMemory,
+    endpoints: BTreeMap<u32, Endpoint>,
+    domains: BTreeMap<u32, Domain>,
+}
+
+impl IommuState {
+    fn new(mem: GuestMemory, endpoints: &BTreeMap<u32, Arc<Mutex<dyn MemoryMapper>>>) -> Self {
+        IommuState {
+            mem,
+            endpoints: endpoints
+                .iter()
+                .map(|(&id, mapper)| {
+                    (
+                        id,
+                        Endpoint {
+                            mapper: mapper.clone(),
+                            domain: None,
+                        },
+                    )
+                })
+                .collect(),
+            domains: BTreeMap::new(),
+        }
+    }
+
+    // Handles one guest request, returning its status.
+    fn handle_request(&mut self, request: Request) -> u8 {
+        match request {
+            Request::Attach {
+                domain,
+                endpoint,
+                flags,
+            } => self.attach(domain, endpoint, flags),
+            Request::Detach { domain, endpoint } => self.detach(domain, endpoint),
+            Request::Map {
+                domain,
+                virt_start,
+                virt_end,
+                phys_start,
+                flags,
+            } => self.map(domain, virt_start, virt_end, phys_start, flags),
+            Request::Unmap {
+                domain,
+                virt_start,
+                virt_end,
+            } => self.unmap(domain, virt_start, virt_end),
+            Request::Probe { endpoint } => {
+                if self.endpoints.contains_key(&endpoint) {
+                    VIRTIO_IOMMU_S_OK
+                } else {
+                    VIRTIO_IOMMU_S_NOENT
+                }
+            }
+            Request::Unsupported => VIRTIO_IOMMU_S_UNSUPP,
+        }
+    }
+
+    fn attach(&mut self, domain: u32, endpoint: u32, flags: u32) -> u8 {
+        if flags != 0 {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        let current = match self.endpoints.get(&endpoint) {
+            Some(ep) => ep.domain,
+            None => return VIRTIO_IOMMU_S_NOENT,
+        };
+        if current == Some(domain) {
+            return VIRTIO_IOMMU_S_OK;
+        }
+        // An endpoint is in one domain at a time, so attaching it moves it.
+        if let Some(current) = current {
+            self.detach(current, endpoint);
+        }
+
+        // The endpoint gets the mappings the domain already has.
+        let mapper = &self.endpoints[&endpoint].mapper;
+        let dom = self.domains.entry(domain).or_default();
+        let mut added: Vec<(u64, &Mapping)> = Vec::new();
+        for (&iova, mapping) in &dom.mappings {
+            if let Err(e) = mapping.add_to(mapper, iova) {
+                error!(
+                    "virtio-iommu: failed to map {:#x} for endpoint {}: {}",
+                    iova, endpoint, e
+                );
+                for (iova, mapping) in added {
+                    mapping.remove_from(mapper, iova);
+                }
+                return VIRTIO_IOMMU_S_IOERR;
+            }
+            added.push((iova, mapping));
+        }
+        dom.endpoints.insert(endpoint);
+        if let Some(ep) = self.endpoints.get_mut(&endpoint) {
+            ep.domain = Some(domain);
+        }
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn detach(&mut self, domain: u32, endpoint: u32) -> u8 {
+        let ep = match self.endpoints.get_mut(&endpoint) {
+            Some(ep) => ep,
+            None => return VIRTIO_IOMMU_S_NOENT,
+        };
+        if ep.domain != Some(domain) {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        ep.domain = None;
+        if let Some(dom) = self.domains.get_mut(&domain) {
+            dom.endpoints.remove(&endpoint);
+            for (&iova, mapping) in &dom.mappings {
+                mapping.remove_from(&ep.mapper, iova);
+            }
+            // A domain goes away along with its mappings once its last endpoint leaves.
+            if dom.endpoints.is_empty() {
+                self.domains.remove(&domain);
+            }
+        }
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn map(
+        &mut self,
+        domain: u32,
+        virt_start: u64,
+        virt_end: u64,
+        phys_start: u64,
+        flags: u32,
+    ) -> u8 {
+        let dom = match self.domains.get_mut(&domain) {
+            Some(dom) => dom,
+            None => return VIRTIO_IOMMU_S_NOENT,
+        };
+        if flags & !(VIRTIO_IOMMU_MAP_F_READ | VIRTIO_IOMMU_MAP_F_WRITE) != 0
+            || virt_start > virt_end
+        {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        if virt_end > IOVA_END
+            || virt_start % PAGE_SIZE != 0
+            || (virt_end + 1) % PAGE_SIZE != 0
+            || phys_start % PAGE_SIZE != 0
+        {
+            return VIRTIO_IOMMU_S_RANGE;
+        }
+        // Mappings don't overlap, so only the last one starting in the range can reach into it.
+        if let Some((_, last)) = dom.mappings.range(..=virt_end).next_back() {
+            if last.virt_end >= virt_start {
+                return VIRTIO_IOMMU_S_INVAL;
+            }
+        }
+        let size = virt_end - virt_start + 1;
+        let host_addr = match self
+            .mem
+            .get_slice_at_addr(GuestAddress(phys_start), size as usize)
+        {
+            Ok(slice) => slice.as_ptr() as u64,
+            Err(_) => return VIRTIO_IOMMU_S_RANGE,
+        };
+
+        let mapping = Mapping {
+            virt_end,
+            host_addr,
+            writable: flags & VIRTIO_IOMMU_MAP_F_WRITE != 0,
+        };
+        let mut mapped: Vec<&Arc<Mutex<dyn MemoryMapper>>> = Vec::new();
+        for endpoint in &dom.endpoints {
+            let mapper = &self.endpoints[endpoint].mapper;
+            if let Err(e) = mapping.add_to(mapper, virt_start) {
+                error!(
+                    "virtio-iommu: failed to map {:#x} for endpoint {}: {}",
+                    virt_start, endpoint, e
+                );
+                for mapper in mapped {
+                    mapping.remove_from(mapper, virt_start);
+                }
+                return VIRTIO_IOMMU_S_IOERR;
+            }
+            mapped.push(mapper);
+        }
+        dom.mappings.insert(virt_start, mapping);
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn unmap(&mut self, domain: u32, virt_start: u64, virt_end: u64) -> u8 {
+        let dom = match self.domains.get_mut(&domain) {
+            Some(dom) => dom,
+            None => return VIRTIO_IOMMU_S_NOENT,
+        };
+        if virt_start > virt_end {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        let iovas: Vec<u64> = dom
+            .mappings
+            .range(..=virt_end)
+            .filter(|(_, mapping)| mapping.virt_end >= virt_start)
+            .map(|(&iova, _)| iova)
+            .collect();
+        // Mappings are only removed whole.
+        if iovas
+            .iter()
+            .any(|iova| *iova < virt_start || dom.mappings[iova].virt_end > virt_end)
+        {
+            return VIRTIO_IOMMU_S_RANGE;
+        }
+        for iova in iovas {
+            if let Some(mapping) = dom.mappings.remove(&iova) {
+                for endpoint in &dom.endpoints {
+                    mapping.remove_from(&self.endpoints[endpoint].mapper, iova);
+                }
+            }
+        }
+        VIRTIO_IOMMU_S_OK
+    }
+
+    // Detaches every endpoint, which removes all of the mappings.
+    fn reset(&mut self) {
+        let attached: Vec<(u32, u32)> = self
+            .endpoints
+            .iter()
+            .filter_map(|(&id, ep)| ep.domain.map(|domain| (domain, id)))
+            .collect();
+        for (domain, endpoint) in attached {
+            self.detach(domain, endpoint);
+        }
+    }
+}
+
+// The properties of every endpoint: the MSI range, which is not translated.
+fn endpoint_properties() -> virtio_iommu_probe_resv_mem {
+    virtio_iommu_probe_resv_mem {
+        prop_type: VIRTIO_IOMMU_PROBE_T_RESV_MEM.into(),
+        // The length doesn't count the type and length fields.
+        length: ((size_of::<virtio_iommu_probe_resv_mem>() - 4) as u16).into(),
+        subtype: VIRTIO_IOMMU_RESV_MEM_T_MSI,
+        start: MSI_IOVA_START.into(),
+        end: MSI_IOVA_END.into(),
+        ..Default::default()
+    }
+}
+
+// Reads one request from `avail_desc`, handles it and writes the response back. Returns the number
+// of bytes written.
+fn process_request(
+    avail_desc: DescriptorChain,
+    state: &mut IommuState,
+) -> descriptor_utils::Result<usize> {
+    let mut reader = Reader::new(state.mem.clone(), avail_desc.clone())?;
+    let mut writer = Writer::new(state.mem.clone(), avail_desc)?;
+    let request = Request::read(&mut reader).map_err(descriptor_utils::Error::IoError)?;
+    let is_probe = matches!(request, Request::Probe { .. });
+    let status = state.handle_request(request);
+    if is_probe {
+        // The properties come before the status and fill all of their space, even when the
+        // endpoint doesn't exist.
+        let mut properties = vec![0u8; PROBE_SIZE as usize];
+        if status == VIRTIO_IOMMU_S_OK {
+            let msi = endpoint_properties();
+            properties[..size_of::<virtio_iommu_probe_resv_mem>()].copy_from_slice(msi.as_slice());
+        }
+        writer
+            .write_all(&properties)
+            .map_err(descriptor_utils::Error::IoError)?;
+    }
+    writer
+        .write_obj(virtio_iommu_req_tail {
+            status,
+            ..Default::default()
+        })
+        .map_err(descriptor_utils::Error::IoError)?;
+    Ok(writer.bytes_written())
+}
+
+// Creates the VIOT table describing the virtio-iommu at `iommu_bdf` and the `endpoints` behind it.
+fn create_viot_table(iommu_bdf: u16, endpoints: &[u32]) -> SDT {
+    let mut viot = SDT::new(
+        *b"VIOT",
+        ACPI_HEADER_LEN,
+        VIOT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        VIOT_OEM_REVISION,
+    );
+    let iommu_node_offset = (viot.len() + size_of::<ViotHeader>()) as u16;
+    viot.append(ViotHeader {
+        _node_count: endpoints.len() as u16 + 1,
+        _node_offset: iommu_node_offset,
+        ..Default::default()
+    });
+    viot.append(ViotVirtioIommuPci {
+        _type: VIOT_NODE_VIRTIO_IOMMU_PCI,
+        _length: size_of::<ViotVirtioIommuPci>() as u16,
+        _bdf: iommu_bdf,
+        ..Default::default()
+    });
+    // Each endpoint gets a range of its own, whose endpoint ID is its BDF.
+    for &endpoint in endpoints {
+        viot.append(ViotPciRange {
+            _type: VIOT_NODE_PCI_RANGE,
+            _length: size_of::<ViotPciRange>() as u16,
+            _endpoint_start: endpoint,
+            _bdf_start: endpoint as u16,
+            _bdf_end: endpoint as u16,
+            _output_node: iommu_node_offset,
+            ..Default::default()
+        });
+    }
+    viot
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    queue: Queue,
+    state: IommuState,
+}
+
+impl Worker {
+    fn process_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = self.queue.pop(&self.state.mem) {
+            let index = avail_desc.index;
+            let len = match process_request(avail_desc, &mut self.state) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("virtio-iommu: failed to process request: {}", e);
+                    0
+                }
+            };
+            self.queue.add_used(&self.state.mem, index, len as u32);
+            needs_interrupt = true;
+        }
+        needs_interrupt
+    }
+
+    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
+        #[derive(PollToken)]
+        enum Token {
+            QueueAvailable,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evt, Token::QueueAvailable),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::QueueAvailable => {
+                        if let Err(e) = queue_evt.read() {
+                            error!("failed reading queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt |= self.process_queue();
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => break 'wait,
+                }
+            }
+            if needs_interrupt {
+                self.interrupt.signal_used_queue(self.queue.vector);
+            }
+        }
+    }
+}
+
+/// Virtio device that lets the guest manage the DMA mappings of the endpoints behind it.
+pub struct Iommu {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+    endpoints: BTreeMap<u32, Arc<Mutex<dyn MemoryMapper>>>,
+    features: u64,
+}
+
+impl Iommu {
+    /// Creates a virtio-iommu for `endpoints`, which are keyed by their endpoint ID, the BDF of
+    /// the PCI device. Endpoints can't do DMA until the guest maps memory for them.
+    pub fn new(
+        base_features: u64,
+        endpoints: BTreeMap<u32, Arc<Mutex<dyn MemoryMapper>>>,
+    ) -> Iommu {
+        Iommu {
+            kill_evt: None,
+            worker_thread: None,
+            endpoints,
+            features: base_features
+                | 1 << VIRTIO_IOMMU_F_INPUT_RANGE
+                | 1 << VIRTIO_IOMMU_F_MAP_UNMAP
+                | 1 << VIRTIO_IOMMU_F_PROBE,
+        }
+    }
+
+    fn get_config(&self) -> virtio_iommu_config {
+        virtio_iommu_config {
+            page_size_mask: (!(PAGE_SIZE - 1)).into(),
+            input_range_end: IOVA_END.into(),
+            probe_size: PROBE_SIZE.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for Iommu {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do with a failure.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Iommu {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.endpoints
+            .values()
+            .map(|mapper| mapper.lock().as_raw_descriptor())
+            .collect()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_IOMMU
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_slice(), offset);
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.features &= value;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let state = IommuState::new(mem, &self.endpoints);
+        let queue = queues.remove(0);
+        let queue_evt = queue_evts.remove(0);
+        let worker_result = thread::Builder::new()
+            .name("virtio_iommu".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    interrupt,
+                    queue,
+                    state,
+                };
+                worker.run(queue_evt, kill_evt);
+                // Endpoints lose their mappings when the driver goes away.
+                worker.state.reset();
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_iommu worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            if worker_thread.join().is_err() {
+                error!("{}: failed to get back resources", self.debug_label());
+                return false;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn generate_acpi(&mut self, pci_address: PciAddress, sdts: &mut Vec<SDT>) {
+        let endpoints: Vec<u32> = self.endpoints.keys().cloned().collect();
+        sdts.push(create_viot_table(pci_address.to_bdf(), &endpoints));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the mappings the IOMMU makes, by IOVA.
+    #[derive(Default)]
+    struct FakeMapper {
+        maps: BTreeMap<u64, (u64, u64, bool)>,
+    }
+
+    impl AsRawDescriptor for FakeMapper {
+        fn as_raw_descriptor(&self) -> RawDescriptor {
+            -1
+        }
+    }
+
+    impl MemoryMapper for FakeMapper {
+        unsafe fn add_map(
+            &mut self,
+            iova: u64,
+            host_addr: u64,
+            size: u64,
+            writable: bool,
+        ) -> std::result::Result<(), Box<dyn StdError + Send>> {
+            self.maps.insert(iova, (host_addr, size, writable));
+            Ok(())
+        }
+
+        fn remove_map(
+            &mut self,
+            iova: u64,
+            size: u64,
+        ) -> std::result::Result<(), Box<dyn StdError + Send>> {
+            match self.maps.remove(&iova) {
+                Some((_, mapped_size, _)) if mapped_size == size => Ok(()),
+                _ => Err(Box::new(io::Error::from(io::ErrorKind::NotFound))),
+            }
+        }
+    }
+
+    fn state(endpoints: &[(u32, Arc<Mutex<FakeMapper>>)]) -> IommuState {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let endpoints = endpoints
+            .iter()
+            .map(|(id, mapper)| (*id, mapper.clone() as Arc<Mutex<dyn MemoryMapper>>))
+            .collect();
+        IommuState::new(mem, &endpoints)
+    }
+
+    fn map(domain: u32, virt_start: u64, virt_end: u64, phys_start: u64) -> Request {
+        Request::Map {
+            domain,
+            virt_start,
+            virt_end,
+            phys_start,
+            flags: VIRTIO_IOMMU_MAP_F_READ | VIRTIO_IOMMU_MAP_F_WRITE,
+        }
+    }
+
+    #[test]
+    fn map_unmap() {
+        let mapper = Arc::new(Mutex::new(FakeMapper::default()));
+        let mut state = state(&[(8, mapper.clone())]);
+        let host_addr = state.mem.get_host_address(GuestAddress(0x4000)).unwrap() as u64;
+
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x2fff, 0x4000)),
+            VIRTIO_IOMMU_S_NOENT
+        );
+        assert_eq!(
+            state.handle_request(Request::Attach {
+                domain: 1,
+                endpoint: 8,
+                flags: 0
+            }),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x2fff, 0x4000)),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert_eq!(
+            mapper.lock().maps.get(&0x1000),
+            Some(&(host_addr, 0x2000, true))
+        );
+        // Mappings can't overlap.
+        assert_eq!(
+            state.handle_request(map(1, 0x2000, 0x3fff, 0x8000)),
+            VIRTIO_IOMMU_S_INVAL
+        );
+        // Or be split.
+        assert_eq!(
+            state.handle_request(Request::Unmap {
+                domain: 1,
+                virt_start: 0x1000,
+                virt_end: 0x1fff
+            }),
+            VIRTIO_IOMMU_S_RANGE
+        );
+        assert_eq!(
+            state.handle_request(Request::Unmap {
+                domain: 1,
+                virt_start: 0,
+                virt_end: u64::MAX
+            }),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert!(mapper.lock().maps.is_empty());
+
+        // Not page aligned.
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x1fff, 0x4800)),
+            VIRTIO_IOMMU_S_RANGE
+        );
+        // Past the end of guest memory.
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x1fff, 0x10_0000)),
+            VIRTIO_IOMMU_S_RANGE
+        );
+        assert_eq!(
+            state.handle_request(Request::Attach {
+                domain: 1,
+                endpoint: 9,
+                flags: 0
+            }),
+            VIRTIO_IOMMU_S_NOENT
+        );
+    }
+
+    #[test]
+    fn attach_detach() {
+        let first = Arc::new(Mutex::new(FakeMapper::default()));
+        let second = Arc::new(Mutex::new(FakeMapper::default()));
+        let mut state = state(&[(8, first.clone()), (16, second.clone())]);
+        let attach = |domain, endpoint| Request::Attach {
+            domain,
+            endpoint,
+            flags: 0,
+        };
+
+        assert_eq!(state.handle_request(attach(1, 8)), VIRTIO_IOMMU_S_OK);
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x1fff, 0)),
+            VIRTIO_IOMMU_S_OK
+        );
+        // Joining a domain gives the endpoint its mappings.
+        assert_eq!(state.handle_request(attach(1, 16)), VIRTIO_IOMMU_S_OK);
+        assert!(second.lock().maps.contains_key(&0x1000));
+
+        // Moving to another domain takes them away.
+        assert_eq!(state.handle_request(attach(2, 8)), VIRTIO_IOMMU_S_OK);
+        assert!(first.lock().maps.is_empty());
+        assert_eq!(
+            state.handle_request(Request::Detach {
+                domain: 1,
+                endpoint: 8
+            }),
+            VIRTIO_IOMMU_S_INVAL
+        );
+
+        // The domain goes away with its last endpoint.
+        assert_eq!(
+            state.handle_request(Request::Detach {
+                domain: 1,
+                endpoint: 16
+            }),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert!(second.lock().maps.is_empty());
+        assert_eq!(
+            state.handle_request(map(1, 0x1000, 0x1fff, 0)),
+            VIRTIO_IOMMU_S_NOENT
+        );
+
+        state.reset();
+        assert!(state.domains.is_empty());
+        assert!(state.endpoints.values().all(|ep| ep.domain.is_none()));
+    }
+
+    #[test]
+    fn viot_table() {
+        let viot = create_viot_table(0x10, &[0x100, 0x208]);
+        let table = viot.as_slice();
+        assert_eq!(table.len(), 36 + 12 + 16 + 2 * 24);
+        // Three nodes, starting with the IOMMU right after the VIOT header.
+        assert_eq!(&table[36..40], &[3, 0, 48, 0]);
+        assert_eq!(
+            &table[48..54],
+            &[VIOT_NODE_VIRTIO_IOMMU_PCI, 0, 16, 0, 0, 0]
+        );
+        assert_eq!(&table[54..56], &[0x10, 0]);
+        // The second endpoint's range only covers it, and points at the IOMMU.
+        let range = &table[88..112];
+        assert_eq!(range[0], VIOT_NODE_PCI_RANGE);
+        assert_eq!(&range[4..8], &[0x08, 0x02, 0, 0]);
+        assert_eq!(&range[12..18], &[0x08, 0x02, 0x08, 0x02, 48, 0]);
+    }
+}
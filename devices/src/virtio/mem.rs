@@ -0,0 +1,665 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements the virtio-mem device, which lets the host grow and shrink guest memory in blocks of
+//! a hotplug region with the guest's cooperation.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use futures::{pin_mut, StreamExt};
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+use base::{
+    error, fallocate, info, AsRawDescriptor, Descriptor, Event, FallocateMode, RawDescriptor,
+    SharedMemory,
+};
+use cros_async::{select, EventAsync, Executor};
+use data_model::{DataInit, Le16, Le64};
+use msg_socket::MsgSender;
+use vm_control::{MemControlCommand, MemControlResponseSocket, MemControlResult};
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{
+    copy_config, descriptor_utils, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_MEM,
+};
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum MemError {
+    /// Failed to create async message receiver.
+    #[error("failed to create async message receiver: {0}")]
+    CreatingMessageReceiver(msg_socket::MsgError),
+    /// The hotplug region isn't a whole number of blocks.
+    #[error("region size {0:#x} is not a multiple of the block size")]
+    InvalidRegionSize(u64),
+    /// Failed to receive command message.
+    #[error("failed to receive command message: {0}")]
+    ReceivingCommand(msg_socket::MsgError),
+}
+pub type Result<T> = std::result::Result<T, MemError>;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// The size of the blocks the guest plugs and unplugs. Linux needs at least the size of a
+/// pageblock, which is 2 MiB on x86_64.
+pub const VIRTIO_MEM_BLOCK_SIZE: u64 = 2 << 20;
+
+// Request types.
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+// Response types.
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+// States reported for `VIRTIO_MEM_REQ_STATE`.
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+// virtio_mem_config is the device configuration space defined by the virtio spec.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_config {
+    block_size: Le64,
+    node_id: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    region_size: Le64,
+    usable_region_size: Le64,
+    plugged_size: Le64,
+    requested_size: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_config {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_req {
+    req_type: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    nb_blocks: Le16,
+    padding_1: [u8; 6],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_req {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_resp {
+    resp_type: Le16,
+    padding: [u8; 6],
+    state: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_resp {}
+
+// MemConfig is modified by the worker and read from the device thread.
+struct MemConfig {
+    addr: u64,
+    region_size: u64,
+    plugged_size: AtomicU64,
+    requested_size: AtomicU64,
+}
+
+// Tracks which blocks of the hotplug region the guest has plugged.
+struct Blocks {
+    addr: u64,
+    plugged: Vec<bool>,
+}
+
+impl Blocks {
+    fn new(addr: u64, region_size: u64) -> Blocks {
+        Blocks {
+            addr,
+            plugged: vec![false; (region_size / VIRTIO_MEM_BLOCK_SIZE) as usize],
+        }
+    }
+
+    // Returns the indices of the `nb_blocks` blocks starting at `addr`, if they are all in the
+    // region.
+    fn range(&self, addr: u64, nb_blocks: u16) -> Option<std::ops::Range<usize>> {
+        let offset = addr.checked_sub(self.addr)?;
+        if offset % VIRTIO_MEM_BLOCK_SIZE != 0 || nb_blocks == 0 {
+            return None;
+        }
+        let start = (offset / VIRTIO_MEM_BLOCK_SIZE) as usize;
+        let end = start.checked_add(nb_blocks as usize)?;
+        if end > self.plugged.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    // Handles one guest request, returning the response type and, for state requests, the state.
+    // `discard` is called with the offset and length of every range that gets unplugged.
+    fn handle_request<F>(
+        &mut self,
+        req: &virtio_mem_req,
+        config: &MemConfig,
+        discard: F,
+    ) -> (u16, u16)
+    where
+        F: FnOnce(u64, u64),
+    {
+        let req_type = req.req_type.to_native();
+        if req_type == VIRTIO_MEM_REQ_UNPLUG_ALL {
+            self.plugged.iter_mut().for_each(|b| *b = false);
+            config.plugged_size.store(0, Ordering::Relaxed);
+            discard(0, config.region_size);
+            return (VIRTIO_MEM_RESP_ACK, 0);
+        }
+
+        let range = match self.range(req.addr.to_native(), req.nb_blocks.to_native()) {
+            Some(r) => r,
+            None => return (VIRTIO_MEM_RESP_ERROR, 0),
+        };
+        let size = range.len() as u64 * VIRTIO_MEM_BLOCK_SIZE;
+        let blocks = &mut self.plugged[range.clone()];
+        match req_type {
+            VIRTIO_MEM_REQ_PLUG => {
+                if blocks.iter().any(|&b| b) {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                let plugged_size = config.plugged_size.load(Ordering::Relaxed);
+                if plugged_size + size > config.requested_size.load(Ordering::Relaxed) {
+                    return (VIRTIO_MEM_RESP_NACK, 0);
+                }
+                blocks.iter_mut().for_each(|b| *b = true);
+                config
+                    .plugged_size
+                    .store(plugged_size + size, Ordering::Relaxed);
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_UNPLUG => {
+                if blocks.iter().any(|&b| !b) {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                blocks.iter_mut().for_each(|b| *b = false);
+                config.plugged_size.fetch_sub(size, Ordering::Relaxed);
+                discard(range.start as u64 * VIRTIO_MEM_BLOCK_SIZE, size);
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_STATE => {
+                let state = if blocks.iter().all(|&b| b) {
+                    VIRTIO_MEM_STATE_PLUGGED
+                } else if blocks.iter().all(|&b| !b) {
+                    VIRTIO_MEM_STATE_UNPLUGGED
+                } else {
+                    VIRTIO_MEM_STATE_MIXED
+                };
+                (VIRTIO_MEM_RESP_ACK, state)
+            }
+            _ => (VIRTIO_MEM_RESP_ERROR, 0),
+        }
+    }
+}
+
+// Reads one request from `avail_desc`, handles it and writes the response back. Returns the number
+// of bytes written.
+fn process_request<F>(
+    avail_desc: DescriptorChain,
+    mem: &GuestMemory,
+    blocks: &mut Blocks,
+    config: &MemConfig,
+    discard: F,
+) -> descriptor_utils::Result<usize>
+where
+    F: FnOnce(u64, u64),
+{
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone())?;
+    let mut writer = Writer::new(mem.clone(), avail_desc)?;
+    let req: virtio_mem_req = reader
+        .read_obj()
+        .map_err(descriptor_utils::Error::IoError)?;
+    let (resp_type, state) = blocks.handle_request(&req, config, discard);
+    writer
+        .write_obj(virtio_mem_resp {
+            resp_type: resp_type.into(),
+            state: state.into(),
+            ..Default::default()
+        })
+        .map_err(descriptor_utils::Error::IoError)?;
+    Ok(writer.bytes_written())
+}
+
+// Gives the memory backing unplugged blocks back to the host. The guest doesn't use them, and they
+// read as zeros if they are plugged again.
+fn discard_range(memory: &SharedMemory, offset: u64, len: u64) {
+    let fd = Descriptor(memory.as_raw_descriptor());
+    if let Err(e) = fallocate(&fd, FallocateMode::PunchHole, true, offset, len) {
+        error!("virtio-mem: failed to discard unplugged memory: {}", e);
+    }
+}
+
+// Async task that handles the guest request queue.
+async fn handle_queue(
+    mem: &GuestMemory,
+    queue: &mut Queue,
+    mut queue_event: EventAsync,
+    interrupt: Rc<RefCell<Interrupt>>,
+    config: &MemConfig,
+    memory: &SharedMemory,
+) {
+    let mut blocks = Blocks::new(config.addr, config.region_size);
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        let discard = |offset, len| discard_range(memory, offset, len);
+        let len = match process_request(avail_desc, mem, &mut blocks, config, discard) {
+            Ok(len) => len,
+            Err(e) => {
+                error!("virtio-mem: failed to process request: {}", e);
+                0
+            }
+        };
+        queue.add_used(mem, index, len as u32);
+        interrupt.borrow_mut().signal_used_queue(queue.vector);
+    }
+}
+
+// Async task that handles the command socket, which asks for the guest's plugged size to change.
+async fn handle_command_socket(
+    ex: &Executor,
+    command_socket: &MemControlResponseSocket,
+    interrupt: Rc<RefCell<Interrupt>>,
+    config: &MemConfig,
+) -> Result<()> {
+    let mut async_messages = command_socket
+        .async_receiver(ex)
+        .map_err(MemError::CreatingMessageReceiver)?;
+    loop {
+        match async_messages.next().await {
+            Ok(MemControlCommand::Resize { num_bytes }) => {
+                let requested_bytes = num_bytes.min(config.region_size) / VIRTIO_MEM_BLOCK_SIZE
+                    * VIRTIO_MEM_BLOCK_SIZE;
+                info!("virtio-mem requested size changed to {}", requested_bytes);
+                config
+                    .requested_size
+                    .store(requested_bytes, Ordering::Relaxed);
+                interrupt.borrow_mut().signal_config_changed();
+                let result = MemControlResult::Resized {
+                    requested_bytes,
+                    plugged_bytes: config.plugged_size.load(Ordering::Relaxed),
+                };
+                if let Err(e) = command_socket.send(&result) {
+                    error!("failed to send resize result: {}", e);
+                }
+            }
+            Err(e) => return Err(MemError::ReceivingCommand(e)),
+        }
+    }
+}
+
+// Async task that resamples the status of the interrupt when the guest sends a request by
+// signalling the resample event associated with the interrupt.
+async fn handle_irq_resample(ex: &Executor, interrupt: Rc<RefCell<Interrupt>>) {
+    let resample_evt = interrupt
+        .borrow_mut()
+        .get_resample_evt()
+        .try_clone()
+        .unwrap();
+    let resample_evt = EventAsync::new(resample_evt.0, ex).unwrap();
+    while resample_evt.next_val().await.is_ok() {
+        interrupt.borrow_mut().do_interrupt_resample();
+    }
+}
+
+// The main worker thread. Runs the request queue and the command socket until the kill event is
+// signaled.
+fn run_worker(
+    queue_evt: Event,
+    mut queue: Queue,
+    command_socket: &MemControlResponseSocket,
+    interrupt: Interrupt,
+    kill_evt: Event,
+    mem: GuestMemory,
+    config: Arc<MemConfig>,
+    memory: &SharedMemory,
+) {
+    // Wrap the interrupt in a `RefCell` so it can be shared between async functions.
+    let interrupt = Rc::new(RefCell::new(interrupt));
+
+    let ex = Executor::new().unwrap();
+
+    // Nothing is plugged when the driver starts, so don't keep memory from an earlier driver.
+    config.plugged_size.store(0, Ordering::Relaxed);
+    discard_range(memory, 0, config.region_size);
+
+    let queue_evt = EventAsync::new(queue_evt.0, &ex).expect("failed to set up the queue event");
+    let requests = handle_queue(
+        &mem,
+        &mut queue,
+        queue_evt,
+        interrupt.clone(),
+        &config,
+        memory,
+    );
+    pin_mut!(requests);
+
+    let command = handle_command_socket(&ex, command_socket, interrupt.clone(), &config);
+    pin_mut!(command);
+
+    let resample = handle_irq_resample(&ex, interrupt);
+    pin_mut!(resample);
+
+    let kill_evt = EventAsync::new(kill_evt.0, &ex).expect("failed to set up the kill event");
+    let kill = async move {
+        let _ = kill_evt.next_val().await;
+    };
+    pin_mut!(kill);
+
+    if let Err(e) = ex.run_until(select!(requests, command, resample, kill)) {
+        error!("error happened in executor: {}", e);
+    }
+}
+
+/// Virtio device for plugging and unplugging guest memory.
+pub struct Mem {
+    command_socket: Option<MemControlResponseSocket>,
+    config: Arc<MemConfig>,
+    memory: Arc<SharedMemory>,
+    features: u64,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<MemControlResponseSocket>>,
+}
+
+impl Mem {
+    /// Creates a new virtio-mem device for the hotplug region at `addr`, which is backed by
+    /// `memory` and is `region_size` bytes long. Nothing is plugged until the host asks for it.
+    pub fn new(
+        base_features: u64,
+        command_socket: MemControlResponseSocket,
+        memory: SharedMemory,
+        addr: GuestAddress,
+        region_size: u64,
+    ) -> Result<Mem> {
+        if region_size % VIRTIO_MEM_BLOCK_SIZE != 0 {
+            return Err(MemError::InvalidRegionSize(region_size));
+        }
+        Ok(Mem {
+            command_socket: Some(command_socket),
+            config: Arc::new(MemConfig {
+                addr: addr.offset(),
+                region_size,
+                plugged_size: AtomicU64::new(0),
+                requested_size: AtomicU64::new(0),
+            }),
+            memory: Arc::new(memory),
+            features: base_features,
+            kill_evt: None,
+            worker_thread: None,
+        })
+    }
+
+    fn get_config(&self) -> virtio_mem_config {
+        virtio_mem_config {
+            block_size: VIRTIO_MEM_BLOCK_SIZE.into(),
+            addr: self.config.addr.into(),
+            region_size: self.config.region_size.into(),
+            usable_region_size: self.config.region_size.into(),
+            plugged_size: self.config.plugged_size.load(Ordering::Relaxed).into(),
+            requested_size: self.config.requested_size.load(Ordering::Relaxed).into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for Mem {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do with a failure.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Mem {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![
+            self.command_socket.as_ref().unwrap().as_raw_descriptor(),
+            self.memory.as_raw_descriptor(),
+        ]
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_MEM
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_slice(), offset);
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.features &= value;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let config = self.config.clone();
+        let memory = self.memory.clone();
+        let command_socket = self.command_socket.take().unwrap();
+        let queue = queues.remove(0);
+        let queue_evt = queue_evts.remove(0);
+        let worker_result =
+            thread::Builder::new()
+                .name("virtio_mem".to_string())
+                .spawn(move || {
+                    run_worker(
+                        queue_evt,
+                        queue,
+                        &command_socket,
+                        interrupt,
+                        kill_evt,
+                        mem,
+                        config,
+                        &memory,
+                    );
+                    command_socket // Return the command socket so it can be re-used.
+                });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_mem worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(command_socket) => {
+                    self.command_socket = Some(command_socket);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn uses_async_executor(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION_ADDR: u64 = 0x1_0000_0000;
+
+    fn config(requested_size: u64) -> MemConfig {
+        MemConfig {
+            addr: REGION_ADDR,
+            region_size: 8 * VIRTIO_MEM_BLOCK_SIZE,
+            plugged_size: AtomicU64::new(0),
+            requested_size: AtomicU64::new(requested_size),
+        }
+    }
+
+    fn req(req_type: u16, block: u64, nb_blocks: u16) -> virtio_mem_req {
+        virtio_mem_req {
+            req_type: req_type.into(),
+            addr: (REGION_ADDR + block * VIRTIO_MEM_BLOCK_SIZE).into(),
+            nb_blocks: nb_blocks.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plug_unplug() {
+        let config = config(4 * VIRTIO_MEM_BLOCK_SIZE);
+        let mut blocks = Blocks::new(config.addr, config.region_size);
+        let no_discard = |_, _| panic!("nothing should be discarded");
+
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_PLUG, 2, 3), &config, no_discard),
+            (VIRTIO_MEM_RESP_ACK, 0)
+        );
+        assert_eq!(
+            config.plugged_size.load(Ordering::Relaxed),
+            3 * VIRTIO_MEM_BLOCK_SIZE
+        );
+        // Plugging more than the requested size is refused.
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_PLUG, 5, 2), &config, no_discard),
+            (VIRTIO_MEM_RESP_NACK, 0)
+        );
+        // So is plugging a block twice.
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_PLUG, 4, 1), &config, no_discard),
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        );
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_STATE, 1, 2), &config, no_discard),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_MIXED)
+        );
+
+        let mut discarded = None;
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_UNPLUG, 3, 1), &config, |offset, len| {
+                discarded = Some((offset, len))
+            }),
+            (VIRTIO_MEM_RESP_ACK, 0)
+        );
+        assert_eq!(
+            discarded,
+            Some((3 * VIRTIO_MEM_BLOCK_SIZE, VIRTIO_MEM_BLOCK_SIZE))
+        );
+        assert_eq!(
+            config.plugged_size.load(Ordering::Relaxed),
+            2 * VIRTIO_MEM_BLOCK_SIZE
+        );
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_STATE, 3, 1), &config, no_discard),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_STATE, 2, 1), &config, no_discard),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_PLUGGED)
+        );
+
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0), &config, |_, _| {}),
+            (VIRTIO_MEM_RESP_ACK, 0)
+        );
+        assert_eq!(config.plugged_size.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let config = config(8 * VIRTIO_MEM_BLOCK_SIZE);
+        let mut blocks = Blocks::new(config.addr, config.region_size);
+        let no_discard = |_, _| panic!("nothing should be discarded");
+
+        // Past the end of the region.
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_PLUG, 7, 2), &config, no_discard),
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        );
+        // Before the start of the region.
+        let mut before = req(VIRTIO_MEM_REQ_PLUG, 0, 1);
+        before.addr = (REGION_ADDR - VIRTIO_MEM_BLOCK_SIZE).into();
+        assert_eq!(
+            blocks.handle_request(&before, &config, no_discard),
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        );
+        // Not block aligned.
+        let mut unaligned = req(VIRTIO_MEM_REQ_PLUG, 0, 1);
+        unaligned.addr = (REGION_ADDR + 0x1000).into();
+        assert_eq!(
+            blocks.handle_request(&unaligned, &config, no_discard),
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        );
+        // Unplugging blocks that aren't plugged.
+        assert_eq!(
+            blocks.handle_request(&req(VIRTIO_MEM_REQ_UNPLUG, 0, 1), &config, no_discard),
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        );
+    }
+}
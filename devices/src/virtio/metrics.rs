@@ -0,0 +1,183 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Per-device counters reported by `crosvm stats devices`.
+//!
+//! The counters live in an anonymous shared mapping that is created before the device processes
+//! are forked, so the main process sees what the sandboxed devices write without any messages
+//! being passed.
+
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use base::{warn, MappedRegion, MemoryMapping, MemoryMappingBuilder, MmapError};
+use sync::Mutex;
+use vm_control::DeviceStats;
+
+/// The most devices a `MetricsRegistry` keeps counters for.
+pub const MAX_METERED_DEVICES: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Counter {
+    Requests,
+    Bytes,
+    Interrupts,
+    QueueDepth,
+    MaxQueueDepth,
+}
+
+const NUM_COUNTERS: usize = 5;
+const SLOT_SIZE: usize = NUM_COUNTERS * size_of::<u64>();
+
+fn counter(mapping: &MemoryMapping, slot: usize, counter: Counter) -> &AtomicU64 {
+    let offset = slot * SLOT_SIZE + counter as usize * size_of::<u64>();
+    assert!(offset + size_of::<u64>() <= mapping.size());
+    // Safe because the offset is inside the mapping and 8-byte aligned since the mapping is page
+    // aligned, and the returned reference can't outlive the mapping.
+    unsafe { &*(mapping.as_ptr().add(offset) as *const AtomicU64) }
+}
+
+/// Hands out counters to devices and reads them back.
+pub struct MetricsRegistry {
+    mapping: Arc<MemoryMapping>,
+    devices: Mutex<Vec<String>>,
+}
+
+impl MetricsRegistry {
+    /// Creates a registry with room for `MAX_METERED_DEVICES` devices.
+    pub fn new() -> Result<MetricsRegistry, MmapError> {
+        let mapping = MemoryMappingBuilder::new(MAX_METERED_DEVICES * SLOT_SIZE).build()?;
+        Ok(MetricsRegistry {
+            mapping: Arc::new(mapping),
+            devices: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns the counters for a new device labeled `device`. If the registry is full, the
+    /// returned counters are discarded.
+    pub fn register(&self, device: String) -> DeviceMetrics {
+        let mut devices = self.devices.lock();
+        if devices.len() == MAX_METERED_DEVICES {
+            warn!("no room left for the counters of {}", device);
+            return DeviceMetrics::default();
+        }
+        let slot = devices.len();
+        devices.push(device);
+        DeviceMetrics {
+            slot: Some((self.mapping.clone(), slot)),
+        }
+    }
+
+    /// Reads the current counters of every registered device, in registration order.
+    pub fn snapshot(&self) -> Vec<DeviceStats> {
+        let load = |slot, c| counter(&self.mapping, slot, c).load(Ordering::Relaxed);
+        self.devices
+            .lock()
+            .iter()
+            .enumerate()
+            .map(|(slot, device)| DeviceStats {
+                device: device.clone(),
+                requests: load(slot, Counter::Requests),
+                bytes: load(slot, Counter::Bytes),
+                interrupts: load(slot, Counter::Interrupts),
+                queue_depth: load(slot, Counter::QueueDepth),
+                max_queue_depth: load(slot, Counter::MaxQueueDepth),
+            })
+            .collect()
+    }
+}
+
+/// The counters of a single device. The default value drops every update, for devices that aren't
+/// registered.
+#[derive(Clone, Default)]
+pub struct DeviceMetrics {
+    slot: Option<(Arc<MemoryMapping>, usize)>,
+}
+
+impl DeviceMetrics {
+    fn counter(&self, c: Counter) -> Option<&AtomicU64> {
+        self.slot
+            .as_ref()
+            .map(|(mapping, slot)| counter(mapping, *slot, c))
+    }
+
+    /// Records a completed request that moved `bytes` bytes.
+    pub fn add_request(&self, bytes: u64) {
+        if let Some(requests) = self.counter(Counter::Requests) {
+            requests.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(total) = self.counter(Counter::Bytes) {
+            total.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an interrupt injected into the guest.
+    pub fn add_interrupt(&self) {
+        if let Some(interrupts) = self.counter(Counter::Interrupts) {
+            interrupts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records how many requests are waiting in a queue.
+    pub fn set_queue_depth(&self, depth: u64) {
+        if let Some(queue_depth) = self.counter(Counter::QueueDepth) {
+            queue_depth.store(depth, Ordering::Relaxed);
+        }
+        if let Some(max_queue_depth) = self.counter(Counter::MaxQueueDepth) {
+            max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let registry = MetricsRegistry::new().unwrap();
+        let block = registry.register("block".to_owned());
+        let net = registry.register("net".to_owned());
+
+        block.add_request(4096);
+        block.add_request(512);
+        block.add_interrupt();
+        block.set_queue_depth(3);
+        block.set_queue_depth(1);
+        net.clone().add_request(1500);
+        DeviceMetrics::default().add_request(1);
+
+        assert_eq!(
+            registry.snapshot(),
+            vec![
+                DeviceStats {
+                    device: "block".to_owned(),
+                    requests: 2,
+                    bytes: 4608,
+                    interrupts: 1,
+                    queue_depth: 1,
+                    max_queue_depth: 3,
+                },
+                DeviceStats {
+                    device: "net".to_owned(),
+                    requests: 1,
+                    bytes: 1500,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn full_registry() {
+        let registry = MetricsRegistry::new().unwrap();
+        for i in 0..MAX_METERED_DEVICES {
+            registry.register(format!("dev{}", i));
+        }
+        let extra = registry.register("extra".to_owned());
+        extra.add_request(1);
+        assert_eq!(registry.snapshot().len(), MAX_METERED_DEVICES);
+    }
+}
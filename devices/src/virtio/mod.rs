@@ -10,13 +10,19 @@ mod console;
 mod descriptor_utils;
 mod input;
 mod interrupt;
+mod iommu;
+mod mem;
+mod metrics;
 mod net;
 mod p9;
 mod pmem;
 mod queue;
 mod rng;
 #[cfg(feature = "tpm")]
+mod swtpm;
+#[cfg(feature = "tpm")]
 mod tpm;
+mod vhost_user_backend;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 mod video;
 mod virtio_device;
@@ -41,13 +47,19 @@ pub use self::descriptor_utils::*;
 pub use self::gpu::*;
 pub use self::input::*;
 pub use self::interrupt::*;
+pub use self::iommu::*;
+pub use self::mem::*;
+pub use self::metrics::*;
 pub use self::net::*;
 pub use self::p9::*;
 pub use self::pmem::*;
 pub use self::queue::*;
 pub use self::rng::*;
 #[cfg(feature = "tpm")]
+pub use self::swtpm::{Error as SwtpmError, Swtpm};
+#[cfg(feature = "tpm")]
 pub use self::tpm::*;
+pub use self::vhost_user_backend::{Error as VhostUserBackendError, VhostUserBackend};
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 pub use self::video::*;
 pub use self::virtio_device::*;
@@ -62,6 +74,7 @@ const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
 const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
+const DEVICE_NEEDS_RESET: u32 = 0x40;
 const DEVICE_FAILED: u32 = 0x80;
 
 // Types taken from linux/virtio_ids.h
@@ -80,6 +93,8 @@ const TYPE_INPUT: u32 = 18;
 const TYPE_VSOCK: u32 = 19;
 const TYPE_CRYPTO: u32 = 20;
 const TYPE_IOMMU: u32 = 23;
+const TYPE_MEM: u32 = 24;
+const TYPE_SOUND: u32 = 25;
 const TYPE_FS: u32 = 26;
 const TYPE_PMEM: u32 = 27;
 const TYPE_VIDEO_ENC: u32 = 30;
@@ -119,6 +134,8 @@ pub fn type_to_str(type_: u32) -> Option<&'static str> {
         TYPE_VSOCK => "vsock",
         TYPE_CRYPTO => "crypto",
         TYPE_IOMMU => "iommu",
+        TYPE_MEM => "mem",
+        TYPE_SOUND => "sound",
         TYPE_FS => "fs",
         TYPE_PMEM => "pmem",
         TYPE_WL => "wl",
@@ -129,6 +146,11 @@ pub fn type_to_str(type_: u32) -> Option<&'static str> {
     })
 }
 
+/// Returns the virtio device type number named `s` by `type_to_str`.
+pub fn str_to_type(s: &str) -> Option<u32> {
+    (0..=MAX_VIRTIO_DEVICE_ID).find(|&type_| type_to_str(type_) == Some(s))
+}
+
 /// Copy virtio device configuration data from a subslice of `src` to a subslice of `dst`.
 /// Unlike std::slice::copy_from_slice(), this function copies as much as possible within
 /// the common subset of the two slices, truncating the requested range instead of
@@ -7,6 +7,7 @@ use std::io::{self, Write};
 use std::mem;
 use std::net::Ipv4Addr;
 use std::os::raw::c_uint;
+use std::panic::{self, AssertUnwindSafe};
 use std::result;
 use std::sync::Arc;
 use std::thread;
@@ -23,7 +24,8 @@ use virtio_sys::virtio_net::{
 use vm_memory::GuestMemory;
 
 use super::{
-    copy_config, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_NET,
+    copy_config, DescriptorError, DeviceMetrics, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_NET,
 };
 
 const QUEUE_SIZE: u16 = 256;
@@ -154,6 +156,7 @@ struct Worker<T: TapT> {
     acked_features: u64,
     vq_pairs: u16,
     kill_evt: Event,
+    metrics: DeviceMetrics,
 }
 
 impl<T> Worker<T>
@@ -161,6 +164,7 @@ where
     T: TapT,
 {
     fn process_rx(&mut self) -> result::Result<(), NetError> {
+        let _trace = cros_tracing::trace_event!(Net, "virtio_net rx");
         let mut needs_interrupt = false;
         let mut exhausted_queue = false;
 
@@ -202,6 +206,7 @@ where
             };
 
             if bytes_written > 0 {
+                self.metrics.add_request(bytes_written.into());
                 self.rx_queue.pop_peeked(&self.mem);
                 self.rx_queue.add_used(&self.mem, index, bytes_written);
                 needs_interrupt = true;
@@ -220,6 +225,7 @@ where
     }
 
     fn process_tx(&mut self) {
+        let _trace = cros_tracing::trace_event!(Net, "virtio_net tx");
         while let Some(desc_chain) = self.tx_queue.pop(&self.mem) {
             let index = desc_chain.index;
 
@@ -236,6 +242,7 @@ where
                                     count, expected_count
                                 );
                             }
+                            self.metrics.add_request(count as u64);
                         }
                         Err(e) => error!("net: tx: failed to write frame to tap: {}", e),
                     }
@@ -357,6 +364,8 @@ where
         }
 
         let mut tap_polling_enabled = true;
+        // Any other way out of the loop leaves the device unusable until the driver resets it.
+        let mut killed = false;
         'wait: loop {
             let events = wait_ctx.wait().map_err(NetError::WaitError)?;
             for event in events.iter().filter(|e| e.is_readable) {
@@ -409,11 +418,15 @@ where
                     }
                     Token::Kill => {
                         let _ = self.kill_evt.read();
+                        killed = true;
                         break 'wait;
                     }
                 }
             }
         }
+        if !killed {
+            self.interrupt.signal_needs_reset();
+        }
         Ok(())
     }
 }
@@ -426,6 +439,7 @@ pub struct Net<T: TapT> {
     taps: Vec<T>,
     avail_features: u64,
     acked_features: u64,
+    metrics: DeviceMetrics,
 }
 
 impl<T> Net<T>
@@ -496,6 +510,7 @@ where
             taps,
             avail_features,
             acked_features: 0u64,
+            metrics: DeviceMetrics::default(),
         })
     }
 
@@ -636,6 +651,10 @@ where
         copy_config(data, 0, config_space.as_slice(), offset);
     }
 
+    fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -672,6 +691,7 @@ where
             let interrupt = interrupt_arc.clone();
             let memory = mem.clone();
             let kill_evt = self.workers_kill_evt.remove(0);
+            let metrics = self.metrics.clone();
             // Queues alternate between rx0, tx0, rx1, tx1, ..., rxN, txN, ctrl.
             let rx_queue = queues.remove(0);
             let tx_queue = queues.remove(0);
@@ -701,10 +721,23 @@ where
                         acked_features,
                         vq_pairs: pairs,
                         kill_evt,
+                        metrics,
                     };
-                    let result = worker.run(rx_queue_evt, tx_queue_evt, ctrl_queue_evt);
-                    if let Err(e) = result {
-                        error!("net worker thread exited with error: {}", e);
+                    // Keep the worker, and with it the tap, if it panics so the device can be
+                    // activated again once the driver resets it.
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        worker.run(rx_queue_evt, tx_queue_evt, ctrl_queue_evt)
+                    }));
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            error!("net worker thread exited with error: {}", e);
+                            worker.interrupt.signal_needs_reset();
+                        }
+                        Err(_) => {
+                            error!("net worker thread panicked");
+                            worker.interrupt.signal_needs_reset();
+                        }
                     }
                     worker
                 });
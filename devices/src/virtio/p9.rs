@@ -8,7 +8,10 @@ use std::mem;
 use std::result;
 use std::thread;
 
-use base::{error, warn, Error as SysError, Event, PollToken, RawDescriptor, WaitContext};
+use base::{
+    error, warn, AsRawDescriptor, Error as SysError, Event, LandlockRuleset, PollToken,
+    RawDescriptor, WaitContext,
+};
 use vm_memory::GuestMemory;
 
 use super::{
@@ -148,6 +151,7 @@ pub struct P9 {
     avail_features: u64,
     acked_features: u64,
     worker: Option<thread::JoinHandle<P9Result<()>>>,
+    landlock_ruleset: Option<LandlockRuleset>,
 }
 
 impl P9 {
@@ -171,16 +175,36 @@ impl P9 {
             avail_features: base_features | 1 << VIRTIO_9P_MOUNT_TAG,
             acked_features: 0,
             worker: None,
+            landlock_ruleset: None,
         })
     }
+
+    /// Restricts the files the device can open to those `ruleset` allows, once it runs in its
+    /// sandbox.
+    pub fn set_landlock_ruleset(&mut self, ruleset: LandlockRuleset) {
+        self.landlock_ruleset = Some(ruleset);
+    }
 }
 
 impl VirtioDevice for P9 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        self.server
+        let mut keep_rds = self
+            .server
             .as_ref()
             .map(p9::Server::keep_fds)
-            .unwrap_or_else(Vec::new)
+            .unwrap_or_else(Vec::new);
+        if let Some(ruleset) = &self.landlock_ruleset {
+            keep_rds.push(ruleset.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn on_device_sandboxed(&mut self) {
+        if let Some(ruleset) = self.landlock_ruleset.take() {
+            if let Err(e) = ruleset.restrict_self() {
+                error!("failed to restrict the files the 9p device can open: {}", e);
+            }
+        }
     }
 
     fn device_type(&self) -> u32 {
@@ -8,12 +8,13 @@ use std::num::Wrapping;
 use std::rc::Rc;
 use std::sync::atomic::{fence, Ordering};
 
-use base::error;
+use base::{error, RawDescriptor};
 use cros_async::{AsyncError, EventAsync};
+use msg_socket::MsgOnSocket;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{GuestAddress, GuestMemory};
 
-use super::{Interrupt, VIRTIO_MSI_NO_VECTOR};
+use super::{DeviceMetrics, Interrupt, VIRTIO_MSI_NO_VECTOR};
 
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 const VIRTQ_DESC_F_WRITE: u16 = 0x2;
@@ -234,6 +235,24 @@ pub struct Queue {
     // processing requests. This is the count of how many are in flight(could be several contexts
     // handling requests in parallel). When this count is zero, notifications are re-enabled.
     notification_disable_count: usize,
+
+    // Where the number of available descriptors is recorded each time one is peeked.
+    metrics: DeviceMetrics,
+}
+
+/// The driver-visible state of a `Queue` and its position in the rings, saved in a VM snapshot.
+#[derive(MsgOnSocket)]
+pub struct QueueSnapshot {
+    size: u16,
+    ready: bool,
+    vector: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    next_avail: u16,
+    next_used: u16,
+    features: u64,
+    last_used: u16,
 }
 
 impl Queue {
@@ -252,15 +271,59 @@ impl Queue {
             features: 0,
             last_used: Wrapping(0),
             notification_disable_count: 0,
+            metrics: DeviceMetrics::default(),
         }
     }
 
+    /// Records the depth of this queue in `metrics` whenever it is peeked.
+    pub fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        self.metrics = metrics;
+    }
+
     /// Return the actual size of the queue, as the driver may not set up a
     /// queue as big as the device allows.
     pub fn actual_size(&self) -> u16 {
         min(self.size, self.max_size)
     }
 
+    /// Starts the queue at ring `index` rather than at the beginning of the rings, to take over
+    /// rings someone else stopped processing with every request completed.
+    pub fn set_next_index(&mut self, index: u16) {
+        self.next_avail = Wrapping(index);
+        self.next_used = Wrapping(index);
+        self.last_used = Wrapping(index);
+    }
+
+    /// Saves the state of the queue for a VM snapshot. The queue must not be in use by a device.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            size: self.size,
+            ready: self.ready,
+            vector: self.vector,
+            desc_table: self.desc_table.offset(),
+            avail_ring: self.avail_ring.offset(),
+            used_ring: self.used_ring.offset(),
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            features: self.features,
+            last_used: self.last_used.0,
+        }
+    }
+
+    /// Loads the state saved by `snapshot`.
+    pub fn restore(&mut self, snapshot: &QueueSnapshot) {
+        self.size = snapshot.size;
+        self.ready = snapshot.ready;
+        self.vector = snapshot.vector;
+        self.desc_table = GuestAddress(snapshot.desc_table);
+        self.avail_ring = GuestAddress(snapshot.avail_ring);
+        self.used_ring = GuestAddress(snapshot.used_ring);
+        self.next_avail = Wrapping(snapshot.next_avail);
+        self.next_used = Wrapping(snapshot.next_used);
+        self.features = snapshot.features;
+        self.last_used = Wrapping(snapshot.last_used);
+    }
+
     /// Reset queue to a clean state
     pub fn reset(&mut self) {
         self.ready = false;
@@ -415,7 +478,11 @@ impl Queue {
         let avail_index = self.get_avail_index(mem);
         let avail_len = avail_index - self.next_avail;
 
-        if avail_len.0 > queue_size || self.next_avail == avail_index {
+        if avail_len.0 > queue_size {
+            return None;
+        }
+        self.metrics.set_queue_depth(avail_len.0.into());
+        if self.next_avail == avail_index {
             return None;
         }
 
@@ -490,6 +557,26 @@ impl Queue {
         self.set_used_index(mem, self.next_used);
     }
 
+    /// Returns every descriptor chain the driver made available but the device hasn't popped, so
+    /// the driver's buffers aren't leaked when a worker stops before processing them.
+    ///
+    /// `complete` is called with each chain before it is put in the used ring and returns the
+    /// number of bytes it wrote to the chain, e.g. to fill in an error status. Returns the number
+    /// of chains given back to the driver.
+    pub fn drain<F>(&mut self, mem: &GuestMemory, mut complete: F) -> usize
+    where
+        F: FnMut(DescriptorChain) -> u32,
+    {
+        let mut count = 0;
+        while let Some(chain) = self.pop(mem) {
+            let index = chain.index;
+            let len = complete(chain);
+            self.add_used(mem, index, len);
+            count += 1;
+        }
+        count
+    }
+
     /// Enable / Disable guest notify device that requests are available on
     /// the descriptor chain.
     pub fn set_notify(&mut self, mem: &GuestMemory, enable: bool) {
@@ -680,6 +767,56 @@ mod tests {
         queue.ack_features((1u64) << VIRTIO_RING_F_EVENT_IDX);
     }
 
+    #[test]
+    fn drain_returns_available_chains() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&vec![(GuestAddress(0x0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.ready = true;
+
+        // Offer the same descriptor twice.
+        let mut avail = Avail::default();
+        avail.idx = Le16::from(2u16);
+        mem.write_obj_at_addr(avail, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+
+        let mut indexes = Vec::new();
+        let drained = queue.drain(&mem, |chain| {
+            indexes.push(chain.index);
+            1
+        });
+        assert_eq!(drained, 2);
+        assert_eq!(indexes, [0, 0]);
+
+        let used: Used = mem.read_obj_from_addr(GuestAddress(USED_OFFSET)).unwrap();
+        assert_eq!(used.idx.to_native(), 2);
+        assert_eq!(used.used_elem_ring[1].len.to_native(), 1);
+
+        // Nothing is left to give back.
+        assert_eq!(queue.drain(&mem, |_| 0), 0);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&vec![(GuestAddress(0x0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.ready = true;
+        queue.ack_features(1 << VIRTIO_RING_F_EVENT_IDX);
+        for _ in 0..3 {
+            queue.add_used(&mem, 0x0, BUFFER_LEN);
+        }
+
+        let mut restored = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        restored.restore(&queue.snapshot());
+        assert!(restored.ready);
+        assert!(restored.is_valid(&mem));
+        assert_eq!(restored.desc_table, queue.desc_table);
+        assert_eq!(restored.used_ring, queue.used_ring);
+        assert_eq!(restored.next_used, Wrapping(3));
+        assert_eq!(restored.features, queue.features);
+    }
+
     #[test]
     fn queue_event_id_guest_fast() {
         let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
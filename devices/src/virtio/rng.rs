@@ -2,12 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cmp::min;
 use std::fmt::{self, Display};
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
 
-use base::{error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
+use base::{error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, Timer, WaitContext};
+use rand_ish::SimpleRng;
 use vm_memory::GuestMemory;
 
 use super::{Interrupt, Queue, VirtioDevice, Writer, TYPE_RNG};
@@ -15,10 +19,15 @@ use super::{Interrupt, Queue, VirtioDevice, Writer, TYPE_RNG};
 const QUEUE_SIZE: u16 = 256;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
+// The period over which `RngParameters::rate` is enforced.
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub enum RngError {
-    /// Can't access /dev/urandom
+    /// Can't access the entropy source
     AccessingRandomDev(io::Error),
+    /// Can't create the rate limiting timer
+    CreatingTimer(base::Error),
 }
 pub type Result<T> = std::result::Result<T, RngError>;
 
@@ -27,16 +36,102 @@ impl Display for RngError {
         use self::RngError::*;
 
         match self {
-            AccessingRandomDev(e) => write!(f, "failed to access /dev/urandom: {}", e),
+            AccessingRandomDev(e) => write!(f, "failed to access the entropy source: {}", e),
+            CreatingTimer(e) => write!(f, "failed to create the rate limiting timer: {}", e),
         }
     }
 }
 
+/// Where the rng device gets the bytes it hands to the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RngSource {
+    /// Read from a host file or device, such as /dev/urandom or /dev/hwrng.
+    File(PathBuf),
+    /// Generate bytes inside crosvm from `seed`, or from a seed read from /dev/urandom if unset.
+    /// This is not cryptographically secure and is meant for tests that need repeatable guest
+    /// entropy.
+    Builtin { seed: Option<u64> },
+}
+
+impl Default for RngSource {
+    fn default() -> RngSource {
+        RngSource::File(PathBuf::from("/dev/urandom"))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RngParameters {
+    pub source: RngSource,
+    /// The most bytes handed to the guest per second, or no limit if unset.
+    pub rate: Option<u64>,
+}
+
+enum Entropy {
+    File(File),
+    Builtin(SimpleRng),
+}
+
+impl Entropy {
+    fn open(source: &RngSource) -> Result<Entropy> {
+        match source {
+            RngSource::File(path) => File::open(path)
+                .map(Entropy::File)
+                .map_err(RngError::AccessingRandomDev),
+            RngSource::Builtin { seed: Some(seed) } => Ok(Entropy::Builtin(SimpleRng::new(*seed))),
+            RngSource::Builtin { seed: None } => {
+                let mut seed = [0u8; 8];
+                File::open("/dev/urandom")
+                    .and_then(|mut f| f.read_exact(&mut seed))
+                    .map_err(RngError::AccessingRandomDev)?;
+                Ok(Entropy::Builtin(SimpleRng::new(u64::from_le_bytes(seed))))
+            }
+        }
+    }
+
+    // Fills up to `limit` bytes of `writer`, returning how many were written.
+    fn write_to(&mut self, writer: &mut Writer, limit: usize) -> io::Result<usize> {
+        match self {
+            Entropy::File(file) => writer.write_from(file, limit),
+            Entropy::Builtin(rng) => {
+                let len = min(writer.available_bytes(), limit);
+                let bytes: Vec<u8> = rng
+                    .flat_map(|v| v.to_le_bytes().to_vec())
+                    .take(len)
+                    .collect();
+                writer.write_all(&bytes)?;
+                Ok(len)
+            }
+        }
+    }
+}
+
+// Caps the bytes handed to the guest in each `RATE_LIMIT_PERIOD`.
+struct RateLimit {
+    rate: u64,
+    budget: u64,
+    timer: Timer,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Result<RateLimit> {
+        let mut timer = Timer::new().map_err(RngError::CreatingTimer)?;
+        timer
+            .reset(RATE_LIMIT_PERIOD, Some(RATE_LIMIT_PERIOD))
+            .map_err(RngError::CreatingTimer)?;
+        Ok(RateLimit {
+            rate,
+            budget: rate,
+            timer,
+        })
+    }
+}
+
 struct Worker {
     interrupt: Interrupt,
     queue: Queue,
     mem: GuestMemory,
-    random_file: File,
+    entropy: Entropy,
+    rate_limit: Option<RateLimit>,
 }
 
 impl Worker {
@@ -44,12 +139,22 @@ impl Worker {
         let queue = &mut self.queue;
 
         let mut needs_interrupt = false;
-        while let Some(avail_desc) = queue.pop(&self.mem) {
+        loop {
+            // Leave requests on the queue until the next period once the budget is spent.
+            let limit = match &self.rate_limit {
+                Some(rate_limit) if rate_limit.budget == 0 => break,
+                Some(rate_limit) => rate_limit.budget as usize,
+                None => std::usize::MAX,
+            };
+            let avail_desc = match queue.pop(&self.mem) {
+                Some(d) => d,
+                None => break,
+            };
             let index = avail_desc.index;
-            let random_file = &mut self.random_file;
+            let entropy = &mut self.entropy;
             let written = match Writer::new(self.mem.clone(), avail_desc)
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
-                .and_then(|mut writer| writer.write_from(random_file, std::usize::MAX))
+                .and_then(|mut writer| entropy.write_to(&mut writer, limit))
             {
                 Ok(n) => n,
                 Err(e) => {
@@ -58,6 +163,9 @@ impl Worker {
                 }
             };
 
+            if let Some(rate_limit) = &mut self.rate_limit {
+                rate_limit.budget = rate_limit.budget.saturating_sub(written as u64);
+            }
             queue.add_used(&self.mem, index, written as u32);
             needs_interrupt = true;
         }
@@ -65,17 +173,22 @@ impl Worker {
         needs_interrupt
     }
 
-    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
+    fn run(&mut self, queue_evt: Event, kill_evt: Event, suspend_evt: Event, resume_evt: Event) {
         #[derive(PollToken)]
         enum Token {
             QueueAvailable,
             InterruptResample,
+            RateLimit,
+            Suspend,
+            Resume,
             Kill,
         }
 
         let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
             (&queue_evt, Token::QueueAvailable),
             (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&suspend_evt, Token::Suspend),
+            (&resume_evt, Token::Resume),
             (&kill_evt, Token::Kill),
         ]) {
             Ok(pc) => pc,
@@ -84,6 +197,12 @@ impl Worker {
                 return;
             }
         };
+        if let Some(rate_limit) = &self.rate_limit {
+            if let Err(e) = wait_ctx.add(&rate_limit.timer, Token::RateLimit) {
+                error!("failed adding rate limit timer to WaitContext: {}", e);
+                return;
+            }
+        }
 
         'wait: loop {
             let events = match wait_ctx.wait() {
@@ -107,6 +226,37 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
+                    Token::RateLimit => {
+                        if let Some(rate_limit) = &mut self.rate_limit {
+                            if let Err(e) = rate_limit.timer.wait() {
+                                error!("failed to wait on rate limit timer: {}", e);
+                                break 'wait;
+                            }
+                            rate_limit.budget = rate_limit.rate;
+                        }
+                        needs_interrupt |= self.process_queue();
+                    }
+                    // The rate limit timer is stopped while the VM is suspended so the worker
+                    // doesn't keep waking up to refill the budget.
+                    Token::Suspend => {
+                        let _ = suspend_evt.read();
+                        if let Some(rate_limit) = &mut self.rate_limit {
+                            if let Err(e) = rate_limit.timer.clear() {
+                                error!("failed to stop rate limit timer: {}", e);
+                            }
+                        }
+                    }
+                    Token::Resume => {
+                        let _ = resume_evt.read();
+                        if let Some(rate_limit) = &mut self.rate_limit {
+                            if let Err(e) = rate_limit
+                                .timer
+                                .reset(RATE_LIMIT_PERIOD, Some(RATE_LIMIT_PERIOD))
+                            {
+                                error!("failed to restart rate limit timer: {}", e);
+                            }
+                        }
+                    }
                     Token::Kill => break 'wait,
                 }
             }
@@ -120,19 +270,26 @@ impl Worker {
 /// Virtio device for exposing entropy to the guest OS through virtio.
 pub struct Rng {
     kill_evt: Option<Event>,
+    suspend_evt: Option<Event>,
+    resume_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<Worker>>,
-    random_file: Option<File>,
+    entropy: Option<Entropy>,
+    rate_limit: Option<RateLimit>,
     virtio_features: u64,
 }
 
 impl Rng {
-    /// Create a new virtio rng device that gets random data from /dev/urandom.
-    pub fn new(virtio_features: u64) -> Result<Rng> {
-        let random_file = File::open("/dev/urandom").map_err(RngError::AccessingRandomDev)?;
+    /// Create a new virtio rng device that gets random data from `params.source`.
+    pub fn new(virtio_features: u64, params: RngParameters) -> Result<Rng> {
+        let entropy = Entropy::open(&params.source)?;
+        let rate_limit = params.rate.map(RateLimit::new).transpose()?;
         Ok(Rng {
             kill_evt: None,
+            suspend_evt: None,
+            resume_evt: None,
             worker_thread: None,
-            random_file: Some(random_file),
+            entropy: Some(entropy),
+            rate_limit,
             virtio_features,
         })
     }
@@ -155,9 +312,12 @@ impl VirtioDevice for Rng {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
         let mut keep_rds = Vec::new();
 
-        if let Some(random_file) = &self.random_file {
+        if let Some(Entropy::File(random_file)) = &self.entropy {
             keep_rds.push(random_file.as_raw_descriptor());
         }
+        if let Some(rate_limit) = &self.rate_limit {
+            keep_rds.push(rate_limit.timer.as_raw_descriptor());
+        }
 
         keep_rds
     }
@@ -194,9 +354,30 @@ impl VirtioDevice for Rng {
         };
         self.kill_evt = Some(self_kill_evt);
 
+        let (self_suspend_evt, suspend_evt) =
+            match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to create suspend Event pair: {}", e);
+                    return;
+                }
+            };
+        self.suspend_evt = Some(self_suspend_evt);
+
+        let (self_resume_evt, resume_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e)))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create resume Event pair: {}", e);
+                return;
+            }
+        };
+        self.resume_evt = Some(self_resume_evt);
+
         let queue = queues.remove(0);
 
-        if let Some(random_file) = self.random_file.take() {
+        if let Some(entropy) = self.entropy.take() {
+            let rate_limit = self.rate_limit.take();
             let worker_result =
                 thread::Builder::new()
                     .name("virtio_rng".to_string())
@@ -205,9 +386,10 @@ impl VirtioDevice for Rng {
                             interrupt,
                             queue,
                             mem,
-                            random_file,
+                            entropy,
+                            rate_limit,
                         };
-                        worker.run(queue_evts.remove(0), kill_evt);
+                        worker.run(queue_evts.remove(0), kill_evt, suspend_evt, resume_evt);
                         worker
                     });
 
@@ -223,7 +405,33 @@ impl VirtioDevice for Rng {
         }
     }
 
+    fn on_device_suspend(&mut self) {
+        if let Some(suspend_evt) = &self.suspend_evt {
+            if let Err(e) = suspend_evt.write(1) {
+                error!(
+                    "{}: failed to notify the suspend event: {}",
+                    self.debug_label(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn on_device_resume(&mut self) {
+        if let Some(resume_evt) = &self.resume_evt {
+            if let Err(e) = resume_evt.write(1) {
+                error!(
+                    "{}: failed to notify the resume event: {}",
+                    self.debug_label(),
+                    e
+                );
+            }
+        }
+    }
+
     fn reset(&mut self) -> bool {
+        self.suspend_evt = None;
+        self.resume_evt = None;
         if let Some(kill_evt) = self.kill_evt.take() {
             if kill_evt.write(1).is_err() {
                 error!("{}: failed to notify the kill event", self.debug_label());
@@ -238,11 +446,48 @@ impl VirtioDevice for Rng {
                     return false;
                 }
                 Ok(worker) => {
-                    self.random_file = Some(worker.random_file);
+                    self.entropy = Some(worker.entropy);
+                    self.rate_limit = worker.rate_limit;
                     return true;
                 }
             }
         }
         false
     }
+
+    fn virtio_sleep(&mut self) -> Option<Vec<Queue>> {
+        self.suspend_evt = None;
+        self.resume_evt = None;
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if let Err(e) = kill_evt.write(1) {
+                error!(
+                    "{}: failed to notify the kill event: {}",
+                    self.debug_label(),
+                    e
+                );
+                return None;
+            }
+        }
+
+        match self.worker_thread.take()?.join() {
+            Err(_) => {
+                error!("{}: failed to get back resources", self.debug_label());
+                None
+            }
+            Ok(worker) => {
+                self.entropy = Some(worker.entropy);
+                self.rate_limit = worker.rate_limit;
+                Some(vec![worker.queue])
+            }
+        }
+    }
+
+    // The entropy source and rate come from the command line, so there is no other state to save.
+    fn virtio_snapshot(&self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+
+    fn virtio_restore(&mut self, data: &[u8]) -> bool {
+        data.is_empty()
+    }
 }
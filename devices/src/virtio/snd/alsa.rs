@@ -0,0 +1,337 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A `StreamSource` that plays and captures audio through the host's ALSA library, for hosts that
+//! don't run CRAS.
+
+use std::ffi::{CStr, CString, NulError};
+use std::os::raw::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+use std::ptr::null_mut;
+
+use audio_streams::capture::{CaptureBuffer, CaptureBufferStream};
+use audio_streams::{
+    BoxError, BufferDrop, NoopStreamControl, PlaybackBuffer, PlaybackBufferStream, SampleFormat,
+    StreamControl, StreamEffect, StreamSource,
+};
+use base::warn;
+use thiserror::Error as ThisError;
+
+#[allow(non_camel_case_types)]
+enum snd_pcm_t {}
+
+const SND_PCM_STREAM_PLAYBACK: c_int = 0;
+const SND_PCM_STREAM_CAPTURE: c_int = 1;
+const SND_PCM_ACCESS_RW_INTERLEAVED: c_int = 3;
+const SND_PCM_FORMAT_U8: c_int = 1;
+const SND_PCM_FORMAT_S16_LE: c_int = 2;
+const SND_PCM_FORMAT_S24_LE: c_int = 6;
+const SND_PCM_FORMAT_S32_LE: c_int = 10;
+
+#[link(name = "asound")]
+extern "C" {
+    fn snd_pcm_open(
+        pcm: *mut *mut snd_pcm_t,
+        name: *const c_char,
+        stream: c_int,
+        mode: c_int,
+    ) -> c_int;
+    fn snd_pcm_set_params(
+        pcm: *mut snd_pcm_t,
+        format: c_int,
+        access: c_int,
+        channels: c_uint,
+        rate: c_uint,
+        soft_resample: c_int,
+        latency: c_uint,
+    ) -> c_int;
+    fn snd_pcm_writei(pcm: *mut snd_pcm_t, buffer: *const c_void, size: c_ulong) -> c_long;
+    fn snd_pcm_readi(pcm: *mut snd_pcm_t, buffer: *mut c_void, size: c_ulong) -> c_long;
+    fn snd_pcm_recover(pcm: *mut snd_pcm_t, err: c_int, silent: c_int) -> c_int;
+    fn snd_pcm_close(pcm: *mut snd_pcm_t) -> c_int;
+    fn snd_strerror(errnum: c_int) -> *const c_char;
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("{0} failed: {1}")]
+    Alsa(&'static str, String),
+    #[error("invalid ALSA device name: {0}")]
+    DeviceName(NulError),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn alsa_error(call: &'static str, err: c_int) -> Error {
+    // Safe because snd_strerror returns a pointer to a static, nul-terminated string for any
+    // error value.
+    let msg = unsafe { CStr::from_ptr(snd_strerror(err)) };
+    Error::Alsa(call, msg.to_string_lossy().into_owned())
+}
+
+fn to_alsa_format(format: SampleFormat) -> c_int {
+    match format {
+        SampleFormat::U8 => SND_PCM_FORMAT_U8,
+        SampleFormat::S16LE => SND_PCM_FORMAT_S16_LE,
+        SampleFormat::S24LE => SND_PCM_FORMAT_S24_LE,
+        SampleFormat::S32LE => SND_PCM_FORMAT_S32_LE,
+    }
+}
+
+// An open ALSA PCM handle, closed on drop.
+struct Pcm {
+    handle: *mut snd_pcm_t,
+    frame_size: usize,
+}
+
+// Safe because the PCM handle is only ever accessed through `&mut self` and ALSA doesn't tie a
+// handle to the thread that opened it.
+unsafe impl Send for Pcm {}
+
+impl Pcm {
+    fn open(
+        device: &CStr,
+        stream: c_int,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<Pcm> {
+        let mut handle = null_mut();
+        // Safe because `handle` is a valid out pointer and `device` is nul-terminated.
+        let ret = unsafe { snd_pcm_open(&mut handle, device.as_ptr(), stream, 0) };
+        if ret < 0 {
+            return Err(alsa_error("snd_pcm_open", ret));
+        }
+        let pcm = Pcm {
+            handle,
+            frame_size: num_channels * format.sample_bytes(),
+        };
+
+        // Ask for room for two periods so that the next period can be filled while the current
+        // one is being played or captured.
+        let latency_us = (buffer_size as u64 * 2 * 1_000_000 / frame_rate.max(1) as u64) as c_uint;
+        // Safe because `pcm.handle` is an open PCM.
+        let ret = unsafe {
+            snd_pcm_set_params(
+                pcm.handle,
+                to_alsa_format(format),
+                SND_PCM_ACCESS_RW_INTERLEAVED,
+                num_channels as c_uint,
+                frame_rate,
+                1, /* soft_resample */
+                latency_us,
+            )
+        };
+        if ret < 0 {
+            return Err(alsa_error("snd_pcm_set_params", ret));
+        }
+        Ok(pcm)
+    }
+
+    // Recovers from an underrun, overrun or suspend reported as `err`.
+    fn recover(&mut self, call: &'static str, err: c_int) -> Result<()> {
+        // Safe because `self.handle` is an open PCM.
+        let ret = unsafe {
+            snd_pcm_recover(self.handle, err, 1 /* silent */)
+        };
+        if ret < 0 {
+            return Err(alsa_error(call, err));
+        }
+        Ok(())
+    }
+
+    // Writes all of the frames in `data`, blocking until ALSA has accepted them.
+    fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while data.len() >= self.frame_size {
+            let frames = data.len() / self.frame_size;
+            // Safe because `data` holds at least `frames` complete frames.
+            let ret = unsafe {
+                snd_pcm_writei(
+                    self.handle,
+                    data.as_ptr() as *const c_void,
+                    frames as c_ulong,
+                )
+            };
+            if ret < 0 {
+                self.recover("snd_pcm_writei", ret as c_int)?;
+                continue;
+            }
+            data = &data[ret as usize * self.frame_size..];
+        }
+        Ok(())
+    }
+
+    // Fills `data` with captured frames, blocking until they are available.
+    fn read(&mut self, data: &mut [u8]) -> Result<()> {
+        let mut offset = 0;
+        while data.len() - offset >= self.frame_size {
+            let frames = (data.len() - offset) / self.frame_size;
+            // Safe because `data[offset..]` has room for at least `frames` complete frames.
+            let ret = unsafe {
+                snd_pcm_readi(
+                    self.handle,
+                    data[offset..].as_mut_ptr() as *mut c_void,
+                    frames as c_ulong,
+                )
+            };
+            if ret < 0 {
+                self.recover("snd_pcm_readi", ret as c_int)?;
+                continue;
+            }
+            offset += ret as usize * self.frame_size;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pcm {
+    fn drop(&mut self) {
+        // Safe because `self.handle` is an open PCM that isn't used after this.
+        unsafe { snd_pcm_close(self.handle) };
+    }
+}
+
+// Records how many bytes the guest wrote to the last playback buffer.
+#[derive(Default)]
+struct PendingWrite {
+    bytes: usize,
+}
+
+impl BufferDrop for PendingWrite {
+    fn trigger(&mut self, nwritten: usize) {
+        self.bytes = nwritten;
+    }
+}
+
+/// A playback stream backed by an ALSA PCM.
+///
+/// Frames written to a buffer are handed to ALSA when the next buffer is requested, which blocks
+/// until ALSA has room for them and so paces the stream at the PCM's frame rate.
+pub struct AlsaPlaybackStream {
+    pcm: Pcm,
+    buffer: Vec<u8>,
+    pending: PendingWrite,
+}
+
+impl AlsaPlaybackStream {
+    fn flush(&mut self) -> Result<()> {
+        let bytes = std::mem::take(&mut self.pending.bytes);
+        self.pcm.write(&self.buffer[..bytes])
+    }
+}
+
+impl PlaybackBufferStream for AlsaPlaybackStream {
+    fn next_playback_buffer(&mut self) -> std::result::Result<PlaybackBuffer, BoxError> {
+        self.flush()?;
+        Ok(PlaybackBuffer::new(
+            self.pcm.frame_size,
+            &mut self.buffer,
+            &mut self.pending,
+        )?)
+    }
+}
+
+impl Drop for AlsaPlaybackStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to flush ALSA playback stream: {}", e);
+        }
+    }
+}
+
+struct NoopDrop;
+
+impl BufferDrop for NoopDrop {
+    fn trigger(&mut self, _nwritten: usize) {}
+}
+
+/// A capture stream backed by an ALSA PCM.
+pub struct AlsaCaptureStream {
+    pcm: Pcm,
+    buffer: Vec<u8>,
+    buffer_drop: NoopDrop,
+}
+
+impl CaptureBufferStream for AlsaCaptureStream {
+    fn next_capture_buffer(&mut self) -> std::result::Result<CaptureBuffer, BoxError> {
+        self.pcm.read(&mut self.buffer)?;
+        Ok(CaptureBuffer::new(
+            self.pcm.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+/// Opens playback and capture streams on an ALSA PCM device.
+pub struct AlsaStreamSource {
+    device: CString,
+}
+
+impl AlsaStreamSource {
+    /// Creates a stream source for the ALSA PCM named `device`, e.g. "default" or "hw:0,0".
+    ///
+    /// The device is opened each time a stream is created.
+    pub fn new(device: &str) -> Result<AlsaStreamSource> {
+        Ok(AlsaStreamSource {
+            device: CString::new(device).map_err(Error::DeviceName)?,
+        })
+    }
+}
+
+impl StreamSource for AlsaStreamSource {
+    fn new_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        let pcm = Pcm::open(
+            &self.device,
+            SND_PCM_STREAM_PLAYBACK,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let buffer = vec![0; buffer_size * pcm.frame_size];
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaPlaybackStream {
+                pcm,
+                buffer,
+                pending: Default::default(),
+            }),
+        ))
+    }
+
+    fn new_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let pcm = Pcm::open(
+            &self.device,
+            SND_PCM_STREAM_CAPTURE,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let buffer = vec![0; buffer_size * pcm.frame_size];
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaCaptureStream {
+                pcm,
+                buffer,
+                buffer_drop: NoopDrop,
+            }),
+        ))
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use audio_streams::SampleFormat;
+
+use crate::virtio::snd::constants::*;
+
+/// Sample formats the device advertises for every PCM stream.
+pub const SUPPORTED_FORMATS: &[u8] = &[
+    VIRTIO_SND_PCM_FMT_U8,
+    VIRTIO_SND_PCM_FMT_S16,
+    VIRTIO_SND_PCM_FMT_S24,
+    VIRTIO_SND_PCM_FMT_S32,
+];
+
+/// Frame rates the device advertises for every PCM stream.
+pub const SUPPORTED_RATES: &[u8] = &[
+    VIRTIO_SND_PCM_RATE_8000,
+    VIRTIO_SND_PCM_RATE_11025,
+    VIRTIO_SND_PCM_RATE_16000,
+    VIRTIO_SND_PCM_RATE_22050,
+    VIRTIO_SND_PCM_RATE_32000,
+    VIRTIO_SND_PCM_RATE_44100,
+    VIRTIO_SND_PCM_RATE_48000,
+];
+
+/// Converts a VIRTIO_SND_PCM_FMT_* value into the matching `SampleFormat`, if the host backend
+/// supports it.
+pub fn from_virtio_sample_format(format: u8) -> Option<SampleFormat> {
+    match format {
+        VIRTIO_SND_PCM_FMT_U8 => Some(SampleFormat::U8),
+        VIRTIO_SND_PCM_FMT_S16 => Some(SampleFormat::S16LE),
+        VIRTIO_SND_PCM_FMT_S24 => Some(SampleFormat::S24LE),
+        VIRTIO_SND_PCM_FMT_S32 => Some(SampleFormat::S32LE),
+        _ => None,
+    }
+}
+
+/// Converts a VIRTIO_SND_PCM_RATE_* value into a frame rate in Hz.
+pub fn from_virtio_frame_rate(rate: u8) -> Option<u32> {
+    Some(match rate {
+        VIRTIO_SND_PCM_RATE_5512 => 5512,
+        VIRTIO_SND_PCM_RATE_8000 => 8000,
+        VIRTIO_SND_PCM_RATE_11025 => 11025,
+        VIRTIO_SND_PCM_RATE_16000 => 16000,
+        VIRTIO_SND_PCM_RATE_22050 => 22050,
+        VIRTIO_SND_PCM_RATE_32000 => 32000,
+        VIRTIO_SND_PCM_RATE_44100 => 44100,
+        VIRTIO_SND_PCM_RATE_48000 => 48000,
+        VIRTIO_SND_PCM_RATE_64000 => 64000,
+        VIRTIO_SND_PCM_RATE_88200 => 88200,
+        VIRTIO_SND_PCM_RATE_96000 => 96000,
+        VIRTIO_SND_PCM_RATE_176400 => 176400,
+        VIRTIO_SND_PCM_RATE_192000 => 192000,
+        VIRTIO_SND_PCM_RATE_384000 => 384000,
+        _ => return None,
+    })
+}
+
+/// Returns the channel positions reported in the channel map of a stream with `channels`
+/// channels.
+pub fn channel_positions(channels: u8) -> [u8; VIRTIO_SND_CHMAP_MAX_SIZE] {
+    let mut positions = [VIRTIO_SND_CHMAP_NONE; VIRTIO_SND_CHMAP_MAX_SIZE];
+    match channels {
+        1 => positions[0] = VIRTIO_SND_CHMAP_MONO,
+        _ => {
+            const LAYOUT: &[u8] = &[
+                VIRTIO_SND_CHMAP_FL,
+                VIRTIO_SND_CHMAP_FR,
+                VIRTIO_SND_CHMAP_RL,
+                VIRTIO_SND_CHMAP_RR,
+                VIRTIO_SND_CHMAP_FC,
+                VIRTIO_SND_CHMAP_LFE,
+                VIRTIO_SND_CHMAP_SL,
+                VIRTIO_SND_CHMAP_SR,
+            ];
+            for (pos, val) in positions
+                .iter_mut()
+                .zip(LAYOUT.iter())
+                .take(channels as usize)
+            {
+                *pos = *val;
+            }
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_formats_convert() {
+        for format in SUPPORTED_FORMATS {
+            assert!(from_virtio_sample_format(*format).is_some());
+        }
+        assert!(from_virtio_sample_format(VIRTIO_SND_PCM_FMT_FLOAT).is_none());
+    }
+
+    #[test]
+    fn supported_rates_convert() {
+        for rate in SUPPORTED_RATES {
+            assert!(from_virtio_frame_rate(*rate).is_some());
+        }
+        assert_eq!(
+            from_virtio_frame_rate(VIRTIO_SND_PCM_RATE_48000),
+            Some(48000)
+        );
+        assert_eq!(from_virtio_frame_rate(200), None);
+    }
+
+    #[test]
+    fn stereo_channel_map() {
+        let positions = channel_positions(2);
+        assert_eq!(positions[0], VIRTIO_SND_CHMAP_FL);
+        assert_eq!(positions[1], VIRTIO_SND_CHMAP_FR);
+        assert_eq!(positions[2], VIRTIO_SND_CHMAP_NONE);
+        assert_eq!(channel_positions(1)[0], VIRTIO_SND_CHMAP_MONO);
+    }
+}
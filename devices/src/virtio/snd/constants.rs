@@ -61,3 +61,32 @@ pub const VIRTIO_SND_PCM_RATE_96000: u8 = 10;
 pub const VIRTIO_SND_PCM_RATE_176400: u8 = 11;
 pub const VIRTIO_SND_PCM_RATE_192000: u8 = 12;
 pub const VIRTIO_SND_PCM_RATE_384000: u8 = 13;
+
+pub const VIRTIO_SND_CHMAP_NONE: u8 = 0;
+pub const VIRTIO_SND_CHMAP_NA: u8 = 1;
+pub const VIRTIO_SND_CHMAP_MONO: u8 = 2;
+pub const VIRTIO_SND_CHMAP_FL: u8 = 3;
+pub const VIRTIO_SND_CHMAP_FR: u8 = 4;
+pub const VIRTIO_SND_CHMAP_RL: u8 = 5;
+pub const VIRTIO_SND_CHMAP_RR: u8 = 6;
+pub const VIRTIO_SND_CHMAP_FC: u8 = 7;
+pub const VIRTIO_SND_CHMAP_LFE: u8 = 8;
+pub const VIRTIO_SND_CHMAP_SL: u8 = 9;
+pub const VIRTIO_SND_CHMAP_SR: u8 = 10;
+
+pub const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+
+// Jack feature bits.
+pub const VIRTIO_SND_JACK_F_REMAP: u32 = 0;
+
+// Event notification types.
+pub const VIRTIO_SND_EVT_JACK_CONNECTED: u32 = 0x1000;
+pub const VIRTIO_SND_EVT_JACK_DISCONNECTED: u32 = 0x1000 + 1;
+pub const VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
+pub const VIRTIO_SND_EVT_PCM_XRUN: u32 = 0x1100 + 1;
+
+// Virtqueue indices.
+pub const VIRTIO_SND_QUEUE_CONTROL: usize = 0;
+pub const VIRTIO_SND_QUEUE_EVENT: usize = 1;
+pub const VIRTIO_SND_QUEUE_TX: usize = 2;
+pub const VIRTIO_SND_QUEUE_RX: usize = 3;
@@ -0,0 +1,354 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::thread;
+
+use audio_streams::{NoopStreamSource, StreamSource};
+use base::{error, Event, RawDescriptor};
+use data_model::{DataInit, Le32};
+use libcras::{CrasClient, CrasClientType, CrasSocketType};
+use vm_memory::GuestMemory;
+
+#[cfg(feature = "audio_alsa")]
+use crate::virtio::snd::alsa::{self, AlsaStreamSource};
+use crate::virtio::snd::constants::*;
+use crate::virtio::snd::layout::*;
+use crate::virtio::snd::worker::{StreamConfig, Worker};
+use crate::virtio::{copy_config, Interrupt, Queue, VirtioDevice, TYPE_SOUND};
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 4;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+#[cfg(feature = "audio_alsa")]
+const ALSA_DEFAULT_DEVICE: &str = "default";
+
+/// Errors that are possible from creating a virtio-snd device.
+#[derive(Debug)]
+pub enum SoundError {
+    /// The requested backend isn't known.
+    InvalidBackend,
+    /// Setting up the ALSA stream source failed.
+    #[cfg(feature = "audio_alsa")]
+    CreateAlsaStreamSource(alsa::Error),
+    /// Connecting to the CRAS server failed.
+    CreateCrasClient(libcras::Error),
+}
+
+impl std::error::Error for SoundError {}
+
+impl Display for SoundError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SoundError::*;
+
+        match self {
+            InvalidBackend => write!(f, "Must be one of: {}", SoundBackend::NAMES),
+            #[cfg(feature = "audio_alsa")]
+            CreateAlsaStreamSource(e) => write!(f, "failed to set up ALSA: {}", e),
+            CreateCrasClient(e) => write!(f, "failed to create CRAS client: {}", e),
+        }
+    }
+}
+
+/// Host audio server that backs the PCM streams of the device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoundBackend {
+    Cras,
+    /// Route streams through the host's ALSA library.
+    #[cfg(feature = "audio_alsa")]
+    Alsa,
+    /// Discard playback frames and capture silence, paced at the requested frame rate.
+    Null,
+}
+
+impl SoundBackend {
+    #[cfg(feature = "audio_alsa")]
+    const NAMES: &'static str = "cras, alsa, null";
+    #[cfg(not(feature = "audio_alsa"))]
+    const NAMES: &'static str = "cras, null";
+}
+
+impl Default for SoundBackend {
+    fn default() -> Self {
+        SoundBackend::Cras
+    }
+}
+
+impl FromStr for SoundBackend {
+    type Err = SoundError;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cras" => Ok(SoundBackend::Cras),
+            #[cfg(feature = "audio_alsa")]
+            "alsa" => Ok(SoundBackend::Alsa),
+            "null" => Ok(SoundBackend::Null),
+            _ => Err(SoundError::InvalidBackend),
+        }
+    }
+}
+
+/// Holds the parameters for a virtio-snd device.
+#[derive(Debug, Clone)]
+pub struct SoundParameters {
+    pub backend: SoundBackend,
+    /// Expose input streams and an input jack to the guest.
+    pub capture: bool,
+    pub num_output_streams: u32,
+    pub num_input_streams: u32,
+    /// Minimum latency, in milliseconds, buffered by the host for each output stream.
+    pub output_latency_ms: u32,
+    /// Minimum latency, in milliseconds, buffered by the host for each input stream.
+    pub input_latency_ms: u32,
+}
+
+impl Default for SoundParameters {
+    fn default() -> Self {
+        SoundParameters {
+            backend: Default::default(),
+            capture: false,
+            num_output_streams: 1,
+            num_input_streams: 1,
+            output_latency_ms: 0,
+            input_latency_ms: 0,
+        }
+    }
+}
+
+impl SoundParameters {
+    fn stream_configs(&self) -> Vec<StreamConfig> {
+        let outputs = (0..self.num_output_streams).map(|_| StreamConfig {
+            direction: VIRTIO_SND_D_OUTPUT,
+            latency_ms: self.output_latency_ms,
+        });
+        let num_inputs = if self.capture {
+            self.num_input_streams
+        } else {
+            0
+        };
+        let inputs = (0..num_inputs).map(|_| StreamConfig {
+            direction: VIRTIO_SND_D_INPUT,
+            latency_ms: self.input_latency_ms,
+        });
+        outputs.chain(inputs).collect()
+    }
+}
+
+/// Virtio device exposing host audio playback and capture streams to the guest.
+pub struct Sound {
+    params: SoundParameters,
+    streams: Vec<StreamConfig>,
+    audio_server: Option<Box<dyn StreamSource>>,
+    avail_features: u64,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+}
+
+impl Sound {
+    /// Create a new virtio-snd device with streams backed by the audio server selected in
+    /// `params`.
+    pub fn new(base_features: u64, params: SoundParameters) -> Result<Sound, SoundError> {
+        let audio_server = Self::create_audio_server(&params)?;
+
+        Ok(Sound {
+            streams: params.stream_configs(),
+            params,
+            audio_server: Some(audio_server),
+            avail_features: base_features,
+            kill_evt: None,
+            worker_thread: None,
+        })
+    }
+
+    fn create_audio_server(params: &SoundParameters) -> Result<Box<dyn StreamSource>, SoundError> {
+        Ok(match params.backend {
+            SoundBackend::Cras => {
+                let mut server = CrasClient::with_type(CrasSocketType::Unified)
+                    .map_err(SoundError::CreateCrasClient)?;
+                server.set_client_type(CrasClientType::CRAS_CLIENT_TYPE_CROSVM);
+                if params.capture {
+                    server.enable_cras_capture();
+                }
+                Box::new(server)
+            }
+            #[cfg(feature = "audio_alsa")]
+            SoundBackend::Alsa => Box::new(
+                AlsaStreamSource::new(ALSA_DEFAULT_DEVICE)
+                    .map_err(SoundError::CreateAlsaStreamSource)?,
+            ),
+            SoundBackend::Null => Box::new(NoopStreamSource::new()),
+        })
+    }
+
+    /// Return the minijail policy file name for the current backend.
+    pub fn minijail_policy(&self) -> &'static str {
+        match self.params.backend {
+            SoundBackend::Cras => "cras_snd_device",
+            #[cfg(feature = "audio_alsa")]
+            SoundBackend::Alsa => "alsa_snd_device",
+            SoundBackend::Null => "null_snd_device",
+        }
+    }
+
+    fn config(&self) -> virtio_snd_config {
+        let jacks = if self.params.capture { 2 } else { 1 };
+        virtio_snd_config {
+            jacks: Le32::from(jacks),
+            streams: Le32::from(self.streams.len() as u32),
+            chmaps: Le32::from(self.streams.len() as u32),
+        }
+    }
+}
+
+impl Drop for Sound {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Sound {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.audio_server
+            .as_ref()
+            .and_then(|server| server.keep_fds())
+            .unwrap_or_default()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_SOUND
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.config().as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let audio_server = match self.audio_server.take() {
+            Some(server) => server,
+            None => {
+                error!("virtio-snd: audio server is already in use");
+                return;
+            }
+        };
+        let capture = self.params.capture;
+        let streams = self.streams.clone();
+
+        let worker_result =
+            thread::Builder::new()
+                .name("virtio_snd".to_string())
+                .spawn(move || {
+                    let mut worker =
+                        Worker::new(mem, interrupt, queues, audio_server, capture, &streams);
+                    worker.run(queue_evts, kill_evt);
+                    worker
+                });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_snd worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(worker) => {
+                    self.audio_server = Some(worker.into_audio_server());
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_backend() {
+        assert_eq!("cras".parse::<SoundBackend>().unwrap(), SoundBackend::Cras);
+        assert_eq!("null".parse::<SoundBackend>().unwrap(), SoundBackend::Null);
+        #[cfg(feature = "audio_alsa")]
+        assert_eq!("alsa".parse::<SoundBackend>().unwrap(), SoundBackend::Alsa);
+        assert!("pulse".parse::<SoundBackend>().is_err());
+    }
+
+    #[test]
+    fn stream_configs_without_capture() {
+        let params = SoundParameters {
+            num_output_streams: 2,
+            ..Default::default()
+        };
+        let streams = params.stream_configs();
+        assert_eq!(streams.len(), 2);
+        assert!(streams.iter().all(|s| s.direction == VIRTIO_SND_D_OUTPUT));
+    }
+
+    #[test]
+    fn stream_configs_latency() {
+        let params = SoundParameters {
+            capture: true,
+            output_latency_ms: 10,
+            input_latency_ms: 20,
+            ..Default::default()
+        };
+        let streams = params.stream_configs();
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].direction, VIRTIO_SND_D_OUTPUT);
+        assert_eq!(streams[0].latency_ms, 10);
+        assert_eq!(streams[1].direction, VIRTIO_SND_D_INPUT);
+        assert_eq!(streams[1].latency_ms, 20);
+    }
+}
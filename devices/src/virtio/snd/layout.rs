@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use crate::virtio::snd::constants::VIRTIO_SND_CHMAP_MAX_SIZE;
 use data_model::{DataInit, Le32, Le64};
 
 #[derive(Copy, Clone, Default)]
@@ -87,3 +88,46 @@ pub struct virtio_snd_pcm_status {
 }
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_snd_pcm_status {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_snd_config {
+    pub jacks: Le32,
+    pub streams: Le32,
+    pub chmaps: Le32,
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_snd_config {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_snd_jack_info {
+    pub hdr: virtio_snd_info,
+    pub features: Le32,
+    pub hda_reg_defconf: Le32,
+    pub hda_reg_caps: Le32,
+    pub connected: u8,
+    pub padding: [u8; 7],
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_snd_jack_info {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_snd_chmap_info {
+    pub hdr: virtio_snd_info,
+    pub direction: u8,
+    pub channels: u8,
+    pub positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_snd_chmap_info {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_snd_event {
+    pub hdr: virtio_snd_hdr,
+    pub data: Le32,
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_snd_event {}
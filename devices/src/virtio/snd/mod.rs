@@ -2,8 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(feature = "audio_alsa")]
+pub mod alsa;
+
+pub mod common;
+
 pub mod constants;
 
+pub mod device;
+
 pub mod layout;
 
 pub mod vios_backend;
+
+mod worker;
+
+pub use self::device::*;
@@ -0,0 +1,868 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use audio_streams::capture::CaptureBufferStream;
+use audio_streams::{
+    BoxError, PlaybackBufferStream, SampleFormat, StreamControl, StreamEffect, StreamSource,
+};
+use base::{error, warn, Event, PollToken, WaitContext};
+use data_model::{DataInit, Le32, Le64};
+use sync::Mutex;
+use thiserror::Error as ThisError;
+use vm_memory::GuestMemory;
+
+use crate::virtio::snd::common::*;
+use crate::virtio::snd::constants::*;
+use crate::virtio::snd::layout::*;
+use crate::virtio::{DescriptorChain, DescriptorError, Interrupt, Queue, Reader, Writer};
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to create the audio stream: {0}")]
+    CreateStream(BoxError),
+    #[error("invalid descriptor chain: {0}")]
+    DescriptorChain(DescriptorError),
+    #[error("failed to access the audio stream buffer: {0}")]
+    StreamBuffer(BoxError),
+    #[error("failed to read from or write to the descriptor chain: {0}")]
+    Io(io::Error),
+    #[error("failed to spawn the pcm worker thread: {0}")]
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Direction and host side latency of a PCM stream exposed to the guest.
+#[derive(Copy, Clone, Debug)]
+pub struct StreamConfig {
+    pub direction: u8,
+    /// Minimum amount of audio, in milliseconds, buffered by the host for this stream.
+    pub latency_ms: u32,
+}
+
+/// Parameters of a PCM stream, as set by the guest with `STREAM_SET_PARAMS`.
+#[derive(Copy, Clone, Debug)]
+struct PcmParams {
+    period_bytes: u32,
+    channels: u8,
+    format: SampleFormat,
+    frame_rate: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PcmState {
+    Idle,
+    ParamsSet,
+    Prepared,
+    Started,
+    Stopped,
+}
+
+/// What the control worker sends to the thread of a prepared stream.
+enum PcmCommand {
+    /// A buffer the guest queued on the tx or rx queue.
+    Buffer(DescriptorChain),
+    /// The guest started the stream, which is now backed by this host stream.
+    Start(HostStream),
+    /// The guest stopped the stream, so its host stream goes away until it is started again.
+    Stop,
+}
+
+/// A PCM stream that has been prepared. Buffers and the host audio stream, which only exists while
+/// the guest has the stream started, are handed to the stream's thread over `sender`.
+struct ActiveStream {
+    sender: Sender<PcmCommand>,
+    control: Option<Box<dyn StreamControl>>,
+    thread: thread::JoinHandle<()>,
+}
+
+struct PcmStream {
+    config: StreamConfig,
+    params: Option<PcmParams>,
+    state: PcmState,
+    active: Option<ActiveStream>,
+}
+
+impl PcmStream {
+    fn new(config: StreamConfig) -> PcmStream {
+        PcmStream {
+            config,
+            params: None,
+            state: PcmState::Idle,
+            active: None,
+        }
+    }
+
+    // Drops the sender so the stream's thread returns any pending buffers and exits, then waits
+    // for it.
+    fn release(&mut self) {
+        if let Some(active) = self.active.take() {
+            drop(active.sender);
+            drop(active.control);
+            if active.thread.join().is_err() {
+                error!("virtio-snd: pcm worker thread panicked");
+            }
+        }
+        self.state = PcmState::Idle;
+        self.params = None;
+    }
+}
+
+fn frame_size(params: &PcmParams) -> usize {
+    params.channels as usize * params.format.sample_bytes()
+}
+
+/// Host buffer size, in frames, for a stream with `params`. The guest's period is used unless the
+/// stream was configured with a larger latency.
+fn buffer_frames(params: &PcmParams, latency_ms: u32) -> usize {
+    let period_frames = params.period_bytes as usize / cmp::max(frame_size(params), 1);
+    let latency_frames = (latency_ms as u64 * params.frame_rate as u64 / 1000) as usize;
+    cmp::max(cmp::max(period_frames, latency_frames), 1)
+}
+
+// The pcm worker threads and the control worker share the tx, rx and event queues.
+#[derive(Clone)]
+struct SharedQueue {
+    queue: Arc<Mutex<Queue>>,
+    mem: GuestMemory,
+    interrupt: Arc<Interrupt>,
+}
+
+impl SharedQueue {
+    fn return_descriptor(&self, index: u16, len: usize) {
+        let mut queue = self.queue.lock();
+        queue.add_used(&self.mem, index, len as u32);
+        self.interrupt.signal_used_queue(queue.vector);
+    }
+}
+
+// Sends notifications to the guest over the event queue, which the guest fills with buffers for the
+// device to write them to. Notifications that come while there is no buffer wait for the next one.
+#[derive(Clone)]
+struct EventQueue {
+    queue: SharedQueue,
+    pending: Arc<Mutex<VecDeque<virtio_snd_event>>>,
+}
+
+impl EventQueue {
+    fn send(&self, code: u32, data: u32) {
+        self.pending.lock().push_back(virtio_snd_event {
+            hdr: virtio_snd_hdr {
+                code: Le32::from(code),
+            },
+            data: Le32::from(data),
+        });
+        self.flush();
+    }
+
+    // Writes pending notifications to the buffers the guest has queued.
+    fn flush(&self) {
+        let mut pending = self.pending.lock();
+        while let Some(event) = pending.front() {
+            let desc = match self.queue.queue.lock().pop(&self.queue.mem) {
+                Some(d) => d,
+                None => return,
+            };
+            let index = desc.index;
+            let len = match Writer::new(self.queue.mem.clone(), desc) {
+                Ok(mut writer) => match writer.write_obj(*event) {
+                    Ok(()) => writer.bytes_written(),
+                    Err(e) => {
+                        error!("virtio-snd: failed to write event: {}", e);
+                        0
+                    }
+                },
+                Err(e) => {
+                    error!("virtio-snd: invalid event descriptor chain: {}", e);
+                    0
+                }
+            };
+            self.queue.return_descriptor(index, len);
+            pending.pop_front();
+        }
+    }
+}
+
+fn write_status(writer: &mut Writer, status: u32, latency_bytes: u32) -> Result<()> {
+    writer
+        .write_obj(virtio_snd_pcm_status {
+            status: Le32::from(status),
+            latency_bytes: Le32::from(latency_bytes),
+        })
+        .map_err(Error::Io)
+}
+
+// Copies one guest buffer from `desc` into the host stream.
+fn play_buffer(
+    stream: &mut dyn PlaybackBufferStream,
+    mem: &GuestMemory,
+    desc: DescriptorChain,
+    latency_bytes: u32,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), desc.clone()).map_err(Error::DescriptorChain)?;
+    let mut writer = Writer::new(mem.clone(), desc).map_err(Error::DescriptorChain)?;
+    reader
+        .read_obj::<virtio_snd_pcm_xfer>()
+        .map_err(Error::Io)?;
+
+    let mut data = vec![0u8; reader.available_bytes()];
+    reader.read_exact(&mut data).map_err(Error::Io)?;
+
+    let mut offset = 0;
+    let mut status = VIRTIO_SND_S_OK;
+    while offset < data.len() {
+        let mut buffer = stream.next_playback_buffer().map_err(Error::StreamBuffer)?;
+        match buffer.write(&data[offset..]) {
+            Ok(0) => break,
+            Ok(n) => offset += n,
+            Err(e) => {
+                warn!("virtio-snd: failed to write playback buffer: {}", e);
+                status = VIRTIO_SND_S_IO_ERR;
+                break;
+            }
+        }
+    }
+    write_status(&mut writer, status, latency_bytes)?;
+    Ok(writer.bytes_written())
+}
+
+// Fills one guest buffer in `desc` with frames captured from the host stream.
+fn capture_buffer(
+    stream: &mut dyn CaptureBufferStream,
+    mem: &GuestMemory,
+    desc: DescriptorChain,
+    latency_bytes: u32,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), desc.clone()).map_err(Error::DescriptorChain)?;
+    let mut writer = Writer::new(mem.clone(), desc).map_err(Error::DescriptorChain)?;
+    reader
+        .read_obj::<virtio_snd_pcm_xfer>()
+        .map_err(Error::Io)?;
+
+    let status_size = size_of::<virtio_snd_pcm_status>();
+    let data_len = writer.available_bytes().saturating_sub(status_size);
+    let mut status_writer = writer.split_at(data_len);
+
+    let mut status = VIRTIO_SND_S_OK;
+    let mut data = Vec::new();
+    while writer.available_bytes() > 0 {
+        let mut buffer = stream.next_capture_buffer().map_err(Error::StreamBuffer)?;
+        data.resize(writer.available_bytes(), 0);
+        match buffer.read(&mut data) {
+            Ok(0) => break,
+            Ok(n) => writer.write_all(&data[..n]).map_err(Error::Io)?,
+            Err(e) => {
+                warn!("virtio-snd: failed to read capture buffer: {}", e);
+                status = VIRTIO_SND_S_IO_ERR;
+                break;
+            }
+        }
+    }
+    write_status(&mut status_writer, status, latency_bytes)?;
+    Ok(writer.bytes_written() + status_writer.bytes_written())
+}
+
+// Returns a buffer to the guest without touching the host stream, e.g. when the stream is
+// released while the guest still has buffers queued.
+fn return_unused_buffer(mem: &GuestMemory, desc: DescriptorChain, status: u32) -> usize {
+    let mut writer = match Writer::new(mem.clone(), desc) {
+        Ok(w) => w,
+        Err(e) => {
+            error!("virtio-snd: invalid descriptor chain: {}", e);
+            return 0;
+        }
+    };
+    let status_size = size_of::<virtio_snd_pcm_status>();
+    let data_len = writer.available_bytes().saturating_sub(status_size);
+    let mut status_writer = writer.split_at(data_len);
+    if let Err(e) = write_status(&mut status_writer, status, 0) {
+        error!("virtio-snd: failed to write pcm status: {}", e);
+    }
+    status_writer.bytes_written()
+}
+
+enum HostStream {
+    Playback(Box<dyn PlaybackBufferStream>),
+    Capture(Box<dyn CaptureBufferStream>),
+}
+
+// Moves the buffers the guest queues for stream `stream_id` to and from its host stream while it is
+// started. Buffers queued while it is stopped wait for it to start again, or are handed back when
+// it is released.
+fn run_pcm_worker(
+    stream_id: u32,
+    receiver: Receiver<PcmCommand>,
+    shared: SharedQueue,
+    events: EventQueue,
+    latency_bytes: u32,
+) {
+    let mut stream = None;
+    let mut pending = VecDeque::new();
+    let mut failed = false;
+    while let Ok(command) = receiver.recv() {
+        match command {
+            PcmCommand::Buffer(desc) => pending.push_back(desc),
+            PcmCommand::Start(s) => {
+                stream = Some(s);
+                failed = false;
+            }
+            PcmCommand::Stop => stream = None,
+        }
+        let stream = match stream.as_mut() {
+            Some(s) => s,
+            None => continue,
+        };
+        while let Some(desc) = pending.pop_front() {
+            let index = desc.index;
+            let len = if failed {
+                return_unused_buffer(&shared.mem, desc, VIRTIO_SND_S_IO_ERR)
+            } else {
+                let res = match stream {
+                    HostStream::Playback(s) => {
+                        play_buffer(s.as_mut(), &shared.mem, desc.clone(), latency_bytes)
+                    }
+                    HostStream::Capture(s) => {
+                        capture_buffer(s.as_mut(), &shared.mem, desc.clone(), latency_bytes)
+                    }
+                };
+                match res {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("virtio-snd: pcm stream failed: {}", e);
+                        failed = true;
+                        events.send(VIRTIO_SND_EVT_PCM_XRUN, stream_id);
+                        return_unused_buffer(&shared.mem, desc, VIRTIO_SND_S_IO_ERR)
+                    }
+                }
+            };
+            shared.return_descriptor(index, len);
+        }
+    }
+    for desc in pending {
+        let index = desc.index;
+        let len = return_unused_buffer(&shared.mem, desc, VIRTIO_SND_S_OK);
+        shared.return_descriptor(index, len);
+    }
+}
+
+/// Processes the control, event, tx and rx queues of the virtio-snd device.
+pub struct Worker {
+    mem: GuestMemory,
+    interrupt: Arc<Interrupt>,
+    control_queue: Queue,
+    event_queue: EventQueue,
+    tx_queue: SharedQueue,
+    rx_queue: SharedQueue,
+    audio_server: Box<dyn StreamSource>,
+    capture: bool,
+    streams: Vec<PcmStream>,
+}
+
+impl Worker {
+    pub fn new(
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        audio_server: Box<dyn StreamSource>,
+        capture: bool,
+        stream_configs: &[StreamConfig],
+    ) -> Worker {
+        let interrupt = Arc::new(interrupt);
+        let rx_queue = queues.remove(VIRTIO_SND_QUEUE_RX);
+        let tx_queue = queues.remove(VIRTIO_SND_QUEUE_TX);
+        let event_queue = queues.remove(VIRTIO_SND_QUEUE_EVENT);
+        let control_queue = queues.remove(VIRTIO_SND_QUEUE_CONTROL);
+        let shared = |queue| SharedQueue {
+            queue: Arc::new(Mutex::new(queue)),
+            mem: mem.clone(),
+            interrupt: interrupt.clone(),
+        };
+        Worker {
+            event_queue: EventQueue {
+                queue: shared(event_queue),
+                pending: Arc::new(Mutex::new(VecDeque::new())),
+            },
+            tx_queue: shared(tx_queue),
+            rx_queue: shared(rx_queue),
+            mem,
+            interrupt,
+            control_queue,
+            audio_server,
+            capture,
+            streams: stream_configs.iter().cloned().map(PcmStream::new).collect(),
+        }
+    }
+
+    /// Releases all streams and returns the host audio server so the device can be reactivated.
+    pub fn into_audio_server(mut self) -> Box<dyn StreamSource> {
+        for stream in self.streams.iter_mut() {
+            stream.release();
+        }
+        self.audio_server
+    }
+
+    fn jacks(&self) -> Vec<virtio_snd_jack_info> {
+        let mut jacks = vec![virtio_snd_jack_info {
+            hdr: virtio_snd_info {
+                hda_fn_nid: Le32::from(VIRTIO_SND_D_OUTPUT as u32),
+            },
+            connected: 1,
+            ..Default::default()
+        }];
+        if self.capture {
+            jacks.push(virtio_snd_jack_info {
+                hdr: virtio_snd_info {
+                    hda_fn_nid: Le32::from(VIRTIO_SND_D_INPUT as u32),
+                },
+                connected: 1,
+                ..Default::default()
+            });
+        }
+        jacks
+    }
+
+    fn pcm_info(&self) -> Vec<virtio_snd_pcm_info> {
+        let formats = SUPPORTED_FORMATS.iter().fold(0u64, |f, v| f | 1 << v);
+        let rates = SUPPORTED_RATES.iter().fold(0u64, |r, v| r | 1 << v);
+        self.streams
+            .iter()
+            .map(|s| virtio_snd_pcm_info {
+                hdr: virtio_snd_info {
+                    hda_fn_nid: Le32::from(s.config.direction as u32),
+                },
+                features: Le32::from(0),
+                formats: Le64::from(formats),
+                rates: Le64::from(rates),
+                direction: s.config.direction,
+                channels_min: 1,
+                channels_max: 2,
+                padding: [0; 5],
+            })
+            .collect()
+    }
+
+    fn chmap_info(&self) -> Vec<virtio_snd_chmap_info> {
+        self.streams
+            .iter()
+            .map(|s| {
+                let channels = s.params.map(|p| p.channels).unwrap_or(2);
+                virtio_snd_chmap_info {
+                    hdr: virtio_snd_info {
+                        hda_fn_nid: Le32::from(s.config.direction as u32),
+                    },
+                    direction: s.config.direction,
+                    channels,
+                    positions: channel_positions(channels),
+                }
+            })
+            .collect()
+    }
+
+    // Writes the `count` items starting at `start_id` of `items` in response to a query.
+    fn write_query_response<T: DataInit>(
+        writer: &mut Writer,
+        query: virtio_snd_query_info,
+        items: &[T],
+    ) -> Result<()> {
+        let start_id = query.start_id.to_native() as usize;
+        let count = query.count.to_native() as usize;
+        let size = query.size.to_native() as usize;
+        let end = start_id.checked_add(count).unwrap_or(usize::MAX);
+        if end > items.len() || size < size_of::<T>() {
+            return Self::write_hdr(writer, VIRTIO_SND_S_BAD_MSG);
+        }
+        Self::write_hdr(writer, VIRTIO_SND_S_OK)?;
+        let padding = vec![0u8; size - size_of::<T>()];
+        for item in &items[start_id..end] {
+            writer.write_obj(*item).map_err(Error::Io)?;
+            writer.write_all(&padding).map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
+    fn write_hdr(writer: &mut Writer, status: u32) -> Result<()> {
+        writer
+            .write_obj(virtio_snd_hdr {
+                code: Le32::from(status),
+            })
+            .map_err(Error::Io)
+    }
+
+    fn set_params(&mut self, params: virtio_snd_pcm_set_params) -> u32 {
+        let stream_id = params.hdr.stream_id.to_native() as usize;
+        let stream = match self.streams.get_mut(stream_id) {
+            Some(s) => s,
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        match stream.state {
+            PcmState::Idle | PcmState::ParamsSet | PcmState::Prepared => (),
+            _ => return VIRTIO_SND_S_BAD_MSG,
+        }
+        let format = match from_virtio_sample_format(params.format) {
+            Some(f) if SUPPORTED_FORMATS.contains(&params.format) => f,
+            _ => return VIRTIO_SND_S_NOT_SUPP,
+        };
+        let frame_rate = match from_virtio_frame_rate(params.rate) {
+            Some(r) if SUPPORTED_RATES.contains(&params.rate) => r,
+            _ => return VIRTIO_SND_S_NOT_SUPP,
+        };
+        if params.channels == 0 || params.channels > 2 {
+            return VIRTIO_SND_S_NOT_SUPP;
+        }
+        let period_bytes = params.period_bytes.to_native();
+        let buffer_bytes = params.buffer_bytes.to_native();
+        if period_bytes == 0 || buffer_bytes < period_bytes {
+            return VIRTIO_SND_S_BAD_MSG;
+        }
+        // Changing the parameters of a prepared stream requires recreating the host stream.
+        stream.release();
+        stream.params = Some(PcmParams {
+            period_bytes,
+            channels: params.channels,
+            format,
+            frame_rate,
+        });
+        stream.state = PcmState::ParamsSet;
+        VIRTIO_SND_S_OK
+    }
+
+    fn prepare(&mut self, stream_id: usize) -> u32 {
+        let stream = match self.streams.get_mut(stream_id) {
+            Some(s) => s,
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        let params = match (stream.state, stream.params) {
+            (PcmState::Prepared, _) => return VIRTIO_SND_S_OK,
+            (PcmState::ParamsSet, Some(params)) => params,
+            _ => return VIRTIO_SND_S_BAD_MSG,
+        };
+
+        let shared = if stream.config.direction == VIRTIO_SND_D_OUTPUT {
+            self.tx_queue.clone()
+        } else {
+            self.rx_queue.clone()
+        };
+        let events = self.event_queue.clone();
+        let frames = buffer_frames(&params, stream.config.latency_ms);
+        let latency_bytes = (frames * frame_size(&params)) as u32;
+        let (sender, receiver) = channel();
+        let thread = match thread::Builder::new()
+            .name(format!("virtio_snd_pcm{}", stream_id))
+            .spawn(move || {
+                run_pcm_worker(stream_id as u32, receiver, shared, events, latency_bytes)
+            })
+            .map_err(Error::SpawnThread)
+        {
+            Ok(t) => t,
+            Err(e) => {
+                error!("virtio-snd: {}", e);
+                return VIRTIO_SND_S_IO_ERR;
+            }
+        };
+        stream.active = Some(ActiveStream {
+            sender,
+            control: None,
+            thread,
+        });
+        stream.state = PcmState::Prepared;
+        VIRTIO_SND_S_OK
+    }
+
+    // Creates the host stream backing `stream_id` and hands it to the stream's thread, which starts
+    // moving the guest's buffers to or from it.
+    fn start(&mut self, stream_id: usize) -> u32 {
+        let stream = match self.streams.get_mut(stream_id) {
+            Some(s) => s,
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        let (params, active) = match (stream.state, stream.params, stream.active.as_mut()) {
+            (PcmState::Prepared, Some(params), Some(active))
+            | (PcmState::Stopped, Some(params), Some(active)) => (params, active),
+            _ => return VIRTIO_SND_S_BAD_MSG,
+        };
+
+        let frames = buffer_frames(&params, stream.config.latency_ms);
+        let (control, host_stream) = if stream.config.direction == VIRTIO_SND_D_OUTPUT {
+            match self.audio_server.new_playback_stream(
+                params.channels as usize,
+                params.format,
+                params.frame_rate,
+                frames,
+            ) {
+                Ok((control, s)) => (control, HostStream::Playback(s)),
+                Err(e) => {
+                    error!("virtio-snd: {}", Error::CreateStream(e));
+                    return VIRTIO_SND_S_IO_ERR;
+                }
+            }
+        } else {
+            match self.audio_server.new_capture_stream(
+                params.channels as usize,
+                params.format,
+                params.frame_rate,
+                frames,
+                &[StreamEffect::NoEffect],
+            ) {
+                Ok((control, s)) => (control, HostStream::Capture(s)),
+                Err(e) => {
+                    error!("virtio-snd: {}", Error::CreateStream(e));
+                    return VIRTIO_SND_S_IO_ERR;
+                }
+            }
+        };
+
+        if active.sender.send(PcmCommand::Start(host_stream)).is_err() {
+            error!(
+                "virtio-snd: pcm worker thread for stream {} exited",
+                stream_id
+            );
+            return VIRTIO_SND_S_IO_ERR;
+        }
+        active.control = Some(control);
+        stream.state = PcmState::Started;
+        VIRTIO_SND_S_OK
+    }
+
+    // Has the thread of `stream_id` drop its host stream, which stops it.
+    fn stop(&mut self, stream_id: usize) -> u32 {
+        let stream = match self.streams.get_mut(stream_id) {
+            Some(s) => s,
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        let active = match (stream.state, stream.active.as_mut()) {
+            (PcmState::Started, Some(active)) => active,
+            _ => return VIRTIO_SND_S_BAD_MSG,
+        };
+        if active.sender.send(PcmCommand::Stop).is_err() {
+            error!(
+                "virtio-snd: pcm worker thread for stream {} exited",
+                stream_id
+            );
+            return VIRTIO_SND_S_IO_ERR;
+        }
+        active.control = None;
+        stream.state = PcmState::Stopped;
+        VIRTIO_SND_S_OK
+    }
+
+    fn release(&mut self, stream_id: usize) -> u32 {
+        match self.streams.get_mut(stream_id) {
+            Some(stream) => match stream.state {
+                PcmState::Prepared | PcmState::Stopped | PcmState::ParamsSet => {
+                    stream.release();
+                    VIRTIO_SND_S_OK
+                }
+                _ => VIRTIO_SND_S_BAD_MSG,
+            },
+            None => VIRTIO_SND_S_BAD_MSG,
+        }
+    }
+
+    fn process_control_request(&mut self, desc: DescriptorChain) -> Result<usize> {
+        let code = Reader::new(self.mem.clone(), desc.clone())
+            .map_err(Error::DescriptorChain)?
+            .read_obj::<virtio_snd_hdr>()
+            .map_err(Error::Io)?
+            .code
+            .to_native();
+        let mut reader =
+            Reader::new(self.mem.clone(), desc.clone()).map_err(Error::DescriptorChain)?;
+        let mut writer = Writer::new(self.mem.clone(), desc).map_err(Error::DescriptorChain)?;
+
+        match code {
+            JACK_INFO => {
+                let query: virtio_snd_query_info = reader.read_obj().map_err(Error::Io)?;
+                Self::write_query_response(&mut writer, query, &self.jacks())?;
+            }
+            STREAM_INFO => {
+                let query: virtio_snd_query_info = reader.read_obj().map_err(Error::Io)?;
+                Self::write_query_response(&mut writer, query, &self.pcm_info())?;
+            }
+            CHANNEL_MAP_INFO => {
+                let query: virtio_snd_query_info = reader.read_obj().map_err(Error::Io)?;
+                Self::write_query_response(&mut writer, query, &self.chmap_info())?;
+            }
+            STREAM_SET_PARAMS => {
+                let params: virtio_snd_pcm_set_params = reader.read_obj().map_err(Error::Io)?;
+                let status = self.set_params(params);
+                Self::write_hdr(&mut writer, status)?;
+            }
+            STREAM_PREPARE | STREAM_RELEASE | STREAM_START | STREAM_STOP => {
+                let hdr: virtio_snd_pcm_hdr = reader.read_obj().map_err(Error::Io)?;
+                let stream_id = hdr.stream_id.to_native() as usize;
+                let status = match code {
+                    STREAM_PREPARE => self.prepare(stream_id),
+                    STREAM_RELEASE => self.release(stream_id),
+                    STREAM_START => self.start(stream_id),
+                    _ => self.stop(stream_id),
+                };
+                Self::write_hdr(&mut writer, status)?;
+            }
+            // Jacks are not remappable, VIRTIO_SND_JACK_F_REMAP isn't advertised.
+            JACK_REMAP => Self::write_hdr(&mut writer, VIRTIO_SND_S_NOT_SUPP)?,
+            _ => {
+                warn!("virtio-snd: unknown control request {:#x}", code);
+                Self::write_hdr(&mut writer, VIRTIO_SND_S_NOT_SUPP)?;
+            }
+        }
+        Ok(writer.bytes_written())
+    }
+
+    fn process_control_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(desc) = self.control_queue.pop(&self.mem) {
+            let index = desc.index;
+            let len = match self.process_control_request(desc) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("virtio-snd: failed to process control request: {}", e);
+                    0
+                }
+            };
+            self.control_queue.add_used(&self.mem, index, len as u32);
+            needs_interrupt = true;
+        }
+        needs_interrupt
+    }
+
+    // Dispatches buffers from the tx or rx queue to the thread of the stream they belong to.
+    fn process_io_queue(&mut self, capture: bool) {
+        let shared = if capture {
+            self.rx_queue.clone()
+        } else {
+            self.tx_queue.clone()
+        };
+        loop {
+            let desc = match shared.queue.lock().pop(&self.mem) {
+                Some(d) => d,
+                None => break,
+            };
+            let stream_id = match Reader::new(self.mem.clone(), desc.clone())
+                .map_err(Error::DescriptorChain)
+                .and_then(|mut r| r.read_obj::<virtio_snd_pcm_xfer>().map_err(Error::Io))
+            {
+                Ok(xfer) => xfer.stream_id.to_native() as usize,
+                Err(e) => {
+                    error!("virtio-snd: invalid pcm transfer: {}", e);
+                    shared.return_descriptor(desc.index, 0);
+                    continue;
+                }
+            };
+            let index = desc.index;
+            let sender = self
+                .streams
+                .get(stream_id)
+                .filter(|s| s.state != PcmState::Idle && s.state != PcmState::ParamsSet)
+                .and_then(|s| s.active.as_ref())
+                .map(|a| a.sender.clone());
+            if let Some(sender) = sender {
+                if sender.send(PcmCommand::Buffer(desc.clone())).is_ok() {
+                    continue;
+                }
+            }
+            let len = return_unused_buffer(&self.mem, desc, VIRTIO_SND_S_BAD_MSG);
+            shared.return_descriptor(index, len);
+        }
+    }
+
+    pub fn run(&mut self, queue_evts: Vec<Event>, kill_evt: Event) {
+        #[derive(PollToken)]
+        enum Token {
+            ControlQueue,
+            EventQueue,
+            TxQueue,
+            RxQueue,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evts[VIRTIO_SND_QUEUE_CONTROL], Token::ControlQueue),
+            (&queue_evts[VIRTIO_SND_QUEUE_EVENT], Token::EventQueue),
+            (&queue_evts[VIRTIO_SND_QUEUE_TX], Token::TxQueue),
+            (&queue_evts[VIRTIO_SND_QUEUE_RX], Token::RxQueue),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            for event in events.iter().filter(|e| e.is_readable) {
+                let queue_evt = match event.token {
+                    Token::ControlQueue => &queue_evts[VIRTIO_SND_QUEUE_CONTROL],
+                    Token::EventQueue => &queue_evts[VIRTIO_SND_QUEUE_EVENT],
+                    Token::TxQueue => &queue_evts[VIRTIO_SND_QUEUE_TX],
+                    Token::RxQueue => &queue_evts[VIRTIO_SND_QUEUE_RX],
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                        continue;
+                    }
+                    Token::Kill => break 'wait,
+                };
+                if let Err(e) = queue_evt.read() {
+                    error!("failed reading queue Event: {}", e);
+                    break 'wait;
+                }
+                match event.token {
+                    Token::ControlQueue => {
+                        if self.process_control_queue() {
+                            self.interrupt.signal_used_queue(self.control_queue.vector);
+                        }
+                    }
+                    Token::EventQueue => self.event_queue.flush(),
+                    Token::TxQueue => self.process_io_queue(false),
+                    Token::RxQueue => self.process_io_queue(true),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(period_bytes: u32, frame_rate: u32) -> PcmParams {
+        PcmParams {
+            period_bytes,
+            channels: 2,
+            format: SampleFormat::S16LE,
+            frame_rate,
+        }
+    }
+
+    #[test]
+    fn buffer_frames_uses_guest_period() {
+        // 1024 bytes of stereo S16LE is 256 frames.
+        assert_eq!(buffer_frames(&params(1024, 48000), 0), 256);
+    }
+
+    #[test]
+    fn buffer_frames_honors_configured_latency() {
+        // 20ms at 48kHz is 960 frames, more than the guest period.
+        assert_eq!(buffer_frames(&params(1024, 48000), 20), 960);
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Client for an external swtpm process.
+//!
+//! swtpm is controlled through a control socket that takes big-endian 32-bit command codes followed
+//! by their arguments and answers with a 32-bit result code. TPM commands themselves go over a
+//! separate data socket, which crosvm hands to swtpm over the control socket with
+//! `CMD_SET_DATAFD`. swtpm keeps the TPM state in its own state directory, so it survives crosvm
+//! restarts.
+
+use std::fmt::{self, Display};
+use std::io::{self, IoSlice, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use base::{RawDescriptor, ScmSocket};
+use remain::sorted;
+
+// Control commands from swtpm's tpm_ioctl.h.
+const CMD_INIT: u32 = 2;
+const CMD_SET_DATAFD: u32 = 16;
+
+// Size of the TPM response header: tag (2 bytes), response size (4 bytes) and response code
+// (4 bytes).
+const TPM_RESPONSE_HEADER_SIZE: usize = 10;
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the swtpm control socket.
+    Connect(PathBuf, io::Error),
+    /// swtpm failed a control command.
+    ControlCommand { cmd: u32, result: u32 },
+    /// Failed to send a control command or read its result.
+    ControlSocket(u32, io::Error),
+    /// Failed to create the data socket pair.
+    CreateDataSocket(io::Error),
+    /// Failed to send a TPM command or read its response.
+    DataSocket(io::Error),
+    /// swtpm sent a response with an invalid size in its header.
+    ResponseSize(usize),
+    /// Failed to send the data socket to swtpm.
+    SendDataSocket(base::Error),
+}
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            Connect(p, e) => write!(f, "failed to connect to swtpm at {}: {}", p.display(), e),
+            ControlCommand { cmd, result } => {
+                write!(f, "swtpm control command {} failed: {:#x}", cmd, result)
+            }
+            ControlSocket(cmd, e) => write!(f, "swtpm control command {} failed: {}", cmd, e),
+            CreateDataSocket(e) => write!(f, "failed to create the swtpm data socket: {}", e),
+            DataSocket(e) => write!(f, "failed to talk to swtpm: {}", e),
+            ResponseSize(size) => write!(f, "swtpm response has an invalid size of {}", size),
+            SendDataSocket(e) => write!(f, "failed to send the data socket to swtpm: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A connection to an swtpm process started with `--ctrl type=unixio,path=PATH`.
+pub struct Swtpm {
+    ctrl: UnixStream,
+    data: UnixStream,
+}
+
+impl Swtpm {
+    /// Connects to the swtpm control socket at `ctrl_path`, hands swtpm a data socket and
+    /// initializes the TPM.
+    pub fn connect(ctrl_path: &Path) -> Result<Swtpm> {
+        let ctrl =
+            UnixStream::connect(ctrl_path).map_err(|e| Error::Connect(ctrl_path.to_owned(), e))?;
+        let (data, swtpm_data) = UnixStream::pair().map_err(Error::CreateDataSocket)?;
+        let mut swtpm = Swtpm { ctrl, data };
+
+        // The data socket rides along with the command code; CMD_SET_DATAFD takes no arguments.
+        let cmd = CMD_SET_DATAFD.to_be_bytes();
+        swtpm
+            .ctrl
+            .send_with_fd(&[IoSlice::new(&cmd)], swtpm_data.as_raw_fd())
+            .map_err(Error::SendDataSocket)?;
+        swtpm.read_result(CMD_SET_DATAFD)?;
+
+        // No init flags: keep any volatile state swtpm saved.
+        swtpm.control(CMD_INIT, &0u32.to_be_bytes())?;
+        Ok(swtpm)
+    }
+
+    fn control(&mut self, cmd: u32, args: &[u8]) -> Result<()> {
+        let mut request = cmd.to_be_bytes().to_vec();
+        request.extend_from_slice(args);
+        self.ctrl
+            .write_all(&request)
+            .map_err(|e| Error::ControlSocket(cmd, e))?;
+        self.read_result(cmd)
+    }
+
+    fn read_result(&mut self, cmd: u32) -> Result<()> {
+        let mut result = [0u8; 4];
+        self.ctrl
+            .read_exact(&mut result)
+            .map_err(|e| Error::ControlSocket(cmd, e))?;
+        match u32::from_be_bytes(result) {
+            0 => Ok(()),
+            result => Err(Error::ControlCommand { cmd, result }),
+        }
+    }
+
+    /// Sends a TPM command and returns swtpm's response, which may be at most `max_size` bytes.
+    pub fn execute_command(&mut self, command: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        self.data.write_all(command).map_err(Error::DataSocket)?;
+
+        let mut response = vec![0u8; TPM_RESPONSE_HEADER_SIZE];
+        self.data
+            .read_exact(&mut response)
+            .map_err(Error::DataSocket)?;
+        let size =
+            u32::from_be_bytes([response[2], response[3], response[4], response[5]]) as usize;
+        if size < TPM_RESPONSE_HEADER_SIZE || size > max_size {
+            return Err(Error::ResponseSize(size));
+        }
+        response.resize(size, 0);
+        self.data
+            .read_exact(&mut response[TPM_RESPONSE_HEADER_SIZE..])
+            .map_err(Error::DataSocket)?;
+        Ok(response)
+    }
+
+    /// Returns the sockets that must be kept open after jailing.
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.ctrl.as_raw_fd(), self.data.as_raw_fd()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn execute_command() {
+        let (ctrl, _ctrl_peer) = UnixStream::pair().unwrap();
+        let (data, mut peer) = UnixStream::pair().unwrap();
+        let mut swtpm = Swtpm { ctrl, data };
+
+        // A TPM2 response with only a header and a success code.
+        let response = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0];
+        peer.write_all(&response).unwrap();
+        assert_eq!(
+            swtpm.execute_command(&[1, 2, 3], 4096).unwrap(),
+            response.to_vec()
+        );
+        let mut command = [0u8; 3];
+        peer.read_exact(&mut command).unwrap();
+        assert_eq!(command, [1, 2, 3]);
+
+        // A response claiming to be larger than allowed.
+        peer.write_all(&[0x80, 0x01, 0, 0, 0x20, 0, 0, 0, 0, 0])
+            .unwrap();
+        match swtpm.execute_command(&[1], 4096) {
+            Err(Error::ResponseSize(0x2000)) => {}
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+}
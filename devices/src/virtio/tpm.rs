@@ -13,6 +13,7 @@ use std::thread;
 use base::{error, Event, PollToken, RawDescriptor, WaitContext};
 use vm_memory::GuestMemory;
 
+use super::swtpm::{self, Swtpm};
 use super::{
     DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_TPM,
 };
@@ -37,8 +38,17 @@ struct Worker {
     device: Device,
 }
 
-struct Device {
-    simulator: tpm2::Simulator,
+/// Where the vTPM device sends the guest's TPM commands.
+pub enum TpmBackend {
+    /// The built-in libtpm2 simulator, which keeps its state in the given directory.
+    Simulator(PathBuf),
+    /// An external swtpm process, which manages its own state.
+    Swtpm(Swtpm),
+}
+
+enum Device {
+    Simulator(tpm2::Simulator),
+    Swtpm(Swtpm),
 }
 
 impl Device {
@@ -56,7 +66,12 @@ impl Device {
         let mut command = vec![0u8; available_bytes];
         reader.read_exact(&mut command).map_err(Error::Read)?;
 
-        let response = self.simulator.execute_command(&command);
+        let response = match self {
+            Device::Simulator(simulator) => simulator.execute_command(&command).to_vec(),
+            Device::Swtpm(swtpm) => swtpm
+                .execute_command(&command, TPM_BUFSIZE)
+                .map_err(Error::Swtpm)?,
+        };
 
         if response.len() > TPM_BUFSIZE {
             return Err(Error::ResponseTooLong {
@@ -156,15 +171,15 @@ impl Worker {
 
 /// Virtio vTPM device.
 pub struct Tpm {
-    storage: PathBuf,
+    backend: Option<TpmBackend>,
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Tpm {
-    pub fn new(storage: PathBuf) -> Tpm {
+    pub fn new(backend: TpmBackend) -> Tpm {
         Tpm {
-            storage,
+            backend: Some(backend),
             kill_evt: None,
             worker_thread: None,
         }
@@ -185,7 +200,10 @@ impl Drop for Tpm {
 
 impl VirtioDevice for Tpm {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        Vec::new()
+        match &self.backend {
+            Some(TpmBackend::Swtpm(swtpm)) => swtpm.keep_rds(),
+            _ => Vec::new(),
+        }
     }
 
     fn device_type(&self) -> u32 {
@@ -209,15 +227,24 @@ impl VirtioDevice for Tpm {
         let queue = queues.remove(0);
         let queue_evt = queue_evts.remove(0);
 
-        if let Err(err) = fs::create_dir_all(&self.storage) {
-            error!("vtpm failed to create directory for simulator: {}", err);
-            return;
-        }
-        if let Err(err) = env::set_current_dir(&self.storage) {
-            error!("vtpm failed to change into simulator directory: {}", err);
-            return;
-        }
-        let simulator = tpm2::Simulator::singleton_in_current_directory();
+        let device = match self.backend.take() {
+            Some(TpmBackend::Simulator(storage)) => {
+                if let Err(err) = fs::create_dir_all(&storage) {
+                    error!("vtpm failed to create directory for simulator: {}", err);
+                    return;
+                }
+                if let Err(err) = env::set_current_dir(&storage) {
+                    error!("vtpm failed to change into simulator directory: {}", err);
+                    return;
+                }
+                Device::Simulator(tpm2::Simulator::singleton_in_current_directory())
+            }
+            Some(TpmBackend::Swtpm(swtpm)) => Device::Swtpm(swtpm),
+            None => {
+                error!("vtpm was activated more than once");
+                return;
+            }
+        };
 
         let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
             Ok(v) => v,
@@ -234,7 +261,7 @@ impl VirtioDevice for Tpm {
             mem,
             queue_evt,
             kill_evt,
-            device: Device { simulator },
+            device,
         };
 
         let worker_result = thread::Builder::new()
@@ -274,6 +301,7 @@ enum Error {
     Read(io::Error),
     ResponseTooLong { size: usize },
     BufferTooSmall { size: usize, required: usize },
+    Swtpm(swtpm::Error),
     Write(io::Error),
 }
 
@@ -299,6 +327,7 @@ impl Display for Error {
                 "vtpm response buffer is too small: {} < {} bytes",
                 size, required
             ),
+            Swtpm(e) => write!(f, "vtpm {}", e),
             Write(e) => write!(f, "vtpm failed to write to guest memory: {}", e),
         }
     }
@@ -13,11 +13,15 @@ use vhost::Error as VhostError;
 
 mod control_socket;
 mod net;
+mod status;
+mod user;
 mod vsock;
 mod worker;
 
 pub use self::control_socket::*;
 pub use self::net::Net;
+pub use self::status::{VhostUserRegistry, VhostUserStatus, MAX_VHOST_USER_DEVICES};
+pub use self::user::VhostUserDevice;
 pub use self::vsock::Vsock;
 
 #[sorted]
@@ -69,10 +73,25 @@ pub enum Error {
     VhostSetVringKick(VhostError),
     /// Set vring num failed.
     VhostSetVringNum(VhostError),
+    /// Reading the config space of a vhost-user backend failed.
+    VhostUserGetConfig(VhostError),
+    /// Asking a vhost-user backend for its number of queues failed.
+    VhostUserGetQueueNum(VhostError),
+    /// A vhost-user backend reported an unusable number of queues.
+    VhostUserInvalidQueueNum(u64),
+    /// Arming or reading the timer for reconnecting to a vhost-user backend failed.
+    VhostUserReconnectTimer(SysError),
+    /// Enabling or disabling a vring of a vhost-user backend failed.
+    VhostUserSetVringEnable(VhostError),
+    /// A vhost-user backend can't report its number of queues, and the device type has no usual
+    /// number.
+    VhostUserUnknownQueueNum(u32),
     /// Failed to set CID for guest.
     VhostVsockSetCid(VhostError),
     /// Failed to start vhost-vsock driver.
     VhostVsockStart(VhostError),
+    /// Removing an event from the wait context failed.
+    WaitContextDelete(SysError),
     /// Error while waiting for events.
     WaitError(SysError),
 }
@@ -109,8 +128,29 @@ impl Display for Error {
             VhostSetVringCall(e) => write!(f, "failed to set vring call: {}", e),
             VhostSetVringKick(e) => write!(f, "failed to set vring kick: {}", e),
             VhostSetVringNum(e) => write!(f, "failed to set vring num: {}", e),
+            VhostUserGetConfig(e) => write!(f, "failed to get vhost-user config: {}", e),
+            VhostUserGetQueueNum(e) => {
+                write!(
+                    f,
+                    "failed to get the vhost-user backend's queue count: {}",
+                    e
+                )
+            }
+            VhostUserInvalidQueueNum(n) => {
+                write!(f, "invalid vhost-user backend queue count: {}", n)
+            }
+            VhostUserReconnectTimer(e) => {
+                write!(f, "failed to use vhost-user reconnect timer: {}", e)
+            }
+            VhostUserSetVringEnable(e) => write!(f, "failed to set vring enable: {}", e),
+            VhostUserUnknownQueueNum(device_type) => write!(
+                f,
+                "vhost-user backend can't report its queue count for device type {}",
+                device_type
+            ),
             VhostVsockSetCid(e) => write!(f, "failed to set CID for guest: {}", e),
             VhostVsockStart(e) => write!(f, "failed to start vhost-vsock driver: {}", e),
+            WaitContextDelete(e) => write!(f, "failed to remove from wait context: {}", e),
             WaitError(e) => write!(f, "failed waiting for events: {}", e),
         }
     }
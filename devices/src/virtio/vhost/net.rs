@@ -51,13 +51,21 @@ where
         mac_addr: MacAddress,
         mem: &GuestMemory,
     ) -> Result<Net<T, U>> {
-        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
-
         let tap: T = T::new(true, false).map_err(Error::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
         tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
         tap.set_mac_address(mac_addr)
             .map_err(Error::TapSetMacAddress)?;
+        tap.enable().map_err(Error::TapEnable)?;
+        let vhost_net_handle = U::new(mem).map_err(Error::VhostOpen)?;
+
+        Net::from(base_features, tap, vhost_net_handle)
+    }
+
+    /// Create a new virtio network device from a tap that is already configured and enabled, and
+    /// the vhost-net handle to serve it with. Neither needs CAP_NET_ADMIN from here on.
+    pub fn from(base_features: u64, tap: T, vhost_net_handle: U) -> Result<Net<T, U>> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
 
         // Set offload flags to match the virtio features below.
         tap.set_offload(
@@ -70,9 +78,6 @@ where
         tap.set_vnet_hdr_size(vnet_hdr_size)
             .map_err(Error::TapSetVnetHdrSize)?;
 
-        tap.enable().map_err(Error::TapEnable)?;
-        let vhost_net_handle = U::new(mem).map_err(Error::VhostOpen)?;
-
         let avail_features = base_features
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CSUM
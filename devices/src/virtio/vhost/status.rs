@@ -0,0 +1,151 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Whether each vhost-user device is connected to its backend, reported by
+//! `crosvm stats vhost-user`.
+//!
+//! As with the device counters, the state lives in an anonymous shared mapping that is created
+//! before the device processes are forked, so the main process sees what the sandboxed devices
+//! write without any messages being passed.
+
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use base::{warn, MappedRegion, MemoryMapping, MemoryMappingBuilder, MmapError};
+use sync::Mutex;
+use vm_control::VhostUserConnection;
+
+/// The most devices a `VhostUserRegistry` keeps the state of.
+pub const MAX_VHOST_USER_DEVICES: usize = 32;
+
+// Each device has a word that is set while its backend is away, so that devices start out
+// connected, followed by its count of reconnects.
+const DISCONNECTED: usize = 0;
+const RECONNECTS: usize = 1;
+const SLOT_SIZE: usize = 2 * size_of::<u64>();
+
+fn word(mapping: &MemoryMapping, slot: usize, word: usize) -> &AtomicU64 {
+    let offset = slot * SLOT_SIZE + word * size_of::<u64>();
+    assert!(offset + size_of::<u64>() <= mapping.size());
+    // Safe because the offset is inside the mapping and 8-byte aligned since the mapping is page
+    // aligned, and the returned reference can't outlive the mapping.
+    unsafe { &*(mapping.as_ptr().add(offset) as *const AtomicU64) }
+}
+
+/// Hands out connection state to vhost-user devices and reads it back.
+pub struct VhostUserRegistry {
+    mapping: Arc<MemoryMapping>,
+    // The label and backend socket of each device.
+    devices: Mutex<Vec<(String, String)>>,
+}
+
+impl VhostUserRegistry {
+    /// Creates a registry with room for `MAX_VHOST_USER_DEVICES` devices.
+    pub fn new() -> Result<VhostUserRegistry, MmapError> {
+        let mapping = MemoryMappingBuilder::new(MAX_VHOST_USER_DEVICES * SLOT_SIZE).build()?;
+        Ok(VhostUserRegistry {
+            mapping: Arc::new(mapping),
+            devices: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns the state of a new device labeled `device`, whose backend listens at
+    /// `socket_path`. If the registry is full, the returned state is discarded.
+    pub fn register(&self, device: String, socket_path: &Path) -> VhostUserStatus {
+        let mut devices = self.devices.lock();
+        if devices.len() == MAX_VHOST_USER_DEVICES {
+            warn!("no room left for the connection state of {}", device);
+            return VhostUserStatus::default();
+        }
+        let slot = devices.len();
+        devices.push((device, socket_path.to_string_lossy().into_owned()));
+        VhostUserStatus {
+            slot: Some((self.mapping.clone(), slot)),
+        }
+    }
+
+    /// Reads the current state of every registered device, in registration order.
+    pub fn snapshot(&self) -> Vec<VhostUserConnection> {
+        self.devices
+            .lock()
+            .iter()
+            .enumerate()
+            .map(|(slot, (device, socket_path))| VhostUserConnection {
+                device: device.clone(),
+                socket_path: socket_path.clone(),
+                connected: word(&self.mapping, slot, DISCONNECTED).load(Ordering::Relaxed) == 0,
+                reconnects: word(&self.mapping, slot, RECONNECTS).load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// The connection state of a single vhost-user device. The default value drops every update, for
+/// devices that aren't registered.
+#[derive(Clone, Default)]
+pub struct VhostUserStatus {
+    slot: Option<(Arc<MemoryMapping>, usize)>,
+}
+
+impl VhostUserStatus {
+    fn word(&self, w: usize) -> Option<&AtomicU64> {
+        self.slot
+            .as_ref()
+            .map(|(mapping, slot)| word(mapping, *slot, w))
+    }
+
+    /// Records that the backend went away, to be waited for until it comes back.
+    pub fn set_disconnected(&self) {
+        if let Some(disconnected) = self.word(DISCONNECTED) {
+            disconnected.store(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the device connected again to a backend that came back.
+    pub fn set_reconnected(&self) {
+        if let Some(disconnected) = self.word(DISCONNECTED) {
+            disconnected.store(0, Ordering::Relaxed);
+        }
+        if let Some(reconnects) = self.word(RECONNECTS) {
+            reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_state() {
+        let registry = VhostUserRegistry::new().unwrap();
+        let block = registry.register("vhost-user-block".to_owned(), Path::new("/run/block"));
+        let net = registry.register("vhost-user-net".to_owned(), Path::new("/run/net"));
+
+        block.set_disconnected();
+        block.set_reconnected();
+        net.clone().set_disconnected();
+        VhostUserStatus::default().set_disconnected();
+
+        assert_eq!(
+            registry.snapshot(),
+            vec![
+                VhostUserConnection {
+                    device: "vhost-user-block".to_owned(),
+                    socket_path: "/run/block".to_owned(),
+                    connected: true,
+                    reconnects: 1,
+                },
+                VhostUserConnection {
+                    device: "vhost-user-net".to_owned(),
+                    socket_path: "/run/net".to_owned(),
+                    connected: false,
+                    reconnects: 0,
+                },
+            ]
+        );
+    }
+}
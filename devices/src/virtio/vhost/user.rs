@@ -0,0 +1,577 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use base::{error, info, warn, AsRawDescriptor, Event, RawDescriptor, Timer};
+use msg_socket::{MsgReceiver, MsgSender};
+use resources::Alloc;
+use vhost::user::{
+    BackendRequest, BackendRequestSocket, ShmMapping, VHOST_USER_FS_FLAG_MAP_R,
+    VHOST_USER_FS_FLAG_MAP_W, VHOST_USER_F_PROTOCOL_FEATURES,
+};
+use vhost::{Vhost, VhostUser};
+use vm_control::{FsMappingRequest, FsMappingRequestSocket, MaybeOwnedDescriptor, VmResponse};
+use vm_memory::GuestMemory;
+
+use super::status::VhostUserStatus;
+use super::worker::Worker;
+use super::{Error, Result};
+use crate::pci::{
+    PciAddress, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability,
+};
+use crate::virtio::{
+    type_to_str, Interrupt, PciCapabilityType, Queue, VirtioDevice, VirtioPciShmCap, TYPE_9P,
+    TYPE_BLOCK, TYPE_CONSOLE, TYPE_FS, TYPE_GPU, TYPE_INPUT, TYPE_NET, TYPE_PMEM, TYPE_RNG,
+    TYPE_SOUND, TYPE_VSOCK,
+};
+
+const QUEUE_SIZE: u16 = 256;
+
+// How often to try connecting again to a backend that went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+const SHM_BAR_NUM: u8 = 4;
+const SHM_BAR_OFFSET: u64 = 0;
+
+// A shared memory region of the device, which the backend maps files into.
+struct SharedMemoryRegion {
+    id: u8,
+    size: u64,
+    socket: FsMappingRequestSocket,
+}
+
+// Returns the number of queues of the device types that have a fixed number, or whose number is
+// fixed unless a feature needing multiqueue support from the backend is negotiated.
+fn default_num_queues(device_type: u32) -> Option<usize> {
+    Some(match device_type {
+        TYPE_BLOCK | TYPE_RNG | TYPE_9P | TYPE_PMEM => 1,
+        TYPE_NET | TYPE_CONSOLE | TYPE_INPUT | TYPE_GPU => 2,
+        // The high priority queue and a single request queue.
+        TYPE_FS => 2,
+        TYPE_VSOCK => 3,
+        TYPE_SOUND => 4,
+        _ => return None,
+    })
+}
+
+/// A virtio device of any type whose queues and config space are served by a vhost-user backend.
+///
+/// Nothing about the device is specific to its type: the queues, features and config space are
+/// all those of the backend, which must serve a device of the type given to `new`.
+pub struct VhostUserDevice {
+    device_type: u32,
+    worker_kill_evt: Option<Event>,
+    kill_evt: Option<Event>,
+    // Used for config space accesses, while a clone is handed to the worker on activation.
+    handle: VhostUser,
+    worker_handle: Option<VhostUser>,
+    interrupts: Option<Vec<Event>>,
+    backend_requests: Option<BackendRequestSocket>,
+    shm: Option<SharedMemoryRegion>,
+    pci_bar: Option<Alloc>,
+    status: VhostUserStatus,
+    queue_sizes: Vec<u16>,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl VhostUserDevice {
+    /// Connects to the vhost-user backend listening at `socket_path`, which serves a device of
+    /// `device_type`.
+    ///
+    /// The number of queues is asked of the backend, or is the usual number for `device_type` if
+    /// the backend can't tell.
+    pub fn new<P: AsRef<Path>>(
+        base_features: u64,
+        device_type: u32,
+        socket_path: P,
+        mem: &GuestMemory,
+    ) -> Result<VhostUserDevice> {
+        let (handle, backend_requests) =
+            VhostUser::connect_with_backend_requests(socket_path, mem).map_err(Error::VhostOpen)?;
+
+        let num_queues = match handle
+            .get_queue_num()
+            .map_err(Error::VhostUserGetQueueNum)?
+        {
+            Some(num) => usize::try_from(num)
+                .ok()
+                .filter(|&num| num > 0)
+                .ok_or(Error::VhostUserInvalidQueueNum(num))?,
+            None => default_num_queues(device_type)
+                .ok_or(Error::VhostUserUnknownQueueNum(device_type))?,
+        };
+
+        // The protocol features bit only concerns the frontend, which acks it in any case.
+        let backend_features = handle.get_features().map_err(Error::VhostGetFeatures)?
+            & !VHOST_USER_F_PROTOCOL_FEATURES;
+
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
+        let mut interrupts = Vec::new();
+        for _ in 0..num_queues {
+            interrupts.push(Event::new().map_err(Error::VhostIrqCreate)?);
+        }
+
+        Ok(VhostUserDevice {
+            device_type,
+            worker_kill_evt: Some(kill_evt.try_clone().map_err(Error::CloneKillEvent)?),
+            kill_evt: Some(kill_evt),
+            worker_handle: Some(handle.clone()),
+            handle,
+            interrupts: Some(interrupts),
+            backend_requests,
+            shm: None,
+            pci_bar: None,
+            status: VhostUserStatus::default(),
+            queue_sizes: vec![QUEUE_SIZE; num_queues],
+            avail_features: base_features | backend_features,
+            acked_features: 0,
+        })
+    }
+
+    /// Gives the device the shared memory region `id` of `size` bytes, such as the DAX window of
+    /// virtio-fs, which the backend maps files into through `socket`.
+    pub fn set_shared_memory_region(&mut self, id: u8, size: u64, socket: FsMappingRequestSocket) {
+        self.shm = Some(SharedMemoryRegion { id, size, socket });
+    }
+
+    /// Reports whether the device is connected to its backend through `status`.
+    pub fn set_status(&mut self, status: VhostUserStatus) {
+        self.status = status;
+    }
+}
+
+/// The worker's side of the connection of a vhost-user device to its backend: handles the requests
+/// the backend makes of the device, and connects again to a backend that went away, such as for an
+/// upgrade, so that the device survives it.
+pub struct BackendConnection {
+    handle: VhostUser,
+    requests: Option<BackendRequestSocket>,
+    // The slot of the shared memory region, and the socket mappings are made with.
+    shm: Option<(u32, FsMappingRequestSocket)>,
+    status: VhostUserStatus,
+    reconnect_timer: Timer,
+}
+
+impl BackendConnection {
+    /// The connection whose hangup means the backend went away.
+    pub fn handle(&self) -> &VhostUser {
+        &self.handle
+    }
+
+    /// The channel the backend makes requests over, if it has one.
+    pub fn requests(&self) -> Option<&BackendRequestSocket> {
+        self.requests.as_ref()
+    }
+
+    /// The timer that fires while the backend is away, to try connecting again.
+    pub fn reconnect_timer(&self) -> &Timer {
+        &self.reconnect_timer
+    }
+
+    /// Handles the next backend request. Returns false once the channel is of no more use, when it
+    /// should be taken with `take_requests`.
+    pub fn handle_request(&self, interrupt: &Interrupt) -> bool {
+        let socket = match &self.requests {
+            Some(socket) => socket,
+            None => return false,
+        };
+        let result = socket.handle_request(|request| match request {
+            BackendRequest::ConfigChanged => {
+                interrupt.signal_config_changed();
+                true
+            }
+            BackendRequest::Map { file, mappings } => {
+                mappings.iter().all(|mapping| self.map(&file, mapping))
+            }
+            BackendRequest::Unmap(mappings) => mappings.iter().all(|mapping| self.unmap(mapping)),
+        });
+        match result {
+            Ok(open) => open,
+            Err(e) => {
+                error!("failed to handle vhost-user backend request: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Takes the channel for backend requests, once it is of no more use.
+    pub fn take_requests(&mut self) -> Option<BackendRequestSocket> {
+        self.requests.take()
+    }
+
+    /// Starts waiting for the backend to come back after it went away.
+    pub fn disconnected(&mut self) -> base::Result<()> {
+        warn!(
+            "vhost-user backend at {} went away, waiting for it to come back",
+            self.socket_path()
+        );
+        self.status.set_disconnected();
+        self.reconnect_timer
+            .reset(RECONNECT_INTERVAL, Some(RECONNECT_INTERVAL))
+    }
+
+    /// Tries connecting again once the reconnect timer fired, returning true if the backend is
+    /// back. The connection then has a new handle, and possibly a new channel for requests.
+    pub fn reconnect(&mut self) -> base::Result<bool> {
+        self.reconnect_timer.wait()?;
+        match self.handle.reconnect() {
+            Ok(requests) => {
+                info!(
+                    "reconnected to vhost-user backend at {}",
+                    self.socket_path()
+                );
+                self.requests = requests;
+                self.status.set_reconnected();
+                self.reconnect_timer.clear()?;
+                Ok(true)
+            }
+            // The backend isn't listening again yet.
+            Err(vhost::Error::VhostUserConnect(_)) => Ok(false),
+            Err(e) => {
+                warn!(
+                    "failed to reconnect to vhost-user backend at {}: {}",
+                    self.socket_path(),
+                    e
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    fn socket_path(&self) -> String {
+        self.handle
+            .path()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    }
+
+    fn map(&self, file: &File, mapping: &ShmMapping) -> bool {
+        let mut prot = 0;
+        if mapping.flags & VHOST_USER_FS_FLAG_MAP_R != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if mapping.flags & VHOST_USER_FS_FLAG_MAP_W != 0 {
+            prot |= libc::PROT_WRITE;
+        }
+        self.mapping_request(|slot| FsMappingRequest::CreateMemoryMapping {
+            slot,
+            fd: MaybeOwnedDescriptor::Borrowed(file.as_raw_descriptor()),
+            size: mapping.len as usize,
+            file_offset: mapping.fd_offset,
+            prot: prot as u32,
+            mem_offset: mapping.shm_offset as usize,
+        })
+    }
+
+    fn unmap(&self, mapping: &ShmMapping) -> bool {
+        self.mapping_request(|slot| FsMappingRequest::RemoveMemoryMapping {
+            slot,
+            offset: mapping.shm_offset as usize,
+            size: mapping.len as usize,
+        })
+    }
+
+    fn mapping_request<F>(&self, request: F) -> bool
+    where
+        F: FnOnce(u32) -> FsMappingRequest,
+    {
+        let (slot, socket) = match &self.shm {
+            Some(shm) => shm,
+            None => {
+                error!("vhost-user backend asked to map memory, but the device has no region");
+                return false;
+            }
+        };
+        let request = request(*slot);
+        if let Err(e) = socket.send(&request) {
+            error!("failed to send request {:?}: {}", request, e);
+            return false;
+        }
+        match socket.recv() {
+            Ok(VmResponse::Ok) => true,
+            r => {
+                error!("failed to process {:?}: {:?}", request, r);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for VhostUserDevice {
+    fn drop(&mut self) {
+        // Only kill the child if it claimed its event.
+        if self.worker_kill_evt.is_none() {
+            if let Some(kill_evt) = &self.kill_evt {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VhostUserDevice {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = vec![self.handle.as_raw_descriptor()];
+
+        if let Some(backend_requests) = &self.backend_requests {
+            keep_rds.push(backend_requests.as_raw_descriptor());
+        }
+
+        if let Some(shm) = &self.shm {
+            keep_rds.push(shm.socket.as_raw_descriptor());
+        }
+
+        if let Some(interrupts) = &self.interrupts {
+            for vhost_int in interrupts.iter() {
+                keep_rds.push(vhost_int.as_raw_descriptor());
+            }
+        }
+
+        if let Some(worker_kill_evt) = &self.worker_kill_evt {
+            keep_rds.push(worker_kill_evt.as_raw_descriptor());
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("{}: got unknown feature ack: {:x}", self.debug_label(), v);
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let result = match u32::try_from(offset) {
+            Ok(offset) => self
+                .handle
+                .get_config(offset, data)
+                .map_err(|e| e.to_string()),
+            Err(_) => Err("offset out of range".to_string()),
+        };
+        if let Err(e) = result {
+            error!("{}: failed to read config space: {}", self.debug_label(), e);
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let result = match u32::try_from(offset) {
+            Ok(offset) => self
+                .handle
+                .set_config(offset, data)
+                .map_err(|e| e.to_string()),
+            Err(_) => Err("offset out of range".to_string()),
+        };
+        if let Err(e) = result {
+            error!(
+                "{}: failed to write config space: {}",
+                self.debug_label(),
+                e
+            );
+        }
+    }
+
+    fn activate(
+        &mut self,
+        _: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            error!(
+                "{}: expected {} queues, got {}",
+                self.debug_label(),
+                self.queue_sizes.len(),
+                queues.len()
+            );
+            return;
+        }
+
+        // Set up the shared memory region before the backend can map anything into it.
+        // TODO(b/176129399): Remove cfg! once DAX is supported on ARM.
+        let mut shm = None;
+        if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            if let (Some(region), Some(pci_bar)) = (self.shm.take(), &self.pci_bar) {
+                match allocate_shm(&region.socket, pci_bar) {
+                    Ok(slot) => shm = Some((slot, region.socket)),
+                    Err(e) => {
+                        error!("{}: {}", self.debug_label(), e);
+                        return;
+                    }
+                }
+            }
+        }
+        let reconnect_timer = match Timer::new() {
+            Ok(timer) => timer,
+            Err(e) => {
+                error!(
+                    "{}: failed to create reconnect timer: {}",
+                    self.debug_label(),
+                    e
+                );
+                return;
+            }
+        };
+
+        if let Some(vhost_handle) = self.worker_handle.take() {
+            let backend = BackendConnection {
+                handle: vhost_handle.clone(),
+                requests: self.backend_requests.take(),
+                shm,
+                status: self.status.clone(),
+                reconnect_timer,
+            };
+            if let Some(interrupts) = self.interrupts.take() {
+                if let Some(kill_evt) = self.worker_kill_evt.take() {
+                    let acked_features = self.acked_features;
+                    let queue_sizes = self.queue_sizes.clone();
+                    // The backend only processes the queues the driver set up.
+                    let ready: Vec<bool> = queues.iter().map(|q| q.ready).collect();
+                    let worker_result =
+                        thread::Builder::new()
+                            .name(self.debug_label())
+                            .spawn(move || {
+                                let mut worker = Worker::new(
+                                    queues,
+                                    vhost_handle,
+                                    interrupts,
+                                    interrupt,
+                                    acked_features,
+                                    kill_evt,
+                                    None,
+                                );
+                                worker.backend = Some(backend);
+                                let activate_vqs = |handle: &VhostUser| -> Result<()> {
+                                    for (index, _) in ready.iter().enumerate().filter(|(_, r)| **r)
+                                    {
+                                        handle
+                                            .set_vring_enable(index, true)
+                                            .map_err(Error::VhostUserSetVringEnable)?;
+                                    }
+                                    Ok(())
+                                };
+                                let cleanup_vqs = |handle: &VhostUser| -> Result<()> {
+                                    for (index, _) in ready.iter().enumerate().filter(|(_, r)| **r)
+                                    {
+                                        handle
+                                            .set_vring_enable(index, false)
+                                            .map_err(Error::VhostUserSetVringEnable)?;
+                                    }
+                                    Ok(())
+                                };
+                                let result =
+                                    worker.run(queue_evts, &queue_sizes, activate_vqs, cleanup_vqs);
+                                if let Err(e) = result {
+                                    error!("vhost-user worker thread exited with error: {}", e);
+                                }
+                            });
+
+                    if let Err(e) = worker_result {
+                        error!("failed to spawn vhost-user worker: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_device_bars(&mut self, address: PciAddress) -> Vec<PciBarConfiguration> {
+        let shm = match &self.shm {
+            Some(shm) => shm,
+            None => return Vec::new(),
+        };
+        self.pci_bar = Some(Alloc::PciBar {
+            bus: address.bus,
+            dev: address.dev,
+            func: address.func,
+            bar: SHM_BAR_NUM,
+        });
+
+        vec![PciBarConfiguration::new(
+            SHM_BAR_NUM as usize,
+            shm.size,
+            PciBarRegionType::Memory64BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        )]
+    }
+
+    fn get_device_caps(&self) -> Vec<Box<dyn PciCapability>> {
+        match &self.shm {
+            Some(shm) => vec![Box::new(VirtioPciShmCap::new(
+                PciCapabilityType::SharedMemoryConfig,
+                SHM_BAR_NUM,
+                SHM_BAR_OFFSET,
+                shm.size,
+                shm.id,
+            ))],
+            None => Vec::new(),
+        }
+    }
+
+    fn debug_label(&self) -> String {
+        format!(
+            "vhost-user-{}",
+            type_to_str(self.device_type).unwrap_or("unknown")
+        )
+    }
+}
+
+// Maps the region of `pci_bar` as the shared memory region, returning its memory slot.
+fn allocate_shm(
+    socket: &FsMappingRequestSocket,
+    pci_bar: &Alloc,
+) -> std::result::Result<u32, String> {
+    socket
+        .send(&FsMappingRequest::AllocateSharedMemoryRegion(*pci_bar))
+        .map_err(|e| format!("failed to send allocation message: {}", e))?;
+    match socket.recv() {
+        Ok(VmResponse::RegisterMemory { pfn: _, slot }) => Ok(slot),
+        r => Err(format!(
+            "unexpected response to allocate shared memory region: {:?}",
+            r
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_queue_nums() {
+        assert_eq!(default_num_queues(TYPE_BLOCK), Some(1));
+        assert_eq!(default_num_queues(TYPE_NET), Some(2));
+        assert_eq!(default_num_queues(TYPE_VSOCK), Some(3));
+        // Balloons have optional queues depending on the features.
+        assert_eq!(default_num_queues(crate::virtio::TYPE_BALLOON), None);
+    }
+}
@@ -2,13 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::path::Path;
 use std::thread;
 
 use data_model::{DataInit, Le64};
 
 use base::{error, warn, AsRawDescriptor, Event, RawDescriptor};
-use vhost::Vhost;
 use vhost::Vsock as VhostVsockHandle;
+use vhost::{Vhost, VhostUser, VsockT};
 use vm_memory::GuestMemory;
 
 use super::worker::Worker;
@@ -19,28 +20,80 @@ const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
-pub struct Vsock {
+/// Size of the virtio-vsock configuration space, which only holds the guest CID.
+const CONFIG_SPACE_SIZE: usize = 8;
+
+/// The device supports SOCK_SEQPACKET sockets.
+const VIRTIO_VSOCK_F_SEQPACKET: u32 = 1;
+
+// Returns the features offered to the guest on top of `base_features`. Packets, and so the record
+// boundaries of SOCK_SEQPACKET sockets, are handled entirely by the vhost backend, which means
+// SEQPACKET can only be offered when the backend supports it.
+fn device_features(base_features: u64, backend_features: u64) -> u64 {
+    base_features
+        | 1 << virtio_sys::vhost::VIRTIO_F_NOTIFY_ON_EMPTY
+        | 1 << virtio_sys::vhost::VIRTIO_RING_F_INDIRECT_DESC
+        | 1 << virtio_sys::vhost::VIRTIO_RING_F_EVENT_IDX
+        | 1 << virtio_sys::vhost::VHOST_F_LOG_ALL
+        | 1 << virtio_sys::vhost::VIRTIO_F_ANY_LAYOUT
+        | backend_features & 1 << VIRTIO_VSOCK_F_SEQPACKET
+}
+
+pub struct Vsock<T: VsockT = VhostVsockHandle> {
     worker_kill_evt: Option<Event>,
     kill_evt: Option<Event>,
-    vhost_handle: Option<VhostVsockHandle>,
+    vhost_handle: Option<T>,
     cid: u64,
     interrupts: Option<Vec<Event>>,
     avail_features: u64,
     acked_features: u64,
 }
 
-impl Vsock {
+impl Vsock<VhostVsockHandle> {
     /// Create a new virtio-vsock device with the given VM cid.
     pub fn new(base_features: u64, cid: u64, mem: &GuestMemory) -> Result<Vsock> {
-        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
         let handle = VhostVsockHandle::new(mem).map_err(Error::VhostOpen)?;
+        Vsock::with_handle(base_features, cid, handle)
+    }
 
-        let avail_features = base_features
-            | 1 << virtio_sys::vhost::VIRTIO_F_NOTIFY_ON_EMPTY
-            | 1 << virtio_sys::vhost::VIRTIO_RING_F_INDIRECT_DESC
-            | 1 << virtio_sys::vhost::VIRTIO_RING_F_EVENT_IDX
-            | 1 << virtio_sys::vhost::VHOST_F_LOG_ALL
-            | 1 << virtio_sys::vhost::VIRTIO_F_ANY_LAYOUT;
+    pub fn new_for_testing(cid: u64, features: u64) -> Vsock {
+        Vsock {
+            worker_kill_evt: None,
+            kill_evt: None,
+            vhost_handle: None,
+            cid,
+            interrupts: None,
+            avail_features: features,
+            acked_features: 0,
+        }
+    }
+}
+
+impl Vsock<VhostUser> {
+    /// Create a new virtio-vsock device whose datapath is served by the vhost-user backend
+    /// listening at `socket_path`.
+    ///
+    /// The guest CID is read from the backend's configuration space.
+    pub fn new_vhost_user<P: AsRef<Path>>(
+        base_features: u64,
+        socket_path: P,
+        mem: &GuestMemory,
+    ) -> Result<Vsock<VhostUser>> {
+        let handle = VhostUser::connect(socket_path, mem).map_err(Error::VhostOpen)?;
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+        handle
+            .get_config(0, &mut config)
+            .map_err(Error::VhostUserGetConfig)?;
+        Vsock::with_handle(base_features, u64::from_le_bytes(config), handle)
+    }
+}
+
+impl<T: VsockT> Vsock<T> {
+    fn with_handle(base_features: u64, cid: u64, handle: T) -> Result<Vsock<T>> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
+
+        let backend_features = handle.get_features().map_err(Error::VhostGetFeatures)?;
+        let avail_features = device_features(base_features, backend_features);
 
         let mut interrupts = Vec::new();
         for _ in 0..NUM_QUEUES {
@@ -58,24 +111,12 @@ impl Vsock {
         })
     }
 
-    pub fn new_for_testing(cid: u64, features: u64) -> Vsock {
-        Vsock {
-            worker_kill_evt: None,
-            kill_evt: None,
-            vhost_handle: None,
-            cid,
-            interrupts: None,
-            avail_features: features,
-            acked_features: 0,
-        }
-    }
-
     pub fn acked_features(&self) -> u64 {
         self.acked_features
     }
 }
 
-impl Drop for Vsock {
+impl<T: VsockT> Drop for Vsock<T> {
     fn drop(&mut self) {
         // Only kill the child if it claimed its event.
         if self.worker_kill_evt.is_none() {
@@ -87,12 +128,12 @@ impl Drop for Vsock {
     }
 }
 
-impl VirtioDevice for Vsock {
+impl<T: 'static + VsockT> VirtioDevice for Vsock<T> {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
         let mut keep_rds = Vec::new();
 
         if let Some(handle) = &self.vhost_handle {
-            keep_rds.push(handle.as_raw_descriptor());
+            keep_rds.extend(handle.keep_rds());
         }
 
         if let Some(interrupt) = &self.interrupts {
@@ -171,12 +212,12 @@ impl VirtioDevice for Vsock {
                                 kill_evt,
                                 None,
                             );
-                            let activate_vqs = |handle: &VhostVsockHandle| -> Result<()> {
+                            let activate_vqs = |handle: &T| -> Result<()> {
                                 handle.set_cid(cid).map_err(Error::VhostVsockSetCid)?;
                                 handle.start().map_err(Error::VhostVsockStart)?;
                                 Ok(())
                             };
-                            let cleanup_vqs = |_handle: &VhostVsockHandle| -> Result<()> { Ok(()) };
+                            let cleanup_vqs = |_handle: &T| -> Result<()> { Ok(()) };
                             let result =
                                 worker.run(queue_evts, QUEUE_SIZES, activate_vqs, cleanup_vqs);
                             if let Err(e) = result {
@@ -275,6 +316,15 @@ mod tests {
         assert_eq!(&buf, &data);
     }
 
+    #[test]
+    fn seqpacket_follows_backend() {
+        let seqpacket = 1 << VIRTIO_VSOCK_F_SEQPACKET;
+        assert_eq!(device_features(0, seqpacket) & seqpacket, seqpacket);
+        assert_eq!(device_features(0, 0) & seqpacket, 0);
+        // Other backend features aren't offered to the guest.
+        assert_eq!(device_features(0, 1 << 5) & (1 << 5), 0);
+    }
+
     #[test]
     fn features() {
         let cid = 5;
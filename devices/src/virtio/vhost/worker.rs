@@ -4,10 +4,11 @@
 
 use std::os::raw::c_ulonglong;
 
-use base::{error, Error as SysError, Event, PollToken, WaitContext};
+use base::{error, Error as SysError, Event, EventType, PollToken, WaitContext};
 use vhost::Vhost;
 
 use super::control_socket::{VhostDevRequest, VhostDevResponse, VhostDevResponseSocket};
+use super::user::BackendConnection;
 use super::{Error, Result};
 use crate::virtio::{Interrupt, Queue};
 use libc::EIO;
@@ -22,6 +23,7 @@ pub struct Worker<T: Vhost> {
     acked_features: u64,
     pub kill_evt: Event,
     pub response_socket: Option<VhostDevResponseSocket>,
+    pub backend: Option<BackendConnection>,
 }
 
 impl<T: Vhost> Worker<T> {
@@ -42,6 +44,7 @@ impl<T: Vhost> Worker<T> {
             acked_features,
             kill_evt,
             response_socket,
+            backend: None,
         }
     }
 
@@ -104,6 +107,9 @@ impl<T: Vhost> Worker<T> {
             InterruptResample,
             Kill,
             ControlNotify,
+            BackendRequest,
+            BackendHangup,
+            BackendReconnect,
         }
 
         let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
@@ -122,10 +128,40 @@ impl<T: Vhost> Worker<T> {
                 .add(socket, Token::ControlNotify)
                 .map_err(Error::CreateWaitContext)?;
         }
+        if let Some(backend) = &self.backend {
+            // Waiting for no events still reports hangups.
+            wait_ctx
+                .add_for_event(backend.handle(), EventType::None, Token::BackendHangup)
+                .map_err(Error::CreateWaitContext)?;
+            wait_ctx
+                .add(backend.reconnect_timer(), Token::BackendReconnect)
+                .map_err(Error::CreateWaitContext)?;
+            if let Some(requests) = backend.requests() {
+                wait_ctx
+                    .add(requests, Token::BackendRequest)
+                    .map_err(Error::CreateWaitContext)?;
+            }
+        }
 
         'wait: loop {
             let events = wait_ctx.wait().map_err(Error::WaitError)?;
 
+            for event in events.iter().filter(|e| e.is_hungup) {
+                if let (Token::BackendHangup, Some(backend)) = (&event.token, &mut self.backend) {
+                    wait_ctx
+                        .delete(backend.handle())
+                        .map_err(Error::WaitContextDelete)?;
+                    if let Some(requests) = backend.take_requests() {
+                        wait_ctx
+                            .delete(&requests)
+                            .map_err(Error::WaitContextDelete)?;
+                    }
+                    backend
+                        .disconnected()
+                        .map_err(Error::VhostUserReconnectTimer)?;
+                }
+            }
+
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
                     Token::VhostIrqi { index } => {
@@ -141,6 +177,40 @@ impl<T: Vhost> Worker<T> {
                         let _ = self.kill_evt.read();
                         break 'wait;
                     }
+                    Token::BackendRequest => {
+                        if let Some(backend) = &mut self.backend {
+                            // Stop listening once the backend hangs up or breaks the protocol.
+                            if !backend.handle_request(&self.interrupt) {
+                                if let Some(requests) = backend.take_requests() {
+                                    wait_ctx
+                                        .delete(&requests)
+                                        .map_err(Error::WaitContextDelete)?;
+                                }
+                            }
+                        }
+                    }
+                    Token::BackendHangup => {}
+                    Token::BackendReconnect => {
+                        if let Some(backend) = &mut self.backend {
+                            let reconnected = backend
+                                .reconnect()
+                                .map_err(Error::VhostUserReconnectTimer)?;
+                            if reconnected {
+                                wait_ctx
+                                    .add_for_event(
+                                        backend.handle(),
+                                        EventType::None,
+                                        Token::BackendHangup,
+                                    )
+                                    .map_err(Error::CreateWaitContext)?;
+                                if let Some(requests) = backend.requests() {
+                                    wait_ctx
+                                        .add(requests, Token::BackendRequest)
+                                        .map_err(Error::CreateWaitContext)?;
+                                }
+                            }
+                        }
+                    }
                     Token::ControlNotify => {
                         if let Some(socket) = &self.response_socket {
                             match socket.recv() {
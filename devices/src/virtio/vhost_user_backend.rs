@@ -0,0 +1,842 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serves a virtio device to a vhost-user frontend, so the device can run in a process of its own,
+//! apart from the VM that uses it.
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, IoSlice, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use base::{
+    error, Error as SysError, Event, FromRawDescriptor, ScmSocket, SharedMemory, SharedMemoryUnix,
+    UserfaultFd,
+};
+use remain::sorted;
+use sync::Mutex;
+use vhost::user::*;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+use super::{Interrupt, Queue, VirtioDevice};
+
+// The protocol features a backend supports.
+const BACKEND_PROTOCOL_FEATURES: u64 = VHOST_USER_PROTOCOL_F_MQ
+    | VHOST_USER_PROTOCOL_F_REPLY_ACK
+    | VHOST_USER_PROTOCOL_F_BACKEND_REQ
+    | VHOST_USER_PROTOCOL_F_PAGEFAULT
+    | VHOST_USER_PROTOCOL_F_CONFIG
+    | VHOST_USER_PROTOCOL_F_BACKEND_SEND_FD;
+
+// No request carries more than the memory table or a config space.
+const MAX_PAYLOAD_SIZE: usize = 0x1000;
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    /// A request was too short for the payload it carries.
+    BadPayload(u32),
+    /// The vring index of a request is out of range.
+    BadVringIndex(u32),
+    /// The device couldn't be stopped to hand its rings back.
+    DeviceReset,
+    /// Creating the interrupt for the device failed.
+    InterruptCreate(SysError),
+    /// The memory table can't change while the device is running.
+    MemoryInUse,
+    /// Mapping guest memory shared by the frontend failed.
+    MemoryMap(GuestMemoryError),
+    /// The frontend's memory regions are backed by more than one file.
+    MemoryMultipleFiles,
+    /// Inspecting guest memory shared by the frontend failed.
+    MemoryStat(io::Error),
+    /// A request arrived with more fds than it takes.
+    MessageFds(u32),
+    /// A request's payload is larger than any request takes.
+    MessageTooLarge(u32),
+    /// A request needs the guest memory table, which the frontend hasn't sent yet.
+    NoMemoryTable(u32),
+    /// Polling rings without a kick event isn't supported.
+    PollingUnsupported,
+    /// A postcopy request came out of order.
+    PostcopyState(u32),
+    /// Receiving a request from the frontend failed.
+    Recv(io::Error),
+    /// Receiving the fds of a request from the frontend failed.
+    RecvFds(SysError),
+    /// Sending a reply to the frontend failed.
+    Send(io::Error),
+    /// A vring address isn't in guest memory shared by the frontend.
+    UnmappedAddress(u64),
+    /// The frontend sent a request the backend doesn't handle.
+    UnsupportedRequest(u32),
+    /// Reporting faults on guest memory through a userfaultfd failed.
+    UserfaultFd(SysError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            BadPayload(request) => write!(f, "vhost-user request {} has a bad payload", request),
+            BadVringIndex(index) => write!(f, "no vring {}", index),
+            DeviceReset => write!(f, "failed to stop the device"),
+            InterruptCreate(e) => write!(f, "failed to create the device interrupt: {}", e),
+            MemoryInUse => write!(f, "guest memory can't change while the device runs"),
+            MemoryMap(e) => write!(f, "failed to map guest memory: {}", e),
+            MemoryMultipleFiles => write!(f, "guest memory regions are in more than one file"),
+            MemoryStat(e) => write!(f, "failed to inspect guest memory: {}", e),
+            MessageFds(request) => write!(f, "too many fds with vhost-user request {}", request),
+            MessageTooLarge(request) => write!(f, "vhost-user request {} is too large", request),
+            NoMemoryTable(request) => write!(
+                f,
+                "vhost-user request {} came before the memory table",
+                request
+            ),
+            PollingUnsupported => write!(f, "vrings without a kick event aren't supported"),
+            PostcopyState(request) => {
+                write!(f, "vhost-user postcopy request {} out of order", request)
+            }
+            Recv(e) => write!(f, "failed to receive vhost-user request: {}", e),
+            RecvFds(e) => write!(f, "failed to receive vhost-user request fds: {}", e),
+            Send(e) => write!(f, "failed to send vhost-user reply: {}", e),
+            UnmappedAddress(addr) => write!(f, "vring address {:#x} isn't in guest memory", addr),
+            UnsupportedRequest(request) => write!(f, "unsupported vhost-user request {}", request),
+            UserfaultFd(e) => write!(f, "failed to report guest memory faults: {}", e),
+        }
+    }
+}
+
+struct Message {
+    request: u32,
+    flags: u32,
+    payload: Vec<u8>,
+    files: Vec<File>,
+}
+
+impl Message {
+    fn u32_at(&self, offset: usize) -> Result<u32> {
+        self.payload
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or(Error::BadPayload(self.request))
+    }
+
+    fn u64_at(&self, offset: usize) -> Result<u64> {
+        self.payload
+            .get(offset..offset + 8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .ok_or(Error::BadPayload(self.request))
+    }
+
+    fn take_file(&mut self) -> Option<File> {
+        if self.files.is_empty() {
+            None
+        } else {
+            Some(self.files.remove(0))
+        }
+    }
+}
+
+struct Vring {
+    queue: Queue,
+    kick_evt: Option<Event>,
+    enabled: bool,
+}
+
+/// Runs a virtio device for vhost-user frontends, one session at a time.
+///
+/// The frontend shares guest memory and hands over each vring's kick and call events, then the
+/// device is activated as it would be by a virtio transport. Used buffers are signaled through the
+/// call event of their vring, and config changes over the backend request channel, if the frontend
+/// set one up.
+pub struct VhostUserBackend {
+    device: Box<dyn VirtioDevice>,
+    vrings: Vec<Vring>,
+    call_evts: Arc<Mutex<Vec<Option<Event>>>>,
+    backend_req: Arc<Mutex<Option<BackendRequester>>>,
+    mem: Option<GuestMemory>,
+    // (frontend address, size, guest address) of each guest memory region, used to find the
+    // vrings, which the frontend gives by its own addresses.
+    mem_regions: Vec<(u64, u64, GuestAddress)>,
+    acked_features: u64,
+    acked_protocol_features: u64,
+    activated: bool,
+    // The userfaultfd handed to the frontend for postcopy live migration, and whether guest memory
+    // is registered with it.
+    uffd: Option<UserfaultFd>,
+    postcopy_listening: bool,
+}
+
+impl VhostUserBackend {
+    /// Creates a backend serving `device`.
+    pub fn new(device: Box<dyn VirtioDevice>) -> VhostUserBackend {
+        let call_evts = device.queue_max_sizes().iter().map(|_| None).collect();
+        let mut backend = VhostUserBackend {
+            device,
+            vrings: Vec::new(),
+            call_evts: Arc::new(Mutex::new(call_evts)),
+            backend_req: Arc::new(Mutex::new(None)),
+            mem: None,
+            mem_regions: Vec::new(),
+            acked_features: 0,
+            acked_protocol_features: 0,
+            activated: false,
+            uffd: None,
+            postcopy_listening: false,
+        };
+        backend.reset_session();
+        backend
+    }
+
+    /// Serves the frontend connected to `socket` until it hangs up or breaks the protocol. The
+    /// device is stopped afterwards, ready for the next session.
+    pub fn serve(&mut self, socket: &UnixStream) -> Result<()> {
+        let result = self.serve_requests(socket);
+        if let Err(e) = self.stop() {
+            error!("{}: {}", self.device.debug_label(), e);
+        }
+        self.reset_session();
+        result
+    }
+
+    fn serve_requests(&mut self, socket: &UnixStream) -> Result<()> {
+        while let Some(mut msg) = recv_message(socket)? {
+            let request = msg.request;
+            // Requests with a reply of their own aren't acknowledged on top of it.
+            let ack = msg.flags & VHOST_USER_NEED_REPLY_MASK != 0
+                && self.acked_protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0
+                && !is_get_request(request);
+            match self.handle_request(socket, &mut msg) {
+                Ok(Some(reply)) => send_reply(socket, request, &reply, &[])?,
+                Ok(None) => {
+                    if ack {
+                        send_reply(socket, request, &0u64.to_le_bytes(), &[])?;
+                    }
+                }
+                Err(e) => {
+                    error!("{}: {}", self.device.debug_label(), e);
+                    if is_get_request(request) {
+                        // The frontend waits for a reply there is no way to give.
+                        return Err(e);
+                    }
+                    if ack {
+                        send_reply(socket, request, &1u64.to_le_bytes(), &[])?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Handles `msg`, returning the payload of its reply if the request has one. The replies that
+    // carry an fd, or that the frontend answers, are sent over `socket` directly.
+    fn handle_request(
+        &mut self,
+        socket: &UnixStream,
+        msg: &mut Message,
+    ) -> Result<Option<Vec<u8>>> {
+        // Only the requests that pass fds keep them.
+        let max_files = match msg.request {
+            VHOST_USER_SET_MEM_TABLE => VHOST_USER_MAX_MEM_REGIONS,
+            VHOST_USER_SET_VRING_KICK
+            | VHOST_USER_SET_VRING_CALL
+            | VHOST_USER_SET_VRING_ERR
+            | VHOST_USER_SET_BACKEND_REQ_FD => 1,
+            _ => 0,
+        };
+        if msg.files.len() > max_files {
+            return Err(Error::MessageFds(msg.request));
+        }
+
+        match msg.request {
+            VHOST_USER_SET_OWNER => {}
+            VHOST_USER_RESET_OWNER => {
+                self.stop()?;
+                self.reset_session();
+            }
+            VHOST_USER_GET_FEATURES => {
+                let features = self.device.features() | VHOST_USER_F_PROTOCOL_FEATURES;
+                return Ok(Some(features.to_le_bytes().to_vec()));
+            }
+            VHOST_USER_SET_FEATURES => {
+                let features = msg.u64_at(0)?;
+                self.acked_features = features & !VHOST_USER_F_PROTOCOL_FEATURES;
+                self.device.ack_features(self.acked_features);
+                // Rings start disabled once protocol features are acked.
+                let enabled = features & VHOST_USER_F_PROTOCOL_FEATURES == 0;
+                for vring in &mut self.vrings {
+                    vring.enabled = enabled;
+                }
+            }
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                return Ok(Some(BACKEND_PROTOCOL_FEATURES.to_le_bytes().to_vec()));
+            }
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                self.acked_protocol_features = msg.u64_at(0)? & BACKEND_PROTOCOL_FEATURES;
+            }
+            VHOST_USER_GET_QUEUE_NUM => {
+                return Ok(Some((self.vrings.len() as u64).to_le_bytes().to_vec()));
+            }
+            VHOST_USER_SET_MEM_TABLE => self.set_mem_table(socket, msg)?,
+            VHOST_USER_SET_VRING_NUM => {
+                let index = msg.u32_at(0)?;
+                let num = msg.u32_at(4)?;
+                self.vring(index)?.queue.size = num as u16;
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let index = msg.u32_at(0)?;
+                let desc_table = self.guest_addr(msg.request, msg.u64_at(8)?)?;
+                let used_ring = self.guest_addr(msg.request, msg.u64_at(16)?)?;
+                let avail_ring = self.guest_addr(msg.request, msg.u64_at(24)?)?;
+                let queue = &mut self.vring(index)?.queue;
+                queue.desc_table = desc_table;
+                queue.used_ring = used_ring;
+                queue.avail_ring = avail_ring;
+            }
+            VHOST_USER_SET_VRING_BASE => {
+                let index = msg.u32_at(0)?;
+                let num = msg.u32_at(4)?;
+                self.vring(index)?.queue.set_next_index(num as u16);
+            }
+            VHOST_USER_GET_VRING_BASE => {
+                let index = msg.u32_at(0)?;
+                self.vring(index)?;
+                // Stopping any ring stops the device, which completes every request it took.
+                self.stop()?;
+                let vring = &mut self.vrings[index as usize];
+                let base = match &self.mem {
+                    Some(mem) => mem
+                        .read_obj_from_addr::<u16>(vring.queue.used_ring.unchecked_add(2))
+                        .unwrap_or(0),
+                    None => 0,
+                };
+                vring.queue.set_next_index(base);
+                let mut reply = index.to_le_bytes().to_vec();
+                reply.extend_from_slice(&(base as u32).to_le_bytes());
+                return Ok(Some(reply));
+            }
+            VHOST_USER_SET_VRING_KICK => {
+                let (index, file) = vring_file(msg)?;
+                let file = file.ok_or(Error::PollingUnsupported)?;
+                // Safe because we own the file, which the event takes over.
+                self.vring(index)?.kick_evt =
+                    Some(unsafe { Event::from_raw_descriptor(file.into_raw_fd()) });
+                self.activate()?;
+            }
+            VHOST_USER_SET_VRING_CALL => {
+                let (index, file) = vring_file(msg)?;
+                self.vring(index)?;
+                // Safe because we own the file, which the event takes over.
+                self.call_evts.lock()[index as usize] =
+                    file.map(|f| unsafe { Event::from_raw_descriptor(f.into_raw_fd()) });
+            }
+            // Errors are only logged.
+            VHOST_USER_SET_VRING_ERR => {
+                vring_file(msg)?;
+            }
+            VHOST_USER_SET_VRING_ENABLE => {
+                let index = msg.u32_at(0)?;
+                let enable = msg.u32_at(4)? != 0;
+                self.vring(index)?.enabled = enable;
+                self.activate()?;
+            }
+            VHOST_USER_GET_CONFIG => {
+                let offset = msg.u32_at(0)?;
+                let size = msg.u32_at(4)? as usize;
+                if msg.payload.len() != 12 + size {
+                    return Err(Error::BadPayload(msg.request));
+                }
+                let mut reply = msg.payload.clone();
+                self.device.read_config(offset as u64, &mut reply[12..]);
+                return Ok(Some(reply));
+            }
+            VHOST_USER_SET_CONFIG => {
+                let offset = msg.u32_at(0)?;
+                let size = msg.u32_at(4)? as usize;
+                if msg.payload.len() != 12 + size {
+                    return Err(Error::BadPayload(msg.request));
+                }
+                self.device.write_config(offset as u64, &msg.payload[12..]);
+            }
+            VHOST_USER_SET_BACKEND_REQ_FD => {
+                let file = msg.take_file().ok_or(Error::BadPayload(msg.request))?;
+                let reply_ack = self.acked_protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+                // Safe because we own the file, which the socket takes over.
+                let socket = unsafe { UnixStream::from_raw_fd(file.into_raw_fd()) };
+                *self.backend_req.lock() = Some(BackendRequester::new(socket, reply_ack));
+            }
+            VHOST_USER_POSTCOPY_ADVISE => {
+                if self.acked_protocol_features & VHOST_USER_PROTOCOL_F_PAGEFAULT == 0 {
+                    return Err(Error::UnsupportedRequest(msg.request));
+                }
+                if self.uffd.is_some() {
+                    return Err(Error::PostcopyState(msg.request));
+                }
+                let uffd = UserfaultFd::new().map_err(Error::UserfaultFd)?;
+                send_reply(socket, msg.request, &[], &[uffd.as_raw_fd()])?;
+                self.uffd = Some(uffd);
+            }
+            VHOST_USER_POSTCOPY_LISTEN => {
+                if self.uffd.is_none() || self.postcopy_listening {
+                    return Err(Error::PostcopyState(msg.request));
+                }
+                self.register_mem()?;
+                self.postcopy_listening = true;
+            }
+            VHOST_USER_POSTCOPY_END => {
+                // The reply tells whether postcopy ended cleanly.
+                let result = self.end_postcopy(msg.request);
+                if let Err(e) = &result {
+                    error!("{}: {}", self.device.debug_label(), e);
+                }
+                return Ok(Some((result.is_err() as u64).to_le_bytes().to_vec()));
+            }
+            request => return Err(Error::UnsupportedRequest(request)),
+        }
+        Ok(None)
+    }
+
+    fn vring(&mut self, index: u32) -> Result<&mut Vring> {
+        self.vrings
+            .get_mut(index as usize)
+            .ok_or(Error::BadVringIndex(index))
+    }
+
+    // Translates an address in the frontend's mapping of guest memory.
+    fn guest_addr(&self, request: u32, addr: u64) -> Result<GuestAddress> {
+        if self.mem.is_none() {
+            return Err(Error::NoMemoryTable(request));
+        }
+        self.mem_regions
+            .iter()
+            .find(|&&(start, size, _)| addr >= start && addr - start < size)
+            .map(|&(start, _, guest_addr)| guest_addr.unchecked_add(addr - start))
+            .ok_or(Error::UnmappedAddress(addr))
+    }
+
+    fn set_mem_table(&mut self, socket: &UnixStream, msg: &mut Message) -> Result<()> {
+        if self.activated {
+            return Err(Error::MemoryInUse);
+        }
+        let num_regions = msg.u32_at(0)? as usize;
+        if num_regions > VHOST_USER_MAX_MEM_REGIONS
+            || msg.payload.len() != 8 + num_regions * 32
+            || msg.files.len() != num_regions
+        {
+            return Err(Error::BadPayload(msg.request));
+        }
+
+        let mut ranges = Vec::with_capacity(num_regions);
+        let mut mem_regions = Vec::with_capacity(num_regions);
+        for i in 0..num_regions {
+            let region = 8 + i * 32;
+            let guest_addr = GuestAddress(msg.u64_at(region)?);
+            let size = msg.u64_at(region + 8)?;
+            let frontend_addr = msg.u64_at(region + 16)?;
+            let offset = msg.u64_at(region + 24)?;
+            ranges.push((guest_addr, size, offset));
+            mem_regions.push((frontend_addr, size, guest_addr));
+        }
+        ranges.sort_by_key(|range| range.0);
+
+        // Each region comes with its own fd, but guest memory is mapped from a single file.
+        let mut files = msg.files.drain(..);
+        let file = match files.next() {
+            Some(f) => f,
+            None => return Err(Error::BadPayload(msg.request)),
+        };
+        let metadata = file.metadata().map_err(Error::MemoryStat)?;
+        for other in files {
+            let other = other.metadata().map_err(Error::MemoryStat)?;
+            if (other.dev(), other.ino()) != (metadata.dev(), metadata.ino()) {
+                return Err(Error::MemoryMultipleFiles);
+            }
+        }
+        let shm = SharedMemory::from_file(file).map_err(|e| Error::MemoryStat(e.into()))?;
+        self.mem = Some(GuestMemory::from_shared_memory(shm, &ranges).map_err(Error::MemoryMap)?);
+        self.mem_regions = mem_regions;
+        if self.postcopy_listening {
+            self.register_postcopy_mem(socket, msg)?;
+        }
+        Ok(())
+    }
+
+    // Replies to a memory table that arrived while listening for faults with where the backend
+    // mapped each region, which the frontend resolves the faults by. Guest memory is registered
+    // once the frontend acknowledges the reply, as it can't resolve faults before.
+    fn register_postcopy_mem(&self, socket: &UnixStream, msg: &Message) -> Result<()> {
+        let mem = self.mem.as_ref().ok_or(Error::NoMemoryTable(msg.request))?;
+        let mut reply = msg.payload.clone();
+        for region in (8..reply.len()).step_by(32) {
+            let guest_addr = GuestAddress(msg.u64_at(region)?);
+            let host_addr = mem.get_host_address(guest_addr).map_err(Error::MemoryMap)?;
+            reply[region + 16..region + 24].copy_from_slice(&(host_addr as u64).to_le_bytes());
+        }
+        send_reply(socket, msg.request, &reply, &[])?;
+
+        match recv_message(socket)? {
+            Some(ack) if ack.request == msg.request && ack.u64_at(0)? == 0 => {}
+            _ => return Err(Error::PostcopyState(msg.request)),
+        }
+        self.register_mem()
+    }
+
+    // Reports the faults on guest memory to the frontend through the postcopy userfaultfd.
+    fn register_mem(&self) -> Result<()> {
+        if let (Some(uffd), Some(mem)) = (&self.uffd, &self.mem) {
+            mem.with_regions(|_, _, size, host_addr, _| uffd.register(host_addr, size))
+                .map_err(Error::UserfaultFd)?;
+        }
+        Ok(())
+    }
+
+    fn end_postcopy(&mut self, request: u32) -> Result<()> {
+        let uffd = self.uffd.take().ok_or(Error::PostcopyState(request))?;
+        // The frontend keeps the userfaultfd open, so guest memory stays registered until it is
+        // unregistered explicitly.
+        if self.postcopy_listening {
+            self.postcopy_listening = false;
+            if let Some(mem) = &self.mem {
+                mem.with_regions(|_, _, size, host_addr, _| uffd.unregister(host_addr, size))
+                    .map_err(Error::UserfaultFd)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Activates the device once every vring is set up and enabled.
+    fn activate(&mut self) -> Result<()> {
+        if self.activated
+            || !self
+                .vrings
+                .iter()
+                .all(|vring| vring.enabled && vring.kick_evt.is_some())
+        {
+            return Ok(());
+        }
+        let mem = match &self.mem {
+            Some(mem) => mem.clone(),
+            None => return Ok(()),
+        };
+
+        let mut queues = Vec::with_capacity(self.vrings.len());
+        let mut queue_evts = Vec::with_capacity(self.vrings.len());
+        for (index, vring) in self.vrings.iter_mut().enumerate() {
+            let mut queue = vring.queue.clone();
+            queue.ready = true;
+            queue.vector = index as u16;
+            queue.ack_features(self.acked_features);
+            queues.push(queue);
+            queue_evts.push(vring.kick_evt.take().unwrap());
+        }
+        let interrupt = Interrupt::new_vhost_user(self.call_evts.clone(), self.backend_req.clone())
+            .map_err(Error::InterruptCreate)?;
+        self.device.activate(mem, interrupt, queues, queue_evts);
+        self.activated = true;
+        Ok(())
+    }
+
+    // Stops the device if it is running. The rings must be set up again to restart it.
+    fn stop(&mut self) -> Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        self.activated = false;
+        if !self.device.reset() {
+            return Err(Error::DeviceReset);
+        }
+        Ok(())
+    }
+
+    fn reset_session(&mut self) {
+        self.vrings = self
+            .device
+            .queue_max_sizes()
+            .iter()
+            .map(|&max_size| Vring {
+                queue: Queue::new(max_size),
+                kick_evt: None,
+                enabled: true,
+            })
+            .collect();
+        for call_evt in self.call_evts.lock().iter_mut() {
+            *call_evt = None;
+        }
+        *self.backend_req.lock() = None;
+        self.mem = None;
+        self.mem_regions.clear();
+        self.acked_features = 0;
+        self.acked_protocol_features = 0;
+        // Unmapping guest memory already unregistered it.
+        self.uffd = None;
+        self.postcopy_listening = false;
+    }
+}
+
+// Requests whose reply carries their result rather than an acknowledgement.
+fn is_get_request(request: u32) -> bool {
+    match request {
+        VHOST_USER_GET_FEATURES
+        | VHOST_USER_GET_PROTOCOL_FEATURES
+        | VHOST_USER_GET_QUEUE_NUM
+        | VHOST_USER_GET_VRING_BASE
+        | VHOST_USER_GET_CONFIG
+        | VHOST_USER_POSTCOPY_ADVISE
+        | VHOST_USER_POSTCOPY_END => true,
+        _ => false,
+    }
+}
+
+// Parses the payload of VHOST_USER_SET_VRING_{KICK,CALL,ERR}.
+fn vring_file(msg: &mut Message) -> Result<(u32, Option<File>)> {
+    let value = msg.u64_at(0)?;
+    let index = (value & VHOST_USER_VRING_IDX_MASK) as u32;
+    if value & VHOST_USER_VRING_NOFD_MASK != 0 {
+        return Ok((index, None));
+    }
+    match msg.take_file() {
+        Some(file) => Ok((index, Some(file))),
+        None => Err(Error::BadPayload(msg.request)),
+    }
+}
+
+// Receives the next request, or `None` once the frontend hangs up.
+fn recv_message(socket: &UnixStream) -> Result<Option<Message>> {
+    let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+    let mut fds = [0; VHOST_USER_MAX_MEM_REGIONS];
+    let (len, fd_count) = socket
+        .recv_with_fds(&mut header, &mut fds)
+        .map_err(Error::RecvFds)?;
+    // Safe because the fds were just received, so nothing else owns them.
+    let files: Vec<File> = fds[..fd_count]
+        .iter()
+        .map(|&fd| unsafe { File::from_raw_fd(fd) })
+        .collect();
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut socket = socket;
+    socket.read_exact(&mut header[len..]).map_err(Error::Recv)?;
+
+    let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::MessageTooLarge(request));
+    }
+    let mut payload = vec![0u8; size];
+    socket.read_exact(&mut payload).map_err(Error::Recv)?;
+    Ok(Some(Message {
+        request,
+        flags,
+        payload,
+        files,
+    }))
+}
+
+fn send_reply(socket: &UnixStream, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+    let mut msg = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&request.to_le_bytes());
+    msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(payload);
+    let sent = socket
+        .send_with_fds(&[IoSlice::new(&msg)], fds)
+        .map_err(|e| Error::Send(e.into()))?;
+    if sent != msg.len() {
+        return Err(Error::Send(io::Error::from(io::ErrorKind::WriteZero)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+
+    use base::RawDescriptor;
+    use vhost::user::{BackendRequest, ShmMapping};
+    use vhost::{Vhost, VhostUser};
+
+    const QUEUE_SIZES: &[u16] = &[16];
+    const FEATURES: u64 = 1 << 32;
+    const MAPPING: ShmMapping = ShmMapping {
+        fd_offset: 0,
+        shm_offset: 0x1000,
+        len: 0x1000,
+        flags: VHOST_USER_FS_FLAG_MAP_R,
+    };
+
+    // Sends the queues it is activated with, signals that it used their buffers and that its
+    // config changed, and maps a file into its shared memory region.
+    struct FakeDevice {
+        activated: Sender<Vec<Queue>>,
+    }
+
+    impl VirtioDevice for FakeDevice {
+        fn keep_rds(&self) -> Vec<RawDescriptor> {
+            Vec::new()
+        }
+
+        fn device_type(&self) -> u32 {
+            2
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+
+        fn features(&self) -> u64 {
+            FEATURES
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = offset as u8 + i as u8;
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemory,
+            interrupt: Interrupt,
+            queues: Vec<Queue>,
+            _queue_evts: Vec<Event>,
+        ) {
+            interrupt.signal_used_queue(queues[0].vector);
+            interrupt.signal_config_changed();
+            // Mapping waits for the frontend, which waits for activation to finish.
+            let backend_req = interrupt.backend_requester().unwrap();
+            thread::spawn(move || {
+                let file = File::open("/dev/zero").unwrap();
+                backend_req
+                    .lock()
+                    .as_ref()
+                    .unwrap()
+                    .map(&file, &[MAPPING])
+                    .unwrap();
+            });
+            self.activated.send(queues).unwrap();
+        }
+
+        fn reset(&mut self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn activate_through_frontend() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (frontend, socket) = UnixStream::pair().unwrap();
+        let (activated, activated_queues) = channel();
+        let backend_thread = thread::spawn(move || {
+            let mut backend = VhostUserBackend::new(Box::new(FakeDevice { activated }));
+            backend.serve(&socket)
+        });
+
+        let (vhost_user, backend_requests) =
+            VhostUser::new_with_backend_requests(frontend, &mem).unwrap();
+        let backend_requests = backend_requests.expect("backend should support backend requests");
+        assert_eq!(vhost_user.get_features().unwrap() & FEATURES, FEATURES);
+        let mut config = [0u8; 4];
+        vhost_user.get_config(4, &mut config).unwrap();
+        assert_eq!(config, [4, 5, 6, 7]);
+
+        let call_evt = Event::new().unwrap();
+        let kick_evt = Event::new().unwrap();
+        vhost_user.set_features(FEATURES).unwrap();
+        vhost_user.set_mem_table().unwrap();
+        vhost_user.set_vring_num(0, 16).unwrap();
+        vhost_user
+            .set_vring_addr(
+                16,
+                16,
+                0,
+                0,
+                GuestAddress(0x1000),
+                GuestAddress(0x3000),
+                GuestAddress(0x2000),
+                None,
+            )
+            .unwrap();
+        vhost_user.set_vring_base(0, 0).unwrap();
+        vhost_user.set_vring_call(0, &call_evt).unwrap();
+        vhost_user.set_vring_kick(0, &kick_evt).unwrap();
+        // Protocol features were negotiated, so the ring waits to be enabled.
+        assert!(activated_queues.try_recv().is_err());
+        vhost_user.set_vring_enable(0, true).unwrap();
+
+        let queues = activated_queues.recv().unwrap();
+        assert_eq!(queues[0].desc_table, GuestAddress(0x1000));
+        assert_eq!(queues[0].avail_ring, GuestAddress(0x2000));
+        assert_eq!(queues[0].used_ring, GuestAddress(0x3000));
+        assert_eq!(call_evt.read().unwrap(), 1);
+        let mut config_changed = false;
+        assert!(backend_requests
+            .handle_request(|request| {
+                config_changed = matches!(request, BackendRequest::ConfigChanged);
+                true
+            })
+            .unwrap());
+        assert!(config_changed);
+        let mut mappings = Vec::new();
+        assert!(backend_requests
+            .handle_request(|request| {
+                if let BackendRequest::Map { mappings: m, .. } = request {
+                    mappings = m;
+                }
+                true
+            })
+            .unwrap());
+        assert_eq!(mappings, vec![MAPPING]);
+
+        drop(vhost_user);
+        backend_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn postcopy_through_frontend() {
+        // Only processes allowed to handle page faults can serve postcopy.
+        if UserfaultFd::new().is_err() {
+            return;
+        }
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (frontend, socket) = UnixStream::pair().unwrap();
+        let (activated, _activated_queues) = channel();
+        let backend_thread = thread::spawn(move || {
+            let mut backend = VhostUserBackend::new(Box::new(FakeDevice { activated }));
+            backend.serve(&socket)
+        });
+
+        let vhost_user = VhostUser::new(frontend, &mem).unwrap();
+        vhost_user.set_mem_table().unwrap();
+        let _uffd = vhost_user.postcopy_advise().unwrap();
+        vhost_user.postcopy_listen().unwrap();
+        // Guest memory is mapped again, and reported back, once the backend listens for faults.
+        vhost_user.set_mem_table().unwrap();
+        let regions = vhost_user.backend_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].guest_addr, GuestAddress(0));
+        assert_eq!(regions[0].size, 0x10000);
+        assert_ne!(regions[0].backend_addr, 0);
+        vhost_user.postcopy_end().unwrap();
+        // Postcopy already ended.
+        assert!(vhost_user.postcopy_end().is_err());
+
+        drop(vhost_user);
+        backend_thread.join().unwrap().unwrap();
+    }
+}
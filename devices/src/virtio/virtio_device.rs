@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use acpi_tables::sdt::SDT;
 use base::{Event, RawDescriptor};
 use vm_memory::GuestMemory;
 
@@ -34,6 +35,12 @@ pub trait VirtioDevice: Send {
     /// The maximum size of each queue that this device supports.
     fn queue_max_sizes(&self) -> &[u16];
 
+    /// The number of MSI-X vectors to give this device when it uses the PCI transport. The
+    /// default is one per queue plus one for configuration changes.
+    fn num_msix_vectors(&self) -> usize {
+        self.queue_max_sizes().len() + 1
+    }
+
     /// The set of feature bits that this device supports in addition to the base features.
     fn features(&self) -> u64 {
         0
@@ -86,5 +93,54 @@ pub trait VirtioDevice: Send {
     /// Invoked when the device is sandboxed.
     fn on_device_sandboxed(&mut self) {}
 
+    /// Invoked when the VM is suspended. Workers driven by timers should stop until
+    /// `on_device_resume` is called.
+    fn on_device_suspend(&mut self) {}
+
+    /// Invoked when the VM resumes after being suspended.
+    fn on_device_resume(&mut self) {}
+
+    /// Stops the workers of an activated device, returning the queues they used with the positions
+    /// they reached in the rings. Returns `None` if the device can't be put to sleep, which is the
+    /// default.
+    fn virtio_sleep(&mut self) -> Option<Vec<Queue>> {
+        None
+    }
+
+    /// Starts the workers again with the queues returned by `virtio_sleep`, or those of a restored
+    /// snapshot. Defaults to activating the device.
+    fn virtio_wake(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        self.activate(mem, interrupt, queues, queue_evts)
+    }
+
+    /// Saves the device specific state for a VM snapshot, while the device is asleep or not yet
+    /// activated. Returns `None` if the device can't be snapshotted, which is the default.
+    fn virtio_snapshot(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Loads the state saved by `virtio_snapshot`, before the device is woken. Returns false if
+    /// the state can't be restored.
+    fn virtio_restore(&mut self, _data: &[u8]) -> bool {
+        false
+    }
+
+    /// Gives the device counters to record the requests it completes in.
+    fn set_metrics(&mut self, _metrics: DeviceMetrics) {}
+
+    /// Whether this device's worker runs on a `cros_async::Executor`.
+    fn uses_async_executor(&self) -> bool {
+        false
+    }
+
     fn control_notify(&self, _behavior: MsixStatus) {}
+
+    /// Adds the ACPI tables that describe the device at `pci_address` to the guest to `sdts`.
+    fn generate_acpi(&mut self, _pci_address: PciAddress, _sdts: &mut Vec<SDT>) {}
 }
@@ -2,22 +2,25 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use sync::Mutex;
 
-use base::{warn, AsRawDescriptor, Event, RawDescriptor, Result};
+use acpi_tables::sdt::SDT;
+use base::{error, warn, AsRawDescriptor, Event, RawDescriptor, Result};
 use data_model::{DataInit, Le32};
 use hypervisor::Datamatch;
-use libc::ERANGE;
+use libc::{EINVAL, ERANGE};
+use msg_socket::{deserialize_from_slice, serialize_to_vec, MsgOnSocket};
 use resources::{Alloc, MmioType, SystemAllocator};
 use vm_memory::GuestMemory;
 
 use super::*;
 use crate::pci::{
-    MsixCap, MsixConfig, PciAddress, PciBarConfiguration, PciCapability, PciCapabilityID,
-    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciDisplaySubclass, PciHeaderType,
-    PciInterruptPin, PciSubclass,
+    MsixCap, MsixConfig, MsixConfigSnapshot, PciAddress, PciBarConfiguration, PciCapability,
+    PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciDisplaySubclass,
+    PciHeaderType, PciInterruptPin, PciSubclass, BITS_PER_PBA_ENTRY, MAX_MSIX_VECTORS_PER_DEVICE,
+    MSIX_PBA_ENTRIES_MODULO, MSIX_TABLE_ENTRIES_MODULO,
 };
 use vm_control::VmIrqRequestSocket;
 
@@ -177,6 +180,8 @@ impl PciSubclass for PciVirtioSubclass {
 // Allocate one bar for the structs pointed to by the capability structures.
 const COMMON_CONFIG_BAR_OFFSET: u64 = 0x0000;
 const COMMON_CONFIG_SIZE: u64 = 56;
+// Offset of device_status in the common configuration.
+const DEVICE_STATUS_OFFSET: u64 = 0x14;
 const ISR_CONFIG_BAR_OFFSET: u64 = 0x1000;
 const ISR_CONFIG_SIZE: u64 = 1;
 const DEVICE_CONFIG_BAR_OFFSET: u64 = 0x2000;
@@ -184,16 +189,59 @@ const DEVICE_CONFIG_SIZE: u64 = 0x1000;
 const NOTIFICATION_BAR_OFFSET: u64 = 0x3000;
 const NOTIFICATION_SIZE: u64 = 0x1000;
 const MSIX_TABLE_BAR_OFFSET: u64 = 0x6000;
-const MSIX_TABLE_SIZE: u64 = 0x1000;
-const MSIX_PBA_BAR_OFFSET: u64 = 0x7000;
-const MSIX_PBA_SIZE: u64 = 0x1000;
-const CAPABILITY_BAR_SIZE: u64 = 0x8000;
+// The MSI-X table and PBA each start on their own page.
+const MSIX_REGION_ALIGN: u64 = 0x1000;
 
 const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+/// Placement of the MSI-X table and PBA after the other structures in the settings BAR, sized for
+/// the number of vectors the device uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MsixLayout {
+    table_size: u64,
+    pba_offset: u64,
+    pba_size: u64,
+    bar_size: u64,
+}
+
+impl MsixLayout {
+    fn new(num_vectors: u16) -> MsixLayout {
+        let round_up =
+            |size: u64| (size + MSIX_REGION_ALIGN - 1) / MSIX_REGION_ALIGN * MSIX_REGION_ALIGN;
+        let num_vectors = u64::from(num_vectors);
+        let table_size = num_vectors * MSIX_TABLE_ENTRIES_MODULO;
+        let pba_offset = MSIX_TABLE_BAR_OFFSET + round_up(table_size);
+        let pba_entries = (num_vectors + BITS_PER_PBA_ENTRY as u64 - 1) / BITS_PER_PBA_ENTRY as u64;
+        let pba_size = pba_entries * MSIX_PBA_ENTRIES_MODULO;
+        MsixLayout {
+            table_size,
+            pba_offset,
+            pba_size,
+            bar_size: (pba_offset + round_up(pba_size)).next_power_of_two(),
+        }
+    }
+}
+
+/// The state of a `VirtioPciDevice` and of the virtio device behind it, saved in a VM snapshot.
+#[derive(MsgOnSocket)]
+struct VirtioPciDeviceSnapshot {
+    config_regs: Vec<u32>,
+    msix_config: MsixConfigSnapshot,
+    driver_status: u8,
+    config_generation: u8,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u16,
+    msix_config_vector: u16,
+    queues: Vec<QueueSnapshot>,
+    device_activated: bool,
+    interrupt_status: usize,
+    device: Vec<u8>,
+}
+
 /// Implements the
 /// [PCI](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-650001)
 /// transport for virtio devices.
@@ -203,6 +251,10 @@ pub struct VirtioPciDevice {
 
     device: Box<dyn VirtioDevice>,
     device_activated: bool,
+    // Set by the device's `Interrupt` when the device stops working.
+    needs_reset: Arc<AtomicBool>,
+    // The queues the device is woken with, while the guest is asleep.
+    sleeping_queues: Option<Vec<Queue>>,
 
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<Event>,
@@ -213,7 +265,9 @@ pub struct VirtioPciDevice {
     settings_bar: u8,
     msix_config: Arc<Mutex<MsixConfig>>,
     msix_cap_reg_idx: Option<usize>,
+    msix_layout: MsixLayout,
     common_config: VirtioPciCommonConfig,
+    metrics: DeviceMetrics,
 }
 
 impl VirtioPciDevice {
@@ -246,10 +300,11 @@ impl VirtioPciDevice {
             ),
         };
 
-        let num_queues = device.queue_max_sizes().len();
-
-        // One MSI-X vector per queue plus one for configuration changes.
-        let msix_num = u16::try_from(num_queues + 1).map_err(|_| base::Error::new(ERANGE))?;
+        let msix_num = u16::try_from(device.num_msix_vectors())
+            .ok()
+            .filter(|n| (1..=MAX_MSIX_VECTORS_PER_DEVICE).contains(n))
+            .ok_or_else(|| base::Error::new(ERANGE))?;
+        let msix_layout = MsixLayout::new(msix_num);
         let msix_config = Arc::new(Mutex::new(MsixConfig::new(msix_num, msi_device_socket)));
 
         let config_regs = PciConfiguration::new(
@@ -268,6 +323,8 @@ impl VirtioPciDevice {
             pci_address: None,
             device,
             device_activated: false,
+            needs_reset: Arc::new(AtomicBool::new(false)),
+            sleeping_queues: None,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: None,
             interrupt_resample_evt: None,
@@ -277,6 +334,7 @@ impl VirtioPciDevice {
             settings_bar: 0,
             msix_config,
             msix_cap_reg_idx: None,
+            msix_layout,
             common_config: VirtioPciCommonConfig {
                 driver_status: 0,
                 config_generation: 0,
@@ -285,9 +343,19 @@ impl VirtioPciDevice {
                 queue_select: 0,
                 msix_config: VIRTIO_MSI_NO_VECTOR,
             },
+            metrics: DeviceMetrics::default(),
         })
     }
 
+    /// Records the requests, interrupts and queue depths of this device in `metrics`.
+    pub fn set_metrics(&mut self, metrics: DeviceMetrics) {
+        for queue in self.queues.iter_mut() {
+            queue.set_metrics(metrics.clone());
+        }
+        self.device.set_metrics(metrics.clone());
+        self.metrics = metrics;
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits =
             (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
@@ -370,7 +438,7 @@ impl VirtioPciDevice {
             self.msix_config.lock().num_vectors(),
             MSIX_TABLE_BAR_OFFSET as u32,
             settings_bar,
-            MSIX_PBA_BAR_OFFSET as u32,
+            self.msix_layout.pba_offset as u32,
         );
         let msix_offset = self
             .config_regs
@@ -385,6 +453,105 @@ impl VirtioPciDevice {
     fn clone_queue_evts(&self) -> Result<Vec<Event>> {
         self.queue_evts.iter().map(|e| e.try_clone()).collect()
     }
+
+    // Builds the interrupt for the device to be woken with, keeping the events for later.
+    fn clone_interrupt(&self) -> Result<Interrupt> {
+        let (interrupt_evt, interrupt_resample_evt) =
+            match (&self.interrupt_evt, &self.interrupt_resample_evt) {
+                (Some(evt), Some(resample_evt)) => (evt.try_clone()?, resample_evt.try_clone()?),
+                _ => return Err(base::Error::new(EINVAL)),
+            };
+        let mut interrupt = Interrupt::new(
+            self.interrupt_status.clone(),
+            interrupt_evt,
+            interrupt_resample_evt,
+            Some(self.msix_config.clone()),
+            self.common_config.msix_config,
+        );
+        interrupt.set_metrics(self.metrics.clone());
+        interrupt.set_needs_reset_flag(self.needs_reset.clone());
+        Ok(interrupt)
+    }
+
+    // Puts the activated device to sleep, returning the queues with the positions it reached.
+    fn sleep_device(&mut self) -> Option<Vec<Queue>> {
+        let queues = self.device.virtio_sleep();
+        if queues.is_none() {
+            error!("{} can't be put to sleep", self.debug_label());
+        }
+        queues
+    }
+
+    fn wake_device(&mut self, queues: Vec<Queue>) -> bool {
+        let mem = match &self.mem {
+            Some(mem) => mem.clone(),
+            None => return false,
+        };
+        match self
+            .clone_interrupt()
+            .and_then(|interrupt| Ok((interrupt, self.clone_queue_evts()?)))
+        {
+            Ok((interrupt, queue_evts)) => {
+                self.device.virtio_wake(mem, interrupt, queues, queue_evts);
+                true
+            }
+            Err(e) => {
+                error!("{} failed to wake: {}", self.debug_label(), e);
+                false
+            }
+        }
+    }
+
+    fn save_state(&self, queues: &[Queue]) -> Option<VirtioPciDeviceSnapshot> {
+        Some(VirtioPciDeviceSnapshot {
+            config_regs: self.config_regs.snapshot(),
+            msix_config: self.msix_config.lock().snapshot(),
+            driver_status: self.common_config.driver_status,
+            config_generation: self.common_config.config_generation,
+            device_feature_select: self.common_config.device_feature_select,
+            driver_feature_select: self.common_config.driver_feature_select,
+            queue_select: self.common_config.queue_select,
+            msix_config_vector: self.common_config.msix_config,
+            queues: queues.iter().map(Queue::snapshot).collect(),
+            device_activated: self.device_activated,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            device: self.device.virtio_snapshot()?,
+        })
+    }
+
+    fn snapshot_state(&self, queues: &[Queue]) -> Option<Vec<u8>> {
+        match serialize_to_vec(&self.save_state(queues)?) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!("{} failed to serialize snapshot: {}", self.debug_label(), e);
+                None
+            }
+        }
+    }
+
+    fn restore_state(&mut self, snapshot: VirtioPciDeviceSnapshot) -> bool {
+        if snapshot.queues.len() != self.queues.len()
+            || !self.config_regs.restore(&snapshot.config_regs)
+            || !self.msix_config.lock().restore(snapshot.msix_config)
+            || !self.device.virtio_restore(&snapshot.device)
+        {
+            return false;
+        }
+        self.common_config.driver_status = snapshot.driver_status;
+        self.common_config.config_generation = snapshot.config_generation;
+        self.common_config.device_feature_select = snapshot.device_feature_select;
+        self.common_config.driver_feature_select = snapshot.driver_feature_select;
+        self.common_config.queue_select = snapshot.queue_select;
+        self.common_config.msix_config = snapshot.msix_config_vector;
+        for (queue, queue_snapshot) in self.queues.iter_mut().zip(snapshot.queues.iter()) {
+            queue.restore(queue_snapshot);
+        }
+        self.interrupt_status
+            .store(snapshot.interrupt_status, Ordering::SeqCst);
+        self.needs_reset.store(false, Ordering::SeqCst);
+        self.device_activated = snapshot.device_activated;
+        true
+    }
 }
 
 impl PciDevice for VirtioPciDevice {
@@ -405,6 +572,9 @@ impl PciDevice for VirtioPciDevice {
                     bar: _,
                 }) => Some(PciAddress { bus, dev, func }),
                 _ => None,
+            };
+            if let Some(address) = self.pci_address {
+                self.msix_config.lock().set_pci_address(address);
             }
         }
         self.pci_address.ok_or(PciDeviceError::PciAllocationFailed)
@@ -444,10 +614,11 @@ impl PciDevice for VirtioPciDevice {
             .expect("allocaten_address must be called prior to allocate_io_bars");
         // Allocate one bar for the structures pointed to by the capability structures.
         let mut ranges = Vec::new();
+        let bar_size = self.msix_layout.bar_size;
         let settings_config_addr = resources
             .mmio_allocator(MmioType::Low)
             .allocate_with_align(
-                CAPABILITY_BAR_SIZE,
+                bar_size,
                 Alloc::PciBar {
                     bus: address.bus,
                     dev: address.dev,
@@ -458,19 +629,19 @@ impl PciDevice for VirtioPciDevice {
                     "virtio-{}-cap_bar",
                     type_to_str(self.device.device_type()).unwrap_or("?")
                 ),
-                CAPABILITY_BAR_SIZE,
+                bar_size,
             )
-            .map_err(|e| PciDeviceError::IoAllocationFailed(CAPABILITY_BAR_SIZE, e))?;
+            .map_err(|e| PciDeviceError::IoAllocationFailed(bar_size, e))?;
         let config = PciBarConfiguration::default()
             .set_register_index(0)
             .set_address(settings_config_addr)
-            .set_size(CAPABILITY_BAR_SIZE);
+            .set_size(bar_size);
         let settings_bar = self
             .config_regs
             .add_pci_bar(config)
             .map_err(|e| PciDeviceError::IoRegistrationFailed(settings_config_addr, e))?
             as u8;
-        ranges.push((settings_config_addr, CAPABILITY_BAR_SIZE));
+        ranges.push((settings_config_addr, bar_size));
 
         // Once the BARs are allocated, the capabilities can be added to the PCI configuration.
         self.add_settings_pci_capabilities(settings_bar)?;
@@ -579,7 +750,14 @@ impl PciDevice for VirtioPciDevice {
                     data,
                     &mut self.queues,
                     self.device.as_mut(),
-                )
+                );
+                if o - COMMON_CONFIG_BAR_OFFSET == DEVICE_STATUS_OFFSET
+                    && self.needs_reset.load(Ordering::SeqCst)
+                {
+                    if let Some(v) = data.get_mut(0) {
+                        *v |= DEVICE_NEEDS_RESET as u8;
+                    }
+                }
             }
             o if ISR_CONFIG_BAR_OFFSET <= o && o < ISR_CONFIG_BAR_OFFSET + ISR_CONFIG_SIZE => {
                 if let Some(v) = data.get_mut(0) {
@@ -598,16 +776,20 @@ impl PciDevice for VirtioPciDevice {
                 // Handled with ioevents.
             }
 
-            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+            o if MSIX_TABLE_BAR_OFFSET <= o
+                && o < MSIX_TABLE_BAR_OFFSET + self.msix_layout.table_size =>
+            {
                 self.msix_config
                     .lock()
                     .read_msix_table(o - MSIX_TABLE_BAR_OFFSET, data);
             }
 
-            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+            o if self.msix_layout.pba_offset <= o
+                && o < self.msix_layout.pba_offset + self.msix_layout.pba_size =>
+            {
                 self.msix_config
                     .lock()
-                    .read_pba_entries(o - MSIX_PBA_BAR_OFFSET, data);
+                    .read_pba_entries(o - self.msix_layout.pba_offset, data);
             }
 
             _ => (),
@@ -645,17 +827,21 @@ impl PciDevice for VirtioPciDevice {
             {
                 // Handled with ioevents.
             }
-            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+            o if MSIX_TABLE_BAR_OFFSET <= o
+                && o < MSIX_TABLE_BAR_OFFSET + self.msix_layout.table_size =>
+            {
                 let behavior = self
                     .msix_config
                     .lock()
                     .write_msix_table(o - MSIX_TABLE_BAR_OFFSET, data);
                 self.device.control_notify(behavior);
             }
-            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+            o if self.msix_layout.pba_offset <= o
+                && o < self.msix_layout.pba_offset + self.msix_layout.pba_size =>
+            {
                 self.msix_config
                     .lock()
-                    .write_pba_entries(o - MSIX_PBA_BAR_OFFSET, data);
+                    .write_pba_entries(o - self.msix_layout.pba_offset, data);
             }
 
             _ => (),
@@ -688,13 +874,15 @@ impl PciDevice for VirtioPciDevice {
                     };
                     if let Some(mem) = self.mem.take() {
                         self.mem = Some(mem.clone());
-                        let interrupt = Interrupt::new(
+                        let mut interrupt = Interrupt::new(
                             self.interrupt_status.clone(),
                             interrupt_evt,
                             interrupt_resample_evt,
                             Some(self.msix_config.clone()),
                             self.common_config.msix_config,
                         );
+                        interrupt.set_metrics(self.metrics.clone());
+                        interrupt.set_needs_reset_flag(self.needs_reset.clone());
 
                         match self.clone_queue_evts() {
                             Ok(queue_evts) => {
@@ -720,8 +908,18 @@ impl PciDevice for VirtioPciDevice {
         }
 
         // Device has been reset by the driver
-        if self.device_activated && self.is_reset_requested() && self.device.reset() {
+        if self.device_activated && self.is_reset_requested() {
+            if !self.device.reset() {
+                if self.needs_reset.load(Ordering::SeqCst) {
+                    error!(
+                        "{} stopped working and can't be reset, it is gone until the VM reboots",
+                        self.debug_label()
+                    );
+                }
+                return;
+            }
             self.device_activated = false;
+            self.needs_reset.store(false, Ordering::SeqCst);
             // reset queues
             self.queues.iter_mut().for_each(Queue::reset);
             // select queue 0 by default
@@ -732,4 +930,124 @@ impl PciDevice for VirtioPciDevice {
     fn on_device_sandboxed(&mut self) {
         self.device.on_device_sandboxed();
     }
+
+    fn on_device_suspend(&mut self) {
+        self.device.on_device_suspend();
+    }
+
+    fn on_device_resume(&mut self) {
+        self.device.on_device_resume();
+    }
+
+    fn on_device_sleep(&mut self) {
+        // Devices that can't sleep keep their workers, which the guest driver should have
+        // quiesced before sleeping.
+        if self.device_activated && self.sleeping_queues.is_none() {
+            self.sleeping_queues = self.device.virtio_sleep();
+        }
+    }
+
+    fn on_device_wake(&mut self) {
+        if let Some(queues) = self.sleeping_queues.take() {
+            self.wake_device(queues);
+        }
+    }
+
+    fn snapshot_device(&mut self) -> Option<Vec<u8>> {
+        if !self.device_activated {
+            return self.snapshot_state(&self.queues);
+        }
+        // The workers have the up to date queues, and must not touch them while they are saved.
+        let queues = self.sleep_device()?;
+        let data = self.snapshot_state(&queues);
+        if !self.wake_device(queues) {
+            return None;
+        }
+        data
+    }
+
+    fn restore_device(&mut self, data: &[u8]) -> bool {
+        let snapshot: VirtioPciDeviceSnapshot = match deserialize_from_slice(data) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("{} got an invalid snapshot: {}", self.debug_label(), e);
+                return false;
+            }
+        };
+        // A device asleep with the guest has no workers left to stop.
+        let guest_asleep = self.sleeping_queues.is_some();
+        let old_queues = match self.sleeping_queues.take() {
+            Some(queues) => Some(queues),
+            None if self.device_activated => match self.sleep_device() {
+                Some(queues) => Some(queues),
+                None => return false,
+            },
+            None => None,
+        };
+        // Kept to go back to if the snapshot doesn't fit, so the device isn't left without workers.
+        let old_state = self.save_state(old_queues.as_deref().unwrap_or(&self.queues));
+        if self.restore_state(snapshot) {
+            if self.device_activated {
+                return self.wake_device(self.queues.clone());
+            }
+            return true;
+        }
+
+        error!("{} failed to restore its snapshot", self.debug_label());
+        if !old_state.map_or(false, |state| self.restore_state(state)) {
+            error!("{} failed to go back to its old state", self.debug_label());
+        }
+        match old_queues {
+            Some(queues) if guest_asleep => self.sleeping_queues = Some(queues),
+            Some(queues) => {
+                self.wake_device(queues);
+            }
+            None => {}
+        }
+        false
+    }
+
+    fn generate_acpi(&mut self, sdts: &mut Vec<SDT>) {
+        if let Some(pci_address) = self.pci_address {
+            self.device.generate_acpi(pci_address, sdts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msix_layout() {
+        // A handful of vectors keeps the original 32 KiB settings BAR.
+        assert_eq!(
+            MsixLayout::new(3),
+            MsixLayout {
+                table_size: 0x30,
+                pba_offset: 0x7000,
+                pba_size: 0x8,
+                bar_size: 0x8000,
+            }
+        );
+        // More vectors than fit in one page of table entries push the PBA further out.
+        assert_eq!(
+            MsixLayout::new(258),
+            MsixLayout {
+                table_size: 0x1020,
+                pba_offset: 0x8000,
+                pba_size: 0x28,
+                bar_size: 0x10000,
+            }
+        );
+        assert_eq!(
+            MsixLayout::new(MAX_MSIX_VECTORS_PER_DEVICE),
+            MsixLayout {
+                table_size: 0x8000,
+                pba_offset: 0xe000,
+                pba_size: 0x100,
+                bar_size: 0x10000,
+            }
+        );
+    }
 }
@@ -29,6 +29,9 @@ use composite::{CompositeDiskFile, CDISK_MAGIC, CDISK_MAGIC_LEN};
 mod android_sparse;
 use android_sparse::{AndroidSparse, SPARSE_HEADER_MAGIC};
 
+mod iso9660;
+pub use iso9660::{create_iso_image, Error as IsoError};
+
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
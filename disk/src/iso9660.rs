@@ -0,0 +1,301 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Builds small, flat ISO 9660 images, such as the config drives read by cloud-init.
+
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+use remain::sorted;
+
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    DirectoryFull,
+    DuplicateFileName(String),
+    FileTooLarge(String),
+    InvalidFileName(String),
+    InvalidVolumeId(String),
+}
+
+impl Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[sorted]
+        match self {
+            DirectoryFull => write!(f, "too many files to fit in the root directory"),
+            DuplicateFileName(name) => write!(f, "file \"{}\" was given more than once", name),
+            FileTooLarge(name) => write!(f, "file \"{}\" is larger than 4GiB", name),
+            InvalidFileName(name) => write!(
+                f,
+                "invalid file name \"{}\": use 1 to {} letters, digits, '-', '_' and at most one '.'",
+                name, MAX_FILE_NAME_LEN
+            ),
+            InvalidVolumeId(id) => write!(
+                f,
+                "invalid volume id \"{}\": use 1 to {} upper case letters, digits and '_'",
+                id, MAX_VOLUME_ID_LEN
+            ),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const SECTOR_SIZE: usize = 2048;
+// Sectors before the primary volume descriptor are the unused system area.
+const PRIMARY_VOLUME_DESCRIPTOR_SECTOR: usize = 16;
+const TERMINATOR_SECTOR: usize = 17;
+const L_PATH_TABLE_SECTOR: usize = 18;
+const M_PATH_TABLE_SECTOR: usize = 19;
+const ROOT_DIRECTORY_SECTOR: usize = 20;
+const FIRST_FILE_SECTOR: usize = 21;
+
+const MAX_FILE_NAME_LEN: usize = 30;
+const MAX_VOLUME_ID_LEN: usize = 32;
+const DIRECTORY_FLAG: u8 = 0x02;
+// A path table holding only the root directory.
+const PATH_TABLE_LEN: usize = 10;
+
+fn both_endian_u16(v: u16) -> [u8; 4] {
+    let le = v.to_le_bytes();
+    let be = v.to_be_bytes();
+    [le[0], le[1], be[0], be[1]]
+}
+
+fn both_endian_u32(v: u32) -> [u8; 8] {
+    let mut out = [0u8; 8];
+    out[..4].copy_from_slice(&v.to_le_bytes());
+    out[4..].copy_from_slice(&v.to_be_bytes());
+    out
+}
+
+// Copies `src` into the start of `dst` and fills the rest with spaces.
+fn pad_with_spaces(dst: &mut [u8], src: &[u8]) {
+    dst[..src.len()].copy_from_slice(src);
+    for b in &mut dst[src.len()..] {
+        *b = b' ';
+    }
+}
+
+fn sectors(len: usize) -> usize {
+    (len + SECTOR_SIZE - 1) / SECTOR_SIZE
+}
+
+fn directory_record(id: &[u8], sector: usize, len: u32, flags: u8) -> Vec<u8> {
+    // Records must have an even length, so identifiers of even length get a padding byte.
+    let record_len = 33 + id.len() + (1 - id.len() % 2);
+    let mut record = vec![0u8; record_len];
+    record[0] = record_len as u8;
+    record[2..10].copy_from_slice(&both_endian_u32(sector as u32));
+    record[10..18].copy_from_slice(&both_endian_u32(len));
+    // Recorded at 1970-01-01 00:00:00 UTC so that images are reproducible.
+    record[18..25].copy_from_slice(&[70, 1, 1, 0, 0, 0, 0]);
+    record[25] = flags;
+    record[28..32].copy_from_slice(&both_endian_u16(1));
+    record[32] = id.len() as u8;
+    record[33..33 + id.len()].copy_from_slice(id);
+    record
+}
+
+// Converts `name` to an ISO 9660 file identifier. Linux maps identifiers back to lower case and
+// drops the version suffix, so "meta-data" is seen by the guest under its original name.
+fn file_identifier(name: &str) -> Result<Vec<u8>> {
+    let valid_chars = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+    let dots = name.bytes().filter(|&b| b == b'.').count();
+    if name.is_empty() || name.len() > MAX_FILE_NAME_LEN || !valid_chars || dots > 1 {
+        return Err(Error::InvalidFileName(name.to_string()));
+    }
+
+    let mut id = name.to_ascii_uppercase();
+    if dots == 0 {
+        id.push('.');
+    }
+    id.push_str(";1");
+    Ok(id.into_bytes())
+}
+
+/// Returns an ISO 9660 image labeled `volume_id` whose root directory holds `files`, given as
+/// pairs of file name and contents.
+pub fn create_iso_image(volume_id: &str, files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let valid_volume_id = volume_id
+        .bytes()
+        .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_');
+    if volume_id.is_empty() || volume_id.len() > MAX_VOLUME_ID_LEN || !valid_volume_id {
+        return Err(Error::InvalidVolumeId(volume_id.to_string()));
+    }
+
+    let mut entries = Vec::with_capacity(files.len());
+    for &(name, data) in files {
+        entries.push((file_identifier(name)?, name, data));
+    }
+    // Directory records must be sorted by identifier.
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(dup) = entries.windows(2).find(|w| w[0].0 == w[1].0) {
+        return Err(Error::DuplicateFileName(dup[1].1.to_string()));
+    }
+
+    let root_self = directory_record(
+        &[0],
+        ROOT_DIRECTORY_SECTOR,
+        SECTOR_SIZE as u32,
+        DIRECTORY_FLAG,
+    );
+    let mut root = root_self.clone();
+    root.extend(directory_record(
+        &[1],
+        ROOT_DIRECTORY_SECTOR,
+        SECTOR_SIZE as u32,
+        DIRECTORY_FLAG,
+    ));
+    let mut sector = FIRST_FILE_SECTOR;
+    let mut extents = Vec::with_capacity(entries.len());
+    for (id, name, data) in &entries {
+        let len = u32::try_from(data.len()).map_err(|_| Error::FileTooLarge(name.to_string()))?;
+        root.extend(directory_record(id, sector, len, 0));
+        extents.push((sector, *data));
+        sector += sectors(data.len());
+    }
+    if root.len() > SECTOR_SIZE {
+        return Err(Error::DirectoryFull);
+    }
+
+    let total_sectors = sector;
+    let mut image = vec![0u8; total_sectors * SECTOR_SIZE];
+
+    let pvd_start = PRIMARY_VOLUME_DESCRIPTOR_SECTOR * SECTOR_SIZE;
+    let pvd = &mut image[pvd_start..pvd_start + SECTOR_SIZE];
+    pvd[0] = 1;
+    pvd[1..6].copy_from_slice(b"CD001");
+    pvd[6] = 1;
+    pad_with_spaces(&mut pvd[8..40], b"LINUX");
+    pad_with_spaces(&mut pvd[40..72], volume_id.as_bytes());
+    pvd[80..88].copy_from_slice(&both_endian_u32(total_sectors as u32));
+    pvd[120..124].copy_from_slice(&both_endian_u16(1));
+    pvd[124..128].copy_from_slice(&both_endian_u16(1));
+    pvd[128..132].copy_from_slice(&both_endian_u16(SECTOR_SIZE as u16));
+    pvd[132..140].copy_from_slice(&both_endian_u32(PATH_TABLE_LEN as u32));
+    pvd[140..144].copy_from_slice(&(L_PATH_TABLE_SECTOR as u32).to_le_bytes());
+    pvd[148..152].copy_from_slice(&(M_PATH_TABLE_SECTOR as u32).to_be_bytes());
+    pvd[156..156 + root_self.len()].copy_from_slice(&root_self);
+    // Volume set, publisher, data preparer and application identifiers, followed by the
+    // copyright, abstract and bibliographic file identifiers.
+    pad_with_spaces(&mut pvd[190..813], &[]);
+    // Creation, modification, expiration and effective dates are all left unspecified.
+    for date in pvd[813..881].chunks_mut(17) {
+        for b in &mut date[..16] {
+            *b = b'0';
+        }
+    }
+    pvd[881] = 1;
+
+    let terminator_start = TERMINATOR_SECTOR * SECTOR_SIZE;
+    let terminator = &mut image[terminator_start..terminator_start + 7];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+
+    for &(path_table_sector, little_endian) in
+        &[(L_PATH_TABLE_SECTOR, true), (M_PATH_TABLE_SECTOR, false)]
+    {
+        let start = path_table_sector * SECTOR_SIZE;
+        let entry = &mut image[start..start + PATH_TABLE_LEN];
+        entry[0] = 1;
+        let (location, parent) = if little_endian {
+            (
+                (ROOT_DIRECTORY_SECTOR as u32).to_le_bytes(),
+                1u16.to_le_bytes(),
+            )
+        } else {
+            (
+                (ROOT_DIRECTORY_SECTOR as u32).to_be_bytes(),
+                1u16.to_be_bytes(),
+            )
+        };
+        entry[2..6].copy_from_slice(&location);
+        entry[6..8].copy_from_slice(&parent);
+    }
+
+    let root_start = ROOT_DIRECTORY_SECTOR * SECTOR_SIZE;
+    image[root_start..root_start + root.len()].copy_from_slice(&root);
+
+    for (sector, data) in extents {
+        let start = sector * SECTOR_SIZE;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_record<'a>(image: &'a [u8], id: &[u8]) -> Option<&'a [u8]> {
+        let root_start = ROOT_DIRECTORY_SECTOR * SECTOR_SIZE;
+        let mut root = &image[root_start..root_start + SECTOR_SIZE];
+        while !root.is_empty() && root[0] != 0 {
+            let (record, rest) = root.split_at(root[0] as usize);
+            if &record[33..33 + record[32] as usize] == id {
+                return Some(record);
+            }
+            root = rest;
+        }
+        None
+    }
+
+    fn read_file<'a>(image: &'a [u8], id: &[u8]) -> &'a [u8] {
+        let record = find_record(image, id).expect("missing directory record");
+        let sector = u32::from_le_bytes([record[2], record[3], record[4], record[5]]) as usize;
+        let len = u32::from_le_bytes([record[10], record[11], record[12], record[13]]) as usize;
+        &image[sector * SECTOR_SIZE..sector * SECTOR_SIZE + len]
+    }
+
+    #[test]
+    fn image_layout() {
+        let user_data = vec![b'x'; SECTOR_SIZE + 1];
+        let image = create_iso_image(
+            "CIDATA",
+            &[
+                ("user-data", &user_data[..]),
+                ("meta-data", b"instance-id: \"test\"\n"),
+            ],
+        )
+        .unwrap();
+
+        let pvd = &image[PRIMARY_VOLUME_DESCRIPTOR_SECTOR * SECTOR_SIZE..];
+        assert_eq!(&pvd[0..7], b"\x01CD001\x01");
+        assert_eq!(&pvd[40..47], b"CIDATA ");
+        // System area, descriptors, path tables, root directory, 1 + 2 file sectors.
+        assert_eq!(image.len(), 24 * SECTOR_SIZE);
+        assert_eq!(&pvd[80..84], &24u32.to_le_bytes());
+
+        assert_eq!(
+            read_file(&image, b"META-DATA.;1"),
+            b"instance-id: \"test\"\n"
+        );
+        assert_eq!(read_file(&image, b"USER-DATA.;1"), &user_data[..]);
+    }
+
+    #[test]
+    fn empty_file() {
+        let image = create_iso_image("CIDATA", &[("vendor-data", b"")]).unwrap();
+        assert_eq!(image.len(), FIRST_FILE_SECTOR * SECTOR_SIZE);
+        assert!(read_file(&image, b"VENDOR-DATA.;1").is_empty());
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(create_iso_image("cidata", &[]).is_err());
+        assert!(create_iso_image("CIDATA", &[("", b"")]).is_err());
+        assert!(create_iso_image("CIDATA", &[("a/b", b"")]).is_err());
+        assert!(create_iso_image("CIDATA", &[("a.b.c", b"")]).is_err());
+        assert!(create_iso_image("CIDATA", &[("a", b""), ("A", b"")]).is_err());
+        assert!(create_iso_image("CIDATA", &[("network-config", b"")]).is_ok());
+    }
+}
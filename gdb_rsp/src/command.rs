@@ -0,0 +1,507 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::packet::{decode_hex, hex_digit};
+use crate::{Error, Result};
+
+/// A thread as gdb names it in `H`, `T` and `vCont` packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadId {
+    /// `0`: whichever thread the stub picks.
+    Any,
+    /// `-1`: every thread.
+    All,
+    /// A thread ID, counted from 1.
+    Id(usize),
+}
+
+/// The operations that an `H` packet picks the thread for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThreadOp {
+    /// `Hc`: continuing and stepping.
+    Continue,
+    /// `Hg`: every other operation, such as reading registers.
+    General,
+}
+
+/// The kind of breakpoint or watchpoint in a `Z` or `z` packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakpointKind {
+    Software,
+    Hardware,
+    WriteWatch,
+    ReadWatch,
+    AccessWatch,
+}
+
+/// What a `vCont` action does to the threads it applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResumeKind {
+    /// `c`
+    Continue,
+    /// `s`
+    Step,
+    /// `C`: continue with a signal.
+    ContinueWithSignal(u8),
+    /// `S`: single-step with a signal.
+    StepWithSignal(u8),
+    /// `t`: stop.
+    Stop,
+}
+
+/// An action of a `vCont` packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VContAction {
+    pub kind: ResumeKind,
+    /// The thread the action applies to, or `None` for every thread without an action of its own.
+    pub thread: Option<ThreadId>,
+}
+
+/// Returns what the actions of a `vCont` packet do to thread `tid`, or `None` if they leave it
+/// alone.
+///
+/// Each thread follows the leftmost action that applies to it, so `vCont;s:1;c` steps thread 1 and
+/// continues the others. `ThreadId::Any` is treated like `ThreadId::All`.
+pub fn resume_kind(actions: &[VContAction], tid: usize) -> Option<ResumeKind> {
+    actions
+        .iter()
+        .find(|action| match action.thread {
+            Some(ThreadId::Id(id)) => id == tid,
+            _ => true,
+        })
+        .map(|action| action.kind)
+}
+
+/// A packet sent by gdb to the stub.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `?`: report why the target stopped.
+    StopReason,
+    /// `g`: read all registers.
+    ReadRegisters,
+    /// `G`: write all registers, in target byte order.
+    WriteRegisters(Vec<u8>),
+    /// `p`: read the register with the given gdb register number.
+    ReadRegister(usize),
+    /// `P`: write the register with the given gdb register number, in target byte order.
+    WriteRegister(usize, Vec<u8>),
+    /// `m`: read memory.
+    ReadMemory { addr: u64, len: usize },
+    /// `M`: write memory.
+    WriteMemory { addr: u64, data: Vec<u8> },
+    /// `c`: continue, optionally from another address.
+    Continue(Option<u64>),
+    /// `s`: single-step, optionally from another address.
+    Step(Option<u64>),
+    /// `Z`: insert a breakpoint or watchpoint of `size` bytes at `addr`.
+    InsertBreakpoint {
+        kind: BreakpointKind,
+        addr: u64,
+        size: usize,
+    },
+    /// `z`: remove a breakpoint or watchpoint inserted by `InsertBreakpoint`.
+    RemoveBreakpoint {
+        kind: BreakpointKind,
+        addr: u64,
+        size: usize,
+    },
+    /// `H`: pick the thread for the following operations of a kind.
+    SetThread(ThreadOp, ThreadId),
+    /// `T`: check whether a thread is alive.
+    ThreadAlive(ThreadId),
+    /// `qSupported`: exchange the protocol features supported by gdb and the stub.
+    Supported(Vec<String>),
+    /// `qfThreadInfo`: start listing the threads.
+    FirstThreadInfo,
+    /// `qsThreadInfo`: continue listing the threads.
+    NextThreadInfo,
+    /// `qAttached`: whether gdb attached to an existing process rather than creating one.
+    Attached,
+    /// `qC`: report the current thread.
+    CurrentThread,
+    /// `QStartNoAckMode`: stop sending and expecting acknowledgements after the reply.
+    StartNoAckMode,
+    /// `D`: detach from the target and let it run.
+    Detach,
+    /// `k`: kill the target.
+    Kill,
+    /// `vCont?`: report the `vCont` actions supported.
+    VContSupported,
+    /// `vCont`: resume or stop each thread as given by its action.
+    VCont(Vec<VContAction>),
+    /// `qXfer:features:read`: read `len` bytes at `offset` of the target description `annex`.
+    ReadFeatures {
+        annex: String,
+        offset: usize,
+        len: usize,
+    },
+    /// `qRcmd`: run a `monitor` command.
+    Monitor(Vec<u8>),
+    /// Any packet not listed above. Stubs answer these with an empty reply.
+    Unknown(Vec<u8>),
+}
+
+impl Command {
+    /// Parses the data of a command packet.
+    ///
+    /// Packets this doesn't know are returned as `Unknown`. Known packets with malformed arguments
+    /// are an error, which the stub should answer with an error reply.
+    pub fn parse(packet: &[u8]) -> Result<Command> {
+        let invalid = || Error::InvalidCommand(String::from_utf8_lossy(packet).into_owned());
+        let (&name, args) = match packet.split_first() {
+            Some(split) => split,
+            None => return Ok(Command::Unknown(Vec::new())),
+        };
+
+        let command = match (name, args) {
+            (b'?', b"") => Command::StopReason,
+            (b'g', b"") => Command::ReadRegisters,
+            (b'G', _) => Command::WriteRegisters(decode_hex(args).ok_or_else(invalid)?),
+            (b'p', _) => Command::ReadRegister(parse_hex(args).ok_or_else(invalid)? as usize),
+            (b'P', _) => {
+                let (reg, value) = split_once(args, b'=').ok_or_else(invalid)?;
+                Command::WriteRegister(
+                    parse_hex(reg).ok_or_else(invalid)? as usize,
+                    decode_hex(value).ok_or_else(invalid)?,
+                )
+            }
+            (b'm', _) => {
+                let (addr, len) = split_once(args, b',').ok_or_else(invalid)?;
+                Command::ReadMemory {
+                    addr: parse_hex(addr).ok_or_else(invalid)?,
+                    len: parse_hex(len).ok_or_else(invalid)? as usize,
+                }
+            }
+            (b'M', _) => {
+                let (range, data) = split_once(args, b':').ok_or_else(invalid)?;
+                let (addr, len) = split_once(range, b',').ok_or_else(invalid)?;
+                let data = decode_hex(data).ok_or_else(invalid)?;
+                if parse_hex(len) != Some(data.len() as u64) {
+                    return Err(invalid());
+                }
+                Command::WriteMemory {
+                    addr: parse_hex(addr).ok_or_else(invalid)?,
+                    data,
+                }
+            }
+            (b'c', _) => Command::Continue(parse_optional_hex(args).ok_or_else(invalid)?),
+            (b's', _) => Command::Step(parse_optional_hex(args).ok_or_else(invalid)?),
+            (b'Z', _) | (b'z', _) => {
+                let mut fields = args.split(|&b| b == b',');
+                let kind = match fields.next() {
+                    Some(b"0") => BreakpointKind::Software,
+                    Some(b"1") => BreakpointKind::Hardware,
+                    Some(b"2") => BreakpointKind::WriteWatch,
+                    Some(b"3") => BreakpointKind::ReadWatch,
+                    Some(b"4") => BreakpointKind::AccessWatch,
+                    _ => return Err(invalid()),
+                };
+                let addr = fields.next().and_then(parse_hex).ok_or_else(invalid)?;
+                // The size may be followed by conditions for the stub to evaluate, which gdb only
+                // sends if the stub reports support for them.
+                let size = fields
+                    .next()
+                    .and_then(|f| parse_hex(f.split(|&b| b == b';').next()?))
+                    .ok_or_else(invalid)? as usize;
+                if name == b'Z' {
+                    Command::InsertBreakpoint { kind, addr, size }
+                } else {
+                    Command::RemoveBreakpoint { kind, addr, size }
+                }
+            }
+            (b'H', [op, thread @ ..]) => {
+                let op = match op {
+                    b'c' => ThreadOp::Continue,
+                    b'g' => ThreadOp::General,
+                    _ => return Err(invalid()),
+                };
+                Command::SetThread(op, parse_thread_id(thread).ok_or_else(invalid)?)
+            }
+            (b'T', _) => Command::ThreadAlive(parse_thread_id(args).ok_or_else(invalid)?),
+            (b'D', _) => Command::Detach,
+            (b'k', b"") => Command::Kill,
+            (b'q', _) => match args {
+                b"Supported" => Command::Supported(Vec::new()),
+                b"fThreadInfo" => Command::FirstThreadInfo,
+                b"sThreadInfo" => Command::NextThreadInfo,
+                b"C" => Command::CurrentThread,
+                _ if args.starts_with(b"Supported:") => Command::Supported(
+                    args[b"Supported:".len()..]
+                        .split(|&b| b == b';')
+                        .map(|f| String::from_utf8_lossy(f).into_owned())
+                        .collect(),
+                ),
+                // gdb adds the process ID when it's debugging several processes.
+                _ if args == b"Attached" || args.starts_with(b"Attached:") => Command::Attached,
+                _ if args.starts_with(b"Xfer:features:read:") => {
+                    let args = &args[b"Xfer:features:read:".len()..];
+                    let (annex, range) = split_once(args, b':').ok_or_else(invalid)?;
+                    let (offset, len) = split_once(range, b',').ok_or_else(invalid)?;
+                    Command::ReadFeatures {
+                        annex: String::from_utf8_lossy(annex).into_owned(),
+                        offset: parse_hex(offset).ok_or_else(invalid)? as usize,
+                        len: parse_hex(len).ok_or_else(invalid)? as usize,
+                    }
+                }
+                _ if args.starts_with(b"Rcmd,") => {
+                    Command::Monitor(decode_hex(&args[b"Rcmd,".len()..]).ok_or_else(invalid)?)
+                }
+                _ => Command::Unknown(packet.to_vec()),
+            },
+            (b'Q', b"StartNoAckMode") => Command::StartNoAckMode,
+            (b'v', b"Cont?") => Command::VContSupported,
+            (b'v', _) if args.starts_with(b"Cont;") => Command::VCont(
+                args[b"Cont;".len()..]
+                    .split(|&b| b == b';')
+                    .map(parse_vcont_action)
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?,
+            ),
+            _ => Command::Unknown(packet.to_vec()),
+        };
+        Ok(command)
+    }
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
+
+// Parses a big endian hex number, as gdb sends addresses, lengths and register numbers.
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes
+        .iter()
+        .try_fold(0u64, |n, &b| Some(n << 4 | hex_digit(b)? as u64))
+}
+
+// Parses the optional address of `c` and `s`.
+fn parse_optional_hex(bytes: &[u8]) -> Option<Option<u64>> {
+    if bytes.is_empty() {
+        Some(None)
+    } else {
+        parse_hex(bytes).map(Some)
+    }
+}
+
+// Parses a thread ID, which may be prefixed by a process ID as `p<pid>.<tid>` when gdb debugs
+// several processes.
+fn parse_thread_id(bytes: &[u8]) -> Option<ThreadId> {
+    let tid = if bytes.starts_with(b"p") {
+        match split_once(&bytes[1..], b'.') {
+            Some((_, tid)) => tid,
+            None => return Some(ThreadId::All),
+        }
+    } else {
+        bytes
+    };
+    match tid {
+        b"-1" => Some(ThreadId::All),
+        b"0" => Some(ThreadId::Any),
+        _ => parse_hex(tid).map(|id| ThreadId::Id(id as usize)),
+    }
+}
+
+// Parses an action of a `vCont` packet, `<kind>[:<thread>]`.
+fn parse_vcont_action(action: &[u8]) -> Option<VContAction> {
+    let (kind, thread) = match split_once(action, b':') {
+        Some((kind, thread)) => (kind, Some(parse_thread_id(thread)?)),
+        None => (action, None),
+    };
+    let signal = |sig: &[u8]| match parse_hex(sig) {
+        Some(sig) if sig <= 0xff => Some(sig as u8),
+        _ => None,
+    };
+    let kind = match kind.split_first()? {
+        (b'c', b"") => ResumeKind::Continue,
+        (b's', b"") => ResumeKind::Step,
+        (b'C', sig) => ResumeKind::ContinueWithSignal(signal(sig)?),
+        (b'S', sig) => ResumeKind::StepWithSignal(signal(sig)?),
+        (b't', b"") => ResumeKind::Stop,
+        _ => return None,
+    };
+    Some(VContAction { kind, thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(packet: &str) -> Result<Command> {
+        Command::parse(packet.as_bytes())
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(parse("g"), Ok(Command::ReadRegisters));
+        assert_eq!(parse("G0102"), Ok(Command::WriteRegisters(vec![1, 2])));
+        assert_eq!(parse("p10"), Ok(Command::ReadRegister(16)));
+        assert_eq!(
+            parse("P11=78563412"),
+            Ok(Command::WriteRegister(17, vec![0x78, 0x56, 0x34, 0x12]))
+        );
+        assert!(parse("p").is_err());
+        assert!(parse("P11").is_err());
+        assert!(parse("G012").is_err());
+    }
+
+    #[test]
+    fn memory() {
+        assert_eq!(
+            parse("mffffffff81000000,40"),
+            Ok(Command::ReadMemory {
+                addr: 0xffff_ffff_8100_0000,
+                len: 0x40
+            })
+        );
+        assert_eq!(
+            parse("M1000,2:cc90"),
+            Ok(Command::WriteMemory {
+                addr: 0x1000,
+                data: vec![0xcc, 0x90]
+            })
+        );
+        assert!(parse("M1000,3:cc90").is_err());
+        assert!(parse("m1000").is_err());
+        assert!(parse("m10000000000000000,1").is_err());
+    }
+
+    #[test]
+    fn run_control() {
+        assert_eq!(parse("?"), Ok(Command::StopReason));
+        assert_eq!(parse("c"), Ok(Command::Continue(None)));
+        assert_eq!(parse("c1000"), Ok(Command::Continue(Some(0x1000))));
+        assert_eq!(parse("s"), Ok(Command::Step(None)));
+        assert_eq!(parse("D"), Ok(Command::Detach));
+        assert_eq!(parse("D;1"), Ok(Command::Detach));
+        assert_eq!(parse("k"), Ok(Command::Kill));
+    }
+
+    #[test]
+    fn vcont() {
+        assert_eq!(parse("vCont?"), Ok(Command::VContSupported));
+        let actions = vec![
+            VContAction {
+                kind: ResumeKind::Step,
+                thread: Some(ThreadId::Id(1)),
+            },
+            VContAction {
+                kind: ResumeKind::Continue,
+                thread: None,
+            },
+        ];
+        assert_eq!(parse("vCont;s:1;c"), Ok(Command::VCont(actions.clone())));
+        assert_eq!(resume_kind(&actions, 1), Some(ResumeKind::Step));
+        assert_eq!(resume_kind(&actions, 2), Some(ResumeKind::Continue));
+
+        // The leftmost action wins.
+        let actions = match parse("vCont;C0b:p1.2;t:-1;s:2") {
+            Ok(Command::VCont(actions)) => actions,
+            c => panic!("unexpected command {:?}", c),
+        };
+        assert_eq!(
+            resume_kind(&actions, 2),
+            Some(ResumeKind::ContinueWithSignal(11))
+        );
+        assert_eq!(resume_kind(&actions, 3), Some(ResumeKind::Stop));
+        assert_eq!(resume_kind(&actions[..1], 3), None);
+
+        assert!(parse("vCont;").is_err());
+        assert!(parse("vCont;x").is_err());
+        assert!(parse("vCont;C100").is_err());
+        assert!(parse("vCont;s:").is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        assert_eq!(
+            parse("Z0,1000,1"),
+            Ok(Command::InsertBreakpoint {
+                kind: BreakpointKind::Software,
+                addr: 0x1000,
+                size: 1
+            })
+        );
+        assert_eq!(
+            parse("z2,2000,8"),
+            Ok(Command::RemoveBreakpoint {
+                kind: BreakpointKind::WriteWatch,
+                addr: 0x2000,
+                size: 8
+            })
+        );
+        assert_eq!(
+            parse("Z1,1000,1;X2,0a"),
+            Ok(Command::InsertBreakpoint {
+                kind: BreakpointKind::Hardware,
+                addr: 0x1000,
+                size: 1
+            })
+        );
+        assert!(parse("Z5,1000,1").is_err());
+        assert!(parse("Z0,1000").is_err());
+    }
+
+    #[test]
+    fn threads() {
+        assert_eq!(
+            parse("Hg0"),
+            Ok(Command::SetThread(ThreadOp::General, ThreadId::Any))
+        );
+        assert_eq!(
+            parse("Hc-1"),
+            Ok(Command::SetThread(ThreadOp::Continue, ThreadId::All))
+        );
+        assert_eq!(
+            parse("Hgp1.a"),
+            Ok(Command::SetThread(ThreadOp::General, ThreadId::Id(10)))
+        );
+        assert_eq!(parse("T2"), Ok(Command::ThreadAlive(ThreadId::Id(2))));
+        assert_eq!(parse("qfThreadInfo"), Ok(Command::FirstThreadInfo));
+        assert_eq!(parse("qsThreadInfo"), Ok(Command::NextThreadInfo));
+        assert_eq!(parse("qC"), Ok(Command::CurrentThread));
+        assert!(parse("Hx1").is_err());
+        assert!(parse("T").is_err());
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(
+            parse("qSupported:multiprocess+;swbreak+;xmlRegisters=i386"),
+            Ok(Command::Supported(vec![
+                "multiprocess+".to_string(),
+                "swbreak+".to_string(),
+                "xmlRegisters=i386".to_string()
+            ]))
+        );
+        assert_eq!(parse("qSupported"), Ok(Command::Supported(Vec::new())));
+        assert_eq!(parse("qAttached:1"), Ok(Command::Attached));
+        assert_eq!(parse("QStartNoAckMode"), Ok(Command::StartNoAckMode));
+        assert_eq!(
+            parse("qXfer:features:read:target.xml:0,ffb"),
+            Ok(Command::ReadFeatures {
+                annex: "target.xml".to_string(),
+                offset: 0,
+                len: 0xffb
+            })
+        );
+        assert!(parse("qXfer:features:read:target.xml:0").is_err());
+        assert_eq!(
+            parse("qRcmd,68656c70"),
+            Ok(Command::Monitor(b"help".to_vec()))
+        );
+        assert!(parse("qRcmd,6").is_err());
+        assert_eq!(
+            parse("qXfer:auxv:read::0,1000"),
+            Ok(Command::Unknown(b"qXfer:auxv:read::0,1000".to_vec()))
+        );
+        assert_eq!(
+            parse("vMustReplyEmpty"),
+            Ok(Command::Unknown(b"vMustReplyEmpty".to_vec()))
+        );
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parsing and encoding of the packets of the gdb remote serial protocol (RSP).
+//!
+//! `PacketParser` splits the bytes sent by gdb into packets and validates their checksums, and
+//! `Command::parse` decodes the packets a stub has to handle. Replies are framed with
+//! `encode_packet`. `RegisterLayout` describes how gdb numbers the registers of each
+//! architecture.
+//!
+//! `Stub` puts these together to serve a gdb session for anything that implements `Target`.
+
+mod command;
+mod packet;
+mod regs;
+mod stub;
+
+use std::fmt::{self, Display};
+
+pub use crate::command::{
+    resume_kind, BreakpointKind, Command, ResumeKind, ThreadId, ThreadOp, VContAction,
+};
+pub use crate::packet::{
+    decode_hex, encode_hex, encode_packet, GdbPacket, PacketParser, ACK, INTERRUPT,
+    MAX_PACKET_SIZE, NACK,
+};
+pub use crate::regs::{AArch64Registers, Register, RegisterLayout, X86_64Registers};
+pub use crate::stub::{
+    Connection, DisconnectReason, StopReason, Stub, StubError, StubResult, Target, TargetError,
+    TargetResult,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The checksum of a packet doesn't match its contents.
+    Checksum { expected: u8, actual: u8 },
+    /// A command packet has a known name but malformed arguments.
+    InvalidCommand(String),
+    /// A checksum digit isn't a hex digit.
+    InvalidChecksumDigit(u8),
+    /// A packet is longer than `MAX_PACKET_SIZE`.
+    PacketTooLong,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Checksum { expected, actual } => write!(
+                f,
+                "packet checksum is {:#04x} but its contents sum to {:#04x}",
+                expected, actual
+            ),
+            InvalidCommand(packet) => write!(f, "invalid command packet: {}", packet),
+            InvalidChecksumDigit(b) => write!(f, "invalid checksum digit: {:#04x}", b),
+            PacketTooLong => write!(f, "packet longer than {} bytes", MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
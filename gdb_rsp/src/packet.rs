@@ -0,0 +1,293 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use crate::{Error, Result};
+
+/// Acknowledges a packet that arrived intact.
+pub const ACK: u8 = b'+';
+/// Asks for a corrupted packet to be sent again.
+pub const NACK: u8 = b'-';
+/// Sent by gdb outside of a packet to stop a running target.
+pub const INTERRUPT: u8 = 0x03;
+
+/// The largest packet data accepted, in bytes as sent on the wire.
+pub const MAX_PACKET_SIZE: usize = 0x10000;
+
+const PACKET_START: u8 = b'$';
+const CHECKSUM_START: u8 = b'#';
+const ESCAPE: u8 = b'}';
+const RUN_LENGTH: u8 = b'*';
+// An escaped byte is sent XORed with this after the escape character.
+const ESCAPE_XOR: u8 = 0x20;
+
+/// What gdb sent, as found by `PacketParser`.
+#[derive(Debug, PartialEq)]
+pub enum GdbPacket {
+    /// gdb received the last packet intact.
+    Ack,
+    /// gdb received the last packet corrupted and wants it again.
+    Nack,
+    /// gdb wants the running target to stop.
+    Interrupt,
+    /// A `$data#checksum` packet with a valid checksum, with the escapes in `data` removed.
+    Command(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Between packets.
+    Idle,
+    // In the data of a packet.
+    Data,
+    // After an escape character in the data of a packet.
+    Escape,
+    // Expecting the first or second checksum digit.
+    Checksum,
+    ChecksumLow(u8),
+}
+
+/// Splits the bytes received from gdb into packets.
+pub struct PacketParser {
+    state: State,
+    data: Vec<u8>,
+    // The sum of the packet bytes received so far, escapes included.
+    sum: u8,
+    len: usize,
+    // Whether the packet got longer than `MAX_PACKET_SIZE`. The rest of it is dropped up to the
+    // checksum, so that none of its bytes are taken for acknowledgements or interrupts.
+    too_long: bool,
+}
+
+impl PacketParser {
+    pub fn new() -> PacketParser {
+        PacketParser {
+            state: State::Idle,
+            data: Vec::new(),
+            sum: 0,
+            len: 0,
+            too_long: false,
+        }
+    }
+
+    /// Feeds the next byte received from gdb.
+    ///
+    /// Returns the packet it completes, if any. Returns an error for a corrupted packet, which
+    /// should be answered with a `NACK` so that gdb sends it again. Intact command packets should
+    /// be answered with an `ACK` unless gdb turned acknowledgements off.
+    pub fn push(&mut self, byte: u8) -> Result<Option<GdbPacket>> {
+        match self.state {
+            State::Idle => match byte {
+                ACK => return Ok(Some(GdbPacket::Ack)),
+                NACK => return Ok(Some(GdbPacket::Nack)),
+                INTERRUPT => return Ok(Some(GdbPacket::Interrupt)),
+                PACKET_START => self.start_packet(),
+                // Anything else between packets is line noise.
+                _ => {}
+            },
+            // A start character in a packet means gdb gave up on the packet and sent a new one.
+            State::Data | State::Escape if byte == PACKET_START => self.start_packet(),
+            State::Data if byte == CHECKSUM_START => self.state = State::Checksum,
+            State::Data | State::Escape => {
+                self.len += 1;
+                self.too_long |= self.len > MAX_PACKET_SIZE;
+                self.sum = self.sum.wrapping_add(byte);
+                if self.too_long {
+                    self.state = State::Data;
+                } else if self.state == State::Escape {
+                    self.data.push(byte ^ ESCAPE_XOR);
+                    self.state = State::Data;
+                } else if byte == ESCAPE {
+                    self.state = State::Escape;
+                } else {
+                    self.data.push(byte);
+                }
+            }
+            State::Checksum => self.state = State::ChecksumLow(self.checksum_digit(byte)?),
+            State::ChecksumLow(high) => {
+                let expected = high << 4 | self.checksum_digit(byte)?;
+                self.state = State::Idle;
+                if self.too_long {
+                    return Err(Error::PacketTooLong);
+                }
+                if expected != self.sum {
+                    return Err(Error::Checksum {
+                        expected,
+                        actual: self.sum,
+                    });
+                }
+                return Ok(Some(GdbPacket::Command(std::mem::take(&mut self.data))));
+            }
+        }
+        Ok(None)
+    }
+
+    fn start_packet(&mut self) {
+        self.state = State::Data;
+        self.data.clear();
+        self.sum = 0;
+        self.len = 0;
+        self.too_long = false;
+    }
+
+    fn checksum_digit(&mut self, byte: u8) -> Result<u8> {
+        hex_digit(byte).ok_or_else(|| {
+            self.state = State::Idle;
+            Error::InvalidChecksumDigit(byte)
+        })
+    }
+}
+
+impl Default for PacketParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames `data` as a packet, escaping the bytes that would end it early.
+pub fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(PACKET_START);
+    for &b in data {
+        match b {
+            PACKET_START | CHECKSUM_START | ESCAPE | RUN_LENGTH => {
+                packet.push(ESCAPE);
+                packet.push(b ^ ESCAPE_XOR);
+            }
+            _ => packet.push(b),
+        }
+    }
+    let sum = packet[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    packet.push(CHECKSUM_START);
+    packet.extend(encode_hex(&[sum]));
+    packet
+}
+
+/// Encodes `bytes` as lower case hex digits, the way registers and memory are sent.
+pub fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    bytes
+        .iter()
+        .flat_map(|&b| vec![DIGITS[(b >> 4) as usize], DIGITS[(b & 0xf) as usize]])
+        .collect()
+}
+
+/// Decodes pairs of hex digits into bytes. Returns `None` if `hex` has an odd length or a byte
+/// that isn't a hex digit.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    let pairs = hex.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+pub(crate) fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut PacketParser, bytes: &[u8]) -> Vec<Result<GdbPacket>> {
+        bytes
+            .iter()
+            .filter_map(|&b| parser.push(b).transpose())
+            .collect()
+    }
+
+    #[test]
+    fn command_packet() {
+        let mut parser = PacketParser::new();
+        assert_eq!(
+            parse_all(&mut parser, b"$g#67"),
+            vec![Ok(GdbPacket::Command(b"g".to_vec()))]
+        );
+        assert_eq!(
+            parse_all(&mut parser, b"$m1000,4#8e"),
+            vec![Ok(GdbPacket::Command(b"m1000,4".to_vec()))]
+        );
+    }
+
+    #[test]
+    fn acks_and_interrupts() {
+        let mut parser = PacketParser::new();
+        assert_eq!(
+            parse_all(&mut parser, b"+-\x03"),
+            vec![
+                Ok(GdbPacket::Ack),
+                Ok(GdbPacket::Nack),
+                Ok(GdbPacket::Interrupt)
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut parser = PacketParser::new();
+        assert_eq!(
+            parse_all(&mut parser, b"$g#66"),
+            vec![Err(Error::Checksum {
+                expected: 0x66,
+                actual: 0x67
+            })]
+        );
+        assert_eq!(
+            parse_all(&mut parser, b"$g#6x"),
+            vec![Err(Error::InvalidChecksumDigit(b'x'))]
+        );
+        // The parser recovers for the next packet.
+        assert_eq!(
+            parse_all(&mut parser, b"$g#67"),
+            vec![Ok(GdbPacket::Command(b"g".to_vec()))]
+        );
+    }
+
+    #[test]
+    fn escaped_data() {
+        let mut parser = PacketParser::new();
+        let data = b"X0,4:$#}*".to_vec();
+        let packet = encode_packet(&data);
+        assert_eq!(&packet[..8], b"$X0,4:}\x04");
+        assert_eq!(
+            parse_all(&mut parser, &packet),
+            vec![Ok(GdbPacket::Command(data))]
+        );
+    }
+
+    #[test]
+    fn restarted_packet() {
+        let mut parser = PacketParser::new();
+        assert_eq!(
+            parse_all(&mut parser, b"noise$m10$g#67"),
+            vec![Ok(GdbPacket::Command(b"g".to_vec()))]
+        );
+    }
+
+    #[test]
+    fn packet_too_long() {
+        let mut parser = PacketParser::new();
+        let mut bytes = vec![b'$'];
+        bytes.resize(MAX_PACKET_SIZE + 2, b'0');
+        // The end of the packet isn't taken for an acknowledgement or an interrupt.
+        bytes.extend(b"+-\x03#00$g#67");
+        assert_eq!(
+            parse_all(&mut parser, &bytes),
+            vec![
+                Err(Error::PacketTooLong),
+                Ok(GdbPacket::Command(b"g".to_vec()))
+            ]
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(encode_hex(&[0x01, 0xab, 0xff]), b"01abff".to_vec());
+        assert_eq!(decode_hex(b"01aBff"), Some(vec![0x01, 0xab, 0xff]));
+        assert_eq!(decode_hex(b"123"), None);
+        assert_eq!(decode_hex(b"1g"), None);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+/// A register as gdb sees it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Register {
+    pub name: &'static str,
+    /// The size in bytes of the register in `g`, `G`, `p` and `P` packets.
+    pub size: usize,
+}
+
+const fn reg(name: &'static str, size: usize) -> Register {
+    Register { name, size }
+}
+
+/// Describes the registers of an architecture in the numbering gdb uses for `p` and `P`, which
+/// is also the order of the registers in `g` and `G`.
+pub trait RegisterLayout {
+    /// The registers, indexed by gdb register number.
+    const REGISTERS: &'static [Register];
+
+    /// Returns the size in bytes of the register with gdb register number `reg`, or `None` if
+    /// there is no such register.
+    fn register_size(reg: usize) -> Option<usize> {
+        Self::REGISTERS.get(reg).map(|r| r.size)
+    }
+
+    /// Returns the size in bytes of all the registers together, as sent in `g` and `G`.
+    fn registers_size() -> usize {
+        Self::REGISTERS.iter().map(|r| r.size).sum()
+    }
+}
+
+/// The x86_64 registers of gdb's `i386:x86-64` core and SSE features.
+pub struct X86_64Registers;
+
+impl RegisterLayout for X86_64Registers {
+    const REGISTERS: &'static [Register] = &[
+        reg("rax", 8),
+        reg("rbx", 8),
+        reg("rcx", 8),
+        reg("rdx", 8),
+        reg("rsi", 8),
+        reg("rdi", 8),
+        reg("rbp", 8),
+        reg("rsp", 8),
+        reg("r8", 8),
+        reg("r9", 8),
+        reg("r10", 8),
+        reg("r11", 8),
+        reg("r12", 8),
+        reg("r13", 8),
+        reg("r14", 8),
+        reg("r15", 8),
+        reg("rip", 8),
+        // gdb only shows the lower half of rflags.
+        reg("eflags", 4),
+        reg("cs", 4),
+        reg("ss", 4),
+        reg("ds", 4),
+        reg("es", 4),
+        reg("fs", 4),
+        reg("gs", 4),
+        // The x87 registers are 80 bits wide.
+        reg("st0", 10),
+        reg("st1", 10),
+        reg("st2", 10),
+        reg("st3", 10),
+        reg("st4", 10),
+        reg("st5", 10),
+        reg("st6", 10),
+        reg("st7", 10),
+        reg("fctrl", 4),
+        reg("fstat", 4),
+        reg("ftag", 4),
+        reg("fiseg", 4),
+        reg("fioff", 4),
+        reg("foseg", 4),
+        reg("fooff", 4),
+        reg("fop", 4),
+        reg("xmm0", 16),
+        reg("xmm1", 16),
+        reg("xmm2", 16),
+        reg("xmm3", 16),
+        reg("xmm4", 16),
+        reg("xmm5", 16),
+        reg("xmm6", 16),
+        reg("xmm7", 16),
+        reg("xmm8", 16),
+        reg("xmm9", 16),
+        reg("xmm10", 16),
+        reg("xmm11", 16),
+        reg("xmm12", 16),
+        reg("xmm13", 16),
+        reg("xmm14", 16),
+        reg("xmm15", 16),
+        reg("mxcsr", 4),
+    ];
+}
+
+/// The aarch64 registers of gdb's `org.gnu.gdb.aarch64.core` feature.
+pub struct AArch64Registers;
+
+impl RegisterLayout for AArch64Registers {
+    const REGISTERS: &'static [Register] = &[
+        reg("x0", 8),
+        reg("x1", 8),
+        reg("x2", 8),
+        reg("x3", 8),
+        reg("x4", 8),
+        reg("x5", 8),
+        reg("x6", 8),
+        reg("x7", 8),
+        reg("x8", 8),
+        reg("x9", 8),
+        reg("x10", 8),
+        reg("x11", 8),
+        reg("x12", 8),
+        reg("x13", 8),
+        reg("x14", 8),
+        reg("x15", 8),
+        reg("x16", 8),
+        reg("x17", 8),
+        reg("x18", 8),
+        reg("x19", 8),
+        reg("x20", 8),
+        reg("x21", 8),
+        reg("x22", 8),
+        reg("x23", 8),
+        reg("x24", 8),
+        reg("x25", 8),
+        reg("x26", 8),
+        reg("x27", 8),
+        reg("x28", 8),
+        reg("x29", 8),
+        reg("x30", 8),
+        reg("sp", 8),
+        reg("pc", 8),
+        // gdb only shows the lower half of PSTATE.
+        reg("cpsr", 4),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_64() {
+        assert_eq!(X86_64Registers::REGISTERS[16].name, "rip");
+        assert_eq!(X86_64Registers::REGISTERS[40].name, "xmm0");
+        assert_eq!(X86_64Registers::register_size(17), Some(4));
+        assert_eq!(X86_64Registers::register_size(24), Some(10));
+        assert_eq!(X86_64Registers::register_size(56), Some(4));
+        assert_eq!(X86_64Registers::register_size(57), None);
+        assert_eq!(X86_64Registers::registers_size(), 536);
+    }
+
+    #[test]
+    fn aarch64() {
+        assert_eq!(AArch64Registers::REGISTERS[31].name, "sp");
+        assert_eq!(AArch64Registers::register_size(32), Some(8));
+        assert_eq!(AArch64Registers::register_size(33), Some(4));
+        assert_eq!(AArch64Registers::register_size(34), None);
+        assert_eq!(AArch64Registers::registers_size(), 268);
+    }
+}
@@ -0,0 +1,754 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt::{self, Display};
+use std::io;
+
+use crate::command::{resume_kind, BreakpointKind, Command, ResumeKind, ThreadId, ThreadOp};
+use crate::packet::{
+    encode_hex, encode_packet, GdbPacket, PacketParser, ACK, MAX_PACKET_SIZE, NACK,
+};
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The byte stream between the stub and gdb.
+pub trait Connection {
+    /// Waits for the next byte from gdb.
+    fn read(&mut self) -> io::Result<u8>;
+
+    /// Returns the next byte from gdb, or `None` if it hasn't arrived yet.
+    fn try_read(&mut self) -> io::Result<Option<u8>>;
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+}
+
+/// Why the target stopped, reported to gdb with the thread that stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The thread finished a single step.
+    DoneStep(usize),
+    /// The thread hit a software breakpoint.
+    SwBreak(usize),
+    /// The thread hit a hardware breakpoint.
+    HwBreak(usize),
+    /// The thread hit the watchpoint of `kind` at `addr`.
+    Watch {
+        tid: usize,
+        kind: BreakpointKind,
+        addr: u64,
+    },
+    /// gdb interrupted the target.
+    Interrupted(usize),
+}
+
+impl StopReason {
+    fn tid(&self) -> usize {
+        use self::StopReason::*;
+
+        match *self {
+            DoneStep(tid) | SwBreak(tid) | HwBreak(tid) | Interrupted(tid) => tid,
+            Watch { tid, .. } => tid,
+        }
+    }
+}
+
+/// A target operation failed. The target logs why, gdb only gets an error reply.
+#[derive(Debug, PartialEq)]
+pub struct TargetError;
+
+pub type TargetResult<T> = std::result::Result<T, TargetError>;
+
+/// The target debugged by a `Stub`.
+///
+/// Threads are numbered from 1, as gdb numbers them. The stub only passes the numbers of existing
+/// threads.
+pub trait Target {
+    /// Returns the number of threads.
+    fn threads(&self) -> usize;
+
+    /// Reads the registers of thread `tid` in the layout of the `g` packet.
+    fn read_registers(&mut self, tid: usize) -> TargetResult<Vec<u8>>;
+
+    /// Writes the registers of thread `tid` in the layout of the `G` packet.
+    fn write_registers(&mut self, tid: usize, regs: &[u8]) -> TargetResult<()>;
+
+    /// Reads the register of thread `tid` with gdb register number `reg`, in target byte order.
+    fn read_register(&mut self, tid: usize, reg: usize) -> TargetResult<Vec<u8>>;
+
+    /// Writes the register of thread `tid` with gdb register number `reg`, in target byte order.
+    fn write_register(&mut self, tid: usize, reg: usize, value: &[u8]) -> TargetResult<()>;
+
+    /// Reads `len` bytes of memory at `addr` as thread `tid` sees it.
+    fn read_memory(&mut self, tid: usize, addr: u64, len: usize) -> TargetResult<Vec<u8>>;
+
+    /// Writes `data` to memory at `addr` as thread `tid` sees it.
+    fn write_memory(&mut self, tid: usize, addr: u64, data: &[u8]) -> TargetResult<()>;
+
+    /// Inserts a breakpoint or watchpoint of `size` bytes at `addr`. Returns `Ok(false)` if the
+    /// target doesn't support breakpoints of `kind`.
+    fn insert_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        addr: u64,
+        size: usize,
+    ) -> TargetResult<bool>;
+
+    /// Removes a breakpoint inserted by `insert_breakpoint`. Returns `Ok(false)` if the target
+    /// doesn't support breakpoints of `kind`.
+    fn remove_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        addr: u64,
+        size: usize,
+    ) -> TargetResult<bool>;
+
+    /// Resumes every thread as given by its action, `actions[tid - 1]`. Threads whose action is
+    /// `ResumeKind::Stop` stay stopped.
+    ///
+    /// Returns once a thread stops, with every other thread stopped too. The target should call
+    /// `interrupted` while it waits, and stop once it returns true.
+    fn resume(
+        &mut self,
+        actions: &[ResumeKind],
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> TargetResult<StopReason>;
+
+    /// Returns the target description read by gdb as `target.xml`, if the target has one.
+    fn target_xml(&self) -> Option<&str> {
+        None
+    }
+
+    /// Runs a `monitor` command and returns its output, or `None` if the target has no monitor
+    /// commands.
+    fn monitor(&mut self, _cmd: &str) -> Option<String> {
+        None
+    }
+}
+
+/// How a gdb session ended.
+#[derive(Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// gdb detached and the target should run on.
+    Detach,
+    /// gdb asked to kill the target.
+    Kill,
+}
+
+#[derive(Debug)]
+pub enum StubError {
+    /// Reading from or writing to gdb failed.
+    Connection(io::Error),
+    /// The target failed to resume, so its state is unknown.
+    Resume,
+}
+
+impl Display for StubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::StubError::*;
+
+        match self {
+            Connection(e) => write!(f, "failed to talk to gdb: {}", e),
+            Resume => write!(f, "failed to resume the target"),
+        }
+    }
+}
+
+impl std::error::Error for StubError {}
+
+impl From<io::Error> for StubError {
+    fn from(e: io::Error) -> Self {
+        StubError::Connection(e)
+    }
+}
+
+pub type StubResult<T> = std::result::Result<T, StubError>;
+
+/// Serves the packets of a gdb session for a `Target`.
+pub struct Stub<C: Connection> {
+    conn: C,
+    parser: PacketParser,
+    // Whether gdb turned acknowledgements off with `QStartNoAckMode`.
+    no_ack: bool,
+    // The last packet sent, for when gdb asks for it again.
+    last_packet: Vec<u8>,
+    // The thread picked with `Hg` for registers and memory.
+    general_thread: usize,
+    // The thread picked with `Hc` for `s`.
+    continue_thread: ThreadId,
+    // Why the target last stopped, or `None` if it hasn't run since gdb connected.
+    stop: Option<StopReason>,
+    // Whether gdb understands the `swbreak` and `hwbreak` stop reasons.
+    swbreak: bool,
+    hwbreak: bool,
+}
+
+impl<C: Connection> Stub<C> {
+    pub fn new(conn: C) -> Self {
+        Stub {
+            conn,
+            parser: PacketParser::new(),
+            no_ack: false,
+            last_packet: Vec::new(),
+            general_thread: 1,
+            continue_thread: ThreadId::Any,
+            stop: None,
+            swbreak: false,
+            hwbreak: false,
+        }
+    }
+
+    /// Serves gdb until it detaches or kills the target. The target is expected to be stopped.
+    pub fn run<T: Target>(&mut self, target: &mut T) -> StubResult<DisconnectReason> {
+        loop {
+            let packet = self.read_packet()?;
+            let reply = match Command::parse(&packet) {
+                Ok(Command::Detach) => {
+                    self.send(b"OK")?;
+                    return Ok(DisconnectReason::Detach);
+                }
+                // gdb doesn't wait for a reply.
+                Ok(Command::Kill) => return Ok(DisconnectReason::Kill),
+                Ok(command) => self.handle(target, command)?,
+                Err(_) => b"E01".to_vec(),
+            };
+            self.send(&reply)?;
+        }
+    }
+
+    // Reads from gdb until a command packet arrives intact.
+    fn read_packet(&mut self) -> StubResult<Vec<u8>> {
+        loop {
+            let byte = self.conn.read()?;
+            match self.parser.push(byte) {
+                Ok(Some(GdbPacket::Command(data))) => {
+                    if !self.no_ack {
+                        self.conn.write_all(&[ACK])?;
+                    }
+                    return Ok(data);
+                }
+                Ok(Some(GdbPacket::Nack)) if !self.no_ack => {
+                    self.conn.write_all(&self.last_packet)?;
+                }
+                // Acknowledgements need no answer, and the target is stopped already.
+                Ok(Some(_)) | Ok(None) => {}
+                // Without acknowledgements gdb won't send the packet again, so there's nothing to
+                // ask for.
+                Err(_) if self.no_ack => {}
+                Err(_) => self.conn.write_all(&[NACK])?,
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> StubResult<()> {
+        self.last_packet = encode_packet(data);
+        self.conn.write_all(&self.last_packet)?;
+        Ok(())
+    }
+
+    // Returns the reply to `command`.
+    fn handle<T: Target>(&mut self, target: &mut T, command: Command) -> StubResult<Vec<u8>> {
+        let tid = self.general_thread;
+        let reply = match command {
+            Command::StopReason => self.stop_reply(),
+            Command::ReadRegisters => hex_reply(target.read_registers(tid)),
+            Command::WriteRegisters(regs) => ok_reply(target.write_registers(tid, &regs)),
+            Command::ReadRegister(reg) => hex_reply(target.read_register(tid, reg)),
+            Command::WriteRegister(reg, value) => ok_reply(target.write_register(tid, reg, &value)),
+            Command::ReadMemory { addr, len } => {
+                // Each byte takes two hex digits.
+                let len = len.min(MAX_PACKET_SIZE / 2);
+                hex_reply(target.read_memory(tid, addr, len))
+            }
+            Command::WriteMemory { addr, data } => ok_reply(target.write_memory(tid, addr, &data)),
+            Command::Continue(None) => {
+                let actions = vec![ResumeKind::Continue; target.threads()];
+                self.resume(target, &actions)?
+            }
+            // Only the thread picked with `Hc` steps, or the one that stopped if gdb didn't pick
+            // one.
+            Command::Step(None) => {
+                let step = match self.continue_thread {
+                    ThreadId::Id(tid) => tid,
+                    ThreadId::Any | ThreadId::All => self.stopped_thread(),
+                };
+                let actions: Vec<_> = (1..=target.threads())
+                    .map(|tid| {
+                        if tid == step {
+                            ResumeKind::Step
+                        } else {
+                            ResumeKind::Stop
+                        }
+                    })
+                    .collect();
+                self.resume(target, &actions)?
+            }
+            // The target can't be resumed at another address.
+            Command::Continue(Some(_)) | Command::Step(Some(_)) => b"E01".to_vec(),
+            Command::InsertBreakpoint { kind, addr, size } => {
+                breakpoint_reply(target.insert_breakpoint(kind, addr, size))
+            }
+            Command::RemoveBreakpoint { kind, addr, size } => {
+                breakpoint_reply(target.remove_breakpoint(kind, addr, size))
+            }
+            Command::SetThread(op, thread) => match (op, thread) {
+                (ThreadOp::General, ThreadId::Id(tid)) if is_thread(target, tid) => {
+                    self.general_thread = tid;
+                    b"OK".to_vec()
+                }
+                (ThreadOp::General, ThreadId::Any) | (ThreadOp::General, ThreadId::All) => {
+                    self.general_thread = self.stopped_thread();
+                    b"OK".to_vec()
+                }
+                (ThreadOp::Continue, ThreadId::Id(tid)) if !is_thread(target, tid) => {
+                    b"E01".to_vec()
+                }
+                (ThreadOp::Continue, thread) => {
+                    self.continue_thread = thread;
+                    b"OK".to_vec()
+                }
+                _ => b"E01".to_vec(),
+            },
+            Command::ThreadAlive(ThreadId::Id(tid)) if is_thread(target, tid) => b"OK".to_vec(),
+            Command::ThreadAlive(_) => b"E01".to_vec(),
+            Command::Supported(features) => {
+                self.swbreak = features.iter().any(|f| f == "swbreak+");
+                self.hwbreak = features.iter().any(|f| f == "hwbreak+");
+                let mut reply = format!(
+                    "PacketSize={:x};QStartNoAckMode+;vContSupported+;swbreak+;hwbreak+",
+                    MAX_PACKET_SIZE
+                );
+                if target.target_xml().is_some() {
+                    reply.push_str(";qXfer:features:read+");
+                }
+                reply.into_bytes()
+            }
+            Command::FirstThreadInfo => {
+                let tids: Vec<_> = (1..=target.threads())
+                    .map(|tid| format!("{:x}", tid))
+                    .collect();
+                format!("m{}", tids.join(",")).into_bytes()
+            }
+            Command::NextThreadInfo => b"l".to_vec(),
+            Command::Attached => b"1".to_vec(),
+            Command::CurrentThread => format!("QC{:x}", self.stopped_thread()).into_bytes(),
+            // The packet was acknowledged already, and gdb acknowledges the reply.
+            Command::StartNoAckMode => {
+                self.no_ack = true;
+                b"OK".to_vec()
+            }
+            Command::VContSupported => b"vCont;c;C;s;S;t".to_vec(),
+            // Threads without an action stay stopped.
+            Command::VCont(actions) => {
+                let actions: Vec<_> = (1..=target.threads())
+                    .map(|tid| resume_kind(&actions, tid).unwrap_or(ResumeKind::Stop))
+                    .collect();
+                self.resume(target, &actions)?
+            }
+            Command::ReadFeatures { annex, offset, len } => match target.target_xml() {
+                Some(xml) if annex == "target.xml" => {
+                    let xml = xml.as_bytes();
+                    let start = offset.min(xml.len());
+                    let end = offset.saturating_add(len).min(xml.len());
+                    // `m` means there's more to read, `l` that this is the last part.
+                    let mut reply = vec![if end < xml.len() { b'm' } else { b'l' }];
+                    reply.extend_from_slice(&xml[start..end]);
+                    reply
+                }
+                _ => b"E00".to_vec(),
+            },
+            Command::Monitor(cmd) => match target.monitor(&String::from_utf8_lossy(&cmd)) {
+                Some(output) => {
+                    // The output goes in `O` packets before the reply.
+                    for chunk in output.as_bytes().chunks((MAX_PACKET_SIZE - 1) / 2) {
+                        let mut packet = vec![b'O'];
+                        packet.extend(encode_hex(chunk));
+                        self.send(&packet)?;
+                    }
+                    b"OK".to_vec()
+                }
+                None => Vec::new(),
+            },
+            Command::Detach | Command::Kill | Command::Unknown(_) => Vec::new(),
+        };
+        Ok(reply)
+    }
+
+    // Resumes the target and returns the stop reply for when it stops.
+    fn resume<T: Target>(&mut self, target: &mut T, actions: &[ResumeKind]) -> StubResult<Vec<u8>> {
+        let conn = &mut self.conn;
+        let parser = &mut self.parser;
+        let mut interrupted = || loop {
+            match conn.try_read() {
+                // Nothing but an interrupt is expected while the target runs.
+                Ok(Some(byte)) => {
+                    if let Ok(Some(GdbPacket::Interrupt)) = parser.push(byte) {
+                        return true;
+                    }
+                }
+                Ok(None) => return false,
+                // Stop the target, the next read from gdb fails too and ends the session.
+                Err(_) => return true,
+            }
+        };
+        let stop = target
+            .resume(actions, &mut interrupted)
+            .map_err(|_| StubError::Resume)?;
+        self.stop = Some(stop);
+        self.general_thread = stop.tid();
+        Ok(self.stop_reply())
+    }
+
+    fn stopped_thread(&self) -> usize {
+        self.stop.map_or(1, |stop| stop.tid())
+    }
+
+    fn stop_reply(&self) -> Vec<u8> {
+        let (signal, reason) = match self.stop {
+            Some(StopReason::SwBreak(_)) if self.swbreak => (SIGTRAP, "swbreak:;".to_string()),
+            Some(StopReason::HwBreak(_)) if self.hwbreak => (SIGTRAP, "hwbreak:;".to_string()),
+            Some(StopReason::Watch { kind, addr, .. }) => {
+                let name = match kind {
+                    BreakpointKind::ReadWatch => "rwatch",
+                    BreakpointKind::AccessWatch => "awatch",
+                    _ => "watch",
+                };
+                (SIGTRAP, format!("{}:{:x};", name, addr))
+            }
+            Some(StopReason::Interrupted(_)) => (SIGINT, String::new()),
+            _ => (SIGTRAP, String::new()),
+        };
+        format!(
+            "T{:02x}thread:{:x};{}",
+            signal,
+            self.stopped_thread(),
+            reason
+        )
+        .into_bytes()
+    }
+}
+
+fn is_thread<T: Target>(target: &T, tid: usize) -> bool {
+    tid >= 1 && tid <= target.threads()
+}
+
+fn ok_reply(result: TargetResult<()>) -> Vec<u8> {
+    match result {
+        Ok(()) => b"OK".to_vec(),
+        Err(_) => b"E01".to_vec(),
+    }
+}
+
+fn hex_reply(result: TargetResult<Vec<u8>>) -> Vec<u8> {
+    match result {
+        Ok(bytes) => encode_hex(&bytes),
+        Err(_) => b"E01".to_vec(),
+    }
+}
+
+// An empty reply tells gdb that the kind of breakpoint isn't supported.
+fn breakpoint_reply(result: TargetResult<bool>) -> Vec<u8> {
+    match result {
+        Ok(true) => b"OK".to_vec(),
+        Ok(false) => Vec::new(),
+        Err(_) => b"E01".to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    // Replays what gdb sent. Reads fail once it's all been read, which ends the session.
+    struct MockConnection {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Connection for MockConnection {
+        fn read(&mut self) -> io::Result<u8> {
+            self.input
+                .pop_front()
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn try_read(&mut self) -> io::Result<Option<u8>> {
+            Ok(self.input.pop_front())
+        }
+
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.output.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    // Two threads, whose register `n` holds `n` in each byte.
+    #[derive(Default)]
+    struct MockTarget {
+        resumes: Vec<Vec<ResumeKind>>,
+    }
+
+    impl Target for MockTarget {
+        fn threads(&self) -> usize {
+            2
+        }
+
+        fn read_registers(&mut self, _tid: usize) -> TargetResult<Vec<u8>> {
+            Ok(vec![0, 0, 1, 1])
+        }
+
+        fn write_registers(&mut self, _tid: usize, _regs: &[u8]) -> TargetResult<()> {
+            Ok(())
+        }
+
+        fn read_register(&mut self, _tid: usize, reg: usize) -> TargetResult<Vec<u8>> {
+            match reg {
+                0 | 1 => Ok(vec![reg as u8; 2]),
+                _ => Err(TargetError),
+            }
+        }
+
+        fn write_register(&mut self, _tid: usize, _reg: usize, _value: &[u8]) -> TargetResult<()> {
+            Ok(())
+        }
+
+        fn read_memory(&mut self, _tid: usize, _addr: u64, len: usize) -> TargetResult<Vec<u8>> {
+            Ok(vec![0; len])
+        }
+
+        fn write_memory(&mut self, _tid: usize, _addr: u64, _data: &[u8]) -> TargetResult<()> {
+            Ok(())
+        }
+
+        fn insert_breakpoint(
+            &mut self,
+            kind: BreakpointKind,
+            _addr: u64,
+            _size: usize,
+        ) -> TargetResult<bool> {
+            Ok(kind == BreakpointKind::Software)
+        }
+
+        fn remove_breakpoint(
+            &mut self,
+            kind: BreakpointKind,
+            _addr: u64,
+            _size: usize,
+        ) -> TargetResult<bool> {
+            Ok(kind == BreakpointKind::Software)
+        }
+
+        // The first thread that runs hits a breakpoint.
+        fn resume(
+            &mut self,
+            actions: &[ResumeKind],
+            _interrupted: &mut dyn FnMut() -> bool,
+        ) -> TargetResult<StopReason> {
+            self.resumes.push(actions.to_vec());
+            let tid = actions
+                .iter()
+                .position(|&a| a != ResumeKind::Stop)
+                .ok_or(TargetError)?
+                + 1;
+            Ok(StopReason::SwBreak(tid))
+        }
+
+        fn target_xml(&self) -> Option<&str> {
+            Some("<target/>")
+        }
+    }
+
+    // Runs a session on what gdb sent and returns what the stub sent back.
+    fn session(target: &mut MockTarget, input: &[u8]) -> (StubResult<DisconnectReason>, Vec<u8>) {
+        let mut stub = Stub::new(MockConnection {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        });
+        let result = stub.run(target);
+        (result, stub.conn.output)
+    }
+
+    fn packets(packets: &[&str]) -> Vec<u8> {
+        packets
+            .iter()
+            .flat_map(|p| encode_packet(p.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn acks() {
+        let mut input = packets(&["p1"]);
+        // A corrupted packet is asked for again, and a NACK from gdb gets the reply again.
+        input.extend(b"$p1#00-");
+        input.extend(packets(&["D"]));
+
+        let mut expected = b"+".to_vec();
+        expected.extend(packets(&["0101"]));
+        expected.extend(b"-");
+        expected.extend(packets(&["0101"]));
+        expected.extend(b"+");
+        expected.extend(packets(&["OK"]));
+
+        let (result, output) = session(&mut MockTarget::default(), &input);
+        assert_eq!(result.unwrap(), DisconnectReason::Detach);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn no_ack_mode() {
+        let mut input = packets(&["QStartNoAckMode"]);
+        input.extend(b"+");
+        input.extend(packets(&["p0"]));
+        // Neither corrupted packets nor NACKs are answered once acknowledgements are off.
+        input.extend(b"$p1#00-");
+        input.extend(packets(&["p5", "k"]));
+
+        let mut expected = b"+".to_vec();
+        expected.extend(packets(&["OK", "0000", "E01"]));
+
+        let (result, output) = session(&mut MockTarget::default(), &input);
+        assert_eq!(result.unwrap(), DisconnectReason::Kill);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn noise_and_long_packets() {
+        let mut input = b"noise+\x03$".to_vec();
+        input.extend(vec![b'0'; MAX_PACKET_SIZE + 1]);
+        input.extend(b"+-\x03#00");
+        input.extend(packets(&["g"]));
+
+        let mut expected = b"-+".to_vec();
+        expected.extend(packets(&["00000101"]));
+
+        let (result, output) = session(&mut MockTarget::default(), &input);
+        match result {
+            Err(StubError::Connection(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn queries() {
+        let input = packets(&[
+            "QStartNoAckMode",
+            "qSupported:swbreak+",
+            "qfThreadInfo",
+            "qsThreadInfo",
+            "qXfer:features:read:target.xml:0,4",
+            "qXfer:features:read:target.xml:4,100",
+            "qXfer:features:read:other.xml:0,100",
+            "qRcmd,68656c70",
+            "Z1,1000,1",
+            "Z0,1000,1",
+            "Hg3",
+            "T2",
+            "vMustReplyEmpty",
+            "D",
+        ]);
+        let mut expected = b"+".to_vec();
+        expected.extend(packets(&[
+            "OK",
+            "PacketSize=10000;QStartNoAckMode+;vContSupported+;swbreak+;hwbreak+;\
+             qXfer:features:read+",
+            "m1,2",
+            "l",
+            "m<tar",
+            "lget/>",
+            "E00",
+            "",
+            "",
+            "OK",
+            "E01",
+            "OK",
+            "",
+            "OK",
+        ]));
+
+        let (result, output) = session(&mut MockTarget::default(), &input);
+        assert_eq!(result.unwrap(), DisconnectReason::Detach);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn stop_replies() {
+        let input = packets(&[
+            "QStartNoAckMode",
+            "?",
+            "qSupported:swbreak+",
+            "c",
+            "?",
+            "qC",
+            "D",
+        ]);
+        let mut expected = b"+".to_vec();
+        expected.extend(packets(&[
+            "OK",
+            "T05thread:1;",
+            "PacketSize=10000;QStartNoAckMode+;vContSupported+;swbreak+;hwbreak+;\
+             qXfer:features:read+",
+            "T05thread:1;swbreak:;",
+            "T05thread:1;swbreak:;",
+            "QC1",
+            "OK",
+        ]));
+
+        let mut target = MockTarget::default();
+        let (result, output) = session(&mut target, &input);
+        assert_eq!(result.unwrap(), DisconnectReason::Detach);
+        assert_eq!(output, expected);
+        assert_eq!(
+            target.resumes,
+            vec![vec![ResumeKind::Continue, ResumeKind::Continue]]
+        );
+    }
+
+    #[test]
+    fn mixed_resume_actions() {
+        let input = packets(&[
+            "QStartNoAckMode",
+            // Step thread 1 and keep thread 2 stopped.
+            "vCont;s:1;t:2",
+            // Thread 1 is left out, so it stays stopped.
+            "vCont;c:2",
+            // Signals aren't dropped here, the target decides what to do with them.
+            "vCont;S05:2;c",
+            // `s` steps the thread picked with `Hc` alone.
+            "Hc2",
+            "s",
+            "c",
+            "D",
+        ]);
+        let mut expected = b"+".to_vec();
+        expected.extend(packets(&[
+            "OK",
+            "T05thread:1;",
+            "T05thread:2;",
+            "T05thread:1;",
+            "OK",
+            "T05thread:2;",
+            "T05thread:1;",
+            "OK",
+        ]));
+
+        let mut target = MockTarget::default();
+        let (result, output) = session(&mut target, &input);
+        assert_eq!(result.unwrap(), DisconnectReason::Detach);
+        assert_eq!(output, expected);
+        use ResumeKind::*;
+        assert_eq!(
+            target.resumes,
+            vec![
+                vec![Step, Stop],
+                vec![Stop, Continue],
+                vec![Continue, StepWithSignal(5)],
+                vec![Stop, Step],
+                vec![Continue, Continue],
+            ]
+        );
+    }
+}
@@ -4,6 +4,7 @@
 
 use base::Result;
 use downcast_rs::impl_downcast;
+use vm_memory::GuestAddress;
 
 use crate::{Hypervisor, IrqRoute, IrqSource, IrqSourceChip, Vcpu, Vm};
 
@@ -20,6 +21,14 @@ pub trait VmAArch64: Vm {
 
     /// Create a Vcpu with the specified Vcpu ID.
     fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuAArch64>>;
+
+    /// Gets the size of the firmware the hypervisor loads into protected VMs.
+    fn get_protected_vm_firmware_size(&self) -> Result<u64>;
+
+    /// Makes this a protected VM, whose memory the host can't access once the guest is running.
+    /// The hypervisor loads its firmware at guest physical address `fw_addr`, which has to be
+    /// backed by a memory region of the VM. This has to be done before any VCPU is created.
+    fn enable_protected_vm(&mut self, fw_addr: GuestAddress) -> Result<()>;
 }
 
 /// A wrapper around creating and using a VCPU on aarch64.
@@ -33,13 +42,16 @@ pub trait VcpuAArch64: Vcpu {
     /// `irq`.
     fn init_pmu(&self, irq: u64) -> Result<()>;
 
-    /// Sets the value of a register on this VCPU.  `reg_id` is the register ID, as specified in the
-    /// KVM API documentation for KVM_SET_ONE_REG.
-    fn set_one_reg(&self, reg_id: u64, data: u64) -> Result<()>;
+    /// Sets up paravirtualized stolen time (PV_TIME_ST) on this VCPU, with the structure the host
+    /// updates with the time the VCPU wasn't running at guest physical address `pvtime_ipa`. The
+    /// address has to be 64-byte aligned and backed by a memory region of the VM.
+    fn init_pvtime(&self, pvtime_ipa: u64) -> Result<()>;
+
+    /// Sets the value of the register `reg` on this VCPU.
+    fn set_one_reg(&self, reg: VcpuRegAArch64, data: u64) -> Result<()>;
 
-    /// Gets the value of a register on this VCPU.  `reg_id` is the register ID, as specified in the
-    /// KVM API documentation for KVM_GET_ONE_REG.
-    fn get_one_reg(&self, reg_id: u64) -> Result<u64>;
+    /// Gets the value of the register `reg` on this VCPU.
+    fn get_one_reg(&self, reg: VcpuRegAArch64) -> Result<u64>;
 
     /// Gets the current PSCI version.
     fn get_psci_version(&self) -> Result<PsciVersion>;
@@ -70,3 +82,17 @@ pub enum VcpuFeature {
     /// Starts the VCPU in a power-off state.
     PowerOff,
 }
+
+/// A core register of an aarch64 VCPU, for `VcpuAArch64::set_one_reg` and
+/// `VcpuAArch64::get_one_reg`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuRegAArch64 {
+    /// General purpose register `Xn`, for `n` from 0 to 30.
+    X(u8),
+    /// Stack pointer.
+    Sp,
+    /// Program counter.
+    Pc,
+    /// Processor state.
+    Pstate,
+}
@@ -5,6 +5,10 @@
 /// An enumeration of different hypervisor capabilities.
 pub enum HypervisorCap {
     ArmPmuV3,
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmPvTime,
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmProtectedVm,
     ImmediateExit,
     S390UserSigp,
     TscDeadlineTimer,
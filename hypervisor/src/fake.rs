@@ -0,0 +1,683 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A hypervisor that doesn't run guest code, for testing the code built on the hypervisor traits
+//! without access to a real hypervisor.
+//!
+//! The VM keeps track of its memory slots and IO events like a real one would, and delivers the IO
+//! events itself in `Vm::handle_io_events`. VCPUs keep their registers in memory, and `Vcpu::run`
+//! returns the exits queued with `FakeVcpu::push_exit`, then `VcpuExit::Shutdown` once there are
+//! none left.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use libc::{EFAULT, EINVAL, EIO, ENOENT, ENOSPC, ENXIO, EOVERFLOW};
+
+use base::{Error, Event, MappedRegion, MmapError, Protection, Result, SafeDescriptor};
+use sync::Mutex;
+use vm_memory::{GuestAddress, GuestMemory};
+
+use crate::{
+    ClockState, Datamatch, DeviceKind, Hypervisor, HypervisorCap, IoEventAddress, MemSlot, Vcpu,
+    VcpuExit, VcpuRunHandle, VfioGroupRegistry, Vm, VmCap,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::{
+    CpuId, DebugRegs, Fpu, HypervisorX86_64, Register, Regs, Sregs, VcpuX86_64, VmX86_64,
+    Watchpoint,
+};
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use crate::{PsciVersion, VcpuAArch64, VcpuFeature, VcpuRegAArch64, VmAArch64};
+
+/// A hypervisor without any capabilities.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FakeHypervisor;
+
+impl Hypervisor for FakeHypervisor {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn check_capability(&self, _cap: &HypervisorCap) -> bool {
+        false
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl HypervisorX86_64 for FakeHypervisor {
+    fn get_supported_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn get_emulated_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn get_msr_index_list(&self) -> Result<Vec<u32>> {
+        Ok(Vec::new())
+    }
+}
+
+// An event registered with `Vm::register_ioevent`.
+struct IoEvent {
+    evt: Event,
+    addr: IoEventAddress,
+    datamatch: Datamatch,
+}
+
+impl IoEvent {
+    // Whether a write of `data` at `addr` signals this event.
+    fn matches(&self, addr: IoEventAddress, data: &[u8]) -> bool {
+        if addr != self.addr {
+            return false;
+        }
+        match self.datamatch {
+            Datamatch::AnyLength => true,
+            Datamatch::U8(None) => data.len() == 1,
+            Datamatch::U8(Some(v)) => data == &v.to_le_bytes()[..],
+            Datamatch::U16(None) => data.len() == 2,
+            Datamatch::U16(Some(v)) => data == &v.to_le_bytes()[..],
+            Datamatch::U32(None) => data.len() == 4,
+            Datamatch::U32(Some(v)) => data == &v.to_le_bytes()[..],
+            Datamatch::U64(None) => data.len() == 8,
+            Datamatch::U64(Some(v)) => data == &v.to_le_bytes()[..],
+        }
+    }
+}
+
+// The regions added with `Vm::add_memory_region`, with where they are in the guest, by slot.
+type MemRegions = BTreeMap<MemSlot, (GuestAddress, Box<dyn MappedRegion>)>;
+
+/// A VM of the `FakeHypervisor`.
+pub struct FakeVm {
+    hypervisor: FakeHypervisor,
+    guest_mem: GuestMemory,
+    mem_regions: Arc<Mutex<MemRegions>>,
+    ioevents: Arc<Mutex<Vec<IoEvent>>>,
+}
+
+impl FakeVm {
+    /// Constructs a new `FakeVm` with `guest_mem` in its first memory slots.
+    pub fn new(hypervisor: &FakeHypervisor, guest_mem: GuestMemory) -> FakeVm {
+        FakeVm {
+            hypervisor: *hypervisor,
+            guest_mem,
+            mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
+            ioevents: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Creates a VCPU with the id `id`.
+    pub fn create_fake_vcpu(&self, id: usize) -> FakeVcpu {
+        FakeVcpu {
+            id,
+            state: Arc::new(Mutex::new(FakeVcpuState::default())),
+        }
+    }
+}
+
+impl Vm for FakeVm {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeVm {
+            hypervisor: self.hypervisor,
+            guest_mem: self.guest_mem.clone(),
+            mem_regions: self.mem_regions.clone(),
+            ioevents: self.ioevents.clone(),
+        })
+    }
+
+    fn check_capability(&self, _c: VmCap) -> bool {
+        false
+    }
+
+    fn check_raw_capability(&self, _cap: u32) -> bool {
+        false
+    }
+
+    fn get_memory(&self) -> &GuestMemory {
+        &self.guest_mem
+    }
+
+    fn add_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        _read_only: bool,
+        _log_dirty_pages: bool,
+    ) -> Result<MemSlot> {
+        let size = mem.size() as u64;
+        let end_addr = guest_addr
+            .checked_add(size)
+            .ok_or_else(|| Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        let mut regions = self.mem_regions.lock();
+        if regions.values().any(|(addr, region)| {
+            guest_addr < addr.unchecked_add(region.size() as u64) && *addr < end_addr
+        }) {
+            return Err(Error::new(ENOSPC));
+        }
+        // The lowest slot that isn't taken by guest memory or another region.
+        let slot = (self.guest_mem.num_regions() as MemSlot..)
+            .find(|slot| !regions.contains_key(slot))
+            .ok_or_else(|| Error::new(ENOSPC))?;
+        regions.insert(slot, (guest_addr, mem));
+        Ok(slot)
+    }
+
+    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let (_, mem) = regions.get_mut(&slot).ok_or_else(|| Error::new(ENOENT))?;
+
+        mem.msync(offset, size).map_err(|err| match err {
+            MmapError::InvalidAddress => Error::new(EFAULT),
+            MmapError::NotPageAligned => Error::new(EINVAL),
+            MmapError::SystemCallFailed(e) => e,
+            _ => Error::new(EIO),
+        })
+    }
+
+    fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
+        match self.mem_regions.lock().remove(&slot) {
+            Some((_, mem)) => Ok(mem),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn create_vfio_group_registry(&self) -> Result<Box<dyn VfioGroupRegistry>> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_dirty_log(&self, _slot: MemSlot, _dirty_log: &mut [u8]) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn register_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioevents.lock().push(IoEvent {
+            evt: evt.try_clone()?,
+            addr,
+            datamatch,
+        });
+        Ok(())
+    }
+
+    /// Unregisters the event registered for `addr` and `datamatch`. As events can't be told apart
+    /// from their clones, `evt` isn't checked.
+    fn unregister_ioevent(
+        &mut self,
+        _evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut ioevents = self.ioevents.lock();
+        match ioevents
+            .iter()
+            .position(|e| e.addr == addr && e.datamatch == datamatch)
+        {
+            Some(index) => {
+                ioevents.remove(index);
+                Ok(())
+            }
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    fn handle_io_events(&self, addr: IoEventAddress, data: &[u8]) -> Result<()> {
+        for ioevent in self.ioevents.lock().iter() {
+            if ioevent.matches(addr, data) {
+                ioevent.evt.write(1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_pvclock(&self) -> Result<ClockState> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_pvclock(&self, _state: &ClockState) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn add_fd_mapping(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        size: usize,
+        fd: &dyn AsRawFd,
+        fd_offset: u64,
+        prot: Protection,
+    ) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+
+        match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let (_, region) = regions.get_mut(&slot).ok_or_else(|| Error::new(EINVAL))?;
+
+        match region.remove_mapping(offset, size) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl VmX86_64 for FakeVm {
+    fn get_hypervisor(&self) -> &dyn HypervisorX86_64 {
+        &self.hypervisor
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>> {
+        Ok(Box::new(self.create_fake_vcpu(id)))
+    }
+
+    fn set_tss_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_identity_map_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+impl VmAArch64 for FakeVm {
+    fn get_hypervisor(&self) -> &dyn Hypervisor {
+        &self.hypervisor
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuAArch64>> {
+        Ok(Box::new(self.create_fake_vcpu(id)))
+    }
+
+    fn get_protected_vm_firmware_size(&self) -> Result<u64> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn enable_protected_vm(&mut self, _fw_addr: GuestAddress) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+}
+
+#[derive(Default)]
+struct FakeVcpuState {
+    exits: VecDeque<VcpuExit>,
+    immediate_exit: bool,
+    // The data last given to `Vcpu::set_data`.
+    data: Vec<u8>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    interrupt_window_requested: bool,
+    // The interrupt vectors injected with `VcpuX86_64::interrupt`, oldest first.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    interrupts: Vec<u32>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    regs: Regs,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    sregs: Sregs,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fpu: Fpu,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    debugregs: DebugRegs,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    xcrs: Vec<Register>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    msrs: BTreeMap<u32, u64>,
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    one_regs: Vec<(VcpuRegAArch64, u64)>,
+}
+
+/// A VCPU of a `FakeVm`. Clones share their registers and queued exits.
+pub struct FakeVcpu {
+    id: usize,
+    state: Arc<Mutex<FakeVcpuState>>,
+}
+
+impl FakeVcpu {
+    /// Queues `exit` to be returned by a later `Vcpu::run`.
+    pub fn push_exit(&self, exit: VcpuExit) {
+        self.state.lock().exits.push_back(exit);
+    }
+
+    /// Returns the data last given to `Vcpu::set_data`.
+    pub fn data(&self) -> Vec<u8> {
+        self.state.lock().data.clone()
+    }
+
+    /// Takes the interrupt vectors injected since the last call, oldest first.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn take_interrupts(&self) -> Vec<u32> {
+        std::mem::take(&mut self.state.lock().interrupts)
+    }
+}
+
+extern "C" fn set_local_immediate_exit_noop() {}
+
+impl Vcpu for FakeVcpu {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeVcpu {
+            id: self.id,
+            state: self.state.clone(),
+        })
+    }
+
+    fn as_vcpu(&self) -> &dyn Vcpu {
+        self
+    }
+
+    fn take_run_handle(&self, _signal_num: Option<c_int>) -> Result<VcpuRunHandle> {
+        Ok(VcpuRunHandle::new(|| {}))
+    }
+
+    fn run(&self, _run_handle: &VcpuRunHandle) -> Result<VcpuExit> {
+        let mut state = self.state.lock();
+        if state.immediate_exit {
+            return Ok(VcpuExit::Intr);
+        }
+        Ok(state.exits.pop_front().unwrap_or(VcpuExit::Shutdown))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_immediate_exit(&self, exit: bool) {
+        self.state.lock().immediate_exit = exit;
+    }
+
+    // Fake VCPUs aren't bound to the thread that runs them, so there is nothing to set.
+    fn set_local_immediate_exit(_exit: bool) {}
+
+    fn set_local_immediate_exit_fn(&self) -> extern "C" fn() {
+        set_local_immediate_exit_noop
+    }
+
+    fn set_data(&self, data: &[u8]) -> Result<()> {
+        self.state.lock().data = data.to_vec();
+        Ok(())
+    }
+
+    fn pvclock_ctrl(&self) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_signal_mask(&self, _signals: &[c_int]) -> Result<()> {
+        Ok(())
+    }
+
+    fn enable_raw_capability(&self, _cap: u32, _args: &[u64; 4]) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl VcpuX86_64 for FakeVcpu {
+    fn set_interrupt_window_requested(&self, requested: bool) {
+        self.state.lock().interrupt_window_requested = requested;
+    }
+
+    fn ready_for_interrupt(&self) -> bool {
+        true
+    }
+
+    fn interrupt(&self, irq: u32) -> Result<()> {
+        self.state.lock().interrupts.push(irq);
+        Ok(())
+    }
+
+    fn inject_nmi(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_regs(&self) -> Result<Regs> {
+        Ok(self.state.lock().regs)
+    }
+
+    fn set_regs(&self, regs: &Regs) -> Result<()> {
+        self.state.lock().regs = *regs;
+        Ok(())
+    }
+
+    fn get_sregs(&self) -> Result<Sregs> {
+        Ok(self.state.lock().sregs)
+    }
+
+    fn set_sregs(&self, sregs: &Sregs) -> Result<()> {
+        self.state.lock().sregs = *sregs;
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<Fpu> {
+        Ok(self.state.lock().fpu)
+    }
+
+    fn set_fpu(&self, fpu: &Fpu) -> Result<()> {
+        self.state.lock().fpu = *fpu;
+        Ok(())
+    }
+
+    fn get_debugregs(&self) -> Result<DebugRegs> {
+        Ok(self.state.lock().debugregs)
+    }
+
+    fn set_debugregs(&self, debugregs: &DebugRegs) -> Result<()> {
+        self.state.lock().debugregs = *debugregs;
+        Ok(())
+    }
+
+    fn get_xcrs(&self) -> Result<Vec<Register>> {
+        Ok(self.state.lock().xcrs.clone())
+    }
+
+    fn set_xcrs(&self, xcrs: &[Register]) -> Result<()> {
+        self.state.lock().xcrs = xcrs.to_vec();
+        Ok(())
+    }
+
+    /// MSRs that were never set read as 0.
+    fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()> {
+        let state = self.state.lock();
+        for msr in msrs.iter_mut() {
+            msr.value = state.msrs.get(&msr.id).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn set_msrs(&self, msrs: &[Register]) -> Result<()> {
+        let mut state = self.state.lock();
+        for msr in msrs {
+            state.msrs.insert(msr.id, msr.value);
+        }
+        Ok(())
+    }
+
+    fn set_cpuid(&self, _cpuid: &CpuId) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_hyperv_cpuid(&self) -> Result<CpuId> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_guest_debug(
+        &self,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[Watchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
+        if breakpoints.len() + watchpoints.len() > 4 {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+impl VcpuAArch64 for FakeVcpu {
+    fn init(&self, _features: &[VcpuFeature]) -> Result<()> {
+        Ok(())
+    }
+
+    fn init_pmu(&self, _irq: u64) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn init_pvtime(&self, _pvtime_ipa: u64) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_one_reg(&self, reg: VcpuRegAArch64, data: u64) -> Result<()> {
+        let mut state = self.state.lock();
+        state.one_regs.retain(|(r, _)| *r != reg);
+        state.one_regs.push((reg, data));
+        Ok(())
+    }
+
+    /// Registers that were never set read as 0.
+    fn get_one_reg(&self, reg: VcpuRegAArch64) -> Result<u64> {
+        let state = self.state.lock();
+        Ok(state
+            .one_regs
+            .iter()
+            .find(|(r, _)| *r == reg)
+            .map_or(0, |(_, data)| *data))
+    }
+
+    fn get_psci_version(&self) -> Result<PsciVersion> {
+        Ok(PsciVersion { major: 0, minor: 2 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base::MemoryMappingBuilder;
+
+    fn new_vm() -> FakeVm {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        FakeVm::new(&FakeHypervisor, guest_mem)
+    }
+
+    #[test]
+    fn memory_slots() {
+        let mut vm = new_vm();
+        let mem_size = 0x1000;
+        let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+        vm.add_memory_region(GuestAddress(0x8000), Box::new(mem), false, false)
+            .expect_err("region overlaps guest memory");
+
+        let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+        let slot = vm
+            .add_memory_region(GuestAddress(0x10000), Box::new(mem), false, false)
+            .unwrap();
+        assert_eq!(slot, 1);
+
+        let mem = MemoryMappingBuilder::new(mem_size).build().unwrap();
+        vm.add_memory_region(GuestAddress(0x10800), Box::new(mem), false, false)
+            .expect_err("region overlaps the one in slot 1");
+
+        let mem = vm.remove_memory_region(slot).unwrap();
+        assert_eq!(mem.size(), mem_size);
+        assert!(vm.remove_memory_region(slot).is_err());
+    }
+
+    #[test]
+    fn io_events() {
+        let mut vm = new_vm();
+        let any = Event::new().unwrap();
+        let matched = Event::new().unwrap();
+        vm.register_ioevent(&any, IoEventAddress::Pio(0xf4), Datamatch::AnyLength)
+            .unwrap();
+        vm.register_ioevent(
+            &matched,
+            IoEventAddress::Mmio(0x1000),
+            Datamatch::U32(Some(0x1234)),
+        )
+        .unwrap();
+
+        vm.handle_io_events(IoEventAddress::Pio(0xf4), &[1])
+            .unwrap();
+        assert_eq!(any.read().unwrap(), 1);
+
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0x4321u32.to_le_bytes())
+            .unwrap();
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0x1234u16.to_le_bytes())
+            .unwrap();
+        vm.handle_io_events(IoEventAddress::Mmio(0x1000), &0x1234u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(matched.read().unwrap(), 1);
+
+        vm.unregister_ioevent(&any, IoEventAddress::Pio(0xf4), Datamatch::AnyLength)
+            .unwrap();
+        vm.unregister_ioevent(&any, IoEventAddress::Pio(0xf4), Datamatch::AnyLength)
+            .expect_err("event was already unregistered");
+    }
+
+    #[test]
+    fn vcpu_exits() {
+        let vm = new_vm();
+        let vcpu = vm.create_fake_vcpu(0);
+        let run_handle = vcpu.take_run_handle(None).unwrap();
+        vcpu.push_exit(VcpuExit::IoIn {
+            port: 0x60,
+            size: 1,
+        });
+        match vcpu.run(&run_handle).unwrap() {
+            VcpuExit::IoIn {
+                port: 0x60,
+                size: 1,
+            } => {}
+            exit => panic!("unexpected exit {:?}", exit),
+        }
+        vcpu.set_data(&[0xaa]).unwrap();
+        assert_eq!(vcpu.data(), vec![0xaa]);
+
+        vcpu.set_immediate_exit(true);
+        assert!(matches!(vcpu.run(&run_handle).unwrap(), VcpuExit::Intr));
+        vcpu.set_immediate_exit(false);
+        assert!(matches!(vcpu.run(&run_handle).unwrap(), VcpuExit::Shutdown));
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn vcpu_registers() {
+        let vm = new_vm();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0x1000;
+        vcpu.set_regs(&regs).unwrap();
+        assert_eq!(vcpu.get_regs().unwrap().rip, 0x1000);
+
+        vcpu.set_msrs(&[Register { id: 0x10, value: 5 }]).unwrap();
+        let mut msrs = vec![
+            Register { id: 0x10, value: 0 },
+            Register { id: 0x11, value: 7 },
+        ];
+        vcpu.get_msrs(&mut msrs).unwrap();
+        assert_eq!(msrs[0].value, 5);
+        assert_eq!(msrs[1].value, 0);
+    }
+}
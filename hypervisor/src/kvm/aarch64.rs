@@ -2,15 +2,20 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use libc::ENXIO;
+use std::mem::size_of;
 
-use base::{errno_result, error, ioctl_with_mut_ref, ioctl_with_ref, Error, Result};
+use libc::{EINVAL, ENXIO};
+
+use base::{
+    errno_result, error, ioctl_with_mut_ref, ioctl_with_ref, Error, Result, SafeDescriptor,
+};
 use kvm_sys::*;
+use vm_memory::GuestAddress;
 
 use super::{KvmVcpu, KvmVm};
 use crate::{
     ClockState, DeviceKind, Hypervisor, IrqSourceChip, PsciVersion, VcpuAArch64, VcpuFeature,
-    VmAArch64, VmCap,
+    VcpuRegAArch64, Vm, VmAArch64, VmCap,
 };
 
 impl KvmVm {
@@ -34,10 +39,37 @@ impl KvmVm {
                 fd: 0,
                 flags: 0,
             }),
+            DeviceKind::ArmVgicIts => Some(kvm_create_device {
+                type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+                fd: 0,
+                flags: 0,
+            }),
             _ => None,
         }
     }
 
+    /// Enables KVM_CAP_ARM_PROTECTED_VM with `flags` selecting the operation and `arg` as its
+    /// argument.
+    ///
+    /// # Safety
+    ///
+    /// For the INFO operation, `arg` has to be the address of a `kvm_protected_vm_info` the kernel
+    /// can write to.
+    unsafe fn enable_protected_vm_cap(&self, flags: u32, arg: u64) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_ARM_PROTECTED_VM,
+            flags,
+            args: [arg, 0, 0, 0],
+            ..Default::default()
+        };
+        let ret = ioctl_with_ref(self, KVM_ENABLE_CAP(), &cap);
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     /// Arch-specific implementation of `Vm::get_pvclock`.  Always returns an error on AArch64.
     pub fn get_pvclock_arch(&self) -> Result<ClockState> {
         Err(Error::new(ENXIO))
@@ -47,6 +79,114 @@ impl KvmVm {
     pub fn set_pvclock_arch(&self, _state: &ClockState) -> Result<()> {
         Err(Error::new(ENXIO))
     }
+
+    /// Creates a VGIC v3 emulated by the kernel, or a v2 if it can't emulate a v3, handling
+    /// `nr_irqs` interrupts with its registers at `addrs`. A v3 comes with an ITS when the kernel
+    /// can emulate one.
+    pub fn create_vgic(&self, addrs: &VgicAddresses, nr_irqs: u32) -> Result<KvmVgic> {
+        let (vgic, kind) = match self.create_device(DeviceKind::ArmVgicV3) {
+            Ok(vgic) => (vgic, DeviceKind::ArmVgicV3),
+            Err(_) => (
+                self.create_device(DeviceKind::ArmVgicV2)?,
+                DeviceKind::ArmVgicV2,
+            ),
+        };
+
+        if kind == DeviceKind::ArmVgicV3 {
+            set_vgic_addr(&vgic, KVM_VGIC_V3_ADDR_TYPE_REDIST, addrs.redist)?;
+            set_vgic_addr(&vgic, KVM_VGIC_V3_ADDR_TYPE_DIST, addrs.dist)?;
+        } else {
+            set_vgic_addr(&vgic, KVM_VGIC_V2_ADDR_TYPE_CPU, addrs.cpu_if)?;
+            set_vgic_addr(&vgic, KVM_VGIC_V2_ADDR_TYPE_DIST, addrs.dist)?;
+        }
+
+        // Kernels that can't emulate an ITS leave PCI devices without MSIs.
+        let its = match kind {
+            DeviceKind::ArmVgicV3 => self.create_device(DeviceKind::ArmVgicIts).ok(),
+            _ => None,
+        };
+        if let Some(its) = &its {
+            set_vgic_addr(its, KVM_VGIC_ITS_ADDR_TYPE, addrs.its)?;
+        }
+
+        // We need to tell the kernel how many irqs to support with this vgic
+        set_device_attr(
+            &vgic,
+            KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            0,
+            &nr_irqs as *const u32 as u64,
+        )?;
+
+        // Finalize the GIC, and then the ITS, which is initialized the same way once the GIC it
+        // sends LPIs to is.
+        set_device_attr(
+            &vgic,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            KVM_DEV_ARM_VGIC_CTRL_INIT as u64,
+            0,
+        )?;
+        if let Some(its) = &its {
+            set_device_attr(
+                its,
+                KVM_DEV_ARM_VGIC_GRP_CTRL,
+                KVM_DEV_ARM_VGIC_CTRL_INIT as u64,
+                0,
+            )?;
+        }
+
+        Ok(KvmVgic { vgic, kind, its })
+    }
+}
+
+/// Where the registers of a VGIC and its ITS are placed in the guest physical address space.
+pub struct VgicAddresses {
+    /// The distributor.
+    pub dist: u64,
+    /// The CPU interface of a VGIC v2.
+    pub cpu_if: u64,
+    /// The redistributors of a VGIC v3, one per VCPU.
+    pub redist: u64,
+    /// The ITS of a VGIC v3.
+    pub its: u64,
+}
+
+/// A VGIC emulated by KVM, as created by `KvmVm::create_vgic`.
+pub struct KvmVgic {
+    /// The KVM device of the VGIC.
+    pub vgic: SafeDescriptor,
+    /// Whether the VGIC is a v2 or v3.
+    pub kind: DeviceKind,
+    /// The KVM device of the ITS translating MSIs into LPIs, if the kernel emulates one.
+    pub its: Option<SafeDescriptor>,
+}
+
+// Sets the attribute `attr` of `group` on the KVM device `device`. `addr` is the address of the
+// value to set it to, or 0 for attributes that don't take one.
+fn set_device_attr(device: &SafeDescriptor, group: u32, attr: u64, addr: u64) -> Result<()> {
+    let device_attr = kvm_device_attr {
+        group,
+        attr,
+        addr,
+        flags: 0,
+    };
+    // Safe because we allocated the struct that's being passed in, and the kernel only reads the
+    // value at `addr`, which the caller keeps alive.
+    let ret = unsafe { ioctl_with_ref(device, KVM_SET_DEVICE_ATTR(), &device_attr) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        errno_result()
+    }
+}
+
+// Places the registers of type `addr_type` of a VGIC or ITS at the guest physical address `addr`.
+fn set_vgic_addr(device: &SafeDescriptor, addr_type: u32, addr: u64) -> Result<()> {
+    set_device_attr(
+        device,
+        KVM_DEV_ARM_VGIC_GRP_ADDR,
+        addr_type as u64,
+        &addr as *const u64 as u64,
+    )
 }
 
 impl VmAArch64 for KvmVm {
@@ -59,6 +199,25 @@ impl VmAArch64 for KvmVm {
         // or VcpuX86.  But both use the same implementation in KvmVm::create_vcpu.
         Ok(Box::new(KvmVm::create_vcpu(self, id)?))
     }
+
+    fn get_protected_vm_firmware_size(&self) -> Result<u64> {
+        let mut info = kvm_protected_vm_info::default();
+        // Safe because the kernel writes no more than a `kvm_protected_vm_info` to `info`.
+        unsafe {
+            self.enable_protected_vm_cap(
+                KVM_CAP_ARM_PROTECTED_VM_FLAGS_INFO,
+                &mut info as *mut kvm_protected_vm_info as u64,
+            )
+        }?;
+        Ok(info.firmware_size)
+    }
+
+    fn enable_protected_vm(&mut self, fw_addr: GuestAddress) -> Result<()> {
+        // Safe because the argument isn't a pointer.
+        unsafe {
+            self.enable_protected_vm_cap(KVM_CAP_ARM_PROTECTED_VM_FLAGS_SET_FW_IPA, fw_addr.0)
+        }
+    }
 }
 
 impl KvmVcpu {
@@ -66,6 +225,57 @@ impl KvmVcpu {
     pub fn pvclock_ctrl_arch(&self) -> Result<()> {
         Err(Error::new(ENXIO))
     }
+
+    /// Sets the value of the register with the KVM register ID `reg_id`, as specified in the KVM
+    /// API documentation for KVM_SET_ONE_REG.
+    fn set_one_kvm_reg(&self, reg_id: u64, data: u64) -> Result<()> {
+        let data_ref = &data as *const u64;
+        let onereg = kvm_one_reg {
+            id: reg_id,
+            addr: data_ref as u64,
+        };
+        // Safe because we allocated the struct and we know the kernel will read exactly the size of
+        // the struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_ONE_REG(), &onereg) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Gets the value of the register with the KVM register ID `reg_id`, as specified in the KVM
+    /// API documentation for KVM_GET_ONE_REG.
+    fn get_one_kvm_reg(&self, reg_id: u64) -> Result<u64> {
+        let val: u64 = 0;
+        let mut onereg = kvm_one_reg {
+            id: reg_id,
+            addr: (&val as *const u64) as u64,
+        };
+
+        // Safe because we allocated the struct and we know the kernel will read exactly the size of
+        // the struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_ONE_REG(), &mut onereg) };
+        if ret == 0 {
+            Ok(val)
+        } else {
+            errno_result()
+        }
+    }
+}
+
+// Translates a core register to its KVM register ID, which is derived from the register's offset in
+// `user_pt_regs`: x0 to x30 followed by sp, pc and pstate, each 64 bits.
+fn kvm_reg_id(reg: VcpuRegAArch64) -> Result<u64> {
+    let index = match reg {
+        VcpuRegAArch64::X(n) if n < 31 => n as usize,
+        VcpuRegAArch64::X(_) => return Err(Error::new(EINVAL)),
+        VcpuRegAArch64::Sp => 31,
+        VcpuRegAArch64::Pc => 32,
+        VcpuRegAArch64::Pstate => 33,
+    };
+    let offset = index * size_of::<u64>();
+    Ok(KVM_REG_ARM64 | KVM_REG_SIZE_U64 | KVM_REG_ARM_CORE as u64 | (offset / 4) as u64)
 }
 
 impl VcpuAArch64 for KvmVcpu {
@@ -142,37 +352,37 @@ impl VcpuAArch64 for KvmVcpu {
         Ok(())
     }
 
-    fn set_one_reg(&self, reg_id: u64, data: u64) -> Result<()> {
-        let data_ref = &data as *const u64;
-        let onereg = kvm_one_reg {
-            id: reg_id,
-            addr: data_ref as u64,
+    fn init_pvtime(&self, pvtime_ipa: u64) -> Result<()> {
+        let pvtime_attr = kvm_device_attr {
+            group: KVM_ARM_VCPU_PVTIME_CTRL,
+            attr: KVM_ARM_VCPU_PVTIME_IPA as u64,
+            addr: &pvtime_ipa as *const u64 as u64,
+            flags: 0,
         };
+
         // Safe because we allocated the struct and we know the kernel will read exactly the size of
         // the struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_ONE_REG(), &onereg) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
+        let ret = unsafe { ioctl_with_ref(self, kvm_sys::KVM_HAS_DEVICE_ATTR(), &pvtime_attr) };
+        if ret < 0 {
+            return errno_result();
         }
-    }
-
-    fn get_one_reg(&self, reg_id: u64) -> Result<u64> {
-        let val: u64 = 0;
-        let mut onereg = kvm_one_reg {
-            id: reg_id,
-            addr: (&val as *const u64) as u64,
-        };
 
         // Safe because we allocated the struct and we know the kernel will read exactly the size of
-        // the struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_GET_ONE_REG(), &mut onereg) };
-        if ret == 0 {
-            Ok(val)
-        } else {
+        // the struct, and the address it points to.
+        let ret = unsafe { ioctl_with_ref(self, kvm_sys::KVM_SET_DEVICE_ATTR(), &pvtime_attr) };
+        if ret < 0 {
             return errno_result();
         }
+
+        Ok(())
+    }
+
+    fn set_one_reg(&self, reg: VcpuRegAArch64, data: u64) -> Result<()> {
+        self.set_one_kvm_reg(kvm_reg_id(reg)?, data)
+    }
+
+    fn get_one_reg(&self, reg: VcpuRegAArch64) -> Result<u64> {
+        self.get_one_kvm_reg(kvm_reg_id(reg)?)
     }
 
     fn get_psci_version(&self) -> Result<PsciVersion> {
@@ -180,7 +390,7 @@ impl VcpuAArch64 for KvmVcpu {
         const KVM_REG_ARM_PSCI_VERSION: u64 =
             KVM_REG_ARM64 | (KVM_REG_SIZE_U64 as u64) | (KVM_REG_ARM_FW as u64);
 
-        match self.get_one_reg(KVM_REG_ARM_PSCI_VERSION) {
+        match self.get_one_kvm_reg(KVM_REG_ARM_PSCI_VERSION) {
             Ok(v) => {
                 let major = (v >> PSCI_VERSION_MAJOR_SHIFT) as u32;
                 let minor = (v as u32) & PSCI_VERSION_MINOR_MASK;
@@ -237,6 +447,7 @@ mod tests {
             source: IrqSource::Msi {
                 address: 0xf000000,
                 data: 0xa0,
+                devid: None,
             },
         }])
         .unwrap();
@@ -253,6 +464,7 @@ mod tests {
                 source: IrqSource::Msi {
                     address: 0xf000000,
                     data: 0xa0,
+                    devid: None,
                 },
             },
         ])
@@ -6,6 +6,8 @@
 mod aarch64;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use aarch64::*;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub use aarch64::{KvmVgic, VgicAddresses};
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod x86_64;
@@ -40,7 +42,7 @@ use vm_memory::{GuestAddress, GuestMemory};
 
 use crate::{
     ClockState, Datamatch, DeviceKind, Hypervisor, HypervisorCap, IoEventAddress, IrqRoute,
-    IrqSource, MPState, MemSlot, Vcpu, VcpuExit, VcpuRunHandle, Vm, VmCap,
+    IrqSource, MPState, MemSlot, Vcpu, VcpuExit, VcpuRunHandle, VfioGroupRegistry, Vm, VmCap,
 };
 
 // Wrapper around KVM_SET_USER_MEMORY_REGION ioctl, which creates, modifies, or deletes a mapping
@@ -172,7 +174,7 @@ impl KvmVm {
                 set_user_memory_region(
                     &vm_descriptor,
                     index as MemSlot,
-                    false,
+                    guest_mem.is_read_only(guest_addr),
                     false,
                     guest_addr.offset(),
                     size as u64,
@@ -508,6 +510,12 @@ impl Vm for KvmVm {
         }
     }
 
+    fn create_vfio_group_registry(&self) -> Result<Box<dyn VfioGroupRegistry>> {
+        Ok(Box::new(KvmVfioGroupRegistry {
+            device: self.create_device(DeviceKind::Vfio)?,
+        }))
+    }
+
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
         let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
@@ -925,7 +933,15 @@ impl Vcpu for KvmVcpu {
             KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
             KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
             KVM_EXIT_HYPERCALL => Ok(VcpuExit::Hypercall),
-            KVM_EXIT_DEBUG => Ok(VcpuExit::Debug),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            KVM_EXIT_DEBUG => {
+                // Safe because the exit_reason (which comes from the kernel) told us which
+                // union field to use.
+                let dr6 = unsafe { run.__bindgen_anon_1.debug.arch.dr6 };
+                Ok(VcpuExit::Debug { dr6 })
+            }
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            KVM_EXIT_DEBUG => Ok(VcpuExit::Debug {}),
             KVM_EXIT_HLT => Ok(VcpuExit::Hlt),
             KVM_EXIT_IRQ_WINDOW_OPEN => Ok(VcpuExit::IrqWindowOpen),
             KVM_EXIT_SHUTDOWN => Ok(VcpuExit::Shutdown),
@@ -1011,12 +1027,52 @@ impl AsRawDescriptor for KvmVcpu {
     }
 }
 
+/// Adds and removes VFIO groups through a KVM VFIO pseudo device.
+struct KvmVfioGroupRegistry {
+    device: SafeDescriptor,
+}
+
+impl KvmVfioGroupRegistry {
+    fn set_group(&self, group: &dyn AsRawDescriptor, attr: u32) -> Result<()> {
+        let group_descriptor = group.as_raw_descriptor();
+        let vfio_dev_attr = kvm_device_attr {
+            flags: 0,
+            group: KVM_DEV_VFIO_GROUP,
+            attr: attr as u64,
+            addr: &group_descriptor as *const RawDescriptor as u64,
+        };
+
+        // Safe because we know that our file is a KVM VFIO device fd, we know the kernel will only
+        // read the attribute and the group descriptor it points to, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(&self.device, KVM_SET_DEVICE_ATTR(), &vfio_dev_attr) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+}
+
+impl VfioGroupRegistry for KvmVfioGroupRegistry {
+    fn add_group(&self, group: &dyn AsRawDescriptor) -> Result<()> {
+        self.set_group(group, KVM_DEV_VFIO_GROUP_ADD)
+    }
+
+    fn remove_group(&self, group: &dyn AsRawDescriptor) -> Result<()> {
+        self.set_group(group, KVM_DEV_VFIO_GROUP_DEL)
+    }
+}
+
 impl<'a> TryFrom<&'a HypervisorCap> for KvmCap {
     type Error = Error;
 
     fn try_from(cap: &'a HypervisorCap) -> Result<KvmCap> {
         match cap {
             HypervisorCap::ArmPmuV3 => Ok(KvmCap::ArmPmuV3),
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            HypervisorCap::ArmPvTime => Ok(KvmCap::ArmPvTime),
+            #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+            HypervisorCap::ArmProtectedVm => Ok(KvmCap::ArmProtectedVm),
             HypervisorCap::ImmediateExit => Ok(KvmCap::ImmediateExit),
             HypervisorCap::S390UserSigp => Ok(KvmCap::S390UserSigp),
             HypervisorCap::TscDeadlineTimer => Ok(KvmCap::TscDeadlineTimer),
@@ -1041,15 +1097,26 @@ impl From<&IrqRoute> for kvm_irq_routing_entry {
                 },
                 ..Default::default()
             },
-            IrqSource::Msi { address, data } => kvm_irq_routing_entry {
+            IrqSource::Msi {
+                address,
+                data,
+                devid,
+            } => kvm_irq_routing_entry {
                 gsi: item.gsi,
                 type_: KVM_IRQ_ROUTING_MSI,
+                flags: if devid.is_some() {
+                    KVM_MSI_VALID_DEVID
+                } else {
+                    0
+                },
                 u: kvm_irq_routing_entry__bindgen_ty_1 {
                     msi: kvm_irq_routing_msi {
                         address_lo: *address as u32,
                         address_hi: (*address >> 32) as u32,
                         data: *data,
-                        ..Default::default()
+                        __bindgen_anon_1: kvm_irq_routing_msi__bindgen_ty_1 {
+                            devid: devid.unwrap_or(0),
+                        },
                     },
                 },
                 ..Default::default()
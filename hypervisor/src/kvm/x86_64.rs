@@ -20,7 +20,7 @@ use crate::{
     ClockState, CpuId, CpuIdEntry, DebugRegs, DescriptorTable, DeviceKind, Fpu, HypervisorX86_64,
     IoapicRedirectionTableEntry, IoapicState, IrqSourceChip, LapicState, PicSelect, PicState,
     PitChannelState, PitState, Register, Regs, Segment, Sregs, VcpuX86_64, VmCap, VmX86_64,
-    NUM_IOAPIC_PINS,
+    Watchpoint, WatchpointKind, NUM_IOAPIC_PINS,
 };
 
 type KvmCpuId = kvm::CpuId;
@@ -575,19 +575,25 @@ impl VcpuX86_64 for KvmVcpu {
         get_cpuid_with_initial_capacity(self, KVM_GET_SUPPORTED_HV_CPUID(), KVM_MAX_ENTRIES)
     }
 
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[Watchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
         use kvm_sys::*;
         let mut dbg: kvm_guest_debug = Default::default();
 
-        if addrs.len() > 4 {
+        if breakpoints.len() + watchpoints.len() > 4 {
             error!(
-                "Support 4 breakpoints at most but {} addresses are passed",
-                addrs.len()
+                "Support 4 breakpoints and watchpoints at most but {} are passed",
+                breakpoints.len() + watchpoints.len()
             );
             return Err(base::Error::new(libc::EINVAL));
         }
 
-        dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+        // int3 instructions patched in by the debugger exit to us instead of reaching the guest.
+        dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP | KVM_GUESTDBG_USE_SW_BP;
         if enable_singlestep {
             dbg.control |= KVM_GUESTDBG_SINGLESTEP;
         }
@@ -597,12 +603,33 @@ impl VcpuX86_64 for KvmVcpu {
         // bit 10: always 1.
         dbg.arch.debugreg[7] = 0x0600;
 
-        for (i, addr) in addrs.iter().enumerate() {
+        for (i, addr) in breakpoints.iter().enumerate() {
             dbg.arch.debugreg[i] = addr.0;
             // Set global breakpoint enable flag
             dbg.arch.debugreg[7] |= 2 << (i * 2);
         }
 
+        for (i, w) in watchpoints.iter().enumerate() {
+            let i = breakpoints.len() + i;
+            // Encodings of the R/W and LEN fields of DR7.
+            let rw = match w.kind {
+                WatchpointKind::Write => 0b01,
+                WatchpointKind::ReadWrite => 0b11,
+            };
+            let len = match w.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                4 => 0b11,
+                _ => return Err(base::Error::new(libc::EINVAL)),
+            };
+            if w.addr.0 % w.len != 0 {
+                return Err(base::Error::new(libc::EINVAL));
+            }
+            dbg.arch.debugreg[i] = w.addr.0;
+            dbg.arch.debugreg[7] |= 2 << (i * 2) | (rw | len << 2) << (16 + i * 4);
+        }
+
         let ret = unsafe {
             // Here we trust the kernel not to read past the end of the kvm_guest_debug struct.
             ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg)
@@ -1431,6 +1458,7 @@ mod tests {
             source: IrqSource::Msi {
                 address: 0xf000000,
                 data: 0xa0,
+                devid: None,
             },
         }])
         .unwrap();
@@ -1447,6 +1475,7 @@ mod tests {
                 source: IrqSource::Msi {
                     address: 0xf000000,
                     data: 0xa0,
+                    devid: None,
                 },
             },
         ])
@@ -1511,6 +1540,30 @@ mod tests {
         assert_eq!(dregs.dr7, dregs2.dr7);
     }
 
+    #[test]
+    fn set_guest_debug() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = KvmVm::new(&kvm, gm).unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let watchpoint = Watchpoint {
+            addr: GuestAddress(0x1000),
+            len: 4,
+            kind: WatchpointKind::Write,
+        };
+        vcpu.set_guest_debug(&[GuestAddress(0x2000)], &[watchpoint], false)
+            .unwrap();
+        // Breakpoints and watchpoints share the 4 debug registers.
+        vcpu.set_guest_debug(&[GuestAddress(0x2000); 4], &[watchpoint], false)
+            .unwrap_err();
+        // Watched ranges must be naturally aligned.
+        let misaligned = Watchpoint {
+            addr: GuestAddress(0x1002),
+            ..watchpoint
+        };
+        vcpu.set_guest_debug(&[], &[misaligned], false).unwrap_err();
+    }
+
     #[test]
     fn xcrs() {
         let kvm = Kvm::new().unwrap();
@@ -6,6 +6,7 @@
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 pub mod aarch64;
 pub mod caps;
+pub mod fake;
 pub mod kvm;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub mod x86_64;
@@ -13,7 +14,9 @@ pub mod x86_64;
 use std::os::raw::c_int;
 use std::os::unix::io::AsRawFd;
 
-use base::{Event, MappedRegion, Protection, RawDescriptor, Result, SafeDescriptor};
+use base::{
+    AsRawDescriptor, Event, MappedRegion, Protection, RawDescriptor, Result, SafeDescriptor,
+};
 use msg_socket::MsgOnSocket;
 use vm_memory::{GuestAddress, GuestMemory};
 
@@ -93,6 +96,10 @@ pub trait Vm: Send {
     /// Creates an emulated device.
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor>;
 
+    /// Creates the object through which VFIO groups are made known to the hypervisor, so that the
+    /// devices in them can be used by the VM.
+    fn create_vfio_group_registry(&self) -> Result<Box<dyn VfioGroupRegistry>>;
+
     /// Gets the bitmap of dirty pages since the last call to `get_dirty_log` for the memory at
     /// `slot`.  Only works on VMs that support `VmCap::DirtyLog`.
     ///
@@ -290,7 +297,7 @@ pub enum IoEventAddress {
 }
 
 /// Used in `Vm::register_ioevent` to indicate a size and optionally value to match.
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Datamatch {
     AnyLength,
     U8(Option<u8>),
@@ -346,7 +353,12 @@ pub enum VcpuExit {
     Unknown,
     Exception,
     Hypercall,
-    Debug,
+    Debug {
+        /// The x86 debug status, saying which breakpoint, watchpoint or single step caused the
+        /// exit.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        dr6: u64,
+    },
     Hlt,
     IrqWindowOpen,
     Shutdown,
@@ -374,6 +386,15 @@ pub enum VcpuExit {
     SystemEvent(u32 /* event_type */, u64 /* flags */),
 }
 
+/// Tells the hypervisor which VFIO groups the devices passed through to a VM belong to.
+pub trait VfioGroupRegistry: Send {
+    /// Adds the VFIO group open at `group` to the VM.
+    fn add_group(&self, group: &dyn AsRawDescriptor) -> Result<()>;
+
+    /// Removes the VFIO group open at `group` from the VM.
+    fn remove_group(&self, group: &dyn AsRawDescriptor) -> Result<()>;
+}
+
 /// A device type to create with `Vm.create_device`.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DeviceKind {
@@ -385,6 +406,9 @@ pub enum DeviceKind {
     /// ARM virtual general interrupt controller v3
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     ArmVgicV3,
+    /// ARM interrupt translation service, which turns MSIs into LPIs of a VGIC v3
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmVgicIts,
 }
 
 /// The source chip of an `IrqSource`
@@ -401,8 +425,17 @@ pub enum IrqSourceChip {
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IrqSource {
-    Irqchip { chip: IrqSourceChip, pin: u32 },
-    Msi { address: u64, data: u32 },
+    Irqchip {
+        chip: IrqSourceChip,
+        pin: u32,
+    },
+    /// An MSI, and the ID of the device that sends it if the interrupt controller needs one to
+    /// translate it, like the ARM ITS does.
+    Msi {
+        address: u64,
+        data: u32,
+        devid: Option<u32>,
+    },
 }
 
 /// A single route for an IRQ.
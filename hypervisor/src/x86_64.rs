@@ -95,12 +95,36 @@ pub trait VcpuX86_64: Vcpu {
     /// Gets the system emulated hyper-v CPUID values.
     fn get_hyperv_cpuid(&self) -> Result<CpuId>;
 
-    /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    /// Sets up debug registers and configure vcpu for handling guest debug events. `breakpoints`
+    /// take the first debug registers and `watchpoints` the ones after them, at most 4 in total.
+    /// While guest debugging is enabled, int3 instructions exit to the VMM as debug events too.
+    fn set_guest_debug(
+        &self,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[Watchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()>;
 }
 
 impl_downcast!(VcpuX86_64);
 
+/// The accesses that trigger a hardware watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    /// x86 can't watch for reads alone, so reads are always watched together with writes.
+    ReadWrite,
+}
+
+/// A hardware data watchpoint, see `VcpuX86_64::set_guest_debug`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: GuestAddress,
+    /// The number of bytes watched: 1, 2, 4 or 8. `addr` must be aligned to it.
+    pub len: u64,
+    pub kind: WatchpointKind,
+}
+
 /// A CpuId Entry contains supported feature information for the given processor.
 /// This can be modified by the hypervisor to pass additional information to the guest kernel
 /// about the hypervisor or vm. Information is returned in the eax, ebx, ecx and edx registers
@@ -227,12 +251,37 @@ pub struct IoapicRedirectionTableEntry {
     dest_id: BitField8,
 }
 
+// Sent as the raw 64 bits of the entry, as the fields don't line up with bytes.
+impl MsgOnSocket for IoapicRedirectionTableEntry {
+    fn fixed_size() -> Option<usize> {
+        u64::fixed_size()
+    }
+
+    unsafe fn read_from_buffer(
+        buffer: &[u8],
+        fds: &[RawDescriptor],
+    ) -> msg_socket::MsgResult<(Self, usize)> {
+        let (raw, fd_count) = u64::read_from_buffer(buffer, fds)?;
+        let mut entry = IoapicRedirectionTableEntry::new();
+        entry.set(0, 64, raw);
+        Ok((entry, fd_count))
+    }
+
+    fn write_to_buffer(
+        &self,
+        buffer: &mut [u8],
+        fds: &mut [RawDescriptor],
+    ) -> msg_socket::MsgResult<usize> {
+        self.get(0, 64).write_to_buffer(buffer, fds)
+    }
+}
+
 /// Number of pins on the IOAPIC.
 pub const NUM_IOAPIC_PINS: usize = 24;
 
 /// Represents the state of the IOAPIC.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, MsgOnSocket)]
 pub struct IoapicState {
     /// base_address is the memory base address for this IOAPIC. It cannot be changed.
     pub base_address: u64,
@@ -254,7 +303,7 @@ pub enum PicSelect {
 }
 
 #[repr(C)]
-#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq, MsgOnSocket)]
 pub enum PicInitState {
     Icw1 = 0,
     Icw2 = 1,
@@ -280,7 +329,7 @@ impl Default for PicInitState {
 
 /// Represents the state of the PIC.
 #[repr(C)]
-#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, MsgOnSocket)]
 pub struct PicState {
     /// Edge detection.
     pub last_irr: u8,
@@ -314,7 +363,7 @@ pub struct PicState {
 /// The Local APIC consists of 64 128-bit registers, but only the first 32-bits of each register
 /// can be used, so this structure only stores the first 32-bits of each register.
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, MsgOnSocket)]
 pub struct LapicState {
     pub regs: [LapicRegister; 64],
 }
@@ -341,7 +390,7 @@ impl Eq for LapicState {}
 /// The PitState represents the state of the PIT (aka the Programmable Interval Timer).
 /// The state is simply the state of it's three channels.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, MsgOnSocket)]
 pub struct PitState {
     pub channels: [PitChannelState; 3],
     /// Hypervisor-specific flags for setting the pit state.
@@ -353,7 +402,7 @@ pub struct PitState {
 /// but the count values and latch values are two bytes. So the access mode controls which of the
 /// two bytes will be read when.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, MsgOnSocket)]
 pub enum PitRWMode {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -380,7 +429,7 @@ impl From<u8> for PitRWMode {
 /// This is related to the PitRWMode, it mainly gives more detail about the state of the channel
 /// with respect to PitRWMode::Both.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, MsgOnSocket)]
 pub enum PitRWState {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -409,7 +458,7 @@ impl From<u8> for PitRWState {
 
 /// The PitChannelState represents the state of one of the PIT's three counters.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, MsgOnSocket)]
 pub struct PitChannelState {
     /// The starting value for the counter.
     pub count: u32,
@@ -467,7 +516,7 @@ impl IrqRoute {
 
 /// State of a VCPU's general purpose registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct Regs {
     pub rax: u64,
     pub rbx: u64,
@@ -491,7 +540,7 @@ pub struct Regs {
 
 /// State of a memory segment.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct Segment {
     pub base: u64,
     pub limit: u32,
@@ -508,7 +557,7 @@ pub struct Segment {
 
 /// State of a global descriptor table or interrupt descriptor table.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct DescriptorTable {
     pub base: u64,
     pub limit: u16,
@@ -516,7 +565,7 @@ pub struct DescriptorTable {
 
 /// State of a VCPU's special registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct Sregs {
     pub cs: Segment,
     pub ds: Segment,
@@ -543,7 +592,7 @@ pub struct Sregs {
 
 /// State of a VCPU's floating point unit.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct Fpu {
     pub fpr: [[u8; 16usize]; 8usize],
     pub fcw: u16,
@@ -558,7 +607,7 @@ pub struct Fpu {
 
 /// State of a VCPU's debug registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, MsgOnSocket)]
 pub struct DebugRegs {
     pub db: [u64; 4usize],
     pub dr6: u64,
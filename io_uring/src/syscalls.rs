@@ -40,3 +40,22 @@ pub unsafe fn io_uring_enter(fd: RawFd, to_submit: u64, to_wait: u64, flags: u32
     }
     Ok(())
 }
+
+pub unsafe fn io_uring_register(
+    fd: RawFd,
+    opcode: u32,
+    arg: *const c_void,
+    nr_args: u32,
+) -> Result<()> {
+    let ret = libc::syscall(
+        SYS_io_uring_register as c_long,
+        fd,
+        opcode as c_int,
+        arg,
+        nr_args as c_int,
+    );
+    if ret < 0 {
+        return Err(Error::last_os_error().raw_os_error().unwrap());
+    }
+    Ok(())
+}
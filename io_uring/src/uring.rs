@@ -11,7 +11,9 @@ use std::fmt;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use data_model::IoBufMut;
 use sync::Mutex;
@@ -38,6 +40,8 @@ pub enum Error {
     MappingSubmitEntries(sys_util::MmapError),
     /// Too many ops are already queued.
     NoSpace,
+    /// The call to `io_uring_register` failed with the given errno.
+    Register(libc::c_int),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -55,10 +59,102 @@ impl fmt::Display for Error {
                 f,
                 "No space for more ring entries, try increasing the size passed to `new`",
             ),
+            Register(e) => write!(f, "Failed to register resources with io uring {}", e),
         }
     }
 }
 
+// Added in 5.13, after the bindings were generated.
+const IORING_POLL_ADD_MULTI: u32 = 1 << 0;
+const IORING_CQE_F_MORE: u32 = 1 << 1;
+
+/// A completed operation returned by `URingContext::wait_completions`.
+pub struct Completion {
+    pub user_data: UserData,
+    pub result: std::io::Result<u32>,
+    /// Set if the operation will complete again, see `URingContext::add_poll_fd_multishot`.
+    pub more: bool,
+}
+
+/// Operations that only some kernels support. See `URingContext::probe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    ReadFixed,
+    WriteFixed,
+    LinkTimeout,
+    AsyncCancel,
+    Fallocate,
+    Statx,
+}
+
+impl Opcode {
+    fn raw(self) -> u8 {
+        let op = match self {
+            Opcode::ReadFixed => IORING_OP_READ_FIXED,
+            Opcode::WriteFixed => IORING_OP_WRITE_FIXED,
+            Opcode::LinkTimeout => IORING_OP_LINK_TIMEOUT,
+            Opcode::AsyncCancel => IORING_OP_ASYNC_CANCEL,
+            Opcode::Fallocate => IORING_OP_FALLOCATE,
+            Opcode::Statx => IORING_OP_STATX,
+        };
+        op as u8
+    }
+}
+
+/// The opcodes supported by the running kernel, as reported by `URingContext::probe`.
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    // Bit `n` is set if opcode `n` is supported.
+    supported: u64,
+}
+
+impl Probe {
+    /// Returns true if the kernel can run `op`.
+    pub fn supports(&self, op: Opcode) -> bool {
+        self.supported & (1 << op.raw()) != 0
+    }
+}
+
+/// Optional behavior of the uring, see `URingContext::features`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// The submit and complete rings share a single mapping.
+    SingleMmap,
+    /// Completions are never dropped when the complete queue overflows.
+    NoDrop,
+    /// Data for an op only has to stay valid until it is submitted, not until it completes.
+    SubmitStable,
+    /// An offset of -1 reads or writes at the file's current position.
+    RwCurPos,
+    /// Ops run with the credentials of the task that submitted them.
+    CurPersonality,
+}
+
+impl Feature {
+    fn raw(self) -> u32 {
+        match self {
+            Feature::SingleMmap => IORING_FEAT_SINGLE_MMAP,
+            Feature::NoDrop => IORING_FEAT_NODROP,
+            Feature::SubmitStable => IORING_FEAT_SUBMIT_STABLE,
+            Feature::RwCurPos => IORING_FEAT_RW_CUR_POS,
+            Feature::CurPersonality => IORING_FEAT_CUR_PERSONALITY,
+        }
+    }
+}
+
+/// The features the kernel reported when the uring was set up.
+#[derive(Clone, Copy, Debug)]
+pub struct Features {
+    flags: u32,
+}
+
+impl Features {
+    /// Returns true if the uring has `feature`.
+    pub fn has(&self, feature: Feature) -> bool {
+        self.flags & feature.raw() != 0
+    }
+}
+
 /// Basic statistics about the operations that have been submitted to the uring.
 #[derive(Default)]
 pub struct URingStats {
@@ -67,6 +163,15 @@ pub struct URingStats {
     total_complete: AtomicU64,    // Total ops completed by io_uring.
 }
 
+// Number of slots in the fixed file table of an SQPOLL uring.
+const FIXED_FILE_SLOTS: u32 = 1024;
+
+// The files registered with an SQPOLL uring, see `URingContext::register_file`.
+struct FixedFiles {
+    slots: BTreeMap<RawFd, u32>,
+    free: Vec<u32>,
+}
+
 struct SubmitQueue {
     submit_ring: SubmitQueueState,
     submit_queue_entries: SubmitQueueEntries,
@@ -74,6 +179,7 @@ struct SubmitQueue {
     submitting: usize, // The number of ops in the process of being submitted.
     added: usize,      // The number of ops added since the last call to `io_uring_enter`.
     num_sqes: usize,   // The total number of sqes allocated in shared memory.
+    fixed_files: Option<FixedFiles>, // Only set for SQPOLL urings.
 }
 
 impl SubmitQueue {
@@ -83,31 +189,60 @@ impl SubmitQueue {
     where
         F: FnMut(&mut io_uring_sqe, &mut libc::iovec),
     {
-        if self.added == self.num_sqes {
+        self.prep_next_sqes(1, |_, sqe, iovec| f(sqe, iovec))
+    }
+
+    // Like `prep_next_sqe`, but fills `count` consecutive sqes, passing `f` the position of each in
+    // the batch. The kernel sees all of them at once, which linked ops rely on.
+    fn prep_next_sqes<F>(&mut self, count: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(usize, &mut io_uring_sqe, &mut libc::iovec),
+    {
+        if self.added + count > self.num_sqes {
             return Err(Error::NoSpace);
         }
 
-        // Find the next free submission entry in the submit ring and fill it with an iovec.
+        // Find the next free submission entries in the submit ring and fill them with an iovec.
         // The below raw pointer derefs are safe because the memory the pointers use lives as long
         // as the mmap in self.
         let tail = self.submit_ring.pointers.tail(Ordering::Relaxed);
-        let next_tail = tail.wrapping_add(1);
-        if next_tail == self.submit_ring.pointers.head(Ordering::Acquire) {
+        let head = self.submit_ring.pointers.head(Ordering::Acquire);
+        // With SQPOLL the kernel consumes entries on its own schedule, so `added` alone doesn't
+        // show whether the entries from `tail` on are free again.
+        if tail.wrapping_sub(head) as usize + count > self.num_sqes {
             return Err(Error::NoSpace);
         }
-        // `tail` is the next sqe to use.
-        let index = (tail & self.submit_ring.ring_mask) as usize;
-        let sqe = self.submit_queue_entries.get_mut(index).unwrap();
 
-        f(sqe, self.io_vecs[index].as_mut());
+        for i in 0..count {
+            // `tail + i` is the next sqe to use.
+            let index = (tail.wrapping_add(i as u32) & self.submit_ring.ring_mask) as usize;
+            let sqe = self.submit_queue_entries.get_mut(index).unwrap();
 
-        // Tells the kernel to use the new index when processing the entry at that index.
-        self.submit_ring.set_array_entry(index, index as u32);
+            f(i, sqe, self.io_vecs[index].as_mut());
+
+            // Ops on registered files refer to them by their slot in the fixed file table. statx
+            // resolves its fd like the `*at` syscalls, which doesn't use the table.
+            let slot = match &self.fixed_files {
+                Some(fixed_files) if sqe.opcode != IORING_OP_STATX as u8 => {
+                    fixed_files.slots.get(&sqe.fd).copied()
+                }
+                _ => None,
+            };
+            if let Some(slot) = slot {
+                sqe.fd = slot as RawFd;
+                sqe.flags |= 1 << IOSQE_FIXED_FILE_BIT;
+            }
+
+            // Tells the kernel to use the new index when processing the entry at that index.
+            self.submit_ring.set_array_entry(index, index as u32);
+        }
         // Ensure the above writes to sqe are seen before the tail is updated.
         // set_tail uses Release ordering when storing to the ring.
-        self.submit_ring.pointers.set_tail(next_tail);
+        self.submit_ring
+            .pointers
+            .set_tail(tail.wrapping_add(count as u32));
 
-        self.added += 1;
+        self.added += count;
 
         Ok(())
     }
@@ -189,6 +324,8 @@ impl SubmitQueue {
 /// ```
 pub struct URingContext {
     ring_file: File, // Holds the io_uring context FD returned from io_uring_setup.
+    sqpoll: bool,    // A kernel thread polls the submit queue.
+    features: u32,   // IORING_FEAT_* flags reported by io_uring_setup.
     submit_ring: Mutex<SubmitQueue>,
     complete_ring: CompleteQueueState,
     in_flight: AtomicUsize, // The number of pending operations.
@@ -199,7 +336,43 @@ impl URingContext {
     /// Creates a `URingContext` where the underlying uring has a space for `num_entries`
     /// simultaneous operations.
     pub fn new(num_entries: usize) -> Result<URingContext> {
-        let ring_params = io_uring_params::default();
+        Self::with_params(num_entries, io_uring_params::default())
+    }
+
+    /// Creates a `URingContext` in SQPOLL mode. A kernel thread picks up new operations as they are
+    /// added, so `submit` only needs a syscall when that thread went to sleep after being idle for
+    /// `idle`. This trades CPU time for lower submission latency.
+    ///
+    /// Kernels before 5.11 only allow SQPOLL for privileged processes and only for ops on files
+    /// registered with `register_file`.
+    pub fn new_sqpoll(num_entries: usize, idle: Duration) -> Result<URingContext> {
+        let ring_params = io_uring_params {
+            flags: IORING_SETUP_SQPOLL,
+            sq_thread_idle: idle.as_millis().min(u32::MAX as u128) as u32,
+            ..Default::default()
+        };
+        let ctx = Self::with_params(num_entries, ring_params)?;
+
+        // Start with an empty table, files are added to it as they are registered.
+        let fds = vec![-1 as RawFd; FIXED_FILE_SLOTS as usize];
+        unsafe {
+            // Safe because the kernel only reads `fds`, which is valid for its length.
+            io_uring_register(
+                ctx.ring_file.as_raw_fd(),
+                IORING_REGISTER_FILES,
+                fds.as_ptr() as *const libc::c_void,
+                FIXED_FILE_SLOTS,
+            )
+            .map_err(Error::Register)?;
+        }
+        ctx.submit_ring.lock().fixed_files = Some(FixedFiles {
+            slots: BTreeMap::new(),
+            free: (0..FIXED_FILE_SLOTS).rev().collect(),
+        });
+        Ok(ctx)
+    }
+
+    fn with_params(num_entries: usize, ring_params: io_uring_params) -> Result<URingContext> {
         // The below unsafe block isolates the creation of the URingContext. Each step on it's own
         // is unsafe. Using the uring FD for the mapping and the offsets returned by the kernel for
         // base addresses maintains safety guarantees assuming the kernel API guarantees are
@@ -254,6 +427,8 @@ impl URingContext {
 
             Ok(URingContext {
                 ring_file,
+                sqpoll: ring_params.flags & IORING_SETUP_SQPOLL != 0,
+                features: ring_params.features,
                 submit_ring: Mutex::new(SubmitQueue {
                     submit_ring,
                     submit_queue_entries,
@@ -261,6 +436,7 @@ impl URingContext {
                     submitting: 0,
                     added: 0,
                     num_sqes: ring_params.sq_entries as usize,
+                    fixed_files: None,
                 }),
                 complete_ring,
                 in_flight: AtomicUsize::new(0),
@@ -310,6 +486,64 @@ impl URingContext {
             .add_rw_op(ptr, len, fd, offset, user_data, IORING_OP_READV as u8)
     }
 
+    /// Asynchronously writes to `fd` from the address given in `ptr`, which must lie within the
+    /// buffer registered at index `buf_index` with `register_buffers`. Saves pinning the memory for
+    /// each operation.
+    /// # Safety
+    /// Same as `add_write`.
+    pub unsafe fn add_write_fixed(
+        &self,
+        ptr: *const u8,
+        len: usize,
+        fd: RawFd,
+        offset: u64,
+        buf_index: u16,
+        user_data: UserData,
+    ) -> Result<()> {
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_WRITE_FIXED as u8;
+            sqe.fd = fd;
+            sqe.addr = ptr as u64;
+            sqe.len = len as u32;
+            sqe.__bindgen_anon_1.off = offset;
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = buf_index;
+            sqe.user_data = user_data;
+
+            sqe.__bindgen_anon_2.rw_flags = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
+    /// Asynchronously reads from `fd` to the address given in `ptr`, which must lie within the
+    /// buffer registered at index `buf_index` with `register_buffers`. Saves pinning the memory for
+    /// each operation.
+    /// # Safety
+    /// Same as `add_read`.
+    pub unsafe fn add_read_fixed(
+        &self,
+        ptr: *mut u8,
+        len: usize,
+        fd: RawFd,
+        offset: u64,
+        buf_index: u16,
+        user_data: UserData,
+    ) -> Result<()> {
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_READ_FIXED as u8;
+            sqe.fd = fd;
+            sqe.addr = ptr as u64;
+            sqe.len = len as u32;
+            sqe.__bindgen_anon_1.off = offset;
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = buf_index;
+            sqe.user_data = user_data;
+
+            sqe.__bindgen_anon_2.rw_flags = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
     /// See 'writev' but accepts an iterator instead of a vector if there isn't already a vector in
     /// existence.
     pub unsafe fn add_writev_iter<I>(
@@ -368,6 +602,79 @@ impl URingContext {
         Ok(())
     }
 
+    /// See `add_writev_then_fsync` but accepts an iterator instead of a vector if there isn't
+    /// already a vector in existence.
+    /// # Safety
+    /// See `add_writev`.
+    pub unsafe fn add_writev_iter_then_fsync<I>(
+        &self,
+        iovecs: I,
+        fd: RawFd,
+        offset: u64,
+        write_user_data: UserData,
+        fsync_user_data: UserData,
+    ) -> Result<()>
+    where
+        I: Iterator<Item = libc::iovec>,
+    {
+        self.add_writev_then_fsync(
+            Pin::from(
+                // Safe because the caller is required to guarantee that the memory pointed to by
+                // `iovecs` lives until the transaction is complete and the completion has been
+                // returned from `wait()`.
+                iovecs
+                    .map(|iov| IoBufMut::from_raw_parts(iov.iov_base as *mut u8, iov.iov_len))
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice(),
+            ),
+            fd,
+            offset,
+            write_user_data,
+            fsync_user_data,
+        )
+    }
+
+    /// Like `add_writev` followed by `add_fsync`, except that the fsync is linked to the write
+    /// (`IOSQE_IO_LINK`) so the kernel only starts it once the write has completed in full. If the
+    /// write fails or is short the fsync completes with `ECANCELED`. Both ops reach the kernel
+    /// together, even with SQPOLL.
+    /// # Safety
+    /// See `add_writev`.
+    pub unsafe fn add_writev_then_fsync(
+        &self,
+        iovecs: Pin<Box<[IoBufMut<'static>]>>,
+        fd: RawFd,
+        offset: u64,
+        write_user_data: UserData,
+        fsync_user_data: UserData,
+    ) -> Result<()> {
+        self.submit_ring
+            .lock()
+            .prep_next_sqes(2, |i, sqe, _iovec| {
+                if i == 0 {
+                    sqe.opcode = IORING_OP_WRITEV as u8;
+                    sqe.addr = iovecs.as_ptr() as *const _ as *const libc::c_void as u64;
+                    sqe.len = iovecs.len() as u32;
+                    sqe.__bindgen_anon_1.off = offset;
+                    sqe.user_data = write_user_data;
+                    sqe.flags = 1 << IOSQE_IO_LINK_BIT;
+                } else {
+                    sqe.opcode = IORING_OP_FSYNC as u8;
+                    sqe.addr = 0;
+                    sqe.len = 0;
+                    sqe.__bindgen_anon_1.off = 0;
+                    sqe.user_data = fsync_user_data;
+                    sqe.flags = 0;
+                }
+                sqe.__bindgen_anon_2.rw_flags = 0;
+                sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = 0;
+                sqe.ioprio = 0;
+                sqe.fd = fd;
+            })?;
+        self.complete_ring.add_op_data(write_user_data, iovecs);
+        Ok(())
+    }
+
     /// See 'readv' but accepts an iterator instead of a vector if there isn't already a vector in
     /// existence.
     pub unsafe fn add_readv_iter<I>(
@@ -444,6 +751,25 @@ impl URingContext {
         })
     }
 
+    /// Asks the kernel to cancel the operation that was added with `target` as its user data. The
+    /// canceled operation still returns a completion, with `ECANCELED` if it was stopped before
+    /// finishing, and the cancel request returns its own completion tagged with `user_data`.
+    pub fn add_cancel(&self, target: UserData, user_data: UserData) -> Result<()> {
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_ASYNC_CANCEL as u8;
+            sqe.fd = -1;
+            sqe.user_data = user_data;
+
+            sqe.addr = target;
+            sqe.len = 0;
+            sqe.__bindgen_anon_1.off = 0;
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = 0;
+            sqe.__bindgen_anon_2.rw_flags = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
     /// Syncs all completed operations, the ordering with in-flight async ops is not
     /// defined.
     pub fn add_fsync(&self, fd: RawFd, user_data: UserData) -> Result<()> {
@@ -489,6 +815,36 @@ impl URingContext {
         })
     }
 
+    /// Asynchronously gets the attributes of the file referred to by `fd`, see `statx(2)`. Only
+    /// the fields selected by `mask` are guaranteed to be filled in.
+    /// # Safety
+    /// `statx_buf` must point to memory that stays valid, and isn't otherwise accessed, until the
+    /// completion is returned from `wait`.
+    pub unsafe fn add_statx(
+        &self,
+        fd: RawFd,
+        mask: u32,
+        statx_buf: *mut libc::statx,
+        user_data: UserData,
+    ) -> Result<()> {
+        // An empty path with AT_EMPTY_PATH makes statx operate on `fd` itself, like fstat.
+        static EMPTY_PATH: &[u8] = b"\0";
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_STATX as u8;
+
+            sqe.fd = fd;
+            sqe.addr = EMPTY_PATH.as_ptr() as u64;
+            sqe.len = mask;
+            sqe.__bindgen_anon_1.off = statx_buf as u64;
+            sqe.__bindgen_anon_2.statx_flags = libc::AT_EMPTY_PATH as u32;
+            sqe.user_data = user_data;
+
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
     /// Adds an FD to be polled based on the given flags.
     /// The user must keep the FD open until the operation completion is returned from
     /// `wait`.
@@ -515,6 +871,31 @@ impl URingContext {
         })
     }
 
+    /// Like `add_poll_fd`, but the poll stays armed after it fires and completes again every time
+    /// `fd` signals one of `events`, until it is canceled or fails. Completions that will be
+    /// followed by more have `more` set, see `wait_completions`. Kernels before 5.13 complete the
+    /// poll right away with `EINVAL`.
+    pub fn add_poll_fd_multishot(
+        &self,
+        fd: RawFd,
+        events: &WatchingEvents,
+        user_data: UserData,
+    ) -> Result<()> {
+        self.submit_ring.lock().prep_next_sqe(|sqe, _iovec| {
+            sqe.opcode = IORING_OP_POLL_ADD as u8;
+            sqe.fd = fd;
+            sqe.user_data = user_data;
+            sqe.__bindgen_anon_2.poll_events = events.get_raw() as u16;
+
+            sqe.addr = 0;
+            sqe.len = IORING_POLL_ADD_MULTI;
+            sqe.__bindgen_anon_1.off = 0;
+            sqe.__bindgen_anon_3.__bindgen_anon_1.buf_index = 0;
+            sqe.ioprio = 0;
+            sqe.flags = 0;
+        })
+    }
+
     /// Removes an FD that was previously added with `add_poll_fd`.
     pub fn remove_poll_fd(
         &self,
@@ -537,6 +918,176 @@ impl URingContext {
         })
     }
 
+    /// Adds `fd` to the fixed file table of an SQPOLL uring, so ops on it are accepted by kernels
+    /// before 5.11 and skip looking up the file on newer ones. Ops on `fd` added afterwards use the
+    /// registration until `unregister_file` is called, which must happen before `fd` is closed.
+    /// Does nothing for urings that aren't in SQPOLL mode.
+    pub fn register_file(&self, fd: RawFd) -> Result<()> {
+        let mut submit_ring = self.submit_ring.lock();
+        let fixed_files = match &mut submit_ring.fixed_files {
+            Some(fixed_files) => fixed_files,
+            None => return Ok(()),
+        };
+        if fixed_files.slots.contains_key(&fd) {
+            return Ok(());
+        }
+        let slot = fixed_files.free.pop().ok_or(Error::NoSpace)?;
+        if let Err(e) = self.update_file(slot, fd) {
+            fixed_files.free.push(slot);
+            return Err(e);
+        }
+        fixed_files.slots.insert(fd, slot);
+        Ok(())
+    }
+
+    /// Removes `fd` from the fixed file table. Ops that were already added keep working.
+    pub fn unregister_file(&self, fd: RawFd) -> Result<()> {
+        let mut submit_ring = self.submit_ring.lock();
+        let slot = match submit_ring
+            .fixed_files
+            .as_mut()
+            .and_then(|f| f.slots.remove(&fd))
+        {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+
+        // Sqes that still refer to the slot must reach the kernel before it is cleared. Once the
+        // kernel has read an sqe it holds its own reference to the file.
+        self.wake_sq_thread(&submit_ring)?;
+        while submit_ring.submit_ring.pointers.head(Ordering::Acquire)
+            != submit_ring.submit_ring.pointers.tail(Ordering::Relaxed)
+        {
+            thread::yield_now();
+        }
+
+        let res = self.update_file(slot, -1);
+        // Don't reuse a slot that may still point to `fd`.
+        if res.is_ok() {
+            if let Some(fixed_files) = submit_ring.fixed_files.as_mut() {
+                fixed_files.free.push(slot);
+            }
+        }
+        res
+    }
+
+    /// Pins `iovecs` in memory so they can be used by `add_read_fixed` and `add_write_fixed`,
+    /// replacing any buffers registered before. Each buffer can be at most 1 GiB and counts
+    /// against the process's locked memory limit.
+    ///
+    /// Kernels before 5.13 wait for all pending operations to complete while buffers are
+    /// registered or unregistered, so this is best done before submitting any operations.
+    /// # Safety
+    /// The memory the iovecs point to must stay valid until the buffers are unregistered or the
+    /// `URingContext` is dropped.
+    pub unsafe fn register_buffers(&self, iovecs: &[libc::iovec]) -> Result<()> {
+        match self.unregister_buffers() {
+            // ENXIO means no buffers were registered.
+            Ok(()) | Err(Error::Register(libc::ENXIO)) => {}
+            Err(e) => return Err(e),
+        }
+        io_uring_register(
+            self.ring_file.as_raw_fd(),
+            IORING_REGISTER_BUFFERS,
+            iovecs.as_ptr() as *const libc::c_void,
+            iovecs.len() as u32,
+        )
+        .map_err(Error::Register)
+    }
+
+    /// Unregisters the buffers added with `register_buffers`.
+    pub fn unregister_buffers(&self) -> Result<()> {
+        unsafe {
+            // Safe because this doesn't touch any memory.
+            io_uring_register(
+                self.ring_file.as_raw_fd(),
+                IORING_UNREGISTER_BUFFERS,
+                std::ptr::null(),
+                0,
+            )
+            .map_err(Error::Register)
+        }
+    }
+
+    /// Asks the kernel which opcodes it supports. Kernels before 5.6 can't answer, in which case
+    /// only the opcodes every kernel since 5.4 has are reported, up to `IORING_OP_TIMEOUT`.
+    pub fn probe(&self) -> Probe {
+        const MAX_OPS: usize = 64;
+        // The header is followed by one 8 byte entry per op. Use u64s to keep it aligned.
+        let header_len = std::mem::size_of::<io_uring_probe>() / 8;
+        let mut buf = vec![0u64; header_len + MAX_OPS];
+        let res = unsafe {
+            // Safe because the kernel writes at most `MAX_OPS` entries after the header, which fit
+            // in `buf`.
+            io_uring_register(
+                self.ring_file.as_raw_fd(),
+                IORING_REGISTER_PROBE,
+                buf.as_mut_ptr() as *const libc::c_void,
+                MAX_OPS as u32,
+            )
+        };
+        if res.is_err() {
+            return Probe {
+                supported: (1 << (IORING_OP_TIMEOUT + 1)) - 1,
+            };
+        }
+
+        // Safe because `buf` is large and aligned enough for the header and the entries the kernel
+        // filled in, and any bit pattern is valid for them.
+        let ops = unsafe {
+            let header = &*(buf.as_ptr() as *const io_uring_probe);
+            std::slice::from_raw_parts(
+                buf.as_ptr().add(header_len) as *const io_uring_probe_op,
+                (header.ops_len as usize).min(MAX_OPS),
+            )
+        };
+        let supported = ops
+            .iter()
+            .filter(|op| u32::from(op.flags) & IO_URING_OP_SUPPORTED != 0)
+            .filter(|op| (op.op as usize) < MAX_OPS)
+            .fold(0, |acc, op| acc | (1 << op.op));
+        Probe { supported }
+    }
+
+    /// Returns the features the kernel reported for this uring. Kernels before 5.4 report none.
+    pub fn features(&self) -> Features {
+        Features {
+            flags: self.features,
+        }
+    }
+
+    fn update_file(&self, slot: u32, fd: RawFd) -> Result<()> {
+        let update = io_uring_files_update {
+            offset: slot,
+            resv: 0,
+            fds: &fd as *const RawFd as u64,
+        };
+        unsafe {
+            // Safe because the kernel only reads `update` and the fd it points to.
+            io_uring_register(
+                self.ring_file.as_raw_fd(),
+                IORING_REGISTER_FILES_UPDATE,
+                &update as *const _ as *const libc::c_void,
+                1,
+            )
+            .map_err(Error::Register)
+        }
+    }
+
+    // Wakes up the kernel thread of an SQPOLL uring if it went to sleep.
+    fn wake_sq_thread(&self, submit_ring: &SubmitQueue) -> Result<()> {
+        if !self.sqpoll || !submit_ring.submit_ring.needs_wakeup() {
+            return Ok(());
+        }
+        self.stats.total_enter_calls.fetch_add(1, Ordering::Relaxed);
+        unsafe {
+            // Safe because no memory is modified without any sqes submitted or completions waited
+            // for.
+            io_uring_enter(self.ring_file.as_raw_fd(), 0, 0, IORING_ENTER_SQ_WAKEUP)
+                .map_err(Error::RingEnter)
+        }
+    }
+
     // Calls io_uring_enter, submitting any new sqes that have been added to the submit queue and
     // waiting for `wait_nr` operations to complete.
     fn enter(&self, wait_nr: u64) -> Result<()> {
@@ -546,6 +1097,10 @@ impl URingContext {
             .fetch_add(completed as u64, Ordering::Relaxed);
         self.in_flight.fetch_sub(completed, Ordering::Relaxed);
 
+        if self.sqpoll {
+            return self.enter_sqpoll(wait_nr);
+        }
+
         let added = self.submit_ring.lock().prepare_submit();
         if added == 0 && wait_nr == 0 {
             return Ok(());
@@ -592,6 +1147,36 @@ impl URingContext {
         Ok(())
     }
 
+    // Like `enter`, but the kernel thread has already seen the new sqes when they were added. A
+    // syscall is only needed to wake that thread up or to wait for completions.
+    fn enter_sqpoll(&self, wait_nr: u64) -> Result<()> {
+        {
+            let mut submit_ring = self.submit_ring.lock();
+            let added = submit_ring.prepare_submit();
+            submit_ring.complete_submit(added);
+            self.stats
+                .total_ops
+                .fetch_add(added as u64, Ordering::Relaxed);
+            self.in_flight.fetch_add(added, Ordering::Release);
+
+            self.wake_sq_thread(&submit_ring)?;
+        }
+        if wait_nr == 0 {
+            return Ok(());
+        }
+        self.stats.total_enter_calls.fetch_add(1, Ordering::Relaxed);
+        unsafe {
+            // Safe because the only memory modified is in the completion queue.
+            io_uring_enter(
+                self.ring_file.as_raw_fd(),
+                0,
+                wait_nr,
+                IORING_ENTER_GETEVENTS,
+            )
+            .map_err(Error::RingEnter)
+        }
+    }
+
     /// Sends operations added with the `add_*` functions to the kernel.
     pub fn submit(&self) -> Result<()> {
         self.enter(0)
@@ -602,6 +1187,12 @@ impl URingContext {
     /// without any new events added, this simply waits for any existing events to complete and
     /// returns as soon an one or more is ready.
     pub fn wait(&self) -> Result<impl Iterator<Item = (UserData, std::io::Result<u32>)> + '_> {
+        self.wait_completions()
+            .map(|completions| completions.map(|c| (c.user_data, c.result)))
+    }
+
+    /// Like `wait`, but also reports whether each operation will complete again.
+    pub fn wait_completions(&self) -> Result<impl Iterator<Item = Completion> + '_> {
         // We only want to wait for events if there aren't already events in the completion queue.
         let wait_nr = if self.complete_ring.num_ready() > 0 {
             0
@@ -653,6 +1244,7 @@ struct SubmitQueueState {
     pointers: QueuePointers,
     ring_mask: u32,
     array: AtomicPtr<u32>,
+    flags: AtomicPtr<u32>,
 }
 
 impl SubmitQueueState {
@@ -668,14 +1260,30 @@ impl SubmitQueueState {
         // This offset is guaranteed to be within the mmap so unwrap the result.
         let ring_mask = mmap.read_obj(params.sq_off.ring_mask as usize).unwrap();
         let array = AtomicPtr::new(ptr.add(params.sq_off.array as usize) as *mut u32);
+        let flags = AtomicPtr::new(ptr.add(params.sq_off.flags as usize) as *mut u32);
         SubmitQueueState {
             _mmap: mmap,
             pointers: QueuePointers { head, tail },
             ring_mask,
             array,
+            flags,
         }
     }
 
+    // Returns true if the kernel's SQPOLL thread went to sleep and has to be woken up to see new
+    // entries.
+    fn needs_wakeup(&self) -> bool {
+        // The tail update must be visible to the kernel thread before checking whether it's asleep,
+        // otherwise it could go to sleep without seeing the new entries.
+        fence(Ordering::SeqCst);
+        // Safe because self being constructed from the correct mmap guaratees that the memory is
+        // valid to read and a u32 is atomic on all supported architectures.
+        let flags = unsafe {
+            (*(self.flags.load(Ordering::Relaxed) as *const AtomicU32)).load(Ordering::Relaxed)
+        };
+        flags & IORING_SQ_NEED_WAKEUP != 0
+    }
+
     // Sets the kernel's array entry at the given `index` to `value`.
     fn set_array_entry(&self, index: usize, value: u32) {
         // Safe because self being constructed from the correct mmap guaratees that the memory is
@@ -749,7 +1357,7 @@ impl CompleteQueueState {
         ::std::mem::replace(&mut data.completed, 0)
     }
 
-    fn pop_front(&self) -> Option<(UserData, std::io::Result<u32>)> {
+    fn pop_front(&self) -> Option<Completion> {
         // Take the lock on self.data first so that 2 threads don't try to pop the same completed op
         // from the queue.
         let mut data = self.data.lock();
@@ -768,9 +1376,12 @@ impl CompleteQueueState {
         let cqe = self.get_cqe(head);
         let user_data = cqe.user_data;
         let res = cqe.res;
+        let more = cqe.flags & IORING_CQE_F_MORE != 0;
 
-        // free the addrs saved for this op.
-        let _ = data.pending_op_addrs.remove(&user_data);
+        // free the addrs saved for this op once it won't complete again.
+        if !more {
+            let _ = data.pending_op_addrs.remove(&user_data);
+        }
 
         // Store the new head and ensure the reads above complete before the kernel sees the
         // update to head, `set_head` uses `Release` ordering
@@ -781,13 +1392,17 @@ impl CompleteQueueState {
             r if r < 0 => Err(std::io::Error::from_raw_os_error(-r)),
             r => Ok(r as u32),
         };
-        Some((user_data, io_res))
+        Some(Completion {
+            user_data,
+            result: io_res,
+            more,
+        })
     }
 }
 
 // Return the completed ops with their result.
 impl<'c> Iterator for &'c CompleteQueueState {
-    type Item = (UserData, std::io::Result<u32>);
+    type Item = Completion;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.pop_front()
@@ -852,7 +1467,7 @@ mod tests {
     use std::time::Duration;
 
     use sync::{Condvar, Mutex};
-    use sys_util::{pipe, PollContext};
+    use sys_util::{pipe, EventFd, PollContext};
     use tempfile::{tempfile, TempDir};
 
     use super::*;
@@ -1178,6 +1793,32 @@ mod tests {
         assert_eq!(new_size, set_size);
     }
 
+    #[test]
+    fn statx() {
+        let f = create_test_file(8192);
+        let uring = URingContext::new(16).unwrap();
+        let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+        unsafe {
+            uring
+                .add_statx(f.as_raw_fd(), libc::STATX_SIZE, &mut buf, 71)
+                .unwrap();
+        }
+        let (user_data, res) = uring.wait().unwrap().next().unwrap();
+        assert_eq!(user_data, 71_u64);
+        match res {
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::InvalidInput {
+                    // skip on kernels that don't support statx.
+                    return;
+                }
+                panic!("Unexpected statx error: {}", e);
+            }
+            Ok(val) => assert_eq!(val, 0_u32),
+        }
+        assert_ne!(buf.stx_mask & libc::STATX_SIZE, 0);
+        assert_eq!(buf.stx_size, 8192);
+    }
+
     #[test]
     fn dev_zero_readable() {
         let f = File::open(Path::new("/dev/zero")).unwrap();
@@ -1233,6 +1874,113 @@ mod tests {
         assert!(results.next().is_none());
     }
 
+    #[test]
+    fn cancel_read() {
+        const PIPE_READ: UserData = 0;
+        const CANCEL: UserData = 1;
+
+        let uring = URingContext::new(4).unwrap();
+        let (pipe_out, _pipe_in) = pipe(true).unwrap();
+        let mut buf = [0u8; 16];
+        unsafe {
+            uring
+                .add_read(
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    pipe_out.as_raw_fd(),
+                    0,
+                    PIPE_READ,
+                )
+                .unwrap();
+        }
+        uring.add_cancel(PIPE_READ, CANCEL).unwrap();
+
+        let mut read_result = None;
+        let mut cancel_result = None;
+        while read_result.is_none() || cancel_result.is_none() {
+            for (user_data, result) in uring.wait().unwrap() {
+                match user_data {
+                    PIPE_READ => read_result = Some(result),
+                    CANCEL => cancel_result = Some(result),
+                    _ => panic!("unexpected user data: {}", user_data),
+                }
+            }
+        }
+        assert_eq!(
+            read_result.unwrap().unwrap_err().raw_os_error(),
+            Some(libc::ECANCELED)
+        );
+        assert_eq!(cancel_result.unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    fn sqpoll_write_read() {
+        let uring = match URingContext::new_sqpoll(16, Duration::from_millis(1)) {
+            Ok(uring) => uring,
+            // Skip on kernels that don't support SQPOLL for this process.
+            Err(Error::Setup(libc::EPERM)) | Err(Error::Setup(libc::EINVAL)) => return,
+            Err(e) => panic!("failed to create SQPOLL uring: {}", e),
+        };
+        let f = create_test_file(0);
+        uring.register_file(f.as_raw_fd()).unwrap();
+
+        let buf = [0x55u8; 4096];
+        unsafe {
+            uring
+                .add_write(buf.as_ptr(), buf.len(), f.as_raw_fd(), 0, 1)
+                .unwrap();
+        }
+        let (user_data, res) = uring.wait().unwrap().next().unwrap();
+        assert_eq!(user_data, 1_u64);
+        assert_eq!(res.unwrap(), buf.len() as u32);
+
+        // Let the kernel thread go idle so the next submission has to wake it up.
+        thread::sleep(Duration::from_millis(10));
+        let mut read_buf = [0u8; 4096];
+        check_one_read(&uring, &mut read_buf, f.as_raw_fd(), 0, 2);
+        assert_eq!(read_buf[..], buf[..]);
+
+        uring.unregister_file(f.as_raw_fd()).unwrap();
+    }
+
+    #[test]
+    fn fixed_buffers() {
+        let f = create_test_file(0);
+        let uring = URingContext::new(16).unwrap();
+        let mut mem = vec![0u8; 8192];
+        let iovec = libc::iovec {
+            iov_base: mem.as_mut_ptr() as *mut libc::c_void,
+            iov_len: mem.len(),
+        };
+        unsafe {
+            // Safe because `mem` outlives `uring`.
+            uring.register_buffers(&[iovec]).unwrap();
+        }
+
+        mem[..4096].copy_from_slice(&[0xa5; 4096]);
+        let (user_data, res) = unsafe {
+            uring
+                .add_write_fixed(mem.as_ptr(), 4096, f.as_raw_fd(), 0, 0, 1)
+                .unwrap();
+            uring.wait().unwrap().next().unwrap()
+        };
+        assert_eq!(user_data, 1_u64);
+        assert_eq!(res.unwrap(), 4096_u32);
+
+        // Read it back into the second half of the buffer.
+        let (user_data, res) = unsafe {
+            uring
+                .add_read_fixed(mem.as_mut_ptr().add(4096), 4096, f.as_raw_fd(), 0, 0, 2)
+                .unwrap();
+            uring.wait().unwrap().next().unwrap()
+        };
+        assert_eq!(user_data, 2_u64);
+        assert_eq!(res.unwrap(), 4096_u32);
+        assert_eq!(mem[..4096], mem[4096..]);
+
+        uring.unregister_buffers().unwrap();
+    }
+
     #[test]
     fn wake_with_nop() {
         const PIPE_READ: UserData = 0;
@@ -1538,4 +2286,126 @@ mod tests {
             (NUM_SUBMITTERS * ITERATIONS + NUM_COMPLETERS) as u64
         );
     }
+
+    #[test]
+    fn probe() {
+        let uring = URingContext::new(16).unwrap();
+        let probe = uring.probe();
+        // Every kernel with io_uring supports these.
+        for op in &[
+            IORING_OP_NOP,
+            IORING_OP_READV,
+            IORING_OP_WRITEV,
+            IORING_OP_POLL_ADD,
+        ] {
+            assert_ne!(probe.supported & (1 << op), 0);
+        }
+        assert!(probe.supports(Opcode::ReadFixed));
+        assert!(probe.supports(Opcode::WriteFixed));
+    }
+
+    #[test]
+    fn features() {
+        let uring = URingContext::new(16).unwrap();
+        // Only pre-5.4 kernels lack this, and they don't have all the ops this crate uses.
+        assert!(uring.features().has(Feature::SingleMmap));
+    }
+
+    #[test]
+    fn poll_multishot() {
+        const POLL: UserData = 1;
+        const CANCEL: UserData = 2;
+
+        let evt = EventFd::new().unwrap();
+        let uring = URingContext::new(16).unwrap();
+        uring
+            .add_poll_fd_multishot(evt.as_raw_fd(), &WatchingEvents::empty().set_read(), POLL)
+            .unwrap();
+
+        // The same poll completes for every signal without being added again.
+        for i in 0..3 {
+            evt.write(1).unwrap();
+            let c = uring.wait_completions().unwrap().next().unwrap();
+            assert_eq!(c.user_data, POLL);
+            match c.result {
+                // Skip on kernels before 5.13.
+                Err(e) if i == 0 && e.raw_os_error() == Some(libc::EINVAL) => return,
+                res => assert!(res.is_ok()),
+            }
+            assert!(c.more);
+            evt.read().unwrap();
+        }
+
+        uring.add_cancel(POLL, CANCEL).unwrap();
+        let mut poll_result = None;
+        while poll_result.is_none() {
+            for c in uring.wait_completions().unwrap() {
+                if c.user_data == POLL {
+                    assert!(!c.more);
+                    poll_result = Some(c.result);
+                }
+            }
+        }
+        assert_eq!(
+            poll_result.unwrap().unwrap_err().raw_os_error(),
+            Some(libc::ECANCELED)
+        );
+    }
+
+    #[test]
+    fn writev_then_fsync() {
+        const WRITE: UserData = 1;
+        const FSYNC: UserData = 2;
+
+        fn run(
+            uring: &URingContext,
+            f: &File,
+            buf: &[u8],
+        ) -> BTreeMap<UserData, std::io::Result<u32>> {
+            let iovec = libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            unsafe {
+                // Safe because `buf` outlives the ops, which complete before this returns.
+                uring
+                    .add_writev_iter_then_fsync(
+                        std::iter::once(iovec),
+                        f.as_raw_fd(),
+                        0,
+                        WRITE,
+                        FSYNC,
+                    )
+                    .unwrap();
+            }
+            let mut results = BTreeMap::new();
+            while results.len() < 2 {
+                results.extend(uring.wait().unwrap());
+            }
+            results
+        }
+
+        let uring = URingContext::new(16).unwrap();
+        let buf = [0xa5u8; 4096];
+
+        let f = create_test_file(0);
+        let mut results = run(&uring, &f, &buf);
+        assert_eq!(results.remove(&WRITE).unwrap().unwrap(), buf.len() as u32);
+        assert_eq!(results.remove(&FSYNC).unwrap().unwrap(), 0);
+
+        // The fsync doesn't run if the write fails.
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("read_only");
+        File::create(&path).unwrap();
+        let f = File::open(&path).unwrap();
+        let mut results = run(&uring, &f, &buf);
+        assert_eq!(
+            results.remove(&WRITE).unwrap().unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+        assert_eq!(
+            results.remove(&FSYNC).unwrap().unwrap_err().raw_os_error(),
+            Some(libc::ECANCELED)
+        );
+    }
 }
@@ -29,9 +29,11 @@ pub enum Error {
     InvalidProgramHeaderMemSize,
     ReadElfHeader,
     ReadKernelImage,
+    ReadNote,
     ReadProgramHeader,
     SeekKernelStart,
     SeekElfStart,
+    SeekNote,
     SeekProgramHeader,
 }
 pub type Result<T> = std::result::Result<T, Error>;
@@ -53,9 +55,11 @@ impl Display for Error {
             InvalidProgramHeaderMemSize => "invalid Program Header memory size",
             ReadElfHeader => "unable to read elf header",
             ReadKernelImage => "unable to read kernel image",
+            ReadNote => "unable to read elf note",
             ReadProgramHeader => "unable to read program header",
             SeekKernelStart => "unable to seek to kernel start",
             SeekElfStart => "unable to seek to elf start",
+            SeekNote => "unable to seek to elf note",
             SeekProgramHeader => "unable to seek to program header",
         };
 
@@ -63,20 +67,15 @@ impl Display for Error {
     }
 }
 
-/// Loads a kernel from a vmlinux elf image to a slice
-///
-/// # Arguments
-///
-/// * `guest_mem` - The guest memory region the kernel is written to.
-/// * `kernel_start` - The offset into `guest_mem` at which to load the kernel.
-/// * `kernel_image` - Input vmlinux image.
-pub fn load_kernel<F>(
-    guest_mem: &GuestMemory,
-    kernel_start: GuestAddress,
-    kernel_image: &mut F,
-) -> Result<u64>
+// The name and type of the Xen ELF note holding the 32-bit entry point of kernels that support the
+// PVH boot protocol.
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+// Reads the header and program headers of an elf image.
+fn read_program_headers<F>(kernel_image: &mut F) -> Result<Vec<elf::Elf64_Phdr>>
 where
-    F: Read + Seek + AsRawDescriptor,
+    F: Read + Seek,
 {
     let mut ehdr: elf::Elf64_Ehdr = Default::default();
     kernel_image
@@ -109,11 +108,90 @@ where
     kernel_image
         .seek(SeekFrom::Start(ehdr.e_phoff))
         .map_err(|_| Error::SeekProgramHeader)?;
-    let phdrs: Vec<elf::Elf64_Phdr> = unsafe {
+    unsafe {
         // Reading the structs is safe for a slice of POD structs.
         base::read_struct_slice(kernel_image, ehdr.e_phnum as usize)
-            .map_err(|_| Error::ReadProgramHeader)?
-    };
+            .map_err(|_| Error::ReadProgramHeader)
+    }
+}
+
+/// Returns the 32-bit entry point of a vmlinux elf image that supports the PVH boot protocol, or
+/// `None` if the image has no such entry point.
+///
+/// # Arguments
+///
+/// * `kernel_image` - Input vmlinux image.
+pub fn get_pvh_entry<F>(kernel_image: &mut F) -> Result<Option<u64>>
+where
+    F: Read + Seek,
+{
+    let phdrs = read_program_headers(kernel_image)?;
+
+    for phdr in &phdrs {
+        if phdr.p_type != elf::PT_NOTE {
+            continue;
+        }
+
+        kernel_image
+            .seek(SeekFrom::Start(phdr.p_offset))
+            .map_err(|_| Error::SeekNote)?;
+        let mut notes = vec![0u8; phdr.p_filesz as usize];
+        kernel_image
+            .read_exact(&mut notes)
+            .map_err(|_| Error::ReadNote)?;
+
+        // Each note is a header of 32-bit name size, descriptor size and type, followed by the
+        // name and the descriptor, each padded to 4 bytes.
+        let mut offset = 0;
+        while offset + 12 <= notes.len() {
+            let read_word = |at: usize| {
+                let mut word = [0u8; 4];
+                word.copy_from_slice(&notes[at..at + 4]);
+                u32::from_le_bytes(word)
+            };
+            let name_size = read_word(offset) as usize;
+            let desc_size = read_word(offset + 4) as usize;
+            let note_type = read_word(offset + 8);
+            let name_start = offset + 12;
+            let desc_start = name_start + ((name_size + 3) & !3);
+            let desc_end = desc_start + desc_size;
+            if desc_end > notes.len() {
+                return Err(Error::ReadNote);
+            }
+
+            if &notes[name_start..name_start + name_size] == XEN_ELFNOTE_NAME
+                && note_type == XEN_ELFNOTE_PHYS32_ENTRY
+            {
+                // The entry point is a 32-bit address, but may be stored in a 64-bit word.
+                if desc_size < 4 {
+                    return Err(Error::ReadNote);
+                }
+                return Ok(Some(u64::from(read_word(desc_start))));
+            }
+
+            offset = desc_start + ((desc_size + 3) & !3);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Loads a kernel from a vmlinux elf image to a slice
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory region the kernel is written to.
+/// * `kernel_start` - The offset into `guest_mem` at which to load the kernel.
+/// * `kernel_image` - Input vmlinux image.
+pub fn load_kernel<F>(
+    guest_mem: &GuestMemory,
+    kernel_start: GuestAddress,
+    kernel_image: &mut F,
+) -> Result<u64>
+where
+    F: Read + Seek + AsRawDescriptor,
+{
+    let phdrs = read_program_headers(kernel_image)?;
 
     let mut kernel_end = 0;
 
@@ -291,4 +369,67 @@ mod test {
             load_kernel(&gm, kernel_addr, &mut bad_image)
         );
     }
+
+    #[test]
+    fn no_pvh_entry() {
+        let mut image = make_elf_bin();
+        assert_eq!(Ok(None), get_pvh_entry(&mut image));
+    }
+
+    #[test]
+    fn pvh_entry() {
+        // An elf header, followed by a single PT_NOTE program header holding a note that isn't
+        // the PVH entry point, then the PVH entry point note.
+        let ehdr_size = mem::size_of::<elf::Elf64_Ehdr>();
+        let phdr_size = mem::size_of::<elf::Elf64_Phdr>();
+        let mut notes = Vec::new();
+        for (name, note_type, desc) in &[
+            (&b"GNU\0"[..], 3u32, &[0xaau8; 6][..]),
+            (
+                XEN_ELFNOTE_NAME,
+                XEN_ELFNOTE_PHYS32_ENTRY,
+                &0x1234u64.to_le_bytes()[..],
+            ),
+        ] {
+            notes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+            notes.extend_from_slice(&note_type.to_le_bytes());
+            notes.extend_from_slice(name);
+            notes.extend_from_slice(desc);
+            notes.resize((notes.len() + 3) & !3, 0);
+        }
+
+        let mut ehdr: elf::Elf64_Ehdr = Default::default();
+        ehdr.e_ident[..4].copy_from_slice(b"\x7fELF");
+        ehdr.e_ident[elf::EI_DATA as usize] = elf::ELFDATA2LSB as u8;
+        ehdr.e_phoff = ehdr_size as u64;
+        ehdr.e_phentsize = phdr_size as u16;
+        ehdr.e_phnum = 1;
+        let phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_NOTE,
+            p_offset: (ehdr_size + phdr_size) as u64,
+            p_filesz: notes.len() as u64,
+            ..Default::default()
+        };
+
+        let mut image = tempfile().expect("failed to create tempfile");
+        // Safe because the headers are POD structs that are only read.
+        unsafe {
+            image
+                .write_all(std::slice::from_raw_parts(
+                    &ehdr as *const _ as *const u8,
+                    ehdr_size,
+                ))
+                .unwrap();
+            image
+                .write_all(std::slice::from_raw_parts(
+                    &phdr as *const _ as *const u8,
+                    phdr_size,
+                ))
+                .unwrap();
+        }
+        image.write_all(&notes).unwrap();
+
+        assert_eq!(Ok(Some(0x1234)), get_pvh_entry(&mut image));
+    }
 }
@@ -120,4 +120,8 @@ pub enum Cap {
     S390UserSigp = KVM_CAP_S390_USER_SIGP,
     ImmediateExit = KVM_CAP_IMMEDIATE_EXIT,
     ArmPmuV3 = KVM_CAP_ARM_PMU_V3,
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmPvTime = KVM_CAP_STEAL_TIME,
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ArmProtectedVm = KVM_CAP_ARM_PROTECTED_VM,
 }
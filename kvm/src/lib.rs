@@ -277,7 +277,7 @@ impl Vm {
                     set_user_memory_region(
                         &vm_file,
                         index as u32,
-                        false,
+                        guest_mem.is_read_only(guest_addr),
                         false,
                         guest_addr.offset() as u64,
                         size as u64,
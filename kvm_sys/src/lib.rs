@@ -53,6 +53,23 @@ pub mod aarch64 {
     use base::{ioctl_ior_nr, ioctl_iow_nr};
     pub use bindings::*;
 
+    // Stolen time support was added to KVM (Linux 5.10) after the bindings were generated.
+    pub const KVM_CAP_STEAL_TIME: u32 = 187;
+    pub const KVM_ARM_VCPU_PVTIME_CTRL: u32 = 2;
+    pub const KVM_ARM_VCPU_PVTIME_IPA: u32 = 0;
+
+    // Protected VMs are only supported by the protected KVM (pKVM) of Android kernels.
+    pub const KVM_CAP_ARM_PROTECTED_VM: u32 = 0xffbadab1;
+    pub const KVM_CAP_ARM_PROTECTED_VM_FLAGS_SET_FW_IPA: u32 = 0;
+    pub const KVM_CAP_ARM_PROTECTED_VM_FLAGS_INFO: u32 = 1;
+
+    #[repr(C)]
+    #[derive(Debug, Default, Copy, Clone)]
+    pub struct kvm_protected_vm_info {
+        pub firmware_size: u64,
+        pub reserved: [u64; 7],
+    }
+
     ioctl_iow_nr!(KVM_ARM_SET_DEVICE_ADDR, KVMIO, 0xab, kvm_arm_device_addr);
     ioctl_iow_nr!(KVM_ARM_VCPU_INIT, KVMIO, 0xae, kvm_vcpu_init);
     ioctl_ior_nr!(KVM_ARM_PREFERRED_TARGET, KVMIO, 0xaf, kvm_vcpu_init);
@@ -42,6 +42,9 @@ pub enum MsgError {
     /// Trying to serialize/deserialize, but msg buffer size is too small. This typically happens
     /// when msg_size() returns a value that is too small.
     WrongMsgBufferSize,
+    /// Trying to store a msg that can hold descriptors in a plain buffer, where they would mean
+    /// nothing.
+    UsesDescriptor,
 }
 
 pub type MsgResult<T> = result::Result<T, MsgError>;
@@ -68,6 +71,7 @@ impl Display for MsgError {
             }
             WrongDescriptorBufferSize => write!(f, "descriptor buffer size too small"),
             WrongMsgBufferSize => write!(f, "msg buffer size too small"),
+            UsesDescriptor => write!(f, "msg with descriptors can't be stored in a buffer"),
         }
     }
 }
@@ -129,6 +133,41 @@ pub trait MsgOnSocket: Sized {
     fn write_to_buffer(&self, buffer: &mut [u8], fds: &mut [RawDescriptor]) -> MsgResult<usize>;
 }
 
+/// Serializes `msg` to a plain byte buffer, for msgs that are stored rather than sent, such as the
+/// state in a VM snapshot. Msgs that can hold descriptors are refused.
+pub fn serialize_to_vec<M: MsgOnSocket>(msg: &M) -> MsgResult<Vec<u8>> {
+    if M::uses_descriptor() {
+        return Err(MsgError::UsesDescriptor);
+    }
+    let mut buffer = vec![0; msg.msg_size()];
+    msg.write_to_buffer(&mut buffer, &mut [])?;
+    Ok(buffer)
+}
+
+/// Deserializes a msg written by `serialize_to_vec`, which must take up all of `buffer`.
+pub fn deserialize_from_slice<M: MsgOnSocket>(buffer: &[u8]) -> MsgResult<M> {
+    if M::uses_descriptor() {
+        return Err(MsgError::UsesDescriptor);
+    }
+    if let Some(size) = M::fixed_size() {
+        if buffer.len() != size {
+            return Err(MsgError::BadRecvSize {
+                expected: size,
+                actual: buffer.len(),
+            });
+        }
+    }
+    // Safe because `M` has no descriptors to read, so there are none to be invalid.
+    let (msg, _) = unsafe { M::read_from_buffer(buffer, &[])? };
+    if msg.msg_size() != buffer.len() {
+        return Err(MsgError::BadRecvSize {
+            expected: msg.msg_size(),
+            actual: buffer.len(),
+        });
+    }
+    Ok(msg)
+}
+
 impl MsgOnSocket for SysError {
     fn fixed_size() -> Option<usize> {
         Some(size_of::<u32>())
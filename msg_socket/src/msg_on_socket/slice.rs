@@ -6,7 +6,7 @@ use base::RawDescriptor;
 use std::mem::{size_of, ManuallyDrop, MaybeUninit};
 use std::ptr::drop_in_place;
 
-use crate::{MsgOnSocket, MsgResult};
+use crate::{MsgError, MsgOnSocket, MsgResult};
 
 use super::{simple_read, simple_write};
 
@@ -130,6 +130,39 @@ impl<T: MsgOnSocket> MsgOnSocket for Vec<T> {
     }
 }
 
+impl MsgOnSocket for String {
+    fn fixed_size() -> Option<usize> {
+        None
+    }
+
+    fn msg_size(&self) -> usize {
+        size_of::<u64>() + self.len()
+    }
+
+    unsafe fn read_from_buffer(buffer: &[u8], _fds: &[RawDescriptor]) -> MsgResult<(Self, usize)> {
+        let mut offset = 0;
+        let len = simple_read::<u64>(buffer, &mut offset)? as usize;
+        let end = offset
+            .checked_add(len)
+            .ok_or(MsgError::WrongMsgBufferSize)?;
+        let bytes = buffer
+            .get(offset..end)
+            .ok_or(MsgError::WrongMsgBufferSize)?;
+        let s = String::from_utf8(bytes.to_vec()).map_err(|_| MsgError::InvalidType)?;
+        Ok((s, 0))
+    }
+
+    fn write_to_buffer(&self, buffer: &mut [u8], _fds: &mut [RawDescriptor]) -> MsgResult<usize> {
+        let mut offset = 0;
+        simple_write(self.len() as u64, buffer, &mut offset)?;
+        buffer
+            .get_mut(offset..offset + self.len())
+            .ok_or(MsgError::WrongMsgBufferSize)?
+            .copy_from_slice(self.as_bytes());
+        Ok(0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,4 +214,20 @@ mod tests {
 
         assert_eq!(vec, read_vec);
     }
+
+    #[test]
+    fn read_write_string() {
+        let s = String::from("serial 0x3f8");
+        let mut buffer = vec![0; s.msg_size()];
+        s.write_to_buffer(&mut buffer, &mut []).unwrap();
+        let read_s = unsafe { String::read_from_buffer(&buffer, &[]) }.unwrap().0;
+        assert_eq!(s, read_s);
+    }
+
+    #[test]
+    fn read_string_bad_len() {
+        let mut buffer = vec![0; size_of::<u64>()];
+        simple_write(u64::MAX, &mut buffer, &mut 0).unwrap();
+        assert!(unsafe { String::read_from_buffer(&buffer, &[]) }.is_err());
+    }
 }
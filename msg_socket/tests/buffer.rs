@@ -0,0 +1,57 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use base::{Event, RawDescriptor};
+
+use msg_socket::*;
+
+#[derive(MsgOnSocket, Debug, PartialEq)]
+struct State {
+    field0: u8,
+    field1: Vec<u32>,
+    field2: Option<u64>,
+}
+
+#[test]
+fn buffer_round_trip() {
+    let state = State {
+        field0: 3,
+        field1: vec![1, 2, 3],
+        field2: Some(0xf0f0),
+    };
+    let buffer = serialize_to_vec(&state).unwrap();
+    assert_eq!(deserialize_from_slice::<State>(&buffer).unwrap(), state);
+}
+
+#[test]
+fn buffer_wrong_size() {
+    let buffer = serialize_to_vec(&0x1234u32).unwrap();
+    assert!(matches!(
+        deserialize_from_slice::<u64>(&buffer),
+        Err(MsgError::BadRecvSize {
+            expected: 8,
+            actual: 4
+        })
+    ));
+
+    let mut buffer = serialize_to_vec(&vec![1u16, 2]).unwrap();
+    buffer.push(0);
+    assert!(matches!(
+        deserialize_from_slice::<Vec<u16>>(&buffer),
+        Err(MsgError::BadRecvSize { .. })
+    ));
+}
+
+#[test]
+fn buffer_refuses_descriptors() {
+    let evt = Event::new().unwrap();
+    assert!(matches!(
+        serialize_to_vec(&evt),
+        Err(MsgError::UsesDescriptor)
+    ));
+    assert!(matches!(
+        deserialize_from_slice::<Event>(&[]),
+        Err(MsgError::UsesDescriptor)
+    ));
+}
@@ -12,7 +12,7 @@ use std::os::raw::*;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str::FromStr;
 
-use libc::EPERM;
+use libc::{EINVAL, EPERM};
 
 use base::Error as SysError;
 use base::FileReadWriteVolatile;
@@ -192,6 +192,34 @@ impl Tap {
         })
     }
 
+    /// Attaches to the existing tap interface `name`, such as a persistent tap created for an
+    /// unprivileged crosvm. `vnet_hdr` and `multi_vq` must match how the tap was created.
+    pub fn new_with_name(name: &[u8], vnet_hdr: bool, multi_vq: bool) -> Result<Tap> {
+        // The name needs room for its nul terminator.
+        if name.is_empty() || name.len() >= net_sys::IFNAMSIZ as usize || name.contains(&0) {
+            return Err(Error::CreateTap(SysError::new(EINVAL)));
+        }
+
+        let mut ifreq: net_sys::ifreq = Default::default();
+        // Safe because only the name and flags of the unions are accessed, and the name was
+        // checked to fit.
+        unsafe {
+            let ifrn_name = ifreq.ifr_ifrn.ifrn_name.as_mut();
+            for (dst, src) in ifrn_name.iter_mut().zip(name.iter()) {
+                *dst = *src as c_char;
+            }
+            ifreq.ifr_ifru.ifru_flags = (net_sys::IFF_TAP
+                | net_sys::IFF_NO_PI
+                | if vnet_hdr { net_sys::IFF_VNET_HDR } else { 0 })
+                as c_short;
+            if multi_vq {
+                ifreq.ifr_ifru.ifru_flags |= net_sys::IFF_MULTI_QUEUE as c_short;
+            }
+        }
+
+        Tap::create_tap_with_ifreq(&mut ifreq)
+    }
+
     fn create_tap_with_ifreq(ifreq: &mut net_sys::ifreq) -> Result<Tap> {
         // Open calls are safe because we give a constant nul-terminated
         // string and verify the result.
@@ -655,6 +683,13 @@ mod tests {
         Tap::new(true, false).unwrap();
     }
 
+    #[test]
+    fn tap_new_with_name() {
+        assert!(Tap::new_with_name(b"", true, false).is_err());
+        assert!(Tap::new_with_name(b"name_too_long_tap", true, false).is_err());
+        assert!(Tap::new_with_name(b"vm\0tap", true, false).is_err());
+    }
+
     #[test]
     fn tap_configure() {
         let tap = Tap::new(true, false).unwrap();
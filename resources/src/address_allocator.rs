@@ -164,6 +164,17 @@ impl AddressAllocator {
             .map_or_else(|| Err(Error::BadAlloc(alloc)), |v| self.insert_at(v.0, v.1))
     }
 
+    /// Releases the allocation that contains `value` back to the free pool.
+    pub fn release_containing(&mut self, value: u64) -> Result<()> {
+        let alloc = self
+            .allocs
+            .iter()
+            .find(|(_, &(start, size, _))| start <= value && value - start < size)
+            .map(|(&alloc, _)| alloc)
+            .ok_or(Error::OutOfBounds)?;
+        self.release(alloc)
+    }
+
     /// Returns allocation associated with `alloc`, or None if no such allocation exists.
     pub fn get(&self, alloc: &Alloc) -> Option<&(u64, u64, String)> {
         self.allocs.get(alloc)
@@ -301,6 +312,17 @@ impl<'a> AddressAllocatorSet<'a> {
         last_res
     }
 
+    pub fn release_containing(&mut self, value: u64) -> Result<()> {
+        let mut last_res = Err(Error::OutOfBounds);
+        for allocator in self.allocators.iter_mut() {
+            last_res = allocator.release_containing(value);
+            if last_res.is_ok() {
+                return last_res;
+            }
+        }
+        last_res
+    }
+
     pub fn get(&self, alloc: &Alloc) -> Option<&(u64, u64, String)> {
         for allocator in self.allocators.iter() {
             let opt = allocator.get(alloc);
@@ -394,6 +416,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn release_containing() {
+        let mut pool = AddressAllocator::new(0x1000, 0x1000, Some(0x100)).unwrap();
+        assert_eq!(
+            pool.allocate(0x200, Alloc::Anon(0), String::from("bar0")),
+            Ok(0x1000)
+        );
+        assert_eq!(
+            pool.allocate(0x100, Alloc::Anon(1), String::from("bar1")),
+            Ok(0x1200)
+        );
+        assert_eq!(pool.release_containing(0x1300), Err(Error::OutOfBounds));
+        assert_eq!(pool.release_containing(0x11ff), Ok(()));
+        assert_eq!(pool.get(&Alloc::Anon(0)), None);
+        assert_eq!(
+            pool.allocate(0x200, Alloc::Anon(2), String::from("bar2")),
+            Ok(0x1000)
+        );
+    }
+
     #[test]
     fn allocate_and_split_allocate_at() {
         let mut pool = AddressAllocator::new(0x1000, 0x1000, Some(0x100)).unwrap();
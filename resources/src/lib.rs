@@ -33,6 +33,8 @@ pub enum Alloc {
     PmemDevice(usize),
     /// pstore region.
     Pstore,
+    /// virtio-mem hotplug region.
+    VirtioMem,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -7,6 +7,11 @@ use base::pagesize;
 use crate::address_allocator::{AddressAllocator, AddressAllocatorSet};
 use crate::{Alloc, Error, Result};
 
+// The number of GSIs that can be handed out, which is as many as KVM can route
+// (`KVM_MAX_IRQ_ROUTES`). MSI vectors each take one, so devices with many queues and hot-added
+// devices need far more than the legacy interrupt lines.
+const MAX_IRQS: u64 = 4096;
+
 /// Manages allocating system resources such as address space and interrupt numbers.
 ///
 /// # Example - Use the `SystemAddress` builder.
@@ -96,7 +101,7 @@ impl SystemAllocator {
             pci_allocator: AddressAllocator::new(8, (256 * 32 * 8) - 8, Some(8))?,
             irq_allocator: AddressAllocator::new(
                 first_irq as u64,
-                1024 - first_irq as u64,
+                MAX_IRQS - first_irq as u64,
                 Some(1),
             )?,
             next_anon_id: 0,
@@ -125,6 +130,11 @@ impl SystemAllocator {
             .is_ok()
     }
 
+    /// Releases an irq number previously reserved with `allocate_irq` or `reserve_irq`.
+    pub fn release_irq(&mut self, irq: u32) -> bool {
+        self.irq_allocator.release_containing(irq as u64).is_ok()
+    }
+
     /// Allocate PCI slot location.
     pub fn allocate_pci(&mut self, tag: String) -> Option<Alloc> {
         let id = self.get_anon_alloc();
@@ -156,6 +166,12 @@ impl SystemAllocator {
         }
     }
 
+    /// Releases a PCI slot location previously reserved with `allocate_pci` or `reserve_pci`.
+    pub fn release_pci(&mut self, bus: u8, dev: u8, func: u8) -> bool {
+        let bdf = ((bus as u64) << 8) | ((dev as u64) << 3) | (func as u64);
+        self.pci_allocator.release_containing(bdf).is_ok()
+    }
+
     /// Gets an allocator to be used for IO memory.
     pub fn io_allocator(&mut self) -> Option<&mut AddressAllocator> {
         self.io_address_space.as_mut()
@@ -206,9 +206,11 @@ struct RutabagaCapsetInfo {
 /// Not thread-safe, but can be made so easily.  Making non-Rutabaga, C/C++ components
 /// thread-safe is more difficult.
 pub struct Rutabaga {
+    // Contexts and resources are declared before the components so they are dropped first, since
+    // they may refer to state owned by a component.
+    contexts: Map<u32, Box<dyn RutabagaContext>>,
     resources: Map<u32, RutabagaResource>,
     components: Map<RutabagaComponentType, Box<dyn RutabagaComponent>>,
-    contexts: Map<u32, Box<dyn RutabagaContext>>,
     default_component: RutabagaComponentType,
     capset_info: Vec<RutabagaCapsetInfo>,
 }
@@ -234,6 +236,15 @@ impl Rutabaga {
         Ok(self.capset_info[idx])
     }
 
+    /// Returns true if this instance can be dropped and replaced by a new one built from the same
+    /// `RutabagaBuilder`.  gfxstream can't be torn down, so it can only be initialized once per
+    /// process.
+    pub fn supports_rebuild(&self) -> bool {
+        !self
+            .components
+            .contains_key(&RutabagaComponentType::Gfxstream)
+    }
+
     /// Gets the version and size for the capabilty set `index`.
     pub fn get_capset_info(&self, index: u32) -> RutabagaResult<(u32, u32, u32)> {
         let capset_info = self.capset_index_to_component_info(index)?;
@@ -630,6 +641,7 @@ impl Rutabaga {
 }
 
 /// Rutabaga Builder, following the Rust builder pattern.
+#[derive(Clone)]
 pub struct RutabagaBuilder {
     display_width: Option<u32>,
     display_height: Option<u32>,
@@ -690,9 +702,10 @@ impl RutabagaBuilder {
 
     /// Builds Rutabaga and returns a handle to it.
     ///
-    /// This should be only called once per every virtual machine instance.  Rutabaga tries to
-    /// intialize all 3D components which have been built. In 2D mode, only the 2D component is
-    /// initialized.
+    /// This should be only called once per every virtual machine instance, unless the previous
+    /// instance has been dropped and `Rutabaga::supports_rebuild` returned true for it.  Rutabaga
+    /// tries to intialize all 3D components which have been built. In 2D mode, only the 2D
+    /// component is initialized.
     pub fn build(self) -> RutabagaResult<Rutabaga> {
         let mut rutabaga_components: Map<RutabagaComponentType, Box<dyn RutabagaComponent>> =
             Default::default();
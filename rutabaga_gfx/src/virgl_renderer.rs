@@ -33,9 +33,13 @@ use libc::close;
 
 type Query = virgl_renderer_export_query;
 
+// virglrenderer is a global state backed library, so only one `VirglRenderer` may exist at a time.
+static INIT_ONCE: AtomicBool = AtomicBool::new(false);
+
 /// The virtio-gpu backend state tracker which supports accelerated rendering.
 pub struct VirglRenderer {
     fence_state: Rc<RefCell<FenceState>>,
+    cookie: *mut VirglCookie,
 }
 
 struct VirglRendererContext {
@@ -86,6 +90,18 @@ impl Drop for VirglRendererContext {
     }
 }
 
+impl Drop for VirglRenderer {
+    fn drop(&mut self) {
+        // Safe because virglrenderer was initialized by `init` and all of its contexts have been
+        // destroyed. After cleanup, virglrenderer no longer uses the cookie.
+        unsafe {
+            virgl_renderer_cleanup(self.cookie as *mut c_void);
+            drop(Box::from_raw(self.cookie));
+        }
+        INIT_ONCE.store(false, Ordering::Release);
+    }
+}
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 extern "C" fn debug_callback(fmt: *const ::std::os::raw::c_char, ap: *mut __va_list_tag) {
     let len: u32 = 256;
@@ -178,17 +194,15 @@ impl VirglRenderer {
         }
 
         // virglrenderer is a global state backed library that uses thread bound OpenGL contexts.
-        // Initialize it only once and use the non-send/non-sync Renderer struct to keep things tied
-        // to whichever thread called this function first.
-        static INIT_ONCE: AtomicBool = AtomicBool::new(false);
+        // Initialize it only once at a time and use the non-send/non-sync Renderer struct to keep
+        // things tied to whichever thread called this function.
         if INIT_ONCE.compare_and_swap(false, true, Ordering::Acquire) {
             return Err(RutabagaError::AlreadyInUse);
         }
 
-        // Cookie is intentionally never freed because virglrenderer never gets uninitialized.
-        // Otherwise, Resource and Context would become invalid because their lifetime is not tied
-        // to the Renderer instance. Doing so greatly simplifies the ownership for users of this
-        // library.
+        // The cookie is freed when the renderer is dropped, after virglrenderer is cleaned up and
+        // can no longer call back into it. `Rutabaga` drops its contexts and resources before its
+        // components, so none of them outlive the renderer.
 
         let fence_state = Rc::new(RefCell::new(FenceState { latest_fence: 0 }));
 
@@ -211,8 +225,17 @@ impl VirglRenderer {
             )
         };
 
-        ret_to_res(ret)?;
-        Ok(Box::new(VirglRenderer { fence_state }))
+        if let Err(e) = ret_to_res(ret) {
+            // Safe because virglrenderer failed to initialize, so it holds no reference to the
+            // cookie.
+            drop(unsafe { Box::from_raw(cookie) });
+            INIT_ONCE.store(false, Ordering::Release);
+            return Err(e);
+        }
+        Ok(Box::new(VirglRenderer {
+            fence_state,
+            cookie,
+        }))
     }
 
     #[allow(unused_variables)]
@@ -0,0 +1,259 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! VM definitions read from a JSON file with `crosvm run --cfg`.
+//!
+//! The file is an object whose keys are the long names of `crosvm run` options. A string or number
+//! is passed as the option's value, `true` turns a flag on, and an array gives an option once for
+//! each element, in order. `false` and `null` leave an option out, which is how a file drops an
+//! option it would otherwise get from an include. The kernel is given with the `kernel` key.
+//!
+//! `include` names other files, relative to the including one, that are read first in the order
+//! given. A key in a file replaces all values of that key from its includes, and a later include
+//! replaces keys of an earlier one. For example:
+//!
+//! ```json
+//! {
+//!     "include": "base.json",
+//!     "mem": 4096,
+//!     "rwdisk": ["/var/vm/root.img", "/var/vm/data.img"],
+//!     "disable-sandbox": true
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+const INCLUDE_KEY: &str = "include";
+const KERNEL_KEY: &str = "kernel";
+// The option this file is given with, which makes no sense inside of one.
+const CFG_KEY: &str = "cfg";
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file includes itself, directly or through other includes.
+    IncludeCycle(PathBuf),
+    /// The value of a key can't be turned into an option.
+    InvalidValue {
+        path: PathBuf,
+        key: String,
+    },
+    /// The top level of a file isn't a JSON object.
+    NotAnObject(PathBuf),
+    Parse(PathBuf, serde_json::Error),
+    Read(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            IncludeCycle(p) => write!(f, "{} includes itself", p.display()),
+            InvalidValue { path, key } => write!(
+                f,
+                "invalid value for `{}` in {}: expected a string, number, boolean or an array of \
+                 strings and numbers",
+                key,
+                path.display()
+            ),
+            NotAnObject(p) => write!(f, "{} does not contain a JSON object", p.display()),
+            Parse(p, e) => write!(f, "failed to parse {}: {}", p.display(), e),
+            Read(p, e) => write!(f, "failed to read {}: {}", p.display(), e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The kernel is stored under the name `set_arguments` gives positional arguments.
+const KERNEL_OPTION: &str = "";
+
+/// The options of a configuration file, with its includes resolved.
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    // Each option maps to its values. A flag that is on has a single `None` value.
+    options: BTreeMap<String, Vec<Option<String>>>,
+}
+
+impl ConfigFile {
+    /// Reads the file at `path` and everything it includes.
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let options = load_options(path, &mut Vec::new())?;
+        Ok(ConfigFile { options })
+    }
+
+    /// Returns the options as command line arguments, leaving out those for which `given` returns
+    /// true. `given` is called with the empty string for the kernel.
+    pub fn to_args<F: Fn(&str) -> bool>(&self, given: F) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut kernel = None;
+        for (name, values) in &self.options {
+            if given(name) {
+                continue;
+            }
+            if name == KERNEL_OPTION {
+                kernel = values.first().cloned().flatten();
+                continue;
+            }
+            for value in values {
+                args.push(match value {
+                    Some(v) => format!("--{}={}", name, v),
+                    None => format!("--{}", name),
+                });
+            }
+        }
+        // Everything after `--` is positional, even if it starts with a dash.
+        if let Some(kernel) = kernel {
+            args.push("--".to_owned());
+            args.push(kernel);
+        }
+        args
+    }
+}
+
+fn option_values(path: &Path, key: &str, value: Value) -> Result<Vec<Option<String>>> {
+    let invalid = || Error::InvalidValue {
+        path: path.to_owned(),
+        key: key.to_owned(),
+    };
+    let single = |value: Value| match value {
+        Value::String(s) if !s.is_empty() => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(invalid()),
+    };
+    Ok(match value {
+        Value::Null | Value::Bool(false) => Vec::new(),
+        Value::Bool(true) => vec![None],
+        Value::Array(values) => values
+            .into_iter()
+            .map(|v| single(v).map(Some))
+            .collect::<Result<_>>()?,
+        v => vec![Some(single(v)?)],
+    })
+}
+
+fn load_options(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<BTreeMap<String, Vec<Option<String>>>> {
+    let path = fs::canonicalize(path).map_err(|e| Error::Read(path.to_owned(), e))?;
+    if stack.contains(&path) {
+        return Err(Error::IncludeCycle(path));
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| Error::Read(path.clone(), e))?;
+    let object = match serde_json::from_str(&contents).map_err(|e| Error::Parse(path.clone(), e))? {
+        Value::Object(object) => object,
+        _ => return Err(Error::NotAnObject(path)),
+    };
+
+    let mut options = BTreeMap::new();
+    let mut own = BTreeMap::new();
+    stack.push(path.clone());
+    for (key, value) in object {
+        match key.as_str() {
+            INCLUDE_KEY => {
+                let includes = option_values(&path, &key, value)?;
+                // The canonical path always has a parent.
+                let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+                for include in includes.into_iter().flatten() {
+                    options.extend(load_options(&dir.join(include), stack)?);
+                }
+            }
+            CFG_KEY | KERNEL_OPTION => {
+                return Err(Error::InvalidValue {
+                    path: path.clone(),
+                    key,
+                })
+            }
+            KERNEL_KEY => {
+                let values = option_values(&path, &key, value)?;
+                if values.len() > 1 || values.contains(&None) {
+                    return Err(Error::InvalidValue { path, key });
+                }
+                own.insert(KERNEL_OPTION.to_owned(), values);
+            }
+            _ => {
+                own.insert(key.clone(), option_values(&path, &key, value)?);
+            }
+        }
+    }
+    stack.pop();
+
+    options.extend(own);
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_and_overrides() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("base.json"),
+            r#"{"kernel": "/boot/vmlinux", "mem": 1024, "cpus": 2, "rwdisk": ["a.img", "b.img"],
+                "disable-sandbox": true}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("vm.json"),
+            r#"{"include": "base.json", "mem": 4096, "rwdisk": ["c.img"],
+                "disable-sandbox": false, "params": ["a", "b=1"]}"#,
+        )
+        .unwrap();
+
+        let file = ConfigFile::load(&dir.path().join("vm.json")).unwrap();
+        assert_eq!(
+            file.to_args(|_| false),
+            vec![
+                "--cpus=2",
+                "--mem=4096",
+                "--params=a",
+                "--params=b=1",
+                "--rwdisk=c.img",
+                "--",
+                "/boot/vmlinux"
+            ]
+        );
+        assert_eq!(
+            file.to_args(|name| name.is_empty() || name == "mem"),
+            vec!["--cpus=2", "--params=a", "--params=b=1", "--rwdisk=c.img"]
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let load = |contents: &str| {
+            let path = dir.path().join("vm.json");
+            fs::write(&path, contents).unwrap();
+            ConfigFile::load(&path)
+        };
+        assert!(matches!(load("[]"), Err(Error::NotAnObject(_))));
+        assert!(matches!(load("{"), Err(Error::Parse(_, _))));
+        assert!(matches!(
+            load(r#"{"gpu": {"width": 800}}"#),
+            Err(Error::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(r#"{"cfg": "other.json"}"#),
+            Err(Error::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(r#"{"include": "vm.json"}"#),
+            Err(Error::IncludeCycle(_))
+        ));
+        assert!(matches!(
+            load(r#"{"include": "missing.json"}"#),
+            Err(Error::Read(_, _))
+        ));
+    }
+}
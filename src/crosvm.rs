@@ -6,27 +6,41 @@
 //! configs.
 
 pub mod argument;
+pub mod config_file;
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 pub mod gdb;
+pub mod metrics_server;
+pub mod net_helper;
 #[path = "linux.rs"]
 pub mod platform;
 #[cfg(feature = "plugin")]
 pub mod plugin;
+pub mod registry;
+pub mod seccomp;
+pub mod usb_hotplug;
 
 use std::collections::BTreeMap;
 use std::net;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-use arch::{Pstore, SerialHardware, SerialParameters, VcpuAffinity};
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use arch::GdbAddress;
+use arch::{CpuFeatures, NumaNode, Pstore, SerialHardware, SerialParameters, VcpuAffinity};
 use devices::virtio::fs::passthrough;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
 #[cfg(feature = "audio")]
+use devices::virtio::snd::SoundParameters;
+use devices::virtio::RngParameters;
+#[cfg(feature = "audio")]
 use devices::Ac97Parameters;
 use libc::{getegid, geteuid};
-use vm_control::BatteryType;
+use vm_control::{BatteryType, VmRequestAccess};
+
+use crate::metrics_server::MetricsAddr;
 
 static SECCOMP_POLICY_DIR: &str = "/usr/share/policy/crosvm";
 
@@ -41,6 +55,100 @@ pub enum Executable {
     Plugin(PathBuf),
 }
 
+/// The hypervisor that runs a VM, selected with `--hypervisor`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HypervisorKind {
+    /// The Linux Kernel-based Virtual Machine.
+    Kvm,
+}
+
+impl FromStr for HypervisorKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kvm" => Ok(HypervisorKind::Kvm),
+            _ => Err("hypervisor must be kvm"),
+        }
+    }
+}
+
+/// Contents of a file handed to the BIOS through fw_cfg.
+#[derive(Debug, PartialEq)]
+pub enum FwCfgData {
+    /// The contents of a host file.
+    File(PathBuf),
+    /// A literal string.
+    String(String),
+}
+
+/// Host bounds on the memory a guest agent may ask to keep through the balloon.
+#[derive(Debug, Default, PartialEq)]
+pub struct BalloonGuestRequests {
+    /// The least memory, in bytes, that a guest request can shrink the guest to.
+    pub min_bytes: u64,
+    /// The most memory, in bytes, that a guest request can grow the guest to. All of guest memory
+    /// if unset.
+    pub max_bytes: Option<u64>,
+}
+
+/// A named preset of VM settings, selected with `--profile`.
+///
+/// A profile only fills in settings that were not given on the command line, so explicit flags
+/// always win regardless of their order relative to `--profile`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    /// An interactive VM: a few vCPUs, room for applications and a balloon the guest can resize.
+    Desktop,
+    /// A VM serving network traffic: more vCPUs and memory, and one net queue pair per vCPU.
+    Server,
+    /// The smallest useful VM: one vCPU and the default memory size.
+    Embedded,
+    /// A throwaway VM for tests: small, and seccomp failures are logged instead of fatal.
+    Test,
+}
+
+impl FromStr for Profile {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "desktop" => Ok(Profile::Desktop),
+            "server" => Ok(Profile::Server),
+            "embedded" => Ok(Profile::Embedded),
+            "test" => Ok(Profile::Test),
+            _ => Err("profile must be one of desktop, server, embedded or test"),
+        }
+    }
+}
+
+impl Profile {
+    /// Fills in the settings of `cfg` that this profile covers and that are still unset.
+    pub fn apply(self, cfg: &mut Config) {
+        let (vcpu_count, memory_mib) = match self {
+            Profile::Desktop => (2, 4096),
+            Profile::Server => (4, 8192),
+            Profile::Embedded => (1, 256),
+            Profile::Test => (1, 512),
+        };
+        let vcpu_count = *cfg.vcpu_count.get_or_insert(vcpu_count);
+        cfg.memory.get_or_insert(memory_mib);
+
+        match self {
+            Profile::Desktop if cfg.balloon => {
+                cfg.balloon_guest_requests
+                    .get_or_insert_with(BalloonGuestRequests::default);
+            }
+            Profile::Desktop => {}
+            Profile::Server => {
+                cfg.net_vq_pairs.get_or_insert(vcpu_count as u16);
+            }
+            Profile::Embedded => {}
+            Profile::Test => cfg.seccomp_log_failures = true,
+        }
+    }
+}
+
 /// Maximum length of a `DiskOption` identifier.
 ///
 /// This is based on the virtio-block ID length limit.
@@ -54,6 +162,40 @@ pub struct DiskOption {
     pub id: Option<[u8; DISK_ID_LEN]>,
 }
 
+/// The tap device `crosvm device net` serves.
+pub enum NetDeviceTap {
+    /// The existing tap interface with this name.
+    Name(String),
+    /// The tap device already open at this fd.
+    Fd(RawFd),
+    /// A new tap interface, configured with these host addresses.
+    New {
+        host_ip: net::Ipv4Addr,
+        netmask: net::Ipv4Addr,
+        mac_address: net_util::MacAddress,
+    },
+}
+
+/// A virtio device served by a vhost-user backend, given with `--vhost-user`.
+pub struct VhostUserOption {
+    /// The virtio device type, such as `TYPE_BLOCK`.
+    pub device_type: u32,
+    /// The unix socket the backend listens on.
+    pub socket_path: PathBuf,
+    /// The size of the shared memory region the backend maps files into, if the device has one.
+    pub shm_size: Option<u64>,
+    /// The ID of the shared memory region, which the driver looks it up by.
+    pub shm_id: u8,
+}
+
+/// A host platform device passed through to the guest with vfio-platform.
+pub struct VfioPlatformOption {
+    /// Path to the device in sysfs.
+    pub path: PathBuf,
+    /// Whether to describe the device to the guest in the device tree.
+    pub dt_node: bool,
+}
+
 /// A bind mount for directories in the plugin process.
 pub struct BindMount {
     pub src: PathBuf,
@@ -61,6 +203,30 @@ pub struct BindMount {
     pub writable: bool,
 }
 
+/// User namespace mappings for the jail of a device, each in the
+/// "inner outer count[,inner outer count]" format of `/proc/<pid>/uid_map`. Devices run as nobody
+/// without one.
+#[derive(Debug, Default, PartialEq)]
+pub struct JailIdMaps {
+    pub uid_map: Option<String>,
+    pub gid_map: Option<String>,
+}
+
+/// The user or group a `ControlPeer` matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlPeerId {
+    Uid(libc::uid_t),
+    /// Matches peers by their effective group, not their supplementary groups.
+    Gid(libc::gid_t),
+}
+
+/// A user or group allowed to connect to the control socket, and the requests it may make.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControlPeer {
+    pub id: ControlPeerId,
+    pub access: VmRequestAccess,
+}
+
 /// A mapping of linux group IDs for the plugin process.
 pub struct GidMap {
     pub inner: libc::gid_t,
@@ -71,6 +237,9 @@ pub struct GidMap {
 pub const DEFAULT_TOUCH_DEVICE_HEIGHT: u32 = 1024;
 pub const DEFAULT_TOUCH_DEVICE_WIDTH: u32 = 1280;
 
+/// Size of the swiotlb of protected VMs, unless `Config::swiotlb` overrides it.
+pub const DEFAULT_SWIOTLB_SIZE_MIB: u64 = 64;
+
 pub struct TouchDeviceOption {
     path: PathBuf,
     width: Option<u32>,
@@ -174,91 +343,173 @@ impl Default for SharedDir {
 /// Aggregate of all configurable options for a running VM.
 pub struct Config {
     pub vcpu_count: Option<usize>,
+    pub max_vcpu_count: Option<usize>,
     pub rt_cpus: Vec<usize>,
+    /// Host CPUs that everything but the VCPUs runs on, if limited.
+    pub housekeeping_cpus: Vec<usize>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    /// Whether guest memory is locked in host memory, so it is never paged out.
+    pub lock_guest_memory: bool,
+    /// Whether KSM may merge identical pages of guest memory.
+    pub merge_guest_memory: bool,
+    /// Whether the VM can be saved with `crosvm snapshot`, which every device must support.
+    pub snapshot: bool,
+    /// CPU model and feature changes from `--cpu-model` and `--cpu-features`.
+    pub cpu_features: CpuFeatures,
     pub memory: Option<u64>,
+    /// Guest NUMA nodes from `--numa`, in order, with their memory sizes in MiB like `memory`.
+    pub numa_nodes: Vec<NumaNode>,
     pub executable_path: Option<Executable>,
     pub android_fstab: Option<PathBuf>,
+    /// Device tree overlays applied, in order, to the guest's device tree.
+    pub dtbo: Vec<PathBuf>,
     pub initrd_path: Option<PathBuf>,
     pub params: Vec<String>,
+    /// Kernel image the BIOS boots, handed over through fw_cfg.
+    pub bios_kernel_path: Option<PathBuf>,
+    /// Named files handed to the BIOS through fw_cfg.
+    pub fw_cfg_files: Vec<(String, FwCfgData)>,
+    /// Firmware device paths the BIOS boots from, in order.
+    pub boot_devices: Vec<String>,
+    /// Writable flash image placed below the BIOS, holding the UEFI variable store.
+    pub pflash_path: Option<PathBuf>,
+    /// Whether the BIOS is mapped read-only in the guest.
+    pub read_only_bios: bool,
     pub socket_path: Option<PathBuf>,
+    /// The peers other than crosvm's own user and root allowed on the control socket. Any peer
+    /// the socket's file permissions let in is allowed if empty.
+    pub control_peers: Vec<ControlPeer>,
+    /// Where device metrics are served in the Prometheus text format, if anywhere.
+    pub metrics_socket: Option<MetricsAddr>,
     pub plugin_root: Option<PathBuf>,
     pub plugin_mounts: Vec<BindMount>,
     pub plugin_gid_maps: Vec<GidMap>,
     pub disks: Vec<DiskOption>,
     pub pmem_devices: Vec<DiskOption>,
     pub pstore: Option<Pstore>,
+    /// `KEY=VALUE` pairs written to the meta-data file of the config drive.
+    pub metadata: Vec<(String, String)>,
+    /// Files placed on the config drive, as pairs of file name and host path.
+    pub metadata_files: Vec<(String, PathBuf)>,
     pub host_ip: Option<net::Ipv4Addr>,
     pub netmask: Option<net::Ipv4Addr>,
     pub mac_address: Option<net_util::MacAddress>,
     pub net_vq_pairs: Option<u16>,
     pub vhost_net: bool,
     pub tap_fd: Vec<RawFd>,
+    /// Where a privileged helper hands over already created network devices.
+    pub net_helper_socket: Option<PathBuf>,
     pub cid: Option<u64>,
+    pub vhost_user_vsock: Option<PathBuf>,
+    pub vhost_user: Vec<VhostUserOption>,
     pub wayland_socket_paths: BTreeMap<String, PathBuf>,
     pub wayland_dmabuf: bool,
     pub x_display: Option<String>,
     pub shared_dirs: Vec<SharedDir>,
     pub sandbox: bool,
+    pub landlock: bool,
     pub seccomp_policy_dir: PathBuf,
     pub seccomp_log_failures: bool,
+    pub jail_id_maps: BTreeMap<String, JailIdMaps>,
     #[cfg(feature = "gpu")]
     pub gpu_parameters: Option<GpuParameters>,
     pub software_tpm: bool,
+    pub swtpm: Option<PathBuf>,
     pub display_window_keyboard: bool,
     pub display_window_mouse: bool,
     #[cfg(feature = "audio")]
     pub ac97_parameters: Vec<Ac97Parameters>,
+    #[cfg(feature = "audio")]
+    pub sound_parameters: Option<SoundParameters>,
     pub serial_parameters: BTreeMap<(SerialHardware, u8), SerialParameters>,
     pub syslog_tag: Option<String>,
+    pub name: Option<String>,
     pub virtio_single_touch: Option<TouchDeviceOption>,
     pub virtio_multi_touch: Option<TouchDeviceOption>,
     pub virtio_trackpad: Option<TouchDeviceOption>,
     pub virtio_mouse: Option<PathBuf>,
     pub virtio_keyboard: Option<PathBuf>,
     pub virtio_input_evdevs: Vec<PathBuf>,
+    pub hypervisor: HypervisorKind,
     pub split_irqchip: bool,
     pub vfio: Vec<PathBuf>,
+    /// Puts the `vfio` devices behind a virtio-iommu, which the guest manages their DMA through.
+    pub virtio_iommu: bool,
+    pub vfio_platform: Vec<VfioPlatformOption>,
+    pub pcie_root_ports: u8,
     pub video_dec: bool,
     pub video_enc: bool,
     pub acpi_tables: Vec<PathBuf>,
     pub protected_vm: bool,
+    /// Size of the guest's swiotlb in MiB, which a protected VM bounces all device DMA through.
+    pub swiotlb: Option<u64>,
     pub battery_type: Option<BatteryType>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    pub gdb: Option<u32>,
+    pub gdb: Option<GdbAddress>,
+    /// Whether the VM has a virtio-balloon device.
+    pub balloon: bool,
     pub balloon_bias: i64,
+    pub balloon_guest_requests: Option<BalloonGuestRequests>,
+    /// Size of the virtio-mem hotplug region in MiB.
+    pub virtio_mem: Option<u64>,
+    pub profile: Option<Profile>,
+    pub rng_parameters: RngParameters,
+    pub disable_io_uring: bool,
+    pub io_uring_sqpoll: Option<Duration>,
+    pub trace_categories: Option<cros_tracing::Categories>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             vcpu_count: None,
+            max_vcpu_count: None,
             rt_cpus: Vec::new(),
+            housekeeping_cpus: Vec::new(),
             vcpu_affinity: None,
             no_smt: false,
+            lock_guest_memory: false,
+            merge_guest_memory: false,
+            snapshot: false,
+            cpu_features: CpuFeatures::default(),
             memory: None,
+            numa_nodes: Vec::new(),
             executable_path: None,
             android_fstab: None,
+            dtbo: Vec::new(),
             initrd_path: None,
+            bios_kernel_path: None,
+            fw_cfg_files: Vec::new(),
+            boot_devices: Vec::new(),
+            pflash_path: None,
+            read_only_bios: false,
             params: Vec::new(),
             socket_path: None,
+            control_peers: Vec::new(),
+            metrics_socket: None,
             plugin_root: None,
             plugin_mounts: Vec::new(),
             plugin_gid_maps: Vec::new(),
             disks: Vec::new(),
             pmem_devices: Vec::new(),
             pstore: None,
+            metadata: Vec::new(),
+            metadata_files: Vec::new(),
             host_ip: None,
             netmask: None,
             mac_address: None,
             net_vq_pairs: None,
             vhost_net: false,
             tap_fd: Vec::new(),
+            net_helper_socket: None,
             cid: None,
+            vhost_user_vsock: None,
+            vhost_user: Vec::new(),
             #[cfg(feature = "gpu")]
             gpu_parameters: None,
             software_tpm: false,
+            swtpm: None,
             wayland_socket_paths: BTreeMap::new(),
             wayland_dmabuf: false,
             x_display: None,
@@ -266,28 +517,46 @@ impl Default for Config {
             display_window_mouse: false,
             shared_dirs: Vec::new(),
             sandbox: !cfg!(feature = "default-no-sandbox"),
+            landlock: false,
             seccomp_policy_dir: PathBuf::from(SECCOMP_POLICY_DIR),
             seccomp_log_failures: false,
+            jail_id_maps: BTreeMap::new(),
             #[cfg(feature = "audio")]
             ac97_parameters: Vec::new(),
+            #[cfg(feature = "audio")]
+            sound_parameters: None,
             serial_parameters: BTreeMap::new(),
             syslog_tag: None,
+            name: None,
             virtio_single_touch: None,
             virtio_multi_touch: None,
             virtio_trackpad: None,
             virtio_mouse: None,
             virtio_keyboard: None,
             virtio_input_evdevs: Vec::new(),
+            hypervisor: HypervisorKind::Kvm,
             split_irqchip: false,
             vfio: Vec::new(),
+            vfio_platform: Vec::new(),
+            virtio_iommu: false,
+            pcie_root_ports: 0,
             video_dec: false,
             video_enc: false,
             acpi_tables: Vec::new(),
             protected_vm: false,
+            swiotlb: None,
             battery_type: None,
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             gdb: None,
+            balloon: true,
             balloon_bias: 0,
+            balloon_guest_requests: None,
+            virtio_mem: None,
+            profile: None,
+            rng_parameters: Default::default(),
+            disable_io_uring: false,
+            io_uring_sqpoll: None,
+            trace_categories: None,
         }
     }
 }
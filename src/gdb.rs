@@ -2,56 +2,260 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::net::TcpListener;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::remove_file;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::mpsc;
 use std::time::Duration;
 
+use arch::GdbAddress;
 use base::{error, info};
+use gdb_rsp::{
+    BreakpointKind, Connection, RegisterLayout, ResumeKind, StopReason, Stub, Target, TargetError,
+    TargetResult,
+};
 use msg_socket::{MsgReceiver, MsgSender};
 use sync::Mutex;
 use vm_control::{
-    VcpuControl, VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage, VmControlRequestSocket,
-    VmRequest, VmResponse,
+    BalloonControlCommand, VcpuControl, VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage,
+    VmControlRequestSocket, VmRequest, VmResponse,
 };
 use vm_memory::GuestAddress;
 
 #[cfg(target_arch = "x86_64")]
-use gdbstub::arch::x86::X86_64_SSE as GdbArch;
-use gdbstub::arch::Arch;
-use gdbstub::target::ext::base::singlethread::{ResumeAction, SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::BaseOps;
-use gdbstub::target::ext::breakpoints::{HwBreakpoint, HwBreakpointOps};
-use gdbstub::target::TargetError::NonFatal;
-use gdbstub::target::{Target, TargetResult};
-use gdbstub::Connection;
+use gdb_rsp::X86_64Registers as GdbRegisters;
+#[cfg(target_arch = "x86_64")]
+use gdbstub::arch::x86::reg::X86_64CoreRegs as CoreRegs;
+use gdbstub::arch::Registers;
+use hypervisor::{Watchpoint, WatchpointKind};
 use remain::sorted;
 use thiserror::Error as ThisError;
 
 #[cfg(target_arch = "x86_64")]
 type ArchUsize = u64;
 
-pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = match TcpListener::bind(addr.clone()) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to create a TCP listener: {}", e);
-            return;
+// The int3 instruction.
+#[cfg(target_arch = "x86_64")]
+const SW_BREAKPOINT_INSN: u8 = 0xcc;
+
+// The number of debug registers shared by hardware breakpoints and watchpoints.
+#[cfg(target_arch = "x86_64")]
+const MAX_HW_BREAKPOINTS: usize = 4;
+
+// Describes the registers in the order `GdbArch` serializes them: the general purpose, segment and
+// x87 registers followed by the SSE registers. gdb reads this with `qXfer:features:read` so that
+// `info registers` and `info all-registers` show the FPU and SSE state with the right types.
+#[cfg(target_arch = "x86_64")]
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>i386:x86-64</architecture>
+  <feature name="org.gnu.gdb.i386.core">
+    <flags id="i386_eflags" size="4">
+      <field name="CF" start="0" end="0"/>
+      <field name="" start="1" end="1"/>
+      <field name="PF" start="2" end="2"/>
+      <field name="AF" start="4" end="4"/>
+      <field name="ZF" start="6" end="6"/>
+      <field name="SF" start="7" end="7"/>
+      <field name="TF" start="8" end="8"/>
+      <field name="IF" start="9" end="9"/>
+      <field name="DF" start="10" end="10"/>
+      <field name="OF" start="11" end="11"/>
+      <field name="NT" start="14" end="14"/>
+      <field name="RF" start="16" end="16"/>
+      <field name="VM" start="17" end="17"/>
+      <field name="AC" start="18" end="18"/>
+      <field name="VIF" start="19" end="19"/>
+      <field name="VIP" start="20" end="20"/>
+      <field name="ID" start="21" end="21"/>
+    </flags>
+    <reg name="rax" bitsize="64" type="int64"/>
+    <reg name="rbx" bitsize="64" type="int64"/>
+    <reg name="rcx" bitsize="64" type="int64"/>
+    <reg name="rdx" bitsize="64" type="int64"/>
+    <reg name="rsi" bitsize="64" type="int64"/>
+    <reg name="rdi" bitsize="64" type="int64"/>
+    <reg name="rbp" bitsize="64" type="data_ptr"/>
+    <reg name="rsp" bitsize="64" type="data_ptr"/>
+    <reg name="r8" bitsize="64" type="int64"/>
+    <reg name="r9" bitsize="64" type="int64"/>
+    <reg name="r10" bitsize="64" type="int64"/>
+    <reg name="r11" bitsize="64" type="int64"/>
+    <reg name="r12" bitsize="64" type="int64"/>
+    <reg name="r13" bitsize="64" type="int64"/>
+    <reg name="r14" bitsize="64" type="int64"/>
+    <reg name="r15" bitsize="64" type="int64"/>
+    <reg name="rip" bitsize="64" type="code_ptr"/>
+    <reg name="eflags" bitsize="32" type="i386_eflags"/>
+    <reg name="cs" bitsize="32" type="int32"/>
+    <reg name="ss" bitsize="32" type="int32"/>
+    <reg name="ds" bitsize="32" type="int32"/>
+    <reg name="es" bitsize="32" type="int32"/>
+    <reg name="fs" bitsize="32" type="int32"/>
+    <reg name="gs" bitsize="32" type="int32"/>
+    <reg name="st0" bitsize="80" type="i387_ext"/>
+    <reg name="st1" bitsize="80" type="i387_ext"/>
+    <reg name="st2" bitsize="80" type="i387_ext"/>
+    <reg name="st3" bitsize="80" type="i387_ext"/>
+    <reg name="st4" bitsize="80" type="i387_ext"/>
+    <reg name="st5" bitsize="80" type="i387_ext"/>
+    <reg name="st6" bitsize="80" type="i387_ext"/>
+    <reg name="st7" bitsize="80" type="i387_ext"/>
+    <reg name="fctrl" bitsize="32" type="int" group="float"/>
+    <reg name="fstat" bitsize="32" type="int" group="float"/>
+    <reg name="ftag" bitsize="32" type="int" group="float"/>
+    <reg name="fiseg" bitsize="32" type="int" group="float"/>
+    <reg name="fioff" bitsize="32" type="int" group="float"/>
+    <reg name="foseg" bitsize="32" type="int" group="float"/>
+    <reg name="fooff" bitsize="32" type="int" group="float"/>
+    <reg name="fop" bitsize="32" type="int" group="float"/>
+  </feature>
+  <feature name="org.gnu.gdb.i386.sse">
+    <vector id="v4f" type="ieee_single" count="4"/>
+    <vector id="v2d" type="ieee_double" count="2"/>
+    <vector id="v16i8" type="int8" count="16"/>
+    <vector id="v8i16" type="int16" count="8"/>
+    <vector id="v4i32" type="int32" count="4"/>
+    <vector id="v2i64" type="int64" count="2"/>
+    <union id="vec128">
+      <field name="v4_float" type="v4f"/>
+      <field name="v2_double" type="v2d"/>
+      <field name="v16_int8" type="v16i8"/>
+      <field name="v8_int16" type="v8i16"/>
+      <field name="v4_int32" type="v4i32"/>
+      <field name="v2_int64" type="v2i64"/>
+      <field name="uint128" type="uint128"/>
+    </union>
+    <flags id="i386_mxcsr" size="4">
+      <field name="IE" start="0" end="0"/>
+      <field name="DE" start="1" end="1"/>
+      <field name="ZE" start="2" end="2"/>
+      <field name="OE" start="3" end="3"/>
+      <field name="UE" start="4" end="4"/>
+      <field name="PE" start="5" end="5"/>
+      <field name="DAZ" start="6" end="6"/>
+      <field name="IM" start="7" end="7"/>
+      <field name="DM" start="8" end="8"/>
+      <field name="ZM" start="9" end="9"/>
+      <field name="OM" start="10" end="10"/>
+      <field name="UM" start="11" end="11"/>
+      <field name="PM" start="12" end="12"/>
+      <field name="FZ" start="15" end="15"/>
+    </flags>
+    <reg name="xmm0" bitsize="128" type="vec128"/>
+    <reg name="xmm1" bitsize="128" type="vec128"/>
+    <reg name="xmm2" bitsize="128" type="vec128"/>
+    <reg name="xmm3" bitsize="128" type="vec128"/>
+    <reg name="xmm4" bitsize="128" type="vec128"/>
+    <reg name="xmm5" bitsize="128" type="vec128"/>
+    <reg name="xmm6" bitsize="128" type="vec128"/>
+    <reg name="xmm7" bitsize="128" type="vec128"/>
+    <reg name="xmm8" bitsize="128" type="vec128"/>
+    <reg name="xmm9" bitsize="128" type="vec128"/>
+    <reg name="xmm10" bitsize="128" type="vec128"/>
+    <reg name="xmm11" bitsize="128" type="vec128"/>
+    <reg name="xmm12" bitsize="128" type="vec128"/>
+    <reg name="xmm13" bitsize="128" type="vec128"/>
+    <reg name="xmm14" bitsize="128" type="vec128"/>
+    <reg name="xmm15" bitsize="128" type="vec128"/>
+    <reg name="mxcsr" bitsize="32" type="i386_mxcsr" group="vector"/>
+  </feature>
+</target>"#;
+
+// gdb numbers threads from 1, so vCPU `n` is thread `n + 1`.
+fn cpu_to_tid(cpu: usize) -> usize {
+    cpu + 1
+}
+
+fn tid_to_cpu(tid: usize) -> usize {
+    tid - 1
+}
+
+// The stream gdb connected on.
+trait Stream: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+struct GdbConnection(Box<dyn Stream>);
+
+impl Connection for GdbConnection {
+    fn read(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.0.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        self.0.set_nonblocking(true)?;
+        let res = self.0.read(&mut byte);
+        self.0.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
         }
-    };
-    info!("Waiting for a GDB connection on {:?}...", addr);
+    }
 
-    let (stream, addr) = match listener.accept() {
-        Ok(v) => v,
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+}
+
+// Waits for a single connection from gdb at `address`.
+fn accept_connection(address: &GdbAddress) -> io::Result<GdbConnection> {
+    match address {
+        GdbAddress::Tcp(port) => {
+            let addr = format!("0.0.0.0:{}", port);
+            let listener = TcpListener::bind(&addr)?;
+            info!("Waiting for a GDB connection on {:?}...", addr);
+            let (stream, addr) = listener.accept()?;
+            info!("GDB connected from {}", addr);
+            // Replies are small and gdb waits for each one, so don't let them sit in the buffer.
+            stream.set_nodelay(true)?;
+            Ok(GdbConnection(Box::new(stream)))
+        }
+        GdbAddress::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            info!("Waiting for a GDB connection on {}...", path.display());
+            let res = listener.accept();
+            // Nobody else can connect, so don't leave the socket file behind.
+            if let Err(e) = remove_file(path) {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+            let (stream, _) = res?;
+            info!("GDB connected on {}", path.display());
+            Ok(GdbConnection(Box::new(stream)))
+        }
+    }
+}
+
+pub fn gdb_thread(mut gdbstub: GdbStub, address: GdbAddress) {
+    let connection = match accept_connection(&address) {
+        Ok(c) => c,
         Err(e) => {
             error!("Failed to accept a connection from GDB: {}", e);
             return;
         }
     };
-    info!("GDB connected from {}", addr);
 
-    let connection: Box<dyn Connection<Error = std::io::Error>> = Box::new(stream);
-    let mut gdb = gdbstub::GdbStub::new(connection);
+    let mut gdb = Stub::new(connection);
 
     match gdb.run(&mut gdbstub) {
         Ok(reason) => {
@@ -62,7 +266,16 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
         }
     }
 
-    // Resume the VM when GDB session is disconnected.
+    // Don't leave breakpoints behind in the guest once nothing can handle them.
+    if let Err(e) = gdbstub.clear_sw_breakpoints() {
+        error!(
+            "Failed to remove software breakpoints after GDB disconnected: {}",
+            e
+        );
+    }
+
+    // Resume the VM when GDB session is disconnected, with every vCPU running.
+    gdbstub.release_vcpus();
     if let Err(e) = gdbstub.vm_request(VmRequest::Resume) {
         error!("Failed to resume the VM after GDB disconnected: {}", e);
     }
@@ -71,6 +284,12 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
 #[sorted]
 #[derive(ThisError, Debug)]
 enum Error {
+    /// gdb referred to a vCPU that doesn't exist.
+    #[error("No vCPU with index {0}")]
+    InvalidVcpu(usize),
+    /// Got an unexpected vCPU response.
+    #[error("Got an unexpected vCPU response: {0:?}")]
+    UnexpectedVcpuResponse(VcpuDebugStatus),
     /// Got an unexpected VM response.
     #[error("Got an unexpected VM response: {0}")]
     UnexpectedVmResponse(VmResponse),
@@ -95,6 +314,9 @@ pub struct GdbStub {
     from_vcpu: mpsc::Receiver<VcpuDebugStatusMessage>,
 
     hw_breakpoints: Vec<GuestAddress>,
+    hw_watchpoints: Vec<(ArchUsize, BreakpointKind)>,
+    // The original byte of guest memory at each software breakpoint.
+    sw_breakpoints: BTreeMap<ArchUsize, u8>,
 }
 
 impl GdbStub {
@@ -108,236 +330,467 @@ impl GdbStub {
             vcpu_com,
             from_vcpu,
             hw_breakpoints: Default::default(),
+            hw_watchpoints: Default::default(),
+            sw_breakpoints: Default::default(),
         }
     }
 
-    fn vcpu_request(&self, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
-        // We use the only one vCPU when GDB is enabled.
-        self.vcpu_com[0].send(request).map_err(Error::VcpuRequest)?;
+    fn vcpu_request(&self, cpu: usize, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
+        self.vcpu_com
+            .get(cpu)
+            .ok_or(Error::InvalidVcpu(cpu))?
+            .send(request)
+            .map_err(Error::VcpuRequest)?;
 
-        match self.from_vcpu.recv_timeout(Duration::from_millis(500)) {
-            Ok(msg) => Ok(msg.msg),
-            Err(e) => Err(Error::VcpuResponse(e)),
+        loop {
+            match self.from_vcpu.recv_timeout(Duration::from_millis(500)) {
+                // Other vCPUs may have stopped too before the VM was suspended. Those stops are
+                // dropped, the vCPUs hit the same breakpoints again once they're resumed.
+                Ok(VcpuDebugStatusMessage {
+                    msg: VcpuDebugStatus::HitBreakPoint(_),
+                    ..
+                }) => {}
+                Ok(msg) if msg.cpu == cpu => return Ok(msg.msg),
+                Ok(msg) => error!("Unexpected message from vCPU {}: {:?}", msg.cpu, msg.msg),
+                Err(e) => return Err(Error::VcpuResponse(e)),
+            }
         }
     }
 
+    // Asks vCPU `cpu` for `request`, which it answers with `CommandComplete`.
+    fn vcpu_command(&self, cpu: usize, request: VcpuDebug) -> TargetResult<()> {
+        match self.vcpu_request(cpu, VcpuControl::Debug(request)) {
+            Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
+            Ok(s) => {
+                error!("Unexpected vCPU response: {:?}", s);
+                Err(TargetError)
+            }
+            Err(e) => {
+                error!("Failed to send a vCPU request: {}", e);
+                Err(TargetError)
+            }
+        }
+    }
+
+    fn read_core_registers(&self, cpu: usize) -> TargetResult<CoreRegs> {
+        match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::ReadRegs)) {
+            Ok(VcpuDebugStatus::RegValues(r)) => Ok(r),
+            Ok(s) => {
+                error!("Unexpected vCPU response for ReadRegs: {:?}", s);
+                Err(TargetError)
+            }
+            Err(e) => {
+                error!("Failed to request ReadRegs: {}", e);
+                Err(TargetError)
+            }
+        }
+    }
+
+    // Guest memory is read and written through vCPU 0's page tables unless gdb asks for a
+    // specific thread.
+    fn read_mem(&self, addr: ArchUsize, len: usize) -> GdbResult<Vec<u8>> {
+        match self.vcpu_request(
+            0,
+            VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(addr), len)),
+        )? {
+            // The vCPU replies with an empty region if the address couldn't be translated.
+            VcpuDebugStatus::MemoryRegion(r) if r.len() == len => Ok(r),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    fn write_mem(&self, addr: ArchUsize, data: Vec<u8>) -> GdbResult<()> {
+        match self.vcpu_request(
+            0,
+            VcpuControl::Debug(VcpuDebug::WriteMem(GuestAddress(addr), data)),
+        )? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    // Reprograms the debug state of every vCPU, which also makes int3 exit to us.
+    fn set_hw_breakpoints(&self) -> GdbResult<()> {
+        (0..self.vcpu_com.len()).try_for_each(|cpu| self.set_vcpu_hw_breakpoints(cpu))
+    }
+
+    // Debug registers are per vCPU, so each one needs the same breakpoints and watchpoints.
+    fn set_vcpu_hw_breakpoints(&self, cpu: usize) -> GdbResult<()> {
+        let watchpoints = self
+            .hw_watchpoints
+            .iter()
+            .map(|&(addr, kind)| Watchpoint {
+                addr: GuestAddress(addr),
+                // gdb doesn't pass the size of the watched value, so watch the largest naturally
+                // aligned range starting at `addr`. Accesses to the bytes after the value may stop
+                // the guest too.
+                len: [8, 4, 2, 1]
+                    .iter()
+                    .copied()
+                    .find(|l| addr % l == 0)
+                    .unwrap_or(1),
+                kind: match kind {
+                    BreakpointKind::WriteWatch => WatchpointKind::Write,
+                    _ => WatchpointKind::ReadWrite,
+                },
+            })
+            .collect();
+        match self.vcpu_request(
+            cpu,
+            VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
+                self.hw_breakpoints.clone(),
+                watchpoints,
+            )),
+        )? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    // Restores the original guest memory at every software breakpoint.
+    fn clear_sw_breakpoints(&mut self) -> GdbResult<()> {
+        while let Some((&addr, &orig)) = self.sw_breakpoints.iter().next() {
+            self.write_mem(addr, vec![orig])?;
+            self.sw_breakpoints.remove(&addr);
+        }
+        Ok(())
+    }
+
     fn vm_request(&self, request: VmRequest) -> GdbResult<()> {
+        match self.vm_query(request)? {
+            VmResponse::Ok => Ok(()),
+            r => Err(Error::UnexpectedVmResponse(r)),
+        }
+    }
+
+    // Like `vm_request`, but hands back whatever the VM answered.
+    fn vm_query(&self, request: VmRequest) -> GdbResult<VmResponse> {
         let vm_socket = self.vm_socket.lock();
         vm_socket.send(&request).map_err(Error::VmRequest)?;
-        match vm_socket.recv() {
-            Ok(VmResponse::Ok) => Ok(()),
-            Ok(r) => Err(Error::UnexpectedVmResponse(r)),
-            Err(e) => Err(Error::VmResponse(e)),
-        }
+        vm_socket.recv().map_err(Error::VmResponse)
     }
-}
 
-impl Target for GdbStub {
-    // TODO(keiichiw): Replace `()` with `X86_64CoreRegId` when we update the gdbstub crate.
-    type Arch = GdbArch<()>;
-    type Error = &'static str;
+    // Lets every vCPU run again when the VM resumes.
+    fn release_vcpus(&self) {
+        for cpu in 0..self.vcpu_com.len() {
+            // Errors are logged by `vcpu_command`.
+            let _ = self.vcpu_command(cpu, VcpuDebug::KeepStopped(false));
+        }
+    }
 
-    fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+    fn suspend(&self) -> TargetResult<()> {
+        self.vm_request(VmRequest::Suspend).map_err(|e| {
+            error!("Failed to suspend the target: {}", e);
+            TargetError
+        })
     }
 
-    // TODO(keiichiw): sw_breakpoint, hw_watchpoint, extended_mode, monitor_cmd, section_offsets
-    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
-        Some(self)
+    /// Adds a software breakpoint by patching an int3 over the instruction at `addr`.
+    fn add_sw_breakpoint(&mut self, addr: ArchUsize) -> TargetResult<()> {
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+
+        let orig = match self.read_mem(addr, 1) {
+            Ok(v) => v[0],
+            Err(e) => {
+                error!(
+                    "Failed to read memory for a SW breakpoint at {:#x}: {}",
+                    addr, e
+                );
+                return Err(TargetError);
+            }
+        };
+        if let Err(e) = self.set_hw_breakpoints() {
+            error!(
+                "Failed to enable guest debugging for a SW breakpoint: {}",
+                e
+            );
+            return Err(TargetError);
+        }
+        if let Err(e) = self.write_mem(addr, vec![SW_BREAKPOINT_INSN]) {
+            error!("Failed to write a SW breakpoint at {:#x}: {}", addr, e);
+            return Err(TargetError);
+        }
+        self.sw_breakpoints.insert(addr, orig);
+        Ok(())
     }
-}
 
-impl SingleThreadOps for GdbStub {
-    fn resume(
-        &mut self,
-        action: ResumeAction,
-        check_gdb_interrupt: &mut dyn FnMut() -> bool,
-    ) -> Result<StopReason<ArchUsize>, Self::Error> {
-        let single_step = ResumeAction::Step == action;
-
-        if single_step {
-            match self.vcpu_request(VcpuControl::Debug(VcpuDebug::EnableSinglestep)) {
-                Ok(VcpuDebugStatus::CommandComplete) => {}
-                Ok(s) => {
-                    error!("Unexpected vCPU response for EnableSinglestep: {:?}", s);
-                    return Err("Unexpected vCPU response for EnableSinglestep");
-                }
-                Err(e) => {
-                    error!("Failed to request EnableSinglestep: {}", e);
-                    return Err("Failed to request EnableSinglestep");
-                }
-            };
+    /// Removes a software breakpoint and restores the original instruction.
+    fn remove_sw_breakpoint(&mut self, addr: ArchUsize) -> TargetResult<()> {
+        let orig = match self.sw_breakpoints.get(&addr) {
+            Some(&orig) => orig,
+            None => return Err(TargetError),
+        };
+        if let Err(e) = self.write_mem(addr, vec![orig]) {
+            error!("Failed to remove the SW breakpoint at {:#x}: {}", addr, e);
+            return Err(TargetError);
         }
+        self.sw_breakpoints.remove(&addr);
+        Ok(())
+    }
 
-        self.vm_request(VmRequest::Resume).map_err(|e| {
-            error!("Failed to resume the target: {}", e);
-            "Failed to resume the target"
-        })?;
+    /// Adds a hardware breakpoint or watchpoint.
+    fn add_hw_breakpoint(&mut self, kind: BreakpointKind, addr: ArchUsize) -> TargetResult<()> {
+        // Breakpoints and watchpoints share the 4 debug registers.
+        if self.hw_breakpoints.len() + self.hw_watchpoints.len() >= MAX_HW_BREAKPOINTS {
+            error!("Not allowed to set more than 4 HW breakpoints and watchpoints");
+            return Err(TargetError);
+        }
+        if kind == BreakpointKind::Hardware {
+            self.hw_breakpoints.push(GuestAddress(addr));
+        } else {
+            self.hw_watchpoints.push((addr, kind));
+        }
 
-        // Polling
-        loop {
-            match self
-                .from_vcpu
-                .recv_timeout(std::time::Duration::from_millis(100))
-            {
-                Ok(msg) => match msg.msg {
-                    VcpuDebugStatus::HitBreakPoint => {
-                        if single_step {
-                            return Ok(StopReason::DoneStep);
-                        } else {
-                            return Ok(StopReason::HwBreak);
-                        }
-                    }
-                    status => {
-                        error!("Unexpected VcpuDebugStatus: {:?}", status);
-                    }
-                },
-                Err(_) => {} // TODO(keiichiw): handle error?
-            };
-
-            if check_gdb_interrupt() {
-                self.vm_request(VmRequest::Suspend).map_err(|e| {
-                    error!("Failed to suspend the target: {}", e);
-                    "Failed to suspend the target"
-                })?;
-                return Ok(StopReason::GdbInterrupt);
+        self.set_hw_breakpoints().map_err(|e| {
+            error!("Failed to request SetHwBreakPoint: {}", e);
+            if kind == BreakpointKind::Hardware {
+                self.hw_breakpoints.pop();
+            } else {
+                self.hw_watchpoints.pop();
             }
+            TargetError
+        })
+    }
+
+    /// Removes a hardware breakpoint or watchpoint.
+    fn remove_hw_breakpoint(&mut self, kind: BreakpointKind, addr: ArchUsize) -> TargetResult<()> {
+        let count = self.hw_breakpoints.len() + self.hw_watchpoints.len();
+        if kind == BreakpointKind::Hardware {
+            self.hw_breakpoints.retain(|&b| b.0 != addr);
+        } else {
+            self.hw_watchpoints.retain(|&w| w != (addr, kind));
+        }
+        if self.hw_breakpoints.len() + self.hw_watchpoints.len() == count {
+            return Err(TargetError);
+        }
+
+        self.set_hw_breakpoints().map_err(|e| {
+            error!("Failed to request SetHwBreakPoint: {}", e);
+            TargetError
+        })
+    }
+}
+
+impl Target for GdbStub {
+    fn threads(&self) -> usize {
+        self.vcpu_com.len()
+    }
+
+    fn read_registers(&mut self, tid: usize) -> TargetResult<Vec<u8>> {
+        let regs = self.read_core_registers(tid_to_cpu(tid))?;
+        let mut bytes = Vec::with_capacity(GdbRegisters::registers_size());
+        // Every register is available, so there are no unknown bytes.
+        regs.gdb_serialize(|b| bytes.push(b.unwrap_or(0)));
+        Ok(bytes)
+    }
+
+    fn write_registers(&mut self, tid: usize, regs: &[u8]) -> TargetResult<()> {
+        let mut core_regs = CoreRegs::default();
+        if core_regs.gdb_deserialize(regs).is_err() {
+            error!("GDB wrote {} bytes of registers", regs.len());
+            return Err(TargetError);
         }
+        self.vcpu_command(tid_to_cpu(tid), VcpuDebug::WriteRegs(Box::new(core_regs)))
     }
 
-    fn read_registers(
-        &mut self,
-        regs: &mut <Self::Arch as Arch>::Registers,
-    ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadRegs)) {
-            Ok(VcpuDebugStatus::RegValues(r)) => {
-                *regs = r;
-                Ok(())
-            }
+    fn read_register(&mut self, tid: usize, reg: usize) -> TargetResult<Vec<u8>> {
+        if GdbRegisters::register_size(reg).is_none() {
+            error!("GDB read nonexistent register {}", reg);
+            return Err(TargetError);
+        }
+        match self.vcpu_request(tid_to_cpu(tid), VcpuControl::Debug(VcpuDebug::ReadReg(reg))) {
+            Ok(VcpuDebugStatus::RegValue(value)) => Ok(value),
             Ok(s) => {
-                error!("Unexpected vCPU response for ReadRegs: {:?}", s);
-                Err(NonFatal)
+                error!("Unexpected vCPU response for ReadReg: {:?}", s);
+                Err(TargetError)
             }
             Err(e) => {
-                error!("Failed to request ReadRegs: {}", e);
-                Err(NonFatal)
+                error!("Failed to request ReadReg: {}", e);
+                Err(TargetError)
             }
         }
     }
 
-    fn write_registers(
-        &mut self,
-        regs: &<Self::Arch as Arch>::Registers,
-    ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(
-            regs.clone(),
-        )))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
-            Ok(s) => {
-                error!("Unexpected vCPU response for WriteRegs: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request WriteRegs: {}", e);
-                Err(NonFatal)
-            }
+    fn write_register(&mut self, tid: usize, reg: usize, value: &[u8]) -> TargetResult<()> {
+        if GdbRegisters::register_size(reg) != Some(value.len()) {
+            error!("GDB wrote {} bytes to register {}", value.len(), reg);
+            return Err(TargetError);
         }
+        self.vcpu_command(tid_to_cpu(tid), VcpuDebug::WriteReg(reg, value.to_vec()))
     }
 
-    fn read_addrs(
-        &mut self,
-        start_addr: <Self::Arch as Arch>::Usize,
-        data: &mut [u8],
-    ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadMem(
-            GuestAddress(start_addr),
-            data.len(),
-        ))) {
-            Ok(VcpuDebugStatus::MemoryRegion(r)) => {
-                for (dst, v) in data.iter_mut().zip(r.iter()) {
-                    *dst = *v;
+    fn read_memory(&mut self, tid: usize, addr: u64, len: usize) -> TargetResult<Vec<u8>> {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(addr), len)),
+        ) {
+            // The vCPU replies with an empty region if the address couldn't be translated.
+            Ok(VcpuDebugStatus::MemoryRegion(mut data)) if data.len() == len => {
+                // Show the original instructions rather than our int3s.
+                let end = addr.saturating_add(len as ArchUsize);
+                for (&bp, &orig) in self.sw_breakpoints.range(addr..end) {
+                    data[(bp - addr) as usize] = orig;
                 }
-                Ok(())
+                Ok(data)
+            }
+            Ok(VcpuDebugStatus::MemoryRegion(_)) => {
+                error!("Failed to read {} bytes at {:#x}", len, addr);
+                Err(TargetError)
             }
             Ok(s) => {
                 error!("Unexpected vCPU response for ReadMem: {:?}", s);
-                Err(NonFatal)
+                Err(TargetError)
             }
             Err(e) => {
                 error!("Failed to request ReadMem: {}", e);
-                Err(NonFatal)
+                Err(TargetError)
             }
         }
     }
 
-    fn write_addrs(
+    fn write_memory(&mut self, tid: usize, addr: u64, data: &[u8]) -> TargetResult<()> {
+        self.vcpu_command(
+            tid_to_cpu(tid),
+            VcpuDebug::WriteMem(GuestAddress(addr), data.to_owned()),
+        )
+    }
+
+    fn insert_breakpoint(
         &mut self,
-        start_addr: <Self::Arch as Arch>::Usize,
-        data: &[u8],
-    ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteMem(
-            GuestAddress(start_addr),
-            data.to_owned(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
-            Ok(s) => {
-                error!("Unexpected vCPU response for WriteMem: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request WriteMem: {}", e);
-                Err(NonFatal)
-            }
+        kind: BreakpointKind,
+        addr: u64,
+        _size: usize,
+    ) -> TargetResult<bool> {
+        match kind {
+            BreakpointKind::Software => self.add_sw_breakpoint(addr)?,
+            _ => self.add_hw_breakpoint(kind, addr)?,
         }
+        Ok(true)
     }
-}
 
-impl HwBreakpoint for GdbStub {
-    /// Add a new hardware breakpoint.
-    /// Return `Ok(false)` if the operation could not be completed.
-    fn add_hw_breakpoint(&mut self, addr: <Self::Arch as Arch>::Usize) -> TargetResult<bool, Self> {
-        // If we already have 4 breakpoints, we cannot set a new one.
-        if self.hw_breakpoints.len() >= 4 {
-            error!("Not allowed to set more than 4 HW breakpoints");
-            return Err(NonFatal);
-        }
-        self.hw_breakpoints.push(GuestAddress(addr));
-
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
-            }
+    fn remove_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        addr: u64,
+        _size: usize,
+    ) -> TargetResult<bool> {
+        match kind {
+            BreakpointKind::Software => self.remove_sw_breakpoint(addr)?,
+            _ => self.remove_hw_breakpoint(kind, addr)?,
         }
+        Ok(true)
     }
 
-    /// Remove an existing hardware breakpoint.
-    /// Return `Ok(false)` if the operation could not be completed.
-    fn remove_hw_breakpoint(
+    fn resume(
         &mut self,
-        addr: <Self::Arch as Arch>::Usize,
-    ) -> TargetResult<bool, Self> {
-        self.hw_breakpoints.retain(|&b| b.0 != addr);
-
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
+        actions: &[ResumeKind],
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> TargetResult<StopReason> {
+        let mut stepping = BTreeSet::new();
+        for (cpu, &action) in actions.iter().enumerate() {
+            // The guest can't be sent signals, so they're ignored.
+            match action {
+                ResumeKind::Step | ResumeKind::StepWithSignal(_) => {
+                    self.vcpu_command(cpu, VcpuDebug::EnableSinglestep)?;
+                    stepping.insert(cpu);
+                }
+                ResumeKind::Continue | ResumeKind::ContinueWithSignal(_) => {
+                    // Reprogramming the debug state also turns off single-stepping left on by an
+                    // earlier step, which would otherwise stop the guest again after one
+                    // instruction.
+                    if let Err(e) = self.set_vcpu_hw_breakpoints(cpu) {
+                        error!("Failed to disable single-stepping: {}", e);
+                        return Err(TargetError);
+                    }
+                }
+                ResumeKind::Stop => {}
             }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
+            // `VmRequest::Resume` runs every vCPU that isn't kept stopped.
+            self.vcpu_command(cpu, VcpuDebug::KeepStopped(action == ResumeKind::Stop))?;
+        }
+
+        if let Err(e) = self.vm_request(VmRequest::Resume) {
+            error!("Failed to resume the target: {}", e);
+            return Err(TargetError);
+        }
+
+        loop {
+            if let Ok(msg) = self.from_vcpu.recv_timeout(Duration::from_millis(100)) {
+                match msg.msg {
+                    VcpuDebugStatus::HitBreakPoint(reg) => {
+                        // Only the vCPU that stopped is waiting for us. Pause the rest of the VM
+                        // too, like for an interrupt from gdb, until gdb resumes it.
+                        self.suspend()?;
+                        let tid = cpu_to_tid(msg.cpu);
+                        if stepping.contains(&msg.cpu) {
+                            return Ok(StopReason::DoneStep(tid));
+                        }
+                        // Watchpoints use the debug registers after the breakpoints.
+                        if let Some(&(addr, kind)) = reg
+                            .and_then(|r| r.checked_sub(self.hw_breakpoints.len()))
+                            .and_then(|w| self.hw_watchpoints.get(w))
+                        {
+                            return Ok(StopReason::Watch { tid, kind, addr });
+                        }
+                        // KVM leaves rip at the int3 when a software breakpoint is hit.
+                        match self.read_core_registers(msg.cpu) {
+                            Ok(regs) if self.sw_breakpoints.contains_key(&regs.rip) => {
+                                return Ok(StopReason::SwBreak(tid));
+                            }
+                            _ => return Ok(StopReason::HwBreak(tid)),
+                        }
+                    }
+                    status => {
+                        error!("Unexpected VcpuDebugStatus: {:?}", status);
+                    }
+                }
+            }
+
+            if interrupted() {
+                self.suspend()?;
+                // Report a vCPU that was running.
+                let cpu = actions
+                    .iter()
+                    .position(|&action| action != ResumeKind::Stop)
+                    .unwrap_or(0);
+                return Ok(StopReason::Interrupted(cpu_to_tid(cpu)));
             }
         }
     }
+
+    fn target_xml(&self) -> Option<&str> {
+        Some(TARGET_XML)
+    }
+
+    fn monitor(&mut self, cmd: &str) -> Option<String> {
+        let request = match cmd.trim() {
+            // The VM is stopped while gdb is in control, so ask for what the balloon holds rather
+            // than for stats that the guest would have to report.
+            "balloon" => VmRequest::BalloonCommand(BalloonControlCommand::Accounting),
+            "devices" => VmRequest::DumpMemoryMap,
+            "executors" => VmRequest::ExecutorStatus,
+            "memory" => VmRequest::MemoryStats,
+            "" | "help" => return Some(format!("{}\n", MONITOR_HELP)),
+            unknown => {
+                return Some(format!(
+                    "Unknown command `{}`.\n{}\n",
+                    unknown, MONITOR_HELP
+                ))
+            }
+        };
+
+        Some(match self.vm_query(request) {
+            Ok(response) => format!("{}\n", response),
+            Err(e) => format!("Failed to query the VM: {}\n", e),
+        })
+    }
 }
+
+const MONITOR_HELP: &str = "\
+Commands for inspecting crosvm:
+  balloon    memory held by the balloon
+  devices    devices on the IO and MMIO buses and their ranges
+  executors  async executor backend of each device
+  memory     memory usage of the VM";
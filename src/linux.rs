@@ -3,14 +3,15 @@
 // found in the LICENSE file.
 
 use std::cmp::{max, min, Reverse};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 #[cfg(feature = "gpu")]
 use std::env;
 use std::error::Error as StdError;
 use std::ffi::CStr;
 use std::fmt::{self, Display};
-use std::fs::{File, OpenOptions};
-use std::io::{self, stdin, Read};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdin, Read, Write};
 use std::iter;
 use std::mem;
 use std::net::Ipv4Addr;
@@ -18,7 +19,7 @@ use std::net::Ipv4Addr;
 use std::num::NonZeroU8;
 use std::num::ParseIntError;
 use std::os::unix::io::FromRawFd;
-use std::os::unix::net::UnixStream;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::ptr;
 use std::str;
@@ -34,18 +35,22 @@ use acpi_tables::sdt::SDT;
 
 use base::net::{UnixSeqpacket, UnixSeqpacketListener, UnlinkUnixSeqpacketListener};
 #[cfg(feature = "gpu")]
+use devices::virtio::gpu::GpuParameters;
+use devices::virtio::vhost::VhostUserRegistry;
+#[cfg(feature = "gpu")]
 use devices::virtio::EventDevice;
-use devices::virtio::{self, Console, VirtioDevice};
+use devices::virtio::{self, Console, MetricsRegistry, VirtioDevice};
 #[cfg(feature = "audio")]
 use devices::Ac97Dev;
 use devices::{
-    self, HostBackendDeviceProvider, IrqChip, IrqEventIndex, KvmKernelIrqChip, PciDevice,
-    VcpuRunState, VfioContainer, VfioDevice, VfioPciDevice, VirtioPciDevice, XhciController,
+    self, CpuHotplugController, CpuHotplugError, HostBackendDeviceProvider, IrqChip, IrqEventIndex,
+    KvmKernelIrqChip, PciDevice, PcieHotplugSlot, PcieRootPort, VcpuRunState, VfioContainer,
+    VfioDevice, VfioDeviceType, VfioPciDevice, VfioPlatformDevice, VirtioPciDevice, XhciController,
 };
 use hypervisor::kvm::{Kvm, KvmVcpu, KvmVm};
 use hypervisor::{HypervisorCap, Vcpu, VcpuExit, VcpuRunHandle, Vm, VmCap};
 use minijail::{self, Minijail};
-use msg_socket::{MsgError, MsgReceiver, MsgSender, MsgSocket};
+use msg_socket::{MsgError, MsgOnSocket, MsgReceiver, MsgSender, MsgSocket};
 use net_util::{Error as NetError, MacAddress, Tap};
 use remain::sorted;
 use resources::{Alloc, MmioType, SystemAllocator};
@@ -54,32 +59,45 @@ use sync::Mutex;
 
 use base::{
     self, block_signal, clear_signal, drop_capabilities, error, flock, get_blocked_signals,
-    get_group_id, get_user_id, getegid, geteuid, info, register_rt_signal_handler,
-    set_cpu_affinity, set_rt_prio_limit, set_rt_round_robin, signal, validate_raw_descriptor, warn,
-    AsRawDescriptor, Event, EventType, ExternalMapping, FlockOperation, FromRawDescriptor,
-    Killable, MemoryMappingArena, PollToken, Protection, RawDescriptor, ScopedEvent, SignalFd,
-    Terminal, Timer, WaitContext, SIGRTMIN,
+    get_cpu_affinity, get_group_id, get_user_id, getegid, geteuid, info,
+    register_rt_signal_handler, set_cpu_affinity, set_rt_prio_limit, set_rt_round_robin, signal,
+    validate_raw_descriptor, warn, AsRawDescriptor, Event, EventType, ExternalMapping,
+    FlockOperation, FromRawDescriptor, Killable, LandlockRuleset, MemoryMappingArena,
+    MemoryMappingBuilder, PollToken, Protection, RawDescriptor, ScopedEvent, SharedMemory,
+    SignalFd, Terminal, Timer, WaitContext, LANDLOCK_ACCESS_FS_ALL, LANDLOCK_ACCESS_FS_EXECUTE,
+    LANDLOCK_ACCESS_FS_READ_FILE, LANDLOCK_ACCESS_FS_WRITE_FILE, SIGRTMIN,
 };
 use vm_control::{
     BalloonControlCommand, BalloonControlRequestSocket, BalloonControlResponseSocket,
-    BalloonControlResult, BalloonStats, DiskControlCommand, DiskControlRequestSocket,
-    DiskControlResponseSocket, DiskControlResult, FsMappingRequest, FsMappingRequestSocket,
-    FsMappingResponseSocket, IrqSetup, UsbControlSocket, VcpuControl, VmControlResponseSocket,
-    VmIrqRequest, VmIrqRequestSocket, VmIrqResponse, VmIrqResponseSocket,
-    VmMemoryControlRequestSocket, VmMemoryControlResponseSocket, VmMemoryRequest, VmMemoryResponse,
-    VmMsyncRequest, VmMsyncRequestSocket, VmMsyncResponse, VmMsyncResponseSocket, VmResponse,
-    VmRunMode,
+    BalloonControlResult, BalloonStats, DeviceExecutor, DiskControlCommand,
+    DiskControlRequestSocket, DiskControlResponseSocket, DiskControlResult, FsMappingRequest,
+    FsMappingRequestSocket, FsMappingResponseSocket, GpuControlCommand, GpuControlRequestSocket,
+    GpuControlResponseSocket, GpuControlResult, IrqSetup, MemControlCommand,
+    MemControlRequestSocket, MemControlResponseSocket, MemControlResult, MemoryMapBus,
+    MemoryMapEntry, SharedMemoryRegions, SnapshotCommand, UsbControlCommand, UsbControlSocket,
+    VcpuCommand, VcpuControl, VfioCommand, VmControlResponseSocket, VmIrqRequest,
+    VmIrqRequestSocket, VmIrqResponse, VmIrqResponseSocket, VmMemoryControlRequestSocket,
+    VmMemoryControlResponseSocket, VmMemoryRequest, VmMemoryResponse, VmMsyncRequest,
+    VmMsyncRequestSocket, VmMsyncResponse, VmMsyncResponseSocket, VmRequest, VmRequestAccess,
+    VmResponse, VmRunMode,
 };
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-use vm_control::{VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage, VmRequest};
-use vm_memory::{GuestAddress, GuestMemory};
+use vm_control::{VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use crate::gdb::{gdb_thread, GdbStub};
-use crate::{Config, DiskOption, Executable, SharedDir, SharedDirKind, TouchDeviceOption};
+use crate::metrics_server::MetricsServer;
+use crate::net_helper::{self, HelperNetDevice};
+use crate::usb_hotplug::UsbHotplug;
+use crate::{
+    registry, seccomp, BalloonGuestRequests, Config, ControlPeer, ControlPeerId, DiskOption,
+    Executable, FwCfgData, HypervisorKind, NetDeviceTap, SharedDir, SharedDirKind,
+    TouchDeviceOption, VfioPlatformOption, VhostUserOption,
+};
 use arch::{
-    self, LinuxArch, RunnableLinuxVm, SerialHardware, SerialParameters, VcpuAffinity,
-    VirtioDeviceStub, VmComponents, VmImage,
+    self, CpuFeatures, HotplugPciDevice, LinuxArch, NumaNode, RunnableLinuxVm, SerialHardware,
+    SerialParameters, VcpuAffinity, VirtioDeviceStub, VmComponents, VmDevices, VmImage,
 };
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -101,8 +119,10 @@ pub enum Error {
     AddGpuDeviceMemory(base::Error),
     AddIrqChipVcpu(base::Error),
     AddPmemDeviceMemory(base::Error),
+    AddVirtioMemDeviceMemory(base::Error),
     AllocateGpuDeviceAddress,
     AllocatePmemDeviceAddress(resources::Error),
+    AllocateVirtioMemDeviceAddress(resources::Error),
     BalloonActualTooLarge,
     BalloonDeviceNew(virtio::BalloonError),
     BlockDeviceNew(base::Error),
@@ -112,12 +132,17 @@ pub enum Error {
     CloneEvent(base::Error),
     CloneVcpu(base::Error),
     ConfigureVcpu(<Arch as LinuxArch>::Error),
+    #[cfg(feature = "tpm")]
+    ConnectSwtpm(virtio::SwtpmError),
     #[cfg(feature = "audio")]
     CreateAc97(devices::PciDeviceError),
+    CreateConfigDrive(disk::IsoError),
     CreateConsole(arch::serial::Error),
     CreateDiskError(disk::Error),
     CreateEvent(base::Error),
     CreateGrallocError(rutabaga_gfx::RutabagaError),
+    CreateLandlockRuleset(base::Error),
+    CreateMetrics(base::MmapError),
     CreateSignalFd(base::SignalFdError),
     CreateSocket(io::Error),
     CreateTapDevice(NetError),
@@ -126,13 +151,16 @@ pub enum Error {
     CreateUsbProvider(devices::usb::host_backend::error::Error),
     CreateVcpu(base::Error),
     CreateVfioDevice(devices::vfio::VfioError),
+    CreateVhostUserRegistry(base::MmapError),
     CreateWaitContext(base::Error),
     DeviceJail(minijail::Error),
     DevicePivotRoot(minijail::Error),
     Disk(PathBuf, io::Error),
     DiskImageLock(base::Error),
     DropCapabilities(base::Error),
+    EmbeddedSeccompPolicy(io::Error),
     FsDeviceNew(virtio::fs::Error),
+    GetCpuAffinity(base::Error),
     GetMaxOpenFiles(io::Error),
     GetSignalMask(signal::Error),
     GuestCachedMissing(),
@@ -141,27 +169,40 @@ pub enum Error {
     GuestFreeTooLarge(std::num::TryFromIntError),
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     HandleDebugCommand(<Arch as LinuxArch>::Error),
+    HotplugVfioDevice(arch::DeviceRegistrationError),
     InputDeviceNew(virtio::InputError),
     InputEventsOpen(std::io::Error),
     InvalidFdPath,
     InvalidWaylandPath,
     IoJail(minijail::Error),
+    LandlockAllowPath(PathBuf, base::Error),
     LoadKernel(Box<dyn StdError>),
+    LockGuestMemory(GuestMemoryError),
     MemoryTooLarge,
     NetDeviceNew(virtio::NetError),
+    NotVfioPciDevice(PathBuf),
+    NotVfioPlatformDevice(PathBuf),
     OpenAcpiTable(PathBuf, io::Error),
     OpenAndroidFstab(PathBuf, io::Error),
     OpenBios(PathBuf, io::Error),
+    OpenBiosKernel(PathBuf, io::Error),
     OpenInitrd(PathBuf, io::Error),
     OpenKernel(PathBuf, io::Error),
+    OpenPflash(PathBuf, io::Error),
+    OpenTraceMarker(io::Error),
     OpenVinput(PathBuf, io::Error),
     P9DeviceNew(virtio::P9Error),
     ParseMaxOpenFiles(ParseIntError),
     PivotRootDoesntExist(&'static str),
     PmemDeviceImageTooBig,
     PmemDeviceNew(base::Error),
+    ReadConfigDriveFile(PathBuf, io::Error),
+    ReadDtCompatible(PathBuf, io::Error),
+    ReadDtbo(PathBuf, io::Error),
+    ReadFwCfgFile(PathBuf, io::Error),
     ReadMemAvailable(io::Error),
     ReadStatm(io::Error),
+    ReceiveNetHelperDevices(PathBuf, io::Error),
     RegisterBalloon(arch::DeviceRegistrationError),
     RegisterBlock(arch::DeviceRegistrationError),
     RegisterGpu(arch::DeviceRegistrationError),
@@ -178,22 +219,46 @@ pub enum Error {
     RunnableVcpu(base::Error),
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     SendDebugStatus(Box<mpsc::SendError<VcpuDebugStatusMessage>>),
+    SetHousekeepingAffinity(base::Error),
     SettingGidMap(minijail::Error),
     SettingMaxOpenFiles(minijail::Error),
     SettingSignalMask(base::Error),
     SettingUidMap(minijail::Error),
     SignalFd(base::SignalFdError),
+    SnapshotDevice(String),
+    SnapshotDeviceCount(usize, usize),
+    SnapshotFile(io::Error),
+    SnapshotHeader,
+    SnapshotIrqChip(<Arch as LinuxArch>::Error),
+    SnapshotMemory(GuestMemoryError),
+    SnapshotPoll(base::Error),
+    SnapshotState(MsgError),
+    SnapshotUnsupported(String),
+    SnapshotVcpu(usize),
+    SnapshotVcpuCount(usize, usize),
+    #[cfg(feature = "audio")]
+    SoundDeviceNew(virtio::snd::SoundError),
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     SpawnGdbServer(io::Error),
+    SpawnSnapshotThread(io::Error),
     SpawnVcpu(io::Error),
+    StartMetricsServer(io::Error),
     Timer(base::Error),
+    UnplugVfioDevice(arch::DeviceRegistrationError),
     ValidateRawDescriptor(base::Error),
     VhostNetDeviceNew(virtio::vhost::Error),
+    VhostUserAccept(io::Error),
+    VhostUserDeviceNew(virtio::vhost::Error),
     VhostVsockDeviceNew(virtio::vhost::Error),
+    VirtioMemDeviceNew(virtio::MemError),
+    VirtioMemMapping(base::MmapError),
+    VirtioMemMemory(base::Error),
+    VirtioMemTooBig,
     VirtioPciDev(base::Error),
     WaitContextAdd(base::Error),
     WaitContextDelete(base::Error),
     WaylandDeviceNew(base::Error),
+    WriteConfigDrive(io::Error),
 }
 
 impl Display for Error {
@@ -206,10 +271,16 @@ impl Display for Error {
             AddGpuDeviceMemory(e) => write!(f, "failed to add gpu device memory: {}", e),
             AddIrqChipVcpu(e) => write!(f, "failed to add vcpu to irq chip: {}", e),
             AddPmemDeviceMemory(e) => write!(f, "failed to add pmem device memory: {}", e),
+            AddVirtioMemDeviceMemory(e) => {
+                write!(f, "failed to add virtio-mem device memory: {}", e)
+            }
             AllocateGpuDeviceAddress => write!(f, "failed to allocate gpu device guest address"),
             AllocatePmemDeviceAddress(e) => {
                 write!(f, "failed to allocate memory for pmem device: {}", e)
             }
+            AllocateVirtioMemDeviceAddress(e) => {
+                write!(f, "failed to allocate memory for virtio-mem device: {}", e)
+            }
             BalloonActualTooLarge => write!(f, "balloon actual size is too large"),
             BalloonDeviceNew(e) => write!(f, "failed to create balloon: {}", e),
             BlockDeviceNew(e) => write!(f, "failed to create block device: {}", e),
@@ -219,12 +290,17 @@ impl Display for Error {
             CloneEvent(e) => write!(f, "failed to clone event: {}", e),
             CloneVcpu(e) => write!(f, "failed to clone vcpu: {}", e),
             ConfigureVcpu(e) => write!(f, "failed to configure vcpu: {}", e),
+            #[cfg(feature = "tpm")]
+            ConnectSwtpm(e) => write!(f, "failed to set up swtpm: {}", e),
             #[cfg(feature = "audio")]
             CreateAc97(e) => write!(f, "failed to create ac97 device: {}", e),
+            CreateConfigDrive(e) => write!(f, "failed to create config drive: {}", e),
             CreateConsole(e) => write!(f, "failed to create console device: {}", e),
             CreateDiskError(e) => write!(f, "failed to create virtual disk: {}", e),
             CreateEvent(e) => write!(f, "failed to create event: {}", e),
             CreateGrallocError(e) => write!(f, "failed to create gralloc: {}", e),
+            CreateLandlockRuleset(e) => write!(f, "failed to create Landlock ruleset: {}", e),
+            CreateMetrics(e) => write!(f, "failed to create device metrics: {}", e),
             CreateSignalFd(e) => write!(f, "failed to create signalfd: {}", e),
             CreateSocket(e) => write!(f, "failed to create socket: {}", e),
             CreateTapDevice(e) => write!(f, "failed to create tap device: {}", e),
@@ -235,13 +311,20 @@ impl Display for Error {
             CreateUsbProvider(e) => write!(f, "failed to create usb provider: {}", e),
             CreateVcpu(e) => write!(f, "failed to create vcpu: {}", e),
             CreateVfioDevice(e) => write!(f, "Failed to create vfio device {}", e),
+            CreateVhostUserRegistry(e) => {
+                write!(f, "failed to create vhost-user connection registry: {}", e)
+            }
             CreateWaitContext(e) => write!(f, "failed to create wait context: {}", e),
             DeviceJail(e) => write!(f, "failed to jail device: {}", e),
             DevicePivotRoot(e) => write!(f, "failed to pivot root device: {}", e),
             Disk(p, e) => write!(f, "failed to load disk image {}: {}", p.display(), e),
             DiskImageLock(e) => write!(f, "failed to lock disk image: {}", e),
             DropCapabilities(e) => write!(f, "failed to drop process capabilities: {}", e),
+            EmbeddedSeccompPolicy(e) => {
+                write!(f, "failed to write built-in seccomp policy: {}", e)
+            }
             FsDeviceNew(e) => write!(f, "failed to create fs device: {}", e),
+            GetCpuAffinity(e) => write!(f, "failed to get the CPU affinity: {}", e),
             GetMaxOpenFiles(e) => write!(f, "failed to get max number of open files: {}", e),
             GetSignalMask(e) => write!(f, "failed to retrieve signal mask for vcpu: {}", e),
             GuestCachedMissing() => write!(f, "guest cached is missing from balloon stats"),
@@ -250,14 +333,26 @@ impl Display for Error {
             GuestFreeTooLarge(e) => write!(f, "guest free is too large: {}", e),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             HandleDebugCommand(e) => write!(f, "failed to handle a gdb command: {}", e),
+            HotplugVfioDevice(e) => write!(f, "failed to hot-add vfio device: {}", e),
             InputDeviceNew(e) => write!(f, "failed to set up input device: {}", e),
             InputEventsOpen(e) => write!(f, "failed to open event device: {}", e),
             InvalidFdPath => write!(f, "failed parsing a /proc/self/fd/*"),
             InvalidWaylandPath => write!(f, "wayland socket path has no parent or file name"),
             IoJail(e) => write!(f, "{}", e),
+            LandlockAllowPath(p, e) => write!(
+                f,
+                "failed to allow access to {} in Landlock ruleset: {}",
+                p.display(),
+                e
+            ),
             LoadKernel(e) => write!(f, "failed to load kernel: {}", e),
+            LockGuestMemory(e) => write!(f, "failed to lock guest memory: {}", e),
             MemoryTooLarge => write!(f, "requested memory size too large"),
             NetDeviceNew(e) => write!(f, "failed to set up virtio networking: {}", e),
+            NotVfioPciDevice(p) => write!(f, "{} isn't a vfio PCI device", p.display()),
+            NotVfioPlatformDevice(p) => {
+                write!(f, "{} isn't a vfio platform device", p.display())
+            }
             OpenAcpiTable(p, e) => write!(f, "failed to open ACPI file {}: {}", p.display(), e),
             OpenAndroidFstab(p, e) => write!(
                 f,
@@ -266,8 +361,13 @@ impl Display for Error {
                 e
             ),
             OpenBios(p, e) => write!(f, "failed to open bios {}: {}", p.display(), e),
+            OpenBiosKernel(p, e) => {
+                write!(f, "failed to open bios kernel image {}: {}", p.display(), e)
+            }
             OpenInitrd(p, e) => write!(f, "failed to open initrd {}: {}", p.display(), e),
             OpenKernel(p, e) => write!(f, "failed to open kernel image {}: {}", p.display(), e),
+            OpenPflash(p, e) => write!(f, "failed to open pflash image {}: {}", p.display(), e),
+            OpenTraceMarker(e) => write!(f, "failed to open the ftrace trace_marker: {}", e),
             OpenVinput(p, e) => write!(f, "failed to open vinput device {}: {}", p.display(), e),
             P9DeviceNew(e) => write!(f, "failed to create 9p device: {}", e),
             ParseMaxOpenFiles(e) => write!(f, "failed to parse max number of open files: {}", e),
@@ -276,12 +376,34 @@ impl Display for Error {
                 write!(f, "failed to create pmem device: pmem device image too big")
             }
             PmemDeviceNew(e) => write!(f, "failed to create pmem device: {}", e),
+            ReadConfigDriveFile(p, e) => {
+                write!(f, "failed to read config drive file {}: {}", p.display(), e)
+            }
+            ReadDtCompatible(p, e) => write!(
+                f,
+                "failed to read device tree compatible strings from {}: {}",
+                p.display(),
+                e
+            ),
+            ReadDtbo(p, e) => write!(
+                f,
+                "failed to read device tree overlay {}: {}",
+                p.display(),
+                e
+            ),
+            ReadFwCfgFile(p, e) => write!(f, "failed to read fw_cfg file {}: {}", p.display(), e),
             ReadMemAvailable(e) => write!(
                 f,
                 "failed to read /sys/kernel/mm/chromeos-low_mem/available: {}",
                 e
             ),
             ReadStatm(e) => write!(f, "failed to read /proc/self/statm: {}", e),
+            ReceiveNetHelperDevices(p, e) => write!(
+                f,
+                "failed to receive network devices from the helper at {}: {}",
+                p.display(),
+                e
+            ),
             RegisterBalloon(e) => write!(f, "error registering balloon device: {}", e),
             RegisterBlock(e) => write!(f, "error registering block device: {}", e),
             RegisterGpu(e) => write!(f, "error registering gpu device: {}", e),
@@ -298,24 +420,68 @@ impl Display for Error {
             RunnableVcpu(e) => write!(f, "failed to set thread id for vcpu: {}", e),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             SendDebugStatus(e) => write!(f, "failed to send a debug status to GDB thread: {}", e),
+            SetHousekeepingAffinity(e) => {
+                write!(f, "failed to move to the housekeeping CPUs: {}", e)
+            }
             SettingGidMap(e) => write!(f, "error setting GID map: {}", e),
             SettingMaxOpenFiles(e) => write!(f, "error setting max open files: {}", e),
             SettingSignalMask(e) => write!(f, "failed to set the signal mask for vcpu: {}", e),
             SettingUidMap(e) => write!(f, "error setting UID map: {}", e),
             SignalFd(e) => write!(f, "failed to read signal fd: {}", e),
+            SnapshotDevice(label) => {
+                write!(f, "device {} failed to save or restore its state", label)
+            }
+            SnapshotDeviceCount(snapshot, vm) => write!(
+                f,
+                "the snapshot has {} devices but the VM has {}",
+                snapshot, vm
+            ),
+            SnapshotFile(e) => write!(f, "failed to read or write the snapshot file: {}", e),
+            SnapshotHeader => write!(f, "not a VM snapshot of a supported version"),
+            SnapshotIrqChip(e) => write!(f, "failed to save or restore the irqchip: {}", e),
+            SnapshotMemory(e) => write!(f, "failed to save or restore guest memory: {}", e),
+            SnapshotPoll(e) => write!(f, "failed to wait for the devices to restore: {}", e),
+            SnapshotState(e) => write!(f, "invalid VM snapshot state: {}", e),
+            SnapshotUnsupported(label) => write!(
+                f,
+                "`--snapshot` was given, but device {} can't be saved in a snapshot",
+                label
+            ),
+            SnapshotVcpu(cpu_id) => {
+                write!(f, "vcpu {} failed to save or restore its state", cpu_id)
+            }
+            SnapshotVcpuCount(snapshot, vm) => write!(
+                f,
+                "the snapshot has {} VCPUs but the VM has {}",
+                snapshot, vm
+            ),
+            #[cfg(feature = "audio")]
+            SoundDeviceNew(e) => write!(f, "failed to set up virtio sound device: {}", e),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             SpawnGdbServer(e) => write!(f, "failed to spawn GDB thread: {}", e),
+            SpawnSnapshotThread(e) => {
+                write!(f, "failed to spawn the snapshot restore thread: {}", e)
+            }
             SpawnVcpu(e) => write!(f, "failed to spawn VCPU thread: {}", e),
+            StartMetricsServer(e) => write!(f, "failed to start the metrics server: {}", e),
             Timer(e) => write!(f, "failed to read timer fd: {}", e),
+            UnplugVfioDevice(e) => write!(f, "failed to remove vfio device: {}", e),
             ValidateRawDescriptor(e) => write!(f, "failed to validate raw descriptor: {}", e),
             VhostNetDeviceNew(e) => write!(f, "failed to set up vhost networking: {}", e),
+            VhostUserAccept(e) => write!(f, "failed to accept a vhost-user frontend: {}", e),
+            VhostUserDeviceNew(e) => write!(f, "failed to set up vhost-user device: {}", e),
             VhostVsockDeviceNew(e) => write!(f, "failed to set up virtual socket device: {}", e),
+            VirtioMemDeviceNew(e) => write!(f, "failed to create virtio-mem device: {}", e),
+            VirtioMemMapping(e) => write!(f, "failed to map virtio-mem memory: {}", e),
+            VirtioMemMemory(e) => write!(f, "failed to create virtio-mem memory: {}", e),
+            VirtioMemTooBig => write!(f, "failed to create virtio-mem device: size too big"),
             VirtioPciDev(e) => write!(f, "failed to create virtio pci dev: {}", e),
             WaitContextAdd(e) => write!(f, "failed to add descriptor to wait context: {}", e),
             WaitContextDelete(e) => {
                 write!(f, "failed to remove descriptor from wait context: {}", e)
             }
             WaylandDeviceNew(e) => write!(f, "failed to create wayland device: {}", e),
+            WriteConfigDrive(e) => write!(f, "failed to write config drive: {}", e),
         }
     }
 }
@@ -371,6 +537,28 @@ fn get_max_open_files() -> Result<u64> {
     }
 }
 
+// Locks guest memory, after warning if the memlock limit is too low for it to work unless crosvm
+// has CAP_IPC_LOCK.
+fn lock_guest_memory(mem: &GuestMemory) -> Result<()> {
+    let mut buf = mem::MaybeUninit::<libc::rlimit64>::zeroed();
+
+    // Safe because this will only modify `buf` and we check the return value.
+    let res = unsafe { libc::prlimit64(0, libc::RLIMIT_MEMLOCK, ptr::null(), buf.as_mut_ptr()) };
+    if res == 0 {
+        // Safe because the kernel guarantees that the struct is fully initialized.
+        let limit = unsafe { buf.assume_init() };
+        if limit.rlim_cur != libc::RLIM64_INFINITY && limit.rlim_cur < mem.memory_size() {
+            warn!(
+                "RLIMIT_MEMLOCK of {} bytes is less than the {} bytes of guest memory to lock",
+                limit.rlim_cur,
+                mem.memory_size()
+            );
+        }
+    }
+
+    mem.mlock().map_err(Error::LockGuestMemory)
+}
+
 struct SandboxConfig<'a> {
     limit_caps: bool,
     log_failures: bool,
@@ -430,8 +618,24 @@ fn create_base_minijail(
             if config.log_failures {
                 j.log_seccomp_filter_failures();
             }
-            j.parse_seccomp_filters(&config.seccomp_policy.with_extension("policy"))
-                .map_err(Error::DeviceJail)?;
+            // Devices without a policy file fall back to the policy built into crosvm.
+            let policy_file = config.seccomp_policy.with_extension("policy");
+            let name = config
+                .seccomp_policy
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            match seccomp::embedded_policy(name) {
+                Some(policy) if !policy_file.exists() => {
+                    let file = seccomp::policy_file(name, &policy)
+                        .map_err(Error::EmbeddedSeccompPolicy)?;
+                    let path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_descriptor()));
+                    j.parse_seccomp_filters(&path).map_err(Error::DeviceJail)?;
+                }
+                _ => j
+                    .parse_seccomp_filters(&policy_file)
+                    .map_err(Error::DeviceJail)?,
+            }
         }
         j.use_seccomp_filter();
         // Don't do init setup.
@@ -462,12 +666,13 @@ fn simple_jail(cfg: &Config, policy: &str) -> Result<Option<Minijail>> {
             return Err(Error::PivotRootDoesntExist(pivot_root));
         }
         let policy_path: PathBuf = cfg.seccomp_policy_dir.join(policy);
+        let id_maps = cfg.jail_id_maps.get(policy);
         let config = SandboxConfig {
             limit_caps: true,
             log_failures: cfg.seccomp_log_failures,
             seccomp_policy: &policy_path,
-            uid_map: None,
-            gid_map: None,
+            uid_map: id_maps.and_then(|maps| maps.uid_map.as_deref()),
+            gid_map: id_maps.and_then(|maps| maps.gid_map.as_deref()),
         };
         Ok(Some(create_base_minijail(root_path, None, Some(&config))?))
     } else {
@@ -477,11 +682,26 @@ fn simple_jail(cfg: &Config, policy: &str) -> Result<Option<Minijail>> {
 
 type DeviceResult<T = VirtioDeviceStub> = std::result::Result<T, Error>;
 
-fn create_block_device(
-    cfg: &Config,
-    disk: &DiskOption,
-    disk_device_socket: DiskControlResponseSocket,
-) -> DeviceResult {
+// The accesses a device sharing a directory with the guest needs to it: any but executing files.
+const SHARED_DIR_LANDLOCK_ACCESS: u64 = LANDLOCK_ACCESS_FS_ALL & !LANDLOCK_ACCESS_FS_EXECUTE;
+
+// Makes the Landlock ruleset for a device process that may only open the files in `paths`, each
+// with its `LANDLOCK_ACCESS_FS_*` rights. Returns `None` unless the config asks for Landlock.
+fn landlock_ruleset(cfg: &Config, paths: &[(&Path, u64)]) -> Result<Option<LandlockRuleset>> {
+    if !cfg.landlock {
+        return Ok(None);
+    }
+    let mut ruleset = LandlockRuleset::new().map_err(Error::CreateLandlockRuleset)?;
+    for &(path, access) in paths {
+        ruleset
+            .allow_path(path, access)
+            .map_err(|e| Error::LandlockAllowPath(path.to_owned(), e))?;
+    }
+    Ok(Some(ruleset))
+}
+
+// Opens the image of `disk`, locked against use by other crosvm instances.
+fn open_disk_image(disk: &DiskOption) -> Result<Box<dyn disk::DiskFile>> {
     // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
     let raw_image: File = if disk.path.parent() == Some(Path::new("/proc/self/fd")) {
         // Safe because we will validate |raw_fd|.
@@ -501,8 +721,16 @@ fn create_block_device(
     };
     flock(&raw_image, lock_op, true).map_err(Error::DiskImageLock)?;
 
-    let disk_file = disk::create_disk_file(raw_image).map_err(Error::CreateDiskError)?;
-    let dev = virtio::Block::new(
+    disk::create_disk_file(raw_image).map_err(Error::CreateDiskError)
+}
+
+fn create_block_device(
+    cfg: &Config,
+    disk: &DiskOption,
+    disk_device_socket: DiskControlResponseSocket,
+) -> DeviceResult {
+    let disk_file = open_disk_image(disk)?;
+    let mut dev = virtio::Block::new(
         virtio::base_features(cfg.protected_vm),
         disk_file,
         disk.read_only,
@@ -512,6 +740,205 @@ fn create_block_device(
         Some(disk_device_socket),
     )
     .map_err(Error::BlockDeviceNew)?;
+    // Inherited descriptors need no rule, as Landlock only checks files being opened.
+    let access = if disk.read_only {
+        LANDLOCK_ACCESS_FS_READ_FILE
+    } else {
+        LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_WRITE_FILE
+    };
+    let mut paths = Vec::new();
+    if disk.path.parent() != Some(Path::new("/proc/self/fd")) {
+        paths.push((disk.path.as_path(), access));
+    }
+    if let Some(ruleset) = landlock_ruleset(cfg, &paths)? {
+        dev.set_landlock_ruleset(ruleset);
+    }
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "block_device")?,
+    })
+}
+
+/// Serves `disk` as a virtio block device to the vhost-user frontends connecting to the unix socket
+/// at `socket_path`, one at a time, until crosvm is killed.
+pub fn run_block_device(socket_path: &Path, disk: &DiskOption) -> Result<()> {
+    let disk_file = open_disk_image(disk)?;
+    let dev = virtio::Block::new(
+        virtio::base_features(false),
+        disk_file,
+        disk.read_only,
+        disk.sparse,
+        disk.block_size,
+        disk.id,
+        None,
+    )
+    .map_err(Error::BlockDeviceNew)?;
+    serve_vhost_user_device(socket_path, Box::new(dev))
+}
+
+/// Serves a virtio net device on `tap` with `vq_pairs` queue pairs, like `run_block_device`.
+pub fn run_net_device(socket_path: &Path, tap: &NetDeviceTap, vq_pairs: u16) -> Result<()> {
+    let features = virtio::base_features(false);
+    let dev = match tap {
+        NetDeviceTap::Name(name) => {
+            let tap = Tap::new_with_name(name.as_bytes(), true, vq_pairs > 1)
+                .map_err(Error::CreateTapDevice)?;
+            virtio::Net::from(features, tap, vq_pairs)
+        }
+        NetDeviceTap::Fd(fd) => {
+            // Safe because we ensure that we get a unique handle to the fd.
+            let tap = unsafe {
+                Tap::from_raw_descriptor(
+                    validate_raw_descriptor(*fd).map_err(Error::ValidateRawDescriptor)?,
+                )
+                .map_err(Error::CreateTapDevice)?
+            };
+            virtio::Net::from(features, tap, vq_pairs)
+        }
+        NetDeviceTap::New {
+            host_ip,
+            netmask,
+            mac_address,
+        } => virtio::Net::<Tap>::new(features, *host_ip, *netmask, *mac_address, vq_pairs),
+    }
+    .map_err(Error::NetDeviceNew)?;
+    serve_vhost_user_device(socket_path, Box::new(dev))
+}
+
+/// Serves a virtio gpu device with `gpu_parameters`, like `run_block_device`. The device displays
+/// on the Wayland compositor at `wayland_socket_path` or else on `x_display`, if it can, and maps
+/// host visible blobs into the shared memory region its frontend gives it.
+#[cfg(feature = "gpu")]
+pub fn run_gpu_device(
+    socket_path: &Path,
+    gpu_parameters: &GpuParameters,
+    wayland_socket_path: Option<&Path>,
+    x_display: Option<String>,
+) -> Result<()> {
+    let mut display_backends = vec![
+        virtio::DisplayBackend::X(x_display),
+        virtio::DisplayBackend::Stub,
+    ];
+    let mut channels = BTreeMap::new();
+    if let Some(path) = wayland_socket_path {
+        display_backends.insert(0, virtio::DisplayBackend::Wayland(Some(path.to_owned())));
+        channels.insert(String::new(), path.to_owned());
+    }
+
+    // Nothing is waiting for the display to be closed in this process.
+    let exit_evt = Event::new().map_err(Error::CreateEvent)?;
+    let dev = virtio::Gpu::new(
+        exit_evt,
+        None,
+        None,
+        NonZeroU8::new(1).unwrap(), // number of scanouts
+        Vec::new(),
+        display_backends,
+        gpu_parameters,
+        Vec::new(),
+        Arc::new(Mutex::new(None)),
+        // The frontend maps blobs from another process, which needs them exported as files.
+        true,
+        virtio::base_features(false),
+        channels,
+    );
+    serve_vhost_user_device(socket_path, Box::new(dev))
+}
+
+// Serves `dev` to the vhost-user frontends connecting to `socket_path`, one at a time.
+fn serve_vhost_user_device(socket_path: &Path, dev: Box<dyn VirtioDevice>) -> Result<()> {
+    let listener = UnixListener::bind(socket_path).map_err(Error::CreateSocket)?;
+    let mut backend = virtio::VhostUserBackend::new(dev);
+    loop {
+        let (socket, _) = listener.accept().map_err(Error::VhostUserAccept)?;
+        info!("vhost-user frontend connected");
+        match backend.serve(&socket) {
+            Ok(()) => info!("vhost-user frontend disconnected"),
+            Err(e) => error!("vhost-user session failed: {}", e),
+        }
+    }
+}
+
+// cloud-init looks for a NoCloud drive by this label.
+const CONFIG_DRIVE_VOLUME_ID: &str = "CIDATA";
+const CONFIG_DRIVE_INSTANCE_ID: &str = "iid-crosvm";
+const CONFIG_DRIVE_BLOCK_SIZE: u32 = 512;
+
+// Quotes `value` as a double quoted YAML scalar so that it is never reinterpreted.
+fn yaml_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn config_drive_meta_data(metadata: &[(String, String)]) -> String {
+    let mut meta_data = String::new();
+    // cloud-init refuses to run without an instance id.
+    if !metadata.iter().any(|(key, _)| key == "instance-id") {
+        meta_data.push_str(&format!(
+            "instance-id: {}\n",
+            yaml_quote(CONFIG_DRIVE_INSTANCE_ID)
+        ));
+    }
+    for (key, value) in metadata {
+        meta_data.push_str(&format!("{}: {}\n", key, yaml_quote(value)));
+    }
+    meta_data
+}
+
+// Builds a read-only ISO 9660 drive in the layout of cloud-init's NoCloud data source, holding the
+// `--metadata-file` files and a meta-data file generated from the `--metadata` pairs.
+fn create_config_drive_device(cfg: &Config) -> DeviceResult {
+    let mut contents = Vec::new();
+    for (name, path) in &cfg.metadata_files {
+        let data = fs::read(path).map_err(|e| Error::ReadConfigDriveFile(path.clone(), e))?;
+        contents.push((name.as_str(), data));
+    }
+    if !contents.iter().any(|(name, _)| *name == "meta-data") {
+        contents.push((
+            "meta-data",
+            config_drive_meta_data(&cfg.metadata).into_bytes(),
+        ));
+    }
+    // NoCloud ignores drives without a user-data file, even if it would be empty.
+    if !contents.iter().any(|(name, _)| *name == "user-data") {
+        contents.push(("user-data", Vec::new()));
+    }
+    let files: Vec<(&str, &[u8])> = contents
+        .iter()
+        .map(|(name, data)| (*name, data.as_slice()))
+        .collect();
+    let image =
+        disk::create_iso_image(CONFIG_DRIVE_VOLUME_ID, &files).map_err(Error::CreateConfigDrive)?;
+
+    let mut drive: File = SharedMemory::named("crosvm_config_drive", image.len() as u64)
+        .map_err(|e| Error::WriteConfigDrive(e.into()))?
+        .into();
+    drive.write_all(&image).map_err(Error::WriteConfigDrive)?;
+
+    let mut dev = virtio::Block::new(
+        virtio::base_features(cfg.protected_vm),
+        Box::new(drive),
+        true,  /* read_only */
+        false, /* sparse */
+        CONFIG_DRIVE_BLOCK_SIZE,
+        None,
+        None,
+    )
+    .map_err(Error::BlockDeviceNew)?;
+    if let Some(ruleset) = landlock_ruleset(cfg, &[])? {
+        dev.set_landlock_ruleset(ruleset);
+    }
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -520,8 +947,11 @@ fn create_block_device(
 }
 
 fn create_rng_device(cfg: &Config) -> DeviceResult {
-    let dev =
-        virtio::Rng::new(virtio::base_features(cfg.protected_vm)).map_err(Error::RngDeviceNew)?;
+    let dev = virtio::Rng::new(
+        virtio::base_features(cfg.protected_vm),
+        cfg.rng_parameters.clone(),
+    )
+    .map_err(Error::RngDeviceNew)?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -529,6 +959,45 @@ fn create_rng_device(cfg: &Config) -> DeviceResult {
     })
 }
 
+#[cfg(feature = "audio")]
+fn create_sound_device(cfg: &Config, params: &virtio::snd::SoundParameters) -> DeviceResult {
+    let dev = virtio::snd::Sound::new(virtio::base_features(cfg.protected_vm), params.clone())
+        .map_err(Error::SoundDeviceNew)?;
+    let jail = simple_jail(&cfg, dev.minijail_policy())?;
+
+    #[cfg(feature = "audio_alsa")]
+    let jail = match (jail, &params.backend) {
+        (Some(mut jail), virtio::snd::SoundBackend::Alsa) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the ALSA
+            // device nodes and configuration into it.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=67108864",
+            )?;
+
+            let dev_snd_path = Path::new("/dev/snd");
+            jail.mount_bind(dev_snd_path, dev_snd_path, true)?;
+            // Configuration read by libasound when opening a PCM.
+            for path in &["/usr/share/alsa", "/etc/asound.conf"] {
+                let path = Path::new(path);
+                if path.exists() {
+                    jail.mount_bind(path, path, false)?;
+                }
+            }
+            Some(jail)
+        }
+        (jail, _) => jail,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 #[cfg(feature = "tpm")]
 fn create_tpm_device(cfg: &Config) -> DeviceResult {
     use base::chown;
@@ -570,7 +1039,7 @@ fn create_tpm_device(cfg: &Config) -> DeviceResult {
         }
     }
 
-    let dev = virtio::Tpm::new(tpm_storage);
+    let dev = virtio::Tpm::new(virtio::TpmBackend::Simulator(tpm_storage));
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -578,6 +1047,17 @@ fn create_tpm_device(cfg: &Config) -> DeviceResult {
     })
 }
 
+#[cfg(feature = "tpm")]
+fn create_swtpm_device(cfg: &Config, ctrl_path: &Path) -> DeviceResult {
+    let swtpm = virtio::Swtpm::connect(ctrl_path).map_err(Error::ConnectSwtpm)?;
+    let dev = virtio::Tpm::new(virtio::TpmBackend::Swtpm(swtpm));
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "tpm_device")?,
+    })
+}
+
 fn create_single_touch_device(cfg: &Config, single_touch_spec: &TouchDeviceOption) -> DeviceResult {
     let socket = single_touch_spec
         .get_path()
@@ -693,8 +1173,12 @@ fn create_vinput_device(cfg: &Config, dev_path: &Path) -> DeviceResult {
 }
 
 fn create_balloon_device(cfg: &Config, socket: BalloonControlResponseSocket) -> DeviceResult {
-    let dev = virtio::Balloon::new(virtio::base_features(cfg.protected_vm), socket)
-        .map_err(Error::BalloonDeviceNew)?;
+    let dev = virtio::Balloon::new(
+        virtio::base_features(cfg.protected_vm),
+        socket,
+        cfg.balloon_guest_requests.is_some(),
+    )
+    .map_err(Error::BalloonDeviceNew)?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -702,15 +1186,7 @@ fn create_balloon_device(cfg: &Config, socket: BalloonControlResponseSocket) ->
     })
 }
 
-fn create_tap_net_device(cfg: &Config, tap_fd: RawDescriptor) -> DeviceResult {
-    // Safe because we ensure that we get a unique handle to the fd.
-    let tap = unsafe {
-        Tap::from_raw_descriptor(
-            validate_raw_descriptor(tap_fd).map_err(Error::ValidateRawDescriptor)?,
-        )
-        .map_err(Error::CreateTapDevice)?
-    };
-
+fn create_tap_net_device(cfg: &Config, tap: Tap) -> DeviceResult {
     let mut vq_pairs = cfg.net_vq_pairs.unwrap_or(1);
     let vcpu_count = cfg.vcpu_count.unwrap_or(1);
     if vcpu_count < vq_pairs as usize {
@@ -726,6 +1202,26 @@ fn create_tap_net_device(cfg: &Config, tap_fd: RawDescriptor) -> DeviceResult {
     })
 }
 
+fn create_vhost_tap_net_device(
+    cfg: &Config,
+    tap: Tap,
+    vhost_net: File,
+    mem: &GuestMemory,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::vhost::Net::<Tap, vhost::Net<Tap>>::from(
+        features,
+        tap,
+        vhost::Net::from_file(vhost_net, mem),
+    )
+    .map_err(Error::VhostNetDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "vhost_net_device")?,
+    })
+}
+
 fn create_net_device(
     cfg: &Config,
     host_ip: Ipv4Addr,
@@ -774,6 +1270,7 @@ fn create_gpu_device(
     cfg: &Config,
     exit_evt: &Event,
     gpu_device_socket: VmMemoryControlRequestSocket,
+    gpu_control_socket: GpuControlResponseSocket,
     gpu_sockets: Vec<virtio::resource_bridge::ResourceResponseSocket>,
     wayland_socket_path: Option<&PathBuf>,
     x_display: Option<String>,
@@ -808,6 +1305,7 @@ fn create_gpu_device(
     let dev = virtio::Gpu::new(
         exit_evt.try_clone().map_err(Error::CloneEvent)?,
         Some(gpu_device_socket),
+        Some(gpu_control_socket),
         NonZeroU8::new(1).unwrap(), // number of scanouts
         gpu_sockets,
         display_backends,
@@ -1071,6 +1569,62 @@ fn create_vhost_vsock_device(cfg: &Config, cid: u64, mem: &GuestMemory) -> Devic
     })
 }
 
+fn create_vhost_user_vsock_device(
+    cfg: &Config,
+    socket_path: &Path,
+    mem: &GuestMemory,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::vhost::Vsock::new_vhost_user(features, socket_path, mem)
+        .map_err(Error::VhostVsockDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "vhost_user_vsock_device")?,
+    })
+}
+
+fn create_vhost_user_device(
+    cfg: &Config,
+    opt: &VhostUserOption,
+    mem: &GuestMemory,
+    shm_socket: Option<FsMappingRequestSocket>,
+    vhost_user_registry: &VhostUserRegistry,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let mut dev =
+        virtio::vhost::VhostUserDevice::new(features, opt.device_type, &opt.socket_path, mem)
+            .map_err(Error::VhostUserDeviceNew)?;
+    if let (Some(size), Some(socket)) = (opt.shm_size, shm_socket) {
+        dev.set_shared_memory_region(opt.shm_id, size, socket);
+    }
+    dev.set_status(vhost_user_registry.register(dev.debug_label(), &opt.socket_path));
+
+    let jail = match simple_jail(&cfg, "vhost_user_device")? {
+        Some(mut jail) => {
+            // Bind mount the directory of the backend's socket into a tmpfs root, so that the
+            // device can connect again to a backend that restarts and listens there anew.
+            if let Some(dir) = opt.socket_path.parent().filter(|dir| dir.is_absolute()) {
+                jail.mount_with_data(
+                    Path::new("none"),
+                    Path::new("/"),
+                    "tmpfs",
+                    (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                    "size=67108864",
+                )?;
+                jail.mount_bind(dir, dir, true)?;
+            }
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 fn create_fs_device(
     cfg: &Config,
     uid_map: &str,
@@ -1103,8 +1657,11 @@ fn create_fs_device(
     let features = virtio::base_features(cfg.protected_vm);
     // TODO(chirantan): Use more than one worker once the kernel driver has been fixed to not panic
     // when num_queues > 1.
-    let dev =
+    let mut dev =
         virtio::fs::Fs::new(features, tag, 1, fs_cfg, device_socket).map_err(Error::FsDeviceNew)?;
+    if let Some(ruleset) = landlock_ruleset(cfg, &[(src, SHARED_DIR_LANDLOCK_ACCESS)])? {
+        dev.set_landlock_ruleset(ruleset);
+    }
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -1147,7 +1704,10 @@ fn create_9p_device(
 
     let features = virtio::base_features(cfg.protected_vm);
     p9_cfg.root = root.into();
-    let dev = virtio::P9::new(features, tag, p9_cfg).map_err(Error::P9DeviceNew)?;
+    let mut dev = virtio::P9::new(features, tag, p9_cfg).map_err(Error::P9DeviceNew)?;
+    if let Some(ruleset) = landlock_ruleset(cfg, &[(src, SHARED_DIR_LANDLOCK_ACCESS)])? {
+        dev.set_landlock_ruleset(ruleset);
+    }
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -1244,12 +1804,71 @@ fn create_pmem_device(
     })
 }
 
+fn create_virtio_mem_device(
+    cfg: &Config,
+    vm: &mut impl Vm,
+    resources: &mut SystemAllocator,
+    size_mib: u64,
+    socket: MemControlResponseSocket,
+) -> DeviceResult {
+    let region_size = size_mib
+        .checked_mul(1024 * 1024)
+        .ok_or(Error::VirtioMemTooBig)?;
+    // Conversion from u64 to usize may fail on 32bit system.
+    let mapping_size = usize::try_from(region_size).map_err(|_| Error::VirtioMemTooBig)?;
+
+    // The region is backed by its own shared memory so that unplugged blocks can be given back to
+    // the host by punching holes in it.
+    let memory =
+        SharedMemory::named("crosvm_virtio_mem", region_size).map_err(Error::VirtioMemMemory)?;
+    let mapping = MemoryMappingBuilder::new(mapping_size)
+        .from_descriptor(&memory)
+        .build()
+        .map_err(Error::VirtioMemMapping)?;
+
+    let mapping_address = resources
+        .mmio_allocator(MmioType::High)
+        .allocate_with_align(
+            region_size,
+            Alloc::VirtioMem,
+            "virtio_mem".to_string(),
+            // Linux hotplugs memory in 128 MiB sections.
+            128 * 1024 * 1024, /* 128 MiB */
+        )
+        .map_err(Error::AllocateVirtioMemDeviceAddress)?;
+
+    vm.add_memory_region(
+        GuestAddress(mapping_address),
+        Box::new(mapping),
+        /* read_only = */ false,
+        /* log_dirty_pages = */ false,
+    )
+    .map_err(Error::AddVirtioMemDeviceMemory)?;
+
+    let dev = virtio::Mem::new(
+        virtio::base_features(cfg.protected_vm),
+        socket,
+        memory,
+        GuestAddress(mapping_address),
+        region_size,
+    )
+    .map_err(Error::VirtioMemDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "virtio_mem_device")?,
+    })
+}
+
 fn create_console_device(cfg: &Config, param: &SerialParameters) -> DeviceResult {
     let mut keep_rds = Vec::new();
     let evt = Event::new().map_err(Error::CreateEvent)?;
-    let dev = param
+    let mut dev = param
         .create_serial_device::<Console>(cfg.protected_vm, &evt, &mut keep_rds)
         .map_err(Error::CreateConsole)?;
+    if param.stdin {
+        dev.track_stdin_window_size();
+    }
 
     let jail = match simple_jail(&cfg, "serial")? {
         Some(mut jail) => {
@@ -1279,7 +1898,7 @@ fn create_console_device(cfg: &Config, param: &SerialParameters) -> DeviceResult
     })
 }
 
-// gpu_device_socket is not used when GPU support is disabled.
+// gpu_device_socket and gpu_control_socket are not used when GPU support is disabled.
 #[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
 fn create_virtio_devices(
     cfg: &Config,
@@ -1289,11 +1908,14 @@ fn create_virtio_devices(
     _exit_evt: &Event,
     wayland_device_socket: VmMemoryControlRequestSocket,
     gpu_device_socket: VmMemoryControlRequestSocket,
-    balloon_device_socket: BalloonControlResponseSocket,
+    gpu_control_socket: GpuControlResponseSocket,
+    balloon_device_socket: Option<BalloonControlResponseSocket>,
+    mem_device_socket: Option<MemControlResponseSocket>,
     disk_device_sockets: &mut Vec<DiskControlResponseSocket>,
     pmem_device_sockets: &mut Vec<VmMsyncRequestSocket>,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
     fs_device_sockets: &mut Vec<FsMappingRequestSocket>,
+    vhost_user_registry: &VhostUserRegistry,
 ) -> DeviceResult<Vec<VirtioDeviceStub>> {
     let mut devs = Vec::new();
 
@@ -1311,6 +1933,12 @@ fn create_virtio_devices(
         devs.push(create_block_device(cfg, disk, disk_device_socket)?);
     }
 
+    // The config drive comes after the `--disk` devices so that it doesn't shift their names in
+    // the guest.
+    if !cfg.metadata.is_empty() || !cfg.metadata_files.is_empty() {
+        devs.push(create_config_drive_device(cfg)?);
+    }
+
     for (index, pmem_disk) in cfg.pmem_devices.iter().enumerate() {
         let pmem_device_socket = pmem_device_sockets.remove(0);
         devs.push(create_pmem_device(
@@ -1325,11 +1953,21 @@ fn create_virtio_devices(
 
     devs.push(create_rng_device(cfg)?);
 
+    #[cfg(feature = "audio")]
+    {
+        if let Some(sound_params) = &cfg.sound_parameters {
+            devs.push(create_sound_device(cfg, sound_params)?);
+        }
+    }
+
     #[cfg(feature = "tpm")]
     {
         if cfg.software_tpm {
             devs.push(create_tpm_device(cfg)?);
         }
+        if let Some(ctrl_path) = &cfg.swtpm {
+            devs.push(create_swtpm_device(cfg, ctrl_path)?);
+        }
     }
 
     if let Some(single_touch_spec) = &cfg.virtio_single_touch {
@@ -1356,11 +1994,39 @@ fn create_virtio_devices(
         devs.push(create_vinput_device(cfg, dev_path)?);
     }
 
-    devs.push(create_balloon_device(cfg, balloon_device_socket)?);
+    if let Some(socket) = balloon_device_socket {
+        devs.push(create_balloon_device(cfg, socket)?);
+    }
+
+    if let (Some(size_mib), Some(socket)) = (cfg.virtio_mem, mem_device_socket) {
+        devs.push(create_virtio_mem_device(
+            cfg, vm, resources, size_mib, socket,
+        )?);
+    }
 
     // We checked above that if the IP is defined, then the netmask is, too.
     for tap_fd in &cfg.tap_fd {
-        devs.push(create_tap_net_device(cfg, *tap_fd)?);
+        // Safe because we ensure that we get a unique handle to the fd.
+        let tap = unsafe {
+            Tap::from_raw_descriptor(
+                validate_raw_descriptor(*tap_fd).map_err(Error::ValidateRawDescriptor)?,
+            )
+            .map_err(Error::CreateTapDevice)?
+        };
+        devs.push(create_tap_net_device(cfg, tap)?);
+    }
+
+    if let Some(path) = &cfg.net_helper_socket {
+        let helper_devices = net_helper::receive_net_devices(path)
+            .map_err(|e| Error::ReceiveNetHelperDevices(path.clone(), e))?;
+        for device in helper_devices {
+            devs.push(match device {
+                HelperNetDevice::Tap(tap) => create_tap_net_device(cfg, tap)?,
+                HelperNetDevice::VhostNet { tap, vhost_net } => {
+                    create_vhost_tap_net_device(cfg, tap, vhost_net, mem)?
+                }
+            });
+        }
     }
 
     if let (Some(host_ip), Some(netmask), Some(mac_address)) =
@@ -1460,6 +2126,7 @@ fn create_virtio_devices(
                 cfg,
                 _exit_evt,
                 gpu_device_socket,
+                gpu_control_socket,
                 resource_bridges,
                 // Use the unnamed socket for GPU display screens.
                 cfg.wayland_socket_paths.get(""),
@@ -1474,6 +2141,21 @@ fn create_virtio_devices(
         devs.push(create_vhost_vsock_device(cfg, cid, mem)?);
     }
 
+    if let Some(socket_path) = &cfg.vhost_user_vsock {
+        devs.push(create_vhost_user_vsock_device(cfg, socket_path, mem)?);
+    }
+
+    for opt in &cfg.vhost_user {
+        let shm_socket = opt.shm_size.map(|_| fs_device_sockets.remove(0));
+        devs.push(create_vhost_user_device(
+            cfg,
+            opt,
+            mem,
+            shm_socket,
+            vhost_user_registry,
+        )?);
+    }
+
     for shared_dir in &cfg.shared_dirs {
         let SharedDir {
             src,
@@ -1515,13 +2197,20 @@ fn create_devices(
     control_sockets: &mut Vec<TaggedControlSocket>,
     wayland_device_socket: VmMemoryControlRequestSocket,
     gpu_device_socket: VmMemoryControlRequestSocket,
-    balloon_device_socket: BalloonControlResponseSocket,
+    gpu_control_socket: GpuControlResponseSocket,
+    balloon_device_socket: Option<BalloonControlResponseSocket>,
+    mem_device_socket: Option<MemControlResponseSocket>,
     disk_device_sockets: &mut Vec<DiskControlResponseSocket>,
     pmem_device_sockets: &mut Vec<VmMsyncRequestSocket>,
     fs_device_sockets: &mut Vec<FsMappingRequestSocket>,
     usb_provider: HostBackendDeviceProvider,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
-) -> DeviceResult<Vec<(Box<dyn PciDevice>, Option<Minijail>)>> {
+    device_executors: &mut Vec<DeviceExecutor>,
+    device_metrics: &MetricsRegistry,
+    vhost_user_registry: &VhostUserRegistry,
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+    hotplug_slots: &mut Vec<PcieHotplugSlot>,
+) -> DeviceResult<VmDevices> {
     let stubs = create_virtio_devices(
         &cfg,
         mem,
@@ -1530,23 +2219,26 @@ fn create_devices(
         exit_evt,
         wayland_device_socket,
         gpu_device_socket,
+        gpu_control_socket,
         balloon_device_socket,
+        mem_device_socket,
         disk_device_sockets,
         pmem_device_sockets,
         map_request,
         fs_device_sockets,
+        vhost_user_registry,
     )?;
 
     let mut pci_devices = Vec::new();
 
     for stub in stubs {
-        let (msi_host_socket, msi_device_socket) =
-            msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
-        control_sockets.push(TaggedControlSocket::VmIrq(msi_host_socket));
-        let dev = VirtioPciDevice::new(mem.clone(), stub.dev, msi_device_socket)
-            .map_err(Error::VirtioPciDev)?;
-        let dev = Box::new(dev) as Box<dyn PciDevice>;
-        pci_devices.push((dev, stub.jail));
+        pci_devices.push(create_virtio_pci_device(
+            stub,
+            mem,
+            control_sockets,
+            device_executors,
+            device_metrics,
+        )?);
     }
 
     #[cfg(feature = "audio")]
@@ -1556,50 +2248,183 @@ fn create_devices(
         pci_devices.push((Box::new(dev), jail));
     }
 
-    // Create xhci controller.
-    let usb_controller = Box::new(XhciController::new(mem.clone(), usb_provider));
-    pci_devices.push((usb_controller, simple_jail(&cfg, "xhci")?));
+    for port_number in 0..cfg.pcie_root_ports {
+        let root_port = PcieRootPort::new(port_number).map_err(Error::CreateEvent)?;
+        hotplug_slots.push(root_port.hotplug_slot());
+        pci_devices.push((Box::new(root_port), None));
+    }
 
-    if !cfg.vfio.is_empty() {
-        let vfio_container = Arc::new(Mutex::new(
-            VfioContainer::new().map_err(Error::CreateVfioDevice)?,
-        ));
+    // Create xhci controller. It can't be saved, so VMs that can be snapshotted have no USB.
+    if !cfg.snapshot {
+        let usb_controller = Box::new(XhciController::new(mem.clone(), usb_provider));
+        pci_devices.push((usb_controller, simple_jail(&cfg, "xhci")?));
+    }
 
-        for vfio_path in &cfg.vfio {
-            // create MSI, MSI-X, and Mem request sockets for each vfio device
-            let (vfio_host_socket_msi, vfio_device_socket_msi) =
-                msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
-            control_sockets.push(TaggedControlSocket::VmIrq(vfio_host_socket_msi));
-
-            let (vfio_host_socket_msix, vfio_device_socket_msix) =
-                msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
-            control_sockets.push(TaggedControlSocket::VmIrq(vfio_host_socket_msix));
-
-            let (vfio_host_socket_mem, vfio_device_socket_mem) =
-                msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>()
-                    .map_err(Error::CreateSocket)?;
-            control_sockets.push(TaggedControlSocket::VmMemory(vfio_host_socket_mem));
-
-            let vfiodevice = VfioDevice::new(vfio_path.as_path(), vm, mem, vfio_container.clone())
-                .map_err(Error::CreateVfioDevice)?;
-            let mut vfiopcidevice = Box::new(VfioPciDevice::new(
-                vfiodevice,
-                vfio_device_socket_msi,
-                vfio_device_socket_msix,
-                vfio_device_socket_mem,
-            ));
-            // early reservation for pass-through PCI devices.
-            if vfiopcidevice.allocate_address(resources).is_err() {
-                warn!(
-                    "address reservation failed for vfio {}",
-                    vfiopcidevice.debug_label()
-                );
+    // Devices behind the virtio-iommu each get a container of their own, which only has the
+    // mappings the guest makes for them.
+    let mut iommu_endpoints: BTreeMap<u32, Arc<Mutex<dyn virtio::MemoryMapper>>> = BTreeMap::new();
+    for vfio_path in &cfg.vfio {
+        let mut iommu_container = None;
+        let container = if cfg.virtio_iommu {
+            iommu_container = Some(Arc::new(Mutex::new(
+                VfioContainer::new_guest_mapped().map_err(Error::CreateVfioDevice)?,
+            )));
+            &mut iommu_container
+        } else {
+            &mut *vfio_container
+        };
+        let mut vfiopcidevice = create_vfio_device(vfio_path, mem, vm, container, control_sockets)?;
+        // early reservation for pass-through PCI devices.
+        match vfiopcidevice.allocate_address(resources) {
+            Ok(address) => {
+                if let Some(container) = iommu_container {
+                    iommu_endpoints.insert(address.to_bdf() as u32, container);
+                }
             }
-            pci_devices.push((vfiopcidevice, simple_jail(&cfg, "vfio_device")?));
+            Err(_) => warn!(
+                "address reservation failed for vfio {}",
+                vfiopcidevice.debug_label()
+            ),
         }
+        pci_devices.push((vfiopcidevice, simple_jail(&cfg, "vfio_device")?));
+    }
+
+    if cfg.virtio_iommu {
+        let iommu = VirtioDeviceStub {
+            dev: Box::new(virtio::Iommu::new(
+                virtio::base_features(cfg.protected_vm),
+                iommu_endpoints,
+            )),
+            jail: simple_jail(&cfg, "iommu_device")?,
+        };
+        pci_devices.push(create_virtio_pci_device(
+            iommu,
+            mem,
+            control_sockets,
+            device_executors,
+            device_metrics,
+        )?);
     }
 
-    Ok(pci_devices)
+    let mut platform_devices = Vec::new();
+    for option in &cfg.vfio_platform {
+        let dev = create_vfio_platform_device(option, mem, vm, vfio_container)?;
+        platform_devices.push((dev, simple_jail(&cfg, "vfio_platform_device")?));
+    }
+
+    Ok(VmDevices {
+        pci: pci_devices,
+        platform: platform_devices,
+    })
+}
+
+// Puts the virtio device of `stub` on the PCI bus.
+fn create_virtio_pci_device(
+    stub: VirtioDeviceStub,
+    mem: &GuestMemory,
+    control_sockets: &mut Vec<TaggedControlSocket>,
+    device_executors: &mut Vec<DeviceExecutor>,
+    device_metrics: &MetricsRegistry,
+) -> DeviceResult<(Box<dyn PciDevice>, Option<Minijail>)> {
+    if stub.dev.uses_async_executor() {
+        // The backend is picked once per process and device processes are forked from this
+        // one, so this matches what the device will use.
+        let (kind, reason) = cros_async::executor_kind();
+        device_executors.push(DeviceExecutor {
+            device: stub.dev.debug_label(),
+            backend: kind.to_string(),
+            reason: reason.to_owned(),
+        });
+    }
+    let (msi_host_socket, msi_device_socket) =
+        msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
+    control_sockets.push(TaggedControlSocket::VmIrq(msi_host_socket));
+    let mut dev = VirtioPciDevice::new(mem.clone(), stub.dev, msi_device_socket)
+        .map_err(Error::VirtioPciDev)?;
+    dev.set_metrics(device_metrics.register(dev.debug_label()));
+    Ok((Box::new(dev), stub.jail))
+}
+
+// The VFIO devices of a VM that aren't behind the virtio-iommu share one container, which is opened
+// the first time one is created, either at boot or when hot-adding.
+fn get_vfio_container(
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+) -> DeviceResult<Arc<Mutex<VfioContainer>>> {
+    match vfio_container {
+        Some(container) => Ok(container.clone()),
+        None => {
+            let container = Arc::new(Mutex::new(
+                VfioContainer::new().map_err(Error::CreateVfioDevice)?,
+            ));
+            *vfio_container = Some(container.clone());
+            Ok(container)
+        }
+    }
+}
+
+fn create_vfio_device(
+    vfio_path: &Path,
+    mem: &GuestMemory,
+    vm: &impl Vm,
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+    control_sockets: &mut Vec<TaggedControlSocket>,
+) -> DeviceResult<Box<VfioPciDevice>> {
+    let container = get_vfio_container(vfio_container)?;
+
+    // create MSI, MSI-X, and Mem request sockets for each vfio device
+    let (vfio_host_socket_msi, vfio_device_socket_msi) =
+        msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
+    let (vfio_host_socket_msix, vfio_device_socket_msix) =
+        msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
+    let (vfio_host_socket_mem, vfio_device_socket_mem) =
+        msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
+
+    let vfiodevice =
+        VfioDevice::new(vfio_path, vm, mem, container).map_err(Error::CreateVfioDevice)?;
+    if vfiodevice.device_type() != VfioDeviceType::Pci {
+        return Err(Error::NotVfioPciDevice(vfio_path.to_owned()));
+    }
+    control_sockets.push(TaggedControlSocket::VmIrq(vfio_host_socket_msi));
+    control_sockets.push(TaggedControlSocket::VmIrq(vfio_host_socket_msix));
+    control_sockets.push(TaggedControlSocket::VmMemory(vfio_host_socket_mem));
+    Ok(Box::new(VfioPciDevice::new(
+        vfiodevice,
+        vfio_device_socket_msi,
+        vfio_device_socket_msix,
+        vfio_device_socket_mem,
+    )))
+}
+
+fn create_vfio_platform_device(
+    option: &VfioPlatformOption,
+    mem: &GuestMemory,
+    vm: &impl Vm,
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+) -> DeviceResult<VfioPlatformDevice> {
+    let container = get_vfio_container(vfio_container)?;
+    let vfiodevice =
+        VfioDevice::new(&option.path, vm, mem, container).map_err(Error::CreateVfioDevice)?;
+    if vfiodevice.device_type() != VfioDeviceType::Platform {
+        return Err(Error::NotVfioPlatformDevice(option.path.clone()));
+    }
+
+    // The guest's node is compatible with whatever the host's node is, so the guest binds the
+    // same driver to the device.
+    let dt_compatible = if option.dt_node {
+        let path = option.path.join("of_node/compatible");
+        let compatible = fs::read(&path).map_err(|e| Error::ReadDtCompatible(path.clone(), e))?;
+        Some(
+            compatible
+                .split(|&b| b == 0)
+                .filter(|c| !c.is_empty())
+                .map(|c| String::from_utf8_lossy(c).into_owned())
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    Ok(VfioPlatformDevice::new(vfiodevice, dt_compatible))
 }
 
 #[derive(Copy, Clone)]
@@ -1712,7 +2537,9 @@ fn runnable_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    cpu_features: &CpuFeatures,
     has_bios: bool,
+    pvh_entry: Option<GuestAddress>,
     use_hypervisor_signals: bool,
 ) -> Result<(V, VcpuRunHandle)>
 where
@@ -1752,7 +2579,9 @@ where
         cpu_id,
         vcpu_count,
         has_bios,
+        pvh_entry,
         no_smt,
+        cpu_features,
     )
     .map_err(Error::ConfigureVcpu)?;
 
@@ -1790,6 +2619,7 @@ fn handle_debug_msg<V>(
     guest_mem: &GuestMemory,
     d: VcpuDebug,
     reply_channel: &mpsc::Sender<VcpuDebugStatusMessage>,
+    keep_stopped: &mut bool,
 ) -> Result<()>
 where
     V: VcpuArch + 'static,
@@ -1815,6 +2645,28 @@ where
                 })
                 .map_err(|e| Error::SendDebugStatus(Box::new(e)))
         }
+        VcpuDebug::ReadReg(reg) => {
+            let msg = VcpuDebugStatusMessage {
+                cpu: cpu_id as usize,
+                msg: VcpuDebugStatus::RegValue(
+                    Arch::debug_read_register(vcpu as &V, reg)
+                        .map_err(Error::HandleDebugCommand)?,
+                ),
+            };
+            reply_channel
+                .send(msg)
+                .map_err(|e| Error::SendDebugStatus(Box::new(e)))
+        }
+        VcpuDebug::WriteReg(reg, data) => {
+            Arch::debug_write_register(vcpu as &V, reg, &data)
+                .map_err(Error::HandleDebugCommand)?;
+            reply_channel
+                .send(VcpuDebugStatusMessage {
+                    cpu: cpu_id as usize,
+                    msg: VcpuDebugStatus::CommandComplete,
+                })
+                .map_err(|e| Error::SendDebugStatus(Box::new(e)))
+        }
         VcpuDebug::ReadMem(vaddr, len) => {
             let msg = VcpuDebugStatusMessage {
                 cpu: cpu_id as usize,
@@ -1846,8 +2698,17 @@ where
                 })
                 .map_err(|e| Error::SendDebugStatus(Box::new(e)))
         }
-        VcpuDebug::SetHwBreakPoint(addrs) => {
-            Arch::debug_set_hw_breakpoints(vcpu as &V, &addrs)
+        VcpuDebug::KeepStopped(keep) => {
+            *keep_stopped = keep;
+            reply_channel
+                .send(VcpuDebugStatusMessage {
+                    cpu: cpu_id as usize,
+                    msg: VcpuDebugStatus::CommandComplete,
+                })
+                .map_err(|e| Error::SendDebugStatus(Box::new(e)))
+        }
+        VcpuDebug::SetHwBreakPoint(addrs, watchpoints) => {
+            Arch::debug_set_hw_breakpoints(vcpu as &V, &addrs, &watchpoints)
                 .map_err(Error::HandleDebugCommand)?;
             reply_channel
                 .send(VcpuDebugStatusMessage {
@@ -1868,8 +2729,10 @@ fn run_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    cpu_features: CpuFeatures,
     start_barrier: Arc<Barrier>,
     has_bios: bool,
+    pvh_entry: Option<GuestAddress>,
     io_bus: devices::Bus,
     mmio_bus: devices::Bus,
     exit_evt: Event,
@@ -1901,7 +2764,9 @@ where
                 run_rt,
                 vcpu_affinity,
                 no_smt,
+                &cpu_features,
                 has_bios,
+                pvh_entry,
                 use_hypervisor_signals,
             );
 
@@ -1921,6 +2786,9 @@ where
                 // Wait until a GDB client attaches
                 run_mode = VmRunMode::Breakpoint;
             }
+            // Whether gdb left this VCPU stopped while it resumes the others.
+            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+            let mut keep_stopped = false;
 
             let mut interrupted_by_signal = false;
 
@@ -1962,6 +2830,10 @@ where
                             match msg {
                                 VcpuControl::RunState(new_mode) => {
                                     run_mode = new_mode;
+                                    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+                                    if keep_stopped && run_mode == VmRunMode::Running {
+                                        run_mode = VmRunMode::Breakpoint;
+                                    }
                                     match run_mode {
                                         VmRunMode::Running => break 'state_loop,
                                         VmRunMode::Suspending => {
@@ -1984,12 +2856,36 @@ where
                                         VmRunMode::Exiting => break 'vcpu_loop,
                                     }
                                 }
+                                VcpuControl::Sync(reply) => {
+                                    let _ = reply.send(());
+                                }
+                                VcpuControl::Snapshot(reply) => {
+                                    let data = match Arch::snapshot_vcpu(&vcpu) {
+                                        Ok(data) => Some(data),
+                                        Err(e) => {
+                                            error!("failed to save vcpu {}: {}", cpu_id, e);
+                                            None
+                                        }
+                                    };
+                                    let _ = reply.send(data);
+                                }
+                                VcpuControl::Restore(data, reply) => {
+                                    let restored = match Arch::restore_vcpu(&vcpu, &data) {
+                                        Ok(()) => true,
+                                        Err(e) => {
+                                            error!("failed to restore vcpu {}: {}", cpu_id, e);
+                                            false
+                                        }
+                                    };
+                                    let _ = reply.send(restored);
+                                }
                                 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
                                 VcpuControl::Debug(d) => {
                                     match &to_gdb_channel {
                                         Some(ref ch) => {
                                             if let Err(e) = handle_debug_msg(
                                                 cpu_id, &vcpu, &guest_mem, d, &ch,
+                                                &mut keep_stopped,
                                             ) {
                                                 error!("Failed to handle gdb message: {}", e);
                                             }
@@ -2076,22 +2972,24 @@ where
                             break;
                         }
                         Ok(VcpuExit::SystemEvent(_, _)) => break,
-                        Ok(VcpuExit::Debug { .. }) => {
-                            #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-                            {
-                                let msg = VcpuDebugStatusMessage {
-                                    cpu: cpu_id as usize,
-                                    msg: VcpuDebugStatus::HitBreakPoint,
-                                };
-                                if let Some(ref ch) = to_gdb_channel {
-                                    if let Err(e) = ch.send(msg) {
-                                        error!("failed to notify breakpoint to GDB thread: {}", e);
-                                        break;
-                                    }
+                        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+                        Ok(VcpuExit::Debug { dr6 }) => {
+                            // DR6 bits 0-3 say which debug register triggered.
+                            let reg = (0..4).find(|i| dr6 & (1 << i) != 0);
+                            let msg = VcpuDebugStatusMessage {
+                                cpu: cpu_id as usize,
+                                msg: VcpuDebugStatus::HitBreakPoint(reg),
+                            };
+                            if let Some(ref ch) = to_gdb_channel {
+                                if let Err(e) = ch.send(msg) {
+                                    error!("failed to notify breakpoint to GDB thread: {}", e);
+                                    break;
                                 }
-                                run_mode = VmRunMode::Breakpoint;
                             }
+                            run_mode = VmRunMode::Breakpoint;
                         }
+                        #[cfg(not(all(target_arch = "x86_64", feature = "gdb")))]
+                        Ok(VcpuExit::Debug { .. }) => {}
                         Ok(r) => warn!("unexpected vcpu exit: {:?}", r),
                         Err(e) => match e.errno() {
                             libc::EINTR => interrupted_by_signal = true,
@@ -2180,6 +3078,12 @@ fn create_kvm_split_irq_chip(
 }
 
 pub fn run_config(cfg: Config) -> Result<()> {
+    match cfg.hypervisor {
+        HypervisorKind::Kvm => run_kvm(cfg),
+    }
+}
+
+fn run_kvm(cfg: Config) -> Result<()> {
     if cfg.split_irqchip {
         #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
         {
@@ -2195,6 +3099,28 @@ pub fn run_config(cfg: Config) -> Result<()> {
     }
 }
 
+// Moves crosvm onto the housekeeping CPUs, so the device processes it forks from now on run there
+// as well. VCPUs without an affinity of their own keep to the CPUs crosvm could run on before.
+fn set_housekeeping_affinity(
+    housekeeping_cpus: &[usize],
+    vcpu_count: usize,
+    vcpu_affinity: Option<VcpuAffinity>,
+) -> Result<Option<VcpuAffinity>> {
+    let cpus = get_cpu_affinity().map_err(Error::GetCpuAffinity)?;
+    set_cpu_affinity(housekeeping_cpus.iter().copied()).map_err(Error::SetHousekeepingAffinity)?;
+
+    Ok(Some(match vcpu_affinity {
+        Some(VcpuAffinity::Global(v)) => VcpuAffinity::Global(v),
+        Some(VcpuAffinity::PerVcpu(mut m)) => {
+            for cpu_id in 0..vcpu_count {
+                m.entry(cpu_id).or_insert_with(|| cpus.clone());
+            }
+            VcpuAffinity::PerVcpu(m)
+        }
+        None => VcpuAffinity::Global(cpus),
+    }))
+}
+
 fn run_vm<V, Vcpu, I, FV, FI>(cfg: Config, create_vm: FV, create_irq_chip: FI) -> Result<()>
 where
     V: VmArch + 'static,
@@ -2214,6 +3140,17 @@ where
         info!("crosvm entering multiprocess mode");
     }
 
+    // Device processes inherit this, so it has to happen before any of them are spawned.
+    if cfg.disable_io_uring {
+        cros_async::disable_uring();
+    }
+    if let Some(idle) = cfg.io_uring_sqpoll {
+        cros_async::enable_uring_sqpoll(idle);
+    }
+    if let Some(categories) = cfg.trace_categories {
+        cros_tracing::init(categories).map_err(Error::OpenTraceMarker)?;
+    }
+
     let (usb_control_socket, usb_provider) =
         HostBackendDeviceProvider::new().map_err(Error::CreateUsbProvider)?;
     // Masking signals is inherently dangerous, since this can persist across clones/execs. Do this
@@ -2239,16 +3176,27 @@ where
 
     let mut control_sockets = Vec::new();
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    let gdb_socket = if let Some(port) = cfg.gdb {
+    let gdb_socket = if let Some(address) = cfg.gdb.clone() {
         // GDB needs a control socket to interrupt vcpus.
         let (gdb_host_socket, gdb_control_socket) =
             msg_socket::pair::<VmResponse, VmRequest>().map_err(Error::CreateSocket)?;
         control_sockets.push(TaggedControlSocket::Vm(gdb_host_socket));
-        Some((port, gdb_control_socket))
+        Some((address, gdb_control_socket))
     } else {
         None
     };
 
+    let vcpu_affinity = if cfg.housekeeping_cpus.is_empty() {
+        cfg.vcpu_affinity.clone()
+    } else {
+        set_housekeeping_affinity(
+            &cfg.housekeeping_cpus,
+            cfg.max_vcpu_count
+                .unwrap_or_else(|| cfg.vcpu_count.unwrap_or(1)),
+            cfg.vcpu_affinity.clone(),
+        )?
+    };
+
     let components = VmComponents {
         memory_size: cfg
             .memory
@@ -2256,8 +3204,12 @@ where
             .checked_mul(1024 * 1024)
             .ok_or(Error::MemoryTooLarge)?,
         vcpu_count: cfg.vcpu_count.unwrap_or(1),
-        vcpu_affinity: cfg.vcpu_affinity.clone(),
+        max_vcpu_count: cfg
+            .max_vcpu_count
+            .unwrap_or_else(|| cfg.vcpu_count.unwrap_or(1)),
+        vcpu_affinity,
         no_smt: cfg.no_smt,
+        cpu_features: cfg.cpu_features.clone(),
         vm_image,
         android_fstab: cfg
             .android_fstab
@@ -2275,6 +3227,56 @@ where
             .collect::<Result<Vec<SDT>>>()?,
         rt_cpus: cfg.rt_cpus.clone(),
         protected_vm: cfg.protected_vm,
+        fw_cfg_kernel: cfg
+            .bios_kernel_path
+            .as_ref()
+            .map(|x| File::open(x).map_err(|e| Error::OpenBiosKernel(x.to_path_buf(), e)))
+            .map_or(Ok(None), |v| v.map(Some))?,
+        fw_cfg_files: cfg
+            .fw_cfg_files
+            .iter()
+            .map(|(name, data)| {
+                let data = match data {
+                    FwCfgData::File(path) => {
+                        fs::read(path).map_err(|e| Error::ReadFwCfgFile(path.clone(), e))?
+                    }
+                    FwCfgData::String(s) => s.clone().into_bytes(),
+                };
+                Ok((name.clone(), data))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        boot_order: cfg.boot_devices.clone(),
+        pflash: cfg
+            .pflash_path
+            .as_ref()
+            .map(|x| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(x)
+                    .map_err(|e| Error::OpenPflash(x.to_path_buf(), e))
+            })
+            .map_or(Ok(None), |v| v.map(Some))?,
+        read_only_bios: cfg.read_only_bios,
+        mergeable_memory: cfg.merge_guest_memory,
+        dt_overlays: cfg
+            .dtbo
+            .iter()
+            .map(|path| fs::read(path).map_err(|e| Error::ReadDtbo(path.clone(), e)))
+            .collect::<Result<Vec<_>>>()?,
+        numa_nodes: cfg
+            .numa_nodes
+            .iter()
+            .map(|node| {
+                Ok(NumaNode {
+                    memory_size: node
+                        .memory_size
+                        .checked_mul(1024 * 1024)
+                        .ok_or(Error::MemoryTooLarge)?,
+                    ..node.clone()
+                })
+            })
+            .collect::<Result<_>>()?,
         #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
         gdb: gdb_socket,
     };
@@ -2286,13 +3288,32 @@ where
         None => None,
     };
 
+    // Lets `crosvm list` find this VM until it exits. The VM runs fine without it.
+    let _registration = cfg.socket_path.as_ref().and_then(|path| {
+        registry::register(path, cfg.name.as_deref(), cfg.memory.unwrap_or(256))
+            .map_err(|e| warn!("failed to add the VM to the registry: {}", e))
+            .ok()
+    });
+
     let (wayland_host_socket, wayland_device_socket) =
         msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
     control_sockets.push(TaggedControlSocket::VmMemory(wayland_host_socket));
     // Balloon gets a special socket so balloon requests can be forwarded from the main process.
-    let (balloon_host_socket, balloon_device_socket) =
-        msg_socket::pair::<BalloonControlCommand, BalloonControlResult>()
+    let (balloon_host_socket, balloon_device_socket) = if cfg.balloon {
+        let (host, device) = msg_socket::pair::<BalloonControlCommand, BalloonControlResult>()
             .map_err(Error::CreateSocket)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
+    // The virtio-mem device likewise takes resize requests from the main process.
+    let (mem_host_socket, mem_device_socket) = if cfg.virtio_mem.is_some() {
+        let (host, device) = msg_socket::pair::<MemControlCommand, MemControlResult>()
+            .map_err(Error::CreateSocket)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
 
     // Create one control socket per disk.
     let mut disk_device_sockets = Vec::new();
@@ -2318,6 +3339,9 @@ where
     let (gpu_host_socket, gpu_device_socket) =
         msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
     control_sockets.push(TaggedControlSocket::VmMemory(gpu_host_socket));
+    // The GPU gets its own socket so renderer commands can be forwarded from the main process.
+    let (gpu_control_host_socket, gpu_control_device_socket) =
+        msg_socket::pair::<GpuControlCommand, GpuControlResult>().map_err(Error::CreateSocket)?;
 
     let (ioapic_host_socket, ioapic_device_socket) =
         msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
@@ -2356,11 +3380,18 @@ where
     let gralloc = RutabagaGralloc::new().map_err(Error::CreateGrallocError)?;
     let map_request: Arc<Mutex<Option<ExternalMapping>>> = Arc::new(Mutex::new(None));
 
+    // Each virtio-fs device and vhost-user device with a shared memory region maps files through
+    // its own socket.
     let fs_count = cfg
         .shared_dirs
         .iter()
         .filter(|sd| sd.kind == SharedDirKind::FS)
-        .count();
+        .count()
+        + cfg
+            .vhost_user
+            .iter()
+            .filter(|opt| opt.shm_size.is_some())
+            .count();
     let mut fs_device_sockets = Vec::with_capacity(fs_count);
     for _ in 0..fs_count {
         let (fs_host_socket, fs_device_socket) =
@@ -2369,6 +3400,11 @@ where
         fs_device_sockets.push(fs_device_socket);
     }
 
+    let mut device_executors = Vec::new();
+    let device_metrics = Arc::new(MetricsRegistry::new().map_err(Error::CreateMetrics)?);
+    let vhost_user_registry = VhostUserRegistry::new().map_err(Error::CreateVhostUserRegistry)?;
+    let mut vfio_container = None;
+    let mut hotplug_slots = Vec::new();
     let linux: RunnableLinuxVm<_, Vcpu, _> = Arch::build_vm(
         components,
         &cfg.serial_parameters,
@@ -2384,12 +3420,19 @@ where
                 &mut control_sockets,
                 wayland_device_socket,
                 gpu_device_socket,
+                gpu_control_device_socket,
                 balloon_device_socket,
+                mem_device_socket,
                 &mut disk_device_sockets,
                 &mut pmem_device_sockets,
                 &mut fs_device_sockets,
                 usb_provider,
                 Arc::clone(&map_request),
+                &mut device_executors,
+                &device_metrics,
+                &vhost_user_registry,
+                &mut vfio_container,
+                &mut hotplug_slots,
             )
         },
         create_vm,
@@ -2397,21 +3440,88 @@ where
     )
     .map_err(Error::BuildVm)?;
 
+    for slot in &hotplug_slots {
+        linux.pci_root.lock().add_hotplug_slot(slot.clone());
+    }
+
+    if cfg.lock_guest_memory {
+        lock_guest_memory(linux.vm.get_memory())?;
+    }
+
+    if cfg.snapshot {
+        check_snapshot_support(&linux.io_bus, &linux.mmio_bus)?;
+    }
+
+    // Started only now because the device processes must not be forked with its thread running.
+    let _metrics_server = match &cfg.metrics_socket {
+        Some(addr) => Some(
+            MetricsServer::start(addr, Arc::clone(&device_metrics), cfg.name.clone())
+                .map_err(Error::StartMetricsServer)?,
+        ),
+        None => None,
+    };
+
     run_control(
         linux,
         control_server_socket,
         control_sockets,
         balloon_host_socket,
+        mem_host_socket,
         &disk_host_sockets,
+        gpu_control_host_socket,
         usb_control_socket,
         sigchld_fd,
-        cfg.sandbox,
+        &cfg,
         Arc::clone(&map_request),
         cfg.balloon_bias,
+        cfg.balloon_guest_requests.as_ref(),
+        &device_executors,
+        &device_metrics,
+        &vhost_user_registry,
+        vfio_container,
+        hotplug_slots,
         gralloc,
+        &cfg.control_peers,
     )
 }
 
+// Returns what the peer on the accepted control socket `socket` may ask of the VM, or `None` if
+// it isn't allowed to use the socket.
+fn control_peer_access(peers: &[ControlPeer], socket: &UnixSeqpacket) -> Option<VmRequestAccess> {
+    if peers.is_empty() {
+        return Some(VmRequestAccess::Full);
+    }
+    let cred = match socket.peer_credentials() {
+        Ok(cred) => cred,
+        Err(e) => {
+            error!(
+                "failed to get the credentials of a control socket peer: {}",
+                e
+            );
+            return None;
+        }
+    };
+    // Either could take over crosvm anyway.
+    if cred.uid == 0 || cred.uid == geteuid() {
+        return Some(VmRequestAccess::Full);
+    }
+    let access = peers
+        .iter()
+        .filter(|peer| match peer.id {
+            ControlPeerId::Uid(uid) => uid == cred.uid,
+            ControlPeerId::Gid(gid) => gid == cred.gid,
+        })
+        .map(|peer| peer.access)
+        .max();
+    if access.is_none() {
+        warn!(
+            "turned away control socket peer with pid {}, uid {} and gid {}",
+            cred.pid, cred.uid, cred.gid
+        );
+    }
+    access
+}
+
 /// Signals all running VCPUs to vmexit, sends VmRunMode message to each VCPU channel, and tells
 /// `irq_chip` to stop blocking halted VCPUs. The channel message is set first because both the
 /// signal and the irq_chip kick could cause the VCPU thread to continue through the VCPU run
@@ -2430,6 +3540,461 @@ fn kick_all_vcpus(
     irq_chip.kick_halted_vcpus();
 }
 
+// Waits until every VCPU has handled the messages sent to it so far, so that after
+// `kick_all_vcpus` with `VmRunMode::Suspending` none of them is running guest code.
+fn sync_all_vcpus(vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)]) {
+    let mut replies = Vec::with_capacity(vcpu_handles.len());
+    for (_, channel) in vcpu_handles {
+        let (reply_send, reply_recv) = mpsc::channel();
+        // A VCPU that has exited can't be running either.
+        if channel.send(VcpuControl::Sync(reply_send)).is_ok() {
+            replies.push(reply_recv);
+        }
+    }
+    for reply in replies {
+        let _ = reply.recv();
+    }
+}
+
+// Dumps guest memory to `file` for `VmRequest::DumpMemory`, with the VCPUs paused unless the VM
+// is suspended already.
+fn handle_dump_memory(
+    file: &File,
+    range: Option<(u64, u64)>,
+    mem: &GuestMemory,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    irq_chip: &impl IrqChip,
+    suspended: bool,
+) -> VmResponse {
+    let (start, end) = match range {
+        Some((addr, len)) => match addr.checked_add(len) {
+            Some(end) if len > 0 && end <= mem.end_addr().offset() => {
+                (GuestAddress(addr), GuestAddress(end))
+            }
+            _ => return VmResponse::Err(base::Error::new(libc::EINVAL)),
+        },
+        None => (GuestAddress(0), mem.end_addr()),
+    };
+
+    if !suspended {
+        kick_all_vcpus(vcpu_handles, irq_chip, &VmRunMode::Suspending);
+    }
+    sync_all_vcpus(vcpu_handles);
+    let result = mem.dump(file, start, end);
+    if !suspended {
+        kick_all_vcpus(vcpu_handles, irq_chip, &VmRunMode::Running);
+    }
+
+    match result {
+        Ok(()) => VmResponse::Ok,
+        Err(e) => {
+            error!("failed to dump guest memory: {}", e);
+            VmResponse::Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
+// Handles a `VmIrqRequest` received on `socket`. The irqfds of MSIs are kept in `msi_gsis` so they
+// can be released once `socket` closes, and those the irqchip needs polled are added to `wait_ctx`
+// with the token made by `irq_fd_token`.
+fn handle_vm_irq_request<T: PollToken>(
+    request: &VmIrqRequest,
+    socket: &VmIrqResponseSocket,
+    irq_chip: &mut impl IrqChipArch,
+    resources: &mut SystemAllocator,
+    msi_gsis: &mut BTreeMap<RawDescriptor, Vec<(u32, Event)>>,
+    wait_ctx: &WaitContext<T>,
+    irq_fd_token: impl Fn(IrqEventIndex) -> T,
+) -> VmIrqResponse {
+    request.execute(
+        |setup| match setup {
+            IrqSetup::Event(irq, ev) => {
+                let event_index = irq_chip.register_irq_event(irq, ev, None)?;
+                match ev.try_clone() {
+                    Ok(ev) => msi_gsis
+                        .entry(socket.as_raw_descriptor())
+                        .or_default()
+                        .push((irq, ev)),
+                    Err(e) => warn!("failed to clone irqfd of gsi {}: {}", irq, e),
+                }
+                if let Some(event_index) = event_index {
+                    match wait_ctx.add(ev, irq_fd_token(event_index)) {
+                        Err(e) => {
+                            warn!("failed to add IrqFd to poll context: {}", e);
+                            Err(e)
+                        }
+                        Ok(_) => Ok(()),
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+            IrqSetup::Route(route) => irq_chip.route_irq(route),
+        },
+        resources,
+    )
+}
+
+// Saves the state of every paused VCPU, by VCPU id.
+fn save_vcpus(
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+) -> Result<Vec<Vec<u8>>> {
+    let mut replies = Vec::with_capacity(vcpu_handles.len());
+    for (_, channel) in vcpu_handles {
+        let (reply_send, reply_recv) = mpsc::channel();
+        let _ = channel.send(VcpuControl::Snapshot(reply_send));
+        replies.push(reply_recv);
+    }
+    let mut vcpus = Vec::with_capacity(replies.len());
+    for (cpu_id, reply) in replies.into_iter().enumerate() {
+        match reply.recv() {
+            Ok(Some(data)) => vcpus.push(data),
+            _ => return Err(Error::SnapshotVcpu(cpu_id)),
+        }
+    }
+    Ok(vcpus)
+}
+
+// Loads the states returned by `save_vcpus` back into the paused VCPUs.
+fn load_vcpus(
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    vcpus: Vec<Vec<u8>>,
+) -> Result<()> {
+    let mut replies = Vec::with_capacity(vcpu_handles.len());
+    for ((_, channel), data) in vcpu_handles.iter().zip(vcpus) {
+        let (reply_send, reply_recv) = mpsc::channel();
+        let _ = channel.send(VcpuControl::Restore(data, reply_send));
+        replies.push(reply_recv);
+    }
+    for (cpu_id, reply) in replies.into_iter().enumerate() {
+        if reply.recv() != Ok(true) {
+            return Err(Error::SnapshotVcpu(cpu_id));
+        }
+    }
+    Ok(())
+}
+
+// The start of a file written by `crosvm snapshot take`. It is followed by the version as a little
+// endian `u32`, the length of the serialized `VmSnapshot` as a little endian `u64`, the
+// `VmSnapshot` itself and finally guest memory as written by `GuestMemory::snapshot`.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"CROSVMSS";
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(MsgOnSocket)]
+struct VmSnapshot {
+    // The state of each VCPU, by VCPU id.
+    vcpus: Vec<Vec<u8>>,
+    irq_chip: Vec<u8>,
+    // The debug label and state of each device on the IO and MMIO buses, in the order of
+    // `Bus::unique_devices`. The label is only kept for error messages, as some include addresses
+    // the guest may have changed.
+    devices: Vec<(String, Vec<u8>)>,
+}
+
+// Makes `--snapshot` fail at startup rather than on the first `crosvm snapshot take` if a device
+// on the IO or MMIO bus can't be saved. None of them is activated yet, so saving them now leaves
+// them as they were.
+fn check_snapshot_support(io_bus: &devices::Bus, mmio_bus: &devices::Bus) -> Result<()> {
+    for device in devices::Bus::unique_devices(&[io_bus, mmio_bus]) {
+        let mut device = device.lock();
+        if device.snapshot().is_none() {
+            return Err(Error::SnapshotUnsupported(device.debug_label()));
+        }
+    }
+    Ok(())
+}
+
+// Saves the VM to `file` for `SnapshotCommand::Take`, with the VCPUs paused unless the VM is
+// suspended already.
+fn handle_snapshot_take<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch>(
+    file: &File,
+    linux: &RunnableLinuxVm<V, Vcpu, I>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    suspended: bool,
+) -> VmResponse {
+    if !suspended {
+        kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
+    }
+    sync_all_vcpus(vcpu_handles);
+    let result = take_snapshot(file, linux, vcpu_handles);
+    if !suspended {
+        kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Running);
+    }
+
+    match result {
+        Ok(()) => VmResponse::Ok,
+        Err(e) => {
+            error!("failed to take a VM snapshot: {}", e);
+            VmResponse::Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
+fn take_snapshot<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch>(
+    file: &File,
+    linux: &RunnableLinuxVm<V, Vcpu, I>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+) -> Result<()> {
+    let vcpus = save_vcpus(vcpu_handles)?;
+    let irq_chip =
+        Arch::snapshot_irq_chip(&linux.irq_chip, vcpus.len()).map_err(Error::SnapshotIrqChip)?;
+
+    let mut devices = Vec::new();
+    for device in devices::Bus::unique_devices(&[&linux.io_bus, &linux.mmio_bus]) {
+        let mut device = device.lock();
+        match device.snapshot() {
+            Some(data) => devices.push((device.debug_label(), data)),
+            None => return Err(Error::SnapshotDevice(device.debug_label())),
+        }
+    }
+
+    let state = msg_socket::serialize_to_vec(&VmSnapshot {
+        vcpus,
+        irq_chip,
+        devices,
+    })
+    .map_err(Error::SnapshotState)?;
+    let mut writer = io::BufWriter::new(file);
+    writer
+        .write_all(SNAPSHOT_MAGIC)
+        .and_then(|_| writer.write_all(&SNAPSHOT_VERSION.to_le_bytes()))
+        .and_then(|_| writer.write_all(&(state.len() as u64).to_le_bytes()))
+        .and_then(|_| writer.write_all(&state))
+        .map_err(Error::SnapshotFile)?;
+    linux
+        .vm
+        .get_memory()
+        .snapshot(&mut writer)
+        .map_err(Error::SnapshotMemory)?;
+    writer.flush().map_err(Error::SnapshotFile)
+}
+
+// Loads the VM from `file` for `SnapshotCommand::Restore`. The VCPUs are paused throughout, and
+// stay paused if the restore fails part way, as the VM would be left with mixed state. Devices
+// restoring MSI-X send requests to the VmIrq sockets among `control_sockets`, which are handled
+// here until every device is done.
+fn handle_snapshot_restore<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch, T: PollToken>(
+    file: &File,
+    linux: &mut RunnableLinuxVm<V, Vcpu, I>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    control_sockets: &[TaggedControlSocket],
+    msi_gsis: &mut BTreeMap<RawDescriptor, Vec<(u32, Event)>>,
+    wait_ctx: &WaitContext<T>,
+    irq_fd_token: impl Fn(IrqEventIndex) -> T,
+    suspended: bool,
+) -> VmResponse {
+    if !suspended {
+        kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
+    }
+    sync_all_vcpus(vcpu_handles);
+    match restore_snapshot(
+        file,
+        linux,
+        vcpu_handles,
+        control_sockets,
+        msi_gsis,
+        wait_ctx,
+        irq_fd_token,
+    ) {
+        Ok(()) => {
+            if !suspended {
+                kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Running);
+            }
+            VmResponse::Ok
+        }
+        Err(e) => {
+            error!(
+                "failed to restore a VM snapshot, leaving the VCPUs paused: {}",
+                e
+            );
+            VmResponse::Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
+fn restore_snapshot<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch, T: PollToken>(
+    file: &File,
+    linux: &mut RunnableLinuxVm<V, Vcpu, I>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    control_sockets: &[TaggedControlSocket],
+    msi_gsis: &mut BTreeMap<RawDescriptor, Vec<(u32, Event)>>,
+    wait_ctx: &WaitContext<T>,
+    irq_fd_token: impl Fn(IrqEventIndex) -> T,
+) -> Result<()> {
+    let mut reader = io::BufReader::new(file);
+    let mut magic = [0u8; 8];
+    let mut version = [0u8; 4];
+    let mut len = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .and_then(|_| reader.read_exact(&mut version))
+        .and_then(|_| reader.read_exact(&mut len))
+        .map_err(Error::SnapshotFile)?;
+    if &magic != SNAPSHOT_MAGIC || u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+        return Err(Error::SnapshotHeader);
+    }
+    let len = u64::from_le_bytes(len);
+    let mut state = Vec::new();
+    // A truncated file must not make us allocate whatever length it claims.
+    reader
+        .by_ref()
+        .take(len)
+        .read_to_end(&mut state)
+        .map_err(Error::SnapshotFile)?;
+    if state.len() as u64 != len {
+        return Err(Error::SnapshotHeader);
+    }
+    let snapshot: VmSnapshot =
+        msg_socket::deserialize_from_slice(&state).map_err(Error::SnapshotState)?;
+
+    let devices = devices::Bus::unique_devices(&[&linux.io_bus, &linux.mmio_bus]);
+    if snapshot.vcpus.len() != vcpu_handles.len() {
+        return Err(Error::SnapshotVcpuCount(
+            snapshot.vcpus.len(),
+            vcpu_handles.len(),
+        ));
+    }
+    if snapshot.devices.len() != devices.len() {
+        return Err(Error::SnapshotDeviceCount(
+            snapshot.devices.len(),
+            devices.len(),
+        ));
+    }
+
+    linux
+        .vm
+        .get_memory()
+        .restore(&mut reader)
+        .map_err(Error::SnapshotMemory)?;
+    Arch::restore_irq_chip(&mut linux.irq_chip, &snapshot.irq_chip)
+        .map_err(Error::SnapshotIrqChip)?;
+
+    load_vcpus(vcpu_handles, snapshot.vcpus)?;
+
+    // The devices are restored on another thread, as those re-enabling MSI-X block until their
+    // VmIrq requests are handled on this one.
+    #[derive(PollToken)]
+    enum RestoreToken {
+        Done,
+        VmIrq { index: usize },
+    }
+
+    let done_evt = Event::new().map_err(Error::CreateEvent)?;
+    let thread_done_evt = done_evt.try_clone().map_err(Error::CloneEvent)?;
+    let device_states = snapshot.devices;
+    let restorer = thread::Builder::new()
+        .name("crosvm_restore".to_owned())
+        .spawn(move || {
+            // Signals completion even if a device panics.
+            let _done_evt = ScopedEvent::from(thread_done_evt);
+            for (device, (_, data)) in devices.iter().zip(device_states) {
+                let mut device = device.lock();
+                if !device.restore(&data) {
+                    return Some(device.debug_label());
+                }
+            }
+            None
+        })
+        .map_err(Error::SpawnSnapshotThread)?;
+
+    let restore_ctx = WaitContext::build_with(&[(&done_evt, RestoreToken::Done)])
+        .map_err(Error::WaitContextAdd)?;
+    for (index, socket) in control_sockets.iter().enumerate() {
+        if let TaggedControlSocket::VmIrq(socket) = socket {
+            restore_ctx
+                .add(socket, RestoreToken::VmIrq { index })
+                .map_err(Error::WaitContextAdd)?;
+        }
+    }
+    'wait: loop {
+        let events = restore_ctx.wait().map_err(Error::SnapshotPoll)?;
+        for event in events.iter().filter(|e| e.is_readable) {
+            match event.token {
+                RestoreToken::Done => break 'wait,
+                RestoreToken::VmIrq { index } => {
+                    if let Some(TaggedControlSocket::VmIrq(socket)) = control_sockets.get(index) {
+                        match socket.recv() {
+                            Ok(request) => {
+                                let response = handle_vm_irq_request(
+                                    &request,
+                                    socket,
+                                    &mut linux.irq_chip,
+                                    &mut linux.resources,
+                                    msi_gsis,
+                                    wait_ctx,
+                                    &irq_fd_token,
+                                );
+                                if let Err(e) = socket.send(&response) {
+                                    error!("failed to send VmIrqResponse: {}", e);
+                                }
+                            }
+                            // Closed sockets are left for the control loop to clean up.
+                            Err(MsgError::RecvZero) => {
+                                if let Err(e) = restore_ctx.delete(socket) {
+                                    warn!("failed to remove VmIrq socket from wait context: {}", e);
+                                }
+                            }
+                            Err(e) => error!("failed to recv VmIrqRequest: {}", e),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    match restorer.join() {
+        Ok(None) => Ok(()),
+        Ok(Some(label)) => Err(Error::SnapshotDevice(label)),
+        Err(_) => Err(Error::SnapshotDevice("restore thread panicked".to_owned())),
+    }
+}
+
+// Tells every device on the IO and MMIO buses that the VM was suspended or resumed, so those with
+// timers of their own can stop them while the VCPUs are paused.
+fn set_devices_suspended(io_bus: &devices::Bus, mmio_bus: &devices::Bus, suspended: bool) {
+    for device in devices::Bus::unique_devices(&[io_bus, mmio_bus]) {
+        let mut device = device.lock();
+        if suspended {
+            device.on_suspend();
+        } else {
+            device.on_resume();
+        }
+    }
+}
+
+// Tells every device on the IO and MMIO buses that the guest went to sleep in S3 or was woken
+// up, so those with workers can stop them while it sleeps.
+fn set_devices_asleep(io_bus: &devices::Bus, mmio_bus: &devices::Bus, asleep: bool) {
+    for device in devices::Bus::unique_devices(&[io_bus, mmio_bus]) {
+        let mut device = device.lock();
+        if asleep {
+            device.on_sleep();
+        } else {
+            device.on_wake();
+        }
+    }
+}
+
+// Lists the ranges claimed on the IO and MMIO buses for `crosvm dump-memmap`.
+fn memory_map(io_bus: &devices::Bus, mmio_bus: &devices::Bus) -> Vec<MemoryMapEntry> {
+    let io = io_bus
+        .device_ranges()
+        .into_iter()
+        .map(|range| (MemoryMapBus::Io, range));
+    let mmio = mmio_bus
+        .device_ranges()
+        .into_iter()
+        .map(|range| (MemoryMapBus::Mmio, range));
+    io.chain(mmio)
+        .map(|(bus, (range, device))| MemoryMapEntry {
+            bus,
+            base: range.base,
+            len: range.len,
+            device,
+        })
+        .collect()
+}
+
 // BalloonPolicy determines the size to set the balloon.
 struct BalloonPolicy {
     // Estimate for when the guest starts aggressivly freeing memory.
@@ -2439,6 +4004,7 @@ struct BalloonPolicy {
     max_balloon_actual: i64, // The largest the balloon has ever been observed.
     prev_balloon_full_percent: i64, // How full was the balloon at the previous timestep.
     prev_guest_available: i64, // Available memory in the guest at the previous timestep.
+    max_balloon_requested: Option<i64>, // The largest balloon allowed by a guest request.
 }
 
 const ONE_KB: i64 = 1024;
@@ -2488,8 +4054,16 @@ impl BalloonPolicy {
             max_balloon_actual,
             prev_balloon_full_percent: 0,
             prev_guest_available: 0,
+            max_balloon_requested: None,
         }
     }
+
+    // Records that the guest asked for the balloon to be at most `balloon_size` bytes, so that
+    // balancing never takes back memory the guest was granted.
+    fn set_guest_request(&mut self, balloon_size: i64) {
+        self.max_balloon_requested = Some(balloon_size);
+    }
+
     fn delta(&mut self, stats: BalloonStats, balloon_actual_u: u64) -> Result<i64> {
         let guest_free = stats
             .free_memory
@@ -2574,6 +4148,11 @@ impl BalloonPolicy {
             || (balloon_delta.abs() * 100) / guest_above_critical > 1
             || (balloon_delta.abs() * 100) / host_above_critical > 1
         {
+            // Don't inflate the balloon past what the guest last asked for.
+            let balloon_delta_capped = match self.max_balloon_requested {
+                Some(max_balloon) => min(balloon_delta_capped, max_balloon - balloon_actual),
+                None => balloon_delta_capped,
+            };
             // Finally, make sure the balloon delta won't cause a negative size.
             let result = max(balloon_delta_capped, -balloon_actual);
             if result != 0 {
@@ -2594,27 +4173,378 @@ impl BalloonPolicy {
     }
 }
 
+// GuestRequestPolicy bounds the memory a guest agent may ask to keep through the balloon.
+struct GuestRequestPolicy {
+    memory_size: u64,
+    min_bytes: u64,
+    max_bytes: u64,
+}
+
+impl GuestRequestPolicy {
+    fn new(memory_size: u64, bounds: &BalloonGuestRequests) -> GuestRequestPolicy {
+        let max_bytes = min(bounds.max_bytes.unwrap_or(memory_size), memory_size);
+        GuestRequestPolicy {
+            memory_size,
+            min_bytes: min(bounds.min_bytes, max_bytes),
+            max_bytes,
+        }
+    }
+
+    // Returns the balloon size that leaves the guest with the memory it asked to keep, within the
+    // host's bounds.
+    fn balloon_target(&self, requested_bytes: u64) -> u64 {
+        let guest_bytes = min(max(requested_bytes, self.min_bytes), self.max_bytes);
+        self.memory_size - guest_bytes
+    }
+}
+
+// Acts on a result from the balloon device that wasn't asked for by a control request: stats
+// requested by the balloon policy timer and guest requests.
+fn handle_balloon_result(
+    result: BalloonControlResult,
+    balloon_host_socket: &BalloonControlRequestSocket,
+    balloon_policy: &mut Option<BalloonPolicy>,
+    guest_request_policy: &Option<GuestRequestPolicy>,
+) {
+    match result {
+        BalloonControlResult::Stats {
+            stats,
+            balloon_actual: balloon_actual_u,
+        } => {
+            match balloon_policy
+                .as_mut()
+                .map(|p| p.delta(stats, balloon_actual_u))
+            {
+                None => {
+                    error!("got result from balloon stats, but no policy is running");
+                }
+                Some(Err(e)) => {
+                    warn!("failed to run balloon policy {}", e);
+                }
+                Some(Ok(delta)) if delta != 0 => {
+                    let target = max((balloon_actual_u as i64) + delta, 0) as u64;
+                    let command = BalloonControlCommand::Adjust { num_bytes: target };
+                    if let Err(e) = balloon_host_socket.send(&command) {
+                        warn!("failed to send memory value to balloon device: {}", e);
+                    }
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+        BalloonControlResult::Accounting { .. } => {
+            warn!("got an unrequested balloon accounting result");
+        }
+        BalloonControlResult::GuestRequest { num_bytes } => match guest_request_policy {
+            None => {
+                warn!("got a guest balloon request, but they are disabled");
+            }
+            Some(policy) => {
+                let target = policy.balloon_target(num_bytes);
+                if let Some(balloon_policy) = balloon_policy.as_mut() {
+                    balloon_policy.set_guest_request(target as i64);
+                }
+                let command = BalloonControlCommand::Adjust { num_bytes: target };
+                if let Err(e) = balloon_host_socket.send(&command) {
+                    warn!("failed to send memory value to balloon device: {}", e);
+                }
+            }
+        },
+    }
+}
+
+// A host PCI device attached to the running VM with `crosvm vfio add`.
+struct HotplugVfioDevice {
+    path: PathBuf,
+    group_id: u32,
+    // Set if the IOMMU group was added to the VFIO container for this device, so it has to be
+    // removed again once no hot-added device of the group is left.
+    owns_group: bool,
+    // Index of the root port slot the device is plugged into, if it isn't on the root bus.
+    slot_index: Option<usize>,
+    device: HotplugPciDevice,
+}
+
+// Handles `crosvm vfio add` and `crosvm vfio remove`. Devices are plugged into the first empty
+// root port slot, or added to the root bus if there is none, and jailed like the VFIO devices
+// given on the command line. The host ends of the control sockets of an added device are pushed
+// to `new_sockets`, and the returned index, if any, is the irq event the control loop now has to
+// service. The processes of removed devices are pushed to `unplugged_pids`.
+//
+// Unlike the devices jailed at startup, an added device is forked while other threads run. The
+// child only takes the locks of the device itself, which no other thread has seen, of the
+// syslogger, which `ProxyDevice` keeps locked across the fork, and of malloc, which glibc's fork
+// takes care of. VCPUs may still be in the middle of a bus access though, so unless the VM is
+// `suspended` already, they are parked in their run loop, where they hold no lock, for the fork.
+// The only other threads, of gdb and the metrics server, never hold a lock the child takes.
+#[allow(clippy::too_many_arguments)]
+fn handle_vfio_command<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch>(
+    command: VfioCommand,
+    cfg: &Config,
+    linux: &mut RunnableLinuxVm<V, Vcpu, I>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<vm_control::VcpuControl>)],
+    suspended: bool,
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+    hotplug_slots: &[PcieHotplugSlot],
+    hotplug_devices: &mut Vec<HotplugVfioDevice>,
+    new_sockets: &mut Vec<TaggedControlSocket>,
+    unplugged_pids: &mut Vec<u32>,
+) -> (VmResponse, Option<IrqEventIndex>) {
+    match command {
+        VfioCommand::Add { path } => {
+            let path = PathBuf::from(path);
+            if hotplug_devices.iter().any(|d| d.path == path) {
+                return (VmResponse::Err(base::Error::new(libc::EEXIST)), None);
+            }
+            let group_id = match VfioDevice::group_id(&path) {
+                Ok(id) => id,
+                Err(e) => {
+                    error!(
+                        "failed to find the iommu group of {}: {}",
+                        path.display(),
+                        e
+                    );
+                    return (VmResponse::Err(base::Error::new(libc::ENOENT)), None);
+                }
+            };
+            let owns_group = !vfio_container
+                .as_ref()
+                .map_or(false, |c| c.lock().has_group(group_id));
+            let slot_index = hotplug_slots.iter().position(|s| s.is_empty());
+            if slot_index.is_none() && !hotplug_slots.is_empty() {
+                error!("no empty pcie slot for vfio device {}", path.display());
+                return (VmResponse::Err(base::Error::new(libc::ENOSPC)), None);
+            }
+            let res = create_vfio_device(
+                &path,
+                linux.vm.get_memory(),
+                &linux.vm,
+                vfio_container,
+                new_sockets,
+            )
+            .and_then(|device| {
+                let jail = simple_jail(cfg, "vfio_device")?;
+                Ok((device, jail))
+            });
+            let res = match res {
+                Ok((device, jail)) => {
+                    let park_vcpus = jail.is_some() && !suspended;
+                    if park_vcpus {
+                        kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
+                        sync_all_vcpus(vcpu_handles);
+                    }
+                    let res = arch::add_hotplug_pci_device(
+                        device,
+                        jail,
+                        &linux.pci_root,
+                        slot_index.map(|i| &hotplug_slots[i]),
+                        &mut linux.irq_chip,
+                        &mut linux.mmio_bus,
+                        &mut linux.resources,
+                        &mut linux.vm,
+                    )
+                    .map_err(Error::HotplugVfioDevice);
+                    if park_vcpus {
+                        kick_all_vcpus(vcpu_handles, &linux.irq_chip, &VmRunMode::Running);
+                    }
+                    res
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(device) => {
+                    match slot_index {
+                        Some(i) => info!("added vfio device {} in pcie slot {}", path.display(), i),
+                        None => info!("added vfio device {} at {}", path.display(), device.address),
+                    }
+                    let irq_event_index = device.irq_event_index;
+                    if let Some(pid) = device.pid {
+                        linux
+                            .pid_debug_label_map
+                            .insert(pid, format!("vfio device {}", path.display()));
+                    }
+                    hotplug_devices.push(HotplugVfioDevice {
+                        path,
+                        group_id,
+                        owns_group,
+                        slot_index,
+                        device,
+                    });
+                    (VmResponse::Ok, irq_event_index)
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    if owns_group {
+                        if let Some(container) = vfio_container {
+                            let _ = container.lock().remove_group(group_id);
+                        }
+                    }
+                    (VmResponse::Err(base::Error::new(libc::EIO)), None)
+                }
+            }
+        }
+        VfioCommand::Remove { path } => {
+            let path = PathBuf::from(path);
+            let index = match hotplug_devices.iter().position(|d| d.path == path) {
+                Some(index) => index,
+                None => return (VmResponse::Err(base::Error::new(libc::ENOENT)), None),
+            };
+            let device = &hotplug_devices[index].device;
+            match &device.slot {
+                // The guest releases the device in its own time after the attention button is
+                // pressed, and the control loop removes it once the guest turned the slot off.
+                Some(slot) => {
+                    if !slot.request_unplug() {
+                        info!("asked the guest to release vfio device {}", path.display());
+                        return (VmResponse::Err(base::Error::new(libc::EINPROGRESS)), None);
+                    }
+                }
+                // The guest driver clears bus mastering when it lets go of the device, so until
+                // then it may still be doing DMA or waiting for interrupts.
+                None => {
+                    if device.bus_master_enabled() {
+                        return (VmResponse::Err(base::Error::new(libc::EBUSY)), None);
+                    }
+                }
+            }
+            let removed = hotplug_devices.remove(index);
+            (
+                unplug_vfio_device(
+                    removed,
+                    linux,
+                    vfio_container,
+                    hotplug_devices,
+                    unplugged_pids,
+                ),
+                None,
+            )
+        }
+    }
+}
+
+// Removes the route and irqfd of a GSI that a device allocated for an MSI vector, and makes the GSI
+// available to other devices.
+fn release_msi_gsi(
+    irq_chip: &mut impl IrqChip,
+    resources: &mut SystemAllocator,
+    gsi: u32,
+    irqfd: &Event,
+) {
+    if let Err(e) = irq_chip.unregister_irq_event(gsi, irqfd) {
+        warn!("failed to unregister irqfd of gsi {}: {}", gsi, e);
+    }
+    if let Err(e) = irq_chip.unroute_irq(gsi) {
+        warn!("failed to remove route of gsi {}: {}", gsi, e);
+    }
+    resources.release_irq(gsi);
+}
+
+// Handles `crosvm vcpu add` and `crosvm vcpu remove`. The VCPUs already exist, so this only tells
+// the guest about them through the CPU hotplug device.
+fn handle_vcpu_command(
+    command: VcpuCommand,
+    cpu_hotplug: Option<&Arc<Mutex<CpuHotplugController>>>,
+) -> VmResponse {
+    let cpu_hotplug = match cpu_hotplug {
+        Some(cpu_hotplug) => cpu_hotplug,
+        None => return VmResponse::Err(base::Error::new(libc::ENOTSUP)),
+    };
+    let result = match command {
+        VcpuCommand::Add { id } => cpu_hotplug.lock().add_cpu(id),
+        VcpuCommand::Remove { id } => cpu_hotplug.lock().remove_cpu(id),
+    };
+    match result {
+        Ok(()) => VmResponse::Ok,
+        Err(e) => {
+            error!("{}", e);
+            let errno = match e {
+                CpuHotplugError::AlreadyPresent(_) => libc::EEXIST,
+                CpuHotplugError::BootCpu => libc::EBUSY,
+                CpuHotplugError::InvalidCpu(_) => libc::EINVAL,
+                CpuHotplugError::NotPresent(_) => libc::ENOENT,
+                CpuHotplugError::TriggerIrq(_) => libc::EIO,
+            };
+            VmResponse::Err(base::Error::new(errno))
+        }
+    }
+}
+
+// Removes a hot-added VFIO device the guest has released, along with its IOMMU group if no other
+// device of the group is left. If the device was jailed, its process is pushed to
+// `unplugged_pids`, as it is about to exit.
+fn unplug_vfio_device<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch>(
+    removed: HotplugVfioDevice,
+    linux: &mut RunnableLinuxVm<V, Vcpu, I>,
+    vfio_container: &mut Option<Arc<Mutex<VfioContainer>>>,
+    hotplug_devices: &mut Vec<HotplugVfioDevice>,
+    unplugged_pids: &mut Vec<u32>,
+) -> VmResponse {
+    unplugged_pids.extend(removed.device.pid);
+    let res = arch::remove_hotplug_pci_device(
+        removed.device,
+        &linux.pci_root,
+        &mut linux.irq_chip,
+        &mut linux.mmio_bus,
+        &mut linux.resources,
+        &mut linux.vm,
+    )
+    .map_err(Error::UnplugVfioDevice);
+    if let Some(other) = hotplug_devices
+        .iter_mut()
+        .find(|d| d.group_id == removed.group_id)
+    {
+        other.owns_group |= removed.owns_group;
+    } else if removed.owns_group {
+        if let Some(container) = vfio_container {
+            if let Err(e) = container.lock().remove_group(removed.group_id) {
+                warn!("failed to release iommu group {}: {}", removed.group_id, e);
+            }
+        }
+    }
+    match res {
+        Ok(()) => {
+            info!("removed vfio device {}", removed.path.display());
+            VmResponse::Ok
+        }
+        Err(e) => {
+            error!("{}", e);
+            VmResponse::Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu, I>,
     control_server_socket: Option<UnlinkUnixSeqpacketListener>,
     mut control_sockets: Vec<TaggedControlSocket>,
-    balloon_host_socket: BalloonControlRequestSocket,
+    balloon_host_socket: Option<BalloonControlRequestSocket>,
+    mem_host_socket: Option<MemControlRequestSocket>,
     disk_host_sockets: &[DiskControlRequestSocket],
+    gpu_control_socket: GpuControlRequestSocket,
     usb_control_socket: UsbControlSocket,
     sigchld_fd: SignalFd,
-    sandbox: bool,
+    cfg: &Config,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
     balloon_bias: i64,
+    balloon_guest_requests: Option<&BalloonGuestRequests>,
+    device_executors: &[DeviceExecutor],
+    device_metrics: &MetricsRegistry,
+    vhost_user_registry: &VhostUserRegistry,
+    mut vfio_container: Option<Arc<Mutex<VfioContainer>>>,
+    hotplug_slots: Vec<PcieHotplugSlot>,
     mut gralloc: RutabagaGralloc,
+    control_peers: &[ControlPeer],
 ) -> Result<()> {
     #[derive(PollToken)]
     enum Token {
         Exit,
         Suspend,
+        Sleep,
         ChildSignal,
         IrqFd { index: IrqEventIndex },
         BalanceMemory,
         BalloonResult,
+        UsbHotplug,
+        PcieSlotUnplug { index: usize },
         VmControlServer,
         VmControl { index: usize },
     }
@@ -2626,6 +4556,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
     let wait_ctx = WaitContext::build_with(&[
         (&linux.exit_evt, Token::Exit),
         (&linux.suspend_evt, Token::Suspend),
+        (&linux.sleep_evt, Token::Sleep),
         (&sigchld_fd, Token::ChildSignal),
     ])
     .map_err(Error::WaitContextAdd)?;
@@ -2652,9 +4583,17 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             .map_err(Error::WaitContextAdd)?;
     }
 
+    for (index, slot) in hotplug_slots.iter().enumerate() {
+        wait_ctx
+            .add(slot.unplug_event(), Token::PcieSlotUnplug { index })
+            .map_err(Error::WaitContextAdd)?;
+    }
+
     // Balance available memory between guest and host every second.
     let mut balancemem_timer = Timer::new().map_err(Error::CreateTimer)?;
-    let mut balloon_policy = if let Ok(critical_margin) = file_to_i64(LOWMEM_MARGIN, 0) {
+    let mut balloon_policy = if balloon_host_socket.is_none() {
+        None
+    } else if let Ok(critical_margin) = file_to_i64(LOWMEM_MARGIN, 0) {
         // Create timer request balloon stats every 1s.
         wait_ctx
             .add(&balancemem_timer, Token::BalanceMemory)
@@ -2665,10 +4604,6 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             .reset(balancemem_dur, Some(balancemem_int))
             .map_err(Error::ResetTimer)?;
 
-        // Listen for balloon statistics from the guest so we can balance.
-        wait_ctx
-            .add(&balloon_host_socket, Token::BalloonResult)
-            .map_err(Error::WaitContextAdd)?;
         Some(BalloonPolicy::new(
             linux.vm.get_memory().memory_size() as i64,
             critical_margin * ONE_MB,
@@ -2678,8 +4613,26 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         warn!("Unable to open low mem margin, maybe not a chrome os kernel");
         None
     };
+    let guest_request_policy = balloon_guest_requests
+        .map(|bounds| GuestRequestPolicy::new(linux.vm.get_memory().memory_size(), bounds));
+
+    if let Some(socket) = &balloon_host_socket {
+        if balloon_policy.is_some() || guest_request_policy.is_some() {
+            // Listen for balloon statistics and requests from the guest so we can balance.
+            wait_ctx
+                .add(socket, Token::BalloonResult)
+                .map_err(Error::WaitContextAdd)?;
+        }
+    }
+
+    // Looks for unplugged persistent USB devices coming back. Only armed while there are any.
+    let mut usb_hotplug_timer = Timer::new().map_err(Error::CreateTimer)?;
+    wait_ctx
+        .add(&usb_hotplug_timer, Token::UsbHotplug)
+        .map_err(Error::WaitContextAdd)?;
+    let mut usb_hotplug = UsbHotplug::default();
 
-    if sandbox {
+    if cfg.sandbox {
         // Before starting VCPUs, in case we started with some capabilities, drop them all.
         drop_capabilities().map_err(Error::DropCapabilities)?;
     }
@@ -2721,8 +4674,10 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             linux.rt_cpus.contains(&cpu_id),
             vcpu_affinity,
             linux.no_smt,
+            linux.cpu_features.clone(),
             vcpu_thread_barrier.clone(),
             linux.has_bios,
+            linux.pvh_entry,
             linux.io_bus.clone(),
             linux.mmio_bus.clone(),
             linux.exit_evt.try_clone().map_err(Error::CloneEvent)?,
@@ -2737,7 +4692,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
 
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     // Spawn GDB thread.
-    if let Some((gdb_port_num, gdb_control_socket)) = linux.gdb.take() {
+    if let Some((gdb_address, gdb_control_socket)) = linux.gdb.take() {
         let to_vcpu_channels = vcpu_handles
             .iter()
             .map(|(_handle, channel)| channel.clone())
@@ -2749,12 +4704,26 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         );
         thread::Builder::new()
             .name("gdb".to_owned())
-            .spawn(move || gdb_thread(target, gdb_port_num))
+            .spawn(move || gdb_thread(target, gdb_address))
             .map_err(Error::SpawnGdbServer)?;
     };
 
     vcpu_thread_barrier.wait();
 
+    let mut shared_memory = SharedMemoryRegions::default();
+    let mut hotplug_vfio_devices = Vec::new();
+    // The processes of unplugged devices, which are expected to exit.
+    let mut unplugged_pids = Vec::new();
+    let mut devices_suspended = false;
+    // Whether the guest put itself to sleep in S3, with its devices asleep.
+    let mut guest_asleep = false;
+    // The GSIs that devices allocated for MSI vectors and their irqfds, by the socket they were
+    // allocated over, so they can be given back when the device goes away.
+    let mut msi_gsis: BTreeMap<RawDescriptor, Vec<(u32, Event)>> = BTreeMap::new();
+    // What the peers of accepted control sockets may ask, by socket. Sockets crosvm made itself
+    // have full access.
+    let mut control_access: BTreeMap<RawDescriptor, VmRequestAccess> = BTreeMap::new();
+
     'wait: loop {
         let events = {
             match wait_ctx.wait() {
@@ -2771,6 +4740,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         }
 
         let mut vm_control_indices_to_remove = Vec::new();
+        let mut vm_control_sockets_to_add = Vec::new();
         for event in events.iter().filter(|e| e.is_readable) {
             match event.token {
                 Token::Exit => {
@@ -2781,11 +4751,42 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                     info!("VM requested suspend");
                     linux.suspend_evt.read().unwrap();
                     kick_all_vcpus(&vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
+                    if !devices_suspended {
+                        set_devices_suspended(&linux.io_bus, &linux.mmio_bus, true);
+                        devices_suspended = true;
+                    }
+                }
+                Token::Sleep => {
+                    info!("VM requested sleep");
+                    linux.sleep_evt.read().unwrap();
+                    kick_all_vcpus(&vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
+                    if !devices_suspended {
+                        set_devices_suspended(&linux.io_bus, &linux.mmio_bus, true);
+                        devices_suspended = true;
+                    }
+                    if !guest_asleep {
+                        // Don't let the devices go to sleep under a VCPU still running.
+                        sync_all_vcpus(&vcpu_handles);
+                        set_devices_asleep(&linux.io_bus, &linux.mmio_bus, true);
+                        guest_asleep = true;
+                    }
                 }
                 Token::ChildSignal => {
-                    // Print all available siginfo structs, then exit the loop.
+                    // Print all available siginfo structs, then exit the loop unless only the
+                    // processes of unplugged devices exited.
+                    let mut child_died = false;
                     while let Some(siginfo) = sigchld_fd.read().map_err(Error::SignalFd)? {
                         let pid = siginfo.ssi_pid;
+                        if let Some(i) = unplugged_pids.iter().position(|&p| p == pid) {
+                            unplugged_pids.swap_remove(i);
+                            linux.pid_debug_label_map.remove(&pid);
+                            // Safe because no memory is passed in and the exit status isn't
+                            // needed.
+                            unsafe {
+                                libc::waitpid(pid as libc::pid_t, ptr::null_mut(), libc::WNOHANG)
+                            };
+                            continue;
+                        }
                         let pid_label = match linux.pid_debug_label_map.get(&pid) {
                             Some(label) => format!("{} (pid {})", label, pid),
                             None => format!("pid {}", pid),
@@ -2794,8 +4795,11 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                             "child {} died: signo {}, status {}, code {}",
                             pid_label, siginfo.ssi_signo, siginfo.ssi_status, siginfo.ssi_code
                         );
+                        child_died = true;
+                    }
+                    if child_died {
+                        break 'wait;
                     }
-                    break 'wait;
                 }
                 Token::IrqFd { index } => {
                     if let Err(e) = linux.irq_chip.service_irq_event(index) {
@@ -2804,52 +4808,67 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                 }
                 Token::BalanceMemory => {
                     balancemem_timer.wait().map_err(Error::Timer)?;
+                    // The guest can't answer while it's suspended.
+                    if devices_suspended {
+                        continue;
+                    }
                     let command = BalloonControlCommand::Stats {};
-                    if let Err(e) = balloon_host_socket.send(&command) {
-                        warn!("failed to send stats request to balloon device: {}", e);
+                    if let Some(socket) = &balloon_host_socket {
+                        if let Err(e) = socket.send(&command) {
+                            warn!("failed to send stats request to balloon device: {}", e);
+                        }
+                    }
+                }
+                Token::UsbHotplug => {
+                    usb_hotplug_timer.wait().map_err(Error::Timer)?;
+                    usb_hotplug.poll(&usb_control_socket);
+                }
+                Token::PcieSlotUnplug { index } => {
+                    let slot = &hotplug_slots[index];
+                    let _ = slot.unplug_event().read();
+                    if !slot.is_released() {
+                        continue;
+                    }
+                    if let Some(i) = hotplug_vfio_devices
+                        .iter()
+                        .position(|d| d.slot_index == Some(index))
+                    {
+                        let removed = hotplug_vfio_devices.remove(i);
+                        unplug_vfio_device(
+                            removed,
+                            &mut linux,
+                            &mut vfio_container,
+                            &mut hotplug_vfio_devices,
+                            &mut unplugged_pids,
+                        );
                     }
                 }
                 Token::BalloonResult => {
-                    match balloon_host_socket.recv() {
-                        Ok(BalloonControlResult::Stats {
-                            stats,
-                            balloon_actual: balloon_actual_u,
-                        }) => {
-                            match balloon_policy
-                                .as_mut()
-                                .map(|p| p.delta(stats, balloon_actual_u))
-                            {
-                                None => {
-                                    error!(
-                                        "got result from balloon stats, but no policy is running"
-                                    );
-                                }
-                                Some(Err(e)) => {
-                                    warn!("failed to run balloon policy {}", e);
-                                }
-                                Some(Ok(delta)) if delta != 0 => {
-                                    let target = max((balloon_actual_u as i64) + delta, 0) as u64;
-                                    let command =
-                                        BalloonControlCommand::Adjust { num_bytes: target };
-                                    if let Err(e) = balloon_host_socket.send(&command) {
-                                        warn!(
-                                            "failed to send memory value to balloon device: {}",
-                                            e
-                                        );
-                                    }
-                                }
-                                Some(Ok(_)) => {}
+                    // Only added to `wait_ctx` for a balloon.
+                    if let Some(socket) = &balloon_host_socket {
+                        match socket.recv() {
+                            Ok(result) => handle_balloon_result(
+                                result,
+                                socket,
+                                &mut balloon_policy,
+                                &guest_request_policy,
+                            ),
+                            Err(e) => {
+                                error!("failed to recv BalloonControlResult: {}", e);
                             }
                         }
-                        Err(e) => {
-                            error!("failed to recv BalloonControlResult: {}", e);
-                        }
-                    };
+                    }
                 }
                 Token::VmControlServer => {
                     if let Some(socket_server) = &control_server_socket {
                         match socket_server.accept() {
                             Ok(socket) => {
+                                let access = match control_peer_access(control_peers, &socket) {
+                                    Some(access) => access,
+                                    // Dropping the socket hangs up on the peer.
+                                    None => continue,
+                                };
+                                control_access.insert(socket.as_raw_descriptor(), access);
                                 wait_ctx
                                     .add(
                                         &socket,
@@ -2869,18 +4888,183 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                     if let Some(socket) = control_sockets.get(index) {
                         match socket {
                             TaggedControlSocket::Vm(socket) => match socket.recv() {
+                                Ok(request)
+                                    if request.required_access()
+                                        > control_access
+                                            .get(&socket.as_raw_descriptor())
+                                            .copied()
+                                            .unwrap_or(VmRequestAccess::Full) =>
+                                {
+                                    warn!("control socket peer isn't allowed {:?}", request);
+                                    let response = VmResponse::Err(base::Error::new(libc::EPERM));
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::VfioCommand(command)) => {
+                                    let (response, irq_event_index) = handle_vfio_command(
+                                        command,
+                                        cfg,
+                                        &mut linux,
+                                        &vcpu_handles,
+                                        devices_suspended,
+                                        &mut vfio_container,
+                                        &hotplug_slots,
+                                        &mut hotplug_vfio_devices,
+                                        &mut vm_control_sockets_to_add,
+                                        &mut unplugged_pids,
+                                    );
+                                    if let Some(index) = irq_event_index {
+                                        let events = linux
+                                            .irq_chip
+                                            .irq_event_tokens()
+                                            .map_err(Error::WaitContextAdd)?;
+                                        for (_, _, evt) in
+                                            events.iter().filter(|(i, _, _)| *i == index)
+                                        {
+                                            wait_ctx
+                                                .add(evt, Token::IrqFd { index })
+                                                .map_err(Error::WaitContextAdd)?;
+                                        }
+                                    }
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::VcpuCommand(command)) => {
+                                    let response =
+                                        handle_vcpu_command(command, linux.cpu_hotplug.as_ref());
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::DumpMemory { file, range }) => {
+                                    let response = handle_dump_memory(
+                                        &file,
+                                        range,
+                                        linux.vm.get_memory(),
+                                        &vcpu_handles,
+                                        &linux.irq_chip,
+                                        devices_suspended,
+                                    );
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                // Only VMs started with `--snapshot` were checked for devices
+                                // that can't be saved.
+                                Ok(VmRequest::Snapshot(_)) if !cfg.snapshot => {
+                                    error!("snapshots need the VM to be started with --snapshot");
+                                    let response = VmResponse::Err(base::Error::new(libc::ENOTSUP));
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::Snapshot(SnapshotCommand::Take { file })) => {
+                                    // A restored guest that was saved asleep would wait forever
+                                    // for its wake status.
+                                    let response = if guest_asleep {
+                                        VmResponse::Err(base::Error::new(libc::EBUSY))
+                                    } else {
+                                        handle_snapshot_take(
+                                            &file,
+                                            &linux,
+                                            &vcpu_handles,
+                                            devices_suspended,
+                                        )
+                                    };
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::Snapshot(SnapshotCommand::Restore { file })) => {
+                                    let response = handle_snapshot_restore(
+                                        &file,
+                                        &mut linux,
+                                        &vcpu_handles,
+                                        &control_sockets,
+                                        &mut msi_gsis,
+                                        &wait_ctx,
+                                        |index| Token::IrqFd { index },
+                                        devices_suspended,
+                                    );
+                                    // The restored guest is awake, with its devices running.
+                                    if let VmResponse::Ok = response {
+                                        guest_asleep = false;
+                                    }
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
+                                Ok(VmRequest::UsbCommand(
+                                    UsbControlCommand::AttachDeviceById {
+                                        vid,
+                                        pid,
+                                        persistent,
+                                    },
+                                )) => {
+                                    let result = usb_hotplug.attach(
+                                        &usb_control_socket,
+                                        vid,
+                                        pid,
+                                        persistent,
+                                    );
+                                    if usb_hotplug.has_persistent() {
+                                        let interval = Duration::from_secs(1);
+                                        if let Err(e) =
+                                            usb_hotplug_timer.reset(interval, Some(interval))
+                                        {
+                                            error!("failed to arm the usb hotplug timer: {}", e);
+                                        }
+                                    }
+                                    let response = VmResponse::UsbResponse(result);
+                                    if let Err(e) = socket.send(&response) {
+                                        error!("failed to send VmResponse: {}", e);
+                                    }
+                                }
                                 Ok(request) => {
+                                    if let VmRequest::UsbCommand(
+                                        UsbControlCommand::DetachDevice { port },
+                                    ) = request
+                                    {
+                                        usb_hotplug.forget_port(port);
+                                        if !usb_hotplug.has_persistent() {
+                                            let _ = usb_hotplug_timer.clear();
+                                        }
+                                    }
                                     let mut run_mode_opt = None;
+                                    let mut balloon_results = Vec::new();
+                                    let (io_bus, mmio_bus) = (&linux.io_bus, &linux.mmio_bus);
                                     let response = request.execute(
                                         &mut run_mode_opt,
-                                        &balloon_host_socket,
+                                        balloon_host_socket.as_ref(),
+                                        &mut balloon_results,
+                                        mem_host_socket.as_ref(),
                                         disk_host_sockets,
+                                        &gpu_control_socket,
                                         &usb_control_socket,
                                         &mut linux.bat_control,
+                                        linux.vm.get_memory().memory_size(),
+                                        &shared_memory,
+                                        || memory_map(io_bus, mmio_bus),
+                                        device_executors,
+                                        || device_metrics.snapshot(),
+                                        || vhost_user_registry.snapshot(),
                                     );
                                     if let Err(e) = socket.send(&response) {
                                         error!("failed to send VmResponse: {}", e);
                                     }
+                                    // Results only come from a balloon.
+                                    if let Some(socket) = &balloon_host_socket {
+                                        for result in balloon_results {
+                                            handle_balloon_result(
+                                                result,
+                                                socket,
+                                                &mut balloon_policy,
+                                                &guest_request_policy,
+                                            );
+                                        }
+                                    }
                                     if let Some(run_mode) = run_mode_opt {
                                         info!("control socket changed run mode to {}", run_mode);
                                         match run_mode {
@@ -2889,6 +5073,22 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                                             }
                                             other => {
                                                 if other == VmRunMode::Running {
+                                                    if guest_asleep {
+                                                        set_devices_asleep(
+                                                            &linux.io_bus,
+                                                            &linux.mmio_bus,
+                                                            false,
+                                                        );
+                                                        guest_asleep = false;
+                                                    }
+                                                    if devices_suspended {
+                                                        set_devices_suspended(
+                                                            &linux.io_bus,
+                                                            &linux.mmio_bus,
+                                                            false,
+                                                        );
+                                                        devices_suspended = false;
+                                                    }
                                                     linux.io_bus.notify_resume();
                                                 }
                                                 kick_all_vcpus(
@@ -2896,6 +5096,16 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                                                     &linux.irq_chip,
                                                     &other,
                                                 );
+                                                if other == VmRunMode::Suspending
+                                                    && !devices_suspended
+                                                {
+                                                    set_devices_suspended(
+                                                        &linux.io_bus,
+                                                        &linux.mmio_bus,
+                                                        true,
+                                                    );
+                                                    devices_suspended = true;
+                                                }
                                             }
                                         }
                                     }
@@ -2915,6 +5125,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                                         &mut linux.resources,
                                         Arc::clone(&map_request),
                                         &mut gralloc,
+                                        &mut shared_memory,
                                     );
                                     if let Err(e) = socket.send(&response) {
                                         error!("failed to send VmMemoryControlResponse: {}", e);
@@ -2930,37 +5141,15 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                             },
                             TaggedControlSocket::VmIrq(socket) => match socket.recv() {
                                 Ok(request) => {
-                                    let response = {
-                                        let irq_chip = &mut linux.irq_chip;
-                                        request.execute(
-                                            |setup| match setup {
-                                                IrqSetup::Event(irq, ev) => {
-                                                    if let Some(event_index) = irq_chip
-                                                        .register_irq_event(irq, ev, None)?
-                                                    {
-                                                        match wait_ctx.add(
-                                                            ev,
-                                                            Token::IrqFd {
-                                                                index: event_index
-                                                            },
-                                                        ) {
-                                                            Err(e) => {
-                                                                warn!("failed to add IrqFd to poll context: {}", e);
-                                                                Err(e)
-                                                            },
-                                                            Ok(_) => {
-                                                                Ok(())
-                                                            }
-                                                        }
-                                                    } else {
-                                                        Ok(())
-                                                    }
-                                                }
-                                                IrqSetup::Route(route) => irq_chip.route_irq(route),
-                                            },
-                                            &mut linux.resources,
-                                        )
-                                    };
+                                    let response = handle_vm_irq_request(
+                                        &request,
+                                        socket,
+                                        &mut linux.irq_chip,
+                                        &mut linux.resources,
+                                        &mut msi_gsis,
+                                        &wait_ctx,
+                                        |index| Token::IrqFd { index },
+                                    );
                                     if let Err(e) = socket.send(&response) {
                                         error!("failed to send VmIrqResponse: {}", e);
                                     }
@@ -3014,6 +5203,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             match event.token {
                 Token::Exit => {}
                 Token::Suspend => {}
+                Token::Sleep => {}
                 Token::ChildSignal => {}
                 Token::IrqFd { index: _ } => {}
                 Token::BalanceMemory => {}
@@ -3050,6 +5240,14 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             // blocking on a socket that will never be ready. See also: crbug.com/1019986
             if let Some(socket) = control_sockets.get(index) {
                 wait_ctx.delete(socket).map_err(Error::WaitContextDelete)?;
+                // A device that hung up, such as a hot-removed one, doesn't need its MSI vectors
+                // anymore.
+                if let Some(gsis) = msi_gsis.remove(&socket.as_raw_descriptor()) {
+                    for (gsi, irqfd) in gsis {
+                        release_msi_gsi(&mut linux.irq_chip, &mut linux.resources, gsi, &irqfd);
+                    }
+                }
+                control_access.remove(&socket.as_raw_descriptor());
             }
 
             // This line implicitly drops the socket at `index` when it gets returned by
@@ -3063,6 +5261,20 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                     .map_err(Error::WaitContextAdd)?;
             }
         }
+
+        // Sockets of hot-added devices are added once the removals above are done so that their
+        // indexes stay valid.
+        for socket in vm_control_sockets_to_add {
+            wait_ctx
+                .add(
+                    socket.as_ref(),
+                    Token::VmControl {
+                        index: control_sockets.len(),
+                    },
+                )
+                .map_err(Error::WaitContextAdd)?;
+            control_sockets.push(socket);
+        }
     }
 
     kick_all_vcpus(&vcpu_handles, &linux.irq_chip, &VmRunMode::Exiting);
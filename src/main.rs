@@ -11,15 +11,18 @@ use std::default::Default;
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader};
+use std::iter::{self, Peekable};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::string::String;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use arch::GdbAddress;
 use arch::{
-    set_default_serial_parameters, Pstore, SerialHardware, SerialParameters, SerialType,
+    set_default_serial_parameters, NumaNode, Pstore, SerialHardware, SerialParameters, SerialType,
     VcpuAffinity,
 };
 use base::{
@@ -27,21 +30,32 @@ use base::{
     validate_raw_descriptor, warn, FromRawDescriptor, IntoRawDescriptor, RawDescriptor,
     SafeDescriptor,
 };
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use crosvm::VfioPlatformOption;
 use crosvm::{
     argument::{self, print_help, set_arguments, Argument},
-    platform, BindMount, Config, DiskOption, Executable, GidMap, SharedDir, TouchDeviceOption,
-    DISK_ID_LEN,
+    config_file::ConfigFile,
+    metrics_server::MetricsAddr,
+    platform, registry, BalloonGuestRequests, BindMount, Config, ControlPeer, ControlPeerId,
+    DiskOption, Executable, FwCfgData, GidMap, JailIdMaps, NetDeviceTap, SharedDir,
+    TouchDeviceOption, VhostUserOption, DEFAULT_SWIOTLB_SIZE_MIB, DISK_ID_LEN,
 };
+use devices::fw_cfg::FW_CFG_MAX_FILE_NAME;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::{GpuMode, GpuParameters};
 #[cfg(feature = "audio")]
+use devices::virtio::snd::{SoundBackend, SoundParameters};
+use devices::virtio::{self, RngParameters, RngSource};
+#[cfg(feature = "audio")]
 use devices::{Ac97Backend, Ac97Parameters};
 use disk::QcowFile;
 use msg_socket::{MsgReceiver, MsgSender, MsgSocket};
+use serde_json::json;
 use vm_control::{
     BalloonControlCommand, BatControlCommand, BatControlResult, BatteryType, DiskControlCommand,
-    MaybeOwnedDescriptor, UsbControlCommand, UsbControlResult, VmControlRequestSocket, VmRequest,
-    VmResponse, USB_CONTROL_MAX_PORTS,
+    GpuControlCommand, GpuControlResult, MaybeOwnedDescriptor, MemControlCommand, SnapshotCommand,
+    UsbControlCommand, UsbControlResult, VcpuCommand, VfioCommand, VmControlRequestSocket,
+    VmRequest, VmRequestAccess, VmResponse, USB_CONTROL_MAX_PORTS, VM_CONTROL_VERSION,
 };
 
 fn executable_is_plugin(executable: &Option<Executable>) -> bool {
@@ -127,27 +141,42 @@ fn parse_cpu_set(s: &str) -> argument::Result<Vec<usize>> {
 fn parse_cpu_affinity(s: &str) -> argument::Result<VcpuAffinity> {
     if s.contains('=') {
         let mut affinity_map = BTreeMap::new();
-        for cpu_pair in s.split(':') {
-            let assignment: Vec<&str> = cpu_pair.split('=').collect();
-            if assignment.len() != 2 {
+        // Assignments are separated by colons or commas. Since host CPU sets are comma-separated
+        // as well, parts without a `=` add to the host CPUs of the assignment before them.
+        let mut guest_cpu = None;
+        for part in s.split(|c| c == ':' || c == ',') {
+            let assignment: Vec<&str> = part.split('=').collect();
+            if assignment.len() > 2 {
                 return Err(argument::Error::InvalidValue {
-                    value: cpu_pair.to_owned(),
+                    value: part.to_owned(),
                     expected: String::from("invalid VCPU assignment syntax"),
                 });
             }
-            let guest_cpu = assignment[0]
-                .parse()
-                .map_err(|_| argument::Error::InvalidValue {
-                    value: assignment[0].to_owned(),
-                    expected: String::from("CPU index must be a non-negative integer"),
-                })?;
-            let host_cpu_set = parse_cpu_set(assignment[1])?;
-            if affinity_map.insert(guest_cpu, host_cpu_set).is_some() {
-                return Err(argument::Error::InvalidValue {
-                    value: cpu_pair.to_owned(),
-                    expected: String::from("VCPU index must be unique"),
-                });
+            if assignment.len() == 2 {
+                let cpu = assignment[0]
+                    .parse()
+                    .map_err(|_| argument::Error::InvalidValue {
+                        value: assignment[0].to_owned(),
+                        expected: String::from("CPU index must be a non-negative integer"),
+                    })?;
+                if affinity_map.insert(cpu, Vec::new()).is_some() {
+                    return Err(argument::Error::InvalidValue {
+                        value: part.to_owned(),
+                        expected: String::from("VCPU index must be unique"),
+                    });
+                }
+                guest_cpu = Some(cpu);
             }
+            let host_cpus = match guest_cpu.and_then(|cpu| affinity_map.get_mut(&cpu)) {
+                Some(host_cpus) => host_cpus,
+                None => {
+                    return Err(argument::Error::InvalidValue {
+                        value: part.to_owned(),
+                        expected: String::from("invalid VCPU assignment syntax"),
+                    })
+                }
+            };
+            host_cpus.extend(parse_cpu_set(assignment[assignment.len() - 1])?);
         }
         Ok(VcpuAffinity::PerVcpu(affinity_map))
     } else {
@@ -438,6 +467,140 @@ fn parse_ac97_options(s: &str) -> argument::Result<Ac97Parameters> {
     Ok(ac97_params)
 }
 
+#[cfg(feature = "audio")]
+fn parse_sound_options(s: &str) -> argument::Result<SoundParameters> {
+    let mut sound_params: SoundParameters = Default::default();
+
+    let opts = s
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.split('='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    fn parse_u32(k: &str, v: &str) -> argument::Result<u32> {
+        v.parse::<u32>().map_err(|_| argument::Error::InvalidValue {
+            value: v.to_string(),
+            expected: format!("{} must be a non-negative integer", k),
+        })
+    }
+
+    for (k, v) in opts {
+        match k {
+            "backend" => {
+                sound_params.backend =
+                    v.parse::<SoundBackend>()
+                        .map_err(|e| argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: e.to_string(),
+                        })?;
+            }
+            "capture" => {
+                sound_params.capture = v.parse::<bool>().map_err(|e| {
+                    argument::Error::Syntax(format!("invalid capture option: {}", e))
+                })?;
+            }
+            "num_output_streams" => {
+                sound_params.num_output_streams = parse_u32(k, v)?;
+            }
+            "num_input_streams" => {
+                sound_params.num_input_streams = parse_u32(k, v)?;
+            }
+            "output_latency_ms" => {
+                sound_params.output_latency_ms = parse_u32(k, v)?;
+            }
+            "input_latency_ms" => {
+                sound_params.input_latency_ms = parse_u32(k, v)?;
+            }
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown sound parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    if sound_params.num_output_streams == 0 {
+        return Err(argument::Error::InvalidValue {
+            value: "0".to_string(),
+            expected: String::from("at least one output stream is required"),
+        });
+    }
+
+    Ok(sound_params)
+}
+
+fn parse_rng_options(s: &str) -> argument::Result<RngParameters> {
+    let mut rng_params: RngParameters = Default::default();
+    let mut seed = None;
+
+    let opts = s
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        match k {
+            "source" => {
+                rng_params.source = match v {
+                    "builtin" => RngSource::Builtin { seed: None },
+                    "" => {
+                        return Err(argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: String::from("`source` must be a path or `builtin`"),
+                        })
+                    }
+                    path => RngSource::File(PathBuf::from(path)),
+                };
+            }
+            "seed" => {
+                seed = Some(
+                    v.parse::<u64>()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: String::from("`seed` must be a non-negative integer"),
+                        })?,
+                );
+            }
+            "rate" => {
+                let rate = v
+                    .parse::<u64>()
+                    .map_err(|_| argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("`rate` must be a positive integer"),
+                    })?;
+                if rate == 0 {
+                    return Err(argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("`rate` must be a positive integer"),
+                    });
+                }
+                rng_params.rate = Some(rate);
+            }
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown rng parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    if let Some(seed) = seed {
+        match &mut rng_params.source {
+            RngSource::Builtin { seed: s } => *s = Some(seed),
+            RngSource::File(_) => {
+                return Err(argument::Error::ExpectedArgument(
+                    "`seed` requires `source=builtin`".to_owned(),
+                ))
+            }
+        }
+    }
+
+    Ok(rng_params)
+}
+
 fn parse_serial_options(s: &str) -> argument::Result<SerialParameters> {
     let mut serial_setting = SerialParameters {
         type_: SerialType::Sink,
@@ -623,115 +786,778 @@ fn parse_plugin_gid_map_option(value: &str) -> argument::Result<GidMap> {
     })
 }
 
-fn parse_battery_options(s: Option<&str>) -> argument::Result<BatteryType> {
-    let mut battery_type: BatteryType = Default::default();
+// Checks a uid or gid map in the "inner outer count[,inner outer count]" format, whose ranges must
+// not overlap either inside or outside of the jail.
+fn parse_id_map(value: &str) -> argument::Result<String> {
+    let invalid = |expected: &str| argument::Error::InvalidValue {
+        value: value.to_owned(),
+        expected: expected.to_owned(),
+    };
+    let overlaps = |ranges: &[(u64, u64)], start: u64, count: u64| {
+        ranges
+            .iter()
+            .any(|&(s, c)| start < s + c && s < start + count)
+    };
 
-    if let Some(s) = s {
-        let opts = s
-            .split(',')
-            .map(|frag| frag.split('='))
-            .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+    let mut inner_ranges = Vec::new();
+    let mut outer_ranges = Vec::new();
+    for range in value.split(',') {
+        let fields = range
+            .split_whitespace()
+            .map(|field| field.parse::<u32>().map(u64::from))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| invalid("id map ranges must be of the form `inner outer count`"))?;
+        let (inner, outer, count) = match fields[..] {
+            [inner, outer, count] if count > 0 => (inner, outer, count),
+            _ => {
+                return Err(invalid(
+                    "id map ranges must be of the form `inner outer count`",
+                ))
+            }
+        };
+        if overlaps(&inner_ranges, inner, count) || overlaps(&outer_ranges, outer, count) {
+            return Err(invalid("id map ranges must not overlap"));
+        }
+        inner_ranges.push((inner, count));
+        outer_ranges.push((outer, count));
+    }
+    Ok(value.to_owned())
+}
 
-        for (k, v) in opts {
-            match k {
-                "type" => match v.parse::<BatteryType>() {
-                    Ok(type_) => battery_type = type_,
-                    Err(e) => {
+fn parse_jail_id_map_options(s: &str) -> argument::Result<(String, JailIdMaps)> {
+    let mut components = s.split(':');
+    let device = components.next().unwrap_or_default();
+    if device.is_empty() {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("`jail-id-map` must start with the device's policy name"),
+        });
+    }
+    if device == "fs_device" || device == "9p_device" {
+        return Err(argument::Error::InvalidValue {
+            value: device.to_owned(),
+            expected: String::from(
+                "shared directories take the `uidmap` and `gidmap` options of `shared-dir`",
+            ),
+        });
+    }
+
+    let mut maps = JailIdMaps::default();
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or_default();
+        let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("`jail-id-map` options must be of the form `kind=value`"),
+        })?;
+        match kind {
+            "uidmap" => maps.uid_map = Some(parse_id_map(value)?),
+            "gidmap" => maps.gid_map = Some(parse_id_map(value)?),
+            _ => {
+                return Err(argument::Error::InvalidValue {
+                    value: kind.to_owned(),
+                    expected: String::from("unrecognized option for `jail-id-map`"),
+                })
+            }
+        }
+    }
+    if maps == JailIdMaps::default() {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("`jail-id-map` needs a `uidmap` or `gidmap`"),
+        });
+    }
+    Ok((device.to_owned(), maps))
+}
+
+fn parse_control_peer(s: &str) -> argument::Result<ControlPeer> {
+    let mut id = None;
+    // Peers only get to look at the VM unless they are trusted with more.
+    let mut access = VmRequestAccess::ReadOnly;
+    for opt in s.split(',') {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or_default();
+        let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("`control-peer` options must be of the form `kind=value`"),
+        })?;
+        let parse_id = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`uid` and `gid` must be integers"),
+                })
+        };
+        match kind {
+            "uid" | "gid" if id.is_some() => {
+                return Err(argument::Error::TooManyArguments(
+                    "`control-peer` takes one `uid` or `gid`".to_owned(),
+                ))
+            }
+            "uid" => id = Some(ControlPeerId::Uid(parse_id(value)?)),
+            "gid" => id = Some(ControlPeerId::Gid(parse_id(value)?)),
+            "access" => {
+                access = match value {
+                    "read-only" => VmRequestAccess::ReadOnly,
+                    "full" => VmRequestAccess::Full,
+                    _ => {
                         return Err(argument::Error::InvalidValue {
-                            value: v.to_string(),
-                            expected: e.to_string(),
-                        });
+                            value: value.to_owned(),
+                            expected: String::from("`access` must be `read-only` or `full`"),
+                        })
                     }
-                },
-                "" => {}
-                _ => {
-                    return Err(argument::Error::UnknownArgument(format!(
-                        "battery parameter {}",
-                        k
-                    )));
                 }
             }
+            _ => {
+                return Err(argument::Error::InvalidValue {
+                    value: kind.to_owned(),
+                    expected: String::from("unrecognized option for `control-peer`"),
+                })
+            }
         }
     }
-
-    Ok(battery_type)
+    let id = id.ok_or_else(|| argument::Error::InvalidValue {
+        value: s.to_owned(),
+        expected: String::from("`control-peer` needs a `uid` or `gid`"),
+    })?;
+    Ok(ControlPeer { id, access })
 }
 
-fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
-    match name {
-        "" => {
-            if cfg.executable_path.is_some() {
-                return Err(argument::Error::TooManyArguments(format!(
-                    "A VM executable was already specified: {:?}",
-                    cfg.executable_path
-                )));
+// Parses the `socket=PATH` options of the vhost-user option `name`.
+fn parse_vhost_user_socket(name: &str, s: &str) -> argument::Result<PathBuf> {
+    let mut socket_path = None;
+
+    let opts = s
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        match k {
+            "socket" => {
+                if v.is_empty() {
+                    return Err(argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("`socket` must be a path"),
+                    });
+                }
+                socket_path = Some(PathBuf::from(v));
             }
-            let kernel_path = PathBuf::from(value.unwrap());
-            if !kernel_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("this kernel path does not exist"),
-                });
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown {} parameter {}",
+                    name, k
+                )));
             }
-            cfg.executable_path = Some(Executable::Kernel(kernel_path));
         }
-        "android-fstab" => {
-            if cfg.android_fstab.is_some()
-                && !cfg.android_fstab.as_ref().unwrap().as_os_str().is_empty()
-            {
-                return Err(argument::Error::TooManyArguments(
-                    "expected exactly one android fstab path".to_owned(),
-                ));
-            } else {
-                let android_fstab = PathBuf::from(value.unwrap());
-                if !android_fstab.exists() {
+    }
+
+    socket_path
+        .ok_or_else(|| argument::Error::ExpectedArgument(format!("`socket` missing from {}", name)))
+}
+
+fn parse_vhost_user_options(s: &str) -> argument::Result<VhostUserOption> {
+    let mut components = s.splitn(2, ',');
+    let type_name = components.next().unwrap();
+    let device_type =
+        virtio::str_to_type(type_name).ok_or_else(|| argument::Error::InvalidValue {
+            value: type_name.to_owned(),
+            expected: String::from("a virtio device type, such as `block` or `net`"),
+        })?;
+
+    let mut socket_path = None;
+    let mut shm_size = None;
+    let mut shm_id = 0;
+
+    let opts = components
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        match k {
+            "socket" => {
+                if v.is_empty() {
                     return Err(argument::Error::InvalidValue {
-                        value: value.unwrap().to_owned(),
-                        expected: String::from("this android fstab path does not exist"),
+                        value: v.to_string(),
+                        expected: String::from("`socket` must be a path"),
                     });
                 }
-                cfg.android_fstab = Some(android_fstab);
+                socket_path = Some(PathBuf::from(v));
             }
-        }
-        "params" => {
-            cfg.params.push(value.unwrap().to_owned());
-        }
-        "cpus" => {
-            if cfg.vcpu_count.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`cpus` already given".to_owned(),
-                ));
+            "shm-size" => {
+                let size = v
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|size| size.is_power_of_two())
+                    .ok_or_else(|| argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("`shm-size` must be a power of two in bytes"),
+                    })?;
+                shm_size = Some(size);
             }
-            cfg.vcpu_count =
-                Some(
-                    value
-                        .unwrap()
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("this value for `cpus` needs to be integer"),
-                        })?,
-                )
-        }
-        "cpu-affinity" => {
-            if cfg.vcpu_affinity.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`cpu-affinity` already given".to_owned(),
-                ));
+            "shm-id" => {
+                shm_id = v.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: v.to_string(),
+                    expected: String::from("`shm-id` must be an integer from 0 to 255"),
+                })?;
+            }
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown vhost-user parameter {}",
+                    k
+                )));
             }
-            cfg.vcpu_affinity = Some(parse_cpu_affinity(value.unwrap())?);
-        }
-        "no-smt" => {
-            cfg.no_smt = true;
         }
-        "rt-cpus" => {
-            if !cfg.rt_cpus.is_empty() {
-                return Err(argument::Error::TooManyArguments(
-                    "`rt-cpus` already given".to_owned(),
+    }
+
+    let socket_path = socket_path.ok_or_else(|| {
+        argument::Error::ExpectedArgument("`socket` missing from vhost-user".to_owned())
+    })?;
+    Ok(VhostUserOption {
+        device_type,
+        socket_path,
+        shm_size,
+        shm_id,
+    })
+}
+
+fn parse_balloon_guest_requests_options(s: Option<&str>) -> argument::Result<BalloonGuestRequests> {
+    let mut bounds = BalloonGuestRequests::default();
+
+    let opts = s
+        .unwrap_or("")
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        let mib = || {
+            v.parse::<u64>()
+                .ok()
+                .and_then(|mib| mib.checked_mul(1024 * 1024))
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: v.to_string(),
+                    expected: format!("`{}` must be a size in MiB", k),
+                })
+        };
+        match k {
+            "min_mib" => bounds.min_bytes = mib()?,
+            "max_mib" => bounds.max_bytes = Some(mib()?),
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown balloon-guest-requests parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    if let Some(max_bytes) = bounds.max_bytes {
+        if bounds.min_bytes > max_bytes {
+            return Err(argument::Error::InvalidValue {
+                value: s.unwrap_or("").to_owned(),
+                expected: String::from("`min_mib` must not be larger than `max_mib`"),
+            });
+        }
+    }
+
+    Ok(bounds)
+}
+
+fn parse_fw_cfg_options(s: &str) -> argument::Result<(String, FwCfgData)> {
+    let mut name = None;
+    let mut data = None;
+
+    let opts = s
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        match k {
+            "name" => {
+                if v.is_empty() || v.len() > FW_CFG_MAX_FILE_NAME {
+                    return Err(argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: format!(
+                            "`name` must be between 1 and {} bytes long",
+                            FW_CFG_MAX_FILE_NAME
+                        ),
+                    });
+                }
+                name = Some(v.to_owned());
+            }
+            "path" | "string" if data.is_some() => {
+                return Err(argument::Error::TooManyArguments(
+                    "only one of `path` and `string` may be given".to_owned(),
+                ));
+            }
+            "path" => {
+                let path = PathBuf::from(v);
+                if !path.is_file() {
+                    return Err(argument::Error::InvalidValue {
+                        value: v.to_string(),
+                        expected: String::from("`path` must be an existing file"),
+                    });
+                }
+                data = Some(FwCfgData::File(path));
+            }
+            "string" => data = Some(FwCfgData::String(v.to_owned())),
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown fw-cfg parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    let name = name.ok_or_else(|| {
+        argument::Error::ExpectedArgument("`name` missing from fw-cfg".to_owned())
+    })?;
+    let data = data.ok_or_else(|| {
+        argument::Error::ExpectedArgument("one of `path` or `string` is required".to_owned())
+    })?;
+    Ok((name, data))
+}
+
+fn parse_metadata_option(name: &str, value: &str) -> argument::Result<(String, String)> {
+    let mut components = value.splitn(2, '=');
+    let key = components.next().unwrap();
+    let val = components.next();
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    match val {
+        Some(val) if valid_key => Ok((key.to_owned(), val.to_owned())),
+        _ => Err(argument::Error::InvalidValue {
+            value: value.to_owned(),
+            expected: format!(
+                "`{}` must be KEY=VALUE, where KEY only has letters, digits, '-', '_' and '.'",
+                name
+            ),
+        }),
+    }
+}
+
+fn parse_battery_options(s: Option<&str>) -> argument::Result<BatteryType> {
+    let mut battery_type: BatteryType = Default::default();
+
+    if let Some(s) = s {
+        let opts = s
+            .split(',')
+            .map(|frag| frag.split('='))
+            .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+        for (k, v) in opts {
+            match k {
+                "type" => match v.parse::<BatteryType>() {
+                    Ok(type_) => battery_type = type_,
+                    Err(e) => {
+                        return Err(argument::Error::InvalidValue {
+                            value: v.to_string(),
+                            expected: e.to_string(),
+                        });
+                    }
+                },
+                "" => {}
+                _ => {
+                    return Err(argument::Error::UnknownArgument(format!(
+                        "battery parameter {}",
+                        k
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(battery_type)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn parse_cpu_features(s: &str) -> argument::Result<Vec<(String, bool)>> {
+    s.split(',')
+        .map(|feature| {
+            let (enable, name) = if let Some(name) = feature.strip_prefix('+') {
+                (true, name)
+            } else if let Some(name) = feature.strip_prefix('-') {
+                (false, name)
+            } else {
+                return Err(argument::Error::InvalidValue {
+                    value: feature.to_owned(),
+                    expected: String::from("CPU features must start with + or -"),
+                });
+            };
+            if name.is_empty() {
+                return Err(argument::Error::InvalidValue {
+                    value: feature.to_owned(),
+                    expected: String::from("a CPU feature name after + or -"),
+                });
+            }
+            Ok((name.to_owned(), enable))
+        })
+        .collect()
+}
+
+fn parse_numa_options(s: &str) -> argument::Result<NumaNode> {
+    let mut node = NumaNode::default();
+    let mut memory_size = None;
+
+    let opts = s
+        .split(',')
+        .filter(|frag| !frag.is_empty())
+        .map(|frag| frag.splitn(2, '='))
+        .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+    for (k, v) in opts {
+        match k {
+            "mem" => {
+                memory_size = Some(v.parse::<u64>().ok().filter(|&mib| mib > 0).ok_or_else(
+                    || argument::Error::InvalidValue {
+                        value: v.to_owned(),
+                        expected: String::from("`mem` must be a non-zero size in MiB"),
+                    },
+                )?)
+            }
+            // The CPU list is colon-separated, since commas separate the options.
+            "cpus" => node.cpus = parse_cpu_set(&v.replace(':', ","))?,
+            "host-node" => {
+                node.host_node = Some(v.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: v.to_owned(),
+                    expected: String::from("`host-node` must be a host NUMA node number"),
+                })?)
+            }
+            "dist" => {
+                node.distances = v
+                    .split(':')
+                    .map(|d| {
+                        d.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: d.to_owned(),
+                            expected: String::from("distances must be integers from 10 to 255"),
+                        })
+                    })
+                    .collect::<argument::Result<_>>()?
+            }
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown numa parameter {}",
+                    k
+                )));
+            }
+        }
+    }
+
+    node.memory_size = memory_size
+        .ok_or_else(|| argument::Error::ExpectedArgument("`mem` missing from numa".to_owned()))?;
+    Ok(node)
+}
+
+// Checks the `--numa` nodes against each other and the rest of the config. Sizes are still in MiB.
+fn validate_numa_nodes(cfg: &Config) -> argument::Result<()> {
+    let nodes = &cfg.numa_nodes;
+    let memory: u64 = nodes.iter().map(|node| node.memory_size).sum();
+    if cfg.memory != Some(memory) {
+        return Err(argument::Error::InvalidValue {
+            value: memory.to_string(),
+            expected: String::from("the memory of the `numa` nodes must add up to `mem`"),
+        });
+    }
+
+    let vcpu_count = cfg
+        .max_vcpu_count
+        .unwrap_or_else(|| cfg.vcpu_count.unwrap_or(1));
+    let mut seen = vec![false; vcpu_count];
+    for cpu in nodes.iter().flat_map(|node| node.cpus.iter().copied()) {
+        if cpu >= vcpu_count || std::mem::replace(&mut seen[cpu], true) {
+            return Err(argument::Error::InvalidValue {
+                value: cpu.to_string(),
+                expected: String::from("each VCPU can only be in one `numa` node"),
+            });
+        }
+    }
+
+    for (i, node) in nodes.iter().enumerate() {
+        if node.distances.is_empty() {
+            continue;
+        }
+        let valid = node.distances.len() == nodes.len()
+            && node.distances.iter().enumerate().all(|(j, &d)| {
+                if i == j {
+                    d == arch::numa::NUMA_LOCAL_DISTANCE
+                } else {
+                    d > arch::numa::NUMA_LOCAL_DISTANCE
+                }
+            });
+        if !valid {
+            return Err(argument::Error::InvalidValue {
+                value: format!("{:?}", node.distances),
+                expected: format!(
+                    "`dist` needs a distance to each of the {} nodes, 10 to itself and more to the others",
+                    nodes.len()
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+fn parse_vfio_platform_options(s: &str) -> argument::Result<VfioPlatformOption> {
+    let mut components = s.split(',');
+    let path = PathBuf::from(components.next().unwrap_or(""));
+    if !path.is_dir() {
+        return Err(argument::Error::InvalidValue {
+            value: path.display().to_string(),
+            expected: String::from("the vfio-platform path should be a directory"),
+        });
+    }
+
+    let mut dt_node = false;
+    for opt in components {
+        match opt {
+            "dt-node" => dt_node = true,
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "vfio-platform parameter {}",
+                    opt
+                )));
+            }
+        }
+    }
+
+    Ok(VfioPlatformOption { path, dt_node })
+}
+
+// Parses the `PATH[,key=value...]` value of `--disk` and its variants.
+fn parse_disk_option(param: &str, read_only: bool) -> argument::Result<DiskOption> {
+    let mut components = param.split(',');
+    let disk_path =
+        PathBuf::from(
+            components
+                .next()
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("missing disk path"),
+                })?,
+        );
+    if !disk_path.exists() {
+        return Err(argument::Error::InvalidValue {
+            value: param.to_owned(),
+            expected: String::from("this disk path does not exist"),
+        });
+    }
+
+    let mut disk = DiskOption {
+        path: disk_path,
+        read_only,
+        sparse: true,
+        block_size: 512,
+        id: None,
+    };
+
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("disk options must not be empty"),
+        })?;
+        let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("disk options must be of the form `kind=value`"),
+        })?;
+
+        match kind {
+            "sparse" => {
+                let sparse = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`sparse` must be a boolean"),
+                })?;
+                disk.sparse = sparse;
+            }
+            "block_size" => {
+                let block_size = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`block_size` must be an integer"),
+                })?;
+                disk.block_size = block_size;
+            }
+            "id" => {
+                if value.len() > DISK_ID_LEN {
+                    return Err(argument::Error::InvalidValue {
+                        value: value.to_owned(),
+                        expected: format!("`id` must be {} or fewer characters", DISK_ID_LEN),
+                    });
+                }
+                let mut id = [0u8; DISK_ID_LEN];
+                // Slicing id to value's length will never panic
+                // because we checked that value will fit into id above.
+                id[..value.len()].copy_from_slice(value.as_bytes());
+                disk.id = Some(id);
+            }
+            _ => {
+                return Err(argument::Error::InvalidValue {
+                    value: kind.to_owned(),
+                    expected: String::from("unrecognized disk option"),
+                });
+            }
+        }
+    }
+
+    Ok(disk)
+}
+
+fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
+    match name {
+        "" => {
+            if cfg.executable_path.is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "A VM executable was already specified: {:?}",
+                    cfg.executable_path
+                )));
+            }
+            let kernel_path = PathBuf::from(value.unwrap());
+            if !kernel_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this kernel path does not exist"),
+                });
+            }
+            cfg.executable_path = Some(Executable::Kernel(kernel_path));
+        }
+        "android-fstab" => {
+            if cfg.android_fstab.is_some()
+                && !cfg.android_fstab.as_ref().unwrap().as_os_str().is_empty()
+            {
+                return Err(argument::Error::TooManyArguments(
+                    "expected exactly one android fstab path".to_owned(),
+                ));
+            } else {
+                let android_fstab = PathBuf::from(value.unwrap());
+                if !android_fstab.exists() {
+                    return Err(argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from("this android fstab path does not exist"),
+                    });
+                }
+                cfg.android_fstab = Some(android_fstab);
+            }
+        }
+        "dtbo" => {
+            let dtbo = PathBuf::from(value.unwrap());
+            if !dtbo.is_file() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this device tree overlay path does not exist"),
+                });
+            }
+            cfg.dtbo.push(dtbo);
+        }
+        "params" => {
+            cfg.params.push(value.unwrap().to_owned());
+        }
+        "cpus" => {
+            if cfg.vcpu_count.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cpus` already given".to_owned(),
+                ));
+            }
+            cfg.vcpu_count =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("this value for `cpus` needs to be integer"),
+                        })?,
+                )
+        }
+        #[cfg(target_arch = "x86_64")]
+        "max-cpus" => {
+            if cfg.max_vcpu_count.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`max-cpus` already given".to_owned(),
+                ));
+            }
+            cfg.max_vcpu_count =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("this value for `max-cpus` needs to be integer"),
+                        })?,
+                )
+        }
+        "cpu-affinity" => {
+            if cfg.vcpu_affinity.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cpu-affinity` already given".to_owned(),
+                ));
+            }
+            cfg.vcpu_affinity = Some(parse_cpu_affinity(value.unwrap())?);
+        }
+        "no-smt" => {
+            cfg.no_smt = true;
+        }
+        "lock-guest-memory" => {
+            cfg.lock_guest_memory = true;
+        }
+        "merge-guest-memory" => {
+            cfg.merge_guest_memory = true;
+        }
+        #[cfg(target_arch = "x86_64")]
+        "snapshot" => {
+            cfg.snapshot = true;
+        }
+        "numa" => {
+            cfg.numa_nodes.push(parse_numa_options(value.unwrap())?);
+        }
+        #[cfg(target_arch = "x86_64")]
+        "cpu-model" => {
+            if cfg.cpu_features.model.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cpu-model` already given".to_owned(),
+                ));
+            }
+            cfg.cpu_features.model = Some(value.unwrap().to_owned());
+        }
+        #[cfg(target_arch = "x86_64")]
+        "nested-virt" => {
+            cfg.cpu_features.nested_virt = true;
+        }
+        #[cfg(target_arch = "x86_64")]
+        "cpu-features" => {
+            cfg.cpu_features
+                .features
+                .extend(parse_cpu_features(value.unwrap())?);
+        }
+        "rt-cpus" => {
+            if !cfg.rt_cpus.is_empty() {
+                return Err(argument::Error::TooManyArguments(
+                    "`rt-cpus` already given".to_owned(),
                 ));
             }
             cfg.rt_cpus = parse_cpu_set(value.unwrap())?;
         }
+        "housekeeping-cpus" => {
+            if !cfg.housekeeping_cpus.is_empty() {
+                return Err(argument::Error::TooManyArguments(
+                    "`housekeeping-cpus` already given".to_owned(),
+                ));
+            }
+            cfg.housekeeping_cpus = parse_cpu_set(value.unwrap())?;
+        }
         "mem" => {
             if cfg.memory.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -761,6 +1587,15 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.ac97_parameters.push(ac97_params);
         }
+        #[cfg(feature = "audio")]
+        "sound" => {
+            if cfg.sound_parameters.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`sound` already given".to_owned(),
+                ));
+            }
+            cfg.sound_parameters = Some(parse_sound_options(value.unwrap_or(""))?);
+        }
         "serial" => {
             let serial_params = parse_serial_options(value.unwrap())?;
             let num = serial_params.num;
@@ -824,25 +1659,17 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             syslog::set_proc_name(value.unwrap());
             cfg.syslog_tag = Some(value.unwrap().to_owned());
         }
+        "name" => {
+            if cfg.name.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`name` already given".to_owned(),
+                ));
+            }
+            cfg.name = Some(value.unwrap().to_owned());
+        }
         "root" | "rwroot" | "disk" | "rwdisk" => {
-            let param = value.unwrap();
-            let mut components = param.split(',');
             let read_only = !name.starts_with("rw");
-            let disk_path =
-                PathBuf::from(
-                    components
-                        .next()
-                        .ok_or_else(|| argument::Error::InvalidValue {
-                            value: param.to_owned(),
-                            expected: String::from("missing disk path"),
-                        })?,
-                );
-            if !disk_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: param.to_owned(),
-                    expected: String::from("this disk path does not exist"),
-                });
-            }
+            let disk = parse_disk_option(value.unwrap(), read_only)?;
             if name.ends_with("root") {
                 if cfg.disks.len() >= 26 {
                     return Err(argument::Error::TooManyArguments(
@@ -855,67 +1682,6 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     if read_only { "ro" } else { "rw" }
                 ));
             }
-
-            let mut disk = DiskOption {
-                path: disk_path,
-                read_only,
-                sparse: true,
-                block_size: 512,
-                id: None,
-            };
-
-            for opt in components {
-                let mut o = opt.splitn(2, '=');
-                let kind = o.next().ok_or_else(|| argument::Error::InvalidValue {
-                    value: opt.to_owned(),
-                    expected: String::from("disk options must not be empty"),
-                })?;
-                let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
-                    value: opt.to_owned(),
-                    expected: String::from("disk options must be of the form `kind=value`"),
-                })?;
-
-                match kind {
-                    "sparse" => {
-                        let sparse = value.parse().map_err(|_| argument::Error::InvalidValue {
-                            value: value.to_owned(),
-                            expected: String::from("`sparse` must be a boolean"),
-                        })?;
-                        disk.sparse = sparse;
-                    }
-                    "block_size" => {
-                        let block_size =
-                            value.parse().map_err(|_| argument::Error::InvalidValue {
-                                value: value.to_owned(),
-                                expected: String::from("`block_size` must be an integer"),
-                            })?;
-                        disk.block_size = block_size;
-                    }
-                    "id" => {
-                        if value.len() > DISK_ID_LEN {
-                            return Err(argument::Error::InvalidValue {
-                                value: value.to_owned(),
-                                expected: format!(
-                                    "`id` must be {} or fewer characters",
-                                    DISK_ID_LEN
-                                ),
-                            });
-                        }
-                        let mut id = [0u8; DISK_ID_LEN];
-                        // Slicing id to value's length will never panic
-                        // because we checked that value will fit into id above.
-                        id[..value.len()].copy_from_slice(value.as_bytes());
-                        disk.id = Some(id);
-                    }
-                    _ => {
-                        return Err(argument::Error::InvalidValue {
-                            value: kind.to_owned(),
-                            expected: String::from("unrecognized disk option"),
-                        });
-                    }
-                }
-            }
-
             cfg.disks.push(disk);
         }
         "pmem-device" | "rw-pmem-device" => {
@@ -978,6 +1744,33 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                 },
             });
         }
+        "metadata" => {
+            let (key, val) = parse_metadata_option(name, value.unwrap())?;
+            if cfg.metadata.iter().any(|(k, _)| *k == key) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`metadata` key `{}` already given",
+                    key
+                )));
+            }
+            cfg.metadata.push((key, val));
+        }
+        "metadata-file" => {
+            let (file_name, path) = parse_metadata_option(name, value.unwrap())?;
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("the metadata-file path should be a file"),
+                });
+            }
+            if cfg.metadata_files.iter().any(|(n, _)| *n == file_name) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`metadata-file` name `{}` already given",
+                    file_name
+                )));
+            }
+            cfg.metadata_files.push((file_name, path));
+        }
         "host_ip" => {
             if cfg.host_ip.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -1126,9 +1919,58 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.socket_path = Some(socket_path);
         }
+        "metrics-socket" => {
+            if cfg.metrics_socket.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`metrics-socket` already given".to_owned(),
+                ));
+            }
+            let addr = value.unwrap().parse::<MetricsAddr>().map_err(|e| {
+                argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: e,
+                }
+            })?;
+            if let MetricsAddr::Unix(path) = &addr {
+                if path.exists() {
+                    return Err(argument::Error::InvalidValue {
+                        value: path.to_string_lossy().into_owned(),
+                        expected: String::from("this socket path already exists"),
+                    });
+                }
+            }
+            cfg.metrics_socket = Some(addr);
+        }
         "disable-sandbox" => {
             cfg.sandbox = false;
         }
+        "landlock" => {
+            cfg.landlock = true;
+        }
+        "disable-io-uring" => {
+            cfg.disable_io_uring = true;
+        }
+        "io-uring-sqpoll" => {
+            let idle_ms =
+                value
+                    .unwrap()
+                    .parse::<u64>()
+                    .map_err(|_| argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from("expected the SQPOLL idle time in milliseconds"),
+                    })?;
+            cfg.io_uring_sqpoll = Some(Duration::from_millis(idle_ms));
+        }
+        "trace" => {
+            let categories = value
+                .unwrap()
+                .parse::<cros_tracing::Categories>()
+                .map_err(|e| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: e,
+                })?;
+            cfg.trace_categories = Some(categories);
+        }
         "cid" => {
             if cfg.cid.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -1145,6 +1987,19 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     })?,
             );
         }
+        "vhost-user-vsock" => {
+            if cfg.vhost_user_vsock.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`vhost-user-vsock` already given".to_owned(),
+                ));
+            }
+            cfg.vhost_user_vsock =
+                Some(parse_vhost_user_socket("vhost-user-vsock", value.unwrap())?);
+        }
+        "vhost-user" => {
+            cfg.vhost_user
+                .push(parse_vhost_user_options(value.unwrap())?);
+        }
         "shared-dir" => {
             // This is formatted as multiple fields, each separated by ":". The first 2 fields are
             // fixed (src:tag).  The rest may appear in any order:
@@ -1208,8 +2063,8 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                                 expected: String::from("`type` must be one of `fs` or `9p`"),
                             })?
                     }
-                    "uidmap" => shared_dir.uid_map = value.into(),
-                    "gidmap" => shared_dir.gid_map = value.into(),
+                    "uidmap" => shared_dir.uid_map = parse_id_map(value)?,
+                    "gidmap" => shared_dir.gid_map = parse_id_map(value)?,
                     "timeout" => {
                         let seconds = value.parse().map_err(|_| argument::Error::InvalidValue {
                             value: value.to_owned(),
@@ -1270,6 +2125,18 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             // `value` is Some because we are in this match so it's safe to unwrap.
             cfg.seccomp_policy_dir = PathBuf::from(value.unwrap());
         }
+        "control-peer" => {
+            cfg.control_peers.push(parse_control_peer(value.unwrap())?);
+        }
+        "jail-id-map" => {
+            let (device, maps) = parse_jail_id_map_options(value.unwrap())?;
+            if cfg.jail_id_maps.insert(device.clone(), maps).is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`jail-id-map` already given for {}",
+                    device
+                )));
+            }
+        }
         "seccomp-log-failures" => {
             // A side-effect of this flag is to force the use of .policy files
             // instead of .bpf files (.bpf files are expected and assumed to be
@@ -1348,6 +2215,14 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
         }
         "vhost-net" => cfg.vhost_net = true,
+        "net-helper-socket" => {
+            if cfg.net_helper_socket.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`net-helper-socket` already given".to_owned(),
+                ));
+            }
+            cfg.net_helper_socket = Some(PathBuf::from(value.unwrap()));
+        }
         "tap-fd" => {
             cfg.tap_fd.push(
                 value
@@ -1369,6 +2244,14 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         "software-tpm" => {
             cfg.software_tpm = true;
         }
+        "swtpm" => {
+            if cfg.swtpm.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`swtpm` already given".to_owned(),
+                ));
+            }
+            cfg.swtpm = Some(PathBuf::from(value.unwrap()));
+        }
         "single-touch" => {
             if cfg.virtio_single_touch.is_some() {
                 return Err(argument::Error::TooManyArguments(
@@ -1449,6 +2332,16 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.virtio_input_evdevs.push(dev_path);
         }
+        "hypervisor" => {
+            cfg.hypervisor =
+                value
+                    .unwrap()
+                    .parse()
+                    .map_err(|e: &str| argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: e.to_owned(),
+                    })?;
+        }
         "split-irqchip" => {
             cfg.split_irqchip = true;
         }
@@ -1464,6 +2357,53 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
             }
             cfg.executable_path = Some(Executable::Bios(PathBuf::from(value.unwrap().to_owned())));
         }
+        "bios-kernel" => {
+            if cfg.bios_kernel_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`bios-kernel` already given".to_owned(),
+                ));
+            }
+            let kernel_path = PathBuf::from(value.unwrap());
+            if !kernel_path.is_file() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this kernel path does not exist"),
+                });
+            }
+            cfg.bios_kernel_path = Some(kernel_path);
+        }
+        "fw-cfg" => {
+            let (name, data) = parse_fw_cfg_options(value.unwrap())?;
+            if cfg.fw_cfg_files.iter().any(|(n, _)| *n == name) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "fw-cfg file {} already given",
+                    name
+                )));
+            }
+            cfg.fw_cfg_files.push((name, data));
+        }
+        "boot-device" => {
+            cfg.boot_devices.push(value.unwrap().to_owned());
+        }
+        #[cfg(target_arch = "x86_64")]
+        "read-only-bios" => {
+            cfg.read_only_bios = true;
+        }
+        "pflash" => {
+            if cfg.pflash_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`pflash` already given".to_owned(),
+                ));
+            }
+            let pflash_path = PathBuf::from(value.unwrap());
+            if !pflash_path.is_file() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this pflash path does not exist"),
+                });
+            }
+            cfg.pflash_path = Some(pflash_path);
+        }
         "vfio" => {
             let vfio_path = PathBuf::from(value.unwrap());
             if !vfio_path.exists() {
@@ -1481,6 +2421,27 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
 
             cfg.vfio.push(vfio_path);
         }
+        #[cfg(target_arch = "x86_64")]
+        "virtio-iommu" => {
+            cfg.virtio_iommu = true;
+        }
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        "vfio-platform" => {
+            cfg.vfio_platform
+                .push(parse_vfio_platform_options(value.unwrap())?);
+        }
+        "pcie-root-ports" => {
+            cfg.pcie_root_ports =
+                value
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from(
+                            "this value for `pcie-root-ports` needs to be an integer from 0 to 255",
+                        ),
+                    })?;
+        }
         "video-decoder" => {
             cfg.video_dec = true;
         }
@@ -1506,7 +2467,18 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         }
         "protected-vm" => {
             cfg.protected_vm = true;
-            cfg.params.push("swiotlb=force".to_string());
+        }
+        "swiotlb" => {
+            let size_mib = value
+                .unwrap()
+                .parse::<u64>()
+                .ok()
+                .filter(|&mib| mib > 0)
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("expected a non-zero size in MiB"),
+                })?;
+            cfg.swiotlb = Some(size_mib);
         }
         "battery" => {
             let params = parse_battery_options(value)?;
@@ -1514,14 +2486,20 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
         }
         #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
         "gdb" => {
-            let port = value
-                .unwrap()
-                .parse()
-                .map_err(|_| argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
+            let value = value.unwrap();
+            // Anything that isn't a number is the path of a unix domain socket.
+            let address = if value.chars().all(|c| c.is_ascii_digit()) {
+                GdbAddress::Tcp(value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
                     expected: String::from("expected a valid port number"),
-                })?;
-            cfg.gdb = Some(port);
+                })?)
+            } else {
+                GdbAddress::Unix(PathBuf::from(value))
+            };
+            cfg.gdb = Some(address);
+        }
+        "no-balloon" => {
+            cfg.balloon = false;
         }
         "balloon_bias_mib" => {
             cfg.balloon_bias =
@@ -1535,6 +2513,41 @@ fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::
                     * 1024
                     * 1024; // cfg.balloon_bias is in bytes.
         }
+        "balloon-guest-requests" => {
+            cfg.balloon_guest_requests = Some(parse_balloon_guest_requests_options(value)?);
+        }
+        "virtio-mem" => {
+            let size_mib = value
+                .unwrap()
+                .parse::<u64>()
+                .ok()
+                .filter(|&mib| mib > 0 && mib % 128 == 0)
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("expected a non-zero multiple of 128 MiB"),
+                })?;
+            cfg.virtio_mem = Some(size_mib);
+        }
+        "rng" => {
+            cfg.rng_parameters = parse_rng_options(value.unwrap())?;
+        }
+        "profile" => {
+            if cfg.profile.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`profile` already given".to_owned(),
+                ));
+            }
+            cfg.profile =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|e: &str| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: e.to_owned(),
+                        })?,
+                );
+        }
         "help" => return Err(argument::Error::PrintHelp),
         _ => unreachable!(),
     }
@@ -1545,6 +2558,14 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
     if cfg.executable_path.is_none() {
         return Err(argument::Error::ExpectedArgument("`KERNEL`".to_owned()));
     }
+    if !cfg.numa_nodes.is_empty() {
+        // Without `mem`, the NUMA nodes rather than the profile give the memory size.
+        let memory = cfg.numa_nodes.iter().map(|node| node.memory_size).sum();
+        cfg.memory.get_or_insert(memory);
+    }
+    if let Some(profile) = cfg.profile {
+        profile.apply(cfg);
+    }
     if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
         if cfg.host_ip.is_none() {
             return Err(argument::Error::ExpectedArgument(
@@ -1562,6 +2583,96 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             ));
         }
     }
+    if cfg.protected_vm {
+        // Devices can't access the private memory of a protected VM, so the guest bounces all of
+        // their DMA through its swiotlb. The kernel takes its size in 2 KiB slabs.
+        let size_mib = cfg.swiotlb.unwrap_or(DEFAULT_SWIOTLB_SIZE_MIB);
+        cfg.params
+            .push(format!("swiotlb={},force", size_mib * 1024 * 1024 / 2048));
+    } else if cfg.swiotlb.is_some() {
+        return Err(argument::Error::ExpectedArgument(
+            "`swiotlb` requires `protected-vm`".to_owned(),
+        ));
+    }
+    if cfg.software_tpm && cfg.swtpm.is_some() {
+        return Err(argument::Error::TooManyArguments(
+            "`software-tpm` and `swtpm` can't be used together".to_owned(),
+        ));
+    }
+    if cfg.landlock && !cfg.sandbox {
+        return Err(argument::Error::TooManyArguments(
+            "`landlock` restricts device processes, which `disable-sandbox` turns off".to_owned(),
+        ));
+    }
+    if cfg.snapshot && cfg.balloon {
+        return Err(argument::Error::TooManyArguments(
+            "`snapshot` needs `no-balloon`, as the balloon device can't be saved".to_owned(),
+        ));
+    }
+    if !cfg.balloon && (cfg.balloon_guest_requests.is_some() || cfg.balloon_bias != 0) {
+        return Err(argument::Error::TooManyArguments(
+            "`balloon-guest-requests` and `balloon_bias_mib` need a balloon, which `no-balloon` \
+             removes"
+                .to_owned(),
+        ));
+    }
+    if cfg.merge_guest_memory
+        && (cfg.sandbox
+            || !cfg.vhost_user.is_empty()
+            || cfg.vhost_user_vsock.is_some()
+            || !cfg.ac97_parameters.is_empty())
+    {
+        return Err(argument::Error::TooManyArguments(
+            "`merge-guest-memory` keeps guest memory out of other processes, so it needs \
+             `disable-sandbox` and can't be combined with `vhost-user`, `vhost-user-vsock` or `ac97`"
+                .to_owned(),
+        ));
+    }
+    if cfg.cid.is_some() && cfg.vhost_user_vsock.is_some() {
+        return Err(argument::Error::TooManyArguments(
+            "`cid` can't be combined with `vhost-user-vsock`; the backend assigns the CID"
+                .to_owned(),
+        ));
+    }
+    if (cfg.bios_kernel_path.is_some()
+        || !cfg.fw_cfg_files.is_empty()
+        || !cfg.boot_devices.is_empty()
+        || cfg.pflash_path.is_some()
+        || cfg.read_only_bios)
+        && !matches!(cfg.executable_path, Some(Executable::Bios(_)))
+    {
+        return Err(argument::Error::ExpectedArgument(
+            "`bios-kernel`, `fw-cfg`, `boot-device`, `pflash` and `read-only-bios` require `bios`"
+                .to_owned(),
+        ));
+    }
+    if !cfg.metadata.is_empty()
+        && cfg
+            .metadata_files
+            .iter()
+            .any(|(name, _)| name == "meta-data")
+    {
+        return Err(argument::Error::TooManyArguments(
+            "`metadata` can't be combined with a `metadata-file` named meta-data".to_owned(),
+        ));
+    }
+    if let Some(max_vcpu_count) = cfg.max_vcpu_count {
+        if max_vcpu_count < cfg.vcpu_count.unwrap_or(1) {
+            return Err(argument::Error::InvalidValue {
+                value: max_vcpu_count.to_string(),
+                expected: String::from("`max-cpus` can't be less than `cpus`"),
+            });
+        }
+        if max_vcpu_count > u8::MAX as usize {
+            return Err(argument::Error::InvalidValue {
+                value: max_vcpu_count.to_string(),
+                expected: String::from("`max-cpus` can't be more than 255"),
+            });
+        }
+    }
+    if !cfg.numa_nodes.is_empty() {
+        validate_numa_nodes(cfg)?;
+    }
     if cfg.plugin_root.is_some() && !executable_is_plugin(&cfg.executable_path) {
         return Err(argument::Error::ExpectedArgument(
             "`plugin-root` requires `plugin`".to_owned(),
@@ -1579,36 +2690,90 @@ fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Err
             }
         }
     }
-    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    if cfg.gdb.is_some() {
-        if cfg.vcpu_count.unwrap_or(1) != 1 {
-            return Err(argument::Error::ExpectedArgument(
-                "`gdb` requires the number of vCPU to be 1".to_owned(),
-            ));
-        }
-    }
     set_default_serial_parameters(&mut cfg.serial_parameters);
     Ok(())
 }
 
+// Sets `cfg` from the command line, then from the `--cfg` file if one was given. Options given on
+// the command line replace all values of the same option in the file.
+fn set_arguments_with_cfg_file<I, R>(
+    args: I,
+    arguments: &[Argument],
+    cfg: &mut Config,
+) -> argument::Result<()>
+where
+    I: Iterator<Item = R>,
+    R: AsRef<str>,
+{
+    let mut cfg_path = None;
+    let mut given = Vec::new();
+    set_arguments(args, arguments, |name, value| {
+        if name == "cfg" {
+            if cfg_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cfg` already given".to_owned(),
+                ));
+            }
+            cfg_path = Some(PathBuf::from(value.unwrap()));
+            return Ok(());
+        }
+        set_argument(cfg, name, value)?;
+        given.push(name.to_owned());
+        Ok(())
+    })?;
+
+    let path = match cfg_path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = ConfigFile::load(&path).map_err(|e| argument::Error::InvalidValue {
+        value: path.to_string_lossy().into_owned(),
+        expected: e.to_string(),
+    })?;
+    let file_args = file.to_args(|name| given.iter().any(|g| g == name));
+    set_arguments(file_args.iter(), arguments, |name, value| {
+        set_argument(cfg, name, value)
+    })
+}
+
 fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
     let arguments =
         &[Argument::positional("KERNEL", "bzImage of kernel to run"),
+          Argument::value("cfg", "PATH", "Read the options of the VM from the JSON file at PATH. Its keys are the long names of these options and `kernel`, and `include` names other files to read first. Options given on the command line replace those in the file."),
           Argument::value("android-fstab", "PATH", "Path to Android fstab"),
+          Argument::value("dtbo", "PATH", "Device tree overlay (.dtbo) to apply to the device tree of ARM guests. Can be given more than once; overlays are applied in order and may refer to the labels in /__symbols__."),
           Argument::short_value('i', "initrd", "PATH", "Initial ramdisk to load."),
           Argument::short_value('p',
                                 "params",
                                 "PARAMS",
                                 "Extra kernel or plugin command line arguments. Can be given more than once."),
           Argument::short_value('c', "cpus", "N", "Number of VCPUs. (default: 1)"),
+          #[cfg(target_arch = "x86_64")]
+          Argument::value("max-cpus", "N", "Maximum number of VCPUs, including those that can be hot-added later with `crosvm vcpu add`. (default: the number of VCPUs)"),
           Argument::value("cpu-affinity", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on (e.g. 0,1-3,5)
-                              or colon-separated list of assignments of guest to host CPU assignments (e.g. 0=0:1=1:2=2) (default: no mask)"),
+                              or list of assignments of guest to host CPUs, separated by colons or commas (e.g. 0=2,1=3 or 0=0,1:1=2-3) (default: no mask)"),
+          Argument::value("housekeeping-cpus", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run everything but the VCPUs on, such as the device processes. VCPUs without a `cpu-affinity` keep to the CPUs crosvm was started with. (e.g. 0,1-3,5) (default: none)"),
           Argument::flag("no-smt", "Don't use SMT in the guest"),
+          #[cfg(target_arch = "x86_64")]
+          Argument::value("cpu-model", "NAME", "CPU model whose features the guest sees instead of all of the host's, so it can move between hosts. One of x86-64, x86-64-v2, x86-64-v3 or x86-64-v4."),
+          #[cfg(target_arch = "x86_64")]
+          Argument::flag("nested-virt", "Let the guest run its own VMs with VMX or SVM. Needs the host's kvm_intel or kvm_amd module loaded with nested=1. Without it, VMX and SVM are hidden from the guest."),
+          #[cfg(target_arch = "x86_64")]
+          Argument::value("cpu-features", "[+|-]FEATURE[,[+|-]FEATURE...]", "Comma-separated CPU features, named as in /proc/cpuinfo, to add to (+) or remove from (-) those the guest sees. `avx512` stands for all AVX-512 features. (e.g. +avx512,-rdrand)"),
           Argument::value("rt-cpus", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on. (e.g. 0,1-3,5) (default: none)"),
           Argument::short_value('m',
                                 "mem",
                                 "N",
                                 "Amount of guest memory in MiB. (default: 256)"),
+          Argument::flag("lock-guest-memory", "Lock all of guest memory in host memory once it is set up, so it is never paged out. Needs an RLIMIT_MEMLOCK of at least the guest memory size, or CAP_IPC_LOCK."),
+          Argument::flag("merge-guest-memory", "Back guest memory with private memory that the host's KSM may merge with identical pages (MADV_MERGEABLE), so hosts running many similar VMs keep those pages only once. KSM must be started through /sys/kernel/mm/ksm/run. Private memory can't be shared with other processes, so this needs --disable-sandbox and can't be combined with --vhost-user, --vhost-user-vsock or --ac97. `crosvm stats memory` reports how much of the process KSM merged."),
+          #[cfg(target_arch = "x86_64")]
+          Argument::flag("snapshot", "Allow the VM to be saved with `crosvm snapshot`. Needs --no-balloon and leaves out the USB controller. crosvm refuses to start if any other device can't be saved, such as virtio devices other than block and rng, VFIO and vhost-user devices."),
+          Argument::value("numa", "mem=N[,cpus=CPUSET][,host-node=N][,dist=D:D...]", "Add a guest NUMA node with N MiB of memory. Can be given more than once; nodes get guest memory in order and their memory must add up to `mem`, which defaults to that sum.
+                              Possible key values:
+                              cpus=CPUSET - Colon-separated list of VCPUs or VCPU ranges in the node (e.g. 0-3:6).
+                              host-node=N - Allocate the node's memory from host NUMA node N only.
+                              dist=D:D... - Distance to each node in order, 10 to itself and more to the others. (default: 20 to other nodes)"),
           Argument::short_value('r',
                                 "root",
                                 "PATH[,key=value[,key=value[,...]]",
@@ -1627,6 +2792,8 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
           Argument::value("rw-pmem-device", "PATH", "Path to a writable disk image."),
           Argument::value("pmem-device", "PATH", "Path to a disk image."),
           Argument::value("pstore", "path=PATH,size=SIZE", "Path to pstore buffer backend file follewed by size."),
+          Argument::value("metadata", "KEY=VALUE", "Adds KEY with VALUE to the meta-data file of a read-only config drive for cloud-init's NoCloud data source, e.g. local-hostname=vm1.  Can be given more than once."),
+          Argument::value("metadata-file", "NAME=PATH", "Places the file at PATH on the config drive as NAME, e.g. user-data=cloud-config.yaml.  Can be given more than once."),
           Argument::value("host_ip",
                           "IP",
                           "IP address to assign to host tap interface."),
@@ -1643,20 +2810,32 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                           capture - Enable audio capture
                           capture_effects - | separated effects to be enabled for recording. The only supported effect value now is EchoCancellation or aec.
                           server - The to the VIOS server (unix socket)."),
+          #[cfg(feature = "audio")]
+          Argument::flag_or_value("sound",
+                          "[backend=BACKEND,capture=true,num_output_streams=N,num_input_streams=N,output_latency_ms=MS,input_latency_ms=MS]",
+                          "Comma separated key=value pairs for setting up a virtio-snd device.
+                          Possible key values:
+                          backend=(cras,alsa,null) - Where to route the audio streams. Defaults to cras. alsa is only available when built with the audio_alsa feature and uses the \"default\" PCM; plugins that need more than /dev/snd may require --disable-sandbox.
+                          capture - Enable audio capture (input streams and an input jack).
+                          num_output_streams - Number of playback streams (default: 1).
+                          num_input_streams - Number of capture streams, if capture is enabled (default: 1).
+                          output_latency_ms - Minimum latency buffered by the host for each playback stream.
+                          input_latency_ms - Minimum latency buffered by the host for each capture stream."),
           Argument::value("serial",
                           "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
                           "Comma separated key=value pairs for setting up serial devices. Can be given more than once.
                           Possible key values:
-                          type=(stdout,syslog,sink,file) - Where to route the serial device
+                          type=(stdout,syslog,sink,file,unix-listen) - Where to route the serial device. unix-listen serves the device on a socket at path that clients may reconnect to; output is buffered while no client is attached.
                           hardware=(serial,virtio-console) - Which type of serial hardware to emulate. Defaults to 8250 UART (serial).
                           num=(1,2,3,4) - Serial Device Number. If not provided, num will default to 1.
-                          path=PATH - The path to the file to write to when type=file
+                          path=PATH - The path to the file to write to when type=file, or of the socket when type=unix-listen
                           input=PATH - The path to the file to read from when not stdin
                           console - Use this serial device as the guest console. Can only be given once. Will default to first serial port if not provided.
                           earlycon - Use this serial device as the early console. Can only be given once.
                           stdin - Direct standard input to this serial device. Can only be given once. Will default to first serial port if not provided.
                           "),
           Argument::value("syslog-tag", "TAG", "When logging to syslog, use the provided tag."),
+          Argument::value("name", "NAME", "Name shown for this VM by `crosvm list`."),
           Argument::value("x-display", "DISPLAY", "X11 display name to use."),
           Argument::flag("display-window-keyboard", "Capture keyboard input from the display window."),
           Argument::flag("display-window-mouse", "Capture keyboard input from the display window."),
@@ -1667,8 +2846,18 @@ fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
                                 "socket",
                                 "PATH",
                                 "Path to put the control socket. If PATH is a directory, a name will be generated."),
+          Argument::value("control-peer", "uid=UID|gid=GID[,access=read-only|full]", "Allow a user, or a group by the effective group of its processes, to use the control socket. Once one is given, other users are turned away, except for crosvm's own user and root. access=read-only (the default) only allows requests that report on the VM, such as `crosvm stats`. The socket's file permissions must still let the peer connect. Can be given more than once."),
+          Argument::value("metrics-socket", "ADDR", "Serve the device counters in the Prometheus text format at http://ADDR/metrics. ADDR is either IP:PORT or the path of a Unix socket to create."),
           Argument::flag("disable-sandbox", "Run all devices in one, non-sandboxed process."),
+          Argument::flag("landlock", "Use Landlock to restrict the block, virtio-fs and 9p device processes to opening the disk images and shared directories they were given."),
+          Argument::flag("disable-io-uring", "Run async devices on the poll executor even if the kernel supports io_uring. See `crosvm executor-status`."),
+          Argument::value("io-uring-sqpoll", "IDLE_MS", "Poll for io_uring submissions from a kernel thread that sleeps after IDLE_MS milliseconds without new requests. Saves a syscall per request for busy async devices like block at the cost of host CPU time. Each device process gets its own thread."),
+          Argument::value("trace", "CATEGORY[,CATEGORY...]", "Write what the devices are doing to the ftrace trace_marker, for `trace-cmd` or Perfetto to record alongside host scheduling. CATEGORY is one of block, net, gpu, executor or all."),
           Argument::value("cid", "CID", "Context ID for virtual sockets."),
+          Argument::value("vhost-user-vsock", "socket=PATH", "Serve virtual sockets from the vhost-user backend listening on the unix socket at PATH instead of the vhost-vsock kernel module. The backend determines the guest's context ID. Can't be combined with --cid."),
+          Argument::value("vhost-user", "TYPE,socket=PATH[,shm-size=BYTES][,shm-id=ID]", "Add a virtio device of TYPE, such as `block` or `net`, served by the vhost-user backend listening on the unix socket at PATH. The queues and config space are the backend's. If the backend goes away, the device waits for a backend to listen at PATH again and sets it up as the old one was. Can be given more than once.
+                              shm-size=BYTES - Give the device a shared memory region of BYTES, a power of two, that the backend maps files into, such as the DAX window of `fs` or the host visible memory of `gpu`.
+                              shm-id=ID - The ID the driver looks the shared memory region up by, such as 1 for the host visible memory of `gpu` (default: 0)."),
           Argument::value("shared-dir", "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE]",
                           "Colon-separated options for configuring a directory to be shared with the VM.
 The first field is the directory to be shared and the second field is the tag that the VM can use to identify the device.
@@ -1680,8 +2869,9 @@ cache=(never, auto, always) - Indicates whether the VM can cache the contents of
 timeout=SECONDS - How long the VM should consider file attributes and directory entries to be valid (default: 5).  If the VM has exclusive access to the directory, then this should be a large value.  If the directory can be modified by other processes, then this should be 0.
 writeback=BOOL - Indicates whether the VM can use writeback caching (default: false).  This is only safe to do when the VM has exclusive access to the files in a directory.  Additionally, the server should have read permission for all files as the VM may issue read requests even for files that are opened write-only.
 "),
-          Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files."),
+          Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files. Devices without a policy file there use the policy built into crosvm, if any."),
           Argument::flag("seccomp-log-failures", "Instead of seccomp filter failures being fatal, they will be logged instead."),
+          Argument::value("jail-id-map", "DEVICE:uidmap=UIDMAP[:gidmap=GIDMAP]", "User namespace mappings for the jails of devices that use the seccomp policy named DEVICE (e.g. block_device), instead of running them as nobody. The maps are in the format \"inner outer count[,inner outer count]\". Mapping ids other than crosvm's own needs CAP_SETUID or CAP_SETGID. Can be given once per device type."),
           #[cfg(feature = "plugin")]
           Argument::value("plugin", "PATH", "Absolute path to plugin process to run under crosvm."),
           #[cfg(feature = "plugin")]
@@ -1698,6 +2888,7 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
           Argument::value("tap-fd",
                           "fd",
                           "File descriptor for configured tap device. A different virtual network card will be added each time this argument is given."),
+          Argument::value("net-helper-socket", "PATH", "Path of a SOCK_SEQPACKET socket to receive configured tap devices from at startup, so crosvm itself needs no CAP_NET_ADMIN. A privileged helper sends one packet per network card, reading `tap` with the tap's descriptor attached, or `vhost-net` with the tap's and an open /dev/vhost-net's descriptors attached, then hangs up."),
           #[cfg(feature = "gpu")]
           Argument::flag_or_value("gpu",
                                   "[width=INT,height=INT]",
@@ -1715,37 +2906,62 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
                                   "),
           #[cfg(feature = "tpm")]
           Argument::flag("software-tpm", "enable a software emulated trusted platform module device"),
+          #[cfg(feature = "tpm")]
+          Argument::value("swtpm", "PATH", "enable a trusted platform module device backed by an swtpm process, given the path of its control socket (swtpm socket --tpm2 --ctrl type=unixio,path=PATH). swtpm keeps the TPM state in its own state directory."),
           Argument::value("evdev", "PATH", "Path to an event device node. The device will be grabbed (unusable from the host) and made available to the guest with the same configuration it shows on the host"),
           Argument::value("single-touch", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read single touch input events (such as those from a touchscreen) and write status updates to, optionally followed by width and height (defaults to 800x1280)."),
           Argument::value("multi-touch", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read multi touch input events (such as those from a touchscreen) and write status updates to, optionally followed by width and height (defaults to 800x1280)."),
           Argument::value("trackpad", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read trackpad input events and write status updates to, optionally followed by screen width and height (defaults to 800x1280)."),
           Argument::value("mouse", "PATH", "Path to a socket from where to read mouse input events and write status updates to."),
           Argument::value("keyboard", "PATH", "Path to a socket from where to read keyboard input events and write status updates to."),
+          Argument::value("hypervisor", "NAME", "Hypervisor that runs the VM. Only kvm is supported. (default: kvm)"),
           #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
           Argument::flag("split-irqchip", "(EXPERIMENTAL) enable split-irqchip support"),
           Argument::value("bios", "PATH", "Path to BIOS/firmware ROM"),
+          Argument::value("bios-kernel", "PATH", "Kernel image for the BIOS to boot. It is passed to the BIOS through fw_cfg along with the initrd and kernel parameters."),
+          Argument::value("fw-cfg", "name=NAME,path=PATH|string=STRING", "Pass a file named NAME to the BIOS through fw_cfg, with the contents of the host file at PATH or the literal STRING (which can't contain commas). Names for custom files should start with \"opt/\". Can be given more than once."),
+          Argument::value("boot-device", "DEVICE_PATH", "Firmware device path for the BIOS to boot from, passed through fw_cfg as the boot order. Can be given more than once, in order of priority."),
+          #[cfg(target_arch = "x86_64")]
+          Argument::flag("read-only-bios", "Map the BIOS read-only (KVM_MEM_READONLY), so the guest can't modify the firmware it measured or booted from. Writes to it exit to crosvm like MMIO and are dropped. Firmware that keeps its variables in its own image needs `pflash` instead."),
+          Argument::value("pflash", "PATH", "Writable image of the flash right below the BIOS, such as the variable store (OVMF_VARS.fd) of UEFI firmware. Changes the BIOS makes to it are written back to the file."),
           Argument::value("vfio", "PATH", "Path to sysfs of pass through or mdev device"),
+          #[cfg(target_arch = "x86_64")]
+          Argument::flag("virtio-iommu", "Add a virtio-iommu and put the --vfio devices behind it, so the guest manages their DMA mappings."),
+          #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+          Argument::value("vfio-platform", "PATH[,dt-node]", "Path to sysfs of a platform device bound to vfio-platform to pass through. With dt-node, the device is described in the guest's device tree with the compatible strings of its host node."),
+          Argument::value("pcie-root-ports", "NUM", "Number of empty PCI Express root ports to add to the root complex."),
           #[cfg(feature = "video-decoder")]
           Argument::flag("video-decoder", "(EXPERIMENTAL) enable virtio-video decoder device"),
           #[cfg(feature = "video-encoder")]
           Argument::flag("video-encoder", "(EXPERIMENTAL) enable virtio-video encoder device"),
           Argument::value("acpi-table", "PATH", "Path to user provided ACPI table"),
           Argument::flag("protected-vm", "(EXPERIMENTAL) prevent host access to guest memory"),
+          Argument::value("swiotlb", "SIZE_MIB", "Size of the bounce buffers a protected VM shares with its devices. (default: 64)"),
           Argument::flag_or_value("battery",
                                   "[type=TYPE]",
                                   "Comma separated key=value pairs for setting up battery device
                                   Possible key values:
                                   type=goldfish - type of battery emulation, defaults to goldfish
                                   "),
-          Argument::value("gdb", "PORT", "(EXPERIMENTAL) gdb on the given port"),
+          Argument::value("gdb", "PORT|PATH", "(EXPERIMENTAL) Start with all vCPUs stopped and wait for gdb to connect on the given TCP port or unix domain socket path."),
+          Argument::flag("no-balloon", "Don't add a virtio-balloon device to the guest. `crosvm balloon` and the balloon options can't be used then."),
           Argument::value("balloon_bias_mib", "N", "Amount to bias balance of memory between host and guest as the balloon inflates, in MiB."),
+          Argument::value("rng", "source=PATH|builtin[,seed=N][,rate=N]", "Where the virtio-rng device gets entropy for the guest. Possible key values:
+                              source=PATH|builtin - A host file or device such as /dev/hwrng, or crosvm's own generator, which is not cryptographically secure and only meant for tests. (default: /dev/urandom)
+                              seed=N - Seed for the builtin generator, for repeatable guest entropy. (default: read from /dev/urandom)
+                              rate=N - Most bytes handed to the guest per second. (default: unlimited)"),
+          Argument::value("profile", "NAME", "Preset for settings not given on the command line. One of:
+                              desktop - 2 vCPUs, 4096 MiB of memory and --balloon-guest-requests.
+                              server - 4 vCPUs, 8192 MiB of memory and one net queue pair per vCPU.
+                              embedded - 1 vCPU and 256 MiB of memory.
+                              test - 1 vCPU, 512 MiB of memory and --seccomp-log-failures."),
+          Argument::flag_or_value("balloon-guest-requests", "[min_mib=N,max_mib=N]", "Let a guest agent resize the balloon by asking for the amount of memory it wants to keep. The guest is kept between min_mib (default: 0) and max_mib (default: all guest memory)."),
+          Argument::value("virtio-mem", "SIZE_MIB", "Add a virtio-mem device with a hotplug region of SIZE_MIB (a multiple of 128) that starts out unplugged. Resize it with `crosvm virtio-mem`."),
           Argument::short_flag('h', "help", "Print help message.")];
 
     let mut cfg = Config::default();
-    let match_res = set_arguments(args, &arguments[..], |name, value| {
-        set_argument(&mut cfg, name, value)
-    })
-    .and_then(|_| validate_arguments(&mut cfg));
+    let match_res = set_arguments_with_cfg_file(args, &arguments[..], &mut cfg)
+        .and_then(|_| validate_arguments(&mut cfg));
 
     match match_res {
         #[cfg(feature = "plugin")]
@@ -1782,15 +2998,55 @@ writeback=BOOL - Indicates whether the VM can use writeback caching (default: fa
     }
 }
 
+// How long to wait for a VM process to answer `VmRequest::Hello`. Processes that predate control
+// protocol versioning never answer it.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Returns the control protocol version of the VM process on `socket`, or `None` if it predates
+// versioning.
+fn control_version(socket: &VmControlRequestSocket) -> Option<u32> {
+    let hello = VmRequest::Hello {
+        version: VM_CONTROL_VERSION,
+    };
+    if socket
+        .as_ref()
+        .set_read_timeout(Some(HELLO_TIMEOUT))
+        .is_err()
+        || socket.send(&hello).is_err()
+    {
+        return None;
+    }
+    let version = match socket.recv() {
+        Ok(VmResponse::Hello { version }) => Some(version),
+        _ => None,
+    };
+    let _ = socket.as_ref().set_read_timeout(None);
+    version
+}
+
 fn handle_request(
     request: &VmRequest,
-    args: std::env::Args,
+    args: impl Iterator<Item = String>,
 ) -> std::result::Result<VmResponse, ()> {
     let mut return_result = Err(());
     for socket_path in args {
         match UnixSeqpacket::connect(&socket_path) {
             Ok(s) => {
                 let socket: VmControlRequestSocket = MsgSocket::new(s);
+                // Requests that every VM process understands don't wait on the handshake, which
+                // only times out with VM processes that predate versioning.
+                let min_version = request.min_version();
+                if min_version > 0 {
+                    let version = control_version(&socket).unwrap_or(0);
+                    if version < min_version {
+                        error!(
+                            "the VM at '{}' speaks control protocol version {}, but this request needs version {}",
+                            socket_path, version, min_version
+                        );
+                        return_result = Err(());
+                        continue;
+                    }
+                }
                 if let Err(e) = socket.send(request) {
                     error!(
                         "failed to send request to socket at '{}': {}",
@@ -1799,7 +3055,12 @@ fn handle_request(
                     return_result = Err(());
                     continue;
                 }
-                match socket.recv() {
+                // If the hello timed out, its answer may still arrive ahead of the response.
+                let mut response = socket.recv();
+                while let Ok(VmResponse::Hello { .. }) = response {
+                    response = socket.recv();
+                }
+                match response {
                     Ok(response) => return_result = Ok(response),
                     Err(e) => {
                         error!(
@@ -1821,6 +3082,50 @@ fn handle_request(
     return_result
 }
 
+// Removes a leading `--json` from `args`, returning whether it was given.
+fn take_json_flag(args: &mut Peekable<std::env::Args>) -> bool {
+    let json = args.peek().map_or(false, |a| a == "--json");
+    if json {
+        args.next();
+    }
+    json
+}
+
+// Prints `response`, as JSON if `json` is set. Responses without data of their own are printed as
+// `{"result": TEXT}`, and errors as `{"error": TEXT}`.
+fn print_response(response: &VmResponse, json: bool) {
+    if !json {
+        println!("{}", response);
+        return;
+    }
+    let value = match response {
+        VmResponse::BalloonStats {
+            stats,
+            balloon_actual,
+        } => json!({ "balloon_actual": balloon_actual, "stats": stats }),
+        VmResponse::BalloonAccounting {
+            balloon_actual,
+            removed_bytes,
+        } => json!({ "balloon_actual": balloon_actual, "removed_bytes": removed_bytes }),
+        VmResponse::MemResized {
+            requested_bytes,
+            plugged_bytes,
+        } => json!({ "requested_bytes": requested_bytes, "plugged_bytes": plugged_bytes }),
+        VmResponse::MemoryStats(stats) => json!(stats),
+        VmResponse::MemoryMap(entries) => json!(entries),
+        VmResponse::ExecutorStatus(executors) => json!(executors),
+        VmResponse::DeviceStats(stats) => json!(stats),
+        VmResponse::VhostUserStatus(connections) => json!(connections),
+        VmResponse::GpuResponse(GpuControlResult::Displays(displays)) => json!(displays),
+        VmResponse::UsbResponse(UsbControlResult::Devices(devices)) => {
+            json!(devices.iter().filter(|d| d.valid()).collect::<Vec<_>>())
+        }
+        VmResponse::Err(e) => json!({ "error": e.to_string() }),
+        r => json!({ "result": r.to_string() }),
+    };
+    println!("{}", value);
+}
+
 fn vms_request(request: &VmRequest, args: std::env::Args) -> std::result::Result<(), ()> {
     let response = handle_request(request, args)?;
     info!("request response was {}", response);
@@ -1840,6 +3145,7 @@ fn suspend_vms(args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() == 0 {
         print_help("crosvm suspend", "VM_SOCKET...", &[]);
         println!("Suspends the crosvm instance listening on each `VM_SOCKET` given.");
+        println!("The VCPUs are paused and devices stop their timers until `crosvm resume`.");
         return Err(());
     }
     vms_request(&VmRequest::Suspend, args)
@@ -1872,16 +3178,240 @@ fn balloon_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::BalloonCommand(command), args)
 }
 
+fn virtio_mem_cmd(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() != 2 {
+        print_help("crosvm virtio-mem", "[--json] SIZE_MIB VM_SOCKET", &[]);
+        println!("Asks the guest to plug `SIZE_MIB` of the virtio-mem hotplug region, unplugging memory if it has more.");
+        return Err(());
+    }
+    let num_bytes = match args.next().unwrap().parse::<u64>() {
+        Ok(n) => n.saturating_mul(1024 * 1024),
+        Err(_) => {
+            error!("Failed to parse size in MiB");
+            return Err(());
+        }
+    };
+
+    let request = VmRequest::MemCommand(MemControlCommand::Resize { num_bytes });
+    let response = handle_request(&request, args)?;
+    print_response(&response, json);
+    Ok(())
+}
+
 fn balloon_stats(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
     if args.len() != 1 {
-        print_help("crosvm balloon_stats", "VM_SOCKET", &[]);
+        print_help("crosvm balloon_stats", "[--json] VM_SOCKET", &[]);
         println!("Prints virtio balloon statistics for a `VM_SOCKET`.");
         return Err(());
     }
-    let command = BalloonControlCommand::Stats {};
-    let request = &VmRequest::BalloonCommand(command);
-    let response = handle_request(request, args)?;
-    println!("{}", response);
+    let command = BalloonControlCommand::Stats {};
+    let request = &VmRequest::BalloonCommand(command);
+    let response = handle_request(request, args)?;
+    print_response(&response, json);
+    Ok(())
+}
+
+fn stats_cmd(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() < 2 {
+        print_help("crosvm stats", "[--json] SUBCOMMAND VM_SOCKET", &[]);
+        println!("Prints statistics of a running VM.");
+        println!("Subcommands:");
+        println!("  memory VM_SOCKET - Report guest memory, balloon, shared memory and host RSS.");
+        println!("  devices VM_SOCKET - Report requests, bytes, interrupts and queue depths of each virtio device.");
+        println!("  vhost-user VM_SOCKET - Report whether each vhost-user device is connected to its backend.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+
+    let request = match subcommand {
+        "memory" => VmRequest::MemoryStats,
+        "devices" => VmRequest::DeviceStats,
+        "vhost-user" => VmRequest::VhostUserStatus,
+        _ => {
+            error!("Unknown stats subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    let response = handle_request(&request, args)?;
+    print_response(&response, json);
+    Ok(())
+}
+
+fn dump_memmap(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() != 1 {
+        print_help("crosvm dump-memmap", "[--json] VM_SOCKET", &[]);
+        println!("Prints the IO and MMIO ranges claimed by each device of a running VM.");
+        return Err(());
+    }
+    let response = handle_request(&VmRequest::DumpMemoryMap, args)?;
+    print_response(&response, json);
+    Ok(())
+}
+
+// Parses a guest address or length, in hex if it starts with "0x".
+fn parse_guest_u64(value: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+// Parses the `ADDR,LEN` argument of `--range`.
+fn parse_dump_range(value: &str) -> std::result::Result<(u64, u64), String> {
+    let mut parts = value.split(',');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(addr), Some(len), None) => {
+            let addr = parse_guest_u64(addr).map_err(|e| format!("invalid address: {}", e))?;
+            let len = parse_guest_u64(len).map_err(|e| format!("invalid length: {}", e))?;
+            Ok((addr, len))
+        }
+        _ => Err(String::from("expected ADDR,LEN")),
+    }
+}
+
+fn dump_mem(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm dump-mem", "FILE [--range ADDR,LEN] VM_SOCKET", &[]);
+        println!("Writes the guest memory of a running VM to FILE as a raw image, with its VCPUs paused. The byte at guest address ADDR + N goes to offset N of the file.");
+        println!("  --range ADDR,LEN - Dump only LEN bytes of guest memory starting at ADDR, instead of all of it. Both may be given in hex with a 0x prefix.");
+        return Err(());
+    }
+    let path = args.next().unwrap();
+    let mut args = args.peekable();
+    let mut range = None;
+    if args.peek().map(String::as_str) == Some("--range") {
+        args.next();
+        let value = match args.next() {
+            Some(v) => v,
+            None => {
+                error!("--range needs a value");
+                return Err(());
+            }
+        };
+        range = Some(parse_dump_range(&value).map_err(|e| error!("invalid --range: {}", e))?);
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .map_err(|e| error!("failed to create '{}': {}", path, e))?;
+    let request = VmRequest::DumpMemory { file, range };
+    match handle_request(&request, args)? {
+        VmResponse::Ok => Ok(()),
+        r => {
+            error!("failed to dump guest memory: {}", r);
+            Err(())
+        }
+    }
+}
+
+fn snapshot_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm snapshot", "SUBCOMMAND FILE VM_SOCKET", &[]);
+        println!("Saves a running VM to a file or loads it back, with its VCPUs paused. Only x86_64 VMs started with --snapshot can be saved.");
+        println!("Subcommands:");
+        println!("  take FILE VM_SOCKET - Write the state of the VCPUs, irqchip, devices and guest memory to FILE.");
+        println!("  restore FILE VM_SOCKET - Load FILE into a VM started with the same options as the one it was taken from.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let path = args.next().unwrap();
+
+    let command = match subcommand {
+        "take" => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .map_err(|e| error!("failed to create '{}': {}", path, e))?;
+            SnapshotCommand::Take { file }
+        }
+        "restore" => {
+            let file = File::open(&path).map_err(|e| error!("failed to open '{}': {}", path, e))?;
+            SnapshotCommand::Restore { file }
+        }
+        c => {
+            error!("invalid subcommand for snapshot: {}", c);
+            return Err(());
+        }
+    };
+    let request = VmRequest::Snapshot(command);
+    match handle_request(&request, args)? {
+        VmResponse::Ok => Ok(()),
+        r => {
+            error!("failed to {} snapshot: {}", subcommand, r);
+            Err(())
+        }
+    }
+}
+
+fn executor_status(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() != 1 {
+        print_help("crosvm executor-status", "[--json] VM_SOCKET", &[]);
+        println!("Prints which async executor backend each device uses and why.");
+        return Err(());
+    }
+    let response = handle_request(&VmRequest::ExecutorStatus, args)?;
+    print_response(&response, json);
+    Ok(())
+}
+
+fn list_vms(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() != 0 {
+        print_help("crosvm list", "[--json]", &[]);
+        println!(
+            "Lists the running crosvm instances of the current user that have a control socket."
+        );
+        return Err(());
+    }
+    let entries = registry::list().map_err(|e| {
+        error!(
+            "failed to read the registry at {}: {}",
+            registry::registry_dir().display(),
+            e
+        )
+    })?;
+    if json {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                json!({
+                    "pid": e.pid,
+                    "socket": e.socket_path.display().to_string(),
+                    "name": e.name,
+                    "memory_mib": e.memory_mib,
+                })
+            })
+            .collect();
+        println!("{}", json!(entries));
+        return Ok(());
+    }
+    println!("{:<8} {:>10} {:<16} socket", "pid", "memory", "name");
+    for entry in entries {
+        println!(
+            "{:<8} {:>6} MiB {:<16} {}",
+            entry.pid,
+            entry.memory_mib,
+            entry.name.as_deref().unwrap_or("-"),
+            entry.socket_path.display()
+        );
+    }
     Ok(())
 }
 
@@ -1964,6 +3494,274 @@ with a '--backing_file'."
     Ok(())
 }
 
+fn block_device(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::value(
+            "socket",
+            "PATH",
+            "Listen for vhost-user frontends on the unix socket at PATH.",
+        ),
+        Argument::value(
+            "disk",
+            "PATH[,key=value[,key=value[,...]]",
+            "Serve the disk image at PATH read-only. Takes the options of `crosvm run --disk`.",
+        ),
+        Argument::value(
+            "rwdisk",
+            "PATH[,key=value[,key=value[,...]]",
+            "Serve the disk image at PATH read-write. Takes the options of `crosvm run --rwdisk`.",
+        ),
+        Argument::short_flag('h', "help", "Print help message."),
+    ];
+    let mut socket_path = None;
+    let mut disk = None;
+    let res = set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "socket" => socket_path = Some(PathBuf::from(value.unwrap())),
+            "disk" | "rwdisk" => {
+                if disk.is_some() {
+                    return Err(argument::Error::TooManyArguments(
+                        "only one disk can be served".to_owned(),
+                    ));
+                }
+                disk = Some(parse_disk_option(value.unwrap(), name == "disk")?);
+            }
+            "help" => return Err(argument::Error::PrintHelp),
+            _ => unreachable!(),
+        }
+        Ok(())
+    });
+    let (socket_path, disk) = match (res, socket_path, disk) {
+        (Ok(()), Some(socket_path), Some(disk)) => (socket_path, disk),
+        (Err(argument::Error::PrintHelp), _, _) | (Ok(()), _, _) => {
+            print_help(
+                "crosvm device block",
+                "--socket PATH --disk PATH",
+                &arguments,
+            );
+            println!("Serves a disk image as a virtio block device to a vhost-user frontend, such as a VM in another crosvm process, one frontend at a time.");
+            return Err(());
+        }
+        (Err(e), _, _) => {
+            error!("{}", e);
+            return Err(());
+        }
+    };
+
+    platform::run_block_device(&socket_path, &disk).map_err(|e| {
+        error!("block device has exited with error: {}", e);
+    })
+}
+
+fn net_device(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::value(
+            "socket",
+            "PATH",
+            "Listen for vhost-user frontends on the unix socket at PATH.",
+        ),
+        Argument::value(
+            "tap-name",
+            "NAME",
+            "Serve the existing tap interface NAME, which must already be configured.",
+        ),
+        Argument::value(
+            "tap-fd",
+            "FD",
+            "Serve the tap device open at FD, which must already be configured.",
+        ),
+        Argument::value(
+            "host_ip",
+            "IP",
+            "Create a tap interface with this IP address on the host side.",
+        ),
+        Argument::value(
+            "netmask",
+            "NETMASK",
+            "Netmask of the created tap interface's subnet.",
+        ),
+        Argument::value("mac", "MAC", "MAC address of the created tap interface."),
+        Argument::value(
+            "net-vq-pairs",
+            "N",
+            "Number of queue pairs to serve. The tap must support multiqueue if N is above 1.",
+        ),
+        Argument::short_flag('h', "help", "Print help message."),
+    ];
+    let mut socket_path = None;
+    let mut tap = None;
+    let mut host_ip = None;
+    let mut netmask = None;
+    let mut mac_address = None;
+    let mut vq_pairs = 1;
+    let res = set_arguments(args, &arguments[..], |name, value| {
+        let value = value.unwrap_or("");
+        match name {
+            "socket" => socket_path = Some(PathBuf::from(value)),
+            "tap-name" | "tap-fd" => {
+                if tap.is_some() {
+                    return Err(argument::Error::TooManyArguments(
+                        "only one tap can be served".to_owned(),
+                    ));
+                }
+                tap = Some(if name == "tap-name" {
+                    NetDeviceTap::Name(value.to_owned())
+                } else {
+                    NetDeviceTap::Fd(value.parse().map_err(|_| argument::Error::InvalidValue {
+                        value: value.to_owned(),
+                        expected: String::from(
+                            "this value for `tap-fd` must be an unsigned integer",
+                        ),
+                    })?)
+                });
+            }
+            "host_ip" | "netmask" => {
+                let addr = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: format!("`{}` needs to be in the form \"x.x.x.x\"", name),
+                })?;
+                if name == "host_ip" {
+                    host_ip = Some(addr);
+                } else {
+                    netmask = Some(addr);
+                }
+            }
+            "mac" => {
+                mac_address = Some(value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`mac` needs to be in the form \"XX:XX:XX:XX:XX:XX\""),
+                })?)
+            }
+            "net-vq-pairs" => {
+                vq_pairs = value.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                    argument::Error::InvalidValue {
+                        value: value.to_owned(),
+                        expected: String::from("`net-vq-pairs` must be a positive integer"),
+                    }
+                })?
+            }
+            "help" => return Err(argument::Error::PrintHelp),
+            _ => unreachable!(),
+        }
+        Ok(())
+    });
+    let res = res.and_then(|()| match (tap, host_ip, netmask, mac_address) {
+        (Some(tap), None, None, None) => Ok(tap),
+        (None, Some(host_ip), Some(netmask), Some(mac_address)) => Ok(NetDeviceTap::New {
+            host_ip,
+            netmask,
+            mac_address,
+        }),
+        (None, None, None, None) => Err(argument::Error::PrintHelp),
+        _ => Err(argument::Error::ExpectedArgument(
+            "either `tap-name` or `tap-fd`, or all of `host_ip`, `netmask` and `mac`".to_owned(),
+        )),
+    });
+    let (socket_path, tap) = match (res, socket_path) {
+        (Ok(tap), Some(socket_path)) => (socket_path, tap),
+        (Err(argument::Error::PrintHelp), _) | (Ok(_), None) => {
+            print_help(
+                "crosvm device net",
+                "--socket PATH (--tap-name NAME | --tap-fd FD | --host_ip IP --netmask NETMASK --mac MAC)",
+                &arguments,
+            );
+            println!("Serves a tap device as a virtio net device to a vhost-user frontend, such as a VM in another crosvm process, one frontend at a time.");
+            return Err(());
+        }
+        (Err(e), _) => {
+            error!("{}", e);
+            return Err(());
+        }
+    };
+
+    platform::run_net_device(&socket_path, &tap, vq_pairs).map_err(|e| {
+        error!("net device has exited with error: {}", e);
+    })
+}
+
+#[cfg(feature = "gpu")]
+fn gpu_device(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::value(
+            "socket",
+            "PATH",
+            "Listen for vhost-user frontends on the unix socket at PATH.",
+        ),
+        Argument::flag_or_value(
+            "gpu",
+            "[key=value,...]",
+            "Set up the device with the options of `crosvm run --gpu`.",
+        ),
+        Argument::value(
+            "wayland-sock",
+            "PATH",
+            "Display on the Wayland compositor listening at PATH.",
+        ),
+        Argument::value("x-display", "DISPLAY", "Display on this X11 display."),
+        Argument::short_flag('h', "help", "Print help message."),
+    ];
+    let mut socket_path = None;
+    let mut gpu_parameters = GpuParameters::default();
+    let mut wayland_socket_path = None;
+    let mut x_display = None;
+    let res = set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "socket" => socket_path = Some(PathBuf::from(value.unwrap())),
+            "gpu" => gpu_parameters = parse_gpu_options(value)?,
+            "wayland-sock" => wayland_socket_path = Some(PathBuf::from(value.unwrap())),
+            "x-display" => x_display = Some(value.unwrap().to_owned()),
+            "help" => return Err(argument::Error::PrintHelp),
+            _ => unreachable!(),
+        }
+        Ok(())
+    });
+    let socket_path = match (res, socket_path) {
+        (Ok(()), Some(socket_path)) => socket_path,
+        (Err(argument::Error::PrintHelp), _) | (Ok(()), None) => {
+            print_help(
+                "crosvm device gpu",
+                "--socket PATH [--gpu OPTIONS]",
+                &arguments,
+            );
+            println!("Serves a virtio gpu device to a vhost-user frontend, such as a VM in another crosvm process, one frontend at a time. Host visible blobs are mapped into the shared memory region the frontend gives with `--vhost-user gpu,socket=PATH,shm-size=BYTES,shm-id=1`.");
+            return Err(());
+        }
+        (Err(e), _) => {
+            error!("{}", e);
+            return Err(());
+        }
+    };
+
+    platform::run_gpu_device(
+        &socket_path,
+        &gpu_parameters,
+        wayland_socket_path.as_deref(),
+        x_display,
+    )
+    .map_err(|e| {
+        error!("gpu device has exited with error: {}", e);
+    })
+}
+
+fn device_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    match args.next().as_deref() {
+        Some("block") => block_device(args),
+        Some("net") => net_device(args),
+        #[cfg(feature = "gpu")]
+        Some("gpu") => gpu_device(args),
+        _ => {
+            print_help("crosvm device", "SUBCOMMAND [ARGS]", &[]);
+            println!("Runs a device in this process, as a vhost-user backend for a VM in another crosvm process. The process can be jailed and updated separately from the VM.");
+            println!("Subcommands:");
+            println!("  block - Serve a disk image as a virtio block device.");
+            println!("  net - Serve a tap device as a virtio net device.");
+            #[cfg(feature = "gpu")]
+            println!("  gpu - Serve a virtio gpu device.");
+            Err(())
+        }
+    }
+}
+
 fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() < 2 {
         print_help("crosvm disk", "SUBCOMMAND VM_SOCKET...", &[]);
@@ -2006,6 +3804,220 @@ fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
     vms_request(&request, args)
 }
 
+// Hands the host PCI device at `sysfs_path` over to `driver`, or back to whichever host driver
+// claims it if `driver` is `None`.
+fn rebind_pci_device(sysfs_path: &Path, driver: Option<&str>) -> std::io::Result<()> {
+    let bdf = sysfs_path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    let current = sysfs_path.join("driver").read_link().ok();
+    if let (Some(driver), Some(current)) = (driver, &current) {
+        if current.file_name() == Some(std::ffi::OsStr::new(driver)) {
+            return Ok(());
+        }
+    }
+    std::fs::write(sysfs_path.join("driver_override"), driver.unwrap_or("\n"))?;
+    if current.is_some() {
+        std::fs::write(sysfs_path.join("driver/unbind"), &bdf)?;
+    }
+    std::fs::write("/sys/bus/pci/drivers_probe", &bdf)
+}
+
+fn vcpu_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm vcpu", "SUBCOMMAND ID VM_SOCKET", &[]);
+        println!("Hot-add or remove VCPUs of a VM started with `--max-cpus`.");
+        println!("Subcommands:");
+        println!(
+            "  add ID VM_SOCKET - Make VCPU `ID` present and ask the guest to bring it online."
+        );
+        println!("  remove ID VM_SOCKET - Ask the guest to take VCPU `ID` offline and eject it.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let id = match args.next().unwrap().parse::<usize>() {
+        Ok(id) => id,
+        Err(_) => {
+            error!("Failed to parse VCPU id");
+            return Err(());
+        }
+    };
+
+    let command = match subcommand {
+        "add" => VcpuCommand::Add { id },
+        "remove" => VcpuCommand::Remove { id },
+        c => {
+            error!("invalid subcommand for vcpu: {}", c);
+            return Err(());
+        }
+    };
+    let request = VmRequest::VcpuCommand(command);
+    match handle_request(&request, args)? {
+        VmResponse::Ok => Ok(()),
+        r => {
+            error!("failed to {} vcpu {}: {}", subcommand, id, r);
+            Err(())
+        }
+    }
+}
+
+fn vfio_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm vfio", "SUBCOMMAND BDF VM_SOCKET", &[]);
+        println!("Attach or detach host PCI devices of a running VM.");
+        println!("Subcommands:");
+        println!("  add BDF VM_SOCKET - Bind the device at `BDF` (e.g. 0000:01:00.0) to vfio-pci and add it to the VM.");
+        println!("  remove BDF VM_SOCKET - Remove the device from the VM and give it back to its host driver. A device in a PCIe root port is released by the guest when asked to; otherwise the guest must release it first.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let mut bdf = args.next().unwrap();
+    if bdf.matches(':').count() == 1 {
+        bdf.insert_str(0, "0000:");
+    }
+    let sysfs_path = Path::new("/sys/bus/pci/devices").join(&bdf);
+    if !sysfs_path.exists() {
+        error!("no PCI device {} on the host", bdf);
+        return Err(());
+    }
+    let path = sysfs_path.to_string_lossy().into_owned();
+
+    match subcommand {
+        "add" => {
+            if let Err(e) = rebind_pci_device(&sysfs_path, Some("vfio-pci")) {
+                error!("failed to bind {} to vfio-pci: {}", bdf, e);
+                return Err(());
+            }
+            let request = VmRequest::VfioCommand(VfioCommand::Add { path });
+            match handle_request(&request, args)? {
+                VmResponse::Ok => {
+                    println!(
+                        "added {}; if the VM has no PCIe root ports, rescan the PCI bus in the guest to find it",
+                        bdf
+                    );
+                    Ok(())
+                }
+                r => {
+                    error!("failed to add {}: {}", bdf, r);
+                    Err(())
+                }
+            }
+        }
+        "remove" => {
+            // The guest is given a few seconds to release a device in a PCIe root port after its
+            // attention button is pressed.
+            const UNPLUG_TIMEOUT: Duration = Duration::from_secs(15);
+            const UNPLUG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+            let socket_path = args.next().unwrap();
+            let request = VmRequest::VfioCommand(VfioCommand::Remove { path });
+            let start = Instant::now();
+            let mut requested = false;
+            loop {
+                match handle_request(&request, iter::once(socket_path.clone()))? {
+                    VmResponse::Ok => break,
+                    // Once the guest released the device, it is gone by the time we ask again.
+                    VmResponse::Err(e) if requested && e.errno() == libc::ENOENT => break,
+                    VmResponse::Err(e) if e.errno() == libc::EINPROGRESS => {
+                        requested = true;
+                        if start.elapsed() > UNPLUG_TIMEOUT {
+                            error!("the guest did not release {}", bdf);
+                            return Err(());
+                        }
+                        sleep(UNPLUG_POLL_INTERVAL);
+                    }
+                    VmResponse::Err(e) if e.errno() == libc::EBUSY => {
+                        error!("the guest is still using {}; unbind its driver first", bdf);
+                        return Err(());
+                    }
+                    r => {
+                        error!("failed to remove {}: {}", bdf, r);
+                        return Err(());
+                    }
+                }
+            }
+            if let Err(e) = rebind_pci_device(&sysfs_path, None) {
+                error!("failed to give {} back to its host driver: {}", bdf, e);
+                return Err(());
+            }
+            Ok(())
+        }
+        _ => {
+            error!("Unknown vfio subcommand '{}'", subcommand);
+            Err(())
+        }
+    }
+}
+
+// Parses a display size given as WIDTHxHEIGHT.
+fn parse_display_size(s: &str) -> std::result::Result<(u32, u32), String> {
+    let mut dims = s.splitn(2, 'x');
+    let (width, height) = match (dims.next(), dims.next()) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(format!("expected WIDTHxHEIGHT, got '{}'", s)),
+    };
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("invalid display size '{}'", s)),
+    }
+}
+
+fn gpu_cmd(args: std::env::Args) -> std::result::Result<(), ()> {
+    let mut args = args.peekable();
+    let json = take_json_flag(&mut args);
+    if args.len() < 2 {
+        print_help("crosvm gpu", "[--json] SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Manage the virtual GPU device.");
+        println!("Subcommands:");
+        println!("  reload VM_SOCKET - Rebuild the GPU renderer, losing the guest's GPU contexts.");
+        println!("  set-resolution DISPLAY WIDTHxHEIGHT VM_SOCKET - Resize a display and tell the guest.");
+        println!("  list-displays VM_SOCKET - Print the size of each display.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+
+    let command = match subcommand {
+        "reload" => GpuControlCommand::ReloadRenderer,
+        "set-resolution" => {
+            if args.len() < 3 {
+                error!("expected DISPLAY WIDTHxHEIGHT VM_SOCKET");
+                return Err(());
+            }
+            let display = args.next().unwrap();
+            let display = display
+                .parse()
+                .map_err(|_| error!("invalid display '{}'", display))?;
+            let (width, height) =
+                parse_display_size(&args.next().unwrap()).map_err(|e| error!("{}", e))?;
+            GpuControlCommand::SetDisplayResolution {
+                display,
+                width,
+                height,
+            }
+        }
+        "list-displays" => GpuControlCommand::ListDisplays,
+        _ => {
+            error!("Unknown gpu subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    let response = handle_request(&VmRequest::GpuCommand(command), args)?;
+    match response {
+        VmResponse::GpuResponse(GpuControlResult::Ok) => Ok(()),
+        VmResponse::GpuResponse(GpuControlResult::Displays(_)) => {
+            print_response(&response, json);
+            Ok(())
+        }
+        r => {
+            error!("gpu request failed: {}", r);
+            Err(())
+        }
+    }
+}
+
 enum ModifyUsbError {
     ArgMissing(&'static str),
     ArgParse(&'static str, String),
@@ -2068,6 +4080,23 @@ fn parse_bus_id_addr(v: &str) -> ModifyUsbResult<(u8, u8, u16, u16)> {
     }
 }
 
+fn parse_vid_pid(v: &str) -> ModifyUsbResult<(u16, u16)> {
+    let mut ids = v.split(':');
+    match (ids.next(), ids.next(), ids.next()) {
+        (Some(vid), Some(pid), None) => {
+            let vid = u16::from_str_radix(&vid, 16)
+                .map_err(|e| ModifyUsbError::ArgParseInt("vid", vid.to_owned(), e))?;
+            let pid = u16::from_str_radix(&pid, 16)
+                .map_err(|e| ModifyUsbError::ArgParseInt("pid", pid.to_owned(), e))?;
+            Ok((vid, pid))
+        }
+        _ => Err(ModifyUsbError::ArgParse(
+            "VENDOR_ID:PRODUCT_ID",
+            v.to_owned(),
+        )),
+    }
+}
+
 fn raw_descriptor_from_path(path: &Path) -> ModifyUsbResult<RawDescriptor> {
     if !path.exists() {
         return Err(ModifyUsbError::PathDoesNotExist(path.to_owned()));
@@ -2089,10 +4118,34 @@ fn raw_descriptor_from_path(path: &Path) -> ModifyUsbResult<RawDescriptor> {
     validate_raw_descriptor(raw_descriptor).map_err(ModifyUsbError::FailedDescriptorValidate)
 }
 
+// Attaches the host device matching a `VENDOR_ID:PRODUCT_ID` selector, which crosvm looks up and
+// opens itself.
+fn usb_attach_by_id(selector: &str, args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
+    let (vid, pid) = parse_vid_pid(selector)?;
+    let mut args = args.peekable();
+    let persistent = args.peek().map_or(false, |a| a == "--persistent");
+    if persistent {
+        args.next();
+    }
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachDeviceById {
+        vid,
+        pid,
+        persistent,
+    });
+    let response = handle_request(&request, args).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 fn usb_attach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
     let val = args
         .next()
         .ok_or(ModifyUsbError::ArgMissing("BUS_ID_ADDR_BUS_NUM_DEV_NUM"))?;
+    if val.split(':').count() == 2 {
+        return usb_attach_by_id(&val, args);
+    }
     let (bus, addr, vid, pid) = parse_bus_id_addr(&val)?;
     let dev_path = PathBuf::from(
         args.next()
@@ -2148,7 +4201,7 @@ fn usb_detach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
     }
 }
 
-fn usb_list(args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
+fn usb_list(args: impl Iterator<Item = String>) -> ModifyUsbResult<UsbControlResult> {
     let mut ports: [u8; USB_CONTROL_MAX_PORTS] = Default::default();
     for (index, port) in ports.iter_mut().enumerate() {
         *port = index as u8
@@ -2164,21 +4217,31 @@ fn usb_list(args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
 fn modify_usb(mut args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() < 2 {
         print_help("crosvm usb",
-                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | detach PORT | list] VM_SOCKET...", &[]);
+                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | attach VENDOR_ID:PRODUCT_ID [--persistent] | detach PORT | list [--json]] VM_SOCKET...", &[]);
+        println!("A device attached by VENDOR_ID:PRODUCT_ID with --persistent is attached again whenever it is replugged, until it is detached.");
         return Err(());
     }
 
     // This unwrap will not panic because of the above length check.
     let command = args.next().unwrap();
+    let mut json = false;
     let result = match command.as_ref() {
         "attach" => usb_attach(args),
         "detach" => usb_detach(args),
-        "list" => usb_list(args),
+        "list" => {
+            let mut args = args.peekable();
+            json = take_json_flag(&mut args);
+            usb_list(args)
+        }
         other => Err(ModifyUsbError::UnknownCommand(other.to_owned())),
     };
     match result {
         Ok(response) => {
-            println!("{}", response);
+            if json {
+                print_response(&VmResponse::UsbResponse(response), json);
+            } else {
+                println!("{}", response);
+            }
             Ok(())
         }
         Err(e) => {
@@ -2194,9 +4257,22 @@ fn print_usage() {
     println!("    stop - Stops crosvm instances via their control sockets.");
     println!("    run  - Start a new crosvm instance.");
     println!("    create_qcow2  - Create a new qcow2 disk image file.");
+    println!("    device - Run a device as a vhost-user backend for another crosvm.");
     println!("    disk - Manage attached virtual disk devices.");
+    println!("    dump-mem - Write the guest memory of a running VM to a file.");
+    println!("    dump-memmap - Print the IO and MMIO ranges registered by devices.");
+    println!("    executor-status - Print the async executor backend used by each device.");
+    println!("    gpu - Manage the virtual GPU device.");
+    println!("    list - List running crosvm instances and their control sockets.");
+    println!("    resume - Resumes suspended crosvm instances.");
+    println!("    snapshot - Save a running VM to a file or load it back.");
+    println!("    stats - Print statistics of a running VM.");
+    println!("    suspend - Pauses crosvm instances until they are resumed.");
     println!("    usb - Manage attached virtual USB devices.");
+    println!("    vcpu - Hot-add or remove VCPUs of a running VM.");
     println!("    version - Show package version.");
+    println!("    vfio - Attach or detach host PCI devices of a running VM.");
+    println!("    virtio-mem - Resize the plugged memory of a virtio-mem device.");
 }
 
 fn pkg_version() -> std::result::Result<(), ()> {
@@ -2286,10 +4362,21 @@ fn crosvm_main() -> std::result::Result<(), ()> {
         Some("run") => run_vm(args),
         Some("balloon") => balloon_vms(args),
         Some("balloon_stats") => balloon_stats(args),
+        Some("stats") => stats_cmd(args),
         Some("create_qcow2") => create_qcow2(args),
+        Some("device") => device_cmd(args),
         Some("disk") => disk_cmd(args),
+        Some("dump-mem") => dump_mem(args),
+        Some("dump-memmap") => dump_memmap(args),
+        Some("executor-status") => executor_status(args),
+        Some("gpu") => gpu_cmd(args),
+        Some("list") => list_vms(args),
+        Some("snapshot") => snapshot_cmd(args),
         Some("usb") => modify_usb(args),
         Some("version") => pkg_version(),
+        Some("vcpu") => vcpu_cmd(args),
+        Some("vfio") => vfio_cmd(args),
+        Some("virtio-mem") => virtio_mem_cmd(args),
         Some("battery") => modify_battery(args),
         Some(c) => {
             println!("invalid subcommand: {:?}", c);
@@ -2322,7 +4409,14 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crosvm::{DEFAULT_TOUCH_DEVICE_HEIGHT, DEFAULT_TOUCH_DEVICE_WIDTH};
+    use crosvm::{HypervisorKind, DEFAULT_TOUCH_DEVICE_HEIGHT, DEFAULT_TOUCH_DEVICE_WIDTH};
+
+    #[test]
+    fn parse_vid_pid_selector() {
+        assert!(matches!(parse_vid_pid("18d1:4ee7"), Ok((0x18d1, 0x4ee7))));
+        assert!(parse_vid_pid("1:2:18d1:4ee7").is_err());
+        assert!(parse_vid_pid("18d1:xyz").is_err());
+    }
 
     #[test]
     fn parse_cpu_set_single() {
@@ -2414,6 +4508,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_cpu_affinity_per_vcpu_commas() {
+        let mut expected_map = BTreeMap::new();
+        expected_map.insert(0, vec![2]);
+        expected_map.insert(1, vec![3, 4]);
+        expected_map.insert(2, vec![5, 6, 8]);
+        assert_eq!(
+            parse_cpu_affinity("0=2,1=3-4,2=5,6:8").expect("parse failed"),
+            VcpuAffinity::PerVcpu(expected_map),
+        );
+        parse_cpu_affinity("1,0=2").expect_err("parse should fail because 1 isn't assigned");
+        parse_cpu_affinity("0=2,0=3").expect_err("parse should fail because VCPU 0 is repeated");
+        parse_cpu_affinity("0=1=2").expect_err("parse should fail because of the syntax");
+    }
+
     #[cfg(feature = "audio")]
     #[test]
     fn parse_ac97_vaild() {
@@ -2434,9 +4543,48 @@ mod tests {
 
     #[cfg(feature = "audio")]
     #[test]
-    fn parse_ac97_vios_valid() {
-        parse_ac97_options("backend=vios,server=/path/to/server")
-            .expect("parse should have succeded");
+    fn parse_ac97_vios_valid() {
+        parse_ac97_options("backend=vios,server=/path/to/server")
+            .expect("parse should have succeded");
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_default() {
+        let params = parse_sound_options("").expect("parse should have succeded");
+        assert_eq!(params.backend, SoundBackend::Cras);
+        assert!(!params.capture);
+        assert_eq!(params.num_output_streams, 1);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_latency() {
+        let params = parse_sound_options(
+            "backend=cras,capture=true,num_input_streams=2,output_latency_ms=10,input_latency_ms=20",
+        )
+        .expect("parse should have succeded");
+        assert!(params.capture);
+        assert_eq!(params.num_input_streams, 2);
+        assert_eq!(params.output_latency_ms, 10);
+        assert_eq!(params.input_latency_ms, 20);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_null_backend() {
+        let params =
+            parse_sound_options("backend=null,capture=true").expect("parse should have succeded");
+        assert_eq!(params.backend, SoundBackend::Null);
+        assert!(params.capture);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn parse_sound_invalid() {
+        parse_sound_options("backend=nope").expect_err("parse should have failed");
+        parse_sound_options("num_output_streams=0").expect_err("parse should have failed");
+        parse_sound_options("output_latency_ms=-1").expect_err("parse should have failed");
     }
 
     #[test]
@@ -2463,6 +4611,14 @@ mod tests {
         assert_eq!(parsed.path, Some(PathBuf::from("foo=bar==.log")));
     }
 
+    #[test]
+    fn parse_serial_unix_listen() {
+        let parsed = parse_serial_options("type=unix-listen,hardware=virtio-console,path=/run/con")
+            .expect("parse should have succeded");
+        assert!(matches!(parsed.type_, SerialType::UnixListen));
+        assert_eq!(parsed.path, Some(PathBuf::from("/run/con")));
+    }
+
     #[test]
     fn parse_serial_invalid_type() {
         parse_serial_options("type=wormhole,num=1").expect_err("parse should have failed");
@@ -2591,6 +4747,574 @@ mod tests {
             .expect_err("parse should fail because count is not a number");
     }
 
+    #[test]
+    fn parse_metadata() {
+        let mut config = Config::default();
+        set_argument(&mut config, "metadata", Some("local-hostname=vm1"))
+            .expect("parse should succeed");
+        set_argument(&mut config, "metadata", Some("motd=a=b")).expect("parse should succeed");
+        assert_eq!(
+            config.metadata,
+            vec![
+                ("local-hostname".to_owned(), "vm1".to_owned()),
+                ("motd".to_owned(), "a=b".to_owned()),
+            ]
+        );
+        set_argument(&mut config, "metadata", Some("motd=c"))
+            .expect_err("parse should fail because the key was already given");
+        set_argument(&mut config, "metadata", Some("hostname"))
+            .expect_err("parse should fail because there is no value");
+        set_argument(&mut config, "metadata", Some("host name=vm1"))
+            .expect_err("parse should fail because the key has a space");
+        set_argument(
+            &mut config,
+            "metadata-file",
+            Some("user-data=/does/not/exist"),
+        )
+        .expect_err("parse should fail because the file doesn't exist");
+    }
+
+    #[test]
+    fn parse_fw_cfg() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Bios(PathBuf::from("bios.bin")));
+        set_argument(&mut config, "fw-cfg", Some("name=opt/a,string=hello"))
+            .expect("parse should succeed");
+        set_argument(&mut config, "fw-cfg", Some("name=opt/b,path=/dev/null"))
+            .expect_err("parse should fail because the path isn't a regular file");
+        set_argument(&mut config, "fw-cfg", Some("name=opt/a,string=again"))
+            .expect_err("parse should fail because the name was already given");
+        set_argument(&mut config, "fw-cfg", Some("name=opt/c"))
+            .expect_err("parse should fail because there are no contents");
+        set_argument(&mut config, "fw-cfg", Some("name=opt/c,string=a,string=b"))
+            .expect_err("parse should fail because the contents were given twice");
+        set_argument(&mut config, "boot-device", Some("/pci@i0cf8/*@4"))
+            .expect("parse should succeed");
+        assert_eq!(
+            config.fw_cfg_files,
+            vec![("opt/a".to_owned(), FwCfgData::String("hello".to_owned()))]
+        );
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because there is no bios");
+    }
+
+    #[test]
+    fn validate_no_balloon() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "no-balloon", None).expect("parse should succeed");
+        set_argument(&mut config, "profile", Some("desktop")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert!(!config.balloon);
+        assert!(config.balloon_guest_requests.is_none());
+
+        set_argument(&mut config, "balloon-guest-requests", None).expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because there is no balloon to resize");
+    }
+
+    #[test]
+    fn validate_merge_guest_memory() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "merge-guest-memory", None).expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because device processes share guest memory");
+
+        set_argument(&mut config, "disable-sandbox", None).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        set_argument(
+            &mut config,
+            "vhost-user-vsock",
+            Some("socket=/tmp/vsock.sock"),
+        )
+        .expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because the backend maps guest memory");
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn validate_snapshot() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "snapshot", None).expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because the balloon can't be saved");
+
+        set_argument(&mut config, "no-balloon", None).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[test]
+    fn parse_dtbo() {
+        let mut config = Config::default();
+        set_argument(&mut config, "dtbo", Some("/does/not/exist"))
+            .expect_err("parse should fail because the file doesn't exist");
+        set_argument(&mut config, "dtbo", Some("/dev/zero"))
+            .expect_err("parse should fail because the path isn't a regular file");
+        set_argument(&mut config, "dtbo", Some("Cargo.toml")).expect("parse should succeed");
+        set_argument(&mut config, "dtbo", Some("README.md")).expect("parse should succeed");
+        assert_eq!(
+            config.dtbo,
+            vec![PathBuf::from("Cargo.toml"), PathBuf::from("README.md")]
+        );
+    }
+
+    #[test]
+    fn dump_range() {
+        assert_eq!(parse_dump_range("0x1000,4096"), Ok((0x1000, 0x1000)));
+        assert_eq!(parse_dump_range("0,0x200000"), Ok((0, 0x200000)));
+        parse_dump_range("0x1000").expect_err("parse should fail because the length is missing");
+        parse_dump_range("0x1000,1,2").expect_err("parse should fail because of the extra value");
+        parse_dump_range("0xg,1").expect_err("parse should fail because the address isn't hex");
+    }
+
+    #[test]
+    fn parse_pflash() {
+        let mut config = Config::default();
+        set_argument(&mut config, "pflash", Some("/does/not/exist"))
+            .expect_err("parse should fail because the file doesn't exist");
+        set_argument(&mut config, "pflash", Some("/dev/zero"))
+            .expect_err("parse should fail because the path isn't a regular file");
+        set_argument(&mut config, "pflash", Some("Cargo.toml")).expect("parse should succeed");
+        set_argument(&mut config, "pflash", Some("Cargo.toml"))
+            .expect_err("parse should fail because pflash was already given");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because there is no bios");
+
+        config
+            .executable_path
+            .replace(Executable::Bios(PathBuf::from("OVMF_CODE.fd")));
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    #[test]
+    fn parse_vfio_platform() {
+        let opt = parse_vfio_platform_options("/sys").expect("parse should succeed");
+        assert_eq!(opt.path, PathBuf::from("/sys"));
+        assert!(!opt.dt_node);
+        let opt = parse_vfio_platform_options("/sys,dt-node").expect("parse should succeed");
+        assert!(opt.dt_node);
+        parse_vfio_platform_options("/sys,bogus")
+            .expect_err("parse should fail because of the unknown parameter");
+        parse_vfio_platform_options("/dev/null")
+            .expect_err("parse should fail because the path isn't a directory");
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn parse_cpu_features_list() {
+        assert_eq!(
+            parse_cpu_features("+avx512,-rdrand").expect("parse should succeed"),
+            vec![("avx512".to_owned(), true), ("rdrand".to_owned(), false)]
+        );
+        parse_cpu_features("avx512").expect_err("parse should fail because of the missing +");
+        parse_cpu_features("+avx2,-").expect_err("parse should fail because of the empty name");
+    }
+
+    #[test]
+    fn parse_hypervisor() {
+        let mut config = Config::default();
+        assert_eq!(config.hypervisor, HypervisorKind::Kvm);
+        set_argument(&mut config, "hypervisor", Some("kvm")).expect("parse should succeed");
+        assert_eq!(config.hypervisor, HypervisorKind::Kvm);
+        set_argument(&mut config, "hypervisor", Some("hvf"))
+            .expect_err("parse should fail because hvf isn't supported");
+    }
+
+    #[test]
+    fn parse_jail_id_map() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "jail-id-map",
+            Some("block_device:uidmap=0 1000 1,1 100000 65535:gidmap=0 1000 1"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(
+            config.jail_id_maps.get("block_device"),
+            Some(&JailIdMaps {
+                uid_map: Some(String::from("0 1000 1,1 100000 65535")),
+                gid_map: Some(String::from("0 1000 1")),
+            })
+        );
+        set_argument(
+            &mut config,
+            "jail-id-map",
+            Some("block_device:uidmap=0 0 1"),
+        )
+        .expect_err("parse should fail because the device already has maps");
+        set_argument(&mut config, "jail-id-map", Some("net_device"))
+            .expect_err("parse should fail because there are no maps");
+        set_argument(&mut config, "jail-id-map", Some("fs_device:uidmap=0 0 1"))
+            .expect_err("parse should fail because shared dirs have their own maps");
+        set_argument(&mut config, "jail-id-map", Some("net_device:uidmap=0 1000"))
+            .expect_err("parse should fail because the count is missing");
+        set_argument(
+            &mut config,
+            "jail-id-map",
+            Some("net_device:uidmap=0 1000 0"),
+        )
+        .expect_err("parse should fail because the count is 0");
+        set_argument(
+            &mut config,
+            "jail-id-map",
+            Some("net_device:gidmap=0 1000 10,5 2000 1"),
+        )
+        .expect_err("parse should fail because the inner ranges overlap");
+        set_argument(
+            &mut config,
+            "jail-id-map",
+            Some("net_device:gidmap=0 1000 10,10 1005 1"),
+        )
+        .expect_err("parse should fail because the outer ranges overlap");
+    }
+
+    #[test]
+    fn parse_control_peer() {
+        let mut config = Config::default();
+        set_argument(&mut config, "control-peer", Some("uid=1000")).expect("parse should succeed");
+        set_argument(&mut config, "control-peer", Some("gid=20,access=full"))
+            .expect("parse should succeed");
+        assert_eq!(
+            config.control_peers,
+            vec![
+                ControlPeer {
+                    id: ControlPeerId::Uid(1000),
+                    access: VmRequestAccess::ReadOnly,
+                },
+                ControlPeer {
+                    id: ControlPeerId::Gid(20),
+                    access: VmRequestAccess::Full,
+                },
+            ]
+        );
+        set_argument(&mut config, "control-peer", Some("access=full"))
+            .expect_err("parse should fail because there is no uid or gid");
+        set_argument(&mut config, "control-peer", Some("uid=1000,gid=20"))
+            .expect_err("parse should fail because there are two ids");
+        set_argument(&mut config, "control-peer", Some("uid=1000,access=write"))
+            .expect_err("parse should fail because the access level is unknown");
+        set_argument(&mut config, "control-peer", Some("uid=alice"))
+            .expect_err("parse should fail because the uid isn't numeric");
+    }
+
+    #[test]
+    fn parse_vhost_user_vsock() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(
+            &mut config,
+            "vhost-user-vsock",
+            Some("socket=/run/vsock.sock"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(
+            config.vhost_user_vsock,
+            Some(PathBuf::from("/run/vsock.sock"))
+        );
+        validate_arguments(&mut config).expect("validation should succeed");
+
+        set_argument(&mut config, "cid", Some("3")).expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because a CID was also given");
+
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "vhost-user-vsock",
+            Some("path=/run/vsock.sock"),
+        )
+        .expect_err("parse should fail because of the unknown key");
+        set_argument(&mut config, "vhost-user-vsock", Some("socket="))
+            .expect_err("parse should fail because the path is empty");
+    }
+
+    #[test]
+    fn parse_vhost_user() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "vhost-user",
+            Some("block,socket=/run/block.sock"),
+        )
+        .expect("parse should succeed");
+        set_argument(&mut config, "vhost-user", Some("net,socket=/run/net.sock"))
+            .expect("parse should succeed");
+        assert_eq!(config.vhost_user.len(), 2);
+        assert_eq!(config.vhost_user[0].device_type, 2);
+        assert_eq!(
+            config.vhost_user[0].socket_path,
+            PathBuf::from("/run/block.sock")
+        );
+        assert_eq!(config.vhost_user[1].device_type, 1);
+        assert_eq!(config.vhost_user[1].shm_size, None);
+
+        set_argument(
+            &mut config,
+            "vhost-user",
+            Some("fs,socket=/run/fs.sock,shm-size=1073741824,shm-id=1"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(config.vhost_user[2].shm_size, Some(1 << 30));
+        assert_eq!(config.vhost_user[2].shm_id, 1);
+        set_argument(
+            &mut config,
+            "vhost-user",
+            Some("fs,socket=/run/fs.sock,shm-size=1000"),
+        )
+        .expect_err("parse should fail because the size isn't a power of two");
+
+        set_argument(
+            &mut config,
+            "vhost-user",
+            Some("floppy,socket=/run/floppy.sock"),
+        )
+        .expect_err("parse should fail because the device type is unknown");
+        set_argument(&mut config, "vhost-user", Some("block"))
+            .expect_err("parse should fail because the socket is missing");
+    }
+
+    #[test]
+    fn parse_balloon_guest_requests() {
+        let mut config = Config::default();
+        set_argument(&mut config, "balloon-guest-requests", None).expect("parse should succeed");
+        assert_eq!(
+            config.balloon_guest_requests,
+            Some(BalloonGuestRequests {
+                min_bytes: 0,
+                max_bytes: None,
+            })
+        );
+
+        set_argument(
+            &mut config,
+            "balloon-guest-requests",
+            Some("min_mib=256,max_mib=1024"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(
+            config.balloon_guest_requests,
+            Some(BalloonGuestRequests {
+                min_bytes: 256 << 20,
+                max_bytes: Some(1024 << 20),
+            })
+        );
+
+        set_argument(
+            &mut config,
+            "balloon-guest-requests",
+            Some("min_mib=1024,max_mib=256"),
+        )
+        .expect_err("parse should fail because min_mib is larger than max_mib");
+        set_argument(&mut config, "balloon-guest-requests", Some("min_mib=lots"))
+            .expect_err("parse should fail because the size is invalid");
+        set_argument(&mut config, "balloon-guest-requests", Some("target=1"))
+            .expect_err("parse should fail because of the unknown key");
+    }
+
+    #[test]
+    fn parse_rng() {
+        assert_eq!(
+            parse_rng_options("source=/dev/hwrng,rate=1024").expect("parse should succeed"),
+            RngParameters {
+                source: RngSource::File(PathBuf::from("/dev/hwrng")),
+                rate: Some(1024),
+            }
+        );
+        assert_eq!(
+            parse_rng_options("seed=7,source=builtin").expect("parse should succeed"),
+            RngParameters {
+                source: RngSource::Builtin { seed: Some(7) },
+                rate: None,
+            }
+        );
+        parse_rng_options("source=/dev/hwrng,seed=7")
+            .expect_err("parse should fail because only the builtin source takes a seed");
+        parse_rng_options("rate=0").expect_err("parse should fail because the rate is zero");
+        parse_rng_options("source=").expect_err("parse should fail because the source is empty");
+        parse_rng_options("bits=8").expect_err("parse should fail because of the unknown key");
+    }
+
+    #[test]
+    fn parse_numa() {
+        let node = parse_numa_options("mem=1024,cpus=0-1:3,host-node=1,dist=10:21")
+            .expect("parse should succeed");
+        assert_eq!(
+            node,
+            NumaNode {
+                memory_size: 1024,
+                cpus: vec![0, 1, 3],
+                host_node: Some(1),
+                distances: vec![10, 21],
+            }
+        );
+        parse_numa_options("cpus=0").expect_err("parse should fail because `mem` is missing");
+        parse_numa_options("mem=0").expect_err("parse should fail because `mem` is zero");
+        parse_numa_options("mem=1,dist=10:x")
+            .expect_err("parse should fail because a distance isn't a number");
+        parse_numa_options("mem=1,node=1")
+            .expect_err("parse should fail because of the unknown key");
+    }
+
+    #[test]
+    fn validate_numa() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "cpus", Some("4")).expect("parse should succeed");
+        set_argument(&mut config, "numa", Some("mem=512,cpus=0:1,dist=10:20"))
+            .expect("parse should succeed");
+        set_argument(&mut config, "numa", Some("mem=1024,cpus=2-3,dist=20:10"))
+            .expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(config.memory, Some(1536));
+
+        config.memory = Some(2048);
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because the nodes don't add up to `mem`");
+
+        config.memory = Some(1536);
+        config.numa_nodes[1].cpus = vec![1, 2];
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because VCPU 1 is in both nodes");
+
+        config.numa_nodes[1].cpus = vec![2, 4];
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because there is no VCPU 4");
+
+        config.numa_nodes[1].cpus = vec![2, 3];
+        config.numa_nodes[1].distances = vec![20, 12];
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because the local distance isn't 10");
+    }
+
+    #[test]
+    fn profile_fills_unset_settings() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "profile", Some("server")).expect("parse should succeed");
+        set_argument(&mut config, "mem", Some("2048")).expect("parse should succeed");
+        set_argument(&mut config, "profile", Some("test"))
+            .expect_err("parse should fail because profile was given twice");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(config.vcpu_count, Some(4));
+        assert_eq!(config.memory, Some(2048));
+        assert_eq!(config.net_vq_pairs, Some(4));
+
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "cpus", Some("3")).expect("parse should succeed");
+        set_argument(&mut config, "profile", Some("desktop")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(config.vcpu_count, Some(3));
+        assert_eq!(config.memory, Some(4096));
+        assert!(config.balloon_guest_requests.is_some());
+
+        set_argument(&mut Config::default(), "profile", Some("laptop"))
+            .expect_err("parse should fail because the profile is unknown");
+    }
+
+    #[test]
+    fn protected_vm_swiotlb() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "protected-vm", None).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(config.params, vec!["swiotlb=32768,force".to_string()]);
+
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "swiotlb", Some("128")).expect("parse should succeed");
+        set_argument(&mut config, "protected-vm", None).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+        assert_eq!(config.params, vec!["swiotlb=65536,force".to_string()]);
+
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "swiotlb", Some("0"))
+            .expect_err("parse should fail because the size is zero");
+        set_argument(&mut config, "swiotlb", Some("64")).expect("parse should succeed");
+        validate_arguments(&mut config)
+            .expect_err("validation should fail because the VM isn't protected");
+    }
+
+    #[test]
+    fn parse_display_size_valid() {
+        assert_eq!(parse_display_size("1920x1080"), Ok((1920, 1080)));
+        parse_display_size("1920").expect_err("parse should fail because the height is missing");
+        parse_display_size("0x1080").expect_err("parse should fail because the width is zero");
+        parse_display_size("1920x-1").expect_err("parse should fail because of the sign");
+    }
+
+    #[test]
+    fn cfg_file_under_command_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let kernel = dir.path().join("vmlinux");
+        std::fs::write(&kernel, "").unwrap();
+        let path = dir.path().join("vm.json");
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{"kernel": {:?}, "mem": 1024, "cpus": 2, "disable-sandbox": true}}"#,
+                kernel
+            ),
+        )
+        .unwrap();
+        let arguments = &[
+            Argument::positional("KERNEL", ""),
+            Argument::value("cfg", "PATH", ""),
+            Argument::value("mem", "N", ""),
+            Argument::short_value('c', "cpus", "N", ""),
+            Argument::flag("disable-sandbox", ""),
+        ];
+        let args = vec!["--mem", "2048", "--cfg", path.to_str().unwrap()];
+
+        let mut config = Config::default();
+        set_arguments_with_cfg_file(args.into_iter(), arguments, &mut config)
+            .expect("parse should succeed");
+        assert_eq!(config.memory, Some(2048));
+        assert_eq!(config.vcpu_count, Some(2));
+        assert!(!config.sandbox);
+        assert!(matches!(
+            config.executable_path,
+            Some(Executable::Kernel(ref p)) if p == &kernel
+        ));
+
+        let args = vec!["--cfg", "/nonexistent.json"];
+        set_arguments_with_cfg_file(args.into_iter(), arguments, &mut Config::default())
+            .expect_err("parse should fail because the file is missing");
+    }
+
     #[test]
     fn single_touch_spec_and_track_pad_spec_default_size() {
         let mut config = Config::default();
@@ -2765,6 +5489,24 @@ mod tests {
         assert!(parse_gpu_options(Some("syncfd=true,backend=3d")).is_err());
     }
 
+    #[test]
+    fn io_uring_sqpoll() {
+        let mut config = Config::default();
+        set_argument(&mut config, "io-uring-sqpoll", Some("20")).unwrap();
+        assert_eq!(config.io_uring_sqpoll, Some(Duration::from_millis(20)));
+        set_argument(&mut config, "io-uring-sqpoll", Some("soon")).unwrap_err();
+    }
+
+    #[test]
+    fn trace_categories() {
+        let mut config = Config::default();
+        set_argument(&mut config, "trace", Some("block,executor")).unwrap();
+        let categories = config.trace_categories.unwrap();
+        assert!(categories.contains(cros_tracing::Category::Block));
+        assert!(!categories.contains(cros_tracing::Category::Gpu));
+        set_argument(&mut config, "trace", Some("disk")).unwrap_err();
+    }
+
     #[test]
     fn parse_battery_vaild() {
         parse_battery_options(Some("type=goldfish")).expect("parse should have succeded");
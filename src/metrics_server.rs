@@ -0,0 +1,272 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serves the device counters over HTTP in the Prometheus text format.
+//!
+//! With `--metrics-socket`, a thread in the main process answers `GET /metrics` with the same
+//! counters `crosvm stats devices` prints, so a Prometheus server can scrape every VM directly.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use base::{error, warn};
+use devices::virtio::MetricsRegistry;
+use vm_control::DeviceStats;
+
+// Scrape requests are a single line plus a few headers, anything longer is cut off.
+const MAX_REQUEST_SIZE: usize = 8192;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the metrics are served.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricsAddr {
+    /// A TCP address such as `127.0.0.1:9100`.
+    Tcp(SocketAddr),
+    /// The path of a Unix stream socket.
+    Unix(PathBuf),
+}
+
+impl FromStr for MetricsAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("an empty metrics address".to_owned());
+        }
+        Ok(match s.parse() {
+            Ok(addr) => MetricsAddr::Tcp(addr),
+            Err(_) => MetricsAddr::Unix(PathBuf::from(s)),
+        })
+    }
+}
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&DeviceStats) -> u64,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "crosvm_device_requests_total",
+        kind: "counter",
+        help: "Requests completed by the device.",
+        value: |s| s.requests,
+    },
+    Metric {
+        name: "crosvm_device_bytes_total",
+        kind: "counter",
+        help: "Bytes moved by the requests the device completed.",
+        value: |s| s.bytes,
+    },
+    Metric {
+        name: "crosvm_device_interrupts_total",
+        kind: "counter",
+        help: "Interrupts the device injected into the guest.",
+        value: |s| s.interrupts,
+    },
+    Metric {
+        name: "crosvm_device_queue_depth",
+        kind: "gauge",
+        help: "Requests waiting in a queue when the device last looked.",
+        value: |s| s.queue_depth,
+    },
+    Metric {
+        name: "crosvm_device_max_queue_depth",
+        kind: "gauge",
+        help: "Most requests ever seen waiting in a queue.",
+        value: |s| s.max_queue_depth,
+    },
+];
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats `stats` in the Prometheus text exposition format. Devices are labeled with their
+/// registration index as well as their label, which several devices may share. `vm_name` is added
+/// as a `vm` label if given.
+pub fn prometheus_text(stats: &[DeviceStats], vm_name: Option<&str>) -> String {
+    let vm_label = match vm_name {
+        Some(name) => format!(",vm=\"{}\"", escape_label(name)),
+        None => String::new(),
+    };
+    let mut text = String::new();
+    for metric in METRICS {
+        // Writing to a String can't fail.
+        let _ = writeln!(text, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", metric.name, metric.kind);
+        for (index, device) in stats.iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "{}{{device=\"{}\",index=\"{}\"{}}} {}",
+                metric.name,
+                escape_label(&device.device),
+                index,
+                vm_label,
+                (metric.value)(device)
+            );
+        }
+    }
+    text
+}
+
+fn respond<S: Read + Write>(stream: &mut S, registry: &MetricsRegistry, vm_name: Option<&str>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+            Err(e) => {
+                warn!("failed to read metrics request: {}", e);
+                return;
+            }
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", prometheus_text(&registry.snapshot(), vm_name))
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("failed to send metrics: {}", e);
+    }
+}
+
+fn serve<S: Read + Write>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    set_timeouts: fn(&S),
+    registry: &MetricsRegistry,
+    vm_name: Option<&str>,
+) {
+    for stream in incoming {
+        match stream {
+            Ok(mut stream) => {
+                set_timeouts(&stream);
+                respond(&mut stream, registry, vm_name);
+            }
+            Err(e) => error!("failed to accept metrics connection: {}", e),
+        }
+    }
+}
+
+/// The listening end of `--metrics-socket`. A Unix socket is removed when this is dropped.
+pub struct MetricsServer {
+    unix_path: Option<PathBuf>,
+}
+
+impl MetricsServer {
+    /// Binds `addr` and answers scrapes on a new thread until the process exits.
+    ///
+    /// This starts a thread, so it must be called after the device processes have been forked.
+    pub fn start(
+        addr: &MetricsAddr,
+        registry: Arc<MetricsRegistry>,
+        vm_name: Option<String>,
+    ) -> io::Result<MetricsServer> {
+        let builder = thread::Builder::new().name("metrics_server".to_owned());
+        match addr {
+            MetricsAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                builder.spawn(move || {
+                    serve(
+                        listener.incoming(),
+                        |s| {
+                            let _ = s.set_read_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = s.set_write_timeout(Some(CLIENT_TIMEOUT));
+                        },
+                        &registry,
+                        vm_name.as_deref(),
+                    )
+                })?;
+                Ok(MetricsServer { unix_path: None })
+            }
+            MetricsAddr::Unix(path) => {
+                let listener = UnixListener::bind(path)?;
+                builder.spawn(move || {
+                    serve(
+                        listener.incoming(),
+                        |s| {
+                            let _ = s.set_read_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = s.set_write_timeout(Some(CLIENT_TIMEOUT));
+                        },
+                        &registry,
+                        vm_name.as_deref(),
+                    )
+                })?;
+                Ok(MetricsServer {
+                    unix_path: Some(path.clone()),
+                })
+            }
+        }
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        if let Some(path) = &self.unix_path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addr() {
+        assert_eq!(
+            "127.0.0.1:9100".parse::<MetricsAddr>(),
+            Ok(MetricsAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 9100))))
+        );
+        assert_eq!(
+            "/run/crosvm/metrics.sock".parse::<MetricsAddr>(),
+            Ok(MetricsAddr::Unix(PathBuf::from("/run/crosvm/metrics.sock")))
+        );
+        assert!("".parse::<MetricsAddr>().is_err());
+    }
+
+    #[test]
+    fn text_format() {
+        let stats = vec![DeviceStats {
+            device: "virtio-pci (block)".to_owned(),
+            requests: 2,
+            bytes: 4608,
+            interrupts: 1,
+            queue_depth: 0,
+            max_queue_depth: 3,
+        }];
+        let text = prometheus_text(&stats, Some("my \"vm\""));
+        assert!(text.contains("# TYPE crosvm_device_requests_total counter\n"));
+        assert!(text.contains(
+            "crosvm_device_bytes_total{device=\"virtio-pci (block)\",index=\"0\",vm=\"my \\\"vm\\\"\"} 4608\n"
+        ));
+        assert!(text.contains("# TYPE crosvm_device_max_queue_depth gauge\n"));
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Receives network devices from a privileged helper, so crosvm can run without CAP_NET_ADMIN.
+//!
+//! With `--net-helper-socket`, crosvm connects to the helper's `SOCK_SEQPACKET` socket at startup
+//! and receives one packet per network device until the helper hangs up. A packet reading `tap`
+//! carries a tap device the helper already configured and brought up. A packet reading
+//! `vhost-net` carries such a tap followed by an open `/dev/vhost-net`.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::Path;
+
+use base::net::UnixSeqpacket;
+use net_util::Tap;
+
+/// A network device handed over by the helper.
+pub enum HelperNetDevice {
+    Tap(Tap),
+    VhostNet { tap: Tap, vhost_net: File },
+}
+
+fn into_tap(file: File) -> io::Result<Tap> {
+    // Safe because we own the descriptor, which `Tap` takes over.
+    unsafe { Tap::from_raw_descriptor(file.into_raw_fd()) }.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("helper sent something other than a tap: {}", e),
+        )
+    })
+}
+
+/// Receives every network device from the helper listening at `path`.
+pub fn receive_net_devices(path: &Path) -> io::Result<Vec<HelperNetDevice>> {
+    let socket = UnixSeqpacket::connect(path)?;
+    let mut devices = Vec::new();
+    loop {
+        let (data, fds) = socket.recv_as_vec_with_fds()?;
+        // Safe because the descriptors were just received, so nothing else owns them. Owning them
+        // first closes them however the packet turns out.
+        let mut files: Vec<File> = fds
+            .into_iter()
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .collect();
+        let device = match (&data[..], files.len()) {
+            // The helper hung up.
+            (b"", 0) => break,
+            (b"tap", 1) => HelperNetDevice::Tap(into_tap(files.remove(0))?),
+            (b"vhost-net", 2) => {
+                let vhost_net = files.remove(1);
+                HelperNetDevice::VhostNet {
+                    tap: into_tap(files.remove(0))?,
+                    vhost_net,
+                }
+            }
+            (data, count) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unexpected packet {:?} with {} descriptors from the helper",
+                        String::from_utf8_lossy(data),
+                        count
+                    ),
+                ))
+            }
+        };
+        devices.push(device);
+    }
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::IoSlice;
+    use std::thread;
+
+    use base::net::UnixSeqpacketListener;
+    use base::{AsRawDescriptor, Event, ScmSocket};
+    use tempfile::TempDir;
+
+    // Serves `packets` as the helper would from a socket in `dir`, returning the socket's path.
+    fn serve(dir: &TempDir, packets: Vec<(&'static [u8], Vec<Event>)>) -> std::path::PathBuf {
+        let path = dir.path().join("helper.sock");
+        let listener = UnixSeqpacketListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+            for (data, events) in packets {
+                let fds: Vec<_> = events.iter().map(|e| e.as_raw_descriptor()).collect();
+                socket.send_with_fds(&[IoSlice::new(data)], &fds).unwrap();
+            }
+        });
+        path
+    }
+
+    #[test]
+    fn no_devices() {
+        let dir = TempDir::new().unwrap();
+        let path = serve(&dir, Vec::new());
+        assert!(receive_net_devices(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn not_a_tap() {
+        let dir = TempDir::new().unwrap();
+        let path = serve(&dir, vec![(b"tap", vec![Event::new().unwrap()])]);
+        // An eventfd isn't a tap.
+        assert!(receive_net_devices(&path).is_err());
+    }
+
+    #[test]
+    fn unexpected_packet() {
+        let dir = TempDir::new().unwrap();
+        let path = serve(&dir, vec![(b"vhost-net", vec![Event::new().unwrap()])]);
+        // vhost-net needs two descriptors.
+        assert!(receive_net_devices(&path).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A per-user registry of running crosvm instances.
+//!
+//! Each instance with a control socket writes a small file named after its PID to the registry
+//! directory, and removes it again when it exits. `crosvm list` reads the directory so users don't
+//! have to keep track of control socket paths themselves.
+
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use base::{error, geteuid, getpid};
+
+/// A running crosvm instance, as recorded in the registry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmEntry {
+    pub pid: i32,
+    pub socket_path: PathBuf,
+    pub name: Option<String>,
+    pub memory_mib: u64,
+}
+
+impl VmEntry {
+    fn serialize(&self) -> String {
+        let mut s = format!(
+            "pid={}\nsocket={}\nmemory_mib={}\n",
+            self.pid,
+            self.socket_path.display(),
+            self.memory_mib
+        );
+        if let Some(name) = &self.name {
+            s.push_str(&format!("name={}\n", name));
+        }
+        s
+    }
+
+    fn parse(s: &str) -> Option<VmEntry> {
+        let mut pid = None;
+        let mut socket_path = None;
+        let mut name = None;
+        let mut memory_mib = None;
+        for line in s.lines() {
+            let mut kv = line.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("pid"), Some(v)) => pid = v.parse().ok(),
+                (Some("socket"), Some(v)) => socket_path = Some(PathBuf::from(v)),
+                (Some("name"), Some(v)) => name = Some(v.to_owned()),
+                (Some("memory_mib"), Some(v)) => memory_mib = v.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(VmEntry {
+            pid: pid?,
+            socket_path: socket_path?,
+            name,
+            memory_mib: memory_mib?,
+        })
+    }
+}
+
+/// Returns the registry directory of the current user: `$XDG_RUNTIME_DIR/crosvm`, or
+/// `/tmp/crosvm-<euid>` if that isn't set.
+pub fn registry_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("crosvm"),
+        None => PathBuf::from(format!("/tmp/crosvm-{}", geteuid())),
+    }
+}
+
+/// Removes the registry entry of this process when dropped.
+pub struct Registration(PathBuf);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            error!(
+                "failed to remove registry entry {}: {}",
+                self.0.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Records this process in the registry. The entry stays until the returned `Registration` is
+/// dropped.
+pub fn register(
+    socket_path: &Path,
+    name: Option<&str>,
+    memory_mib: u64,
+) -> io::Result<Registration> {
+    register_in(
+        &registry_dir(),
+        &VmEntry {
+            pid: getpid(),
+            socket_path: socket_path.to_owned(),
+            name: name.map(str::to_owned),
+            memory_mib,
+        },
+    )
+}
+
+fn register_in(dir: &Path, entry: &VmEntry) -> io::Result<Registration> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let path = dir.join(entry.pid.to_string());
+    File::create(&path)?.write_all(entry.serialize().as_bytes())?;
+    Ok(Registration(path))
+}
+
+/// Lists the crosvm instances in the registry, ordered by PID. Entries left behind by instances
+/// that are no longer running are removed.
+pub fn list() -> io::Result<Vec<VmEntry>> {
+    list_in(&registry_dir())
+}
+
+fn list_in(dir: &Path) -> io::Result<Vec<VmEntry>> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(r) => r,
+        // Nothing has registered yet.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for dir_entry in read_dir {
+        let path = dir_entry?.path();
+        let entry = match fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| VmEntry::parse(&s))
+        {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if process_exists(entry.pid) {
+            entries.push(entry);
+        } else {
+            // The instance exited without cleaning up, e.g. because it was killed.
+            let _ = fs::remove_file(&path);
+        }
+    }
+    entries.sort_by_key(|e| e.pid);
+    Ok(entries)
+}
+
+fn process_exists(pid: i32) -> bool {
+    // Safe because signal 0 only checks whether `pid` could be signaled.
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_list() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let entry = VmEntry {
+            pid: getpid(),
+            socket_path: PathBuf::from("/run/crosvm/vm.sock"),
+            name: Some("test vm".to_owned()),
+            memory_mib: 512,
+        };
+        let registration = register_in(dir.path(), &entry).unwrap();
+        assert_eq!(list_in(dir.path()).unwrap(), vec![entry]);
+
+        drop(registration);
+        assert_eq!(list_in(dir.path()).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The seccomp policies of the `seccomp` directory, built into crosvm so that devices can be
+//! sandboxed without installing policy files.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use base::SharedMemory;
+
+// Defines `EMBEDDED_POLICIES`, the policies of each architecture the target falls back to, in the
+// order they are searched. Empty without the `embedded-seccomp` feature.
+include!(concat!(env!("OUT_DIR"), "/seccomp_policies.rs"));
+
+const INCLUDE_DIRECTIVE: &str = "@include";
+
+// Returns the built-in policy `name` of the first architecture that has one.
+fn find_policy(name: &str) -> Option<&'static str> {
+    EMBEDDED_POLICIES
+        .iter()
+        .flat_map(|(_, policies)| policies.iter())
+        .find(|(policy_name, _)| *policy_name == name)
+        .map(|(_, policy)| *policy)
+}
+
+/// Returns the built-in seccomp policy for devices using `name`, e.g. "block_device", with the
+/// policies it `@include`s inlined. Returns `None` if there is no such policy, or if a policy it
+/// includes isn't built in.
+pub fn embedded_policy(name: &str) -> Option<String> {
+    let mut text = String::new();
+    for line in find_policy(name)?.lines() {
+        match line.trim_start().strip_prefix(INCLUDE_DIRECTIVE) {
+            // Policies include each other by their installed path, of which only the name is
+            // kept.
+            Some(path) => {
+                let included = Path::new(path.trim()).file_stem()?.to_str()?;
+                text.push_str(&embedded_policy(included)?);
+            }
+            None => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    Some(text)
+}
+
+/// Writes `policy` to an in-memory file, for minijail to parse from its `/proc/self/fd` path.
+pub fn policy_file(name: &str, policy: &str) -> io::Result<File> {
+    let mut file: File =
+        SharedMemory::named(format!("crosvm_{}_policy", name), policy.len() as u64)?.into();
+    file.write_all(policy.as_bytes())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    // Returns the names of the policies in the `seccomp` directory of `arch`.
+    fn policy_names(arch: &str) -> BTreeSet<String> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("seccomp")
+            .join(arch);
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "policy"))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    // A device without a policy for some architecture can't be sandboxed there.
+    #[test]
+    fn policies_for_every_arch() {
+        let x86_64 = policy_names("x86_64");
+        assert_eq!(policy_names("aarch64"), x86_64);
+        assert_eq!(policy_names("arm"), x86_64);
+    }
+
+    #[test]
+    fn includes_inlined() {
+        if EMBEDDED_POLICIES.is_empty() {
+            return;
+        }
+        let policy = embedded_policy("block_device").unwrap();
+        assert!(!policy.contains(INCLUDE_DIRECTIVE));
+        assert!(policy.contains(find_policy("common_device").unwrap()));
+        assert!(policy.contains("fdatasync: 1"));
+    }
+
+    #[test]
+    fn missing_policy() {
+        assert_eq!(embedded_policy("no_such_device"), None);
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Attaching host USB devices by vendor and product ID.
+//!
+//! The xHCI device is sandboxed and can't open `/dev/bus/usb` itself, so the main process looks
+//! the device up in sysfs, opens it, and forwards the descriptor to the USB device provider.
+//! Devices attached as persistent are remembered and attached again whenever they reappear after
+//! being unplugged from the host.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use base::{error, info, FromRawDescriptor, IntoRawDescriptor, SafeDescriptor};
+use msg_socket::{MsgReceiver, MsgSender};
+use vm_control::{
+    MaybeOwnedDescriptor, UsbControlCommand, UsbControlResult, UsbControlSocket,
+    USB_CONTROL_MAX_PORTS,
+};
+
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// The location of a host USB device.
+#[derive(Debug, PartialEq)]
+pub struct HostUsbDevice {
+    pub bus: u8,
+    pub addr: u8,
+}
+
+impl HostUsbDevice {
+    /// Opens the usbdevfs node of this device.
+    pub fn open(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/bus/usb/{:03}/{:03}", self.bus, self.addr))
+    }
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+
+fn find_in(sysfs: &Path, vid: u16, pid: u16) -> io::Result<Option<HostUsbDevice>> {
+    let mut dirs = fs::read_dir(sysfs)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect::<Vec<_>>();
+    // Sort so the same device is picked every time if several match.
+    dirs.sort();
+    for dir in dirs {
+        let id = |name: &str| read_attr(&dir, name).and_then(|v| u16::from_str_radix(&v, 16).ok());
+        if id("idVendor") != Some(vid) || id("idProduct") != Some(pid) {
+            continue;
+        }
+        let num = |name: &str| read_attr(&dir, name).and_then(|v| v.parse::<u8>().ok());
+        if let (Some(bus), Some(addr)) = (num("busnum"), num("devnum")) {
+            return Ok(Some(HostUsbDevice { bus, addr }));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the first host USB device with the given vendor and product ID.
+pub fn find_host_device(vid: u16, pid: u16) -> io::Result<Option<HostUsbDevice>> {
+    find_in(Path::new(SYSFS_USB_DEVICES), vid, pid)
+}
+
+fn send_command(socket: &UsbControlSocket, command: &UsbControlCommand) -> UsbControlResult {
+    if let Err(e) = socket.send(command) {
+        error!("failed to send command to usb control socket: {}", e);
+        return UsbControlResult::FailedToOpenDevice;
+    }
+    match socket.recv() {
+        Ok(result) => result,
+        Err(e) => {
+            error!("failed to recv result from usb control socket: {}", e);
+            UsbControlResult::FailedToOpenDevice
+        }
+    }
+}
+
+fn attach_by_id(socket: &UsbControlSocket, vid: u16, pid: u16) -> UsbControlResult {
+    let device = match find_host_device(vid, pid) {
+        Ok(Some(device)) => device,
+        Ok(None) => return UsbControlResult::NoSuchDevice,
+        Err(e) => {
+            error!("failed to scan {}: {}", SYSFS_USB_DEVICES, e);
+            return UsbControlResult::NoSuchDevice;
+        }
+    };
+    let file = match device.open() {
+        Ok(file) => file,
+        Err(e) => {
+            error!(
+                "failed to open usb device {:03}:{:03}: {}",
+                device.bus, device.addr, e
+            );
+            return UsbControlResult::FailedToOpenDevice;
+        }
+    };
+    send_command(
+        socket,
+        &UsbControlCommand::AttachDevice {
+            bus: device.bus,
+            addr: device.addr,
+            vid,
+            pid,
+            // Safe because we are transferring ownership of the file's descriptor.
+            descriptor: Some(MaybeOwnedDescriptor::Owned(unsafe {
+                SafeDescriptor::from_raw_descriptor(file.into_raw_descriptor())
+            })),
+        },
+    )
+}
+
+struct PersistentDevice {
+    vid: u16,
+    pid: u16,
+    // The port the device is attached to, or `None` while it is unplugged.
+    port: Option<u8>,
+}
+
+/// Tracks the USB devices that were attached by ID, re-attaching the persistent ones.
+#[derive(Default)]
+pub struct UsbHotplug {
+    persistent: Vec<PersistentDevice>,
+}
+
+impl UsbHotplug {
+    /// Attaches the first host device matching `vid:pid`. If `persistent` is set, the device will
+    /// be attached again by `poll` each time it comes back after being unplugged.
+    pub fn attach(
+        &mut self,
+        socket: &UsbControlSocket,
+        vid: u16,
+        pid: u16,
+        persistent: bool,
+    ) -> UsbControlResult {
+        let result = attach_by_id(socket, vid, pid);
+        if let UsbControlResult::Ok { port } = result {
+            if persistent {
+                self.persistent.push(PersistentDevice {
+                    vid,
+                    pid,
+                    port: Some(port),
+                });
+            }
+        }
+        result
+    }
+
+    /// Stops re-attaching the device on `port`, because it was detached on request.
+    pub fn forget_port(&mut self, port: u8) {
+        self.persistent.retain(|d| d.port != Some(port));
+    }
+
+    /// Whether any devices need to be re-attached when they come back.
+    pub fn has_persistent(&self) -> bool {
+        !self.persistent.is_empty()
+    }
+
+    /// Notices persistent devices that were unplugged and attaches those that have reappeared.
+    pub fn poll(&mut self, socket: &UsbControlSocket) {
+        if self.persistent.is_empty() {
+            return;
+        }
+
+        let mut ports: [u8; USB_CONTROL_MAX_PORTS] = Default::default();
+        for (index, port) in ports.iter_mut().enumerate() {
+            *port = index as u8;
+        }
+        let attached = match send_command(socket, &UsbControlCommand::ListDevice { ports }) {
+            UsbControlResult::Devices(devices) => devices,
+            r => {
+                error!("unexpected result listing usb devices: {}", r);
+                return;
+            }
+        };
+
+        for device in &mut self.persistent {
+            if let Some(port) = device.port {
+                let present = attached.iter().any(|a| {
+                    a.valid()
+                        && a.port == port
+                        && a.vendor_id == device.vid
+                        && a.product_id == device.pid
+                });
+                if present {
+                    continue;
+                }
+                info!(
+                    "usb device {:04x}:{:04x} was unplugged from port {}",
+                    device.vid, device.pid, port
+                );
+                device.port = None;
+            }
+            if let UsbControlResult::Ok { port } = attach_by_id(socket, device.vid, device.pid) {
+                info!(
+                    "usb device {:04x}:{:04x} reattached to port {}",
+                    device.vid, device.pid, port
+                );
+                device.port = Some(port);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_device(sysfs: &Path, name: &str, attrs: &[(&str, &str)]) {
+        let dir = sysfs.join(name);
+        fs::create_dir(&dir).unwrap();
+        for (attr, value) in attrs {
+            fs::write(dir.join(attr), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn find_by_id() {
+        let sysfs = tempfile::TempDir::new().unwrap();
+        let device = |vid, pid, bus, dev| {
+            [
+                ("idVendor", vid),
+                ("idProduct", pid),
+                ("busnum", bus),
+                ("devnum", dev),
+            ]
+        };
+        add_device(sysfs.path(), "usb1", &device("1d6b", "0002", "1", "1"));
+        add_device(sysfs.path(), "1-1", &device("18d1", "4ee7", "1", "12"));
+        add_device(sysfs.path(), "1-1:1.0", &[("bInterfaceClass", "ff")]);
+
+        assert_eq!(
+            find_in(sysfs.path(), 0x18d1, 0x4ee7).unwrap(),
+            Some(HostUsbDevice { bus: 1, addr: 12 })
+        );
+        assert_eq!(find_in(sysfs.path(), 0x18d1, 0x4ee0).unwrap(), None);
+    }
+}
@@ -4,7 +4,7 @@
 
 //! Sync primitive types whose methods panic rather than returning error in case of poison.
 //!
-//! The Mutex/Condvar type in this crates wraps the standard library versions and mirrors the same
+//! The Mutex/Condvar/RwLock types in this crate wraps the standard library versions and mirrors the same
 //! methods, except that they panic where the standard library would return an Error. This API
 //! codifies our error handling strategy around poisoned mutexes in crosvm.
 //!
@@ -23,6 +23,8 @@
 
 mod condvar;
 mod mutex;
+mod rwlock;
 
 pub use crate::condvar::Condvar;
 pub use crate::mutex::{Mutex, WouldBlock};
+pub use crate::rwlock::RwLock;
@@ -0,0 +1,85 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reader-writer lock type whose methods panic rather than returning error in
+//! case of poison.
+//!
+//! See the Mutex type of this crate for the reasoning. Developers should feel
+//! free to use sync::RwLock anywhere in crosvm that they would otherwise be
+//! using std::sync::RwLock.
+
+use std::fmt::{self, Debug};
+use std::sync::{RwLock as StdRwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A reader-writer lock, which allows any number of readers or one writer at a
+/// time.
+#[derive(Default)]
+pub struct RwLock<T: ?Sized> {
+    std: StdRwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new reader-writer lock in an unlocked state ready for use.
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            std: StdRwLock::new(value),
+        }
+    }
+
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        match self.std.into_inner() {
+            Ok(value) => value,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    ///
+    /// Any number of readers may hold the lock at once, but not while a writer
+    /// holds it.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        match self.std.read() {
+            Ok(guard) => guard,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current
+    /// thread until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        match self.std.write() {
+            Ok(guard) => guard,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the RwLock mutably, no actual locking needs to
+    /// take place -- the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        match self.std.get_mut() {
+            Ok(value) => value,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    fn from(value: T) -> Self {
+        RwLock {
+            std: StdRwLock::from(value),
+        }
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for RwLock<T> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.std, formatter)
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Wrappers for the Landlock LSM, which restricts the files a process can open.
+
+use std::ffi::CString;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr::null;
+
+use libc::{c_long, c_void, syscall, EINVAL, O_CLOEXEC, O_PATH};
+use syscall_defines::linux::LinuxSyscall::{
+    SYS_landlock_add_rule, SYS_landlock_create_ruleset, SYS_landlock_restrict_self,
+};
+
+use crate::{
+    errno_result, AsRawDescriptor, Error, FromRawDescriptor, RawDescriptor, Result, SafeDescriptor,
+};
+
+pub const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+pub const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+pub const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+pub const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+pub const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+pub const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+pub const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+pub const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+pub const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+pub const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+pub const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+pub const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+pub const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+
+/// The accesses known to the first version of Landlock, all of which a `LandlockRuleset` denies
+/// unless a rule allows them.
+pub const LANDLOCK_ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+/// The accesses that rules for files, rather than directories, may allow.
+pub const LANDLOCK_ACCESS_FS_FILE: u64 =
+    LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Returns the version of the Landlock ABI the kernel supports, or an error if it doesn't support
+/// Landlock or it is disabled.
+pub fn landlock_abi_version() -> Result<u32> {
+    // Safe because the kernel only returns the ABI version for a null attribute with this flag.
+    let ret = unsafe {
+        syscall(
+            SYS_landlock_create_ruleset as c_long,
+            null::<LandlockRulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if ret < 0 {
+        return errno_result();
+    }
+    Ok(ret as u32)
+}
+
+/// A set of rules for the files a process may access, enforced on the process once it calls
+/// `restrict_self`. Files it already has open are unaffected.
+pub struct LandlockRuleset {
+    descriptor: SafeDescriptor,
+}
+
+impl LandlockRuleset {
+    /// Creates a ruleset denying every file system access until rules allow some.
+    pub fn new() -> Result<LandlockRuleset> {
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: LANDLOCK_ACCESS_FS_ALL,
+        };
+        // Safe because the kernel only reads `attr`, whose size we pass, and we check the result.
+        let ret = unsafe {
+            syscall(
+                SYS_landlock_create_ruleset as c_long,
+                &attr as *const LandlockRulesetAttr,
+                size_of::<LandlockRulesetAttr>(),
+                0u32,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(LandlockRuleset {
+            // Safe because the kernel just gave us this descriptor and nothing else owns it.
+            descriptor: unsafe { SafeDescriptor::from_raw_descriptor(ret as RawDescriptor) },
+        })
+    }
+
+    /// Allows the `access` rights, a mask of `LANDLOCK_ACCESS_FS_*`, to `path` and everything
+    /// beneath it. Only the rights of `LANDLOCK_ACCESS_FS_FILE` apply to files.
+    pub fn allow_path(&mut self, path: &Path, access: u64) -> Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::new(EINVAL))?;
+        // Safe because `path` is a valid C string and we check the result.
+        let fd = unsafe { libc::open(path.as_ptr(), O_PATH | O_CLOEXEC) };
+        if fd < 0 {
+            return errno_result();
+        }
+        // Safe because we own the descriptor we just opened.
+        let parent = unsafe { SafeDescriptor::from_raw_descriptor(fd) };
+
+        let attr = LandlockPathBeneathAttr {
+            allowed_access: access,
+            parent_fd: parent.as_raw_descriptor(),
+        };
+        // Safe because the kernel only reads `attr` and we check the result.
+        let ret = unsafe {
+            syscall(
+                SYS_landlock_add_rule as c_long,
+                self.descriptor.as_raw_descriptor(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const LandlockPathBeneathAttr as *const c_void,
+                0u32,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Enforces the ruleset on the calling thread and the threads and processes it creates from
+    /// now on. The thread must have the no_new_privs flag set, as jailed processes do, or
+    /// CAP_SYS_ADMIN.
+    pub fn restrict_self(&self) -> Result<()> {
+        // Safe because the kernel only uses the ruleset descriptor and we check the result.
+        let ret = unsafe {
+            syscall(
+                SYS_landlock_restrict_self as c_long,
+                self.descriptor.as_raw_descriptor(),
+                0u32,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for LandlockRuleset {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.descriptor.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, File};
+    use std::io;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn restrict_to_dir() {
+        if landlock_abi_version().is_err() {
+            // The kernel doesn't support Landlock.
+            return;
+        }
+        let allowed = TempDir::new().unwrap();
+        let denied = TempDir::new().unwrap();
+        fs::write(allowed.path().join("file"), b"ok").unwrap();
+        fs::write(denied.path().join("file"), b"no").unwrap();
+
+        let mut ruleset = LandlockRuleset::new().unwrap();
+        ruleset
+            .allow_path(allowed.path(), LANDLOCK_ACCESS_FS_READ_FILE)
+            .unwrap();
+
+        // Restricting is irreversible, so do it in a child process.
+        // Safe because the child only makes syscalls and exits.
+        match unsafe { libc::fork() } {
+            0 => {
+                // Safe because this only sets a flag of the calling thread.
+                let ok = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == 0
+                    && ruleset.restrict_self().is_ok()
+                    && File::open(allowed.path().join("file")).is_ok()
+                    && File::open(denied.path().join("file"))
+                        .map_err(|e| e.kind() == io::ErrorKind::PermissionDenied)
+                        .err()
+                        == Some(true);
+                // Safe because it never returns.
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            pid => {
+                assert!(pid > 0);
+                let mut status = 0;
+                // Safe because `status` is a valid pointer.
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+}
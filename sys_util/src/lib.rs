@@ -28,6 +28,7 @@ mod external_mapping;
 mod file_flags;
 pub mod file_traits;
 mod fork;
+mod landlock;
 mod mmap;
 pub mod net;
 mod passwd;
@@ -43,6 +44,7 @@ mod sock_ctrl_msg;
 mod struct_util;
 mod terminal;
 mod timerfd;
+mod userfaultfd;
 mod write_zeroes;
 
 pub use crate::alloc::LayoutAllocation;
@@ -55,6 +57,7 @@ pub use crate::external_mapping::*;
 pub use crate::file_flags::*;
 pub use crate::fork::*;
 pub use crate::ioctl::*;
+pub use crate::landlock::*;
 pub use crate::mmap::*;
 pub use crate::passwd::*;
 pub use crate::poll::*;
@@ -68,6 +71,7 @@ pub use crate::sock_ctrl_msg::*;
 pub use crate::struct_util::*;
 pub use crate::terminal::*;
 pub use crate::timerfd::*;
+pub use crate::userfaultfd::*;
 pub use poll_token_derive::*;
 
 pub use crate::external_mapping::Error as ExternalMappingError;
@@ -89,8 +93,8 @@ use std::os::unix::net::UnixDatagram;
 use std::ptr;
 
 use libc::{
-    c_int, c_long, fcntl, gid_t, kill, pid_t, pipe2, syscall, sysconf, uid_t, waitpid, F_GETFL,
-    F_SETFL, O_CLOEXEC, SIGKILL, WNOHANG, _SC_IOV_MAX, _SC_PAGESIZE,
+    c_int, c_long, fcntl, gid_t, kill, pid_t, pipe2, syscall, sysconf, uid_t, waitpid, _SC_IOV_MAX,
+    _SC_PAGESIZE, F_GETFL, F_SETFL, O_CLOEXEC, SIGKILL, WNOHANG,
 };
 
 use syscall_defines::linux::LinuxSyscall::SYS_getpid;
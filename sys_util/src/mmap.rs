@@ -234,6 +234,27 @@ impl MemoryMapping {
         }
     }
 
+    /// Creates an anonymous private mapping of `size` bytes with `prot` protection. Unlike shared
+    /// mappings, KSM can merge its pages, but child processes get copies of them instead of sharing
+    /// them.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes.
+    /// * `prot` - Protection (e.g. readable/writable) of the memory region.
+    pub fn new_private_protection(size: usize, prot: Protection) -> Result<MemoryMapping> {
+        // This is safe because we are creating an anonymous mapping in a place not already used by
+        // any other area in this process.
+        unsafe {
+            MemoryMapping::try_mmap(
+                None,
+                size,
+                prot.into(),
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                None,
+            )
+        }
+    }
+
     /// Maps the first `size` bytes of the given `fd` as read/write.
     ///
     /// # Arguments
@@ -683,6 +704,93 @@ impl MemoryMapping {
         }
     }
 
+    /// Uses madvise to free the pages of the specified range of a private mapping, like
+    /// `remove_range` does for shared mappings. The range reads as zeros afterwards.
+    pub fn discard_range(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        // Safe because the range was checked to be in the mapping, discarding pages of a private
+        // mapping is the same as the guest zeroing them, and we check the return value.
+        let ret = unsafe {
+            libc::madvise(
+                (self.addr as usize + mem_offset) as *mut _,
+                count,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if ret < 0 {
+            Err(Error::InvalidRange(mem_offset, count, self.size()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Locks the pages of the specified range in memory with mlock, which faults them in first,
+    /// so they are never paged out.
+    pub fn mlock(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        // Safe because the range was checked to be in the mapping, and we check the return value.
+        let ret = unsafe { libc::mlock((self.addr as usize + mem_offset) as *const _, count) };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(errno::Error::last()));
+        }
+        Ok(())
+    }
+
+    /// Uses madvise to let KSM merge the pages of the specified range with identical pages.
+    pub fn set_mergeable(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        // Safe because the range was checked to be in the mapping, merging pages doesn't change
+        // their contents, and we check the return value.
+        let ret = unsafe {
+            libc::madvise(
+                (self.addr as usize + mem_offset) as *mut _,
+                count,
+                libc::MADV_MERGEABLE,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(errno::Error::last()));
+        }
+        Ok(())
+    }
+
+    /// Uses mbind to allocate the pages of the specified range from host NUMA node `node` only.
+    /// Pages that are already allocated are moved to the node if they aren't shared with another
+    /// process. For mappings of shared memory, the policy applies to the memory itself, so it holds
+    /// for every process that maps it.
+    pub fn bind_to_numa_node(&self, mem_offset: usize, count: usize, node: u32) -> Result<()> {
+        // From include/uapi/linux/mempolicy.h.
+        const MPOL_BIND: libc::c_int = 2;
+        const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+        self.range_end(mem_offset, count)
+            .map_err(|_| Error::InvalidRange(mem_offset, count, self.size()))?;
+        let node = node as usize;
+        let mut nodemask = vec![0 as libc::c_ulong; node / 64 + 1];
+        nodemask[node / 64] |= 1 << (node % 64);
+        // Safe because the range was checked to be in the mapping, the kernel only reads
+        // `nodemask.len() * 64` bits of the node mask, and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.addr as usize + mem_offset,
+                count,
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                // The kernel ignores the last bit of the mask.
+                nodemask.len() * 64 + 1,
+                MPOL_MF_MOVE,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(errno::Error::last()));
+        }
+        Ok(())
+    }
+
     // Check that offset+count is valid and return the sum.
     fn range_end(&self, offset: usize, count: usize) -> Result<usize> {
         let mem_end = offset.checked_add(count).ok_or(Error::InvalidAddress)?;
@@ -315,6 +315,32 @@ impl UnixSeqpacket {
     pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
         self.set_timeout(timeout, libc::SO_SNDTIMEO)
     }
+
+    /// Gets the process ID, user ID and group ID of the peer, as they were when it connected.
+    pub fn peer_credentials(&self) -> io::Result<libc::ucred> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because we own the fd, `cred` is as large as `len` says, and the return value is
+        // checked.
+        let ret = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(cred)
+        }
+    }
 }
 
 impl Drop for UnixSeqpacket {
@@ -711,4 +737,18 @@ mod tests {
         let recv_data = s2.recv_as_vec().expect("failed to recv data");
         assert_eq!(recv_data, vec![0, 1, 2, 3, 4]);
     }
+
+    #[test]
+    fn unix_seqpacket_peer_credentials() {
+        let (s1, _s2) = UnixSeqpacket::pair().expect("failed to create socket pair");
+        let cred = s1
+            .peer_credentials()
+            .expect("failed to get peer credentials");
+        // Safe because these only return the IDs of this process.
+        unsafe {
+            assert_eq!(cred.pid, libc::getpid());
+            assert_eq!(cred.uid, libc::geteuid());
+            assert_eq!(cred.gid, libc::getegid());
+        }
+    }
 }
@@ -7,7 +7,10 @@
 use std::iter::FromIterator;
 use std::mem;
 
-use libc::{cpu_set_t, sched_setaffinity, CPU_SET, CPU_SETSIZE, CPU_ZERO, EINVAL};
+use libc::{
+    cpu_set_t, sched_getaffinity, sched_setaffinity, CPU_ISSET, CPU_SET, CPU_SETSIZE, CPU_ZERO,
+    EINVAL,
+};
 
 use crate::{errno_result, Error, Result};
 
@@ -63,6 +66,24 @@ pub fn set_cpu_affinity<I: IntoIterator<Item = usize>>(cpus: I) -> Result<()> {
     }
 }
 
+/// Get the set of CPUs the current thread is allowed to run on.
+pub fn get_cpu_affinity() -> Result<Vec<usize>> {
+    // cpu_set_t is a C struct and can be safely initialized with zeroed memory.
+    let mut cpuset: cpu_set_t = unsafe { mem::zeroed() };
+
+    // Safe because we pass 0 for the current thread, and cpuset is a valid pointer and only
+    // used for the duration of this call.
+    let res = unsafe { sched_getaffinity(0, mem::size_of_val(&cpuset), &mut cpuset) };
+    if res != 0 {
+        return errno_result();
+    }
+
+    // Safe because cpuset was filled in by sched_getaffinity and every index is in range.
+    Ok((0..CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { CPU_ISSET(cpu, &cpuset) })
+        .collect())
+}
+
 /// Enable experimental core scheduling for the current thread.
 ///
 /// If succesful, the kernel should not schedule this thread with any other thread within the same
@@ -297,6 +297,22 @@ pub fn echo_stderr(enable: bool) {
     state.stderr = enable;
 }
 
+/// Keeps the global syslogger locked until dropped. See `lock_for_fork`.
+pub struct ForkGuard {
+    _guard: Option<MutexGuard<'static, State>>,
+}
+
+/// Locks the global syslogger, so that a process forked while other threads are running doesn't
+/// start with the lock held by a thread that was logging. Both the parent and the child must drop
+/// the returned guard after forking.
+///
+/// Does nothing if syslog was never initialized.
+pub fn lock_for_fork() -> ForkGuard {
+    ForkGuard {
+        _guard: lock().ok(),
+    }
+}
+
 /// Retrieves the file descriptors owned by the global syslogger.
 ///
 /// Does nothing if syslog was never initialized. If their are any file descriptors, they will be
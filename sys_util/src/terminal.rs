@@ -7,8 +7,8 @@ use std::mem::zeroed;
 use std::os::unix::io::RawFd;
 
 use libc::{
-    isatty, read, tcgetattr, tcsetattr, termios, ECHO, ICANON, ISIG, O_NONBLOCK, STDIN_FILENO,
-    TCSANOW,
+    ioctl, isatty, read, tcgetattr, tcsetattr, termios, winsize, ECHO, ICANON, ISIG, O_NONBLOCK,
+    STDIN_FILENO, TCSANOW, TIOCGWINSZ,
 };
 
 use crate::{add_fd_flags, clear_fd_flags, errno_result, Result};
@@ -86,6 +86,18 @@ pub unsafe trait Terminal {
             clear_fd_flags(self.tty_fd(), O_NONBLOCK)
         }
     }
+
+    /// Gets the size of this terminal's window as `(columns, rows)`.
+    fn window_size(&self) -> Result<(u16, u16)> {
+        // Safe because winsize is plain data that the ioctl totally overwrites.
+        let mut ws: winsize = unsafe { zeroed() };
+        // Safe because the kernel only writes a winsize to `ws` and we check the return value.
+        let ret = unsafe { ioctl(self.tty_fd(), TIOCGWINSZ, &mut ws as *mut winsize) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok((ws.ws_col, ws.ws_row))
+    }
 }
 
 // Safe because we return a genuine terminal fd that never changes and shares our lifetime.
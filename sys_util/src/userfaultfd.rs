@@ -0,0 +1,149 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use libc::{c_long, syscall, O_CLOEXEC, O_NONBLOCK};
+use syscall_defines::linux::LinuxSyscall::SYS_userfaultfd;
+
+use crate::{errno_result, ioctl_with_mut_ref, ioctl_with_ref, Result};
+
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u32 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1 << 0;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API_IOCTL, UFFDIO, 0x3f, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+
+/// A safe wrapper around a Linux userfaultfd (man 2 userfaultfd), which reports the page faults in
+/// the memory ranges registered with it to whoever reads it, instead of the kernel resolving them.
+pub struct UserfaultFd(File);
+
+impl UserfaultFd {
+    /// Creates a new userfaultfd, which doesn't block its readers.
+    pub fn new() -> Result<UserfaultFd> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let ret = unsafe { syscall(SYS_userfaultfd as c_long, O_CLOEXEC | O_NONBLOCK) };
+        if ret < 0 {
+            return errno_result();
+        }
+        // Safe because we uniquely own the file descriptor.
+        let uffd = UserfaultFd(unsafe { File::from_raw_fd(ret as RawFd) });
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // Safe because the kernel only writes to `api`, which is the size it expects, and we check
+        // the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API_IOCTL(), &mut api) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(uffd)
+    }
+
+    /// Reports the faults on missing pages in the `len` bytes of memory mapped at `addr`.
+    pub fn register(&self, addr: usize, len: usize) -> Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: addr as u64,
+                len: len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // Safe because the kernel only writes to `register`, which is the size it expects, and we
+        // check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, UFFDIO_REGISTER(), &mut register) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Stops reporting the faults in the `len` bytes of memory mapped at `addr`.
+    pub fn unregister(&self, addr: usize, len: usize) -> Result<()> {
+        let range = UffdioRange {
+            start: addr as u64,
+            len: len as u64,
+        };
+        // Safe because the kernel only reads `range`, which is the size it expects, and we check
+        // the return value.
+        let ret = unsafe { ioctl_with_ref(self, UFFDIO_UNREGISTER(), &range) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserfaultFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UserfaultFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        UserfaultFd(File::from_raw_fd(fd))
+    }
+}
+
+impl IntoRawFd for UserfaultFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Error, MappedRegion, MemoryMapping};
+
+    #[test]
+    fn register_mapping() {
+        let uffd = match UserfaultFd::new() {
+            Ok(uffd) => uffd,
+            // Unprivileged processes may not be allowed to handle page faults.
+            Err(e) if e == Error::new(libc::EPERM) => return,
+            Err(e) => panic!("failed to create userfaultfd: {}", e),
+        };
+        let mapping = MemoryMapping::new(0x2000).unwrap();
+        uffd.register(mapping.as_ptr() as usize, mapping.size())
+            .unwrap();
+        uffd.unregister(mapping.as_ptr() as usize, mapping.size())
+            .unwrap();
+        // Only whole pages can be registered.
+        assert!(uffd
+            .register(mapping.as_ptr() as usize + 1, 0x1000)
+            .is_err());
+    }
+}
@@ -276,6 +276,9 @@ pub enum LinuxSyscall {
     SYS_io_uring_setup = 425,
     SYS_io_uring_enter = 426,
     SYS_io_uring_register = 427,
+    SYS_landlock_create_ruleset = 444,
+    SYS_landlock_add_rule = 445,
+    SYS_landlock_restrict_self = 446,
     SYS_open = 1024,
     SYS_link = 1025,
     SYS_unlink = 1026,
@@ -407,4 +407,7 @@ pub enum LinuxSyscall {
     SYS_fspick = 433,
     SYS_pidfd_open = 434,
     SYS_clone3 = 435,
+    SYS_landlock_create_ruleset = 444,
+    SYS_landlock_add_rule = 445,
+    SYS_landlock_restrict_self = 446,
 }
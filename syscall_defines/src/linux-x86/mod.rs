@@ -434,4 +434,7 @@ pub enum LinuxSyscall {
     SYS_clone3 = 435,
     SYS_openat2 = 437,
     SYS_pidfd_getfd = 438,
+    SYS_landlock_create_ruleset = 444,
+    SYS_landlock_add_rule = 445,
+    SYS_landlock_restrict_self = 446,
 }
@@ -356,6 +356,9 @@ pub enum LinuxSyscall {
     SYS_clone3 = 435,
     SYS_openat2 = 437,
     SYS_pidfd_getfd = 438,
+    SYS_landlock_create_ruleset = 444,
+    SYS_landlock_add_rule = 445,
+    SYS_landlock_restrict_self = 446,
     compat_SYS_rt_sigaction = 512,
     compat_SYS_rt_sigreturn = 513,
     compat_SYS_ioctl = 514,
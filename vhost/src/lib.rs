@@ -3,11 +3,13 @@
 // found in the LICENSE file.
 
 pub mod net;
+pub mod user;
 mod vsock;
 
 pub use crate::net::Net;
 pub use crate::net::NetT;
-pub use crate::vsock::Vsock;
+pub use crate::user::VhostUser;
+pub use crate::vsock::{Vsock, VsockT};
 
 use std::alloc::Layout;
 use std::fmt::{self, Display};
@@ -36,6 +38,40 @@ pub enum Error {
     AvailAddress(GuestMemoryError),
     /// Invalid log address.
     LogAddress(GuestMemoryError),
+    /// Error connecting to a vhost-user backend.
+    VhostUserConnect(IoError),
+    /// A vhost-user backend made a request the frontend can't parse or doesn't handle.
+    VhostUserBadBackendRequest(u32),
+    /// Error receiving the fds of a vhost-user message.
+    VhostUserRecvFds(base::Error),
+    /// Error sending a message to a vhost-user backend.
+    VhostUserSend(base::Error),
+    /// Error reading a reply from a vhost-user backend.
+    VhostUserRecv(IoError),
+    /// The vhost-user backend sent a reply that doesn't match the request.
+    VhostUserInvalidReply(u32),
+    /// The vhost-user backend reported that a request failed.
+    VhostUserRequestFailed(u32),
+    /// The vhost-user backend can't expose the device configuration space.
+    VhostUserConfigUnsupported,
+    /// A config space access is larger than a vhost-user message can carry.
+    VhostUserConfigTooLarge(usize),
+    /// Guest memory has more regions than a vhost-user memory table can hold.
+    VhostUserTooManyRegions(usize),
+    /// Guest memory is private to this process, so a vhost-user backend can't map it.
+    VhostUserPrivateMemory,
+    /// Error duplicating an event to set up again in a reconnected vhost-user backend.
+    VhostUserCloneEvent(base::Error),
+    /// The vhost-user connection wasn't made by path, so it can't be made again.
+    VhostUserNoReconnect,
+    /// A reconnected vhost-user backend doesn't support what the frontend set up in the old one.
+    VhostUserReconnectMismatch,
+    /// The vhost-user backend can't report faults on guest memory for postcopy live migration.
+    VhostUserPostcopyUnsupported,
+    /// A postcopy request was made of a vhost-user backend out of order.
+    VhostUserPostcopyState(u32),
+    /// A vhost-user backend can't be reconnected to during postcopy live migration.
+    VhostUserPostcopyReconnect,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -51,6 +87,46 @@ impl Display for Error {
             UsedAddress(e) => write!(f, "invalid used address: {}", e),
             AvailAddress(e) => write!(f, "invalid available address: {}", e),
             LogAddress(e) => write!(f, "invalid log address: {}", e),
+            VhostUserConnect(e) => write!(f, "failed to connect to vhost-user backend: {}", e),
+            VhostUserBadBackendRequest(request) => {
+                write!(f, "bad vhost-user backend request {}", request)
+            }
+            VhostUserRecvFds(e) => write!(f, "failed to receive vhost-user message fds: {}", e),
+            VhostUserSend(e) => write!(f, "failed to send vhost-user message: {}", e),
+            VhostUserRecv(e) => write!(f, "failed to receive vhost-user reply: {}", e),
+            VhostUserInvalidReply(request) => {
+                write!(f, "invalid vhost-user reply to request {}", request)
+            }
+            VhostUserRequestFailed(request) => {
+                write!(f, "vhost-user backend failed request {}", request)
+            }
+            VhostUserConfigUnsupported => {
+                write!(f, "vhost-user backend doesn't support config space access")
+            }
+            VhostUserConfigTooLarge(size) => {
+                write!(f, "config space access too large for vhost-user: {}", size)
+            }
+            VhostUserTooManyRegions(n) => {
+                write!(f, "too many guest memory regions for vhost-user: {}", n)
+            }
+            VhostUserPrivateMemory => {
+                write!(f, "vhost-user backends can't map private guest memory")
+            }
+            VhostUserCloneEvent(e) => write!(f, "failed to clone vhost-user vring event: {}", e),
+            VhostUserNoReconnect => write!(f, "vhost-user backend has no socket path to reconnect"),
+            VhostUserReconnectMismatch => write!(
+                f,
+                "reconnected vhost-user backend doesn't support the features of the old one"
+            ),
+            VhostUserPostcopyUnsupported => {
+                write!(f, "vhost-user backend doesn't support postcopy")
+            }
+            VhostUserPostcopyState(request) => {
+                write!(f, "vhost-user postcopy request {} out of order", request)
+            }
+            VhostUserPostcopyReconnect => {
+                write!(f, "can't reconnect to a vhost-user backend during postcopy")
+            }
         }
     }
 }
@@ -59,6 +135,52 @@ fn ioctl_result<T>() -> Result<T> {
     Err(Error::IoctlError(IoError::last_os_error()))
 }
 
+/// Validates the guest addresses of a vring and translates them into the host addresses expected
+/// by the vhost backend.
+fn vring_addr<T: Vhost>(
+    vhost: &T,
+    queue_max_size: u16,
+    queue_size: u16,
+    queue_index: usize,
+    flags: u32,
+    desc_addr: GuestAddress,
+    used_addr: GuestAddress,
+    avail_addr: GuestAddress,
+    log_addr: Option<GuestAddress>,
+) -> Result<virtio_sys::vhost_vring_addr> {
+    // TODO(smbarber): Refactor out virtio from crosvm so we can
+    // validate a Queue struct directly.
+    if !vhost.is_valid(queue_max_size, queue_size, desc_addr, used_addr, avail_addr) {
+        return Err(Error::InvalidQueue);
+    }
+
+    let desc_addr = vhost
+        .mem()
+        .get_host_address(desc_addr)
+        .map_err(Error::DescriptorTableAddress)?;
+    let used_addr = vhost
+        .mem()
+        .get_host_address(used_addr)
+        .map_err(Error::UsedAddress)?;
+    let avail_addr = vhost
+        .mem()
+        .get_host_address(avail_addr)
+        .map_err(Error::AvailAddress)?;
+    let log_addr = match log_addr {
+        None => null(),
+        Some(a) => vhost.mem().get_host_address(a).map_err(Error::LogAddress)?,
+    };
+
+    Ok(virtio_sys::vhost_vring_addr {
+        index: queue_index as u32,
+        flags,
+        desc_user_addr: desc_addr as u64,
+        used_user_addr: used_addr as u64,
+        avail_user_addr: avail_addr as u64,
+        log_guest_addr: log_addr as u64,
+    })
+}
+
 /// An interface for setting up vhost-based virtio devices.  Vhost-based devices are different
 /// from regular virtio devices because the host kernel takes care of handling all the data
 /// transfer.  The device itself only needs to deal with setting up the kernel driver and
@@ -232,37 +354,17 @@ pub trait Vhost: AsRawDescriptor + std::marker::Sized {
         avail_addr: GuestAddress,
         log_addr: Option<GuestAddress>,
     ) -> Result<()> {
-        // TODO(smbarber): Refactor out virtio from crosvm so we can
-        // validate a Queue struct directly.
-        if !self.is_valid(queue_max_size, queue_size, desc_addr, used_addr, avail_addr) {
-            return Err(Error::InvalidQueue);
-        }
-
-        let desc_addr = self
-            .mem()
-            .get_host_address(desc_addr)
-            .map_err(Error::DescriptorTableAddress)?;
-        let used_addr = self
-            .mem()
-            .get_host_address(used_addr)
-            .map_err(Error::UsedAddress)?;
-        let avail_addr = self
-            .mem()
-            .get_host_address(avail_addr)
-            .map_err(Error::AvailAddress)?;
-        let log_addr = match log_addr {
-            None => null(),
-            Some(a) => self.mem().get_host_address(a).map_err(Error::LogAddress)?,
-        };
-
-        let vring_addr = virtio_sys::vhost_vring_addr {
-            index: queue_index as u32,
+        let vring_addr = vring_addr(
+            self,
+            queue_max_size,
+            queue_size,
+            queue_index,
             flags,
-            desc_user_addr: desc_addr as u64,
-            used_user_addr: used_addr as u64,
-            avail_user_addr: avail_addr as u64,
-            log_guest_addr: log_addr as u64,
-        };
+            desc_addr,
+            used_addr,
+            avail_addr,
+            log_addr,
+        )?;
 
         // This ioctl is called on a valid vhost_net fd and has its
         // return value checked.
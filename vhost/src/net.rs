@@ -82,6 +82,22 @@ where
     }
 }
 
+impl<T> Net<T> {
+    /// Takes over `descriptor`, a `/dev/vhost-net` opened elsewhere, such as by a privileged
+    /// helper.
+    ///
+    /// # Arguments
+    /// * `descriptor` - Open /dev/vhost-net file.
+    /// * `mem` - Guest memory mapping.
+    pub fn from_file(descriptor: File, mem: &GuestMemory) -> Net<T> {
+        Net::<T> {
+            descriptor,
+            mem: mem.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 impl<T> Vhost for Net<T> {
     fn mem(&self) -> &GuestMemory {
         &self.mem
@@ -0,0 +1,1320 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Frontend side of the vhost-user protocol, which hands a virtqueue datapath to a backend running
+//! in another process instead of a vhost kernel driver, and the message definitions backends share.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{IoSlice, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base::{AsRawDescriptor, Event, RawDescriptor, ScmSocket};
+use sync::Mutex;
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{vring_addr, Error, Result, Vhost};
+
+pub const VHOST_USER_GET_FEATURES: u32 = 1;
+pub const VHOST_USER_SET_FEATURES: u32 = 2;
+pub const VHOST_USER_SET_OWNER: u32 = 3;
+pub const VHOST_USER_RESET_OWNER: u32 = 4;
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub const VHOST_USER_SET_VRING_NUM: u32 = 8;
+pub const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+pub const VHOST_USER_SET_VRING_BASE: u32 = 10;
+pub const VHOST_USER_GET_VRING_BASE: u32 = 11;
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub const VHOST_USER_SET_VRING_ERR: u32 = 14;
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+pub const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+pub const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+pub const VHOST_USER_SET_BACKEND_REQ_FD: u32 = 21;
+pub const VHOST_USER_GET_CONFIG: u32 = 24;
+pub const VHOST_USER_SET_CONFIG: u32 = 25;
+pub const VHOST_USER_POSTCOPY_ADVISE: u32 = 28;
+pub const VHOST_USER_POSTCOPY_LISTEN: u32 = 29;
+pub const VHOST_USER_POSTCOPY_END: u32 = 30;
+
+// Requests a backend sends to its frontend over the channel set up by
+// VHOST_USER_SET_BACKEND_REQ_FD.
+pub const VHOST_USER_BACKEND_CONFIG_CHANGE_MSG: u32 = 2;
+pub const VHOST_USER_BACKEND_FS_MAP: u32 = 6;
+pub const VHOST_USER_BACKEND_FS_UNMAP: u32 = 7;
+
+pub const VHOST_USER_VERSION: u32 = 0x1;
+pub const VHOST_USER_REPLY_MASK: u32 = 0x4;
+pub const VHOST_USER_NEED_REPLY_MASK: u32 = 0x8;
+pub const VHOST_USER_HEADER_SIZE: usize = 12;
+
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+pub const VHOST_USER_PROTOCOL_F_BACKEND_REQ: u64 = 1 << 5;
+pub const VHOST_USER_PROTOCOL_F_PAGEFAULT: u64 = 1 << 8;
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+pub const VHOST_USER_PROTOCOL_F_BACKEND_SEND_FD: u64 = 1 << 10;
+const SUPPORTED_PROTOCOL_FEATURES: u64 = VHOST_USER_PROTOCOL_F_MQ
+    | VHOST_USER_PROTOCOL_F_REPLY_ACK
+    | VHOST_USER_PROTOCOL_F_PAGEFAULT
+    | VHOST_USER_PROTOCOL_F_CONFIG;
+
+/// Set in the payload of VHOST_USER_SET_VRING_{KICK,CALL,ERR} when no fd accompanies it.
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 1 << 8;
+/// The vring index in the payload of VHOST_USER_SET_VRING_{KICK,CALL,ERR}.
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+
+pub const VHOST_USER_MAX_MEM_REGIONS: usize = 8;
+/// The largest config space access a single VHOST_USER_{GET,SET}_CONFIG can carry.
+pub const VHOST_USER_CONFIG_SIZE: usize = 256;
+
+/// The number of mappings a VHOST_USER_BACKEND_FS_{MAP,UNMAP} request carries, the unused ones
+/// having a length of 0.
+pub const VHOST_USER_FS_BACKEND_ENTRIES: usize = 8;
+pub const VHOST_USER_FS_FLAG_MAP_R: u64 = 1 << 0;
+pub const VHOST_USER_FS_FLAG_MAP_W: u64 = 1 << 1;
+const FS_BACKEND_MSG_SIZE: usize = VHOST_USER_FS_BACKEND_ENTRIES * 32;
+
+/// Handle to a vhost-user backend listening on a unix socket.
+///
+/// The `Vhost` methods are forwarded to the backend as vhost-user messages, so any device that
+/// drives a vhost kernel driver through `Vhost` can drive a vhost-user backend instead.
+///
+/// Clones share the connection, which each request holds until the backend replies, so a device
+/// can keep using the backend's config space while its worker thread owns another clone.
+///
+/// What the frontend asks of the backend is recorded, even if the backend is away, so that when a
+/// backend that was connected to by path goes away, `reconnect` can set it all up again in the
+/// backend that replaces it.
+#[derive(Clone)]
+pub struct VhostUser {
+    conn: Arc<Mutex<Connection>>,
+    mem: GuestMemory,
+    path: Option<PathBuf>,
+}
+
+// A connection to a backend and what the frontend asked of it.
+struct Connection {
+    socket: UnixStream,
+    // Whether the backend supports VHOST_USER_F_PROTOCOL_FEATURES, which must then be acked along
+    // with the device features.
+    has_protocol_features: bool,
+    protocol_features: u64,
+    // The protocol features the frontend asked for, which it asks a reconnected backend for too.
+    supported_protocol_features: u64,
+    features: Option<u64>,
+    mem_table: bool,
+    vrings: BTreeMap<usize, VringState>,
+    postcopy: Postcopy,
+    // Where the backend mapped each guest memory region, as it replied to the last memory table
+    // sent while it listened for faults.
+    backend_regions: Vec<BackendRegion>,
+}
+
+// How far postcopy live migration got with the backend.
+#[derive(Clone, Copy, PartialEq)]
+enum Postcopy {
+    Off,
+    Advised,
+    Listening,
+}
+
+// What the frontend asked of a vring of the backend.
+#[derive(Default)]
+struct VringState {
+    num: Option<u32>,
+    // The VHOST_USER_SET_VRING_ADDR payload, and where the used ring is in guest memory.
+    addr: Option<(Vec<u8>, GuestAddress)>,
+    base: Option<u32>,
+    call: Option<Event>,
+    kick: Option<Event>,
+    enabled: bool,
+}
+
+impl Connection {
+    // Claims the backend on `socket` and negotiates the protocol features.
+    fn negotiate(socket: UnixStream, supported_protocol_features: u64) -> Result<Connection> {
+        let mut conn = Connection {
+            socket,
+            has_protocol_features: false,
+            protocol_features: 0,
+            supported_protocol_features,
+            features: None,
+            mem_table: false,
+            vrings: BTreeMap::new(),
+            postcopy: Postcopy::Off,
+            backend_regions: Vec::new(),
+        };
+
+        send(&conn.socket, VHOST_USER_SET_OWNER, &[], &[], false)?;
+        let features = conn.get_u64(VHOST_USER_GET_FEATURES)?;
+        if features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            conn.has_protocol_features = true;
+            let protocol_features =
+                conn.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)? & supported_protocol_features;
+            send(
+                &conn.socket,
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                &protocol_features.to_le_bytes(),
+                &[],
+                false,
+            )?;
+            conn.protocol_features = protocol_features;
+        }
+        Ok(conn)
+    }
+
+    // Sets up the channel for backend requests, if the backend supports it.
+    fn backend_requests(&self) -> Result<Option<BackendRequestSocket>> {
+        if self.protocol_features & VHOST_USER_PROTOCOL_F_BACKEND_REQ == 0 {
+            return Ok(None);
+        }
+
+        let (frontend, backend) = UnixStream::pair().map_err(Error::VhostUserConnect)?;
+        self.request(
+            VHOST_USER_SET_BACKEND_REQ_FD,
+            &[],
+            &[backend.as_raw_descriptor()],
+        )?;
+        Ok(Some(BackendRequestSocket { socket: frontend }))
+    }
+
+    // Sets up everything `old` had set up in its backend in this connection's backend.
+    fn replay(&mut self, old: &Connection, mem: &GuestMemory) -> Result<()> {
+        if self.has_protocol_features != old.has_protocol_features
+            || self.protocol_features != old.protocol_features
+        {
+            return Err(Error::VhostUserReconnectMismatch);
+        }
+        // Faults on the pages that haven't arrived yet go to the userfaultfd of the old backend.
+        if old.postcopy != Postcopy::Off {
+            return Err(Error::VhostUserPostcopyReconnect);
+        }
+        if let Some(features) = old.features {
+            let backend_features = self.get_u64(VHOST_USER_GET_FEATURES)?;
+            if features & !backend_features != 0 {
+                return Err(Error::VhostUserReconnectMismatch);
+            }
+            self.request(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])?;
+            self.features = Some(features);
+        }
+        if old.mem_table {
+            self.set_mem_table(mem)?;
+        }
+
+        for (&index, vring) in &old.vrings {
+            let mut state = VringState::default();
+            if let Some(num) = vring.num {
+                self.set_vring_state(VHOST_USER_SET_VRING_NUM, index, num)?;
+                state.num = Some(num);
+            }
+            if let Some((payload, used_addr)) = &vring.addr {
+                self.request(VHOST_USER_SET_VRING_ADDR, payload, &[])?;
+                state.addr = Some((payload.clone(), *used_addr));
+            }
+            // The old backend may have taken buffers it never used, so the new one picks up from
+            // the last buffer that was used rather than the base the vring started from.
+            let base = match &vring.addr {
+                Some((_, used_addr)) => Some(used_index(mem, *used_addr)? as u32),
+                None => vring.base,
+            };
+            if let Some(base) = base {
+                self.set_vring_state(VHOST_USER_SET_VRING_BASE, index, base)?;
+                state.base = Some(base);
+            }
+            if let Some(call) = &vring.call {
+                self.set_vring_file(VHOST_USER_SET_VRING_CALL, index, call)?;
+                state.call = Some(call.try_clone().map_err(Error::VhostUserCloneEvent)?);
+            }
+            if let Some(kick) = &vring.kick {
+                self.set_vring_file(VHOST_USER_SET_VRING_KICK, index, kick)?;
+                state.kick = Some(kick.try_clone().map_err(Error::VhostUserCloneEvent)?);
+            }
+            if vring.enabled && self.has_protocol_features {
+                self.set_vring_state(VHOST_USER_SET_VRING_ENABLE, index, 1)?;
+            }
+            state.enabled = vring.enabled;
+            self.vrings.insert(index, state);
+        }
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, mem: &GuestMemory) -> Result<()> {
+        let num_regions = mem.num_regions() as usize;
+        if num_regions > VHOST_USER_MAX_MEM_REGIONS {
+            return Err(Error::VhostUserTooManyRegions(num_regions));
+        }
+
+        let mut payload = Vec::with_capacity(8 + num_regions * 32);
+        payload.extend_from_slice(&(num_regions as u32).to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        let _ = mem.with_regions::<_, ()>(|_, guest_addr, size, host_addr, memfd_offset| {
+            payload.extend_from_slice(&guest_addr.offset().to_le_bytes());
+            payload.extend_from_slice(&(size as u64).to_le_bytes());
+            payload.extend_from_slice(&(host_addr as u64).to_le_bytes());
+            payload.extend_from_slice(&memfd_offset.to_le_bytes());
+            Ok(())
+        });
+
+        // Every region is backed by the same memfd, at the offset given in the region.
+        let shm = mem.shm().ok_or(Error::VhostUserPrivateMemory)?;
+        let fds = vec![shm.as_raw_descriptor(); num_regions];
+        if self.postcopy != Postcopy::Listening {
+            return self.request(VHOST_USER_SET_MEM_TABLE, &payload, &fds);
+        }
+
+        // A backend listening for faults replies with the table of where it mapped each region,
+        // and only registers the regions once the frontend acknowledges the reply.
+        let need_reply = self.reply_ack();
+        send(
+            &self.socket,
+            VHOST_USER_SET_MEM_TABLE,
+            &payload,
+            &fds,
+            need_reply,
+        )?;
+        let reply = recv_reply(&self.socket, VHOST_USER_SET_MEM_TABLE, payload.len())?;
+        self.backend_regions = reply[8..]
+            .chunks(32)
+            .map(|region| {
+                let field = |i: usize| u64::from_le_bytes(region[i..i + 8].try_into().unwrap());
+                BackendRegion {
+                    guest_addr: GuestAddress(field(0)),
+                    size: field(8),
+                    backend_addr: field(16),
+                }
+            })
+            .collect();
+        send_reply(&self.socket, VHOST_USER_SET_MEM_TABLE, &0u64.to_le_bytes())?;
+        if need_reply {
+            self.recv_ack(VHOST_USER_SET_MEM_TABLE)?;
+        }
+        Ok(())
+    }
+
+    fn set_vring_state(&self, request: u32, queue_index: usize, num: u32) -> Result<()> {
+        let mut payload = [0u8; 8];
+        payload[..4].copy_from_slice(&(queue_index as u32).to_le_bytes());
+        payload[4..].copy_from_slice(&num.to_le_bytes());
+        self.request(request, &payload, &[])
+    }
+
+    fn set_vring_file(&self, request: u32, queue_index: usize, event: &Event) -> Result<()> {
+        self.request(
+            request,
+            &(queue_index as u64).to_le_bytes(),
+            &[event.as_raw_descriptor()],
+        )
+    }
+
+    // Sends a request that has no reply of its own, waiting for the backend to acknowledge it if
+    // the backend supports that.
+    fn request(&self, request: u32, payload: &[u8], fds: &[RawDescriptor]) -> Result<()> {
+        let need_reply = self.reply_ack();
+        send(&self.socket, request, payload, fds, need_reply)?;
+        if need_reply {
+            self.recv_ack(request)?;
+        }
+        Ok(())
+    }
+
+    fn reply_ack(&self) -> bool {
+        self.protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0
+    }
+
+    // Receives the reply to `request` that tells whether it succeeded.
+    fn recv_ack(&self, request: u32) -> Result<()> {
+        let reply = recv_reply(&self.socket, request, 8)?;
+        if u64::from_le_bytes(reply[..].try_into().unwrap()) != 0 {
+            return Err(Error::VhostUserRequestFailed(request));
+        }
+        Ok(())
+    }
+
+    fn get_u64(&self, request: u32) -> Result<u64> {
+        send(&self.socket, request, &[], &[], false)?;
+        let reply = recv_reply(&self.socket, request, 8)?;
+        Ok(u64::from_le_bytes(reply[..].try_into().unwrap()))
+    }
+
+    fn vring(&mut self, queue_index: usize) -> &mut VringState {
+        self.vrings.entry(queue_index).or_default()
+    }
+
+    fn check_postcopy(&self) -> Result<()> {
+        if self.protocol_features & VHOST_USER_PROTOCOL_F_PAGEFAULT == 0 {
+            return Err(Error::VhostUserPostcopyUnsupported);
+        }
+        Ok(())
+    }
+}
+
+impl VhostUser {
+    /// Connect to the vhost-user backend listening at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P, mem: &GuestMemory) -> Result<VhostUser> {
+        let socket = UnixStream::connect(&path).map_err(Error::VhostUserConnect)?;
+        let mut vhost_user = VhostUser::new(socket, mem)?;
+        vhost_user.path = Some(path.as_ref().to_path_buf());
+        Ok(vhost_user)
+    }
+
+    /// Set up a vhost-user session over an already connected `socket`.
+    ///
+    /// The backend is claimed and the protocol features are negotiated immediately so that the
+    /// device configuration can be read before the guest starts.
+    pub fn new(socket: UnixStream, mem: &GuestMemory) -> Result<VhostUser> {
+        VhostUser::negotiate(socket, mem, SUPPORTED_PROTOCOL_FEATURES)
+    }
+
+    /// Like `connect`, but also sets up the channel the backend makes requests of the frontend
+    /// over, if the backend supports it. The requests must then be handled, or the backend may
+    /// block waiting for their replies.
+    pub fn connect_with_backend_requests<P: AsRef<Path>>(
+        path: P,
+        mem: &GuestMemory,
+    ) -> Result<(VhostUser, Option<BackendRequestSocket>)> {
+        let socket = UnixStream::connect(&path).map_err(Error::VhostUserConnect)?;
+        let (mut vhost_user, requests) = VhostUser::new_with_backend_requests(socket, mem)?;
+        vhost_user.path = Some(path.as_ref().to_path_buf());
+        Ok((vhost_user, requests))
+    }
+
+    /// Like `new`, but also sets up the channel for backend requests as with
+    /// `connect_with_backend_requests`.
+    pub fn new_with_backend_requests(
+        socket: UnixStream,
+        mem: &GuestMemory,
+    ) -> Result<(VhostUser, Option<BackendRequestSocket>)> {
+        let vhost_user = VhostUser::negotiate(
+            socket,
+            mem,
+            SUPPORTED_PROTOCOL_FEATURES
+                | VHOST_USER_PROTOCOL_F_BACKEND_REQ
+                | VHOST_USER_PROTOCOL_F_BACKEND_SEND_FD,
+        )?;
+        let requests = vhost_user.conn.lock().backend_requests()?;
+        Ok((vhost_user, requests))
+    }
+
+    fn negotiate(
+        socket: UnixStream,
+        mem: &GuestMemory,
+        supported_protocol_features: u64,
+    ) -> Result<VhostUser> {
+        let conn = Connection::negotiate(socket, supported_protocol_features)?;
+        Ok(VhostUser {
+            conn: Arc::new(Mutex::new(conn)),
+            mem: mem.clone(),
+            path: None,
+        })
+    }
+
+    /// Connects again to the socket the backend listened on, and sets up everything that was set
+    /// up in the old backend in the backend now listening there. Clones switch to the new
+    /// connection too.
+    ///
+    /// Returns the new channel for backend requests, if the old connection had one.
+    pub fn reconnect(&self) -> Result<Option<BackendRequestSocket>> {
+        let path = self.path.as_ref().ok_or(Error::VhostUserNoReconnect)?;
+        let socket = UnixStream::connect(path).map_err(Error::VhostUserConnect)?;
+        let mut conn = self.conn.lock();
+        let mut new_conn = Connection::negotiate(socket, conn.supported_protocol_features)?;
+        new_conn.replay(&conn, &self.mem)?;
+        let requests = new_conn.backend_requests()?;
+        *conn = new_conn;
+        Ok(requests)
+    }
+
+    /// Returns the unix socket the backend listens on, if the connection was made by path.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Read `data.len()` bytes of the device configuration space, starting at `offset`.
+    pub fn get_config(&self, offset: u32, data: &mut [u8]) -> Result<()> {
+        self.check_config()?;
+        let mut payload = config_payload(offset, data.len())?;
+        payload.resize(12 + data.len(), 0);
+        let reply = {
+            let conn = self.conn.lock();
+            send(&conn.socket, VHOST_USER_GET_CONFIG, &payload, &[], false)?;
+            recv_reply(&conn.socket, VHOST_USER_GET_CONFIG, payload.len())?
+        };
+        // A backend signals failure by replying with an empty config.
+        if reply[4..8] != payload[4..8] {
+            return Err(Error::VhostUserRequestFailed(VHOST_USER_GET_CONFIG));
+        }
+        data.copy_from_slice(&reply[12..]);
+        Ok(())
+    }
+
+    /// Write `data` to the device configuration space, starting at `offset`.
+    pub fn set_config(&self, offset: u32, data: &[u8]) -> Result<()> {
+        self.check_config()?;
+        let mut payload = config_payload(offset, data.len())?;
+        payload.extend_from_slice(data);
+        self.conn
+            .lock()
+            .request(VHOST_USER_SET_CONFIG, &payload, &[])
+    }
+
+    fn check_config(&self) -> Result<()> {
+        if self.conn.lock().protocol_features & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            return Err(Error::VhostUserConfigUnsupported);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of queues the backend serves, or `None` if it can't report it.
+    pub fn get_queue_num(&self) -> Result<Option<u64>> {
+        let conn = self.conn.lock();
+        if conn.protocol_features & VHOST_USER_PROTOCOL_F_MQ == 0 {
+            return Ok(None);
+        }
+        conn.get_u64(VHOST_USER_GET_QUEUE_NUM).map(Some)
+    }
+
+    /// Enable or disable processing of a vring by the backend.
+    ///
+    /// Backends that don't negotiate protocol features start processing a vring as soon as its
+    /// kick event is set, so this is a no-op for them.
+    pub fn set_vring_enable(&self, queue_index: usize, enable: bool) -> Result<()> {
+        let mut conn = self.conn.lock();
+        if !conn.has_protocol_features {
+            return Ok(());
+        }
+        conn.vring(queue_index).enabled = enable;
+        conn.set_vring_state(VHOST_USER_SET_VRING_ENABLE, queue_index, enable as u32)
+    }
+
+    /// Advises the backend that guest memory is migrated after the VM starts running, in which
+    /// case the pages that haven't arrived yet must fault instead of reading as zeroes. Returns the
+    /// userfaultfd the backend reports those faults on, once it listens for them.
+    ///
+    /// The backend must support `VHOST_USER_PROTOCOL_F_PAGEFAULT`. A backend that was advised
+    /// can't be reconnected to, as its replacement wouldn't report faults on the same userfaultfd.
+    pub fn postcopy_advise(&self) -> Result<File> {
+        let mut conn = self.conn.lock();
+        conn.check_postcopy()?;
+        send(&conn.socket, VHOST_USER_POSTCOPY_ADVISE, &[], &[], false)?;
+        let (request, flags, _, mut files) = recv_message(&conn.socket)?
+            .ok_or(Error::VhostUserInvalidReply(VHOST_USER_POSTCOPY_ADVISE))?;
+        if request != VHOST_USER_POSTCOPY_ADVISE
+            || flags & VHOST_USER_REPLY_MASK == 0
+            || files.len() != 1
+        {
+            return Err(Error::VhostUserInvalidReply(VHOST_USER_POSTCOPY_ADVISE));
+        }
+        conn.postcopy = Postcopy::Advised;
+        Ok(files.remove(0))
+    }
+
+    /// Tells the backend that the VM started running, so it must report faults on the guest
+    /// memory it mapped. From now on, setting the memory table records where the backend mapped
+    /// each region, which `backend_regions` returns.
+    pub fn postcopy_listen(&self) -> Result<()> {
+        let mut conn = self.conn.lock();
+        if conn.postcopy != Postcopy::Advised {
+            return Err(Error::VhostUserPostcopyState(VHOST_USER_POSTCOPY_LISTEN));
+        }
+        conn.request(VHOST_USER_POSTCOPY_LISTEN, &[], &[])?;
+        conn.postcopy = Postcopy::Listening;
+        Ok(())
+    }
+
+    /// Tells the backend that all of guest memory arrived, so it stops reporting faults.
+    pub fn postcopy_end(&self) -> Result<()> {
+        let mut conn = self.conn.lock();
+        if conn.postcopy == Postcopy::Off {
+            return Err(Error::VhostUserPostcopyState(VHOST_USER_POSTCOPY_END));
+        }
+        if conn.get_u64(VHOST_USER_POSTCOPY_END)? != 0 {
+            return Err(Error::VhostUserRequestFailed(VHOST_USER_POSTCOPY_END));
+        }
+        conn.postcopy = Postcopy::Off;
+        conn.backend_regions.clear();
+        Ok(())
+    }
+
+    /// Returns where the backend mapped each guest memory region, for the faults it reports on its
+    /// userfaultfd to be resolved. Empty until the memory table is set while the backend listens.
+    pub fn backend_regions(&self) -> Vec<BackendRegion> {
+        self.conn.lock().backend_regions.clone()
+    }
+
+    fn set_vring_file(&self, request: u32, queue_index: usize, event: &Event) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let recorded = Some(event.try_clone().map_err(Error::VhostUserCloneEvent)?);
+        let vring = conn.vring(queue_index);
+        if request == VHOST_USER_SET_VRING_CALL {
+            vring.call = recorded;
+        } else {
+            vring.kick = recorded;
+        }
+        conn.set_vring_file(request, queue_index, event)
+    }
+}
+
+// Reads the index of the next entry the device will put in the used ring at `used_addr`.
+fn used_index(mem: &GuestMemory, used_addr: GuestAddress) -> Result<u16> {
+    mem.read_obj_from_addr(used_addr.unchecked_add(2))
+        .map_err(Error::UsedAddress)
+}
+
+/// Where a vhost-user backend mapped a guest memory region, which it reports the faults in by
+/// its own addresses during postcopy live migration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackendRegion {
+    pub guest_addr: GuestAddress,
+    pub size: u64,
+    pub backend_addr: u64,
+}
+
+/// A range of a device's shared memory region that a vhost-user backend asks its frontend to map a
+/// file into, or to unmap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShmMapping {
+    /// Where the mapping starts in the file. Unused when unmapping.
+    pub fd_offset: u64,
+    /// Where the mapping starts in the shared memory region.
+    pub shm_offset: u64,
+    pub len: u64,
+    /// A mask of `VHOST_USER_FS_FLAG_MAP_*`. Unused when unmapping.
+    pub flags: u64,
+}
+
+/// A request a vhost-user backend makes of its frontend.
+#[derive(Debug)]
+pub enum BackendRequest {
+    /// The device config space changed, which the driver has to be told about.
+    ConfigChanged,
+    /// Map `file` into the device's shared memory region.
+    Map {
+        file: File,
+        mappings: Vec<ShmMapping>,
+    },
+    /// Unmap parts of the device's shared memory region.
+    Unmap(Vec<ShmMapping>),
+}
+
+/// The frontend's end of the channel a vhost-user backend makes requests of it over.
+///
+/// The map requests have the layout of VHOST_USER_BACKEND_FS_{MAP,UNMAP}, which suits the shared
+/// memory region of any device, even though virtio-fs introduced them for its DAX window.
+pub struct BackendRequestSocket {
+    socket: UnixStream,
+}
+
+impl BackendRequestSocket {
+    /// Receives the next request and passes it to `handler`, whose result is replied to the
+    /// backend if the backend asked for a reply. Returns false once the backend hung up.
+    pub fn handle_request<F>(&self, handler: F) -> Result<bool>
+    where
+        F: FnOnce(BackendRequest) -> bool,
+    {
+        let (request, flags, payload, mut files) = match recv_message(&self.socket)? {
+            Some(msg) => msg,
+            None => return Ok(false),
+        };
+        let backend_request = match request {
+            VHOST_USER_BACKEND_CONFIG_CHANGE_MSG => BackendRequest::ConfigChanged,
+            VHOST_USER_BACKEND_FS_MAP | VHOST_USER_BACKEND_FS_UNMAP => {
+                let mappings = parse_fs_backend_msg(request, &payload)?;
+                if request == VHOST_USER_BACKEND_FS_UNMAP {
+                    BackendRequest::Unmap(mappings)
+                } else if files.len() == 1 {
+                    BackendRequest::Map {
+                        file: files.remove(0),
+                        mappings,
+                    }
+                } else {
+                    return Err(Error::VhostUserBadBackendRequest(request));
+                }
+            }
+            _ => return Err(Error::VhostUserBadBackendRequest(request)),
+        };
+
+        let ok = handler(backend_request);
+        if flags & VHOST_USER_NEED_REPLY_MASK != 0 {
+            send_reply(&self.socket, request, &(!ok as u64).to_le_bytes())?;
+        }
+        Ok(true)
+    }
+}
+
+impl AsRawDescriptor for BackendRequestSocket {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+/// The backend's end of the channel it makes requests of its frontend over, received with
+/// VHOST_USER_SET_BACKEND_REQ_FD.
+pub struct BackendRequester {
+    socket: Mutex<UnixStream>,
+    reply_ack: bool,
+}
+
+impl BackendRequester {
+    /// Wraps the channel `socket`. With `reply_ack`, the requests that change the shared memory
+    /// region wait for the frontend to carry them out.
+    pub fn new(socket: UnixStream, reply_ack: bool) -> BackendRequester {
+        BackendRequester {
+            socket: Mutex::new(socket),
+            reply_ack,
+        }
+    }
+
+    /// Tells the frontend the device config space changed. Doesn't wait for the frontend, which
+    /// may be busy waiting for the backend itself.
+    pub fn config_changed(&self) -> Result<()> {
+        send(
+            &self.socket.lock(),
+            VHOST_USER_BACKEND_CONFIG_CHANGE_MSG,
+            &[],
+            &[],
+            false,
+        )
+    }
+
+    /// Asks the frontend to map `file` into the device's shared memory region.
+    pub fn map(&self, file: &File, mappings: &[ShmMapping]) -> Result<()> {
+        self.fs_request(VHOST_USER_BACKEND_FS_MAP, mappings, &[file.as_raw_fd()])
+    }
+
+    /// Asks the frontend to unmap parts of the device's shared memory region.
+    pub fn unmap(&self, mappings: &[ShmMapping]) -> Result<()> {
+        self.fs_request(VHOST_USER_BACKEND_FS_UNMAP, mappings, &[])
+    }
+
+    fn fs_request(
+        &self,
+        request: u32,
+        mappings: &[ShmMapping],
+        fds: &[RawDescriptor],
+    ) -> Result<()> {
+        if mappings.len() > VHOST_USER_FS_BACKEND_ENTRIES {
+            return Err(Error::VhostUserBadBackendRequest(request));
+        }
+        let mut payload = vec![0u8; FS_BACKEND_MSG_SIZE];
+        for (i, mapping) in mappings.iter().enumerate() {
+            let fields = [
+                mapping.fd_offset,
+                mapping.shm_offset,
+                mapping.len,
+                mapping.flags,
+            ];
+            for (field, value) in fields.iter().enumerate() {
+                let start = (field * VHOST_USER_FS_BACKEND_ENTRIES + i) * 8;
+                payload[start..start + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let socket = self.socket.lock();
+        send(&socket, request, &payload, fds, self.reply_ack)?;
+        if self.reply_ack {
+            let reply = recv_reply(&socket, request, 8)?;
+            if u64::from_le_bytes(reply[..].try_into().unwrap()) != 0 {
+                return Err(Error::VhostUserRequestFailed(request));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parses the mappings of a VHOST_USER_BACKEND_FS_{MAP,UNMAP} payload, which holds an array of each
+// field in turn, skipping the unused ones.
+fn parse_fs_backend_msg(request: u32, payload: &[u8]) -> Result<Vec<ShmMapping>> {
+    if payload.len() != FS_BACKEND_MSG_SIZE {
+        return Err(Error::VhostUserBadBackendRequest(request));
+    }
+    let field = |field: usize, i: usize| {
+        let start = (field * VHOST_USER_FS_BACKEND_ENTRIES + i) * 8;
+        u64::from_le_bytes(payload[start..start + 8].try_into().unwrap())
+    };
+    Ok((0..VHOST_USER_FS_BACKEND_ENTRIES)
+        .map(|i| ShmMapping {
+            fd_offset: field(0, i),
+            shm_offset: field(1, i),
+            len: field(2, i),
+            flags: field(3, i),
+        })
+        .filter(|mapping| mapping.len != 0)
+        .collect())
+}
+
+// Returns the header of a VHOST_USER_{GET,SET}_CONFIG payload for `size` bytes at `offset`.
+fn config_payload(offset: u32, size: usize) -> Result<Vec<u8>> {
+    if size > VHOST_USER_CONFIG_SIZE {
+        return Err(Error::VhostUserConfigTooLarge(size));
+    }
+    let mut payload = Vec::with_capacity(12 + size);
+    payload.extend_from_slice(&offset.to_le_bytes());
+    payload.extend_from_slice(&(size as u32).to_le_bytes());
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    Ok(payload)
+}
+
+fn send(
+    socket: &UnixStream,
+    request: u32,
+    payload: &[u8],
+    fds: &[RawDescriptor],
+    need_reply: bool,
+) -> Result<()> {
+    let mut flags = VHOST_USER_VERSION;
+    if need_reply {
+        flags |= VHOST_USER_NEED_REPLY_MASK;
+    }
+
+    let mut msg = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&request.to_le_bytes());
+    msg.extend_from_slice(&flags.to_le_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(payload);
+    send_raw(socket, &msg, fds)
+}
+
+fn send_reply(socket: &UnixStream, request: u32, payload: &[u8]) -> Result<()> {
+    let mut msg = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&request.to_le_bytes());
+    msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(payload);
+    send_raw(socket, &msg, &[])
+}
+
+fn send_raw(socket: &UnixStream, msg: &[u8], fds: &[RawDescriptor]) -> Result<()> {
+    let sent = socket
+        .send_with_fds(&[IoSlice::new(msg)], fds)
+        .map_err(Error::VhostUserSend)?;
+    if sent != msg.len() {
+        return Err(Error::VhostUserSend(base::Error::new(libc::EIO)));
+    }
+    Ok(())
+}
+
+fn recv_reply(mut socket: &UnixStream, request: u32, size: usize) -> Result<Vec<u8>> {
+    let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+    socket
+        .read_exact(&mut header)
+        .map_err(Error::VhostUserRecv)?;
+
+    let reply_request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let reply_flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let reply_size = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if reply_request != request
+        || reply_flags & VHOST_USER_REPLY_MASK == 0
+        || reply_size as usize != size
+    {
+        return Err(Error::VhostUserInvalidReply(request));
+    }
+
+    let mut payload = vec![0u8; size];
+    socket
+        .read_exact(&mut payload)
+        .map_err(Error::VhostUserRecv)?;
+    Ok(payload)
+}
+
+// Receives the request, flags, payload and fds of the next message, or `None` once the peer hung
+// up.
+fn recv_message(socket: &UnixStream) -> Result<Option<(u32, u32, Vec<u8>, Vec<File>)>> {
+    let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+    let mut fds = [0; VHOST_USER_MAX_MEM_REGIONS];
+    let (len, fd_count) = socket
+        .recv_with_fds(&mut header, &mut fds)
+        .map_err(Error::VhostUserRecvFds)?;
+    // Safe because the fds were just received, so nothing else owns them.
+    let files: Vec<File> = fds[..fd_count]
+        .iter()
+        .map(|&fd| unsafe { File::from_raw_fd(fd) })
+        .collect();
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut socket = socket;
+    socket
+        .read_exact(&mut header[len..])
+        .map_err(Error::VhostUserRecv)?;
+
+    let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    if size > FS_BACKEND_MSG_SIZE {
+        return Err(Error::VhostUserBadBackendRequest(request));
+    }
+    let mut payload = vec![0u8; size];
+    socket
+        .read_exact(&mut payload)
+        .map_err(Error::VhostUserRecv)?;
+    Ok(Some((request, flags, payload, files)))
+}
+
+impl Vhost for VhostUser {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    fn set_owner(&self) -> Result<()> {
+        // The backend was already claimed when the connection was set up.
+        Ok(())
+    }
+
+    fn get_features(&self) -> Result<u64> {
+        self.conn.lock().get_u64(VHOST_USER_GET_FEATURES)
+    }
+
+    fn set_features(&self, features: u64) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let mut features = features;
+        if conn.has_protocol_features {
+            features |= VHOST_USER_F_PROTOCOL_FEATURES;
+        }
+        conn.features = Some(features);
+        conn.request(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    fn set_mem_table(&self) -> Result<()> {
+        let mut conn = self.conn.lock();
+        conn.mem_table = true;
+        conn.set_mem_table(&self.mem)
+    }
+
+    fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
+        let mut conn = self.conn.lock();
+        conn.vring(queue_index).num = Some(num as u32);
+        conn.set_vring_state(VHOST_USER_SET_VRING_NUM, queue_index, num as u32)
+    }
+
+    fn set_vring_addr(
+        &self,
+        queue_max_size: u16,
+        queue_size: u16,
+        queue_index: usize,
+        flags: u32,
+        desc_addr: GuestAddress,
+        used_addr: GuestAddress,
+        avail_addr: GuestAddress,
+        log_addr: Option<GuestAddress>,
+    ) -> Result<()> {
+        let addr = vring_addr(
+            self,
+            queue_max_size,
+            queue_size,
+            queue_index,
+            flags,
+            desc_addr,
+            used_addr,
+            avail_addr,
+            log_addr,
+        )?;
+
+        let mut payload = Vec::with_capacity(40);
+        payload.extend_from_slice(&addr.index.to_le_bytes());
+        payload.extend_from_slice(&addr.flags.to_le_bytes());
+        payload.extend_from_slice(&addr.desc_user_addr.to_le_bytes());
+        payload.extend_from_slice(&addr.used_user_addr.to_le_bytes());
+        payload.extend_from_slice(&addr.avail_user_addr.to_le_bytes());
+        payload.extend_from_slice(&addr.log_guest_addr.to_le_bytes());
+        let mut conn = self.conn.lock();
+        conn.vring(queue_index).addr = Some((payload.clone(), used_addr));
+        conn.request(VHOST_USER_SET_VRING_ADDR, &payload, &[])
+    }
+
+    fn set_vring_base(&self, queue_index: usize, num: u16) -> Result<()> {
+        let mut conn = self.conn.lock();
+        conn.vring(queue_index).base = Some(num as u32);
+        conn.set_vring_state(VHOST_USER_SET_VRING_BASE, queue_index, num as u32)
+    }
+
+    fn set_vring_call(&self, queue_index: usize, event: &Event) -> Result<()> {
+        self.set_vring_file(VHOST_USER_SET_VRING_CALL, queue_index, event)
+    }
+
+    fn set_vring_kick(&self, queue_index: usize, event: &Event) -> Result<()> {
+        self.set_vring_file(VHOST_USER_SET_VRING_KICK, queue_index, event)
+    }
+}
+
+impl AsRawDescriptor for VhostUser {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.conn.lock().socket.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    // Reads one message from the frontend, returning its request, flags and payload.
+    fn read_message(socket: &mut UnixStream) -> (u32, u32, Vec<u8>) {
+        let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+        socket.read_exact(&mut header).unwrap();
+        let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let size = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let mut payload = vec![0u8; size as usize];
+        socket.read_exact(&mut payload).unwrap();
+        (request, flags, payload)
+    }
+
+    fn write_reply(socket: &mut UnixStream, request: u32, payload: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+        msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        msg.extend_from_slice(payload);
+        socket.write_all(&msg).unwrap();
+    }
+
+    #[test]
+    fn negotiate_and_get_config() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+
+        let backend_thread = thread::spawn(move || {
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_OWNER);
+
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_FEATURES);
+            write_reply(
+                &mut backend,
+                request,
+                &VHOST_USER_F_PROTOCOL_FEATURES.to_le_bytes(),
+            );
+
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_PROTOCOL_FEATURES);
+            write_reply(&mut backend, request, &u64::max_value().to_le_bytes());
+
+            let (request, _, payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_PROTOCOL_FEATURES);
+            assert_eq!(payload, SUPPORTED_PROTOCOL_FEATURES.to_le_bytes());
+
+            let (request, _, mut payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_CONFIG);
+            payload[12..].copy_from_slice(&42u64.to_le_bytes());
+            write_reply(&mut backend, request, &payload);
+
+            let (request, flags, payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_VRING_ENABLE);
+            assert_ne!(flags & VHOST_USER_NEED_REPLY_MASK, 0);
+            assert_eq!(payload, [1, 0, 0, 0, 1, 0, 0, 0]);
+            write_reply(&mut backend, request, &0u64.to_le_bytes());
+        });
+
+        let vhost_user = VhostUser::new(frontend, &mem).unwrap();
+        let mut config = [0u8; 8];
+        vhost_user.get_config(0, &mut config).unwrap();
+        assert_eq!(u64::from_le_bytes(config), 42);
+        vhost_user.set_vring_enable(1, true).unwrap();
+
+        backend_thread.join().unwrap();
+    }
+
+    #[test]
+    fn queue_num_and_set_config() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+
+        let backend_thread = thread::spawn(move || {
+            read_message(&mut backend);
+            let (request, _, _) = read_message(&mut backend);
+            write_reply(
+                &mut backend,
+                request,
+                &VHOST_USER_F_PROTOCOL_FEATURES.to_le_bytes(),
+            );
+            let (request, _, _) = read_message(&mut backend);
+            write_reply(
+                &mut backend,
+                request,
+                &(VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_CONFIG).to_le_bytes(),
+            );
+            read_message(&mut backend);
+
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_QUEUE_NUM);
+            write_reply(&mut backend, request, &3u64.to_le_bytes());
+
+            let (request, flags, payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_CONFIG);
+            // Without REPLY_ACK the frontend doesn't wait for the backend.
+            assert_eq!(flags & VHOST_USER_NEED_REPLY_MASK, 0);
+            assert_eq!(payload, [4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0xaa, 0xbb]);
+        });
+
+        let vhost_user = VhostUser::new(frontend, &mem).unwrap();
+        // Clones share the connection.
+        let clone = vhost_user.clone();
+        assert_eq!(clone.get_queue_num().unwrap(), Some(3));
+        vhost_user.set_config(4, &[0xaa, 0xbb]).unwrap();
+        backend_thread.join().unwrap();
+
+        match vhost_user.get_config(0, &mut [0u8; VHOST_USER_CONFIG_SIZE + 1]) {
+            Err(Error::VhostUserConfigTooLarge(_)) => {}
+            r => panic!("unexpected get_config result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn backend_requests() {
+        let (frontend, backend) = UnixStream::pair().unwrap();
+        let requests = BackendRequestSocket { socket: frontend };
+        let requester = BackendRequester::new(backend, true);
+        let mapping = ShmMapping {
+            fd_offset: 0x1000,
+            shm_offset: 0x20000,
+            len: 0x3000,
+            flags: VHOST_USER_FS_FLAG_MAP_R | VHOST_USER_FS_FLAG_MAP_W,
+        };
+
+        let backend_thread = thread::spawn(move || {
+            requester.config_changed().unwrap();
+            let file = File::open("/dev/null").unwrap();
+            requester.map(&file, &[mapping]).unwrap();
+            // The frontend fails the unmap.
+            assert!(requester.unmap(&[mapping]).is_err());
+        });
+
+        assert!(requests
+            .handle_request(|r| matches!(r, BackendRequest::ConfigChanged))
+            .unwrap());
+        assert!(requests
+            .handle_request(|r| match r {
+                BackendRequest::Map { mappings, .. } => mappings == [mapping],
+                _ => false,
+            })
+            .unwrap());
+        assert!(requests
+            .handle_request(|r| match r {
+                BackendRequest::Unmap(mappings) => {
+                    assert_eq!(mappings, [mapping]);
+                    false
+                }
+                _ => true,
+            })
+            .unwrap());
+        backend_thread.join().unwrap();
+        // The backend hung up.
+        assert!(!requests.handle_request(|_| true).unwrap());
+    }
+
+    // Serves the handshake of a backend with protocol features but none of the optional ones, then
+    // reads `count` more messages, returning their requests and payloads.
+    fn serve_session(mut backend: UnixStream, count: usize) -> Vec<(u32, Vec<u8>)> {
+        read_message(&mut backend);
+        let (request, _, _) = read_message(&mut backend);
+        write_reply(
+            &mut backend,
+            request,
+            &(VHOST_USER_F_PROTOCOL_FEATURES | 1).to_le_bytes(),
+        );
+        let (request, _, _) = read_message(&mut backend);
+        write_reply(&mut backend, request, &0u64.to_le_bytes());
+        read_message(&mut backend);
+
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            let (request, _, payload) = read_message(&mut backend);
+            if request == VHOST_USER_GET_FEATURES {
+                write_reply(
+                    &mut backend,
+                    request,
+                    &(VHOST_USER_F_PROTOCOL_FEATURES | 1).to_le_bytes(),
+                );
+            }
+            messages.push((request, payload));
+        }
+        messages
+    }
+
+    #[test]
+    fn reconnect() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("backend.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let backend_thread = thread::spawn(move || {
+            let (backend, _) = listener.accept().unwrap();
+            serve_session(backend, 8);
+            listener
+        });
+
+        let vhost_user = VhostUser::connect(&path, &mem).unwrap();
+        let event = Event::new().unwrap();
+        vhost_user.set_features(1).unwrap();
+        vhost_user.set_mem_table().unwrap();
+        vhost_user.set_vring_num(0, 16).unwrap();
+        vhost_user
+            .set_vring_addr(
+                16,
+                16,
+                0,
+                0,
+                GuestAddress(0x1000),
+                GuestAddress(0x3000),
+                GuestAddress(0x2000),
+                None,
+            )
+            .unwrap();
+        vhost_user.set_vring_base(0, 0).unwrap();
+        vhost_user.set_vring_call(0, &event).unwrap();
+        vhost_user.set_vring_kick(0, &event).unwrap();
+        vhost_user.set_vring_enable(0, true).unwrap();
+        // The first backend goes away once it has read everything.
+        let listener = backend_thread.join().unwrap();
+
+        // The device used 5 buffers before the backend went away.
+        mem.write_obj_at_addr(5u16, GuestAddress(0x3002)).unwrap();
+        let backend_thread = thread::spawn(move || {
+            let (backend, _) = listener.accept().unwrap();
+            serve_session(backend, 9)
+        });
+        assert!(vhost_user.reconnect().unwrap().is_none());
+        let messages = backend_thread.join().unwrap();
+
+        let requests: Vec<u32> = messages.iter().map(|(request, _)| *request).collect();
+        assert_eq!(
+            requests,
+            [
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_SET_FEATURES,
+                VHOST_USER_SET_MEM_TABLE,
+                VHOST_USER_SET_VRING_NUM,
+                VHOST_USER_SET_VRING_ADDR,
+                VHOST_USER_SET_VRING_BASE,
+                VHOST_USER_SET_VRING_CALL,
+                VHOST_USER_SET_VRING_KICK,
+                VHOST_USER_SET_VRING_ENABLE,
+            ]
+        );
+        assert_eq!(
+            messages[1].1,
+            (VHOST_USER_F_PROTOCOL_FEATURES | 1).to_le_bytes()
+        );
+        // The new backend picks up after the last used buffer.
+        assert_eq!(messages[5].1, [0, 0, 0, 0, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn postcopy() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+
+        let backend_thread = thread::spawn(move || {
+            read_message(&mut backend);
+            let (request, _, _) = read_message(&mut backend);
+            write_reply(
+                &mut backend,
+                request,
+                &VHOST_USER_F_PROTOCOL_FEATURES.to_le_bytes(),
+            );
+            let (request, _, _) = read_message(&mut backend);
+            write_reply(
+                &mut backend,
+                request,
+                &(VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_PAGEFAULT).to_le_bytes(),
+            );
+            read_message(&mut backend);
+
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_POSTCOPY_ADVISE);
+            let uffd = File::open("/dev/null").unwrap();
+            let mut msg = Vec::new();
+            msg.extend_from_slice(&request.to_le_bytes());
+            msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+            msg.extend_from_slice(&0u32.to_le_bytes());
+            send_raw(&backend, &msg, &[uffd.as_raw_fd()]).unwrap();
+
+            let (request, flags, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_POSTCOPY_LISTEN);
+            assert_ne!(flags & VHOST_USER_NEED_REPLY_MASK, 0);
+            write_reply(&mut backend, request, &0u64.to_le_bytes());
+
+            // The backend replies with the table, swapping in its own address of the region.
+            let (request, _, mut payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_MEM_TABLE);
+            payload[24..32].copy_from_slice(&0x7f00_0000u64.to_le_bytes());
+            write_reply(&mut backend, request, &payload);
+            let (request, flags, payload) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_MEM_TABLE);
+            assert_ne!(flags & VHOST_USER_REPLY_MASK, 0);
+            assert_eq!(payload, 0u64.to_le_bytes());
+            write_reply(&mut backend, request, &0u64.to_le_bytes());
+
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_POSTCOPY_END);
+            write_reply(&mut backend, request, &0u64.to_le_bytes());
+        });
+
+        let vhost_user = VhostUser::new(frontend, &mem).unwrap();
+        match vhost_user.postcopy_listen() {
+            Err(Error::VhostUserPostcopyState(VHOST_USER_POSTCOPY_LISTEN)) => {}
+            r => panic!("unexpected postcopy_listen result: {:?}", r),
+        }
+        vhost_user.postcopy_advise().unwrap();
+        vhost_user.postcopy_listen().unwrap();
+        vhost_user.set_mem_table().unwrap();
+        assert_eq!(
+            vhost_user.backend_regions(),
+            [BackendRegion {
+                guest_addr: GuestAddress(0),
+                size: 0x10000,
+                backend_addr: 0x7f00_0000,
+            }]
+        );
+        vhost_user.postcopy_end().unwrap();
+        assert!(vhost_user.backend_regions().is_empty());
+        backend_thread.join().unwrap();
+    }
+
+    #[test]
+    fn no_protocol_features() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+
+        let backend_thread = thread::spawn(move || {
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_SET_OWNER);
+            let (request, _, _) = read_message(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_FEATURES);
+            write_reply(&mut backend, request, &0u64.to_le_bytes());
+        });
+
+        let vhost_user = VhostUser::new(frontend, &mem).unwrap();
+        backend_thread.join().unwrap();
+
+        let mut config = [0u8; 8];
+        match vhost_user.get_config(0, &mut config) {
+            Err(Error::VhostUserConfigUnsupported) => {}
+            r => panic!("unexpected get_config result: {:?}", r),
+        }
+        // Without protocol features the rings are enabled implicitly, so nothing is sent.
+        vhost_user.set_vring_enable(0, true).unwrap();
+        assert_eq!(vhost_user.get_queue_num().unwrap(), None);
+        match vhost_user.postcopy_advise() {
+            Err(Error::VhostUserPostcopyUnsupported) => {}
+            r => panic!("unexpected postcopy_advise result: {:?}", r),
+        }
+        // The connection wasn't made by path, so it can't be made again.
+        match vhost_user.reconnect() {
+            Err(Error::VhostUserNoReconnect) => {}
+            r => panic!("unexpected reconnect result: {:?}", r.map(|_| ())),
+        }
+    }
+}
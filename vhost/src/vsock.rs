@@ -9,10 +9,30 @@ use base::{ioctl_with_ref, AsRawDescriptor, RawDescriptor};
 use virtio_sys::{VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
 use vm_memory::GuestMemory;
 
-use super::{ioctl_result, Error, Result, Vhost};
+use super::{ioctl_result, Error, Result, Vhost, VhostUser};
 
 static DEVICE: &str = "/dev/vhost-vsock";
 
+/// The vring indices serviced by a vsock backend: rx and tx. The event queue is handled by the
+/// device itself.
+const VSOCK_VRINGS: &[usize] = &[0, 1];
+
+/// Operations common to the handles that can serve the vsock datapath.
+pub trait VsockT: Vhost + Send {
+    /// Set the CID for the guest.  This number is used for routing all data destined for
+    /// programs running in the guest.
+    ///
+    /// # Arguments
+    /// * `cid` - CID to assign to the guest
+    fn set_cid(&self, cid: u64) -> Result<()>;
+
+    /// Tell the backend to start performing data transfer.
+    fn start(&self) -> Result<()>;
+
+    /// Descriptors that must stay open for the handle to be used from a sandboxed process.
+    fn keep_rds(&self) -> Vec<RawDescriptor>;
+}
+
 /// Handle for running VHOST_VSOCK ioctls.
 pub struct Vsock {
     descriptor: File,
@@ -33,13 +53,24 @@ impl Vsock {
         })
     }
 
-    /// Set the CID for the guest.  This number is used for routing all data destined for
-    /// programs
-    /// running in the guest.
-    ///
-    /// # Arguments
-    /// * `cid` - CID to assign to the guest
-    pub fn set_cid(&self, cid: u64) -> Result<()> {
+    /// Tell the VHOST driver to stop performing data transfer.
+    pub fn stop(&self) -> Result<()> {
+        self.set_running(false)
+    }
+
+    fn set_running(&self, running: bool) -> Result<()> {
+        let on: ::std::os::raw::c_int = if running { 1 } else { 0 };
+        let ret = unsafe { ioctl_with_ref(&self.descriptor, VHOST_VSOCK_SET_RUNNING(), &on) };
+
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+}
+
+impl VsockT for Vsock {
+    fn set_cid(&self, cid: u64) -> Result<()> {
         let ret = unsafe { ioctl_with_ref(&self.descriptor, VHOST_VSOCK_SET_GUEST_CID(), &cid) };
         if ret < 0 {
             return ioctl_result();
@@ -47,25 +78,35 @@ impl Vsock {
         Ok(())
     }
 
-    /// Tell the VHOST driver to start performing data transfer.
-    pub fn start(&self) -> Result<()> {
+    fn start(&self) -> Result<()> {
         self.set_running(true)
     }
 
-    /// Tell the VHOST driver to stop performing data transfer.
-    pub fn stop(&self) -> Result<()> {
-        self.set_running(false)
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.descriptor.as_raw_descriptor()]
     }
+}
 
-    fn set_running(&self, running: bool) -> Result<()> {
-        let on: ::std::os::raw::c_int = if running { 1 } else { 0 };
-        let ret = unsafe { ioctl_with_ref(&self.descriptor, VHOST_VSOCK_SET_RUNNING(), &on) };
+impl VsockT for VhostUser {
+    fn set_cid(&self, _cid: u64) -> Result<()> {
+        // The backend is started with the guest's CID and the device reports that CID back to the
+        // guest, so there is nothing to configure.
+        Ok(())
+    }
 
-        if ret < 0 {
-            return ioctl_result();
+    fn start(&self) -> Result<()> {
+        for &index in VSOCK_VRINGS {
+            self.set_vring_enable(index, true)?;
         }
         Ok(())
     }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        // The guest memory descriptor is passed to the backend when the memory table is set.
+        let mut rds = vec![self.as_raw_descriptor()];
+        rds.extend(self.mem().shm().map(AsRawDescriptor::as_raw_descriptor));
+        rds
+    }
 }
 
 impl Vhost for Vsock {
@@ -4,6 +4,8 @@
 
 #[cfg(target_arch = "x86_64")]
 use gdbstub::arch::x86::reg::X86_64CoreRegs as CoreRegs;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::x86_64::Watchpoint;
 use vm_memory::GuestAddress;
 
 /// Messages that can be sent to a vCPU to set/get its state from the debugger.
@@ -12,18 +14,31 @@ pub enum VcpuDebug {
     ReadMem(GuestAddress, usize),
     ReadRegs,
     WriteRegs(Box<CoreRegs>),
+    /// Reads the register with the given gdb register number.
+    ReadReg(usize),
+    /// Writes the register with the given gdb register number, in target byte order.
+    WriteReg(usize, Vec<u8>),
     WriteMem(GuestAddress, Vec<u8>),
     EnableSinglestep,
-    SetHwBreakPoint(Vec<GuestAddress>),
+    /// Sets whether the vCPU stays stopped when the VM resumes, so that gdb can resume the other
+    /// vCPUs on their own.
+    KeepStopped(bool),
+    /// Sets the hardware breakpoints and watchpoints, replacing any set before.
+    SetHwBreakPoint(Vec<GuestAddress>, Vec<Watchpoint>),
 }
 
 /// Messages that can be sent from a vCPU to update the state to the debugger.
 #[derive(Debug)]
 pub enum VcpuDebugStatus {
     RegValues(CoreRegs),
+    /// The value of the register asked for with `ReadReg`, in target byte order.
+    RegValue(Vec<u8>),
     MemoryRegion(Vec<u8>),
     CommandComplete,
-    HitBreakPoint,
+    /// The vCPU stopped on a breakpoint, watchpoint or single step. Carries the index of the
+    /// debug register that triggered, counting breakpoints then watchpoints as passed to
+    /// `SetHwBreakPoint`, if a debug register caused the stop.
+    HitBreakPoint(Option<usize>),
 }
 
 /// Pair of a vCPU ID and messages that can be sent from the vCPU to update the state to the
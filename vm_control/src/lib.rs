@@ -13,28 +13,34 @@
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 pub mod gdb;
 
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
-use std::fs::File;
+use std::fs::{self, File};
 use std::mem::ManuallyDrop;
 use std::os::raw::c_int;
 use std::result::Result as StdResult;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
-use libc::{EINVAL, EIO, ENODEV};
+use libc::{EINVAL, EIO, ENODEV, ENOTSUP};
 
 use base::{
-    error, AsRawDescriptor, Error as SysError, Event, ExternalMapping, Fd, FromRawDescriptor,
-    IntoRawDescriptor, MappedRegion, MemoryMappingArena, MemoryMappingBuilder, MmapError,
-    Protection, RawDescriptor, Result, SafeDescriptor,
+    error, pagesize, AsRawDescriptor, Error as SysError, Event, ExternalMapping, Fd,
+    FromRawDescriptor, IntoRawDescriptor, MappedRegion, MemoryMappingArena, MemoryMappingBuilder,
+    MmapError, Protection, RawDescriptor, Result, SafeDescriptor,
 };
 use hypervisor::{IrqRoute, IrqSource, Vm};
 use msg_socket::{MsgError, MsgOnSocket, MsgReceiver, MsgResult, MsgSender, MsgSocket};
 use resources::{Alloc, MmioType, SystemAllocator};
 use rutabaga_gfx::{DrmFormat, ImageAllocationInfo, RutabagaGralloc, RutabagaGrallocFlags};
+use serde::Serialize;
 use sync::Mutex;
 use vm_memory::GuestAddress;
 
+// How long to wait for the balloon device to report its size.
+const BALLOON_ACCOUNTING_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Struct that describes the offset and stride of a plane located in GPU memory.
 #[derive(Clone, Copy, Debug, PartialEq, Default, MsgOnSocket)]
 pub struct GpuMemoryPlaneDesc {
@@ -58,6 +64,12 @@ pub enum VcpuControl {
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     Debug(VcpuDebug),
     RunState(VmRunMode),
+    /// Reply once the VCPU has handled the messages sent before this one.
+    Sync(mpsc::Sender<()>),
+    /// Reply with the state of the paused VCPU for a VM snapshot, or `None` if it can't be saved.
+    Snapshot(mpsc::Sender<Option<Vec<u8>>>),
+    /// Load VCPU state saved by `Snapshot` into the paused VCPU, and reply whether it succeeded.
+    Restore(Vec<u8>, mpsc::Sender<bool>),
 }
 
 /// A file descriptor either borrowed or owned by this.
@@ -166,10 +178,12 @@ pub enum BalloonControlCommand {
         num_bytes: u64,
     },
     Stats,
+    /// Report the memory currently held by the balloon without waiting for guest statistics.
+    Accounting,
 }
 
 // BalloonStats holds stats returned from the stats_queue.
-#[derive(Default, MsgOnSocket, Debug)]
+#[derive(Default, MsgOnSocket, Debug, Serialize)]
 pub struct BalloonStats {
     pub swap_in: Option<u64>,
     pub swap_out: Option<u64>,
@@ -226,6 +240,55 @@ pub enum BalloonControlResult {
         stats: BalloonStats,
         balloon_actual: u64,
     },
+    Accounting {
+        /// Size of the balloon, as reported by the guest.
+        balloon_actual: u64,
+        /// Bytes of guest memory given back to the host through the balloon.
+        removed_bytes: u64,
+    },
+    /// Sent unprompted when a guest agent asks for the amount of memory it wants to keep, in bytes.
+    GuestRequest { num_bytes: u64 },
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum MemControlCommand {
+    /// Ask the guest to plug or unplug virtio-mem blocks until `num_bytes` of the hotplug region
+    /// are plugged.
+    Resize { num_bytes: u64 },
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum MemControlResult {
+    /// The size the guest was asked for, rounded down to whole blocks and clamped to the region,
+    /// and the size it currently has plugged.
+    Resized {
+        requested_bytes: u64,
+        plugged_bytes: u64,
+    },
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum VfioCommand {
+    /// Attach the host PCI device at sysfs `path`, which must already be bound to vfio-pci.
+    Add { path: String },
+    /// Detach the host PCI device at sysfs `path` that was attached with `Add`.
+    Remove { path: String },
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum VcpuCommand {
+    /// Make VCPU `id` present and ask the guest to bring it online.
+    Add { id: usize },
+    /// Ask the guest to take VCPU `id` offline and eject it.
+    Remove { id: usize },
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum SnapshotCommand {
+    /// Write the state of the VCPUs, irqchip, devices and guest memory to `file`.
+    Take { file: File },
+    /// Load the state written by `Take` from `file` into a VM started with the same options.
+    Restore { file: File },
 }
 
 #[derive(MsgOnSocket, Debug)]
@@ -250,6 +313,74 @@ pub enum DiskControlResult {
     Err(SysError),
 }
 
+#[derive(MsgOnSocket, Debug)]
+pub enum GpuControlCommand {
+    /// Tear down the renderer and build a new one. Resources are recreated where possible, but the
+    /// guest's contexts are lost and commands on them fail until the guest recreates them.
+    ReloadRenderer,
+    /// Change the size of a display and tell the guest its display configuration changed.
+    SetDisplayResolution {
+        display: u32,
+        width: u32,
+        height: u32,
+    },
+    /// List the displays of the device with their current sizes.
+    ListDisplays,
+}
+
+impl Display for GpuControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GpuControlCommand::*;
+
+        match self {
+            ReloadRenderer => write!(f, "gpu_reload_renderer"),
+            SetDisplayResolution { .. } => write!(f, "gpu_set_display_resolution"),
+            ListDisplays => write!(f, "gpu_list_displays"),
+        }
+    }
+}
+
+/// A display of the virtual GPU, as listed by `GpuControlCommand::ListDisplays`.
+#[derive(MsgOnSocket, Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct GpuDisplayInfo {
+    pub display: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Display for GpuDisplayInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<7} {}x{}", self.display, self.width, self.height)
+    }
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum GpuControlResult {
+    Ok,
+    Displays(Vec<GpuDisplayInfo>),
+    NoSuchDisplay(u32),
+    InvalidResolution { width: u32, height: u32 },
+}
+
+impl Display for GpuControlResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpuControlResult::Ok => write!(f, "ok"),
+            GpuControlResult::Displays(displays) => {
+                write!(f, "display size")?;
+                for display in displays {
+                    write!(f, "\n{}", display)?;
+                }
+                Ok(())
+            }
+            GpuControlResult::NoSuchDisplay(display) => write!(f, "no display {}", display),
+            GpuControlResult::InvalidResolution { width, height } => {
+                write!(f, "invalid display resolution {}x{}", width, height)
+            }
+        }
+    }
+}
+
 #[derive(MsgOnSocket, Debug)]
 pub enum UsbControlCommand {
     AttachDevice {
@@ -265,9 +396,17 @@ pub enum UsbControlCommand {
     ListDevice {
         ports: [u8; USB_CONTROL_MAX_PORTS],
     },
+    /// Attaches the first host device with the given vendor and product ID. The main process
+    /// opens the device, so this never reaches the USB device provider. With `persistent` set,
+    /// the device is attached again each time it reappears after being unplugged.
+    AttachDeviceById {
+        vid: u16,
+        pid: u16,
+        persistent: bool,
+    },
 }
 
-#[derive(MsgOnSocket, Copy, Clone, Debug, Default)]
+#[derive(MsgOnSocket, Copy, Clone, Debug, Default, Serialize)]
 pub struct UsbControlAttachedDevice {
     pub port: u8,
     pub vendor_id: u16,
@@ -275,7 +414,8 @@ pub struct UsbControlAttachedDevice {
 }
 
 impl UsbControlAttachedDevice {
-    fn valid(self) -> bool {
+    /// Whether this slot of a `UsbControlResult::Devices` list holds a device.
+    pub fn valid(self) -> bool {
         self.port != 0
     }
 }
@@ -345,6 +485,7 @@ impl VmMemoryRequest {
     /// # Arguments
     /// * `vm` - The `Vm` to perform the request on.
     /// * `allocator` - Used to allocate addresses.
+    /// * `shared_memory` - Keeps track of the memory registered on behalf of devices.
     ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmMemoryResponse` with the intended purpose of sending the response back over the socket
@@ -355,23 +496,33 @@ impl VmMemoryRequest {
         sys_allocator: &mut SystemAllocator,
         map_request: Arc<Mutex<Option<ExternalMapping>>>,
         gralloc: &mut RutabagaGralloc,
+        shared_memory: &mut SharedMemoryRegions,
     ) -> VmMemoryResponse {
         use self::VmMemoryRequest::*;
         match *self {
             RegisterMemory(ref descriptor, size) => {
                 match register_memory(vm, sys_allocator, descriptor, size, None) {
-                    Ok((pfn, slot)) => VmMemoryResponse::RegisterMemory { pfn, slot },
+                    Ok((pfn, slot)) => {
+                        shared_memory.insert(slot, size as u64);
+                        VmMemoryResponse::RegisterMemory { pfn, slot }
+                    }
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
             RegisterFdAtPciBarOffset(alloc, ref descriptor, size, offset) => {
                 match register_memory(vm, sys_allocator, descriptor, size, Some((alloc, offset))) {
-                    Ok((pfn, slot)) => VmMemoryResponse::RegisterMemory { pfn, slot },
+                    Ok((pfn, slot)) => {
+                        shared_memory.insert(slot, size as u64);
+                        VmMemoryResponse::RegisterMemory { pfn, slot }
+                    }
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
             UnregisterMemory(slot) => match vm.remove_memory_region(slot) {
-                Ok(_) => VmMemoryResponse::Ok,
+                Ok(_) => {
+                    shared_memory.remove(slot);
+                    VmMemoryResponse::Ok
+                }
                 Err(e) => VmMemoryResponse::Err(e),
             },
             RegisterHostPointerAtPciBarOffset(alloc, offset) => {
@@ -381,8 +532,12 @@ impl VmMemoryRequest {
                     .ok_or_else(|| VmMemoryResponse::Err(SysError::new(EINVAL)))
                     .unwrap();
 
+                let size = mem.size() as u64;
                 match register_memory_hva(vm, sys_allocator, Box::new(mem), (alloc, offset)) {
-                    Ok((pfn, slot)) => VmMemoryResponse::RegisterMemory { pfn, slot },
+                    Ok((pfn, slot)) => {
+                        shared_memory.insert(slot, size);
+                        VmMemoryResponse::RegisterMemory { pfn, slot }
+                    }
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
@@ -435,18 +590,21 @@ impl VmMemoryRequest {
                     reqs.size as usize,
                     None,
                 ) {
-                    Ok((pfn, slot)) => VmMemoryResponse::AllocateAndRegisterGpuMemory {
-                        // Safe because ownership is transferred to SafeDescriptor via
-                        // into_raw_descriptor
-                        descriptor: MaybeOwnedDescriptor::Owned(unsafe {
-                            SafeDescriptor::from_raw_descriptor(
-                                handle.os_handle.into_raw_descriptor(),
-                            )
-                        }),
-                        pfn,
-                        slot,
-                        desc,
-                    },
+                    Ok((pfn, slot)) => {
+                        shared_memory.insert(slot, reqs.size);
+                        VmMemoryResponse::AllocateAndRegisterGpuMemory {
+                            // Safe because ownership is transferred to SafeDescriptor via
+                            // into_raw_descriptor
+                            descriptor: MaybeOwnedDescriptor::Owned(unsafe {
+                                SafeDescriptor::from_raw_descriptor(
+                                    handle.os_handle.into_raw_descriptor(),
+                                )
+                            }),
+                            pfn,
+                            slot,
+                            desc,
+                        }
+                    }
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
@@ -465,7 +623,10 @@ impl VmMemoryRequest {
                     Err(_e) => return VmMemoryResponse::Err(SysError::new(EINVAL)),
                 };
                 match vm.add_memory_region(GuestAddress(gpa), Box::new(mmap), false, false) {
-                    Ok(_) => VmMemoryResponse::Ok,
+                    Ok(slot) => {
+                        shared_memory.insert(slot, size as u64);
+                        VmMemoryResponse::Ok
+                    }
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
@@ -473,6 +634,218 @@ impl VmMemoryRequest {
     }
 }
 
+/// Bookkeeping of the host memory mapped into the guest on behalf of devices (e.g. gpu and wayland
+/// buffers), indexed by memory slot.
+#[derive(Default, Debug)]
+pub struct SharedMemoryRegions {
+    regions: BTreeMap<MemSlot, u64>,
+}
+
+impl SharedMemoryRegions {
+    /// Records that `size` bytes were mapped into the guest at `slot`.
+    pub fn insert(&mut self, slot: MemSlot, size: u64) {
+        self.regions.insert(slot, size);
+    }
+
+    /// Forgets the mapping at `slot`, if any.
+    pub fn remove(&mut self, slot: MemSlot) {
+        self.regions.remove(&slot);
+    }
+
+    /// Returns the total number of bytes currently mapped.
+    pub fn total_size(&self) -> u64 {
+        self.regions.values().sum()
+    }
+}
+
+/// Returns the resident set size in bytes of the current process, as reported by procfs.
+fn host_rss() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(resident_pages * pagesize() as u64)
+}
+
+/// Returns the bytes of memory of the current process that KSM merged with identical pages, as
+/// reported by procfs.
+fn ksm_merged() -> Option<u64> {
+    let merging_pages = fs::read_to_string("/proc/self/ksm_merging_pages").ok()?;
+    let merging_pages = merging_pages.trim().parse::<u64>().ok()?;
+    Some(merging_pages * pagesize() as u64)
+}
+
+/// Per-VM memory usage report combining host and guest side accounting.
+#[derive(MsgOnSocket, Debug, Default, Serialize)]
+pub struct VmMemoryStats {
+    /// Size of the guest memory the VM was started with.
+    pub guest_memory: u64,
+    /// Resident set size of the main crosvm process. Device processes are not included.
+    pub host_rss: Option<u64>,
+    /// Size of the balloon, as reported by the guest. Missing if the VM has no balloon or the
+    /// balloon didn't answer in time.
+    pub balloon_actual: Option<u64>,
+    /// Bytes of guest memory released to the host with `remove_range` by the balloon. Missing
+    /// along with `balloon_actual`.
+    pub balloon_removed: Option<u64>,
+    /// Host memory mapped into the guest on behalf of devices, e.g. gpu and wayland buffers.
+    pub shared_memory: u64,
+    /// Memory of the main crosvm process merged by KSM, which includes guest memory with
+    /// `--merge-guest-memory`. Each merged page shares its host memory with at least one other page.
+    /// Missing if the host kernel doesn't report it, which needs KSM and Linux 5.16 or later.
+    pub ksm_merged: Option<u64>,
+}
+
+impl VmMemoryStats {
+    /// Estimates the host memory that backs the guest: its memory minus what the balloon gave
+    /// back, plus the memory shared by devices.
+    pub fn guest_footprint(&self) -> u64 {
+        self.guest_memory
+            .saturating_sub(self.balloon_removed.unwrap_or(0))
+            .saturating_add(self.shared_memory)
+    }
+}
+
+impl Display for VmMemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        write!(f, "\n    guest_memory: {}", self.guest_memory)?;
+        if let Some(host_rss) = self.host_rss {
+            write!(f, "\n    host_rss: {}", host_rss)?;
+        }
+        if let Some(balloon_actual) = self.balloon_actual {
+            write!(f, "\n    balloon_actual: {}", balloon_actual)?;
+        }
+        if let Some(balloon_removed) = self.balloon_removed {
+            write!(f, "\n    balloon_removed: {}", balloon_removed)?;
+        }
+        write!(f, "\n    shared_memory: {}", self.shared_memory)?;
+        if let Some(ksm_merged) = self.ksm_merged {
+            write!(f, "\n    ksm_merged: {}", ksm_merged)?;
+        }
+        write!(f, "\n    guest_footprint: {}", self.guest_footprint())?;
+        write!(f, "\n}}")
+    }
+}
+
+/// Address space that a `MemoryMapEntry` was registered in.
+#[derive(MsgOnSocket, Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryMapBus {
+    Io,
+    Mmio,
+}
+
+impl Display for MemoryMapBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryMapBus::Io => write!(f, "io"),
+            MemoryMapBus::Mmio => write!(f, "mmio"),
+        }
+    }
+}
+
+/// A range of the IO or MMIO address space claimed by a device.
+#[derive(MsgOnSocket, Debug, Clone, PartialEq, Serialize)]
+pub struct MemoryMapEntry {
+    pub bus: MemoryMapBus,
+    pub base: u64,
+    pub len: u64,
+    /// Debug label of the device that owns the range.
+    pub device: String,
+}
+
+impl Display for MemoryMapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<4} {:#018x}-{:#018x} {:>#12x} {}",
+            self.bus,
+            self.base,
+            self.base.saturating_add(self.len).saturating_sub(1),
+            self.len,
+            self.device
+        )
+    }
+}
+
+/// The async executor backend used by a device, for `crosvm executor-status`.
+#[derive(MsgOnSocket, Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceExecutor {
+    /// Debug label of the device.
+    pub device: String,
+    /// Either "uring" or "poll".
+    pub backend: String,
+    /// Why the backend was picked.
+    pub reason: String,
+}
+
+impl Display for DeviceExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<24} {:<7} {}", self.device, self.backend, self.reason)
+    }
+}
+
+/// Counters kept by a device since the VM started, for `crosvm stats devices`.
+#[derive(MsgOnSocket, Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeviceStats {
+    /// Debug label of the device.
+    pub device: String,
+    /// Requests the device has completed.
+    pub requests: u64,
+    /// Bytes the device has moved for those requests.
+    pub bytes: u64,
+    /// Interrupts injected into the guest.
+    pub interrupts: u64,
+    /// Requests waiting in a queue when the device last looked.
+    pub queue_depth: u64,
+    /// Most requests ever seen waiting in a queue.
+    pub max_queue_depth: u64,
+}
+
+impl Display for DeviceStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} {:>10} {:>14} {:>10} {:>5} {:>5}",
+            self.device,
+            self.requests,
+            self.bytes,
+            self.interrupts,
+            self.queue_depth,
+            self.max_queue_depth
+        )
+    }
+}
+
+/// The connection of a vhost-user device to its backend, for `crosvm stats vhost-user`.
+#[derive(MsgOnSocket, Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VhostUserConnection {
+    /// Debug label of the device.
+    pub device: String,
+    /// The unix socket the backend listens on.
+    pub socket_path: String,
+    /// Whether the backend is connected, rather than being waited for to come back.
+    pub connected: bool,
+    /// How many times the device reconnected to a backend that came back.
+    pub reconnects: u64,
+}
+
+impl Display for VhostUserConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<24} {:<12} {:>10} {}",
+            self.device,
+            if self.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            self.reconnects,
+            self.socket_path
+        )
+    }
+}
+
 #[derive(MsgOnSocket, Debug)]
 pub enum VmMemoryResponse {
     /// The request to register memory into guest address space was successfully done at page frame
@@ -497,11 +870,13 @@ pub enum VmMemoryResponse {
 pub enum VmIrqRequest {
     /// Allocate one gsi, and associate gsi to irqfd with register_irqfd()
     AllocateOneMsi { irqfd: MaybeOwnedDescriptor },
-    /// Add one msi route entry into the IRQ chip.
+    /// Add one msi route entry into the IRQ chip. `msi_devid` is the PCI requester ID of the
+    /// device sending the MSI, if it has one.
     AddMsiRoute {
         gsi: u32,
         msi_address: u64,
         msi_data: u32,
+        msi_devid: Option<u32>,
     },
 }
 
@@ -554,12 +929,14 @@ impl VmIrqRequest {
                 gsi,
                 msi_address,
                 msi_data,
+                msi_devid,
             } => {
                 let route = IrqRoute {
                     gsi,
                     source: IrqSource::Msi {
                         address: msi_address,
                         data: msi_data,
+                        devid: msi_devid,
                     },
                 };
                 match set_up_irq(IrqSetup::Route(route)) {
@@ -925,9 +1302,17 @@ pub type BalloonControlResponseSocket = MsgSocket<BalloonControlResult, BalloonC
 pub type BatControlRequestSocket = MsgSocket<BatControlCommand, BatControlResult>;
 pub type BatControlResponseSocket = MsgSocket<BatControlResult, BatControlCommand>;
 
+pub type MemControlRequestSocket = MsgSocket<MemControlCommand, MemControlResult>;
+pub type MemControlResponseSocket = MsgSocket<MemControlResult, MemControlCommand>;
+
 pub type DiskControlRequestSocket = MsgSocket<DiskControlCommand, DiskControlResult>;
 pub type DiskControlResponseSocket = MsgSocket<DiskControlResult, DiskControlCommand>;
 
+// GPU commands are carried out asynchronously because the device may need the main process to
+// service its memory requests while handling them.
+pub type GpuControlRequestSocket = MsgSocket<GpuControlCommand, GpuControlResult>;
+pub type GpuControlResponseSocket = MsgSocket<GpuControlResult, GpuControlCommand>;
+
 pub type FsMappingRequestSocket = MsgSocket<FsMappingRequest, VmResponse>;
 pub type FsMappingResponseSocket = MsgSocket<VmResponse, FsMappingRequest>;
 
@@ -942,9 +1327,29 @@ pub type VmIrqResponseSocket = MsgSocket<VmIrqResponse, VmIrqRequest>;
 pub type VmMsyncRequestSocket = MsgSocket<VmMsyncRequest, VmMsyncResponse>;
 pub type VmMsyncResponseSocket = MsgSocket<VmMsyncResponse, VmMsyncRequest>;
 
+/// The version of the `VmRequest` and `VmResponse` messages, exchanged with `VmRequest::Hello`.
+///
+/// Messages are encoded by variant index, so variants must only ever be added at the end of these
+/// enums, and existing variants must keep their fields. Bump this when adding a request, so that a
+/// newer `crosvm` binary can tell whether a long-running VM process understands it.
+///
+/// Version 6 moved the variants added before versioning after the ones of unversioned VM
+/// processes, where they should have been from the start. `VmRequest::Hello` and the variants that
+/// follow it kept their indices. Version 7 added `VmMemoryStats::ksm_merged`.
+pub const VM_CONTROL_VERSION: u32 = 8;
+
 pub type VmControlRequestSocket = MsgSocket<VmRequest, VmResponse>;
 pub type VmControlResponseSocket = MsgSocket<VmResponse, VmRequest>;
 
+/// What a control socket client may ask of the VM, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VmRequestAccess {
+    /// Only requests that report on the VM without changing it.
+    ReadOnly,
+    /// Every request.
+    Full,
+}
+
 /// A request to the main process to perform some operation on the VM.
 ///
 /// Unless otherwise noted, each request should expect a `VmResponse::Ok` to be received on success.
@@ -968,6 +1373,88 @@ pub enum VmRequest {
     UsbCommand(UsbControlCommand),
     /// Command to set battery.
     BatCommand(BatteryType, BatControlCommand),
+    /// Command for the virtio-mem device.
+    MemCommand(MemControlCommand),
+    /// Command for the GPU device. `VmResponse::Ok` only indicates that the command was delivered.
+    GpuCommand(GpuControlCommand),
+    /// Report the memory usage of the VM.
+    MemoryStats,
+    /// List the IO and MMIO ranges registered by devices.
+    DumpMemoryMap,
+    /// List the async executor backend used by each device that has one.
+    ExecutorStatus,
+    /// Report the counters of every device that keeps them.
+    DeviceStats,
+    /// Hot-add or remove a VFIO PCI device.
+    VfioCommand(VfioCommand),
+    /// Ask for the protocol version of the VM process, answered by `VmResponse::Hello`. VM
+    /// processes that predate versioning don't answer at all.
+    Hello { version: u32 },
+    /// Hot-add or remove a VCPU, up to the maximum number of VCPUs the VM was started with.
+    VcpuCommand(VcpuCommand),
+    /// Write guest memory to `file` as a raw image, with the VCPUs paused. `range` is the guest
+    /// address and length of the memory to dump, or `None` for all of it.
+    DumpMemory {
+        file: File,
+        range: Option<(u64, u64)>,
+    },
+    /// Report whether each vhost-user device is connected to its backend.
+    VhostUserStatus,
+    /// Save the VM to a file or load it back, with the VCPUs paused.
+    Snapshot(SnapshotCommand),
+}
+
+impl VmRequest {
+    /// The `VM_CONTROL_VERSION` that introduced this request or the layout of its response, or 0
+    /// for requests that predate versioning.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            VmRequest::Exit
+            | VmRequest::Suspend
+            | VmRequest::Resume
+            | VmRequest::BalloonCommand(BalloonControlCommand::Adjust { .. })
+            | VmRequest::BalloonCommand(BalloonControlCommand::Stats)
+            | VmRequest::DiskCommand { .. }
+            | VmRequest::UsbCommand(UsbControlCommand::AttachDevice { .. })
+            | VmRequest::UsbCommand(UsbControlCommand::DetachDevice { .. })
+            | VmRequest::UsbCommand(UsbControlCommand::ListDevice { .. })
+            | VmRequest::BatCommand(..) => 0,
+            VmRequest::Hello { .. } => 1,
+            VmRequest::VcpuCommand(_) => 3,
+            VmRequest::DumpMemory { .. } => 4,
+            VmRequest::VhostUserStatus => 5,
+            VmRequest::BalloonCommand(BalloonControlCommand::Accounting)
+            | VmRequest::UsbCommand(UsbControlCommand::AttachDeviceById { .. })
+            | VmRequest::MemCommand(_)
+            | VmRequest::GpuCommand(_)
+            | VmRequest::DumpMemoryMap
+            | VmRequest::ExecutorStatus
+            | VmRequest::DeviceStats
+            | VmRequest::VfioCommand(_) => 6,
+            VmRequest::MemoryStats => 7,
+            VmRequest::Snapshot(_) => 8,
+        }
+    }
+
+    /// The access a client needs for this request. Guest memory dumps and snapshots need full
+    /// access, as they reveal the guest's contents.
+    pub fn required_access(&self) -> VmRequestAccess {
+        match self {
+            VmRequest::MemoryStats
+            | VmRequest::DumpMemoryMap
+            | VmRequest::ExecutorStatus
+            | VmRequest::DeviceStats
+            | VmRequest::VhostUserStatus
+            | VmRequest::Hello { .. }
+            | VmRequest::BalloonCommand(BalloonControlCommand::Stats)
+            | VmRequest::BalloonCommand(BalloonControlCommand::Accounting)
+            | VmRequest::GpuCommand(GpuControlCommand::ListDisplays)
+            | VmRequest::UsbCommand(UsbControlCommand::ListDevice { .. }) => {
+                VmRequestAccess::ReadOnly
+            }
+            _ => VmRequestAccess::Full,
+        }
+    }
 }
 
 fn register_memory(
@@ -1020,20 +1507,80 @@ fn register_memory_hva(
     Ok((addr >> 12, slot))
 }
 
+// Queries the balloon for its size and the memory it gave back to the host, in bytes. Unlike
+// stats, the balloon answers this without involving the guest, so don't wait for long if the
+// device isn't running yet. Other results that arrive first are added to `balloon_results`.
+fn balloon_accounting(
+    balloon_host_socket: &BalloonControlRequestSocket,
+    balloon_results: &mut Vec<BalloonControlResult>,
+) -> Option<(u64, u64)> {
+    if let Err(e) = balloon_host_socket.send(&BalloonControlCommand::Accounting) {
+        error!("balloon socket send failed: {}", e);
+        return None;
+    }
+    let socket = balloon_host_socket.as_ref();
+    if let Err(e) = socket.set_read_timeout(Some(BALLOON_ACCOUNTING_TIMEOUT)) {
+        error!("failed to set balloon socket timeout: {}", e);
+        return None;
+    }
+    let result = loop {
+        match balloon_host_socket.recv() {
+            Ok(BalloonControlResult::Accounting {
+                balloon_actual,
+                removed_bytes,
+            }) => break Some((balloon_actual, removed_bytes)),
+            // Stats requested by someone else, such as the memory balancing timer, or a request
+            // from the guest.
+            Ok(result) => balloon_results.push(result),
+            Err(e) => {
+                error!("balloon socket recv failed: {}", e);
+                break None;
+            }
+        }
+    };
+    if let Err(e) = socket.set_read_timeout(None) {
+        error!("failed to clear balloon socket timeout: {}", e);
+    }
+    result
+}
+
 impl VmRequest {
     /// Executes this request on the given Vm and other mutable state.
     ///
+    /// `balloon_results` collects the results from the balloon that arrive while waiting for the
+    /// result of the request, which the caller must handle as if it had received them itself.
+    /// `balloon_host_socket` is only present if the VM has a balloon device, and
+    /// `mem_host_socket` only if it has a virtio-mem device.
+    /// `memory_map` is only called for `DumpMemoryMap` and lists the ranges of every device bus.
+    /// `device_executors` is the answer to `ExecutorStatus`.
+    /// `device_stats` is only called for `DeviceStats` and reads the current device counters.
+    /// `vhost_user_status` is only called for `VhostUserStatus` and reads the current connections.
+    ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmResponse` with the intended purpose of sending the response back over the  socket that
     /// received this `VmRequest`.
-    pub fn execute(
+    pub fn execute<F, G, H>(
         &self,
         run_mode: &mut Option<VmRunMode>,
-        balloon_host_socket: &BalloonControlRequestSocket,
+        balloon_host_socket: Option<&BalloonControlRequestSocket>,
+        balloon_results: &mut Vec<BalloonControlResult>,
+        mem_host_socket: Option<&MemControlRequestSocket>,
         disk_host_sockets: &[DiskControlRequestSocket],
+        gpu_control_socket: &GpuControlRequestSocket,
         usb_control_socket: &UsbControlSocket,
         bat_control: &mut Option<BatControl>,
-    ) -> VmResponse {
+        guest_memory_size: u64,
+        shared_memory: &SharedMemoryRegions,
+        memory_map: F,
+        device_executors: &[DeviceExecutor],
+        device_stats: G,
+        vhost_user_status: H,
+    ) -> VmResponse
+    where
+        F: FnOnce() -> Vec<MemoryMapEntry>,
+        G: FnOnce() -> Vec<DeviceStats>,
+        H: FnOnce() -> Vec<VhostUserConnection>,
+    {
         match *self {
             VmRequest::Exit => {
                 *run_mode = Some(VmRunMode::Exiting);
@@ -1048,27 +1595,107 @@ impl VmRequest {
                 VmResponse::Ok
             }
             VmRequest::BalloonCommand(BalloonControlCommand::Adjust { num_bytes }) => {
+                let balloon_host_socket = match balloon_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
                 match balloon_host_socket.send(&BalloonControlCommand::Adjust { num_bytes }) {
                     Ok(_) => VmResponse::Ok,
                     Err(_) => VmResponse::Err(SysError::last()),
                 }
             }
+            VmRequest::BalloonCommand(BalloonControlCommand::Accounting) => {
+                let balloon_host_socket = match balloon_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                match balloon_accounting(balloon_host_socket, balloon_results) {
+                    Some((balloon_actual, removed_bytes)) => VmResponse::BalloonAccounting {
+                        balloon_actual,
+                        removed_bytes,
+                    },
+                    None => VmResponse::Err(SysError::new(EIO)),
+                }
+            }
+            VmRequest::MemoryStats => {
+                // Without a balloon, skip the query rather than wait out its timeout.
+                let balloon = balloon_host_socket
+                    .and_then(|socket| balloon_accounting(socket, balloon_results));
+                VmResponse::MemoryStats(VmMemoryStats {
+                    guest_memory: guest_memory_size,
+                    host_rss: host_rss(),
+                    balloon_actual: balloon.map(|(actual, _)| actual),
+                    balloon_removed: balloon.map(|(_, removed)| removed),
+                    shared_memory: shared_memory.total_size(),
+                    ksm_merged: ksm_merged(),
+                })
+            }
+            VmRequest::DumpMemoryMap => VmResponse::MemoryMap(memory_map()),
+            VmRequest::ExecutorStatus => VmResponse::ExecutorStatus(device_executors.to_vec()),
+            VmRequest::DeviceStats => VmResponse::DeviceStats(device_stats()),
+            VmRequest::VhostUserStatus => VmResponse::VhostUserStatus(vhost_user_status()),
+            // Hotplug changes the buses and the PCI root, so the control loop handles it before
+            // calling `execute`.
+            VmRequest::VfioCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            // The control loop owns the CPU hotplug device.
+            VmRequest::VcpuCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            // Pausing the VCPUs needs their handles, which only the control loop has.
+            VmRequest::DumpMemory { .. } => VmResponse::Err(SysError::new(ENOTSUP)),
+            VmRequest::Snapshot(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            VmRequest::Hello { .. } => VmResponse::Hello {
+                version: VM_CONTROL_VERSION,
+            },
+            VmRequest::MemCommand(ref command) => {
+                let sock = match mem_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                if let Err(e) = sock.send(command) {
+                    error!("virtio-mem socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(EIO));
+                }
+                match sock.recv() {
+                    Ok(MemControlResult::Resized {
+                        requested_bytes,
+                        plugged_bytes,
+                    }) => VmResponse::MemResized {
+                        requested_bytes,
+                        plugged_bytes,
+                    },
+                    Err(e) => {
+                        error!("virtio-mem socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EIO))
+                    }
+                }
+            }
             VmRequest::BalloonCommand(BalloonControlCommand::Stats) => {
-                match balloon_host_socket.send(&BalloonControlCommand::Stats {}) {
-                    Ok(_) => match balloon_host_socket.recv() {
+                let balloon_host_socket = match balloon_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                if balloon_host_socket
+                    .send(&BalloonControlCommand::Stats {})
+                    .is_err()
+                {
+                    return VmResponse::Err(SysError::last());
+                }
+                loop {
+                    match balloon_host_socket.recv() {
                         Ok(BalloonControlResult::Stats {
                             stats,
                             balloon_actual,
-                        }) => VmResponse::BalloonStats {
-                            stats,
-                            balloon_actual,
-                        },
+                        }) => {
+                            break VmResponse::BalloonStats {
+                                stats,
+                                balloon_actual,
+                            }
+                        }
+                        Ok(result) => balloon_results.push(result),
                         Err(e) => {
                             error!("balloon socket recv failed: {}", e);
-                            VmResponse::Err(SysError::last())
+                            break VmResponse::Err(SysError::last());
                         }
-                    },
-                    Err(_) => VmResponse::Err(SysError::last()),
+                    }
                 }
             }
             VmRequest::DiskCommand {
@@ -1094,6 +1721,19 @@ impl VmRequest {
                     VmResponse::Err(SysError::new(ENODEV))
                 }
             }
+            VmRequest::GpuCommand(ref command) => {
+                if let Err(e) = gpu_control_socket.send(command) {
+                    error!("gpu socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(ENODEV));
+                }
+                match gpu_control_socket.recv() {
+                    Ok(result) => VmResponse::GpuResponse(result),
+                    Err(e) => {
+                        error!("gpu socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EIO))
+                    }
+                }
+            }
             VmRequest::UsbCommand(ref cmd) => {
                 let res = usb_control_socket.send(cmd);
                 if let Err(e) = res {
@@ -1166,6 +1806,30 @@ pub enum VmResponse {
     UsbResponse(UsbControlResult),
     /// Results of battery control commands.
     BatResponse(BatControlResult),
+    /// Memory held by the balloon.
+    BalloonAccounting {
+        balloon_actual: u64,
+        removed_bytes: u64,
+    },
+    /// Result of a virtio-mem resize.
+    MemResized {
+        requested_bytes: u64,
+        plugged_bytes: u64,
+    },
+    /// Memory usage report of the VM.
+    MemoryStats(VmMemoryStats),
+    /// Ranges registered on the IO and MMIO buses, ordered by bus and base address.
+    MemoryMap(Vec<MemoryMapEntry>),
+    /// Async executor backend of each device that uses one.
+    ExecutorStatus(Vec<DeviceExecutor>),
+    /// Counters of each device that keeps them.
+    DeviceStats(Vec<DeviceStats>),
+    /// The `VM_CONTROL_VERSION` of the VM process.
+    Hello { version: u32 },
+    /// Results of gpu control commands.
+    GpuResponse(GpuControlResult),
+    /// Connection of each vhost-user device to its backend.
+    VhostUserStatus(Vec<VhostUserConnection>),
 }
 
 impl Display for VmResponse {
@@ -1193,8 +1857,63 @@ impl Display for VmResponse {
                 "balloon size: {}\nballoon stats: {}",
                 balloon_actual, stats
             ),
+            BalloonAccounting {
+                balloon_actual,
+                removed_bytes,
+            } => write!(
+                f,
+                "balloon size: {}\nballoon removed: {}",
+                balloon_actual, removed_bytes
+            ),
+            MemResized {
+                requested_bytes,
+                plugged_bytes,
+            } => write!(
+                f,
+                "virtio-mem requested size: {}\nvirtio-mem plugged size: {}",
+                requested_bytes, plugged_bytes
+            ),
+            MemoryStats(stats) => write!(f, "memory stats: {}", stats),
+            MemoryMap(entries) => {
+                write!(f, "bus  {:<37} {:>12} device", "range", "length")?;
+                for entry in entries {
+                    write!(f, "\n{}", entry)?;
+                }
+                std::result::Result::Ok(())
+            }
+            ExecutorStatus(executors) => {
+                write!(f, "{:<24} {:<7} reason", "device", "backend")?;
+                for executor in executors {
+                    write!(f, "\n{}", executor)?;
+                }
+                std::result::Result::Ok(())
+            }
+            DeviceStats(stats) => {
+                write!(
+                    f,
+                    "{:<24} {:>10} {:>14} {:>10} {:>5} {:>5}",
+                    "device", "requests", "bytes", "interrupts", "depth", "max"
+                )?;
+                for device in stats {
+                    write!(f, "\n{}", device)?;
+                }
+                std::result::Result::Ok(())
+            }
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
             BatResponse(result) => write!(f, "{}", result),
+            Hello { version } => write!(f, "control protocol version {}", version),
+            GpuResponse(result) => write!(f, "{}", result),
+            VhostUserStatus(connections) => {
+                write!(
+                    f,
+                    "{:<24} {:<12} {:>10} socket",
+                    "device", "state", "reconnects"
+                )?;
+                for connection in connections {
+                    write!(f, "\n{}", connection)?;
+                }
+                std::result::Result::Ok(())
+            }
         }
     }
 }
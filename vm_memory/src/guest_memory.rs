@@ -4,10 +4,12 @@
 
 //! Track memory regions that are mapped to the guest VM.
 
-use std::convert::AsRef;
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::mem::size_of;
+use std::os::unix::fs::FileExt;
 use std::result;
 use std::sync::Arc;
 
@@ -15,7 +17,7 @@ use crate::guest_address::GuestAddress;
 use base::{pagesize, Error as SysError};
 use base::{
     AsRawDescriptor, MappedRegion, MemfdSeals, MemoryMapping, MemoryMappingBuilder,
-    MemoryMappingUnix, MmapError, RawDescriptor, SharedMemory, SharedMemoryUnix,
+    MemoryMappingUnix, MmapError, SharedMemory, SharedMemoryUnix,
 };
 use cros_async::{
     uring_mem::{self, BorrowedIoVec},
@@ -27,16 +29,22 @@ use data_model::DataInit;
 #[derive(Debug)]
 pub enum Error {
     DescriptorChainOverflow,
+    DumpWrite(io::Error),
     InvalidGuestAddress(GuestAddress),
     MemoryAccess(GuestAddress, MmapError),
     MemoryMappingFailed(MmapError),
     MemoryRegionOverlap,
+    MemoryRegionOutsideShm { size: u64, offset: u64 },
     MemoryRegionTooLarge(u64),
     MemoryNotAligned,
+    MemoryMergeFailed(MmapError),
     MemoryCreationFailed(SysError),
     MemoryAddSealsFailed(SysError),
     ShortWrite { expected: usize, completed: usize },
     ShortRead { expected: usize, completed: usize },
+    SnapshotLayoutMismatch,
+    SnapshotRead(io::Error),
+    SnapshotWrite(io::Error),
     SplitOutOfBounds(usize),
     VolatileMemoryAccess(VolatileMemoryError),
 }
@@ -53,14 +61,21 @@ impl Display for Error {
                 f,
                 "the combined length of all the buffers in a DescriptorChain is too large"
             ),
+            DumpWrite(e) => write!(f, "failed to write guest memory dump: {}", e),
             InvalidGuestAddress(addr) => write!(f, "invalid guest address {}", addr),
             MemoryAccess(addr, e) => {
                 write!(f, "invalid guest memory access at addr={}: {}", addr, e)
             }
             MemoryMappingFailed(e) => write!(f, "failed to map guest memory: {}", e),
             MemoryRegionOverlap => write!(f, "memory regions overlap"),
+            MemoryRegionOutsideShm { size, offset } => write!(
+                f,
+                "memory region of {} bytes at offset {} ends past the shm",
+                size, offset
+            ),
             MemoryRegionTooLarge(size) => write!(f, "memory region size {} is too large", size),
             MemoryNotAligned => write!(f, "shm regions must be page aligned"),
+            MemoryMergeFailed(e) => write!(f, "failed to let KSM merge guest memory: {}", e),
             MemoryCreationFailed(_) => write!(f, "failed to create shm region"),
             MemoryAddSealsFailed(e) => write!(f, "failed to set seals on shm region: {}", e),
             ShortWrite {
@@ -79,16 +94,25 @@ impl Display for Error {
                 "incomplete read of {} instead of {} bytes",
                 completed, expected,
             ),
+            SnapshotLayoutMismatch => {
+                write!(f, "snapshot regions don't match the guest memory regions")
+            }
+            SnapshotRead(e) => write!(f, "failed to read guest memory snapshot: {}", e),
+            SnapshotWrite(e) => write!(f, "failed to write guest memory snapshot: {}", e),
             SplitOutOfBounds(off) => write!(f, "DescriptorChain split is out of bounds: {}", off),
             VolatileMemoryAccess(e) => e.fmt(f),
         }
     }
 }
 
+// How much guest memory `snapshot` and `restore` copy at a time.
+const SNAPSHOT_CHUNK_SIZE: usize = 1 << 20;
+
 struct MemoryRegion {
     mapping: MemoryMapping,
     guest_base: GuestAddress,
     memfd_offset: u64,
+    read_only: bool,
 }
 
 impl MemoryRegion {
@@ -108,36 +132,22 @@ impl MemoryRegion {
 
 /// Tracks a memory region and where it is mapped in the guest, along with a shm
 /// fd of the underlying memory regions.
+///
+/// The shm is a memfd sealed against shrinking and growing, so other processes that are handed
+/// its fd, such as vhost-user backends, can map guest memory without the mapping being truncated
+/// from under them. Use `with_regions` to find where each region lives in the fd. Guest memory
+/// made by `new_mergeable` has no shm.
 #[derive(Clone)]
 pub struct GuestMemory {
     regions: Arc<[MemoryRegion]>,
-    shm: Arc<SharedMemory>,
-}
-
-impl AsRawDescriptor for GuestMemory {
-    fn as_raw_descriptor(&self) -> RawDescriptor {
-        self.shm.as_raw_descriptor()
-    }
-}
-
-impl AsRef<SharedMemory> for GuestMemory {
-    fn as_ref(&self) -> &SharedMemory {
-        &self.shm
-    }
+    shm: Option<Arc<SharedMemory>>,
 }
 
 impl GuestMemory {
     /// Creates backing shm for GuestMemory regions
     fn create_memfd(ranges: &[(GuestAddress, u64)]) -> Result<SharedMemory> {
-        let mut aligned_size = 0;
-        let pg_size = pagesize();
-        for range in ranges {
-            if range.1 % pg_size as u64 != 0 {
-                return Err(Error::MemoryNotAligned);
-            }
-
-            aligned_size += range.1;
-        }
+        GuestMemory::check_aligned(ranges)?;
+        let aligned_size = ranges.iter().map(|range| range.1).sum();
 
         let mut seals = MemfdSeals::new();
 
@@ -152,49 +162,145 @@ impl GuestMemory {
         Ok(shm)
     }
 
+    fn check_aligned(ranges: &[(GuestAddress, u64)]) -> Result<()> {
+        let pg_size = pagesize() as u64;
+        if ranges.iter().any(|range| range.1 % pg_size != 0) {
+            return Err(Error::MemoryNotAligned);
+        }
+        Ok(())
+    }
+
+    fn check_read_only(ranges: &[(GuestAddress, u64)], read_only: &[GuestAddress]) -> Result<()> {
+        match read_only
+            .iter()
+            .find(|&&addr| !ranges.iter().any(|range| range.0 == addr))
+        {
+            Some(&addr) => Err(Error::InvalidGuestAddress(addr)),
+            None => Ok(()),
+        }
+    }
+
+    // Lays out the regions back to back in the shm, returning their (Address, Size, Offset).
+    fn back_to_back(ranges: &[(GuestAddress, u64)]) -> Vec<(GuestAddress, u64, u64)> {
+        let mut offset = 0;
+        ranges
+            .iter()
+            .map(|&(addr, size)| {
+                let range = (addr, size, offset);
+                offset += size;
+                range
+            })
+            .collect()
+    }
+
     /// Creates a container for guest memory regions.
     /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
     pub fn new(ranges: &[(GuestAddress, u64)]) -> Result<GuestMemory> {
+        GuestMemory::new_with_read_only(ranges, &[])
+    }
+
+    /// Creates a container for guest memory regions like `new`, except that the guest may only
+    /// read the regions starting at the addresses in `read_only`. crosvm can still write them, so
+    /// they can be loaded with firmware after the VM is created.
+    pub fn new_with_read_only(
+        ranges: &[(GuestAddress, u64)],
+        read_only: &[GuestAddress],
+    ) -> Result<GuestMemory> {
+        GuestMemory::check_read_only(ranges, read_only)?;
+
         // Create shm
 
         let shm = GuestMemory::create_memfd(ranges)?;
-        // Create memory regions
-        let mut regions = Vec::<MemoryRegion>::new();
-        let mut offset = 0;
+        GuestMemory::map_regions(Some(shm), &GuestMemory::back_to_back(ranges), read_only)
+    }
+
+    /// Creates a container for guest memory regions like `new_with_read_only`, except that the
+    /// regions are private anonymous memory, which the host's KSM may merge with identical pages.
+    /// Without a shm, the memory can't be shared with other processes, such as jailed devices or
+    /// vhost-user backends.
+    pub fn new_mergeable(
+        ranges: &[(GuestAddress, u64)],
+        read_only: &[GuestAddress],
+    ) -> Result<GuestMemory> {
+        GuestMemory::check_read_only(ranges, read_only)?;
+        GuestMemory::check_aligned(ranges)?;
+        GuestMemory::map_regions(None, &GuestMemory::back_to_back(ranges), read_only)
+    }
 
-        for range in ranges {
+    /// Creates a container for guest memory regions backed by an existing `shm`, such as guest
+    /// memory shared by another process. Regions are specified as (Address, Size, Offset in `shm`)
+    /// tuples sorted by Address.
+    pub fn from_shared_memory(
+        shm: SharedMemory,
+        ranges: &[(GuestAddress, u64, u64)],
+    ) -> Result<GuestMemory> {
+        GuestMemory::map_regions(Some(shm), ranges, &[])
+    }
+
+    // Maps `ranges` of `shm`, or of private memory if there is no `shm`.
+    fn map_regions(
+        shm: Option<SharedMemory>,
+        ranges: &[(GuestAddress, u64, u64)],
+        read_only: &[GuestAddress],
+    ) -> Result<GuestMemory> {
+        let mut regions = Vec::<MemoryRegion>::new();
+        for &(guest_base, size, offset) in ranges {
             if let Some(last) = regions.last() {
                 if last
                     .guest_base
                     .checked_add(last.mapping.size() as u64)
-                    .map_or(true, |a| a > range.0)
+                    .map_or(true, |a| a > guest_base)
                 {
                     return Err(Error::MemoryRegionOverlap);
                 }
             }
 
-            let size =
-                usize::try_from(range.1).map_err(|_| Error::MemoryRegionTooLarge(range.1))?;
-            let mapping = MemoryMappingBuilder::new(size)
-                .from_descriptor(&shm)
-                .offset(offset)
-                .build()
-                .map_err(Error::MemoryMappingFailed)?;
+            let mapping_size =
+                usize::try_from(size).map_err(|_| Error::MemoryRegionTooLarge(size))?;
+            let mapping = match &shm {
+                Some(shm) => {
+                    if offset
+                        .checked_add(size)
+                        .map_or(true, |end| end > shm.size())
+                    {
+                        return Err(Error::MemoryRegionOutsideShm { size, offset });
+                    }
+                    MemoryMappingBuilder::new(mapping_size)
+                        .from_descriptor(shm)
+                        .offset(offset)
+                        .build()
+                        .map_err(Error::MemoryMappingFailed)?
+                }
+                None => {
+                    let mapping = MemoryMappingBuilder::new(mapping_size)
+                        .private()
+                        .build()
+                        .map_err(Error::MemoryMappingFailed)?;
+                    mapping
+                        .set_mergeable(0, mapping_size)
+                        .map_err(Error::MemoryMergeFailed)?;
+                    mapping
+                }
+            };
             regions.push(MemoryRegion {
                 mapping,
-                guest_base: range.0,
+                guest_base,
                 memfd_offset: offset,
+                read_only: read_only.contains(&guest_base),
             });
-
-            offset += size as u64;
         }
 
         Ok(GuestMemory {
             regions: Arc::from(regions),
-            shm: Arc::new(shm),
+            shm: shm.map(Arc::new),
         })
     }
 
+    /// Returns the memfd backing guest memory, or `None` for memory made by `new_mergeable`.
+    pub fn shm(&self) -> Option<&SharedMemory> {
+        self.shm.as_deref()
+    }
+
     /// Returns the end address of memory.
     ///
     /// # Examples
@@ -237,6 +343,14 @@ impl GuestMemory {
             .any(|region| region.start() < end && start < region.end())
     }
 
+    /// Returns true if `addr` is in a region the guest may only read. The hypervisor turns the
+    /// guest's writes to such regions into MMIO exits.
+    pub fn is_read_only(&self, addr: GuestAddress) -> bool {
+        self.regions
+            .iter()
+            .any(|region| region.read_only && region.contains(addr))
+    }
+
     /// Returns the address plus the offset if it is in range.
     pub fn checked_offset(&self, addr: GuestAddress, offset: u64) -> Option<GuestAddress> {
         addr.checked_add(offset).and_then(|a| {
@@ -255,13 +369,38 @@ impl GuestMemory {
 
     /// Madvise away the address range in the host that is associated with the given guest range.
     pub fn remove_range(&self, addr: GuestAddress, count: u64) -> Result<()> {
+        let private = self.shm.is_none();
+        self.do_in_region(addr, move |mapping, offset| {
+            if private {
+                mapping.discard_range(offset, count as usize)
+            } else {
+                mapping.remove_range(offset, count as usize)
+            }
+            .map_err(|e| Error::MemoryAccess(addr, e))
+        })
+    }
+
+    /// Allocates the host memory backing the given guest range from host NUMA node `node` only.
+    /// The range must not span more than one region.
+    pub fn bind_to_numa_node(&self, addr: GuestAddress, count: u64, node: u32) -> Result<()> {
         self.do_in_region(addr, move |mapping, offset| {
             mapping
-                .remove_range(offset, count as usize)
+                .bind_to_numa_node(offset, count as usize, node)
                 .map_err(|e| Error::MemoryAccess(addr, e))
         })
     }
 
+    /// Locks all of guest memory in host memory, so none of it is ever paged out.
+    pub fn mlock(&self) -> Result<()> {
+        for region in self.regions.iter() {
+            region
+                .mapping
+                .mlock(0, region.mapping.size())
+                .map_err(|e| Error::MemoryAccess(region.start(), e))?;
+        }
+        Ok(())
+    }
+
     /// Perform the specified action on each region's addresses.
     ///
     /// Callback is called with arguments:
@@ -286,6 +425,83 @@ impl GuestMemory {
         Ok(())
     }
 
+    /// Writes the contents of guest memory to `w`. Each region is written as its guest address
+    /// and size, both little endian `u64`s, followed by its contents.
+    pub fn snapshot<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE];
+        for region in self.regions.iter() {
+            let size = region.mapping.size();
+            w.write_all(&region.start().offset().to_le_bytes())
+                .and_then(|_| w.write_all(&(size as u64).to_le_bytes()))
+                .map_err(Error::SnapshotWrite)?;
+            let mut offset = 0;
+            while offset < size {
+                let len = SNAPSHOT_CHUNK_SIZE.min(size - offset);
+                region
+                    .mapping
+                    .read_slice(&mut buf[..len], offset)
+                    .map_err(|e| Error::MemoryAccess(region.start(), e))?;
+                w.write_all(&buf[..len]).map_err(Error::SnapshotWrite)?;
+                offset += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads guest memory from a snapshot written by `snapshot`. The snapshot must come from guest
+    /// memory with exactly the same regions.
+    pub fn restore<R: Read>(&self, r: &mut R) -> Result<()> {
+        let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE];
+        for region in self.regions.iter() {
+            let mut header = [0u8; 16];
+            r.read_exact(&mut header).map_err(Error::SnapshotRead)?;
+            let (snapshot_addr, snapshot_size) = header.split_at(8);
+            let size = region.mapping.size();
+            if u64::from_le_bytes(<[u8; 8]>::try_from(snapshot_addr).unwrap())
+                != region.start().offset()
+                || u64::from_le_bytes(<[u8; 8]>::try_from(snapshot_size).unwrap()) != size as u64
+            {
+                return Err(Error::SnapshotLayoutMismatch);
+            }
+            let mut offset = 0;
+            while offset < size {
+                let len = SNAPSHOT_CHUNK_SIZE.min(size - offset);
+                r.read_exact(&mut buf[..len]).map_err(Error::SnapshotRead)?;
+                region
+                    .mapping
+                    .write_slice(&buf[..len], offset)
+                    .map_err(|e| Error::MemoryAccess(region.start(), e))?;
+                offset += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the guest memory from `start` up to `end` to `file` as a raw image, where the byte
+    /// at guest address `start + n` goes to offset `n` of the file. Addresses outside of guest
+    /// memory are left as holes in the file.
+    pub fn dump(&self, file: &File, start: GuestAddress, end: GuestAddress) -> Result<()> {
+        file.set_len(end.offset_from(start))
+            .map_err(Error::DumpWrite)?;
+        let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE];
+        for region in self.regions.iter() {
+            let dump_start = region.start().max(start);
+            let dump_end = region.end().min(end);
+            let mut addr = dump_start;
+            while addr < dump_end {
+                let len = (SNAPSHOT_CHUNK_SIZE as u64).min(dump_end.offset_from(addr)) as usize;
+                region
+                    .mapping
+                    .read_slice(&mut buf[..len], addr.offset_from(region.start()) as usize)
+                    .map_err(|e| Error::MemoryAccess(addr, e))?;
+                file.write_all_at(&buf[..len], addr.offset_from(start))
+                    .map_err(Error::DumpWrite)?;
+                addr = addr.unchecked_add(len as u64);
+            }
+        }
+        Ok(())
+    }
+
     /// Writes a slice to guest memory at the specified guest address.
     /// Returns the number of bytes written.  The number of bytes written can
     /// be less than the length of the slice if there isn't enough room in the
@@ -690,6 +906,8 @@ unsafe impl BackingMemory for GuestMemory {
 mod tests {
     use super::*;
     use base::kernel_has_memfd;
+    use std::fs;
+    use std::path::Path;
 
     #[test]
     fn test_alignment() {
@@ -700,6 +918,32 @@ mod tests {
         assert!(GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).is_ok());
     }
 
+    #[test]
+    fn snapshot_restore() {
+        let ranges = [(GuestAddress(0x0), 0x1000), (GuestAddress(0x4000), 0x2000)];
+        let gm = GuestMemory::new(&ranges).unwrap();
+        gm.write_obj_at_addr(0x1234_5678u32, GuestAddress(0x10))
+            .unwrap();
+        gm.write_obj_at_addr(0xdead_beefu32, GuestAddress(0x5ff0))
+            .unwrap();
+        let mut snapshot = Vec::new();
+        gm.snapshot(&mut snapshot).unwrap();
+        assert_eq!(snapshot.len(), 2 * 16 + 0x3000);
+
+        let restored = GuestMemory::new(&ranges).unwrap();
+        restored.restore(&mut &snapshot[..]).unwrap();
+        let val: u32 = restored.read_obj_from_addr(GuestAddress(0x10)).unwrap();
+        assert_eq!(val, 0x1234_5678);
+        let val: u32 = restored.read_obj_from_addr(GuestAddress(0x5ff0)).unwrap();
+        assert_eq!(val, 0xdead_beef);
+
+        let other = GuestMemory::new(&[(GuestAddress(0x0), 0x3000)]).unwrap();
+        assert!(matches!(
+            other.restore(&mut &snapshot[..]),
+            Err(Error::SnapshotLayoutMismatch)
+        ));
+    }
+
     #[test]
     fn two_regions() {
         let start_addr1 = GuestAddress(0x0);
@@ -714,6 +958,46 @@ mod tests {
         assert!(GuestMemory::new(&[(start_addr1, 0x2000), (start_addr2, 0x2000)]).is_err());
     }
 
+    #[test]
+    fn read_only_regions() {
+        let ranges = [(GuestAddress(0x0), 0x4000), (GuestAddress(0x8000), 0x2000)];
+        let gm = GuestMemory::new_with_read_only(&ranges, &[GuestAddress(0x8000)]).unwrap();
+        assert!(!gm.is_read_only(GuestAddress(0x1000)));
+        assert!(gm.is_read_only(GuestAddress(0x9fff)));
+        assert!(!gm.is_read_only(GuestAddress(0xa000)));
+        // crosvm itself can still load the region.
+        gm.write_obj_at_addr(0x55u8, GuestAddress(0x8000)).unwrap();
+        assert!(GuestMemory::new_with_read_only(&ranges, &[GuestAddress(0x1000)]).is_err());
+    }
+
+    #[test]
+    fn from_shared_memory() {
+        let file: File = SharedMemory::anon(0x3000).unwrap().into();
+        let other = file.try_clone().unwrap();
+        let gm = GuestMemory::from_shared_memory(
+            SharedMemory::from_file(file).unwrap(),
+            &[(GuestAddress(0x0), 0x3000, 0)],
+        )
+        .unwrap();
+        let shared = GuestMemory::from_shared_memory(
+            SharedMemory::from_file(other).unwrap(),
+            &[(GuestAddress(0x10000), 0x1000, 0x2000)],
+        )
+        .unwrap();
+        gm.write_obj_at_addr(0x55u8, GuestAddress(0x2000)).unwrap();
+        assert_eq!(
+            shared
+                .read_obj_from_addr::<u8>(GuestAddress(0x10000))
+                .unwrap(),
+            0x55
+        );
+
+        let shm = SharedMemory::anon(0x1000).unwrap();
+        assert!(
+            GuestMemory::from_shared_memory(shm, &[(GuestAddress(0x0), 0x1000, 0x1000)]).is_err()
+        );
+    }
+
     #[test]
     fn region_hole() {
         let start_addr1 = GuestAddress(0x0);
@@ -833,6 +1117,92 @@ mod tests {
         assert!(mem.get_host_address(bad_addr).is_err());
     }
 
+    #[test]
+    fn dump() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x3000), 0x1000)])
+            .unwrap();
+        gm.write_obj_at_addr(0x11u8, GuestAddress(0x800)).unwrap();
+        gm.write_obj_at_addr(0x22u8, GuestAddress(0x3010)).unwrap();
+
+        let file = tempfile::tempfile().unwrap();
+        gm.dump(&file, GuestAddress(0x0), gm.end_addr()).unwrap();
+        let mut image = Vec::new();
+        (&file).read_to_end(&mut image).unwrap();
+        assert_eq!(image.len(), 0x4000);
+        assert_eq!(image[0x800], 0x11);
+        assert_eq!(image[0x3010], 0x22);
+        assert!(image[0x1000..0x3000].iter().all(|&b| b == 0));
+
+        let file = tempfile::tempfile().unwrap();
+        gm.dump(&file, GuestAddress(0x800), GuestAddress(0x3020))
+            .unwrap();
+        let mut image = Vec::new();
+        (&file).read_to_end(&mut image).unwrap();
+        assert_eq!(image.len(), 0x2820);
+        assert_eq!(image[0x0], 0x11);
+        assert_eq!(image[0x2810], 0x22);
+    }
+
+    #[test]
+    fn memfd_seals() {
+        if !kernel_has_memfd() {
+            return;
+        }
+
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x10000), 0x2000)])
+            .unwrap();
+        let shm = gm.shm().unwrap();
+        assert_eq!(shm.size(), 0x3000);
+        let seals = shm.get_seals().unwrap();
+        assert!(seals.shrink_seal());
+        assert!(seals.grow_seal());
+        assert!(seals.seal_seal());
+        // The contents must stay writable by whoever maps the fd.
+        assert!(!seals.write_seal());
+    }
+
+    #[test]
+    fn mergeable() {
+        if !Path::new("/sys/kernel/mm/ksm").exists() {
+            return;
+        }
+
+        let gm = GuestMemory::new_mergeable(&[(GuestAddress(0x0), 0x4000)], &[]).unwrap();
+        assert!(gm.shm().is_none());
+
+        // KSM only scans the mappings with the `mg` flag, which shared mappings never get.
+        let host_addr = gm.get_host_address(GuestAddress(0x0)).unwrap() as u64;
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut in_mapping = false;
+        let mut vm_flags = None;
+        for line in smaps.lines() {
+            let range = line.split_whitespace().next().and_then(|r| {
+                let (start, end) = r.split_at(r.find('-')?);
+                Some((
+                    u64::from_str_radix(start, 16).ok()?,
+                    u64::from_str_radix(&end[1..], 16).ok()?,
+                ))
+            });
+            if let Some((start, end)) = range {
+                in_mapping = start <= host_addr && host_addr < end;
+            } else if in_mapping && line.starts_with("VmFlags:") {
+                vm_flags = Some(line.to_string());
+            }
+        }
+        assert!(vm_flags
+            .unwrap()
+            .split_whitespace()
+            .any(|flag| flag == "mg"));
+
+        // Removed ranges of private memory read as zeros too.
+        gm.write_obj_at_addr(0x55u8, GuestAddress(0x1000)).unwrap();
+        gm.remove_range(GuestAddress(0x1000), 0x1000).unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(0x1000)).unwrap(),
+            0
+        );
+    }
+
     #[test]
     fn memfd_offset() {
         if !kernel_has_memfd() {
@@ -852,7 +1222,7 @@ mod tests {
 
         let _ = gm.with_regions::<_, ()>(|index, _, size, _, memfd_offset| {
             let mmap = MemoryMappingBuilder::new(size)
-                .from_descriptor(gm.as_ref())
+                .from_descriptor(gm.shm().unwrap())
                 .offset(memfd_offset)
                 .build()
                 .unwrap();
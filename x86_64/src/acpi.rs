@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 use acpi_tables::{rsdp::RSDP, sdt::SDT};
+use arch::numa::{numa_distance, NumaNode};
 use data_model::DataInit;
 use vm_memory::{GuestAddress, GuestMemory};
 
@@ -39,6 +40,53 @@ struct IOAPIC {
 // Safe as IOAPIC structure only contains raw data
 unsafe impl DataInit for IOAPIC {}
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct MCFGEntry {
+    _base_address: u64,
+    _segment_group: u16,
+    _start_bus: u8,
+    _end_bus: u8,
+    _reserved: u32,
+}
+
+// Safe as MCFGEntry structure only contains raw data
+unsafe impl DataInit for MCFGEntry {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ProcessorAffinity {
+    _type: u8,
+    _length: u8,
+    _proximity_domain_lo: u8,
+    _apic_id: u8,
+    _flags: u32,
+    _local_sapic_eid: u8,
+    _proximity_domain_hi: [u8; 3],
+    _clock_domain: u32,
+}
+
+// Safe as ProcessorAffinity structure only contains raw data
+unsafe impl DataInit for ProcessorAffinity {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct MemoryAffinity {
+    _type: u8,
+    _length: u8,
+    _proximity_domain_lo: u16,
+    _proximity_domain_hi: u16,
+    _reserved1: u16,
+    _base_address: u64,
+    _length_bytes: u64,
+    _reserved2: u32,
+    _flags: u32,
+    _reserved3: u64,
+}
+
+// Safe as MemoryAffinity structure only contains raw data
+unsafe impl DataInit for MemoryAffinity {}
+
 const OEM_REVISION: u32 = 1;
 //DSDT
 const DSDT_REVISION: u8 = 6;
@@ -69,6 +117,22 @@ const MADT_TYPE_LOCAL_APIC: u8 = 0;
 const MADT_TYPE_IO_APIC: u8 = 1;
 // MADT flags
 const MADT_ENABLED: u32 = 1;
+const MADT_ONLINE_CAPABLE: u32 = 2;
+// MCFG
+const MCFG_LEN: u32 = 44;
+const MCFG_REVISION: u8 = 1;
+// SRAT
+const SRAT_LEN: u32 = 48;
+const SRAT_REVISION: u8 = 3;
+// SRAT fields offset
+const SRAT_FIELD_TABLE_REVISION: usize = 36;
+// SRAT types
+const SRAT_TYPE_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_TYPE_MEMORY_AFFINITY: u8 = 1;
+// SRAT flags
+const SRAT_ENABLED: u32 = 1;
+// SLIT
+const SLIT_REVISION: u8 = 1;
 // XSDT
 const XSDT_REVISION: u8 = 1;
 
@@ -89,21 +153,88 @@ fn create_dsdt_table(amls: Vec<u8>) -> SDT {
     dsdt
 }
 
+// Creates the SRAT, which puts each VCPU and range of memory in its NUMA node.
+fn create_srat_table(numa_nodes: &[NumaNode], numa_ranges: &[Vec<(GuestAddress, u64)>]) -> SDT {
+    let mut srat = SDT::new(
+        *b"SRAT",
+        SRAT_LEN,
+        SRAT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    // Reserved, but must be 1 for compatibility.
+    srat.write(SRAT_FIELD_TABLE_REVISION, 1u32);
+
+    for (node, (numa_node, ranges)) in numa_nodes.iter().zip(numa_ranges).enumerate() {
+        for &cpu in &numa_node.cpus {
+            srat.append(ProcessorAffinity {
+                _type: SRAT_TYPE_PROCESSOR_AFFINITY,
+                _length: std::mem::size_of::<ProcessorAffinity>() as u8,
+                _proximity_domain_lo: node as u8,
+                _apic_id: cpu as u8,
+                _flags: SRAT_ENABLED,
+                ..Default::default()
+            });
+        }
+        for &(start, size) in ranges {
+            srat.append(MemoryAffinity {
+                _type: SRAT_TYPE_MEMORY_AFFINITY,
+                _length: std::mem::size_of::<MemoryAffinity>() as u8,
+                _proximity_domain_lo: node as u16,
+                _base_address: start.offset(),
+                _length_bytes: size,
+                _flags: SRAT_ENABLED,
+                ..Default::default()
+            });
+        }
+    }
+
+    srat
+}
+
+// Creates the SLIT, which holds the distances between the NUMA nodes.
+fn create_slit_table(numa_nodes: &[NumaNode]) -> SDT {
+    let mut slit = SDT::new(
+        *b"SLIT",
+        acpi_tables::HEADER_LEN,
+        SLIT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    slit.append(numa_nodes.len() as u64);
+    for from in 0..numa_nodes.len() {
+        for to in 0..numa_nodes.len() {
+            slit.append(numa_distance(numa_nodes, from, to));
+        }
+    }
+
+    slit
+}
+
 /// Create ACPI tables and return the RSDP.
-/// The basic tables DSDT/FACP/MADT/XSDT are constructed in this function.
+/// The basic tables DSDT/FACP/MADT/MCFG/XSDT are constructed in this function.
 /// # Arguments
 ///
 /// * `guest_mem` - The guest memory where the tables will be stored.
 /// * `num_cpus` - Used to construct the MADT.
+/// * `max_cpus` - The number of CPUs once CPUs are hot-added. The MADT lists the CPUs past
+///                `num_cpus` as disabled but online capable.
 /// * `sci_irq` - Used to fill the FACP SCI_INTERRUPT field, which
 ///               is going to be used by the ACPI drivers to register
 ///               sci handler.
 /// * `acpi_dev_resource` - resouces needed by the ACPI devices for creating tables
+/// * `numa_nodes` - The guest NUMA nodes, described by a SRAT and SLIT if there are any.
+/// * `numa_ranges` - The guest memory of each NUMA node.
 pub fn create_acpi_tables(
     guest_mem: &GuestMemory,
     num_cpus: u8,
+    max_cpus: u8,
     sci_irq: u32,
     acpi_dev_resource: ACPIDevResource,
+    numa_nodes: &[NumaNode],
+    numa_ranges: &[Vec<(GuestAddress, u64)>],
 ) -> Option<GuestAddress> {
     // RSDP is at the HI RSDP WINDOW
     let rsdp_offset = GuestAddress(super::ACPI_HI_RSDP_WINDOW_BASE);
@@ -198,13 +329,17 @@ pub fn create_acpi_tables(
         super::mptable::APIC_DEFAULT_PHYS_BASE as u32,
     );
 
-    for cpu in 0..num_cpus {
+    for cpu in 0..max_cpus {
         let lapic = LocalAPIC {
             _type: MADT_TYPE_LOCAL_APIC,
             _length: std::mem::size_of::<LocalAPIC>() as u8,
             _processor_id: cpu,
             _apic_id: cpu,
-            _flags: MADT_ENABLED,
+            _flags: if cpu < num_cpus {
+                MADT_ENABLED
+            } else {
+                MADT_ONLINE_CAPABLE
+            },
         };
         madt.append(lapic);
     }
@@ -220,6 +355,36 @@ pub fn create_acpi_tables(
     tables.push(offset.0);
     offset = offset.checked_add(madt.len() as u64)?;
 
+    // MCFG
+    let mut mcfg = SDT::new(
+        *b"MCFG",
+        MCFG_LEN,
+        MCFG_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    mcfg.append(MCFGEntry {
+        _base_address: super::PCIE_CFG_MMIO_START,
+        _end_bus: ((super::PCIE_CFG_MMIO_SIZE >> 20) - 1) as u8,
+        ..Default::default()
+    });
+
+    guest_mem.write_at_addr(mcfg.as_slice(), offset).ok()?;
+    tables.push(offset.0);
+    offset = offset.checked_add(mcfg.len() as u64)?;
+
+    if !numa_nodes.is_empty() {
+        for sdt in &[
+            create_srat_table(numa_nodes, numa_ranges),
+            create_slit_table(numa_nodes),
+        ] {
+            guest_mem.write_at_addr(sdt.as_slice(), offset).ok()?;
+            tables.push(offset.0);
+            offset = offset.checked_add(sdt.len() as u64)?;
+        }
+    }
+
     // XSDT
     let mut xsdt = SDT::new(
         *b"XSDT",
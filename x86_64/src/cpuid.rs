@@ -6,6 +6,7 @@ use std::arch::x86_64::{__cpuid, __cpuid_count};
 use std::fmt::{self, Display};
 use std::result;
 
+use arch::CpuFeatures;
 use devices::{IrqChipCap, IrqChipX86_64};
 use hypervisor::{HypervisorX86_64, VcpuX86_64};
 
@@ -13,6 +14,10 @@ use hypervisor::{HypervisorX86_64, VcpuX86_64};
 pub enum Error {
     GetSupportedCpusFailed(base::Error),
     SetSupportedCpusFailed(base::Error),
+    UnknownCpuFeature(String),
+    NestedVirtUnsupported,
+    UnknownCpuModel(String),
+    UnsupportedCpuFeature(String),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -25,6 +30,15 @@ impl Display for Error {
         match self {
             GetSupportedCpusFailed(e) => write!(f, "GetSupportedCpus ioctl failed: {}", e),
             SetSupportedCpusFailed(e) => write!(f, "SetSupportedCpus ioctl failed: {}", e),
+            NestedVirtUnsupported => write!(
+                f,
+                "nested virtualization isn't supported, or isn't enabled in the host's KVM module"
+            ),
+            UnknownCpuFeature(name) => write!(f, "unknown CPU feature: {}", name),
+            UnknownCpuModel(name) => write!(f, "unknown CPU model: {}", name),
+            UnsupportedCpuFeature(name) => {
+                write!(f, "CPU feature not supported by the host: {}", name)
+            }
         }
     }
 }
@@ -35,6 +49,8 @@ const EBX_CLFLUSH_SIZE_SHIFT: u32 = 8; // Bytes flushed when executing CLFLUSH.
 const EBX_CPU_COUNT_SHIFT: u32 = 16; // Index of this CPU.
 const EBX_CPUID_SHIFT: u32 = 24; // Index of this CPU.
 const ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
+const ECX_VMX_SHIFT: u32 = 5; // Intel virtualization extensions, in leaf 1.
+const ECX_SVM_SHIFT: u32 = 2; // AMD virtualization extensions, in leaf 0x80000001.
 const ECX_X2APIC_SHIFT: u32 = 21; // APIC supports extended xAPIC (x2APIC) standard.
 const ECX_TSC_DEADLINE_TIMER_SHIFT: u32 = 24; // TSC deadline mode of APIC timer.
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
@@ -146,6 +162,281 @@ fn filter_cpuid(
     Ok(())
 }
 
+#[derive(Clone, Copy)]
+enum CpuidReg {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+// A CPU feature that `CpuFeatures` can add or remove, and the CPUID bit that advertises it.
+struct CpuFeature {
+    // The name Linux shows in /proc/cpuinfo.
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u32,
+}
+
+const fn feature(
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u32,
+) -> CpuFeature {
+    CpuFeature {
+        name,
+        function,
+        index,
+        reg,
+        bit,
+    }
+}
+
+const CPU_FEATURES: &[CpuFeature] = &[
+    feature("pni", 1, 0, CpuidReg::Ecx, 0),
+    feature("pclmulqdq", 1, 0, CpuidReg::Ecx, 1),
+    feature("ssse3", 1, 0, CpuidReg::Ecx, 9),
+    feature("fma", 1, 0, CpuidReg::Ecx, 12),
+    feature("cx16", 1, 0, CpuidReg::Ecx, 13),
+    feature("sse4_1", 1, 0, CpuidReg::Ecx, 19),
+    feature("sse4_2", 1, 0, CpuidReg::Ecx, 20),
+    feature("movbe", 1, 0, CpuidReg::Ecx, 22),
+    feature("popcnt", 1, 0, CpuidReg::Ecx, 23),
+    feature("aes", 1, 0, CpuidReg::Ecx, 25),
+    feature("xsave", 1, 0, CpuidReg::Ecx, 26),
+    feature("avx", 1, 0, CpuidReg::Ecx, 28),
+    feature("f16c", 1, 0, CpuidReg::Ecx, 29),
+    feature("rdrand", 1, 0, CpuidReg::Ecx, 30),
+    feature("fsgsbase", 7, 0, CpuidReg::Ebx, 0),
+    feature("bmi1", 7, 0, CpuidReg::Ebx, 3),
+    feature("hle", 7, 0, CpuidReg::Ebx, 4),
+    feature("avx2", 7, 0, CpuidReg::Ebx, 5),
+    feature("smep", 7, 0, CpuidReg::Ebx, 7),
+    feature("bmi2", 7, 0, CpuidReg::Ebx, 8),
+    feature("erms", 7, 0, CpuidReg::Ebx, 9),
+    feature("invpcid", 7, 0, CpuidReg::Ebx, 10),
+    feature("rtm", 7, 0, CpuidReg::Ebx, 11),
+    feature("avx512f", 7, 0, CpuidReg::Ebx, 16),
+    feature("avx512dq", 7, 0, CpuidReg::Ebx, 17),
+    feature("rdseed", 7, 0, CpuidReg::Ebx, 18),
+    feature("adx", 7, 0, CpuidReg::Ebx, 19),
+    feature("smap", 7, 0, CpuidReg::Ebx, 20),
+    feature("avx512ifma", 7, 0, CpuidReg::Ebx, 21),
+    feature("clflushopt", 7, 0, CpuidReg::Ebx, 23),
+    feature("clwb", 7, 0, CpuidReg::Ebx, 24),
+    feature("avx512pf", 7, 0, CpuidReg::Ebx, 26),
+    feature("avx512er", 7, 0, CpuidReg::Ebx, 27),
+    feature("avx512cd", 7, 0, CpuidReg::Ebx, 28),
+    feature("sha_ni", 7, 0, CpuidReg::Ebx, 29),
+    feature("avx512bw", 7, 0, CpuidReg::Ebx, 30),
+    feature("avx512vl", 7, 0, CpuidReg::Ebx, 31),
+    feature("avx512vbmi", 7, 0, CpuidReg::Ecx, 1),
+    feature("umip", 7, 0, CpuidReg::Ecx, 2),
+    feature("pku", 7, 0, CpuidReg::Ecx, 3),
+    feature("avx512_vbmi2", 7, 0, CpuidReg::Ecx, 6),
+    feature("gfni", 7, 0, CpuidReg::Ecx, 8),
+    feature("vaes", 7, 0, CpuidReg::Ecx, 9),
+    feature("vpclmulqdq", 7, 0, CpuidReg::Ecx, 10),
+    feature("avx512_vnni", 7, 0, CpuidReg::Ecx, 11),
+    feature("avx512_bitalg", 7, 0, CpuidReg::Ecx, 12),
+    feature("avx512_vpopcntdq", 7, 0, CpuidReg::Ecx, 14),
+    feature("rdpid", 7, 0, CpuidReg::Ecx, 22),
+    feature("avx512_4vnniw", 7, 0, CpuidReg::Edx, 2),
+    feature("avx512_4fmaps", 7, 0, CpuidReg::Edx, 3),
+    feature("lahf_lm", 0x80000001, 0, CpuidReg::Ecx, 0),
+    feature("abm", 0x80000001, 0, CpuidReg::Ecx, 5),
+    feature("sse4a", 0x80000001, 0, CpuidReg::Ecx, 6),
+    feature("3dnowprefetch", 0x80000001, 0, CpuidReg::Ecx, 8),
+    feature("pdpe1gb", 0x80000001, 0, CpuidReg::Edx, 26),
+    feature("rdtscp", 0x80000001, 0, CpuidReg::Edx, 27),
+];
+
+// Names that stand for several features at once.
+const CPU_FEATURE_GROUPS: &[(&str, &[&str])] = &[(
+    "avx512",
+    &[
+        "avx512f",
+        "avx512dq",
+        "avx512ifma",
+        "avx512pf",
+        "avx512er",
+        "avx512cd",
+        "avx512bw",
+        "avx512vl",
+        "avx512vbmi",
+        "avx512_vbmi2",
+        "avx512_vnni",
+        "avx512_bitalg",
+        "avx512_vpopcntdq",
+        "avx512_4vnniw",
+        "avx512_4fmaps",
+    ],
+)];
+
+// CPU models, after the x86-64 psABI microarchitecture levels. Each model also has the features of
+// the models before it; the features of `CPU_FEATURES` a model doesn't have are hidden.
+const CPU_MODELS: &[(&str, &[&str])] = &[
+    ("x86-64", &[]),
+    (
+        "x86-64-v2",
+        &[
+            "cx16", "lahf_lm", "popcnt", "pni", "sse4_1", "sse4_2", "ssse3",
+        ],
+    ),
+    (
+        "x86-64-v3",
+        &[
+            "abm", "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "movbe", "xsave",
+        ],
+    ),
+    (
+        "x86-64-v4",
+        &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"],
+    ),
+];
+
+// Returns the names of the features of `model`.
+fn model_features(model: &str) -> Result<Vec<&'static str>> {
+    let level = CPU_MODELS
+        .iter()
+        .position(|(name, _)| *name == model)
+        .ok_or_else(|| Error::UnknownCpuModel(model.to_owned()))?;
+    Ok(CPU_MODELS[..=level]
+        .iter()
+        .flat_map(|(_, features)| features.iter().copied())
+        .collect())
+}
+
+// Returns the indices in `CPU_FEATURES` of the feature or group of features `name`.
+fn find_features(name: &str) -> Result<Vec<usize>> {
+    let names = match CPU_FEATURE_GROUPS.iter().find(|(group, _)| *group == name) {
+        Some((_, members)) => members.to_vec(),
+        None => vec![name],
+    };
+    names
+        .iter()
+        .map(|name| {
+            CPU_FEATURES
+                .iter()
+                .position(|feature| feature.name == *name)
+                .ok_or_else(|| Error::UnknownCpuFeature((*name).to_owned()))
+        })
+        .collect()
+}
+
+// Returns the register of `cpuid` holding the bit of `feature`, if `cpuid` has its leaf.
+fn feature_reg<'a>(cpuid: &'a mut hypervisor::CpuId, feature: &CpuFeature) -> Option<&'a mut u32> {
+    cpuid
+        .cpu_id_entries
+        .iter_mut()
+        .find(|entry| entry.function == feature.function && entry.index == feature.index)
+        .map(|entry| match feature.reg {
+            CpuidReg::Ebx => &mut entry.ebx,
+            CpuidReg::Ecx => &mut entry.ecx,
+            CpuidReg::Edx => &mut entry.edx,
+        })
+}
+
+/// Checks that the CPU model and the features of `cpu_features` are known.
+pub fn check_cpu_features(cpu_features: &CpuFeatures) -> Result<()> {
+    if let Some(model) = &cpu_features.model {
+        model_features(model)?;
+    }
+    for (name, _) in &cpu_features.features {
+        find_features(name)?;
+    }
+    Ok(())
+}
+
+// Hides the features from the guest that `cpu_features` removes from those `cpuid` advertises.
+// Features are never advertised if they weren't already, so enabling one the host lacks fails.
+fn apply_cpu_features(cpuid: &mut hypervisor::CpuId, cpu_features: &CpuFeatures) -> Result<()> {
+    let mut enabled = match &cpu_features.model {
+        Some(model) => {
+            let features = model_features(model)?;
+            CPU_FEATURES
+                .iter()
+                .map(|feature| features.contains(&feature.name))
+                .collect()
+        }
+        None => vec![true; CPU_FEATURES.len()],
+    };
+
+    for (name, enable) in &cpu_features.features {
+        let indices = find_features(name)?;
+        // A group is usable as long as the host has some of its features.
+        if *enable
+            && !indices.iter().any(|&i| {
+                let feature = &CPU_FEATURES[i];
+                feature_reg(cpuid, feature).map_or(false, |reg| *reg & (1 << feature.bit) != 0)
+            })
+        {
+            return Err(Error::UnsupportedCpuFeature(name.clone()));
+        }
+        for i in indices {
+            enabled[i] = *enable;
+        }
+    }
+
+    for (feature, enabled) in CPU_FEATURES.iter().zip(enabled) {
+        if !enabled {
+            if let Some(reg) = feature_reg(cpuid, feature) {
+                *reg &= !(1 << feature.bit);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Returns whether `cpuid` advertises VMX.
+fn has_vmx(cpuid: &hypervisor::CpuId) -> bool {
+    cpuid
+        .cpu_id_entries
+        .iter()
+        .any(|entry| entry.function == 1 && entry.ecx & (1 << ECX_VMX_SHIFT) != 0)
+}
+
+// Leaves the virtualization extensions `cpuid` advertises to the guest when `nested_virt` is set,
+// which KVM only does when its module's nested parameter is on, and hides them otherwise.
+fn apply_nested_virt(cpuid: &mut hypervisor::CpuId, nested_virt: bool) -> Result<()> {
+    let mut supported = false;
+    for entry in &mut cpuid.cpu_id_entries {
+        let bit = match entry.function {
+            1 => 1 << ECX_VMX_SHIFT,
+            0x80000001 => 1 << ECX_SVM_SHIFT,
+            _ => continue,
+        };
+        supported |= entry.ecx & bit != 0;
+        if !nested_virt {
+            entry.ecx &= !bit;
+        }
+    }
+    if nested_virt && !supported {
+        return Err(Error::NestedVirtUnsupported);
+    }
+    Ok(())
+}
+
+/// Returns whether the guest gets VMX, which needs MSRs set up for it besides the CPUID bit.
+///
+/// # Arguments
+///
+/// * `hypervisor` - `HypervisorX86_64` impl for getting supported CPU IDs.
+/// * `cpu_features` - The CPU model and features to advertise to the guest.
+pub fn nested_vmx(hypervisor: &dyn HypervisorX86_64, cpu_features: &CpuFeatures) -> Result<bool> {
+    if !cpu_features.nested_virt {
+        return Ok(false);
+    }
+    let cpuid = hypervisor
+        .get_supported_cpuid()
+        .map_err(Error::GetSupportedCpusFailed)?;
+    Ok(has_vmx(&cpuid))
+}
+
 /// Sets up the cpuid entries for the given vcpu.  Can fail if there are too many CPUs specified or
 /// if an ioctl returns an error.
 ///
@@ -155,6 +446,7 @@ fn filter_cpuid(
 /// * `vcpu` - `VcpuX86_64` for setting CPU ID.
 /// * `vcpu_id` - The vcpu index of `vcpu`.
 /// * `nrcpus` - The number of vcpus being used by this VM.
+/// * `cpu_features` - The CPU model and features to advertise to the guest.
 pub fn setup_cpuid(
     hypervisor: &dyn HypervisorX86_64,
     irq_chip: &dyn IrqChipX86_64,
@@ -162,12 +454,15 @@ pub fn setup_cpuid(
     vcpu_id: usize,
     nrcpus: usize,
     no_smt: bool,
+    cpu_features: &CpuFeatures,
 ) -> Result<()> {
     let mut cpuid = hypervisor
         .get_supported_cpuid()
         .map_err(Error::GetSupportedCpusFailed)?;
 
     filter_cpuid(vcpu_id, nrcpus, &mut cpuid, irq_chip, no_smt)?;
+    apply_cpu_features(&mut cpuid, cpu_features)?;
+    apply_nested_virt(&mut cpuid, cpu_features.nested_virt)?;
 
     vcpu.set_cpuid(&cpuid)
         .map_err(Error::SetSupportedCpusFailed)
@@ -224,4 +519,106 @@ mod tests {
         assert_ne!(0, entries[1].ecx & (1 << ECX_HYPERVISOR_SHIFT));
         assert_ne!(0, entries[1].edx & (1 << EDX_HTT_SHIFT));
     }
+
+    fn feature_bit(cpuid: &mut hypervisor::CpuId, name: &str) -> bool {
+        let feature = &CPU_FEATURES[find_features(name).unwrap()[0]];
+        *feature_reg(cpuid, feature).unwrap() & (1 << feature.bit) != 0
+    }
+
+    fn all_features_cpuid() -> hypervisor::CpuId {
+        let mut cpuid = hypervisor::CpuId::new(3);
+        for function in &[1, 7, 0x80000001] {
+            cpuid.cpu_id_entries.push(CpuIdEntry {
+                function: *function,
+                ebx: 0xffffffff,
+                ecx: 0xffffffff,
+                edx: 0xffffffff,
+                ..Default::default()
+            });
+        }
+        cpuid
+    }
+
+    #[test]
+    fn cpu_features() {
+        let mut cpuid = all_features_cpuid();
+        let cpu_features = CpuFeatures {
+            model: None,
+            features: vec![("avx512".to_owned(), false), ("rdrand".to_owned(), false)],
+            nested_virt: false,
+        };
+        apply_cpu_features(&mut cpuid, &cpu_features).unwrap();
+        assert!(!feature_bit(&mut cpuid, "avx512f"));
+        assert!(!feature_bit(&mut cpuid, "avx512_vnni"));
+        assert!(!feature_bit(&mut cpuid, "rdrand"));
+        assert!(feature_bit(&mut cpuid, "avx2"));
+        // Bits that aren't features, like the hypervisor bit, are left alone.
+        assert_ne!(0, cpuid.cpu_id_entries[0].ecx & (1 << ECX_HYPERVISOR_SHIFT));
+    }
+
+    #[test]
+    fn cpu_model() {
+        let mut cpuid = all_features_cpuid();
+        let cpu_features = CpuFeatures {
+            model: Some("x86-64-v3".to_owned()),
+            features: vec![("aes".to_owned(), true), ("movbe".to_owned(), false)],
+            nested_virt: false,
+        };
+        apply_cpu_features(&mut cpuid, &cpu_features).unwrap();
+        assert!(feature_bit(&mut cpuid, "sse4_2"));
+        assert!(feature_bit(&mut cpuid, "avx2"));
+        assert!(feature_bit(&mut cpuid, "aes"));
+        assert!(!feature_bit(&mut cpuid, "movbe"));
+        assert!(!feature_bit(&mut cpuid, "avx512f"));
+        assert!(!feature_bit(&mut cpuid, "rdrand"));
+    }
+
+    #[test]
+    fn unsupported_cpu_features() {
+        let mut cpuid = all_features_cpuid();
+        cpuid.cpu_id_entries[0].ecx = 0;
+        let cpu_features = CpuFeatures {
+            model: None,
+            features: vec![("rdrand".to_owned(), true)],
+            nested_virt: false,
+        };
+        assert_eq!(
+            apply_cpu_features(&mut cpuid, &cpu_features),
+            Err(Error::UnsupportedCpuFeature("rdrand".to_owned()))
+        );
+
+        let cpu_features = CpuFeatures {
+            model: Some("i486".to_owned()),
+            features: Vec::new(),
+            nested_virt: false,
+        };
+        assert_eq!(
+            check_cpu_features(&cpu_features),
+            Err(Error::UnknownCpuModel("i486".to_owned()))
+        );
+        let cpu_features = CpuFeatures {
+            model: None,
+            features: vec![("avx1024".to_owned(), false)],
+            nested_virt: false,
+        };
+        assert_eq!(
+            check_cpu_features(&cpu_features),
+            Err(Error::UnknownCpuFeature("avx1024".to_owned()))
+        );
+    }
+
+    #[test]
+    fn nested_virt() {
+        let mut cpuid = all_features_cpuid();
+        apply_nested_virt(&mut cpuid, true).unwrap();
+        assert!(has_vmx(&cpuid));
+
+        apply_nested_virt(&mut cpuid, false).unwrap();
+        assert!(!has_vmx(&cpuid));
+        assert_eq!(0, cpuid.cpu_id_entries[2].ecx & (1 << ECX_SVM_SHIFT));
+        assert_eq!(
+            apply_nested_virt(&mut cpuid, true),
+            Err(Error::NestedVirtUnsupported)
+        );
+    }
 }
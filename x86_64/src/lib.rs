@@ -5,6 +5,7 @@
 mod fdt;
 
 const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
 const SETUP_DTB: u32 = 2;
 const X86_64_FDT_MAX_SIZE: u64 = 0x200000;
 
@@ -41,6 +42,7 @@ mod cpuid;
 mod gdt;
 mod interrupts;
 mod mptable;
+mod pvh;
 mod regs;
 mod smbios;
 
@@ -49,7 +51,7 @@ use std::error::Error as StdError;
 use std::ffi::{CStr, CString};
 use std::fmt::{self, Display};
 use std::fs::File;
-use std::io::{self, Seek};
+use std::io::{self, Read, Seek};
 use std::mem;
 use std::sync::Arc;
 
@@ -57,13 +59,18 @@ use crate::bootparam::boot_params;
 use acpi_tables::aml::Aml;
 use acpi_tables::sdt::SDT;
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, CpuFeatures, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware,
+    SerialParameters, VmComponents, VmDevices, VmImage,
 };
-use base::Event;
-use devices::{IrqChip, IrqChipX86_64, PciConfigIo, PciDevice};
-use hypervisor::{HypervisorX86_64, VcpuX86_64, VmX86_64};
+use base::{warn, Event, RawDescriptor};
+use devices::fw_cfg;
+use devices::{
+    CpuHotplugController, IrqChip, IrqChipX86_64, PciConfigIo, PciConfigMmio,
+    PCIE_CONFIG_REGISTER_BITS,
+};
+use hypervisor::{HypervisorX86_64, PicSelect, VcpuX86_64, VmX86_64};
 use minijail::Minijail;
+use msg_socket::{deserialize_from_slice, serialize_to_vec, MsgError, MsgOnSocket};
 use remain::sorted;
 use resources::SystemAllocator;
 use sync::Mutex;
@@ -71,8 +78,9 @@ use vm_control::{BatControl, BatteryType};
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use {
+    gdb_rsp::{RegisterLayout, X86_64Registers},
     gdbstub::arch::x86::reg::X86_64CoreRegs,
-    hypervisor::x86_64::{Regs, Sregs},
+    hypervisor::x86_64::{Fpu, Regs, Segment, Sregs, Watchpoint},
 };
 
 #[sorted]
@@ -80,6 +88,8 @@ use {
 pub enum Error {
     AllocateIOResouce(resources::Error),
     AllocateIrq,
+    AllocatePciAddress(devices::PciDeviceError),
+    BindNumaMemory(GuestMemoryError),
     CloneEvent(base::Error),
     Cmdline(kernel_cmdline::Error),
     ConfigureSystem,
@@ -87,9 +97,11 @@ pub enum Error {
     CreateDevices(Box<dyn StdError>),
     CreateEvent(base::Error),
     CreateFdt(arch::fdt::Error),
+    CreateFwCfg(devices::FwCfgError),
     CreateIoapicDevice(base::Error),
     CreateIrqChip(Box<dyn StdError>),
     CreatePciRoot(arch::DeviceRegistrationError),
+    CreatePflash(devices::PflashError),
     CreatePit(base::Error),
     CreatePitDevice(devices::PitError),
     CreateSerialDevices(arch::DeviceRegistrationError),
@@ -100,6 +112,8 @@ pub enum Error {
     EnableSinglestep(base::Error),
     EnableSplitIrqchip(base::Error),
     GetSerialCmdline(GetSerialCmdlineError),
+    InvalidGdbRegister(usize),
+    IrqChipState(base::Error),
     KernelOffsetPastEnd,
     LoadBios(io::Error),
     LoadBzImage(bzimage::Error),
@@ -107,10 +121,15 @@ pub enum Error {
     LoadInitrd(arch::LoadImageError),
     LoadKernel(kernel_loader::Error),
     PageNotPresent,
+    PflashTooLarge(u64),
     Pstore(arch::pstore::Error),
+    ReadFwCfgImage(io::Error),
     ReadingGuestMemory(vm_memory::GuestMemoryError),
     ReadRegs(base::Error),
+    RegisterCpuHotplug(devices::BusError),
     RegisterIrqfd(base::Error),
+    RegisterPcieCfgMmio(devices::BusError),
+    RegisterPflash(devices::BusError),
     RegisterVsock(arch::DeviceRegistrationError),
     SetHwBreakpoint(base::Error),
     SetLint(interrupts::Error),
@@ -123,6 +142,7 @@ pub enum Error {
     SetupRegs(regs::Error),
     SetupSmbios(smbios::Error),
     SetupSregs(regs::Error),
+    SnapshotState(MsgError),
     TranslatingVirtAddr,
     WriteRegs(base::Error),
     WritingGuestMemory(GuestMemoryError),
@@ -139,6 +159,10 @@ impl Display for Error {
         match self {
             AllocateIOResouce(e) => write!(f, "error allocating IO resource: {}", e),
             AllocateIrq => write!(f, "error allocating a single irq"),
+            AllocatePciAddress(e) => write!(f, "error allocating a PCI address: {}", e),
+            BindNumaMemory(e) => {
+                write!(f, "failed to bind NUMA node memory to its host node: {}", e)
+            }
             CloneEvent(e) => write!(f, "unable to clone an Event: {}", e),
             Cmdline(e) => write!(f, "the given kernel command line was invalid: {}", e),
             ConfigureSystem => write!(f, "error configuring the system"),
@@ -146,9 +170,11 @@ impl Display for Error {
             CreateDevices(e) => write!(f, "error creating devices: {}", e),
             CreateEvent(e) => write!(f, "unable to make an Event: {}", e),
             CreateFdt(e) => write!(f, "failed to create fdt: {}", e),
+            CreateFwCfg(e) => write!(f, "failed to set up fw_cfg: {}", e),
             CreateIoapicDevice(e) => write!(f, "failed to create IOAPIC device: {}", e),
             CreateIrqChip(e) => write!(f, "failed to create IRQ chip: {}", e),
             CreatePciRoot(e) => write!(f, "failed to create a PCI root hub: {}", e),
+            CreatePflash(e) => write!(f, "failed to create pflash device: {}", e),
             CreatePit(e) => write!(f, "unable to create PIT: {}", e),
             CreatePitDevice(e) => write!(f, "unable to make PIT device: {}", e),
             CreateSerialDevices(e) => write!(f, "unable to create serial devices: {}", e),
@@ -159,6 +185,12 @@ impl Display for Error {
             EnableSinglestep(e) => write!(f, "failed to enable singlestep execution: {}", e),
             EnableSplitIrqchip(e) => write!(f, "failed to enable split irqchip: {}", e),
             GetSerialCmdline(e) => write!(f, "failed to get serial cmdline: {}", e),
+            InvalidGdbRegister(reg) => write!(
+                f,
+                "gdb register {} doesn't exist or was given the wrong size",
+                reg
+            ),
+            IrqChipState(e) => write!(f, "failed to save or restore the irqchip state: {}", e),
             KernelOffsetPastEnd => write!(f, "the kernel extends past the end of RAM"),
             LoadBios(e) => write!(f, "error loading bios: {}", e),
             LoadBzImage(e) => write!(f, "error loading kernel bzImage: {}", e),
@@ -166,10 +198,19 @@ impl Display for Error {
             LoadInitrd(e) => write!(f, "error loading initrd: {}", e),
             LoadKernel(e) => write!(f, "error loading Kernel: {}", e),
             PageNotPresent => write!(f, "error translating address: Page not present"),
+            PflashTooLarge(size) => write!(
+                f,
+                "pflash of {} bytes and the bios don't fit in {} bytes",
+                size, FIRMWARE_MAX_LEN
+            ),
             Pstore(e) => write!(f, "failed to allocate pstore region: {}", e),
+            ReadFwCfgImage(e) => write!(f, "failed to read image for fw_cfg: {}", e),
             ReadingGuestMemory(e) => write!(f, "error reading guest memory {}", e),
             ReadRegs(e) => write!(f, "error reading CPU registers {}", e),
+            RegisterCpuHotplug(e) => write!(f, "error registering the CPU hotplug device: {}", e),
             RegisterIrqfd(e) => write!(f, "error registering an IrqFd: {}", e),
+            RegisterPcieCfgMmio(e) => write!(f, "error registering PCIe ECAM region: {}", e),
+            RegisterPflash(e) => write!(f, "error registering pflash device: {}", e),
             RegisterVsock(e) => write!(f, "error registering virtual socket device: {}", e),
             SetHwBreakpoint(e) => write!(f, "failed to set a hardware breakpoint: {}", e),
             SetLint(e) => write!(f, "failed to set interrupts: {}", e),
@@ -182,6 +223,7 @@ impl Display for Error {
             SetupRegs(e) => write!(f, "failed to set up registers: {}", e),
             SetupSmbios(e) => write!(f, "failed to set up SMBIOS: {}", e),
             SetupSregs(e) => write!(f, "failed to set up sregs: {}", e),
+            SnapshotState(e) => write!(f, "invalid VM snapshot state: {}", e),
             TranslatingVirtAddr => write!(f, "failed to translate virtual address"),
             WriteRegs(e) => write!(f, "error writing CPU registers {}", e),
             WritingGuestMemory(e) => write!(f, "error writing guest memory {}", e),
@@ -195,6 +237,27 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 impl std::error::Error for Error {}
 
+/// The state of a VCPU saved in a VM snapshot.
+#[derive(MsgOnSocket)]
+struct VcpuSnapshot {
+    regs: hypervisor::Regs,
+    sregs: hypervisor::Sregs,
+    fpu: hypervisor::Fpu,
+    debugregs: hypervisor::DebugRegs,
+    xcrs: Vec<hypervisor::Register>,
+    msrs: Vec<hypervisor::Register>,
+}
+
+/// The state of the irqchip saved in a VM snapshot, with the local APIC of every VCPU.
+#[derive(MsgOnSocket)]
+struct IrqChipSnapshot {
+    pic_primary: hypervisor::PicState,
+    pic_secondary: hypervisor::PicState,
+    ioapic: hypervisor::IoapicState,
+    lapics: Vec<hypervisor::LapicState>,
+    pit: hypervisor::PitState,
+}
+
 pub struct X8664arch;
 
 const BOOT_STACK_POINTER: u64 = 0x8000;
@@ -202,19 +265,27 @@ const BOOT_STACK_POINTER: u64 = 0x8000;
 const MEM_32BIT_GAP_SIZE: u64 = 768 << 20;
 const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
 const END_ADDR_BEFORE_32BITS: u64 = FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE;
-const MMIO_SIZE: u64 = MEM_32BIT_GAP_SIZE - 0x8000000;
+// PCI Express ECAM region, 1 MiB of configuration space for each of buses 0 to 63. It sits
+// between the low MMIO region and the fixed devices at the top of the 32-bit gap.
+const PCIE_CFG_MMIO_SIZE: u64 = 0x4000000;
+const PCIE_CFG_MMIO_START: u64 = FIRST_ADDR_PAST_32BITS - 0x8000000 - PCIE_CFG_MMIO_SIZE;
+const MMIO_SIZE: u64 = MEM_32BIT_GAP_SIZE - 0x8000000 - PCIE_CFG_MMIO_SIZE;
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 const ZERO_PAGE_OFFSET: u64 = 0x7000;
 /// The x86 reset vector for i386+ and x86_64 puts the processor into an "unreal mode" where it
 /// can access the last 1 MB of the 32-bit address space in 16-bit mode, and starts the instruction
 /// pointer at the effective physical address 0xFFFFFFF0.
-const BIOS_LEN: usize = 1 << 20;
-const BIOS_START: u64 = FIRST_ADDR_PAST_32BITS - (BIOS_LEN as u64);
+// The BIOS is mapped right below 4 GiB, with the flash from `pflash` right below it. Together they
+// have to stay clear of the local APIC and IOAPIC.
+const FIRMWARE_MAX_LEN: u64 = 16 << 20;
 const TSS_ADDR: u64 = 0xfffbd000;
 
 const KERNEL_START_OFFSET: u64 = 0x200000;
 const CMDLINE_OFFSET: u64 = 0x20000;
 const CMDLINE_MAX_SIZE: u64 = KERNEL_START_OFFSET - CMDLINE_OFFSET;
+// Offsets into the header of a bzImage.
+const BZIMAGE_SETUP_SECTS_OFFSET: usize = 0x1f1;
+const BZIMAGE_HEADER_MAGIC_OFFSET: usize = 0x202;
 const X86_64_SERIAL_1_3_IRQ: u32 = 4;
 const X86_64_SERIAL_2_4_IRQ: u32 = 3;
 // X86_64_SCI_IRQ is used to fill the ACPI FACP table.
@@ -227,6 +298,15 @@ pub const X86_64_SCI_IRQ: u32 = 5;
 // The CMOS RTC uses IRQ 8; start allocating IRQs at 9.
 pub const X86_64_IRQ_BASE: u32 = 9;
 const ACPI_HI_RSDP_WINDOW_BASE: u64 = 0x000E0000;
+// Where the PVH boot protocol structures go. They take the place of the zero page, which kernels
+// booted with PVH don't use.
+const PVH_INFO_START: u64 = 0x6000;
+const PVH_MODLIST_START: u64 = 0x6040;
+const PVH_MEMMAP_START: u64 = ZERO_PAGE_OFFSET;
+// Kernels booted with PVH are loaded at their physical addresses, which can be anywhere past the
+// first megabyte.
+const PVH_HIGH_RAM_START: u64 = 0x100000;
+const EBDA_START: u64 = 0x0009fc00;
 
 fn configure_system(
     guest_mem: &GuestMemory,
@@ -238,13 +318,10 @@ fn configure_system(
     initrd: Option<(GuestAddress, usize)>,
     mut params: boot_params,
 ) -> Result<()> {
-    const EBDA_START: u64 = 0x0009fc00;
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x53726448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
     const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000; // Must be non-zero.
-    let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
-    let end_32bit_gap_start = GuestAddress(END_ADDR_BEFORE_32BITS);
 
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
     params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
@@ -260,31 +337,8 @@ fn configure_system(
         params.hdr.ramdisk_size = initrd_size as u32;
     }
 
-    add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
-
-    let mem_end = guest_mem.end_addr();
-    if mem_end < end_32bit_gap_start {
-        add_e820_entry(
-            &mut params,
-            kernel_addr.offset() as u64,
-            mem_end.offset_from(kernel_addr) as u64,
-            E820_RAM,
-        )?;
-    } else {
-        add_e820_entry(
-            &mut params,
-            kernel_addr.offset() as u64,
-            end_32bit_gap_start.offset_from(kernel_addr) as u64,
-            E820_RAM,
-        )?;
-        if mem_end > first_addr_past_32bits {
-            add_e820_entry(
-                &mut params,
-                first_addr_past_32bits.offset() as u64,
-                mem_end.offset_from(first_addr_past_32bits) as u64,
-                E820_RAM,
-            )?;
-        }
+    for (addr, size, mem_type) in e820_entries(guest_mem, kernel_addr) {
+        add_e820_entry(&mut params, addr, size, mem_type)?;
     }
 
     let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
@@ -298,6 +352,89 @@ fn configure_system(
     Ok(())
 }
 
+/// Configures the structures describing the boot to a kernel booted with the PVH boot protocol.
+fn configure_pvh_system(
+    guest_mem: &GuestMemory,
+    cmdline_addr: GuestAddress,
+    initrd: Option<(GuestAddress, usize)>,
+    rsdp_addr: Option<GuestAddress>,
+) -> Result<()> {
+    let mut start_info = pvh::StartInfo {
+        magic: pvh::XEN_HVM_START_MAGIC_VALUE,
+        version: pvh::XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: cmdline_addr.offset(),
+        rsdp_paddr: rsdp_addr.map_or(0, |addr| addr.offset()),
+        memmap_paddr: PVH_MEMMAP_START,
+        ..Default::default()
+    };
+
+    if let Some((initrd_addr, initrd_size)) = initrd {
+        let modlist_entry = pvh::ModlistEntry {
+            paddr: initrd_addr.offset(),
+            size: initrd_size as u64,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(modlist_entry, GuestAddress(PVH_MODLIST_START))
+            .map_err(Error::WritingGuestMemory)?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = PVH_MODLIST_START;
+    }
+
+    let mut memmap_addr = GuestAddress(PVH_MEMMAP_START);
+    for (addr, size, mem_type) in e820_entries(guest_mem, GuestAddress(PVH_HIGH_RAM_START)) {
+        let memmap_entry = pvh::MemmapTableEntry {
+            addr,
+            size,
+            type_: mem_type,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(memmap_entry, memmap_addr)
+            .map_err(Error::WritingGuestMemory)?;
+        memmap_addr = memmap_addr.unchecked_add(mem::size_of::<pvh::MemmapTableEntry>() as u64);
+        start_info.memmap_entries += 1;
+    }
+
+    guest_mem
+        .write_obj_at_addr(start_info, GuestAddress(PVH_INFO_START))
+        .map_err(Error::WritingGuestMemory)?;
+
+    Ok(())
+}
+
+/// Returns the e820 map of the guest as (address, size, type) entries. The RAM past the first
+/// megabyte that the guest may use starts at `ram_start`.
+fn e820_entries(guest_mem: &GuestMemory, ram_start: GuestAddress) -> Vec<(u64, u64, u32)> {
+    let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
+    let end_32bit_gap_start = GuestAddress(END_ADDR_BEFORE_32BITS);
+
+    let mut entries = vec![(0, EBDA_START, E820_RAM)];
+
+    let mem_end = guest_mem.end_addr();
+    if mem_end < end_32bit_gap_start {
+        entries.push((ram_start.offset(), mem_end.offset_from(ram_start), E820_RAM));
+    } else {
+        entries.push((
+            ram_start.offset(),
+            end_32bit_gap_start.offset_from(ram_start),
+            E820_RAM,
+        ));
+        if mem_end > first_addr_past_32bits {
+            entries.push((
+                first_addr_past_32bits.offset(),
+                mem_end.offset_from(first_addr_past_32bits),
+                E820_RAM,
+            ));
+        }
+    }
+
+    // Linux only uses the ECAM region described by MCFG if it is also reserved in the e820 map.
+    entries.push((PCIE_CFG_MMIO_START, PCIE_CFG_MMIO_SIZE, E820_RESERVED));
+
+    entries
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(params: &mut boot_params, addr: u64, size: u64, mem_type: u32) -> Result<()> {
@@ -313,11 +450,16 @@ fn add_e820_entry(params: &mut boot_params, addr: u64, size: u64, mem_type: u32)
     Ok(())
 }
 
+/// Returns the address the BIOS starts at, which ends right at 4 GiB.
+fn bios_start(bios_size: u64) -> u64 {
+    FIRST_ADDR_PAST_32BITS - bios_size
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
-/// carve out at the end of 32bit address space.
-fn arch_memory_regions(size: u64, has_bios: bool) -> Vec<(GuestAddress, u64)> {
+/// carve out at the end of 32bit address space, where a BIOS of `bios_size` bytes is mapped.
+fn arch_memory_regions(size: u64, bios_size: Option<u64>) -> Vec<(GuestAddress, u64)> {
     let mem_end = GuestAddress(size);
     let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
     let end_32bit_gap_start = GuestAddress(END_ADDR_BEFORE_32BITS);
@@ -325,13 +467,13 @@ fn arch_memory_regions(size: u64, has_bios: bool) -> Vec<(GuestAddress, u64)> {
     let mut regions = Vec::new();
     if mem_end <= end_32bit_gap_start {
         regions.push((GuestAddress(0), size));
-        if has_bios {
-            regions.push((GuestAddress(BIOS_START), BIOS_LEN as u64));
+        if let Some(bios_size) = bios_size {
+            regions.push((GuestAddress(bios_start(bios_size)), bios_size));
         }
     } else {
         regions.push((GuestAddress(0), end_32bit_gap_start.offset()));
-        if has_bios {
-            regions.push((GuestAddress(BIOS_START), BIOS_LEN as u64));
+        if let Some(bios_size) = bios_size {
+            regions.push((GuestAddress(bios_start(bios_size)), bios_size));
         }
         regions.push((
             first_addr_past_32bits,
@@ -363,21 +505,43 @@ impl arch::LinuxArch for X8664arch {
             &mut V,
             &mut SystemAllocator,
             &Event,
-        ) -> std::result::Result<Vec<(Box<dyn PciDevice>, Option<Minijail>)>, E1>,
+        ) -> std::result::Result<VmDevices, E1>,
         FV: FnOnce(GuestMemory) -> std::result::Result<V, E2>,
         FI: FnOnce(&V, /* vcpu_count: */ usize) -> std::result::Result<I, E3>,
         E1: StdError + 'static,
         E2: StdError + 'static,
         E3: StdError + 'static,
     {
-        let has_bios = matches!(components.vm_image, VmImage::Bios(_));
-        let mem = Self::setup_memory(components.memory_size, has_bios)?;
+        // Reject unknown CPU models and features before anything is set up, instead of failing
+        // later on the vcpu threads.
+        cpuid::check_cpu_features(&components.cpu_features).map_err(Error::SetupCpuid)?;
+
+        let bios_size = match components.vm_image {
+            VmImage::Bios(ref mut bios) => Some(Self::get_bios_size(bios)?),
+            VmImage::Kernel(_) => None,
+        };
+        let has_bios = bios_size.is_some();
+        let mem = Self::setup_memory(
+            components.memory_size,
+            bios_size,
+            components.read_only_bios,
+            components.mergeable_memory,
+        )?;
+        let numa_ranges = arch::numa::numa_node_ranges(
+            &arch_memory_regions(components.memory_size, None),
+            &components.numa_nodes,
+        );
+        arch::numa::bind_numa_memory(&mem, &components.numa_nodes, &numa_ranges)
+            .map_err(Error::BindNumaMemory)?;
         let mut resources = Self::get_resource_allocator(&mem);
 
         let vcpu_count = components.vcpu_count;
+        // VCPUs that can be hot-added later are created up front, and wait for the guest to
+        // start them just like the VCPUs it hasn't brought up yet at boot.
+        let max_vcpu_count = components.max_vcpu_count;
         let mut vm = create_vm(mem.clone()).map_err(|e| Error::CreateVm(Box::new(e)))?;
         let mut irq_chip =
-            create_irq_chip(&vm, vcpu_count).map_err(|e| Error::CreateIrqChip(Box::new(e)))?;
+            create_irq_chip(&vm, max_vcpu_count).map_err(|e| Error::CreateIrqChip(Box::new(e)))?;
 
         let tss_addr = GuestAddress(TSS_ADDR);
         vm.set_tss_addr(tss_addr).map_err(Error::SetTssAddr)?;
@@ -386,8 +550,21 @@ impl arch::LinuxArch for X8664arch {
 
         let exit_evt = Event::new().map_err(Error::CreateEvent)?;
 
-        let pci_devices = create_devices(&mem, &mut vm, &mut resources, &exit_evt)
+        // Platform devices are only described to guests in device trees, which x86_64 doesn't use.
+        let VmDevices {
+            pci: mut pci_devices,
+            ..
+        } = create_devices(&mem, &mut vm, &mut resources, &exit_evt)
             .map_err(|e| Error::CreateDevices(Box::new(e)))?;
+        // Devices that describe themselves in ACPI tables, like the virtio-iommu, need their PCI
+        // address to do so, and are moved into device processes by `generate_pci_root`.
+        let mut acpi_sdts = components.acpi_sdts;
+        for (device, _jail) in pci_devices.iter_mut() {
+            device
+                .allocate_address(&mut resources)
+                .map_err(Error::AllocatePciAddress)?;
+            device.generate_acpi(&mut acpi_sdts);
+        }
         let (pci, pci_irqs, pid_debug_label_map) = arch::generate_pci_root(
             pci_devices,
             &mut irq_chip,
@@ -397,10 +574,19 @@ impl arch::LinuxArch for X8664arch {
             4, // Share the four pin interrupts (INTx#)
         )
         .map_err(Error::CreatePciRoot)?;
-        let pci_bus = Arc::new(Mutex::new(PciConfigIo::new(pci)));
+        let pci_bus = Arc::new(Mutex::new(PciConfigIo::new(pci.clone())));
+        let pcie_cfg_mmio = Arc::new(Mutex::new(PciConfigMmio::new(
+            pci.clone(),
+            PCIE_CONFIG_REGISTER_BITS,
+        )));
+        mmio_bus
+            .insert(pcie_cfg_mmio, PCIE_CFG_MMIO_START, PCIE_CFG_MMIO_SIZE)
+            .map_err(Error::RegisterPcieCfgMmio)?;
 
         // Event used to notify crosvm that guest OS is trying to suspend.
         let suspend_evt = Event::new().map_err(Error::CreateEvent)?;
+        // Event used to notify crosvm that guest OS is trying to enter S3.
+        let sleep_evt = Event::new().map_err(Error::CreateEvent)?;
 
         let mut io_bus = Self::setup_io_bus(
             irq_chip.pit_uses_speaker_port(),
@@ -417,15 +603,18 @@ impl arch::LinuxArch for X8664arch {
             serial_jail,
         )?;
 
-        let (acpi_dev_resource, bat_control) = Self::setup_acpi_devices(
+        let (acpi_dev_resource, bat_control, cpu_hotplug) = Self::setup_acpi_devices(
             &mut io_bus,
             &mut resources,
             suspend_evt.try_clone().map_err(Error::CloneEvent)?,
+            sleep_evt.try_clone().map_err(Error::CloneEvent)?,
             exit_evt.try_clone().map_err(Error::CloneEvent)?,
-            components.acpi_sdts,
+            acpi_sdts,
             &mut irq_chip,
             battery,
             &mut mmio_bus,
+            vcpu_count,
+            max_vcpu_count,
         )?;
 
         let ramoops_region = match components.pstore {
@@ -452,10 +641,36 @@ impl arch::LinuxArch for X8664arch {
         mptable::setup_mptable(&mem, vcpu_count as u8, pci_irqs).map_err(Error::SetupMptable)?;
         smbios::setup_smbios(&mem).map_err(Error::SetupSmbios)?;
         // TODO (tjeznach) Write RSDP to bootconfig before writing to memory
-        acpi::create_acpi_tables(&mem, vcpu_count as u8, X86_64_SCI_IRQ, acpi_dev_resource);
+        let rsdp_addr = acpi::create_acpi_tables(
+            &mem,
+            vcpu_count as u8,
+            max_vcpu_count as u8,
+            X86_64_SCI_IRQ,
+            acpi_dev_resource,
+            &components.numa_nodes,
+            &numa_ranges,
+        );
 
+        let mut pvh_entry = None;
         match components.vm_image {
-            VmImage::Bios(ref mut bios) => Self::load_bios(&mem, bios)?,
+            VmImage::Bios(ref mut bios) => {
+                // Only set for a BIOS image, above.
+                let bios_size = bios_size.unwrap();
+                Self::load_bios(&mem, bios, bios_size)?;
+                if let Some(pflash) = components.pflash.take() {
+                    Self::setup_pflash(&mut mmio_bus, pflash, bios_size)?;
+                }
+                Self::setup_fw_cfg(
+                    &mut io_bus,
+                    components.memory_size,
+                    vcpu_count,
+                    components.fw_cfg_kernel.take(),
+                    components.initrd_image.take(),
+                    &components.extra_kernel_params,
+                    mem::take(&mut components.fw_cfg_files),
+                    &components.boot_order,
+                )?;
+            }
             VmImage::Kernel(ref mut kernel_image) => {
                 let mut cmdline = Self::get_base_linux_cmdline();
 
@@ -484,19 +699,35 @@ impl arch::LinuxArch for X8664arch {
                     }
                 }
 
-                // separate out load_kernel from other setup to get a specific error for
-                // kernel loading
-                let (params, kernel_end) = Self::load_kernel(&mem, kernel_image)?;
-
-                Self::setup_system_memory(
-                    &mem,
-                    components.memory_size,
-                    &CString::new(cmdline).unwrap(),
-                    components.initrd_image,
-                    components.android_fstab,
-                    kernel_end,
-                    params,
-                )?;
+                // Elf kernels that support the PVH boot protocol are booted with it, which doesn't
+                // need a bzImage.
+                if let Some((entry, kernel_end)) = Self::load_pvh_kernel(&mem, kernel_image)? {
+                    if components.android_fstab.is_some() {
+                        warn!("the android fstab is only passed to kernels not booted with PVH");
+                    }
+                    Self::setup_pvh_system_memory(
+                        &mem,
+                        &CString::new(cmdline).unwrap(),
+                        components.initrd_image,
+                        kernel_end,
+                        rsdp_addr,
+                    )?;
+                    pvh_entry = Some(entry);
+                } else {
+                    // separate out load_kernel from other setup to get a specific error for
+                    // kernel loading
+                    let (params, kernel_end) = Self::load_kernel(&mem, kernel_image)?;
+
+                    Self::setup_system_memory(
+                        &mem,
+                        components.memory_size,
+                        &CString::new(cmdline).unwrap(),
+                        components.initrd_image,
+                        components.android_fstab,
+                        kernel_end,
+                        params,
+                    )?;
+                }
             }
         }
 
@@ -504,18 +735,23 @@ impl arch::LinuxArch for X8664arch {
             vm,
             resources,
             exit_evt,
-            vcpu_count,
+            vcpu_count: max_vcpu_count,
             vcpus: None,
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            cpu_features: components.cpu_features,
             irq_chip,
             has_bios,
+            pvh_entry,
             io_bus,
             mmio_bus,
+            pci_root: pci,
             pid_debug_label_map,
             suspend_evt,
+            sleep_evt,
             rt_cpus: components.rt_cpus,
             bat_control,
+            cpu_hotplug,
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             gdb: components.gdb,
         })
@@ -529,17 +765,40 @@ impl arch::LinuxArch for X8664arch {
         vcpu_id: usize,
         num_cpus: usize,
         has_bios: bool,
+        pvh_entry: Option<GuestAddress>,
         no_smt: bool,
+        cpu_features: &CpuFeatures,
     ) -> Result<()> {
-        cpuid::setup_cpuid(hypervisor, irq_chip, vcpu, vcpu_id, num_cpus, no_smt)
-            .map_err(Error::SetupCpuid)?;
+        cpuid::setup_cpuid(
+            hypervisor,
+            irq_chip,
+            vcpu,
+            vcpu_id,
+            num_cpus,
+            no_smt,
+            cpu_features,
+        )
+        .map_err(Error::SetupCpuid)?;
 
         if has_bios {
             return Ok(());
         }
 
+        let nested_vmx = cpuid::nested_vmx(hypervisor, cpu_features).map_err(Error::SetupCpuid)?;
+        regs::setup_msrs(vcpu, END_ADDR_BEFORE_32BITS, nested_vmx).map_err(Error::SetupMsrs)?;
+
+        if let Some(pvh_entry) = pvh_entry {
+            // PVH kernels are entered in 32-bit protected mode, with the address of the start
+            // info in ebx.
+            regs::setup_pvh_regs(vcpu, pvh_entry.offset(), PVH_INFO_START)
+                .map_err(Error::SetupRegs)?;
+            regs::setup_fpu(vcpu).map_err(Error::SetupFpu)?;
+            regs::setup_pvh_sregs(guest_mem, vcpu).map_err(Error::SetupSregs)?;
+            interrupts::set_lint(vcpu_id, irq_chip).map_err(Error::SetLint)?;
+            return Ok(());
+        }
+
         let kernel_load_addr = GuestAddress(KERNEL_START_OFFSET);
-        regs::setup_msrs(vcpu, END_ADDR_BEFORE_32BITS).map_err(Error::SetupMsrs)?;
         let kernel_end = guest_mem
             .checked_offset(kernel_load_addr, KERNEL_64BIT_ENTRY_OFFSET)
             .ok_or(Error::KernelOffsetPastEnd)?;
@@ -557,6 +816,70 @@ impl arch::LinuxArch for X8664arch {
         Ok(())
     }
 
+    fn snapshot_vcpu<T: VcpuX86_64>(vcpu: &T) -> Result<Vec<u8>> {
+        let snapshot = VcpuSnapshot {
+            regs: vcpu.get_regs().map_err(Error::ReadRegs)?,
+            sregs: vcpu.get_sregs().map_err(Error::ReadRegs)?,
+            fpu: vcpu.get_fpu().map_err(Error::ReadRegs)?,
+            debugregs: vcpu.get_debugregs().map_err(Error::ReadRegs)?,
+            // Hosts without XSAVE have no XCRs.
+            xcrs: vcpu.get_xcrs().unwrap_or_default(),
+            msrs: regs::snapshot_msrs(vcpu),
+        };
+        serialize_to_vec(&snapshot).map_err(Error::SnapshotState)
+    }
+
+    fn restore_vcpu<T: VcpuX86_64>(vcpu: &T, data: &[u8]) -> Result<()> {
+        let snapshot: VcpuSnapshot = deserialize_from_slice(data).map_err(Error::SnapshotState)?;
+        vcpu.set_sregs(&snapshot.sregs).map_err(Error::WriteRegs)?;
+        vcpu.set_regs(&snapshot.regs).map_err(Error::WriteRegs)?;
+        vcpu.set_fpu(&snapshot.fpu).map_err(Error::WriteRegs)?;
+        vcpu.set_debugregs(&snapshot.debugregs)
+            .map_err(Error::WriteRegs)?;
+        if !snapshot.xcrs.is_empty() {
+            vcpu.set_xcrs(&snapshot.xcrs).map_err(Error::WriteRegs)?;
+        }
+        vcpu.set_msrs(&snapshot.msrs).map_err(Error::WriteRegs)
+    }
+
+    fn snapshot_irq_chip(irq_chip: &dyn IrqChipX86_64, vcpu_count: usize) -> Result<Vec<u8>> {
+        let snapshot = IrqChipSnapshot {
+            pic_primary: irq_chip
+                .get_pic_state(PicSelect::Primary)
+                .map_err(Error::IrqChipState)?,
+            pic_secondary: irq_chip
+                .get_pic_state(PicSelect::Secondary)
+                .map_err(Error::IrqChipState)?,
+            ioapic: irq_chip.get_ioapic_state().map_err(Error::IrqChipState)?,
+            lapics: (0..vcpu_count)
+                .map(|vcpu_id| irq_chip.get_lapic_state(vcpu_id))
+                .collect::<base::Result<_>>()
+                .map_err(Error::IrqChipState)?,
+            pit: irq_chip.get_pit().map_err(Error::IrqChipState)?,
+        };
+        serialize_to_vec(&snapshot).map_err(Error::SnapshotState)
+    }
+
+    fn restore_irq_chip(irq_chip: &mut dyn IrqChipX86_64, data: &[u8]) -> Result<()> {
+        let snapshot: IrqChipSnapshot =
+            deserialize_from_slice(data).map_err(Error::SnapshotState)?;
+        irq_chip
+            .set_pic_state(PicSelect::Primary, &snapshot.pic_primary)
+            .map_err(Error::IrqChipState)?;
+        irq_chip
+            .set_pic_state(PicSelect::Secondary, &snapshot.pic_secondary)
+            .map_err(Error::IrqChipState)?;
+        irq_chip
+            .set_ioapic_state(&snapshot.ioapic)
+            .map_err(Error::IrqChipState)?;
+        for (vcpu_id, lapic) in snapshot.lapics.iter().enumerate() {
+            irq_chip
+                .set_lapic_state(vcpu_id, lapic)
+                .map_err(Error::IrqChipState)?;
+        }
+        irq_chip.set_pit(&snapshot.pit).map_err(Error::IrqChipState)
+    }
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     fn debug_read_registers<T: VcpuX86_64>(vcpu: &T) -> Result<X86_64CoreRegs> {
         // General registers: RAX, RBX, RCX, RDX, RSI, RDI, RBP, RSP, r8-r15
@@ -635,6 +958,104 @@ impl arch::LinuxArch for X8664arch {
         Ok(())
     }
 
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    fn debug_read_register<T: VcpuX86_64>(vcpu: &T, reg: usize) -> Result<Vec<u8>> {
+        let size = X86_64Registers::register_size(reg).ok_or(Error::InvalidGdbRegister(reg))?;
+        let fpu = || vcpu.get_fpu().map_err(Error::ReadRegs);
+        let mut value = match GdbRegister::new(reg).ok_or(Error::InvalidGdbRegister(reg))? {
+            GdbRegister::General(field) => {
+                let mut regs = vcpu.get_regs().map_err(Error::ReadRegs)?;
+                field(&mut regs).to_le_bytes().to_vec()
+            }
+            GdbRegister::Selector(field) => {
+                let mut sregs = vcpu.get_sregs().map_err(Error::ReadRegs)?;
+                // GDB uses only the selectors.
+                field(&mut sregs).selector.to_le_bytes().to_vec()
+            }
+            GdbRegister::St(n) => fpu()?.fpr[n].to_vec(),
+            GdbRegister::Fctrl => fpu()?.fcw.to_le_bytes().to_vec(),
+            GdbRegister::Fstat => fpu()?.fsw.to_le_bytes().to_vec(),
+            GdbRegister::Ftag => full_tag_word(fpu()?.ftwx).to_le_bytes().to_vec(),
+            // KVM doesn't keep the x87 segment selectors, which 64-bit code doesn't use.
+            GdbRegister::Fiseg | GdbRegister::Foseg => Vec::new(),
+            GdbRegister::Fioff => fpu()?.last_ip.to_le_bytes().to_vec(),
+            GdbRegister::Fooff => fpu()?.last_dp.to_le_bytes().to_vec(),
+            GdbRegister::Fop => fpu()?.last_opcode.to_le_bytes().to_vec(),
+            GdbRegister::Xmm(n) => fpu()?.xmm[n].to_vec(),
+            GdbRegister::Mxcsr => fpu()?.mxcsr.to_le_bytes().to_vec(),
+        };
+        // Registers that gdb shows partially, like eflags, are the lower bytes of the vCPU's.
+        value.resize(size, 0);
+        Ok(value)
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    fn debug_write_register<T: VcpuX86_64>(vcpu: &T, reg: usize, data: &[u8]) -> Result<()> {
+        if X86_64Registers::register_size(reg) != Some(data.len()) {
+            return Err(Error::InvalidGdbRegister(reg));
+        }
+        // Writes keep the upper bytes of the registers that gdb shows partially.
+        let set_lower = |value: &mut [u8]| {
+            let len = std::cmp::min(value.len(), data.len());
+            value[..len].copy_from_slice(&data[..len]);
+        };
+        match GdbRegister::new(reg).ok_or(Error::InvalidGdbRegister(reg))? {
+            GdbRegister::General(field) => {
+                let mut regs = vcpu.get_regs().map_err(Error::ReadRegs)?;
+                let mut value = field(&mut regs).to_le_bytes();
+                set_lower(&mut value);
+                *field(&mut regs) = u64::from_le_bytes(value);
+                vcpu.set_regs(&regs).map_err(Error::WriteRegs)
+            }
+            GdbRegister::Selector(field) => {
+                let mut sregs = vcpu.get_sregs().map_err(Error::ReadRegs)?;
+                let mut selector = field(&mut sregs).selector.to_le_bytes();
+                set_lower(&mut selector);
+                field(&mut sregs).selector = u16::from_le_bytes(selector);
+                vcpu.set_sregs(&sregs).map_err(Error::WriteRegs)
+            }
+            GdbRegister::St(n) => update_fpu(vcpu, |fpu| set_lower(&mut fpu.fpr[n][..])),
+            GdbRegister::Fctrl => update_fpu(vcpu, |fpu| {
+                let mut fcw = fpu.fcw.to_le_bytes();
+                set_lower(&mut fcw);
+                fpu.fcw = u16::from_le_bytes(fcw);
+            }),
+            GdbRegister::Fstat => update_fpu(vcpu, |fpu| {
+                let mut fsw = fpu.fsw.to_le_bytes();
+                set_lower(&mut fsw);
+                fpu.fsw = u16::from_le_bytes(fsw);
+            }),
+            GdbRegister::Ftag => update_fpu(vcpu, |fpu| {
+                let mut ftag = full_tag_word(fpu.ftwx).to_le_bytes();
+                set_lower(&mut ftag);
+                fpu.ftwx = abridged_tag_word(u16::from_le_bytes(ftag));
+            }),
+            // There are no x87 segment selectors to write.
+            GdbRegister::Fiseg | GdbRegister::Foseg => Ok(()),
+            GdbRegister::Fioff => update_fpu(vcpu, |fpu| {
+                let mut last_ip = fpu.last_ip.to_le_bytes();
+                set_lower(&mut last_ip);
+                fpu.last_ip = u64::from_le_bytes(last_ip);
+            }),
+            GdbRegister::Fooff => update_fpu(vcpu, |fpu| {
+                let mut last_dp = fpu.last_dp.to_le_bytes();
+                set_lower(&mut last_dp);
+                fpu.last_dp = u64::from_le_bytes(last_dp);
+            }),
+            GdbRegister::Fop => update_fpu(vcpu, |fpu| {
+                let mut last_opcode = fpu.last_opcode.to_le_bytes();
+                set_lower(&mut last_opcode);
+                fpu.last_opcode = u16::from_le_bytes(last_opcode);
+            }),
+            GdbRegister::Xmm(n) => update_fpu(vcpu, |fpu| set_lower(&mut fpu.xmm[n][..])),
+            GdbRegister::Mxcsr => update_fpu(vcpu, |fpu| {
+                let mut mxcsr = fpu.mxcsr.to_le_bytes();
+                set_lower(&mut mxcsr);
+                fpu.mxcsr = u32::from_le_bytes(mxcsr);
+            }),
+        }
+    }
+
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     fn debug_read_memory<T: VcpuX86_64>(
         vcpu: &T,
@@ -689,7 +1110,7 @@ impl arch::LinuxArch for X8664arch {
 
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     fn debug_enable_singlestep<T: VcpuX86_64>(vcpu: &T) -> Result<()> {
-        vcpu.set_guest_debug(&[], true /* enable_singlestep */)
+        vcpu.set_guest_debug(&[], &[], true /* enable_singlestep */)
             .map_err(Error::EnableSinglestep)
     }
 
@@ -697,16 +1118,112 @@ impl arch::LinuxArch for X8664arch {
     fn debug_set_hw_breakpoints<T: VcpuX86_64>(
         vcpu: &T,
         breakpoints: &[GuestAddress],
+        watchpoints: &[Watchpoint],
     ) -> Result<()> {
-        vcpu.set_guest_debug(&breakpoints, false /* enable_singlestep */)
-            .map_err(Error::SetHwBreakpoint)
+        vcpu.set_guest_debug(
+            &breakpoints,
+            watchpoints,
+            false, /* enable_singlestep */
+        )
+        .map_err(Error::SetHwBreakpoint)
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+// Where the vCPU keeps each register of `X86_64Registers`.
+enum GdbRegister {
+    // A general register, rip or rflags.
+    General(fn(&mut Regs) -> &mut u64),
+    // The selector of a segment register.
+    Selector(fn(&mut Sregs) -> &mut Segment),
+    St(usize),
+    Fctrl,
+    Fstat,
+    Ftag,
+    Fiseg,
+    Fioff,
+    Foseg,
+    Fooff,
+    Fop,
+    Xmm(usize),
+    Mxcsr,
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+impl GdbRegister {
+    fn new(reg: usize) -> Option<GdbRegister> {
+        use GdbRegister::*;
+
+        Some(match reg {
+            0 => General(|r| &mut r.rax),
+            1 => General(|r| &mut r.rbx),
+            2 => General(|r| &mut r.rcx),
+            3 => General(|r| &mut r.rdx),
+            4 => General(|r| &mut r.rsi),
+            5 => General(|r| &mut r.rdi),
+            6 => General(|r| &mut r.rbp),
+            7 => General(|r| &mut r.rsp),
+            8 => General(|r| &mut r.r8),
+            9 => General(|r| &mut r.r9),
+            10 => General(|r| &mut r.r10),
+            11 => General(|r| &mut r.r11),
+            12 => General(|r| &mut r.r12),
+            13 => General(|r| &mut r.r13),
+            14 => General(|r| &mut r.r14),
+            15 => General(|r| &mut r.r15),
+            16 => General(|r| &mut r.rip),
+            17 => General(|r| &mut r.rflags),
+            18 => Selector(|s| &mut s.cs),
+            19 => Selector(|s| &mut s.ss),
+            20 => Selector(|s| &mut s.ds),
+            21 => Selector(|s| &mut s.es),
+            22 => Selector(|s| &mut s.fs),
+            23 => Selector(|s| &mut s.gs),
+            24..=31 => St(reg - 24),
+            32 => Fctrl,
+            33 => Fstat,
+            34 => Ftag,
+            35 => Fiseg,
+            36 => Fioff,
+            37 => Foseg,
+            38 => Fooff,
+            39 => Fop,
+            40..=55 => Xmm(reg - 40),
+            56 => Mxcsr,
+            _ => return None,
+        })
     }
 }
 
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+fn update_fpu<T: VcpuX86_64>(vcpu: &T, update: impl FnOnce(&mut Fpu)) -> Result<()> {
+    let mut fpu = vcpu.get_fpu().map_err(Error::ReadRegs)?;
+    update(&mut fpu);
+    vcpu.set_fpu(&fpu).map_err(Error::WriteRegs)
+}
+
+// KVM keeps the abridged x87 tag word of FXSAVE, with a bit set for each register that isn't
+// empty. gdb shows the full tag word, with two bits for each register that are 0b11 when it's
+// empty. Registers that aren't empty are shown as valid rather than classified as zero or special.
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+fn full_tag_word(ftwx: u8) -> u16 {
+    (0..8)
+        .filter(|i| ftwx & (1 << i) == 0)
+        .fold(0, |ftag, i| ftag | (0b11 << (2 * i)))
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+fn abridged_tag_word(ftag: u16) -> u8 {
+    (0..8)
+        .filter(|i| (ftag >> (2 * i)) & 0b11 != 0b11)
+        .fold(0, |ftwx, i| ftwx | (1 << i))
+}
+
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 // return the translated address and the size of the page it resides in.
 fn phys_addr(mem: &GuestMemory, vaddr: u64, sregs: &Sregs) -> Result<(u64, u64)> {
     const CR0_PG_MASK: u64 = 1 << 31;
+    const CR4_PSE_MASK: u64 = 1 << 4;
     const CR4_PAE_MASK: u64 = 1 << 5;
     const CR4_LA57_MASK: u64 = 1 << 12;
     const MSR_EFER_LMA: u64 = 1 << 10;
@@ -717,6 +1234,7 @@ fn phys_addr(mem: &GuestMemory, vaddr: u64, sregs: &Sregs) -> Result<(u64, u64)>
 
     const PAGE_SIZE_4K: u64 = 4 * 1024;
     const PAGE_SIZE_2M: u64 = 2 * 1024 * 1024;
+    const PAGE_SIZE_4M: u64 = 4 * 1024 * 1024;
     const PAGE_SIZE_1G: u64 = 1024 * 1024 * 1024;
 
     fn next_pte(mem: &GuestMemory, curr_table_addr: u64, vaddr: u64, level: usize) -> Result<u64> {
@@ -754,36 +1272,82 @@ fn phys_addr(mem: &GuestMemory, vaddr: u64, sregs: &Sregs) -> Result<(u64, u64)>
         ((addr >> offset) & 0x1ff) << 3
     }
 
+    // Translates `vaddr` with 32-bit paging, where the two levels of tables hold 1024 4 byte
+    // entries each.
+    fn legacy_phys_addr(mem: &GuestMemory, vaddr: u64, cr3: u64, pse: bool) -> Result<(u64, u64)> {
+        const ADDR_MASK: u64 = 0xffff_f000;
+        let read_ent = |addr: u64| -> Result<u64> {
+            let ent: u32 = mem
+                .read_obj_from_addr(GuestAddress(addr))
+                .map_err(|_| Error::TranslatingVirtAddr)?;
+            if u64::from(ent) & PAGE_PRESENT == 0 {
+                return Err(Error::PageNotPresent);
+            }
+            Ok(ent.into())
+        };
+
+        let pde = read_ent((cr3 & ADDR_MASK) + ((vaddr >> 22) & 0x3ff) * 4)?;
+        if pse && pde & PAGE_PSE_MASK != 0 {
+            // It's a 4M page with the PSE bit in the directory entry.
+            let paddr = pde & !(PAGE_SIZE_4M - 1) | page_offset(vaddr, PAGE_SIZE_4M);
+            return Ok((paddr, PAGE_SIZE_4M));
+        }
+        let pte = read_ent((pde & ADDR_MASK) + ((vaddr >> 12) & 0x3ff) * 4)?;
+        Ok((
+            pte & ADDR_MASK | page_offset(vaddr, PAGE_SIZE_4K),
+            PAGE_SIZE_4K,
+        ))
+    }
+
     if sregs.cr0 & CR0_PG_MASK == 0 {
         return Ok((vaddr, PAGE_SIZE_4K));
     }
 
     if sregs.cr4 & CR4_PAE_MASK == 0 {
-        return Err(Error::TranslatingVirtAddr);
+        return legacy_phys_addr(
+            mem,
+            vaddr & 0xffff_ffff,
+            sregs.cr3,
+            sregs.cr4 & CR4_PSE_MASK != 0,
+        );
     }
 
-    if sregs.efer & MSR_EFER_LMA != 0 {
-        // TODO - check LA57
-        if sregs.cr4 & CR4_LA57_MASK != 0 {}
-        let p4_ent = next_pte(mem, sregs.cr3, vaddr, 4)?;
+    let p2_table = if sregs.efer & MSR_EFER_LMA != 0 {
+        // With 5-level paging CR3 points at the PML5 table instead of the PML4 table.
+        let p4_table = if sregs.cr4 & CR4_LA57_MASK != 0 {
+            next_pte(mem, sregs.cr3, vaddr, 5)?
+        } else {
+            sregs.cr3
+        };
+        let p4_ent = next_pte(mem, p4_table, vaddr, 4)?;
         let p3_ent = next_pte(mem, p4_ent, vaddr, 3)?;
-        // TODO check if it's a 1G page with the PSE bit in p2_ent
         if p3_ent & PAGE_PSE_MASK != 0 {
             // It's a 1G page with the PSE bit in p3_ent
             let paddr = p3_ent & PTE_ADDR_MASK | page_offset(vaddr, PAGE_SIZE_1G);
             return Ok((paddr, PAGE_SIZE_1G));
         }
-        let p2_ent = next_pte(mem, p3_ent, vaddr, 2)?;
-        if p2_ent & PAGE_PSE_MASK != 0 {
-            // It's a 2M page with the PSE bit in p2_ent
-            let paddr = p2_ent & PTE_ADDR_MASK | page_offset(vaddr, PAGE_SIZE_2M);
-            return Ok((paddr, PAGE_SIZE_2M));
+        p3_ent
+    } else {
+        // 32-bit PAE paging: CR3 points at four 8 byte entries indexed by bits 31:30.
+        let vaddr = vaddr & 0xffff_ffff;
+        let pdpte: u64 = mem
+            .read_obj_from_addr(GuestAddress((sregs.cr3 & 0xffff_ffe0) + (vaddr >> 30) * 8))
+            .map_err(|_| Error::TranslatingVirtAddr)?;
+        if pdpte & PAGE_PRESENT == 0 {
+            return Err(Error::PageNotPresent);
         }
-        let p1_ent = next_pte(mem, p2_ent, vaddr, 1)?;
-        let paddr = p1_ent & PTE_ADDR_MASK | page_offset(vaddr, PAGE_SIZE_4K);
-        return Ok((paddr, PAGE_SIZE_4K));
+        pdpte
+    };
+
+    let p2_ent = next_pte(mem, p2_table, vaddr, 2)?;
+    if p2_ent & PAGE_PSE_MASK != 0 {
+        // It's a 2M page with the PSE bit in p2_ent
+        let paddr = p2_ent & PTE_ADDR_MASK | page_offset(vaddr, PAGE_SIZE_2M);
+        return Ok((paddr, PAGE_SIZE_2M));
     }
-    Err(Error::TranslatingVirtAddr)
+    let p1_ent = next_pte(mem, p2_ent, vaddr, 1)?;
+    let paddr = p1_ent & PTE_ADDR_MASK | page_offset(vaddr, PAGE_SIZE_4K);
+    Ok((paddr, PAGE_SIZE_4K))
 }
 
 impl X8664arch {
@@ -793,27 +1357,60 @@ impl X8664arch {
     ///
     /// * `mem` - The memory to be used by the guest.
     /// * `bios_image` - the File object for the specified bios
-    fn load_bios(mem: &GuestMemory, bios_image: &mut File) -> Result<()> {
+    /// Returns the size of the BIOS image, which is mapped whole below 4 GiB, so has to be a
+    /// multiple of the page size.
+    fn get_bios_size(bios_image: &mut File) -> Result<u64> {
         let bios_image_length = bios_image
             .seek(io::SeekFrom::End(0))
             .map_err(Error::LoadBios)?;
-        if bios_image_length != BIOS_LEN as u64 {
+        if bios_image_length == 0
+            || bios_image_length % base::pagesize() as u64 != 0
+            || bios_image_length > FIRMWARE_MAX_LEN
+        {
             return Err(Error::LoadBios(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
-                    "bios was {} bytes, expected {}",
-                    bios_image_length, BIOS_LEN
+                    "bios was {} bytes, expected a multiple of {} up to {}",
+                    bios_image_length,
+                    base::pagesize(),
+                    FIRMWARE_MAX_LEN
                 ),
             )));
         }
+        Ok(bios_image_length)
+    }
+
+    fn load_bios(mem: &GuestMemory, bios_image: &mut File, bios_size: u64) -> Result<()> {
         bios_image
             .seek(io::SeekFrom::Start(0))
             .map_err(Error::LoadBios)?;
-        mem.read_to_memory(GuestAddress(BIOS_START), bios_image, BIOS_LEN)
-            .map_err(Error::SetupGuestMemory)?;
+        mem.read_to_memory(
+            GuestAddress(bios_start(bios_size)),
+            bios_image,
+            bios_size as usize,
+        )
+        .map_err(Error::SetupGuestMemory)?;
         Ok(())
     }
 
+    /// Sets up the flash right below the BIOS, which firmware like OVMF keeps its variable store
+    /// in. Unlike the BIOS, the flash isn't guest memory, so the contents firmware programs can be
+    /// written back to `pflash_image`.
+    fn setup_pflash(mmio_bus: &mut devices::Bus, pflash_image: File, bios_size: u64) -> Result<()> {
+        let pflash = devices::Pflash::new(pflash_image).map_err(Error::CreatePflash)?;
+        let pflash_size = pflash.size();
+        if pflash_size > FIRMWARE_MAX_LEN - bios_size {
+            return Err(Error::PflashTooLarge(pflash_size));
+        }
+        mmio_bus
+            .insert(
+                Arc::new(Mutex::new(pflash)),
+                bios_start(bios_size) - pflash_size,
+                pflash_size,
+            )
+            .map_err(Error::RegisterPflash)
+    }
+
     /// Loads the kernel from an open file.
     ///
     /// # Arguments
@@ -832,6 +1429,23 @@ impl X8664arch {
         }
     }
 
+    /// Loads an elf kernel that supports the PVH boot protocol at the physical addresses it was
+    /// linked at. Returns its entry point and the end of the loaded image, or `None` if the
+    /// kernel isn't an elf image supporting PVH.
+    fn load_pvh_kernel(
+        mem: &GuestMemory,
+        kernel_image: &mut File,
+    ) -> Result<Option<(GuestAddress, u64)>> {
+        let pvh_entry = match kernel_loader::get_pvh_entry(kernel_image) {
+            Ok(Some(pvh_entry)) => GuestAddress(pvh_entry),
+            Ok(None) | Err(kernel_loader::Error::InvalidElfMagicNumber) => return Ok(None),
+            Err(e) => return Err(Error::LoadKernel(e)),
+        };
+        let kernel_end = kernel_loader::load_kernel(mem, GuestAddress(0), kernel_image)
+            .map_err(Error::LoadKernel)?;
+        Ok(Some((pvh_entry, kernel_end)))
+    }
+
     /// Configures the system memory space should be called once per vm before
     /// starting vcpu threads.
     ///
@@ -873,28 +1487,12 @@ impl X8664arch {
         };
 
         let initrd = match initrd_file {
-            Some(mut initrd_file) => {
-                let mut initrd_addr_max = u64::from(params.hdr.initrd_addr_max);
-                // Default initrd_addr_max for old kernels (see Documentation/x86/boot.txt).
-                if initrd_addr_max == 0 {
-                    initrd_addr_max = 0x37FFFFFF;
-                }
-
-                let mem_max = mem.end_addr().offset() - 1;
-                if initrd_addr_max > mem_max {
-                    initrd_addr_max = mem_max;
-                }
-
-                let (initrd_start, initrd_size) = arch::load_image_high(
-                    mem,
-                    &mut initrd_file,
-                    GuestAddress(free_addr),
-                    GuestAddress(initrd_addr_max),
-                    base::pagesize() as u64,
-                )
-                .map_err(Error::LoadInitrd)?;
-                Some((initrd_start, initrd_size))
-            }
+            Some(initrd_file) => Some(Self::load_initrd(
+                mem,
+                initrd_file,
+                free_addr,
+                u64::from(params.hdr.initrd_addr_max),
+            )?),
             None => None,
         };
 
@@ -911,12 +1509,84 @@ impl X8664arch {
         Ok(())
     }
 
+    /// Configures the system memory space of a kernel booted with the PVH boot protocol. Should
+    /// be called once per vm before starting vcpu threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory to be used by the guest.
+    /// * `cmdline` - the kernel commandline
+    /// * `initrd_file` - an initial ramdisk image
+    /// * `kernel_end` - the end of the loaded kernel image
+    /// * `rsdp_addr` - the address of the ACPI RSDP, if there is one
+    fn setup_pvh_system_memory(
+        mem: &GuestMemory,
+        cmdline: &CStr,
+        initrd_file: Option<File>,
+        kernel_end: u64,
+        rsdp_addr: Option<GuestAddress>,
+    ) -> Result<()> {
+        kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)
+            .map_err(Error::LoadCmdline)?;
+
+        let initrd = match initrd_file {
+            Some(initrd_file) => Some(Self::load_initrd(mem, initrd_file, kernel_end, 0)?),
+            None => None,
+        };
+
+        configure_pvh_system(mem, GuestAddress(CMDLINE_OFFSET), initrd, rsdp_addr)
+    }
+
+    /// Loads the initrd as high as possible in guest memory below `initrd_addr_max`, past
+    /// `free_addr`. An `initrd_addr_max` of 0 means the default of old kernels.
+    fn load_initrd(
+        mem: &GuestMemory,
+        mut initrd_file: File,
+        free_addr: u64,
+        mut initrd_addr_max: u64,
+    ) -> Result<(GuestAddress, usize)> {
+        // Default initrd_addr_max for old kernels (see Documentation/x86/boot.txt).
+        if initrd_addr_max == 0 {
+            initrd_addr_max = 0x37FFFFFF;
+        }
+
+        let mem_max = mem.end_addr().offset() - 1;
+        if initrd_addr_max > mem_max {
+            initrd_addr_max = mem_max;
+        }
+
+        arch::load_image_high(
+            mem,
+            &mut initrd_file,
+            GuestAddress(free_addr),
+            GuestAddress(initrd_addr_max),
+            base::pagesize() as u64,
+        )
+        .map_err(Error::LoadInitrd)
+    }
+
     /// This creates a GuestMemory object for this VM
     ///
     /// * `mem_size` - Desired physical memory size in bytes for this VM
-    fn setup_memory(mem_size: u64, has_bios: bool) -> Result<GuestMemory> {
-        let arch_mem_regions = arch_memory_regions(mem_size, has_bios);
-        let mem = GuestMemory::new(&arch_mem_regions).map_err(Error::SetupGuestMemory)?;
+    /// * `read_only_bios` - Whether the guest may only read the memory the BIOS is loaded into
+    /// * `mergeable` - Whether the memory is private memory that KSM may merge
+    fn setup_memory(
+        mem_size: u64,
+        bios_size: Option<u64>,
+        read_only_bios: bool,
+        mergeable: bool,
+    ) -> Result<GuestMemory> {
+        let arch_mem_regions = arch_memory_regions(mem_size, bios_size);
+        let read_only = match bios_size {
+            Some(bios_size) if read_only_bios => vec![GuestAddress(bios_start(bios_size))],
+            _ => Vec::new(),
+        };
+        let mem = if mergeable {
+            GuestMemory::new_mergeable(&arch_mem_regions, &read_only)
+        } else {
+            GuestMemory::new_with_read_only(&arch_mem_regions, &read_only)
+        }
+        .map_err(Error::SetupGuestMemory)?;
         Ok(mem)
     }
 
@@ -970,11 +1640,19 @@ impl X8664arch {
             fn debug_label(&self) -> String {
                 "no device".to_owned()
             }
+
+            fn snapshot(&mut self) -> Option<Vec<u8>> {
+                Some(Vec::new())
+            }
+
+            fn restore(&mut self, data: &[u8]) -> bool {
+                data.is_empty()
+            }
         }
 
         let mut io_bus = devices::Bus::new();
 
-        let mem_regions = arch_memory_regions(mem_size, false);
+        let mem_regions = arch_memory_regions(mem_size, None);
 
         let mem_below_4g = mem_regions
             .iter()
@@ -1020,6 +1698,115 @@ impl X8664arch {
         Ok(io_bus)
     }
 
+    /// Sets up the fw_cfg device that BIOS payloads use to find the kernel, initrd and command
+    /// line to boot, the boot order, the memory map and any extra files given by the user.
+    ///
+    /// # Arguments
+    ///
+    /// * - `io_bus` - the I/O bus to add the device to
+    /// * - `mem_size` - the size in bytes of physical ram for the guest
+    /// * - `vcpu_count` - the number of vcpus
+    /// * - `kernel` - optional kernel image, split into setup and kernel blobs if it is a bzImage
+    /// * - `initrd` - optional initial ramdisk image
+    /// * - `params` - kernel command line parameters
+    /// * - `files` - named files to add
+    /// * - `boot_order` - firmware device paths to boot from, in order
+    fn setup_fw_cfg(
+        io_bus: &mut devices::Bus,
+        mem_size: u64,
+        vcpu_count: usize,
+        kernel: Option<File>,
+        initrd: Option<File>,
+        params: &[String],
+        files: Vec<(String, Vec<u8>)>,
+        boot_order: &[String],
+    ) -> Result<()> {
+        fn read_image(mut image: File) -> Result<Vec<u8>> {
+            let mut data = Vec::new();
+            image
+                .read_to_end(&mut data)
+                .map_err(Error::ReadFwCfgImage)?;
+            Ok(data)
+        }
+
+        let mut fw_cfg = devices::FwCfg::new();
+        fw_cfg.add_u64(fw_cfg::FW_CFG_RAM_SIZE, mem_size);
+        fw_cfg.add_u16(fw_cfg::FW_CFG_NB_CPUS, vcpu_count as u16);
+        fw_cfg.add_u16(fw_cfg::FW_CFG_MAX_CPUS, vcpu_count as u16);
+        // IRQ0 of the PIT is routed to GSI 2, so the BIOS has to add an interrupt source
+        // override for it to the MADT.
+        fw_cfg.add_u32(fw_cfg::FW_CFG_IRQ0_OVERRIDE, 1);
+
+        if let Some(kernel) = kernel {
+            let mut kernel = read_image(kernel)?;
+            // Like QEMU, hand out the real-mode setup code of a bzImage separately from the
+            // protected-mode kernel.
+            if kernel.len() > BZIMAGE_HEADER_MAGIC_OFFSET + 4
+                && &kernel[BZIMAGE_HEADER_MAGIC_OFFSET..BZIMAGE_HEADER_MAGIC_OFFSET + 4] == b"HdrS"
+            {
+                let setup_sects = match kernel[BZIMAGE_SETUP_SECTS_OFFSET] {
+                    0 => 4,
+                    n => n as usize,
+                };
+                let setup_size = ((setup_sects + 1) * 512).min(kernel.len());
+                let protected_mode = kernel.split_off(setup_size);
+                fw_cfg.add_u32(fw_cfg::FW_CFG_SETUP_SIZE, kernel.len() as u32);
+                fw_cfg.add_item(fw_cfg::FW_CFG_SETUP_DATA, kernel);
+                kernel = protected_mode;
+            }
+            fw_cfg.add_u32(fw_cfg::FW_CFG_KERNEL_SIZE, kernel.len() as u32);
+            fw_cfg.add_item(fw_cfg::FW_CFG_KERNEL_DATA, kernel);
+        }
+
+        if let Some(initrd) = initrd {
+            let initrd = read_image(initrd)?;
+            fw_cfg.add_u32(fw_cfg::FW_CFG_INITRD_SIZE, initrd.len() as u32);
+            fw_cfg.add_item(fw_cfg::FW_CFG_INITRD_DATA, initrd);
+        }
+
+        if !params.is_empty() {
+            let mut cmdline = params.join(" ").into_bytes();
+            cmdline.push(0);
+            fw_cfg.add_u32(fw_cfg::FW_CFG_CMDLINE_SIZE, cmdline.len() as u32);
+            fw_cfg.add_item(fw_cfg::FW_CFG_CMDLINE_DATA, cmdline);
+        }
+
+        let mut e820 = Vec::new();
+        for (addr, size) in arch_memory_regions(mem_size, None) {
+            e820.extend_from_slice(&addr.offset().to_le_bytes());
+            e820.extend_from_slice(&size.to_le_bytes());
+            e820.extend_from_slice(&E820_RAM.to_le_bytes());
+        }
+        e820.extend_from_slice(&PCIE_CFG_MMIO_START.to_le_bytes());
+        e820.extend_from_slice(&PCIE_CFG_MMIO_SIZE.to_le_bytes());
+        e820.extend_from_slice(&E820_RESERVED.to_le_bytes());
+        fw_cfg
+            .add_file("etc/e820", e820)
+            .map_err(Error::CreateFwCfg)?;
+
+        if !boot_order.is_empty() {
+            let mut bootorder = boot_order.join("\n").into_bytes();
+            bootorder.push(0);
+            fw_cfg
+                .add_file("bootorder", bootorder)
+                .map_err(Error::CreateFwCfg)?;
+        }
+
+        for (name, data) in files {
+            fw_cfg.add_file(&name, data).map_err(Error::CreateFwCfg)?;
+        }
+
+        io_bus
+            .insert(
+                Arc::new(Mutex::new(fw_cfg)),
+                fw_cfg::FW_CFG_IO_BASE,
+                fw_cfg::FW_CFG_IO_SIZE,
+            )
+            .unwrap();
+
+        Ok(())
+    }
+
     /// Sets up the acpi devices for this platform and
     /// return the resources which is used to set the ACPI tables.
     ///
@@ -1029,20 +1816,30 @@ impl X8664arch {
     /// * - `resources` the SystemAllocator to allocate IO and MMIO for acpi
     ///                devices.
     /// * - `suspend_evt` the event object which used to suspend the vm
+    /// * - `sleep_evt` the event object which used to suspend the vm for S3
     /// * - `sdts` ACPI system description tables
     /// * - `irq_chip` the IrqChip object for registering irq events
     /// * - `battery` indicate whether to create the battery
     /// * - `mmio_bus` the MMIO bus to add the devices to
+    /// * - `vcpu_count` the number of vcpus present at boot
+    /// * - `max_vcpu_count` the number of vcpus the guest can have after hotplug
     fn setup_acpi_devices(
         io_bus: &mut devices::Bus,
         resources: &mut SystemAllocator,
         suspend_evt: Event,
+        sleep_evt: Event,
         exit_evt: Event,
         sdts: Vec<SDT>,
         irq_chip: &mut impl IrqChip,
         battery: (&Option<BatteryType>, Option<Minijail>),
         mmio_bus: &mut devices::Bus,
-    ) -> Result<(acpi::ACPIDevResource, Option<BatControl>)> {
+        vcpu_count: usize,
+        max_vcpu_count: usize,
+    ) -> Result<(
+        acpi::ACPIDevResource,
+        Option<BatControl>,
+        Option<Arc<Mutex<CpuHotplugController>>>,
+    )> {
         // The AML data for the acpi devices
         let mut amls = Vec::new();
 
@@ -1059,7 +1856,7 @@ impl X8664arch {
             None => 0x600,
         };
 
-        let pmresource = devices::ACPIPMResource::new(suspend_evt, exit_evt);
+        let pmresource = devices::ACPIPMResource::new(suspend_evt, sleep_evt, exit_evt);
         Aml::to_aml_bytes(&pmresource, &mut amls);
         let pm = Arc::new(Mutex::new(pmresource));
         io_bus
@@ -1074,7 +1871,7 @@ impl X8664arch {
         let bat_control = if let Some(battery_type) = battery.0 {
             match battery_type {
                 BatteryType::Goldfish => {
-                    let control_socket = arch::add_goldfish_battery(
+                    let (control_socket, _) = arch::add_goldfish_battery(
                         &mut amls,
                         battery.1,
                         mmio_bus,
@@ -1093,6 +1890,42 @@ impl X8664arch {
             None
         };
 
+        let cpu_hotplug = if max_vcpu_count > vcpu_count {
+            let alloc = resources.get_anon_alloc();
+            let io_base = match resources.io_allocator() {
+                Some(io) => io
+                    .allocate_with_align(
+                        devices::cpu_hotplug::CPU_HOTPLUG_RESOURCE_LEN as u64,
+                        alloc,
+                        "CpuHotplug".to_string(),
+                        (devices::cpu_hotplug::CPU_HOTPLUG_RESOURCE_LEN as u64).next_power_of_two(),
+                    )
+                    .map_err(Error::AllocateIOResouce)?,
+                None => 0x610,
+            };
+            let gsi = resources.allocate_irq().ok_or(Error::AllocateIrq)?;
+            let irq_evt = Event::new().map_err(Error::CreateEvent)?;
+            // Edge triggered, as the guest reads and clears the pending events in one go.
+            irq_chip
+                .register_irq_event(gsi, &irq_evt, None)
+                .map_err(Error::RegisterIrqfd)?;
+
+            let controller =
+                CpuHotplugController::new(io_base, gsi, irq_evt, vcpu_count, max_vcpu_count);
+            Aml::to_aml_bytes(&controller, &mut amls);
+            let controller = Arc::new(Mutex::new(controller));
+            io_bus
+                .insert(
+                    controller.clone(),
+                    io_base,
+                    devices::cpu_hotplug::CPU_HOTPLUG_RESOURCE_LEN as u64,
+                )
+                .map_err(Error::RegisterCpuHotplug)?;
+            Some(controller)
+        } else {
+            None
+        };
+
         Ok((
             acpi::ACPIDevResource {
                 amls,
@@ -1100,6 +1933,7 @@ impl X8664arch {
                 sdts,
             },
             bat_control,
+            cpu_hotplug,
         ))
     }
 
@@ -1149,9 +1983,12 @@ mod test_integration;
 mod tests {
     use super::*;
 
+    const BIOS_LEN: usize = 1 << 20;
+    const BIOS_START: u64 = FIRST_ADDR_PAST_32BITS - (BIOS_LEN as u64);
+
     #[test]
     fn regions_lt_4gb_nobios() {
-        let regions = arch_memory_regions(1u64 << 29, /* has_bios */ false);
+        let regions = arch_memory_regions(1u64 << 29, /* bios_size */ None);
         assert_eq!(1, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(1u64 << 29, regions[0].1);
@@ -1159,7 +1996,7 @@ mod tests {
 
     #[test]
     fn regions_gt_4gb_nobios() {
-        let regions = arch_memory_regions((1u64 << 32) + 0x8000, /* has_bios */ false);
+        let regions = arch_memory_regions((1u64 << 32) + 0x8000, /* bios_size */ None);
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(1u64 << 32), regions[1].0);
@@ -1167,7 +2004,7 @@ mod tests {
 
     #[test]
     fn regions_lt_4gb_bios() {
-        let regions = arch_memory_regions(1u64 << 29, /* has_bios */ true);
+        let regions = arch_memory_regions(1u64 << 29, /* bios_size */ Some(BIOS_LEN as u64));
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(1u64 << 29, regions[0].1);
@@ -1177,7 +2014,10 @@ mod tests {
 
     #[test]
     fn regions_gt_4gb_bios() {
-        let regions = arch_memory_regions((1u64 << 32) + 0x8000, /* has_bios */ true);
+        let regions = arch_memory_regions(
+            (1u64 << 32) + 0x8000,
+            /* bios_size */ Some(BIOS_LEN as u64),
+        );
         assert_eq!(3, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(BIOS_START), regions[1].0);
@@ -1188,7 +2028,7 @@ mod tests {
     #[test]
     fn regions_eq_4gb_nobios() {
         // Test with size = 3328, which is exactly 4 GiB minus the size of the gap (768 MiB).
-        let regions = arch_memory_regions(3328 << 20, /* has_bios */ false);
+        let regions = arch_memory_regions(3328 << 20, /* bios_size */ None);
         assert_eq!(1, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(3328 << 20, regions[0].1);
@@ -1197,11 +2037,149 @@ mod tests {
     #[test]
     fn regions_eq_4gb_bios() {
         // Test with size = 3328, which is exactly 4 GiB minus the size of the gap (768 MiB).
-        let regions = arch_memory_regions(3328 << 20, /* has_bios */ true);
+        let regions = arch_memory_regions(3328 << 20, /* bios_size */ Some(BIOS_LEN as u64));
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(3328 << 20, regions[0].1);
         assert_eq!(GuestAddress(BIOS_START), regions[1].0);
         assert_eq!(BIOS_LEN as u64, regions[1].1);
     }
+
+    #[test]
+    fn pvh_start_info() {
+        let mem = GuestMemory::new(&arch_memory_regions(1u64 << 29, None)).unwrap();
+        let initrd = (GuestAddress(0x100_0000), 0x1234);
+        configure_pvh_system(
+            &mem,
+            GuestAddress(CMDLINE_OFFSET),
+            Some(initrd),
+            Some(GuestAddress(ACPI_HI_RSDP_WINDOW_BASE)),
+        )
+        .unwrap();
+
+        let start_info: pvh::StartInfo = mem
+            .read_obj_from_addr(GuestAddress(PVH_INFO_START))
+            .unwrap();
+        assert_eq!(pvh::XEN_HVM_START_MAGIC_VALUE, start_info.magic);
+        assert_eq!(CMDLINE_OFFSET, start_info.cmdline_paddr);
+        assert_eq!(ACPI_HI_RSDP_WINDOW_BASE, start_info.rsdp_paddr);
+
+        assert_eq!(1, start_info.nr_modules);
+        let module: pvh::ModlistEntry = mem
+            .read_obj_from_addr(GuestAddress(start_info.modlist_paddr))
+            .unwrap();
+        assert_eq!(initrd.0.offset(), module.paddr);
+        assert_eq!(initrd.1 as u64, module.size);
+
+        // Low memory, RAM from the first megabyte to the end of memory and the reserved ECAM.
+        assert_eq!(3, start_info.memmap_entries);
+        let ram: pvh::MemmapTableEntry = mem
+            .read_obj_from_addr(GuestAddress(
+                start_info.memmap_paddr + mem::size_of::<pvh::MemmapTableEntry>() as u64,
+            ))
+            .unwrap();
+        assert_eq!(PVH_HIGH_RAM_START, ram.addr);
+        assert_eq!((1u64 << 29) - PVH_HIGH_RAM_START, ram.size);
+        assert_eq!(E820_RAM, ram.type_);
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    fn paging_mem() -> GuestMemory {
+        GuestMemory::new(&[(GuestAddress(0), 0x100_0000)]).unwrap()
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    #[test]
+    fn phys_addr_long_mode() {
+        let mem = paging_mem();
+        let vaddr = 0xffff_8000_0020_1234u64;
+        let index = |level: u64| (vaddr >> (12 + 9 * (level - 1))) & 0x1ff;
+        // PML4 at 0x1000, PDPT at 0x2000, PD at 0x3000, PT at 0x4000, data page at 0x80_0000.
+        for (level, table) in &[(4, 0x1000u64), (3, 0x2000), (2, 0x3000)] {
+            mem.write_obj_at_addr(table + 0x1000 + 3, GuestAddress(table + index(*level) * 8))
+                .unwrap();
+        }
+        mem.write_obj_at_addr(0x80_0003u64, GuestAddress(0x4000 + index(1) * 8))
+            .unwrap();
+        let mut sregs = Sregs {
+            cr0: 1 << 31,
+            cr3: 0x1000,
+            cr4: 1 << 5,
+            efer: 1 << 10,
+            ..Default::default()
+        };
+        assert_eq!(phys_addr(&mem, vaddr, &sregs).unwrap(), (0x80_0234, 0x1000));
+
+        // A 2M page in the PD.
+        mem.write_obj_at_addr(0x60_0083u64, GuestAddress(0x3000 + index(2) * 8))
+            .unwrap();
+        assert_eq!(
+            phys_addr(&mem, vaddr, &sregs).unwrap(),
+            (0x60_1234, 0x20_0000)
+        );
+
+        // The same tables under a PML5 at 0x5000.
+        let pml5_index = (vaddr >> 48) & 0x1ff;
+        mem.write_obj_at_addr(0x1003u64, GuestAddress(0x5000 + pml5_index * 8))
+            .unwrap();
+        sregs.cr3 = 0x5000;
+        sregs.cr4 |= 1 << 12;
+        assert_eq!(
+            phys_addr(&mem, vaddr, &sregs).unwrap(),
+            (0x60_1234, 0x20_0000)
+        );
+
+        // Nothing is mapped at the bottom of the address space.
+        match phys_addr(&mem, 0, &sregs) {
+            Err(Error::PageNotPresent) => {}
+            r => panic!("unexpected translation of an unmapped page: {:?}", r),
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    #[test]
+    fn phys_addr_32bit() {
+        let mem = paging_mem();
+        let vaddr = 0xc040_1234u64;
+        let mut sregs = Sregs {
+            cr0: 1 << 31,
+            cr3: 0x1000,
+            ..Default::default()
+        };
+
+        // Two levels of 4 byte entries: PD at 0x1000, PT at 0x2000.
+        mem.write_obj_at_addr(0x2003u32, GuestAddress(0x1000 + (vaddr >> 22) * 4))
+            .unwrap();
+        mem.write_obj_at_addr(
+            0x80_0003u32,
+            GuestAddress(0x2000 + ((vaddr >> 12) & 0x3ff) * 4),
+        )
+        .unwrap();
+        assert_eq!(phys_addr(&mem, vaddr, &sregs).unwrap(), (0x80_0234, 0x1000));
+
+        // PAE: four PDPTEs at 0x3000, PD at 0x4000 with a 2M page.
+        mem.write_obj_at_addr(0x4001u64, GuestAddress(0x3000 + (vaddr >> 30) * 8))
+            .unwrap();
+        mem.write_obj_at_addr(
+            0x60_0083u64,
+            GuestAddress(0x4000 + ((vaddr >> 21) & 0x1ff) * 8),
+        )
+        .unwrap();
+        sregs.cr3 = 0x3000;
+        sregs.cr4 = 1 << 5;
+        assert_eq!(
+            phys_addr(&mem, vaddr, &sregs).unwrap(),
+            (0x60_1234, 0x20_0000)
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    #[test]
+    fn gdb_tag_words() {
+        // st0 and st7 in use, the others empty.
+        assert_eq!(full_tag_word(0b1000_0001), 0b0011_1111_1111_1100);
+        assert_eq!(abridged_tag_word(0b0011_1111_1111_1100), 0b1000_0001);
+        // Zero and special registers aren't empty either.
+        assert_eq!(abridged_tag_word(0b1111_1111_1110_0100), 0b0000_0111);
+    }
 }
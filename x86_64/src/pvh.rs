@@ -0,0 +1,58 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Structures passed to kernels booted with the PVH boot protocol. See
+//! xen/include/public/arch-x86/hvm/start_info.h in the Xen sources for their definitions.
+
+use data_model::DataInit;
+
+/// The value of `StartInfo::magic`.
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
+/// The version of `StartInfo` that describes the memory map.
+pub const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Describes the boot to the kernel, whose entry point gets its address in `ebx`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+// Safe as StartInfo structure only contains raw data
+unsafe impl DataInit for StartInfo {}
+
+/// A module, such as an initrd, loaded for the kernel.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ModlistEntry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+// Safe as ModlistEntry structure only contains raw data
+unsafe impl DataInit for ModlistEntry {}
+
+/// An entry of the memory map, using the e820 memory types.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemmapTableEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+// Safe as MemmapTableEntry structure only contains raw data
+unsafe impl DataInit for MemmapTableEntry {}
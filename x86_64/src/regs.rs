@@ -147,7 +147,7 @@ fn append_mtrr_entries(vpu: &dyn VcpuX86_64, pci_start: u64, entries: &mut Vec<R
     });
 }
 
-fn create_msr_entries(vcpu: &dyn VcpuX86_64, pci_start: u64) -> Vec<Register> {
+fn create_msr_entries(vcpu: &dyn VcpuX86_64, pci_start: u64, nested_vmx: bool) -> Vec<Register> {
     let mut entries = vec![
         Register {
             id: crate::msr_index::MSR_IA32_SYSENTER_CS,
@@ -192,17 +192,67 @@ fn create_msr_entries(vcpu: &dyn VcpuX86_64, pci_start: u64) -> Vec<Register> {
             value: crate::msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64,
         },
     ];
+    if nested_vmx {
+        // Firmware normally does this, so the guest kernel finds VMXON already allowed.
+        entries.push(Register {
+            id: crate::msr_index::MSR_IA32_FEATURE_CONTROL,
+            value: (crate::msr_index::FEATURE_CONTROL_LOCKED
+                | crate::msr_index::FEATURE_CONTROL_VMXON_ENABLED_OUTSIDE_SMX)
+                as u64,
+        });
+    }
     append_mtrr_entries(vcpu, pci_start, &mut entries);
     entries
 }
 
+// The kvmclock MSRs, which msr_index doesn't have.
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+// The MSRs kept in a VM snapshot: those set up at boot, and those the guest kernel sets up for
+// system calls, per-CPU data and its clock. The rest of the VCPU state has them covered.
+const SNAPSHOT_MSRS: &[u32] = &[
+    crate::msr_index::MSR_IA32_SYSENTER_CS,
+    crate::msr_index::MSR_IA32_SYSENTER_ESP,
+    crate::msr_index::MSR_IA32_SYSENTER_EIP,
+    crate::msr_index::MSR_STAR,
+    crate::msr_index::MSR_CSTAR,
+    crate::msr_index::MSR_KERNEL_GS_BASE,
+    crate::msr_index::MSR_SYSCALL_MASK,
+    crate::msr_index::MSR_LSTAR,
+    crate::msr_index::MSR_TSC_AUX,
+    crate::msr_index::MSR_IA32_TSC,
+    crate::msr_index::MSR_IA32_MISC_ENABLE,
+    crate::msr_index::MSR_IA32_CR_PAT,
+    crate::msr_index::MSR_MTRRdefType,
+    crate::msr_index::MSR_IA32_FEATURE_CONTROL,
+    MSR_KVM_WALL_CLOCK_NEW,
+    MSR_KVM_SYSTEM_TIME_NEW,
+];
+
+/// Reads the MSRs to keep in a VM snapshot of `vcpu`, skipping those the host doesn't let the
+/// guest use.
+pub fn snapshot_msrs(vcpu: &dyn VcpuX86_64) -> Vec<Register> {
+    let mut msrs = Vec::with_capacity(SNAPSHOT_MSRS.len());
+    for &id in SNAPSHOT_MSRS {
+        // KVM stops at the first MSR it can't read, so they are read one at a time.
+        let mut msr = vec![Register { id, value: 0 }];
+        if vcpu.get_msrs(&mut msr).is_ok() {
+            msrs.extend(msr);
+        }
+    }
+    msrs
+}
+
 /// Configure Model specific registers for x86
 ///
 /// # Arguments
 ///
 /// * `vcpu` - Structure for the vcpu that holds the vcpu fd.
-pub fn setup_msrs(vcpu: &dyn VcpuX86_64, pci_start: u64) -> Result<()> {
-    let msrs = create_msr_entries(vcpu, pci_start);
+/// * `pci_start` - Where the PCI hole below 4G starts, which isn't cached.
+/// * `nested_vmx` - Whether the guest gets VMX, and may use it.
+pub fn setup_msrs(vcpu: &dyn VcpuX86_64, pci_start: u64, nested_vmx: bool) -> Result<()> {
+    let msrs = create_msr_entries(vcpu, pci_start, nested_vmx);
     vcpu.set_msrs(&msrs).map_err(Error::MsrIoctlFailed)
 }
 
@@ -242,6 +292,24 @@ pub fn setup_regs(vcpu: &dyn VcpuX86_64, boot_ip: u64, boot_sp: u64, boot_si: u6
     vcpu.set_regs(&regs).map_err(Error::SettingRegistersIoctl)
 }
 
+/// Configure base registers for a CPU entering a kernel through its PVH entry point.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `boot_ip` - Starting instruction pointer.
+/// * `start_info` - Address of the `hvm_start_info` structure describing the boot.
+pub fn setup_pvh_regs(vcpu: &dyn VcpuX86_64, boot_ip: u64, start_info: u64) -> Result<()> {
+    let regs = Regs {
+        rflags: 0x0000000000000002u64,
+        rip: boot_ip,
+        rbx: start_info,
+        ..Default::default()
+    };
+
+    vcpu.set_regs(&regs).map_err(Error::SettingRegistersIoctl)
+}
+
 const X86_CR0_PE: u64 = 0x1;
 const X86_CR0_PG: u64 = 0x80000000;
 const X86_CR4_PAE: u64 = 0x20;
@@ -310,6 +378,40 @@ fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut Sregs) -> Result<
     Ok(())
 }
 
+fn configure_pvh_segments_and_sregs(mem: &GuestMemory, sregs: &mut Sregs) -> Result<()> {
+    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
+        gdt::gdt_entry(0, 0, 0),            // NULL
+        gdt::gdt_entry(0xc09b, 0, 0xfffff), // CODE
+        gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
+        gdt::gdt_entry(0x008b, 0, 0x67),    // TSS
+    ];
+
+    let code_seg = gdt::segment_from_gdt(gdt_table[1], 1);
+    let data_seg = gdt::segment_from_gdt(gdt_table[2], 2);
+    let tss_seg = gdt::segment_from_gdt(gdt_table[3], 3);
+
+    // Write segments
+    write_gdt_table(&gdt_table[..], mem)?;
+    sregs.gdt.base = BOOT_GDT_OFFSET as u64;
+    sregs.gdt.limit = mem::size_of_val(&gdt_table) as u16 - 1;
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.tr = tss_seg;
+
+    /* 32-bit protected mode with paging disabled */
+    sregs.cr0 |= X86_CR0_PE;
+    sregs.cr0 &= !X86_CR0_PG;
+    sregs.cr4 = 0;
+    sregs.efer = 0;
+
+    Ok(())
+}
+
 fn setup_page_tables(mem: &GuestMemory, sregs: &mut Sregs) -> Result<()> {
     // Puts PML4 right after zero page but aligned to 4k.
     let boot_pml4_addr = GuestAddress(0x9000);
@@ -354,6 +456,23 @@ pub fn setup_sregs(mem: &GuestMemory, vcpu: &dyn VcpuX86_64) -> Result<()> {
     Ok(())
 }
 
+/// Configures the segment registers for a CPU entering a kernel through its PVH entry point, in
+/// 32-bit protected mode with paging disabled.
+///
+/// # Arguments
+///
+/// * `mem` - The memory that will be passed to the guest.
+/// * `vcpu` - The VCPU to configure registers on.
+pub fn setup_pvh_sregs(mem: &GuestMemory, vcpu: &dyn VcpuX86_64) -> Result<()> {
+    let mut sregs = vcpu.get_sregs().map_err(Error::GetSRegsIoctlFailed)?;
+
+    configure_pvh_segments_and_sregs(mem, &mut sregs)?;
+
+    vcpu.set_sregs(&sregs).map_err(Error::SetSRegsIoctlFailed)?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -393,6 +512,31 @@ mod tests {
         assert_eq!(EFER_LME, sregs.efer);
     }
 
+    #[test]
+    fn pvh_segments_and_sregs() {
+        let mut sregs = Sregs {
+            cr0: X86_CR0_PG,
+            efer: EFER_LME | EFER_LMA,
+            ..Default::default()
+        };
+        let gm = create_guest_mem();
+        configure_pvh_segments_and_sregs(&gm, &mut sregs).unwrap();
+
+        assert_eq!(0x0, read_u64(&gm, BOOT_GDT_OFFSET));
+        assert_eq!(0xcf9b000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 8));
+        assert_eq!(0xcf93000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 16));
+        assert_eq!(0x8b0000000067, read_u64(&gm, BOOT_GDT_OFFSET + 24));
+
+        assert_eq!(1, sregs.cs.db);
+        assert_eq!(0, sregs.cs.l);
+        assert_eq!(0x8, sregs.cs.selector);
+        assert_eq!(0x10, sregs.ss.selector);
+        assert_eq!(0x67, sregs.tr.limit);
+        assert_eq!(X86_CR0_PE, sregs.cr0);
+        assert_eq!(0, sregs.cr4);
+        assert_eq!(0, sregs.efer);
+    }
+
     #[test]
     fn page_tables() {
         let mut sregs = Default::default();
@@ -100,7 +100,7 @@ where
     let write_addr = GuestAddress(0x4000);
 
     // guest mem is 400 pages
-    let guest_mem = X8664arch::setup_memory(memory_size, false).unwrap();
+    let guest_mem = X8664arch::setup_memory(memory_size, None, false, false).unwrap();
     // let guest_mem = GuestMemory::new(&[(GuestAddress(0), memory_size)]).unwrap();
     let mut resources = X8664arch::get_resource_allocator(&guest_mem);
 
@@ -179,17 +179,21 @@ where
     // let (params, kernel_end) = X8664arch::load_kernel(&guest_mem, &mut kernel_image).expect("failed to load kernel");
 
     let suspend_evt = Event::new().unwrap();
+    let sleep_evt = Event::new().unwrap();
     let acpi_dev_resource = X8664arch::setup_acpi_devices(
         &mut io_bus,
         &mut resources,
         suspend_evt
             .try_clone()
             .expect("unable to clone suspend_evt"),
+        sleep_evt.try_clone().expect("unable to clone sleep_evt"),
         exit_evt.try_clone().expect("unable to clone exit_evt"),
         Default::default(),
         &mut irq_chip,
         (&None, None),
         &mut mmio_bus,
+        1,
+        1,
     )
     .unwrap();
 
@@ -208,7 +212,7 @@ where
     mptable::setup_mptable(&guest_mem, 1, pci_irqs).expect("failed to setup mptable");
     smbios::setup_smbios(&guest_mem).expect("failed to setup smbios");
 
-    acpi::create_acpi_tables(&guest_mem, 1, X86_64_SCI_IRQ, acpi_dev_resource.0);
+    acpi::create_acpi_tables(&guest_mem, 1, 1, X86_64_SCI_IRQ, acpi_dev_resource.0);
 
     let guest_mem2 = guest_mem.clone();
 
@@ -227,7 +231,7 @@ where
                 .expect("failed to add vcpu to irqchip");
 
             setup_cpuid(&hyp, &irq_chip, &vcpu, 0, 1, false).unwrap();
-            setup_msrs(&vcpu, END_ADDR_BEFORE_32BITS).unwrap();
+            setup_msrs(&vcpu, END_ADDR_BEFORE_32BITS, false).unwrap();
 
             setup_regs(
                 &vcpu,